@@ -124,6 +124,16 @@ pub fn test(_args: TokenStream, item: TokenStream) -> TokenStream {
 ///
 ///     By default, there is no time limit.
 ///
+/// - `MSIM_MINIMIZE`: Minimize the faults of a failing run.
+///
+///     Faults drawn with `msim::rand::maybe` and `choose` are removed or lowered while it fails.
+///
+///     The smallest choices that still fail are printed.
+///
+///     By default, it is disabled.
+///
+/// - `MSIM_TEST_CHOICES`: Replay the choices printed by `MSIM_MINIMIZE`, with `MSIM_TEST_SEED`.
+///
 /// - `MSIM_TEST_CHECK_DETERMINISM`: Enable determinism check.
 ///
 ///     The test will be run at least twice with the same seed.
@@ -131,6 +141,45 @@ pub fn test(_args: TokenStream, item: TokenStream) -> TokenStream {
 ///
 ///     By default, it is disabled.
 ///
+/// - `MSIM_TEST_CHECK_HASH_ORDER`: Enable determinism check with different std hash keys.
+///
+///     Like `#[sim_test(check_hash_order)]`, each run uses different keys for std maps and sets.
+///
+///     A divergence comes from code that depends on their iteration order.
+///
+///     The panic shows where the diverging random value was drawn.
+///
+///     By default, it is disabled.
+///
+/// - `MSIM_FAILURE_REPORT_DIR`: Set the directory failure reports are written to.
+///
+///     When a test fails, a report describing the state of the simulation is written to
+///     `<dir>/<test>-<seed>`.
+///
+///     By default, reports are written to `msim-failures` in the system temp directory.
+///
+/// - `MSIM_DISABLE_FAILURE_REPORT`: Disable writing failure reports.
+///
+/// - `MSIM_SEED_CORPUS`: Set the seed corpus file.
+///
+///     The seeds of the corpus for the test are run first.
+///
+///     By default, the corpus is `msim-seeds.txt` in the directory of the crate manifest.
+///
+/// - `MSIM_RECORD_SEEDS`: Add failing seeds to the seed corpus.
+///
+///     By default, they are not recorded.
+///
+/// - `MSIM_DISABLE_SEED_CORPUS`: Neither run nor record the seeds of the corpus.
+///
+/// - `MSIM_PROFILE`: Enable virtual time profiling.
+///
+///     The largest contributors to virtual time are printed at the end of each run.
+///
+/// - `MSIM_PROFILE_WALL_CLOCK`: Enable wall clock profiling of the simulator.
+///
+///     The real time spent in the simulator itself is printed at the end of each run.
+///
 /// The test can also be provided a configuration by passing an expression with a type that
 /// can be made into() a TestConfig - SimConfig is the basic choice, see TestConfig for more
 /// options.
@@ -215,6 +264,12 @@ fn parse_test(mut input: syn::ItemFn, args: syn::AttributeArgs) -> Result<TokenS
     });
 
     let check_determinism = test_config.check_determinism;
+    let check_hash_order = test_config.check_hash_order;
+    let fn_name = input.sig.ident.to_string();
+    let output = match &input.sig.output {
+        syn::ReturnType::Default => quote! { () },
+        syn::ReturnType::Type(_, ty) => quote! { #ty },
+    };
 
     let brace_token = input.block.brace_token;
     input.block = syn::parse2(quote_spanned! {last_stmt_end_span=>
@@ -232,7 +287,9 @@ fn parse_test(mut input: syn::ItemFn, args: syn::AttributeArgs) -> Result<TokenS
             let time_limit_s = std::env::var("MSIM_TEST_TIME_LIMIT").ok().map(|num_str| {
                 num_str.parse::<f64>().expect("MSIM_TEST_TIME_LIMIT should be an number")
             });
-            let check = ::std::env::var("MSIM_TEST_CHECK_DETERMINISM").is_ok() || #check_determinism;
+            let check_hash_order = ::std::env::var("MSIM_TEST_CHECK_HASH_ORDER").is_ok() || #check_hash_order;
+            let check = ::std::env::var("MSIM_TEST_CHECK_DETERMINISM").is_ok() || #check_determinism || check_hash_order;
+            let minimize = ::std::env::var("MSIM_MINIMIZE").is_ok();
             if check {
                 count = count.max(2);
             }
@@ -250,11 +307,30 @@ fn parse_test(mut input: syn::ItemFn, args: syn::AttributeArgs) -> Result<TokenS
                 #crate_ident::rand::GlobalRng::new_with_seed(seed).gen::<u64>()
             }
 
+            let test_name = concat!(module_path!(), "::", #fn_name);
+            let corpus = #crate_ident::corpus::SeedCorpus::default_path(env!("CARGO_MANIFEST_DIR"));
+            let record = #crate_ident::corpus::SeedCorpus::record_path(env!("CARGO_MANIFEST_DIR"));
+            // run the seeds of the corpus first, unless checking determinism with a single seed.
+            let mut seeds = if check {
+                vec![]
+            } else {
+                #crate_ident::corpus::load_seeds(corpus.as_deref(), test_name)
+            };
+            let corpus_len = seeds.len();
+            for _ in 0..count {
+                seeds.push(seed);
+                if !check {
+                    seed = next_seed(seed);
+                }
+            }
+
             let mut rand_log = None;
             let mut return_value = None;
-            for i in 0..count {
+            for (i, seed) in seeds.into_iter().enumerate() {
+                let origin = if i < corpus_len { " from the seed corpus" } else { "" };
+                let i = i as u64;
                 let mut inner_seed = seed;
-                println!("starting test iteration {} with seed {}", i, inner_seed);
+                println!("starting test iteration {} with seed {}{}", i, inner_seed, origin);
 
                 let config = std::thread::spawn(move || {
                     let rt = #crate_ident::runtime::Runtime::with_seed_and_config(inner_seed, #crate_ident::SimConfig::default());
@@ -282,10 +358,16 @@ fn parse_test(mut input: syn::ItemFn, args: syn::AttributeArgs) -> Result<TokenS
                     }
 
                     for _j in 0..*repeat {
-                        let sim_config = sim_config.clone();
+                        let mut sim_config = sim_config.clone();
+                        if check_hash_order {
+                            // only change the iteration order of std maps and sets between runs.
+                            sim_config.hashing.seed = Some(inner_seed.wrapping_add(i));
+                        }
                         let rand_log0 = rand_log.take();
                         let res = std::thread::spawn(move || {
+                            let minimize_config = minimize.then(|| sim_config.clone());
                             let mut rt = #crate_ident::runtime::Runtime::with_seed_and_config(inner_seed, sim_config);
+                            #crate_ident::explore::apply_choices_env(rt.handle());
                             if check {
                                 rt.enable_determinism_check(rand_log0);
                             }
@@ -299,31 +381,74 @@ fn parse_test(mut input: syn::ItemFn, args: syn::AttributeArgs) -> Result<TokenS
                             );
 
                             let rt_read = rt.read().unwrap();
-                            let ret = rt_read.as_ref().unwrap().block_on(async #body);
+                            let ret = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                                rt_read.as_ref().unwrap().block_on(async #body)
+                            }));
                             let _ = stop_tx.send(());
                             watchdog.join().unwrap();
+                            let ret = match ret {
+                                Ok(ret) => ret,
+                                Err(e) => {
+                                    let report = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                                        rt_read.as_ref().unwrap().failure_report().with_test_name(test_name)
+                                    })).ok();
+                                    if let Some(config) = minimize_config {
+                                        std::mem::drop(rt_read);
+                                        let min = #crate_ident::explore::minimize_failure(inner_seed, config, || async {
+                                            let _: #output = async #body.await;
+                                        });
+                                        match min {
+                                            Some(min) => println!(
+                                                "note: run with `MSIM_TEST_SEED={} MSIM_TEST_CHOICES={}` to reproduce with minimized faults: {}",
+                                                inner_seed,
+                                                #crate_ident::explore::format_choices(&min.choices),
+                                                min.message,
+                                            ),
+                                            None => println!("note: the failure did not reproduce, it was not minimized"),
+                                        }
+                                    }
+                                    return Err((report, e));
+                                }
+                            };
+                            if let Some(profile) = rt_read.as_ref().unwrap().profile() {
+                                println!("{}", profile);
+                            }
+                            if let Some(report) = rt_read.as_ref().unwrap().perf_report() {
+                                println!("{}", report);
+                            }
                             std::mem::drop(rt_read);
 
                             let log = rt.write().unwrap().take().unwrap().take_rand_log();
-                            (ret, log)
+                            Ok((ret, log))
                         }).join();
                         match res {
-                            Ok((ret, log)) => {
+                            Ok(Ok((ret, log))) => {
                                 return_value = Some(ret);
                                 rand_log = log;
                             }
+                            Ok(Err((report, e))) => {
+                                println!("note: run with `MSIM_TEST_SEED={}` environment variable to reproduce this error", inner_seed);
+                                #crate_ident::corpus::record_failure(record.as_deref(), test_name, inner_seed);
+                                match report.map(|r| r.persist()) {
+                                    Some(Ok(Some(dir))) => {
+                                        println!("note: failure report written to {}", dir.display());
+                                    }
+                                    Some(Err(err)) => {
+                                        println!("note: failed to write failure report: {}", err);
+                                    }
+                                    _ => {}
+                                }
+                                ::std::panic::resume_unwind(e);
+                            }
                             Err(e) => {
                                 println!("note: run with `MSIM_TEST_SEED={}` environment variable to reproduce this error", inner_seed);
+                                #crate_ident::corpus::record_failure(record.as_deref(), test_name, inner_seed);
                                 ::std::panic::resume_unwind(e);
                             }
                         }
                         inner_seed += 1;
                     }
                 }
-
-                if !check {
-                    seed = next_seed(seed);
-                }
             }
             return_value.unwrap()
         }
@@ -344,6 +469,7 @@ struct TestConfig {
     network_config_expr: Option<syn::Expr>,
     run_in_client_node: bool,
     check_determinism: bool,
+    check_hash_order: bool,
 }
 
 impl TestConfig {
@@ -368,6 +494,7 @@ impl Default for TestConfig {
             network_config_expr: None,
             run_in_client_node: true,
             check_determinism: false,
+            check_hash_order: false,
         }
     }
 }
@@ -438,6 +565,10 @@ fn build_test_config(args: syn::AttributeArgs) -> Result<TestConfig, syn::Error>
                         config.check_determinism = true;
                         continue;
                     }
+                    "check_hash_order" => {
+                        config.check_hash_order = true;
+                        continue;
+                    }
                     "threaded_scheduler" | "multi_thread" => {
                         format!(
                             "Set the runtime flavor with #[{}(flavor = \"multi_thread\")].",
@@ -454,7 +585,7 @@ fn build_test_config(args: syn::AttributeArgs) -> Result<TestConfig, syn::Error>
                         format!("The `{}` attribute requires an argument.", name)
                     }
                     name => {
-                        format!("Unknown attribute {} is specified; expected one of: `flavor`, `worker_threads`, `start_paused`, `crate`, `check_determinism`", name)
+                        format!("Unknown attribute {} is specified; expected one of: `flavor`, `worker_threads`, `start_paused`, `crate`, `check_determinism`, `check_hash_order`", name)
                     }
                 };
                 return Err(syn::Error::new_spanned(path, msg));
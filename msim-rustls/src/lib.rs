@@ -0,0 +1,156 @@
+//! TLS over simulated TCP streams.
+//!
+//! This crate wires [`tokio_rustls`] to the TCP streams of the simulator, so that tests can cover
+//! the encrypted path of a system end-to-end. In projects that patch `tokio` with `msim-tokio`,
+//! the listeners and streams used here are the simulated ones, and the handshake runs over the
+//! simulated network. Randomness used by rustls is drawn from the simulator, so handshakes are
+//! deterministic for a given seed.
+//!
+//! Cryptographic work takes no virtual time. To keep handshake timing realistic yet
+//! deterministic, each side of a handshake is charged a fixed amount of virtual time, see
+//! [`DEFAULT_HANDSHAKE_COST`].
+//!
+//! # Example
+//!
+//! ```ignore
+//! // server
+//! let listener = TlsListener::bind("10.0.0.1:443", server_config).await?;
+//! let (mut stream, peer) = listener.accept().await?;
+//!
+//! // client
+//! let connector = Connector::new(client_config);
+//! let name = ServerName::try_from("server.test")?;
+//! let mut stream = connector.connect("10.0.0.1:443", name).await?;
+//! ```
+
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, ServerConfig};
+
+pub use tokio_rustls::{client, rustls, server, TlsAcceptor, TlsConnector};
+
+/// Virtual time charged to each side of a handshake by default.
+pub const DEFAULT_HANDSHAKE_COST: Duration = Duration::from_millis(1);
+
+type Handshake = BoxFuture<'static, (io::Result<server::TlsStream<TcpStream>>, SocketAddr)>;
+
+/// A TCP listener accepting TLS connections.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshake_cost: Duration,
+    /// Handshakes of the accepted connections, in progress.
+    handshakes: Mutex<FuturesUnordered<Handshake>>,
+}
+
+impl std::fmt::Debug for TlsListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("listener", &self.listener)
+            .field("handshake_cost", &self.handshake_cost)
+            .finish()
+    }
+}
+
+impl TlsListener {
+    /// Bind a listener to `addr`, accepting connections with `config`.
+    pub async fn bind(addr: impl ToSocketAddrs, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self::from_listener(listener, config))
+    }
+
+    /// Accept TLS connections on an existing listener.
+    pub fn from_listener(listener: TcpListener, config: Arc<ServerConfig>) -> Self {
+        TlsListener {
+            listener,
+            acceptor: TlsAcceptor::from(config),
+            handshake_cost: DEFAULT_HANDSHAKE_COST,
+            handshakes: Default::default(),
+        }
+    }
+
+    /// Set the virtual time charged to the server side of each handshake.
+    pub fn handshake_cost(mut self, cost: Duration) -> Self {
+        self.handshake_cost = cost;
+        self
+    }
+
+    /// Returns the local address of the listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept a connection and complete the handshake.
+    ///
+    /// Handshakes run concurrently while this is awaited, so a slow or stalled client does not
+    /// hold back the others: the first connection to complete its handshake is returned. An
+    /// error is returned if the handshake of a connection fails, the listener can still be used
+    /// afterwards.
+    pub async fn accept(&self) -> io::Result<(server::TlsStream<TcpStream>, SocketAddr)> {
+        std::future::poll_fn(|cx| {
+            let mut handshakes = self.handshakes.lock().unwrap();
+            while let Poll::Ready((stream, peer)) = self.listener.poll_accept(cx)? {
+                let (acceptor, cost) = (self.acceptor.clone(), self.handshake_cost);
+                handshakes.push(Box::pin(async move {
+                    tokio::time::sleep(cost).await;
+                    (acceptor.accept(stream).await, peer)
+                }));
+            }
+            match handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some((stream, peer))) => Poll::Ready(stream.map(|s| (s, peer))),
+                _ => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+/// Opens TLS connections.
+#[derive(Clone)]
+pub struct Connector {
+    connector: TlsConnector,
+    handshake_cost: Duration,
+}
+
+impl Connector {
+    /// Create a connector using `config`.
+    pub fn new(config: Arc<ClientConfig>) -> Self {
+        Connector {
+            connector: TlsConnector::from(config),
+            handshake_cost: DEFAULT_HANDSHAKE_COST,
+        }
+    }
+
+    /// Set the virtual time charged to the client side of each handshake.
+    pub fn handshake_cost(mut self, cost: Duration) -> Self {
+        self.handshake_cost = cost;
+        self
+    }
+
+    /// Connect to `addr` and complete the handshake, verifying the server as `server_name`.
+    pub async fn connect(
+        &self,
+        addr: impl ToSocketAddrs,
+        server_name: ServerName<'static>,
+    ) -> io::Result<client::TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await?;
+        self.connect_with(stream, server_name).await
+    }
+
+    /// Complete the handshake over an established stream.
+    pub async fn connect_with(
+        &self,
+        stream: TcpStream,
+        server_name: ServerName<'static>,
+    ) -> io::Result<client::TlsStream<TcpStream>> {
+        tokio::time::sleep(self.handshake_cost).await;
+        self.connector.connect(server_name, stream).await
+    }
+}
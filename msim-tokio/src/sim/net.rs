@@ -24,8 +24,8 @@ pub use std::net::ToSocketAddrs;
 
 use msim::net::{
     get_endpoint_from_socket,
-    network::{Payload, PayloadType},
-    try_get_endpoint_from_socket, Endpoint, OwnedFd,
+    network::{Payload, PayloadData, PayloadType},
+    try_get_endpoint_from_socket, Endpoint, Error, OwnedFd,
 };
 use real_tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf, Ready};
 
@@ -120,10 +120,7 @@ impl TcpListener {
             .await
             .map_err(|e| {
                 trace!("error sending local_tcp_id to {}: {} ", from, e);
-                io::Error::new(
-                    io::ErrorKind::ConnectionReset,
-                    format!("peer {} hung up", from),
-                )
+                io::Error::from(Error::Reset(from))
             })?;
 
         debug!(
@@ -232,21 +229,19 @@ enum Message {
 
 impl Message {
     fn new(payload: Payload) -> Self {
-        let s = *payload.data.downcast::<Message>().unwrap();
-        match (&s, &payload.ty) {
-            (Message::TcpId(_), PayloadType::TcpSignalConnect)
-            | (Message::Payload(_, _), PayloadType::TcpData) => (),
-            _ => panic!("invalid payload type {:?}, {:?}", s, payload.ty),
+        match (payload.data, payload.ty) {
+            (PayloadData::TcpConnect(id), PayloadType::TcpSignalConnect) => Message::TcpId(id),
+            (PayloadData::TcpSegment(s, v), PayloadType::TcpData) => Message::Payload(s, v.into()),
+            (data, ty) => panic!("invalid payload type {:?}, {:?}", data, ty),
         }
-        s
     }
 
     fn tcp_id(p: u32) -> Payload {
-        Payload::new_tcp_connect(Box::new(Message::TcpId(p)))
+        Payload::tcp_connect(p)
     }
 
     fn payload(s: u32, v: Vec<u8>) -> Payload {
-        Payload::new_tcp_data(Box::new(Message::Payload(s, v)))
+        Payload::tcp_data(s, v)
     }
 
     fn unwrap_payload(self) -> (u32, Vec<u8>) {
@@ -455,10 +450,7 @@ impl TcpStream {
             .is_peer_live(Some(self.state.remote_sock), remote_tcp_id)
         {
             debug!("peer {} hung up", self.state.remote_sock);
-            return Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::ConnectionReset,
-                format!("peer {} hung up", self.state.remote_sock),
-            )));
+            return Poll::Ready(Err(Error::Reset(self.state.remote_sock).into()));
         }
 
         let tag = self.state.next_recv_tag();
@@ -711,7 +703,7 @@ impl TcpSocket {
             .lock()
             .unwrap()
             .as_ref()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "socket is not connected"))
+            .ok_or_else(|| Error::NotConnected.into())
             .map(|ep| ep.local_addr().unwrap())
     }
 
@@ -814,6 +806,7 @@ mod tests {
     use bytes::{BufMut, BytesMut};
     use futures::join;
     use msim::{
+        net::Error,
         rand,
         rand::RngCore,
         runtime::{init_logger, Handle, Runtime},
@@ -1268,4 +1261,41 @@ mod tests {
             f.await.unwrap();
         });
     }
+
+    #[test]
+    fn tcp_hangup_error_cause() {
+        let runtime = Runtime::new();
+
+        runtime.block_on(async move {
+            let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+            let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+            let handle = Handle::current();
+            let node1 = handle.create_node().ip(addr1.ip()).build();
+            let node2 = handle.create_node().ip(addr2.ip()).build();
+
+            node1.spawn(async move {
+                let listener = TcpListener::bind(addr1).await.unwrap();
+                let (socket, _) = listener.accept().await.unwrap();
+                drop(socket);
+                // the connection shares the endpoint of the listener, so keep it open.
+                sleep(Duration::from_secs(10)).await;
+            });
+
+            let f = node2.spawn(async move {
+                // wait for the server to listen
+                sleep(Duration::from_millis(1)).await;
+                let mut socket = TcpStream::connect(addr1).await.unwrap();
+                sleep(Duration::from_secs(1)).await;
+
+                let err = socket.read_u64().await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+                assert_eq!(Error::from_io(&err), Some(&Error::Reset(addr1)));
+
+                let err = socket.write_u64(1).await.unwrap_err();
+                assert_eq!(Error::from_io(&err), Some(&Error::Reset(addr1)));
+            });
+
+            f.await.unwrap();
+        });
+    }
 }
@@ -4,6 +4,7 @@ use std::io;
 use std::time::Duration;
 
 use msim::runtime as ms_runtime;
+pub use msim::runtime::RuntimeMetrics;
 use msim::task::JoinHandle;
 
 use tracing::{debug, warn};
@@ -46,6 +47,7 @@ impl Handle {
         todo!()
     }
 
+    #[track_caller]
     pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
     where
         F: Future + Send + 'static,
@@ -54,6 +56,11 @@ impl Handle {
         ms_runtime::NodeHandle::current().spawn(future)
     }
 
+    /// The metrics of the runtime of the current node.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        ms_runtime::NodeHandle::current().metrics()
+    }
+
     pub fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
     where
         F: FnOnce() -> R + Send + 'static,
@@ -116,6 +123,7 @@ impl Runtime {
         self.handle.enter()
     }
 
+    #[track_caller]
     pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
     where
         F: Future + Send + 'static,
@@ -375,6 +383,7 @@ impl LocalSet {
     }
 
     /// spawn a task onto the local task set.
+    #[track_caller]
     pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
     where
         F: Future + 'static,
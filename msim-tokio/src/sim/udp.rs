@@ -6,7 +6,7 @@ use std::{
     task::{Context, Poll},
 };
 
-use msim::net::{get_endpoint_from_socket, Endpoint, OwnedFd};
+use msim::net::{get_endpoint_from_socket, Endpoint, Error, OwnedFd};
 use real_tokio::io::{Interest, ReadBuf, Ready};
 
 use bytes::BufMut;
@@ -209,10 +209,7 @@ impl UdpSocket {
         if ready {
             f()
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "socket operation would block",
-            ))
+            Err(Error::BufferFull.into())
         }
     }
 
@@ -0,0 +1,152 @@
+//! Tower services over simulated TCP streams.
+//!
+//! Stacks built on [tower](https://docs.rs/tower), such as hyper and tonic, take a connector
+//! service to open client connections and a stream of accepted connections to serve. This crate
+//! provides both over the TCP streams of the simulator: in projects that patch `tokio` with
+//! `msim-tokio`, [`Connector`] and [`Incoming`] open and accept simulated connections, and the
+//! same code runs over real sockets otherwise.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // server
+//! let incoming = Incoming::bind("10.0.0.1:8080").await?;
+//! Server::builder().add_service(svc).serve_with_incoming(incoming).await?;
+//!
+//! // client
+//! let endpoint = Endpoint::from_static("http://10.0.0.1:8080");
+//! let channel = endpoint.connect_with_connector(Connector::new()).await?;
+//! ```
+
+use futures_core::Stream;
+use http::Uri;
+use hyper_util::rt::TokioIo;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tower_service::Service;
+
+/// The future returned by [`Connector`].
+pub type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<TokioIo<TcpStream>>> + Send>>;
+
+/// A tower service opening a TCP connection to the authority of a [`Uri`].
+///
+/// The port defaults to 443 for `https` and to 80 otherwise. The stream is wrapped in a
+/// [`TokioIo`], which implements the io traits of hyper 1 that tonic and hyper clients take;
+/// [`TokioIo::into_inner`] returns the [`TcpStream`].
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    connect_timeout: Option<Duration>,
+    nodelay: bool,
+}
+
+impl Connector {
+    /// Create a connector without a connect timeout.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail connections that are not established within `timeout` with
+    /// [`io::ErrorKind::TimedOut`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the connections.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = TokioIo<TcpStream>;
+    type Error = io::Error;
+    type Future = ConnectFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let host = uri.host().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("no host in {uri}"))
+            })?;
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+            let connect = TcpStream::connect(format!("{host}:{port}"));
+            let stream = match this.connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect).await.map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("connect to {uri} timed out"),
+                    )
+                })??,
+                None => connect.await?,
+            };
+            stream.set_nodelay(this.nodelay)?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+/// A stream of the connections accepted by a TCP listener, to serve with a tower stack.
+///
+/// The stream never ends. An error accepting a connection is yielded as an item, and the
+/// listener can still be used afterwards.
+#[derive(Debug)]
+pub struct Incoming {
+    listener: TcpListener,
+    nodelay: bool,
+}
+
+impl Incoming {
+    /// Bind a listener to `addr`.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self::from_listener(listener))
+    }
+
+    /// Accept connections on an existing listener.
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Incoming {
+            listener,
+            nodelay: false,
+        }
+    }
+
+    /// Set `TCP_NODELAY` on the accepted connections.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Returns the local address of the listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (stream, _) = match self.listener.poll_accept(cx) {
+            Poll::Ready(Ok(accepted)) => accepted,
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(Some(stream.set_nodelay(self.nodelay).map(|()| stream)))
+    }
+}
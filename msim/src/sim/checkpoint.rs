@@ -0,0 +1,256 @@
+//! Checkpoints of long simulations, to resume them after an interruption.
+//!
+//! A soak test running for hours of wall clock time is expensive to restart from scratch when
+//! its CI job is interrupted. The state of the tasks of a simulation cannot be persisted, but a
+//! soak test usually drives the system from a model that can: the workload position, the
+//! operations acknowledged so far, the faults already injected. The test persists that state
+//! periodically with [`Handle::checkpoint_every`], and on start resumes from the last checkpoint
+//! with [`Handle::resume_from`]:
+//!
+//! ```ignore
+//! let handle = msim::runtime::Handle::current();
+//! let mut model = match handle.resume_from(&path)? {
+//!     Some(checkpoint) => Model::decode(checkpoint.state()),
+//!     None => Model::default(),
+//! };
+//! let shared = model.shared();
+//! handle.checkpoint_every(Duration::from_secs(600), &path, move || shared.encode());
+//! ```
+//!
+//! Checkpoints are written atomically, so an interruption while writing leaves the previous
+//! checkpoint intact. They carry the seed of the run and a checksum of the state, which are
+//! verified when loading.
+
+use super::utils::off_thread;
+use crate::{
+    net::network::checksum,
+    runtime::{Handle, ScheduleId},
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, error};
+
+/// The first line of a checkpoint file starts with this tag and the format version.
+const MAGIC: &str = "msim-checkpoint 1";
+
+/// A persisted state of a simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    seed: u64,
+    elapsed: Duration,
+    state: Vec<u8>,
+}
+
+impl Checkpoint {
+    /// Create a checkpoint of `state`, taken at `elapsed` virtual time into a run with `seed`.
+    pub fn new(seed: u64, elapsed: Duration, state: Vec<u8>) -> Self {
+        Checkpoint {
+            seed,
+            elapsed,
+            state,
+        }
+    }
+
+    /// The seed of the checkpointed run.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Virtual time since the start of the checkpointed run when the checkpoint was taken.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The persisted state.
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    /// Take the persisted state.
+    pub fn into_state(self) -> Vec<u8> {
+        self.state
+    }
+
+    /// Encode the checkpoint: a header line with the seed, the virtual time, and the length and
+    /// checksum of the state, followed by the state.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "{MAGIC} seed={} elapsed={} len={} checksum={:016x}\n",
+            self.seed,
+            self.elapsed.as_nanos(),
+            self.state.len(),
+            checksum(&self.state)
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&self.state);
+        bytes
+    }
+
+    /// Decode a checkpoint, verifying its integrity.
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let end = bytes
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid("truncated checkpoint header"))?;
+        let header = std::str::from_utf8(&bytes[..end])
+            .ok()
+            .and_then(|header| header.strip_prefix(MAGIC))
+            .ok_or_else(|| invalid("not a checkpoint, or an unsupported version"))?;
+        let field = |name: &str| {
+            header
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+                .ok_or_else(|| invalid(&format!("missing {name} in checkpoint header")))
+        };
+        let number = |name: &str, radix: u32| {
+            u64::from_str_radix(field(name)?, radix)
+                .map_err(|_| invalid(&format!("invalid {name} in checkpoint header")))
+        };
+        let state = &bytes[end + 1..];
+        if number("len", 10)? != state.len() as u64 {
+            return Err(invalid("checkpoint state is truncated"));
+        }
+        if number("checksum", 16)? != checksum(state) {
+            return Err(invalid("checkpoint state is corrupted"));
+        }
+        Ok(Checkpoint {
+            seed: number("seed", 10)?,
+            elapsed: Duration::from_nanos(number("elapsed", 10)?),
+            state: state.to_vec(),
+        })
+    }
+
+    /// Load and verify the checkpoint at `path`. Returns `None` if there is none.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let path = path.as_ref();
+        match off_thread(|| fs::read(path)) {
+            Ok(bytes) => Self::decode(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the checkpoint to `path`, replacing the previous one atomically.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let bytes = self.encode();
+        off_thread(|| {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&tmp, bytes)?;
+            fs::rename(&tmp, path)
+        })
+    }
+}
+
+impl Handle {
+    /// Write a checkpoint of `state` to `path` every `interval` of virtual time.
+    ///
+    /// The checkpoints are taken by a [scheduled event](Handle::schedule_every) named
+    /// `checkpoint`, which can be cancelled with the returned id. A checkpoint that fails to be
+    /// written is logged, and the previous one is kept.
+    pub fn checkpoint_every(
+        &self,
+        interval: Duration,
+        path: impl Into<PathBuf>,
+        mut state: impl FnMut() -> Vec<u8> + Send + 'static,
+    ) -> ScheduleId {
+        let path = path.into();
+        let handle = self.clone();
+        self.schedule_every(interval, "checkpoint", move || {
+            let elapsed = handle.time.time_since_clock_base();
+            let checkpoint = Checkpoint::new(handle.seed, elapsed, state());
+            match checkpoint.write_to(&path) {
+                Ok(()) => debug!("checkpoint at {elapsed:?} written to {}", path.display()),
+                Err(e) => error!("failed to write checkpoint to {}: {e}", path.display()),
+            }
+        })
+    }
+
+    /// Load the checkpoint at `path` to resume a run. Returns `None` if there is none.
+    ///
+    /// Returns an error if the checkpoint is corrupted, or was taken in a run with another seed,
+    /// as resuming it would not reproduce the interrupted run.
+    pub fn resume_from(&self, path: impl AsRef<Path>) -> io::Result<Option<Checkpoint>> {
+        let Some(checkpoint) = Checkpoint::load(path.as_ref())? else {
+            return Ok(None);
+        };
+        if checkpoint.seed != self.seed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} was taken with seed {}, not {}",
+                    path.as_ref().display(),
+                    checkpoint.seed,
+                    self.seed
+                ),
+            ));
+        }
+        Ok(Some(checkpoint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, time::sleep};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn checkpoint() {
+        let dir = std::env::temp_dir().join(format!("msim-checkpoint-{}", std::process::id()));
+        let path = dir.join("soak.ckpt");
+        let _ = off_thread(|| fs::remove_dir_all(&dir));
+        let runtime = Runtime::with_seed(7);
+        let handle = runtime.handle().clone();
+        assert_eq!(handle.resume_from(&path).unwrap(), None);
+
+        let ops = Arc::new(AtomicU64::new(0));
+        let ops2 = ops.clone();
+        let id = handle.checkpoint_every(Duration::from_secs(10), &path, move || {
+            ops2.load(Ordering::SeqCst).to_le_bytes().to_vec()
+        });
+        runtime.block_on(async move {
+            sleep(Duration::from_millis(500)).await;
+            for _ in 0..35 {
+                sleep(Duration::from_secs(1)).await;
+                ops.fetch_add(1, Ordering::SeqCst);
+            }
+            handle.cancel_scheduled(id);
+        });
+
+        let checkpoint = runtime.handle().resume_from(&path).unwrap().unwrap();
+        assert_eq!(checkpoint.seed(), 7);
+        assert_eq!(checkpoint.elapsed().as_secs_f64().round(), 30.0);
+        assert_eq!(checkpoint.state(), 29u64.to_le_bytes());
+
+        let e = Runtime::with_seed(8)
+            .handle()
+            .resume_from(&path)
+            .unwrap_err();
+        assert!(e.to_string().contains("taken with seed 7, not 8"));
+
+        let mut bytes = checkpoint.encode();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let e = Checkpoint::decode(&bytes).unwrap_err();
+        assert!(e.to_string().contains("corrupted"));
+        let e = Checkpoint::decode(&bytes[..last]).unwrap_err();
+        assert!(e.to_string().contains("truncated"));
+        assert!(Checkpoint::decode(b"garbage\n").is_err());
+
+        off_thread(|| fs::remove_dir_all(&dir)).unwrap();
+    }
+}
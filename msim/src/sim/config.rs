@@ -1,6 +1,11 @@
 //! Simulation configuration.
 
 pub use crate::net::config::*;
+pub use crate::sim::intercept::{Unsupported, UnsupportedPolicy};
+use crate::{
+    host::HostConfig, inputs::InputConfig, logs::LogConfig, profile::ProfileConfig,
+    progress::ProgressConfig, rand::HashingConfig, runtime::PanicPolicy, trace::TraceConfig,
+};
 
 /// Simulation configuration.
 #[cfg_attr(docsrs, doc(cfg(msim)))]
@@ -8,6 +13,35 @@ pub use crate::net::config::*;
 pub struct SimConfig {
     /// Network configurations.
     pub net: NetworkConfig,
+
+    /// Event tracing configurations.
+    pub trace: TraceConfig,
+
+    /// Virtual time profiling configurations.
+    pub profile: ProfileConfig,
+
+    /// Progress reporting configurations.
+    pub progress: ProgressConfig,
+
+    /// Keys of std `HashMap` and `HashSet`.
+    pub hashing: HashingConfig,
+
+    /// Node log capture configurations.
+    pub logs: LogConfig,
+
+    /// External input recording configurations.
+    pub inputs: InputConfig,
+
+    /// What happens when a task of a node panics.
+    pub panic: PanicPolicy,
+
+    /// Characteristics of the simulated hosts.
+    pub host: HostConfig,
+
+    /// How intercepted library calls handle the cases the simulator does not support. Unknown
+    /// socket options have their own policy, see
+    /// [`NetworkConfig::unknown_sockopt`](crate::net::NetworkConfig::unknown_sockopt).
+    pub unsupported: UnsupportedPolicy,
 }
 
 /// Configuration for a series of tests
@@ -0,0 +1,251 @@
+//! Seed corpus.
+//!
+//! A seed that made a test fail, or that reached an interesting schedule, is worth running again
+//! on every later execution of the test, as a regression test for the bug it found. A
+//! [`SeedCorpus`] is a plain text file listing such seeds, one per line with the name of the
+//! test, and an optional note:
+//!
+//! ```text
+//! # msim seed corpus
+//! my_crate::tests::election 1700000000 split vote after leader crash
+//! my_crate::tests::election 42
+//! ```
+//!
+//! `#[sim_test]` runs the seeds of the corpus for the test first, before the seeds of
+//! `MSIM_TEST_SEED` and `MSIM_TEST_NUM`. The corpus is `msim-seeds.txt` in the directory of the
+//! manifest of the tested crate, meant to be committed along with the tests, or the file set by
+//! `MSIM_SEED_CORPUS`. Set `MSIM_RECORD_SEEDS` to also add the seed of a failing run to the
+//! corpus, which is off by default so that test runs do not write into the source tree, and
+//! `MSIM_DISABLE_SEED_CORPUS` to neither run nor record seeds.
+//!
+//! Seeds found otherwise, e.g. by a search over seeds, can be added with [`SeedCorpus::add`].
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The name of the corpus file in the directory of the manifest of the tested crate.
+pub const DEFAULT_FILE_NAME: &str = "msim-seeds.txt";
+
+/// A seed of the corpus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusEntry {
+    /// The full name of the test, e.g. `my_crate::tests::election`.
+    pub test: String,
+    /// The seed to run the test with.
+    pub seed: u64,
+    /// Why the seed is interesting.
+    pub note: Option<String>,
+}
+
+impl fmt::Display for CorpusEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.test, self.seed)?;
+        if let Some(note) = &self.note {
+            write!(f, " {note}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The seeds worth running again, see the [module](self) documentation.
+#[derive(Debug, Clone)]
+pub struct SeedCorpus {
+    path: PathBuf,
+    entries: Vec<CorpusEntry>,
+}
+
+impl SeedCorpus {
+    /// The corpus of the tests of the crate whose manifest is in `manifest_dir`, as configured by
+    /// the environment.
+    ///
+    /// Returns `None` if the corpus is disabled.
+    pub fn default_path(manifest_dir: impl AsRef<Path>) -> Option<PathBuf> {
+        if std::env::var("MSIM_DISABLE_SEED_CORPUS").is_ok() {
+            return None;
+        }
+        let path = std::env::var_os("MSIM_SEED_CORPUS")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| manifest_dir.as_ref().join(DEFAULT_FILE_NAME));
+        Some(path)
+    }
+
+    /// The corpus that failing seeds of the crate whose manifest is in `manifest_dir` are added
+    /// to, which is the [default corpus](Self::default_path) if `MSIM_RECORD_SEEDS` is set.
+    ///
+    /// Returns `None` if recording is not enabled.
+    pub fn record_path(manifest_dir: impl AsRef<Path>) -> Option<PathBuf> {
+        std::env::var_os("MSIM_RECORD_SEEDS")?;
+        Self::default_path(manifest_dir)
+    }
+
+    /// Load the corpus at `path`, which is empty if the file does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text).map_err(|line| {
+                let msg = format!("{}:{line}: expected `<test> <seed> [note]`", path.display());
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(SeedCorpus { path, entries })
+    }
+
+    /// The file of the corpus.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All seeds of the corpus, in the order they were added.
+    pub fn entries(&self) -> &[CorpusEntry] {
+        &self.entries
+    }
+
+    /// The seeds of `test`, in the order they were added.
+    pub fn seeds(&self, test: &str) -> Vec<u64> {
+        let entries = self.entries.iter().filter(|entry| entry.test == test);
+        entries.map(|entry| entry.seed).collect()
+    }
+
+    /// Add a seed of `test`, unless the corpus has it already.
+    ///
+    /// Returns whether the seed was added. The file is only written by [`save`](Self::save).
+    pub fn add(&mut self, test: &str, seed: u64, note: Option<&str>) -> bool {
+        if self.seeds(test).contains(&seed) {
+            return false;
+        }
+        // notes must stay on the line of their seed.
+        let note = note.map(|note| note.split_whitespace().collect::<Vec<_>>().join(" "));
+        self.entries.push(CorpusEntry {
+            test: test.into(),
+            seed,
+            note: note.filter(|note| !note.is_empty()),
+        });
+        true
+    }
+
+    /// Write the corpus to its file.
+    pub fn save(&self) -> io::Result<()> {
+        fs::write(&self.path, self.to_string())
+    }
+}
+
+impl fmt::Display for SeedCorpus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# msim seed corpus: <test> <seed> [note]")?;
+        for entry in &self.entries {
+            writeln!(f, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the entries of a corpus file, or return the number of the first invalid line.
+fn parse(text: &str) -> Result<Vec<CorpusEntry>, usize> {
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(3, char::is_whitespace);
+        let test = parts.next().unwrap();
+        let seed = parts.next().and_then(|seed| seed.parse().ok());
+        let Some(seed) = seed else {
+            return Err(i + 1);
+        };
+        let note = parts.next().map(str::trim).filter(|note| !note.is_empty());
+        entries.push(CorpusEntry {
+            test: test.into(),
+            seed,
+            note: note.map(Into::into),
+        });
+    }
+    Ok(entries)
+}
+
+/// The seeds of `test` in the corpus at `path`, for `#[sim_test]`.
+///
+/// A corpus that cannot be read is reported and ignored.
+#[doc(hidden)]
+pub fn load_seeds(path: Option<&Path>, test: &str) -> Vec<u64> {
+    let Some(path) = path else {
+        return Vec::new();
+    };
+    match SeedCorpus::open(path) {
+        Ok(corpus) => corpus.seeds(test),
+        Err(e) => {
+            println!("note: failed to read seed corpus: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Add the seed of a failing run of `test` to the corpus at `path`, for `#[sim_test]`.
+#[doc(hidden)]
+pub fn record_failure(path: Option<&Path>, test: &str, seed: u64) {
+    let Some(path) = path else {
+        return;
+    };
+    let result = SeedCorpus::open(path).and_then(|mut corpus| {
+        if corpus.add(test, seed, Some("failed")) {
+            corpus.save()?;
+            println!("note: seed {seed} added to {}", path.display());
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        println!("note: failed to add the seed to the seed corpus: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus() {
+        let text = "\
+# a comment
+a::test 1 split vote
+a::test 2
+
+b::test 3
+";
+        let entries = parse(text).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].note.as_deref(), Some("split vote"));
+        assert_eq!(entries[1].note, None);
+        assert_eq!(parse("a::test\n"), Err(1));
+        assert_eq!(parse("# seeds\na::test x\n"), Err(2));
+
+        // syscalls may be intercepted on this thread, so use the file system from another one.
+        std::thread::spawn(|| {
+            let dir = std::env::temp_dir().join(format!("msim-corpus-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(DEFAULT_FILE_NAME);
+            let _ = fs::remove_file(&path);
+            let mut corpus = SeedCorpus::open(&path).unwrap();
+            assert!(corpus.entries().is_empty());
+            assert!(corpus.add("a::test", 1, Some("split\nvote")));
+            assert!(corpus.add("a::test", 2, None));
+            assert!(!corpus.add("a::test", 1, None));
+            assert!(corpus.add("b::test", 1, None));
+            corpus.save().unwrap();
+
+            let corpus = SeedCorpus::open(&path).unwrap();
+            assert_eq!(corpus.seeds("a::test"), [1, 2]);
+            assert_eq!(corpus.entries()[0].note.as_deref(), Some("split vote"));
+
+            record_failure(Some(&path), "a::test", 3);
+            assert_eq!(load_seeds(Some(&path), "a::test"), [1, 2, 3]);
+            assert!(load_seeds(None, "a::test").is_empty());
+            fs::remove_dir_all(&dir).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+}
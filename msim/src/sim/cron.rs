@@ -0,0 +1,192 @@
+//! Jobs scheduled by wall-clock time.
+//!
+//! Maintenance tasks of real systems, such as pruning or checkpointing, often run at fixed times
+//! of the wall clock rather than at fixed intervals since the process started: every hour on the
+//! hour, or every day at 03:00. Every node of a deployment then runs them at about the same time,
+//! and only the deviations of their clocks set them apart. A [`Cron`] waits for the slots of a
+//! [`Schedule`] by the wall clock of the current node, including the steps of its
+//! [`ClockPersonality`](crate::time::ClockPersonality), so that such jobs run in the simulation
+//! when they would in production:
+//!
+//! ```ignore
+//! use msim::cron::{self, Schedule};
+//!
+//! // prune every hour at five past, on the clock of the node.
+//! let hourly = Schedule::every(Duration::from_secs(3600)).at(Duration::from_secs(300));
+//! cron::spawn("prune", hourly, || async { store.prune().await });
+//! ```
+//!
+//! Like cron, a job runs at most once per slot: slots missed while the previous run was still
+//! going, or skipped by a step of the clock, are not made up for, and a slot is not run again when
+//! the clock steps back.
+
+use crate::{
+    task::{spawn_named, JoinHandle},
+    time::{sleep, SystemTime, UNIX_EPOCH},
+};
+use std::{future::Future, time::Duration};
+use tracing::debug;
+
+/// The wall-clock times at which a job runs: every `period` since the Unix epoch, shifted by
+/// `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    period: Duration,
+    offset: Duration,
+}
+
+impl Schedule {
+    /// Run at every multiple of `period` since the Unix epoch, e.g. every hour on the hour.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn every(period: Duration) -> Self {
+        assert!(!period.is_zero(), "period must be positive");
+        Schedule {
+            period,
+            offset: Duration::ZERO,
+        }
+    }
+
+    /// Shift the slots by `offset`, e.g. to run daily at 03:00 UTC.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is not shorter than the period.
+    pub fn at(mut self, offset: Duration) -> Self {
+        assert!(
+            offset < self.period,
+            "offset must be shorter than the period"
+        );
+        self.offset = offset;
+        self
+    }
+
+    /// The first slot after `time`.
+    pub fn next_after(&self, time: SystemTime) -> SystemTime {
+        self.time_of(self.slot_of(time) + 1)
+    }
+
+    /// The index of the last slot at or before `time`.
+    fn slot_of(&self, time: SystemTime) -> u128 {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.saturating_sub(self.offset).as_nanos() / self.period.as_nanos()
+    }
+
+    /// The wall-clock time of a slot.
+    fn time_of(&self, slot: u128) -> SystemTime {
+        let nanos = slot * self.period.as_nanos() + self.offset.as_nanos();
+        let since_epoch = Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        );
+        UNIX_EPOCH + since_epoch
+    }
+}
+
+/// Waits for the slots of a [`Schedule`] by the wall clock of the current node.
+#[derive(Debug)]
+pub struct Cron {
+    schedule: Schedule,
+    /// The last slot returned, or the slot when the cron was created.
+    last: u128,
+}
+
+impl Cron {
+    /// Wait for the slots of `schedule` after the current time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a runtime.
+    pub fn new(schedule: Schedule) -> Self {
+        Cron {
+            schedule,
+            last: schedule.slot_of(SystemTime::now()),
+        }
+    }
+
+    /// The schedule of the cron.
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// Wait until the wall clock reaches the next slot, and return the time of the slot.
+    ///
+    /// If the clock passed several slots since the last call, only the latest is returned.
+    pub async fn tick(&mut self) -> SystemTime {
+        loop {
+            let now = SystemTime::now();
+            let slot = self.schedule.slot_of(now);
+            if slot > self.last {
+                self.last = slot;
+                return self.schedule.time_of(slot);
+            }
+            // the clock may be stepped meanwhile, so read it again when the sleep ends.
+            let next = self.schedule.time_of(self.last + 1);
+            sleep(next.duration_since(now).unwrap_or_default()).await;
+        }
+    }
+}
+
+/// Spawn a task named `name` on the current node that runs `job` at each slot of `schedule`.
+///
+/// Runs of the job do not overlap. The task ends with the node, or when the returned handle is
+/// aborted.
+pub fn spawn<F, Fut>(name: &str, schedule: Schedule, mut job: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let name_ = name.to_string();
+    let mut cron = Cron::new(schedule);
+    spawn_named(name, async move {
+        loop {
+            let slot = cron.tick().await;
+            debug!("cron {name_}: running the job of {slot:?}");
+            job().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, time::ClockPersonality};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn cron() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        runtime
+            .handle()
+            .set_clock(node.id(), ClockPersonality::jumpy_vm());
+        let f = node.spawn(async move {
+            let minutely = Schedule::every(Duration::from_secs(60)).at(Duration::from_secs(10));
+            let slot = minutely.next_after(SystemTime::now());
+            let since_epoch = slot.duration_since(UNIX_EPOCH).unwrap();
+            assert_eq!(since_epoch.as_secs() % 60, 10);
+            assert_eq!(since_epoch.subsec_nanos(), 0);
+
+            let runs = Arc::new(Mutex::new(vec![]));
+            let runs_ = runs.clone();
+            let job = spawn("minutely", minutely, move || {
+                let runs = runs_.clone();
+                async move { runs.lock().unwrap().push(SystemTime::now()) }
+            });
+            sleep(Duration::from_secs(300)).await;
+            job.abort();
+
+            // runs are on time by the clock of the node, whose steps move them in true time.
+            let runs = runs.lock().unwrap();
+            assert_eq!(runs.len(), 5);
+            for (i, run) in runs.iter().enumerate() {
+                let slot = slot + Duration::from_secs(60 * i as u64);
+                let late = run.duration_since(slot).unwrap();
+                assert!(late < Duration::from_secs(1), "{late:?}");
+            }
+        });
+        runtime.block_on(f).unwrap();
+    }
+}
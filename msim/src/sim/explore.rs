@@ -0,0 +1,560 @@
+//! Exhaustive exploration of small models.
+//!
+//! Random testing samples the space of message delivery orders. For the critical core of a
+//! protocol, running on a very small cluster (up to three nodes, and a bounded number of
+//! messages), it is feasible to enumerate all of them instead. An [`Explorer`] runs a test
+//! repeatedly, each time with a different combination of choices:
+//!
+//! - every message is delivered in one of a few latency slots, so that messages in flight can
+//!   overtake each other in every possible way;
+//! - every call to [`choose`](crate::rand::choose) and [`maybe`](crate::rand::maybe) takes each
+//!   of its options in turn.
+//!
+//! Only the first `max_depth` choice points of a run are varied, later ones take their first
+//! option. Depths are explored in increasing order, so a failing run is reported with the
+//! smallest number of varied choices, which makes the [`Counterexample`] easier to understand.
+//!
+//! Larger tests can instead be run with random choices with [`Explorer::sample`], and a failing
+//! run then shrunk with [`Explorer::minimize`]. By convention, the first option of a choice is
+//! the benign one (e.g. `maybe(p)` returning `false`, i.e. no fault injected, or the shortest
+//! delivery latency), and later options are increasingly disruptive. The minimizer resets as many
+//! choices as possible to their first option, and lowers the remaining ones, while the test keeps
+//! failing.
+//!
+//! A failing `#[sim_test]` is minimized the same way when `MSIM_MINIMIZE` is set: faults
+//! injected with [`maybe`](crate::rand::maybe) and [`choose`](crate::rand::choose) are removed or
+//! made less disruptive while the seed keeps failing, and the minimized choices can be replayed
+//! with `MSIM_TEST_CHOICES`, along with the seed. Message latencies are not varied, so that the
+//! first run is the failing one.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::explore::Explorer;
+//!
+//! let result = Explorer::new().max_depth(6).run(|| async {
+//!     // create 3 nodes and check an invariant
+//! });
+//! if let Some(cex) = result.counterexample {
+//!     panic!("{cex}");
+//! }
+//! ```
+
+use crate::{
+    context,
+    rand::Rng,
+    runtime::{Handle, Runtime},
+    task::NodeId,
+    trace::{Event, EventKind},
+    SimConfig,
+};
+use std::{
+    fmt,
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+/// The choices of one run, see [`Explorer`].
+#[derive(Debug)]
+pub(crate) struct Script {
+    /// The choices to make first.
+    prefix: Vec<usize>,
+    /// The choices made so far, with the number of options of each.
+    made: Vec<(usize, usize)>,
+    /// Number of latency slots for message delivery.
+    slots: usize,
+    /// Width of a latency slot.
+    slot: Duration,
+    /// Whether choices past the prefix are random, rather than the first option.
+    random: bool,
+    /// Whether the latency of messages is a choice.
+    delivery: bool,
+}
+
+impl Script {
+    /// Make the next choice among `options`, calling `random` for a random choice.
+    pub(crate) fn next(&mut self, options: usize, random: impl FnOnce() -> usize) -> usize {
+        let index = match self.prefix.get(self.made.len()) {
+            Some(&index) if index < options => index,
+            // the run diverged from the one the prefix was derived from.
+            Some(_) => 0,
+            None if self.random => random(),
+            None => 0,
+        };
+        self.made.push((index, options));
+        index
+    }
+}
+
+/// Returns the latency of a message while exploring, or `None` otherwise.
+///
+/// The latency slot is a choice of the current [`Script`], and is recorded in the trace.
+pub(crate) fn delivery_latency() -> Option<Duration> {
+    let node = context::try_current_task().map_or(NodeId::zero(), |task| task.node());
+    context::try_current(|h| {
+        let mut script = h.script.lock().unwrap();
+        let script = script.as_mut().filter(|script| script.delivery)?;
+        let options = script.slots;
+        let index = script.next(options, || {
+            h.scenario_rand.with(|rng| rng.gen_range(0..options))
+        });
+        h.trace.record(EventKind::Choice {
+            node,
+            index,
+            options,
+        });
+        Some(script.slot * (index as u32 + 1))
+    })
+    .flatten()
+}
+
+/// A failing run found by an [`Explorer`].
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    /// The choices of the run. Choices past the end take their first option.
+    pub choices: Vec<usize>,
+    /// The panic message of the run.
+    pub message: String,
+    /// The recorded events of the run.
+    pub timeline: Vec<Event>,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "counterexample with choices {:?}: {}",
+            self.choices, self.message
+        )?;
+        for event in &self.timeline {
+            writeln!(f, "{event}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of an exploration.
+#[derive(Debug, Clone)]
+pub struct Exploration {
+    /// The number of runs.
+    pub runs: usize,
+    /// Whether all runs up to the maximum depth were explored, rather than stopping at the time
+    /// budget or a failure.
+    pub exhausted: bool,
+    /// The first failing run, if any.
+    pub counterexample: Option<Counterexample>,
+}
+
+/// Enumerates the delivery orders and scenario choices of a test, see the [module](self)
+/// documentation.
+#[derive(Debug, Clone)]
+pub struct Explorer {
+    config: SimConfig,
+    seed: u64,
+    max_depth: usize,
+    slots: usize,
+    slot: Duration,
+    budget: Duration,
+    vary_delivery: bool,
+}
+
+impl Default for Explorer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Explorer {
+    /// Create an explorer with a maximum depth of 6 and 3 latency slots of 1ms.
+    pub fn new() -> Self {
+        Explorer {
+            config: SimConfig::default(),
+            seed: 0,
+            max_depth: 6,
+            slots: 3,
+            slot: Duration::from_millis(1),
+            budget: Duration::from_secs(60),
+            vary_delivery: true,
+        }
+    }
+
+    /// Set the configuration of the runtimes. Tracing is always enabled.
+    pub fn config(mut self, config: SimConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set the seed of the runtimes, which drives everything but the explored choices.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Vary at most the first `depth` choices of a run.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Deliver messages in one of `slots` latency slots of width `slot`.
+    pub fn delivery_slots(mut self, slots: usize, slot: Duration) -> Self {
+        assert!(slots > 0, "at least one slot is required");
+        self.slots = slots;
+        self.slot = slot;
+        self
+    }
+
+    /// Whether to vary the latency of messages, which is on by default. Otherwise, only the
+    /// scenario choices are varied, and messages have the latency of the network configuration,
+    /// so that a run with random choices is the same as without an explorer.
+    pub fn vary_delivery(mut self, vary: bool) -> Self {
+        self.vary_delivery = vary;
+        self
+    }
+
+    /// Stop exploring after `budget` of wall clock time.
+    pub fn budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Explore the runs of `test`, stopping at the first failure.
+    ///
+    /// `test` is called once per run, inside a fresh runtime.
+    pub fn run<F, Fut>(&self, test: F) -> Exploration
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let start = Instant::now();
+        let mut runs = 0;
+        for depth in 0..=self.max_depth {
+            let mut prefix = vec![];
+            // whether any run made more choices than the depth, otherwise deeper is the same.
+            let mut deeper = false;
+            loop {
+                if start.elapsed() > self.budget {
+                    return Exploration {
+                        runs,
+                        exhausted: false,
+                        counterexample: None,
+                    };
+                }
+                runs += 1;
+                let (made, failure) = self.run_once(prefix, false, &test);
+                if let Some(counterexample) = failure {
+                    return Exploration {
+                        runs,
+                        exhausted: false,
+                        counterexample: Some(counterexample),
+                    };
+                }
+                deeper |= made.len() > depth;
+                // backtrack to the last choice within the depth that has options left.
+                let last = made[..made.len().min(depth)]
+                    .iter()
+                    .rposition(|(index, options)| index + 1 < *options);
+                let Some(i) = last else { break };
+                prefix = made[..i].iter().map(|(index, _)| *index).collect();
+                prefix.push(made[i].0 + 1);
+            }
+            if !deeper {
+                break;
+            }
+        }
+        Exploration {
+            runs,
+            exhausted: true,
+            counterexample: None,
+        }
+    }
+
+    /// Run `test` once with the given choices, e.g. those of a [`Counterexample`].
+    ///
+    /// Panics if the run fails.
+    pub fn replay<F, Fut>(&self, choices: &[usize], test: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        if let (_, Some(counterexample)) = self.run_once(choices.to_vec(), false, &test) {
+            panic!("{counterexample}");
+        }
+    }
+
+    /// Run `test` once with random choices, drawn from the scenario stream of the seed.
+    ///
+    /// Returns the failure, if any, with all the choices of the run.
+    pub fn sample<F, Fut>(&self, test: F) -> Option<Counterexample>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.run_once(vec![], true, &test).1
+    }
+
+    /// Shrink a failing run by re-running `test` with fewer and less disruptive choices.
+    ///
+    /// First, choices are reset to their first option with delta debugging: groups of choices
+    /// are reset while the test keeps failing, with smaller and smaller groups. Then each
+    /// remaining choice is lowered to the smallest option that still fails. Any failure counts,
+    /// not only one with the same message.
+    pub fn minimize<F, Fut>(&self, counterexample: &Counterexample, test: F) -> Counterexample
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let fails = |choices: Vec<usize>| self.run_once(choices, false, &test).1;
+        let mut best = counterexample.clone();
+
+        // remove choices
+        let mut granularity = 2;
+        loop {
+            let active: Vec<usize> = (0..best.choices.len())
+                .filter(|i| best.choices[*i] != 0)
+                .collect();
+            if active.is_empty() {
+                break;
+            }
+            let chunk = active.len().div_ceil(granularity);
+            let reduced = active.chunks(chunk).find_map(|group| {
+                let mut choices = best.choices.clone();
+                for i in group {
+                    choices[*i] = 0;
+                }
+                fails(choices)
+            });
+            match reduced {
+                Some(failure) => {
+                    best = failure;
+                    granularity = (granularity - 1).max(2);
+                }
+                None if granularity >= active.len() => break,
+                None => granularity = (granularity * 2).min(active.len()),
+            }
+        }
+
+        // coarsen the remaining ones
+        for i in 0..best.choices.len() {
+            for index in 1..best.choices.get(i).copied().unwrap_or(0) {
+                let mut choices = best.choices.clone();
+                choices[i] = index;
+                if let Some(failure) = fails(choices) {
+                    best = failure;
+                    break;
+                }
+            }
+        }
+        debug!(
+            "minimized choices {:?} to {:?}",
+            counterexample.choices, best.choices
+        );
+        best
+    }
+
+    /// Run `test` once, returning the choices made and the failure, if any.
+    fn run_once<F, Fut>(
+        &self,
+        prefix: Vec<usize>,
+        random: bool,
+        test: &F,
+    ) -> (Vec<(usize, usize)>, Option<Counterexample>)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut config = self.config.clone();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(self.seed, config);
+        let handle: Handle = runtime.handle().clone();
+        *handle.script.lock().unwrap() = Some(Script {
+            prefix,
+            made: vec![],
+            slots: self.slots,
+            slot: self.slot,
+            random,
+            delivery: self.vary_delivery,
+        });
+
+        let result = catch_unwind(AssertUnwindSafe(|| runtime.block_on(test())));
+        let script = handle.script.lock().unwrap().take().unwrap();
+        let failure = result.err().map(|payload| {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            let mut choices: Vec<_> = script.made.iter().map(|(index, _)| *index).collect();
+            while choices.last() == Some(&0) {
+                choices.pop();
+            }
+            Counterexample {
+                choices,
+                message,
+                timeline: handle.trace.timeline().events().to_vec(),
+            }
+        });
+        (script.made, failure)
+    }
+}
+
+/// Minimize the scenario choices of a failed run of a `#[sim_test]` with `seed` and `config`,
+/// see `MSIM_MINIMIZE`.
+///
+/// Returns `None` if the run does not fail again.
+#[doc(hidden)]
+pub fn minimize_failure<F, Fut>(seed: u64, config: SimConfig, test: F) -> Option<Counterexample>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let explorer = Explorer::new()
+        .seed(seed)
+        .config(config)
+        .vary_delivery(false);
+    let failure = explorer.sample(&test)?;
+    Some(explorer.minimize(&failure, &test))
+}
+
+/// Make the runtime of a `#[sim_test]` take the scenario choices of `MSIM_TEST_CHOICES`, a
+/// comma-separated list of option indices printed by `MSIM_MINIMIZE`.
+#[doc(hidden)]
+pub fn apply_choices_env(handle: &Handle) {
+    let Ok(choices) = std::env::var("MSIM_TEST_CHOICES") else {
+        return;
+    };
+    let choices = choices.split(',').filter(|index| !index.trim().is_empty());
+    let prefix = choices
+        .map(|index| index.trim().parse())
+        .collect::<Result<_, _>>()
+        .expect("MSIM_TEST_CHOICES should be a comma-separated list of integers");
+    set_choices(handle, prefix);
+}
+
+/// Make the runtime take the scenario choices `prefix`, and the first option of later ones.
+fn set_choices(handle: &Handle, prefix: Vec<usize>) {
+    *handle.script.lock().unwrap() = Some(Script {
+        prefix,
+        made: vec![],
+        slots: 1,
+        slot: Duration::ZERO,
+        random: false,
+        delivery: false,
+    });
+}
+
+/// Format choices for `MSIM_TEST_CHOICES`.
+#[doc(hidden)]
+pub fn format_choices(choices: &[usize]) -> String {
+    let choices: Vec<_> = choices.iter().map(ToString::to_string).collect();
+    choices.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{network::Payload, Endpoint},
+        rand::{choose, maybe},
+        time::sleep,
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn explore() {
+        // two nodes send a message to a third one, which expects them in a fixed order.
+        let test = || async {
+            let handle = Handle::current();
+            let addr = "10.0.0.3:1".parse::<SocketAddr>().unwrap();
+            let receiver = handle.create_node().ip(addr.ip()).build();
+            for (i, ip) in ["10.0.0.1", "10.0.0.2"].into_iter().enumerate() {
+                let node = handle.create_node().ip(ip.parse().unwrap()).build();
+                node.spawn(async move {
+                    let ep = Endpoint::bind(libc::SOCK_DGRAM, (ip, 1)).await.unwrap();
+                    sleep(Duration::from_millis(10 + i as u64)).await;
+                    ep.send_to(addr, 1, Payload::new_udp(Box::new(vec![i as u8])))
+                        .await
+                        .unwrap();
+                });
+            }
+            receiver
+                .spawn(async move {
+                    let ep = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                    for i in 0..2 {
+                        let mut buf = [0; 1];
+                        ep.recv_from(1, &mut buf).await.unwrap();
+                        assert_eq!(buf[0], i, "out of order");
+                    }
+                })
+                .await
+                .unwrap();
+        };
+
+        let result = Explorer::new().max_depth(0).run(test);
+        assert_eq!(result.runs, 1);
+        assert!(result.exhausted);
+
+        let explorer = Explorer::new().max_depth(2);
+        let cex = explorer.run(test).counterexample.unwrap();
+        assert!(cex.message.contains("out of order"));
+        assert!(!cex.choices.is_empty() && cex.choices.len() <= 2);
+        assert!(cex.to_string().contains("choice"));
+        let replay = catch_unwind(AssertUnwindSafe(|| explorer.replay(&cex.choices, test)));
+        assert!(replay.is_err());
+
+        // all 2^3 combinations of three binary choices.
+        let result = Explorer::new().run(|| async {
+            for _ in 0..3 {
+                choose(&[false, true]);
+            }
+        });
+        assert_eq!(result.runs, 1 + 2 + 4 + 8);
+        assert!(result.exhausted);
+    }
+
+    #[test]
+    fn minimize() {
+        // fails if fault 3 is injected with a severity of at least 2.
+        let test = || async {
+            let faults: Vec<bool> = (0..8).map(|_| maybe(0.5)).collect();
+            let severity = *choose(&[0, 1, 2, 3]);
+            assert!(!(faults[3] && severity >= 2), "failed");
+        };
+
+        let explorer = (0..)
+            .map(|seed| Explorer::new().seed(seed))
+            .find(|explorer| explorer.sample(test).is_some())
+            .unwrap();
+        let cex = explorer.sample(test).unwrap();
+        assert_eq!(cex.choices.len(), 9);
+        let min = explorer.minimize(&cex, test);
+        assert_eq!(min.choices, [0, 0, 0, 1, 0, 0, 0, 0, 2]);
+        assert_eq!(min.message, "failed");
+    }
+
+    #[test]
+    fn minimize_failure() {
+        let test = || async {
+            let faults: Vec<bool> = (0..8).map(|_| maybe(0.5)).collect();
+            assert!(!faults[3], "failed");
+        };
+        let seed = (0..)
+            .find(|seed| {
+                let runtime = Runtime::with_seed_and_config(*seed, SimConfig::default());
+                catch_unwind(AssertUnwindSafe(|| runtime.block_on(test()))).is_err()
+            })
+            .unwrap();
+        let min = super::minimize_failure(seed, SimConfig::default(), test).unwrap();
+        assert_eq!(min.choices, [0, 0, 0, 1]);
+        assert_eq!(format_choices(&min.choices), "0,0,0,1");
+        let runtime = Runtime::new();
+        set_choices(runtime.handle(), min.choices);
+        assert!(catch_unwind(AssertUnwindSafe(|| runtime.block_on(test()))).is_err());
+        // a run that does not fail again is not minimized.
+        assert!(super::minimize_failure(seed, SimConfig::default(), || async {}).is_none());
+    }
+}
@@ -0,0 +1,1076 @@
+//! Composite fault presets.
+//!
+//! Crashes and partitions are easy to inject with [`Handle::kill`] and [`NetSim::disconnect`],
+//! but many production incidents look different: a node stays up and reachable, yet everything
+//! it does is slow and unreliable. The presets in this module apply several correlated symptoms
+//! to a node at once.
+//!
+//! [`LatencySpike`] models short, transient stalls instead, like garbage collection pauses or
+//! compaction stalls, that are recorded in the [trace](crate::trace) so that application symptoms
+//! can be correlated with them.
+//!
+//! [`HostStall`] freezes the whole simulation in real time, as if the test process was
+//! descheduled by the host, to catch code that mixes the wall clock with the virtual clock.
+//!
+//! [`FailureDomain`] models correlated failures: all the nodes of a rack, availability zone or
+//! region crash or get cut off from the rest of the network at once.
+//!
+//! [`FaultStats`] totals the faults that were actually injected: messages dropped on each link,
+//! crashes of each node, and how long partitions lasted. A chaos test whose faults are
+//! misconfigured passes without testing anything, so [`FaultBounds`] let a test, or a
+//! [scenario](crate::scenario::Scenario::expect_faults), declare how much chaos it expects.
+//!
+//! [`Handle::kill`]: crate::runtime::Handle::kill
+//! [`NetSim::disconnect`]: crate::net::NetSim::disconnect
+
+use crate::{
+    fs::FsSim,
+    net::{Degradation, LatencyDistribution, NetSim},
+    plugin::simulator,
+    runtime::Handle,
+    task::NodeId,
+    time::Instant,
+    trace::EventKind,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    ops::{Bound, Range, RangeBounds},
+    str::FromStr,
+    time::Duration,
+};
+
+/// A "gray failure": a node that is slow but not dead.
+///
+/// While active, traffic to and from the node sees elevated latency, partial udp packet loss and
+/// occasional stalls long enough to trigger timeouts, and every disk operation on the node is
+/// slowed down.
+///
+/// # Example
+///
+/// ```ignore
+/// let gray = GrayFailure::new(0.8);
+/// gray.start(node.id());
+/// sleep(Duration::from_secs(60)).await;
+/// GrayFailure::stop(node.id());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrayFailure {
+    intensity: f64,
+}
+
+impl Default for GrayFailure {
+    fn default() -> Self {
+        Self::new(0.5)
+    }
+}
+
+impl GrayFailure {
+    /// Create a gray failure with the given intensity, from 0.0 (no symptoms) to 1.0 (severe).
+    pub fn new(intensity: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&intensity),
+            "intensity must be in [0, 1]: {intensity}"
+        );
+        Self { intensity }
+    }
+
+    /// The intensity of the failure.
+    pub fn intensity(&self) -> f64 {
+        self.intensity
+    }
+
+    /// The network degradation applied to the node.
+    pub fn network(&self) -> Degradation {
+        Degradation {
+            extra_latency: self.scaled(Duration::from_millis(50)..Duration::from_millis(500)),
+            packet_loss_rate: 0.2 * self.intensity,
+            stall_rate: 0.05 * self.intensity,
+            stall_latency: self.scaled(Duration::from_secs(5)..Duration::from_secs(30)),
+        }
+    }
+
+    /// The latency applied to every disk operation on the node.
+    pub fn disk_latency(&self) -> LatencyDistribution {
+        self.scaled(Duration::from_millis(10)..Duration::from_millis(200))
+    }
+
+    /// Start the failure on a node.
+    pub fn start(&self, node: NodeId) {
+        simulator::<NetSim>().degrade_node(node, Some(self.network()));
+        simulator::<FsSim>().set_io_latency(node, Some(self.disk_latency()));
+    }
+
+    /// Stop any gray failure on a node, restoring normal behavior.
+    pub fn stop(node: NodeId) {
+        simulator::<NetSim>().degrade_node(node, None);
+        simulator::<FsSim>().set_io_latency(node, None);
+    }
+
+    fn scaled(&self, range: Range<Duration>) -> LatencyDistribution {
+        let start = range.start.mul_f64(self.intensity);
+        let end = range.end.mul_f64(self.intensity);
+        if start < end {
+            LatencyDistribution::uniform(start..end)
+        } else {
+            LatencyDistribution::Constant(start)
+        }
+    }
+}
+
+/// A short latency spike on a node, like a garbage collection pause or a compaction stall.
+///
+/// During a spike, the tasks of the node are paused for [`pause`](Self::pause), and traffic sent
+/// or received by the node sees extra latency for the whole duration of the spike. Each spike is
+/// recorded as an [`EventKind::LatencySpike`] in the trace.
+///
+/// The extra latency adds up with any degradation of the node set with
+/// [`NetSim::degrade_node`] or [`GrayFailure`] and with overlapping spikes, and the pause
+/// overlaps with [`Handle::pause`]: a spike only resumes a node that no one else paused.
+///
+/// # Example
+///
+/// ```ignore
+/// // a 200ms stop-the-world pause every 10s
+/// let gc = LatencySpike::new(Duration::from_millis(200));
+/// loop {
+///     sleep(Duration::from_secs(10)).await;
+///     gc.start(node.id());
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencySpike {
+    duration: Duration,
+    pause: Duration,
+    extra_latency: LatencyDistribution,
+}
+
+impl LatencySpike {
+    /// Create a spike that pauses the node for `duration`, and delays its packets by up to
+    /// `duration`.
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            pause: duration,
+            extra_latency: LatencyDistribution::uniform(Duration::ZERO..duration),
+        }
+    }
+
+    /// Pause the tasks of the node for `pause`, at most the duration of the spike. Zero only
+    /// affects the links of the node.
+    pub fn pause(mut self, pause: Duration) -> Self {
+        self.pause = pause.min(self.duration);
+        self
+    }
+
+    /// Set the extra latency of packets sent or received by the node during the spike.
+    pub fn extra_latency(mut self, latency: LatencyDistribution) -> Self {
+        self.extra_latency = latency;
+        self
+    }
+
+    /// The duration of the spike.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Start a spike on a node now.
+    pub fn start(&self, node: NodeId) {
+        let handle = Handle::current();
+        let net = simulator::<NetSim>();
+        let now = handle.time().now_instant();
+        handle.trace().record(EventKind::LatencySpike {
+            node,
+            duration: self.duration,
+        });
+        let time = handle.time().clone();
+
+        if !self.pause.is_zero() {
+            handle.pause_for_fault(node);
+            let handle = handle.clone();
+            // run on the main node, the paused node cannot fire its own timers.
+            time.add_timer_for_node(NodeId::zero(), now + self.pause, move || {
+                handle.resume_for_fault(node);
+            });
+        }
+        let degradation = Degradation {
+            extra_latency: self.extra_latency.clone(),
+            ..Default::default()
+        };
+        let overlay = net.add_degradation(node, degradation);
+        time.add_timer_for_node(NodeId::zero(), now + self.duration, move || {
+            net.remove_degradation(node, overlay);
+        });
+    }
+
+    /// Schedule a spike on a node at `deadline`.
+    pub fn start_at(&self, node: NodeId, deadline: Instant) {
+        let spike = self.clone();
+        let handle = Handle::current();
+        let time = handle.time().clone();
+        time.add_timer_for_node(NodeId::zero(), deadline, move || {
+            let _guard = handle.clone().enter();
+            spike.start(node);
+        });
+    }
+}
+
+/// A stall of the host running the simulation, like the test process being descheduled or a VM
+/// being migrated.
+///
+/// The executor is blocked for [`duration`](Self::duration) of real time, while the virtual clock
+/// stands still: every task and timer runs in the same order, at the same virtual time, as
+/// without the stall. Only code reading the real clock, e.g. through the
+/// [profiler](crate::perf) or a bypass of the simulated clock, notices it, which makes the stall
+/// a way to check that no deadline or measurement mixes the wall clock with the virtual one.
+///
+/// Each stall is recorded as an [`EventKind::HostStall`] in the trace. The
+/// [watchdog](crate::runtime::start_watchdog) takes stalls longer than its timeout for a
+/// deadlock.
+///
+/// # Example
+///
+/// ```ignore
+/// // a 3s hiccup of the host, 10s into the run
+/// HostStall::new(Duration::from_secs(3)).start_at(Instant::now() + Duration::from_secs(10));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostStall {
+    duration: Duration,
+}
+
+impl HostStall {
+    /// Create a stall lasting `duration` of real time.
+    pub fn new(duration: Duration) -> Self {
+        Self { duration }
+    }
+
+    /// The real duration of the stall.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Stall the host now. Returns when the stall is over, without any virtual time passing.
+    pub fn start(&self) {
+        Handle::current().trace().record(EventKind::HostStall {
+            duration: self.duration,
+        });
+        // the simulated clock only advances when the executor is idle, blocking it stalls
+        // everything at once.
+        std::thread::sleep(self.duration);
+    }
+
+    /// Schedule a stall of the host at `deadline`.
+    pub fn start_at(&self, deadline: Instant) {
+        let stall = *self;
+        let handle = Handle::current();
+        let time = handle.time().clone();
+        time.add_timer_for_node(NodeId::zero(), deadline, move || {
+            let _guard = handle.clone().enter();
+            stall.start();
+        });
+    }
+}
+
+/// The level of a [`FailureDomain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DomainLevel {
+    /// Nodes sharing a rack, its power supply and top-of-rack switch.
+    Rack,
+    /// Nodes in the same availability zone.
+    Zone,
+    /// Nodes in the same region.
+    Region,
+}
+
+impl DomainLevel {
+    /// The node label holding the name of the domain at this level.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Rack => "rack",
+            Self::Zone => "zone",
+            Self::Region => "region",
+        }
+    }
+}
+
+/// A fault injected into a whole failure domain, as recorded in the trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DomainFaultKind {
+    /// All nodes of the domain were killed.
+    Crash,
+    /// All nodes of the domain were restarted.
+    Restart,
+    /// The domain was cut off from the other nodes.
+    Isolate,
+    /// The domain was connected to the other nodes again.
+    Heal,
+}
+
+impl fmt::Display for DomainFaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Crash => "crash",
+            Self::Restart => "restart",
+            Self::Isolate => "isolate",
+            Self::Heal => "heal",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A set of nodes that fail together: a rack, an availability zone or a region.
+///
+/// Nodes are placed in domains with [`NodeBuilder::failure_domain`], which adds the label of the
+/// level with the name of the domain, so a node can be in a rack, a zone and a region at once.
+/// Each fault injected into a domain is recorded as an [`EventKind::DomainFault`] in the trace.
+///
+/// # Example
+///
+/// ```ignore
+/// let zone = FailureDomain::zone("us-east-1a");
+/// let node = handle.create_node().failure_domain(zone.clone()).build();
+/// // ...
+/// zone.crash();
+/// sleep(Duration::from_secs(30)).await;
+/// zone.restart();
+/// ```
+///
+/// [`NodeBuilder::failure_domain`]: crate::runtime::NodeBuilder::failure_domain
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FailureDomain {
+    level: DomainLevel,
+    name: String,
+}
+
+impl FailureDomain {
+    /// A domain at `level` named `name`.
+    pub fn new(level: DomainLevel, name: impl Into<String>) -> Self {
+        Self {
+            level,
+            name: name.into(),
+        }
+    }
+
+    /// A rack named `name`.
+    pub fn rack(name: impl Into<String>) -> Self {
+        Self::new(DomainLevel::Rack, name)
+    }
+
+    /// An availability zone named `name`.
+    pub fn zone(name: impl Into<String>) -> Self {
+        Self::new(DomainLevel::Zone, name)
+    }
+
+    /// A region named `name`.
+    pub fn region(name: impl Into<String>) -> Self {
+        Self::new(DomainLevel::Region, name)
+    }
+
+    /// The level of the domain.
+    pub fn level(&self) -> DomainLevel {
+        self.level
+    }
+
+    /// The name of the domain.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The nodes of the domain, ordered by id.
+    pub fn nodes(&self) -> Vec<NodeId> {
+        Handle::current().nodes_with_label(self.level.label(), &self.name)
+    }
+
+    /// Kill all nodes of the domain, and return them.
+    pub fn crash(&self) -> Vec<NodeId> {
+        let handle = Handle::current();
+        let nodes = self.record(&handle, DomainFaultKind::Crash);
+        for node in &nodes {
+            handle.kill(*node);
+        }
+        nodes
+    }
+
+    /// Restart all nodes of the domain, and return them.
+    pub fn restart(&self) -> Vec<NodeId> {
+        let handle = Handle::current();
+        let nodes = self.record(&handle, DomainFaultKind::Restart);
+        for node in &nodes {
+            handle.restart(*node);
+        }
+        nodes
+    }
+
+    /// Cut the links between the nodes of the domain and all other nodes, in both directions,
+    /// and return the nodes of the domain. The nodes of the domain can still reach each other.
+    pub fn isolate(&self) -> Vec<NodeId> {
+        let handle = Handle::current();
+        let nodes = self.record(&handle, DomainFaultKind::Isolate);
+        let net = simulator::<NetSim>();
+        for (inside, outside) in self.links(&handle, &nodes) {
+            net.disconnect2(inside, outside);
+        }
+        nodes
+    }
+
+    /// Connect the nodes of the domain to all other nodes again, and return them.
+    ///
+    /// This also connects links between the domain and other nodes that were disconnected by
+    /// other means.
+    pub fn heal(&self) -> Vec<NodeId> {
+        let handle = Handle::current();
+        let nodes = self.record(&handle, DomainFaultKind::Heal);
+        let net = simulator::<NetSim>();
+        for (inside, outside) in self.links(&handle, &nodes) {
+            net.connect2(inside, outside);
+        }
+        nodes
+    }
+
+    fn record(&self, handle: &Handle, fault: DomainFaultKind) -> Vec<NodeId> {
+        let nodes = self.nodes();
+        handle.trace().record(EventKind::DomainFault {
+            domain: self.to_string(),
+            fault,
+            nodes: nodes.clone(),
+        });
+        nodes
+    }
+
+    /// The links from the nodes of the domain to the other nodes.
+    fn links(&self, handle: &Handle, nodes: &[NodeId]) -> Vec<(NodeId, NodeId)> {
+        let outside: Vec<_> = handle
+            .nodes()
+            .map(|node| node.id)
+            .filter(|id| !nodes.contains(id))
+            .collect();
+        let links = nodes.iter().flat_map(|inside| {
+            let outside = &outside;
+            outside.iter().map(move |other| (*inside, *other))
+        });
+        links.collect()
+    }
+}
+
+impl fmt::Display for FailureDomain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.level.label(), self.name)
+    }
+}
+
+/// Totals of the faults injected up to a point in time, see [`FaultStats::current`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultStats {
+    /// When the totals were taken.
+    pub at: Instant,
+    /// Messages dropped by injected faults on each link, keyed by (src, dst), see
+    /// [`DropReason::is_fault`](crate::net::DropReason::is_fault).
+    pub dropped: BTreeMap<(NodeId, NodeId), u64>,
+    /// Crashes of each node, see [`Handle::crashes`].
+    pub crashes: BTreeMap<NodeId, u64>,
+    /// The number of partitions, see [`NetSim::partition_totals`].
+    ///
+    /// Each direction of a link is a partition of its own, so that [`NetSim::disconnect2`]
+    /// counts as two partitions.
+    pub partitions: u64,
+    /// The sum of the durations of all partitions. Partitions in progress last until the time of
+    /// the totals.
+    pub partition_time: Duration,
+    /// The number of partitions in progress at the time of the totals, see
+    /// [`NetSim::partitions`].
+    pub open_partitions: u64,
+}
+
+impl FaultStats {
+    /// The faults injected since the start of the simulation.
+    pub fn current() -> Self {
+        let net = simulator::<NetSim>();
+        let (partitions, partition_time) = net.partition_totals();
+        FaultStats {
+            at: Instant::now(),
+            dropped: net.fault_drops(),
+            crashes: Handle::current().crashes(),
+            partitions,
+            partition_time,
+            open_partitions: net.partitions().len() as u64,
+        }
+    }
+
+    /// The faults injected between `earlier` and these totals.
+    ///
+    /// Partitions in progress at the time of `earlier` count from then.
+    pub fn since(&self, earlier: &FaultStats) -> FaultStats {
+        fn diff<K: Ord + Copy>(
+            now: &BTreeMap<K, u64>,
+            before: &BTreeMap<K, u64>,
+        ) -> BTreeMap<K, u64> {
+            let diff = now
+                .iter()
+                .map(|(k, n)| (*k, n - before.get(k).unwrap_or(&0)));
+            diff.filter(|(_, n)| *n > 0).collect()
+        }
+        let started = self.partitions - earlier.partitions;
+        FaultStats {
+            at: self.at,
+            dropped: diff(&self.dropped, &earlier.dropped),
+            crashes: diff(&self.crashes, &earlier.crashes),
+            partitions: started + earlier.open_partitions,
+            partition_time: self.partition_time.saturating_sub(earlier.partition_time),
+            open_partitions: self.open_partitions,
+        }
+    }
+
+    /// The number of messages dropped on all links.
+    pub fn total_dropped(&self) -> u64 {
+        self.dropped.values().sum()
+    }
+
+    /// The number of crashes of all nodes.
+    pub fn total_crashes(&self) -> u64 {
+        self.crashes.values().sum()
+    }
+}
+
+impl fmt::Display for FaultStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages dropped, {} crashes, {} partitions lasting {:?}",
+            self.total_dropped(),
+            self.total_crashes(),
+            self.partitions,
+            self.partition_time
+        )
+    }
+}
+
+type Bounds<T> = (Bound<T>, Bound<T>);
+
+/// The expected amount of injected faults, checked against [`FaultStats`].
+///
+/// Bounds are ranges, and unset bounds accept anything:
+///
+/// ```ignore
+/// let bounds = FaultBounds::new().crashes(1..).partition_time(Duration::from_secs(10)..);
+/// bounds.check(&FaultStats::current().since(&start))?;
+/// ```
+///
+/// Bounds can also be parsed from a scenario file, with one bound per line, and durations in
+/// `ms`, `s`, `m` or `h`:
+///
+/// ```text
+/// # at least one crash, and 10s to 1m of partitions
+/// crashes 1..
+/// dropped ..=1000
+/// partitions 1..
+/// partition-time 10s..1m
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FaultBounds {
+    dropped: Option<Bounds<u64>>,
+    crashes: Option<Bounds<u64>>,
+    partitions: Option<Bounds<u64>>,
+    partition_time: Option<Bounds<Duration>>,
+}
+
+impl FaultBounds {
+    /// Bounds that accept anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect the number of messages dropped on all links to be in `range`.
+    pub fn dropped(mut self, range: impl RangeBounds<u64>) -> Self {
+        self.dropped = Some(bounds(range));
+        self
+    }
+
+    /// Expect the number of crashes of all nodes to be in `range`.
+    pub fn crashes(mut self, range: impl RangeBounds<u64>) -> Self {
+        self.crashes = Some(bounds(range));
+        self
+    }
+
+    /// Expect the number of partitions to be in `range`.
+    pub fn partitions(mut self, range: impl RangeBounds<u64>) -> Self {
+        self.partitions = Some(bounds(range));
+        self
+    }
+
+    /// Expect the sum of the durations of all partitions to be in `range`.
+    pub fn partition_time(mut self, range: impl RangeBounds<Duration>) -> Self {
+        self.partition_time = Some(bounds(range));
+        self
+    }
+
+    /// Check that `stats` are within the bounds.
+    ///
+    /// The error lists every bound that is not met.
+    pub fn check(&self, stats: &FaultStats) -> io::Result<()> {
+        let mut errors = vec![];
+        let counts = [
+            ("dropped messages", &self.dropped, stats.total_dropped()),
+            ("crashes", &self.crashes, stats.total_crashes()),
+            ("partitions", &self.partitions, stats.partitions),
+        ];
+        for (name, range, value) in counts {
+            if let Some(range) = range.filter(|range| !range.contains(&value)) {
+                errors.push(format!(
+                    "expected {} {name}, got {value}",
+                    FmtBounds(&range)
+                ));
+            }
+        }
+        let time = stats.partition_time;
+        if let Some(range) = self.partition_time.filter(|range| !range.contains(&time)) {
+            let range = FmtBounds(&range);
+            errors.push(format!("expected {range} of partitions, got {time:?}"));
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let msg = format!("injected faults out of bounds: {}", errors.join(", "));
+        Err(io::Error::other(msg))
+    }
+}
+
+impl FromStr for FaultBounds {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut bounds = FaultBounds::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                let msg = format!("line {}: expected `<fault> <range>`, got `{line}`", i + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            };
+            let (name, range) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let range = range.trim();
+            let count = || parse_range(range, str::parse::<u64>).ok_or_else(invalid);
+            bounds = match name {
+                "dropped" => bounds.dropped(count()?),
+                "crashes" => bounds.crashes(count()?),
+                "partitions" => bounds.partitions(count()?),
+                "partition-time" => {
+                    bounds.partition_time(parse_range(range, parse_duration).ok_or_else(invalid)?)
+                }
+                _ => return Err(invalid()),
+            };
+        }
+        Ok(bounds)
+    }
+}
+
+fn bounds<T: Copy>(range: impl RangeBounds<T>) -> Bounds<T> {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// Parse a range in Rust syntax: `a..b`, `a..=b`, `a..`, `..b` or `..=b`.
+fn parse_range<T, E>(s: &str, parse: impl Fn(&str) -> Result<T, E>) -> Option<Bounds<T>> {
+    let (start, end) = s.split_once("..")?;
+    let start = match start {
+        "" => Bound::Unbounded,
+        start => Bound::Included(parse(start).ok()?),
+    };
+    let end = match end.strip_prefix('=') {
+        Some(end) => Bound::Included(parse(end).ok()?),
+        None if end.is_empty() => Bound::Unbounded,
+        None => Bound::Excluded(parse(end).ok()?),
+    };
+    Some((start, end))
+}
+
+/// Parse a duration such as `500ms`, `10s`, `2m` or `1h`.
+fn parse_duration(s: &str) -> Result<Duration, ()> {
+    let unit = s.find(|c: char| !c.is_ascii_digit()).ok_or(())?;
+    let value: u64 = s[..unit].parse().map_err(|_| ())?;
+    match &s[unit..] {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => Err(()),
+    }
+}
+
+/// Formats bounds in Rust syntax, with `a<..` for a start bound excluding `a`.
+struct FmtBounds<'a, T>(&'a Bounds<T>);
+
+impl<T: fmt::Debug> fmt::Display for FmtBounds<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 .0 {
+            Bound::Included(start) => write!(f, "{start:?}..")?,
+            Bound::Excluded(start) => write!(f, "{start:?}<..")?,
+            Bound::Unbounded => write!(f, "..")?,
+        }
+        match &self.0 .1 {
+            Bound::Included(end) => write!(f, "={end:?}"),
+            Bound::Excluded(end) => write!(f, "{end:?}"),
+            Bound::Unbounded => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fs::File,
+        net::{network::Payload, Endpoint, PartitionTarget},
+        runtime::Runtime,
+        time::sleep,
+    };
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    #[test]
+    fn gray_failure() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let id = node.id();
+
+        let f = node.spawn(async move {
+            let file = File::create("file").await.unwrap();
+            let start = Instant::now();
+            file.write_all_at(b"hello", 0).await.unwrap();
+            assert_eq!(start.elapsed(), Duration::ZERO);
+
+            GrayFailure::new(1.0).start(id);
+            let start = Instant::now();
+            file.write_all_at(b"hello", 0).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(10));
+
+            GrayFailure::stop(id);
+            let start = Instant::now();
+            file.sync_all().await.unwrap();
+            assert_eq!(start.elapsed(), Duration::ZERO);
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn latency_spike() {
+        let mut config = crate::SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticks_ = ticks.clone();
+        let node = runtime.create_node().build();
+        node.spawn(async move {
+            loop {
+                sleep(Duration::from_millis(10)).await;
+                ticks_.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let id1 = node1.id();
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            loop {
+                let (_, from) = ep.recv_from_raw(1).await.unwrap();
+                ep.send_to_raw(from, 1, Payload::new_udp(Box::new(vec![1u8])))
+                    .await
+                    .unwrap();
+            }
+        });
+        let rtt = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut rtt = vec![];
+            sleep(Duration::from_secs(3)).await;
+            for _ in 0..2 {
+                let start = Instant::now();
+                ep.send_to_raw(addr1, 1, Payload::new_udp(Box::new(vec![1u8])))
+                    .await
+                    .unwrap();
+                ep.recv_from_raw(1).await.unwrap();
+                rtt.push(start.elapsed());
+                sleep(Duration::from_secs(2)).await;
+            }
+            rtt
+        });
+
+        let id = node.id();
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(1)).await;
+            let before = ticks.load(Ordering::SeqCst);
+            LatencySpike::new(Duration::from_secs(1)).start(id);
+            sleep(Duration::from_millis(900)).await;
+            assert_eq!(ticks.load(Ordering::SeqCst), before);
+            sleep(Duration::from_millis(200)).await;
+            assert!(ticks.load(Ordering::SeqCst) > before);
+
+            // a link-only spike on the echo server, starting just before the first ping at 3s
+            let spike = LatencySpike::new(Duration::from_secs(1))
+                .pause(Duration::ZERO)
+                .extra_latency(LatencyDistribution::Constant(Duration::from_millis(500)));
+            spike.start_at(id1, Instant::now() + Duration::from_millis(800));
+            let rtt = rtt.await.unwrap();
+            assert!(rtt[0] >= Duration::from_secs(1), "{rtt:?}");
+            assert!(rtt[1] < Duration::from_millis(100), "{rtt:?}");
+
+            crate::trace::timeline().assert(crate::seq!(
+                crate::trace::latency_spike(id),
+                crate::trace::node_pause(id),
+                crate::trace::node_resume(id),
+                crate::trace::latency_spike(id1),
+            ));
+
+            // a spike does not resume a node paused by the test.
+            let handle = Handle::current();
+            handle.pause(id);
+            LatencySpike::new(Duration::from_secs(1)).start(id);
+            sleep(Duration::from_secs(2)).await;
+            let before = ticks.load(Ordering::SeqCst);
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(ticks.load(Ordering::SeqCst), before);
+            // and the test does not resume a node paused by a spike.
+            LatencySpike::new(Duration::from_secs(1)).start(id);
+            handle.resume(id);
+            sleep(Duration::from_millis(900)).await;
+            assert_eq!(ticks.load(Ordering::SeqCst), before);
+            sleep(Duration::from_millis(200)).await;
+            assert!(ticks.load(Ordering::SeqCst) > before);
+        });
+    }
+
+    #[test]
+    fn host_stall() {
+        use crate::perf::real_now;
+
+        let mut config = crate::SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticks_ = ticks.clone();
+        let node = runtime.create_node().build();
+        node.spawn(async move {
+            loop {
+                sleep(Duration::from_millis(10)).await;
+                ticks_.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        runtime.block_on(async move {
+            let stall = HostStall::new(Duration::from_millis(200));
+            let (start, real) = (Instant::now(), real_now());
+            stall.start();
+            assert_eq!(start.elapsed(), Duration::ZERO);
+            assert!(real_now() - real >= stall.duration());
+
+            // virtual time and ordering are unchanged by a scheduled stall
+            stall.start_at(start + Duration::from_millis(505));
+            let real = real_now();
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(start.elapsed().as_millis(), 1000);
+            assert!((99..=100).contains(&ticks.load(Ordering::SeqCst)));
+            assert!(real_now() - real >= stall.duration());
+
+            let stalls: Vec<_> = (crate::trace::timeline().events().iter())
+                .filter(|e| crate::trace::host_stall().matches(e))
+                .map(|e| e.time)
+                .collect();
+            assert_eq!(stalls.len(), 2);
+            assert_eq!((stalls[1] - stalls[0]).as_millis(), 505);
+        });
+    }
+
+    #[test]
+    fn failure_domain() {
+        use crate::{
+            runtime::{ExitStatus, NodeHandle},
+            trace::{domain_fault, node_kill, timeline},
+        };
+
+        /// Returns whether `from` gets an echo from `to`.
+        async fn ping(from: &(NodeHandle, SocketAddr), to: SocketAddr) -> bool {
+            let src = SocketAddr::new(from.1.ip(), 2);
+            let ping = from.0.spawn(async move {
+                let ep = Endpoint::bind(libc::SOCK_DGRAM, src).await.unwrap();
+                let payload = Payload::new_udp(Box::new(vec![1u8]));
+                if ep.send_to_raw(to, 1, payload).await.is_err() {
+                    return false;
+                }
+                crate::time::timeout(Duration::from_secs(1), ep.recv_from_raw(2))
+                    .await
+                    .is_ok()
+            });
+            ping.await.unwrap()
+        }
+
+        let mut config = crate::SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let (zone_a, zone_b) = (FailureDomain::zone("a"), FailureDomain::zone("b"));
+        let mut nodes = vec![];
+        for (i, zone) in [&zone_a, &zone_a, &zone_b].into_iter().enumerate() {
+            let addr: SocketAddr = format!("10.0.0.{}:1", i + 1).parse().unwrap();
+            let node = runtime
+                .create_node()
+                .ip(addr.ip())
+                .failure_domain(zone.clone())
+                .failure_domain(FailureDomain::region("eu"))
+                .init(move || async move {
+                    let ep = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                    loop {
+                        let (_, from) = ep.recv_from_raw(1).await.unwrap();
+                        let payload = Payload::new_udp(Box::new(vec![1u8]));
+                        ep.send_to_raw(from, 2, payload).await.unwrap();
+                    }
+                })
+                .build();
+            nodes.push((node, addr));
+        }
+        let ids: Vec<_> = nodes.iter().map(|(node, _)| node.id()).collect();
+        let handle = runtime.handle().clone();
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(zone_a.nodes(), &ids[..2]);
+            assert_eq!(FailureDomain::region("eu").nodes(), ids);
+            assert!(ping(&nodes[0], nodes[1].1).await);
+            assert!(ping(&nodes[0], nodes[2].1).await);
+
+            // the zone is cut off from the others, but not from itself.
+            assert_eq!(zone_a.isolate(), &ids[..2]);
+            assert!(ping(&nodes[0], nodes[1].1).await);
+            assert!(!ping(&nodes[0], nodes[2].1).await);
+            assert!(!ping(&nodes[2], nodes[1].1).await);
+            zone_a.heal();
+            assert!(ping(&nodes[2], nodes[1].1).await);
+
+            assert_eq!(zone_b.crash(), &ids[2..]);
+            assert_eq!(handle.exit_status(ids[2]), Some(ExitStatus::Killed));
+            assert!(!ping(&nodes[0], nodes[2].1).await);
+            zone_b.restart();
+            sleep(Duration::from_secs(1)).await;
+            assert!(ping(&nodes[0], nodes[2].1).await);
+
+            timeline().assert(crate::seq!(
+                domain_fault(DomainFaultKind::Isolate, &zone_a),
+                domain_fault(DomainFaultKind::Heal, &zone_a),
+                domain_fault(DomainFaultKind::Crash, &zone_b),
+                node_kill(ids[2]),
+                domain_fault(DomainFaultKind::Restart, &zone_b),
+            ));
+            let events = timeline();
+            let isolate = events
+                .events()
+                .iter()
+                .find(|e| matches!(e.kind, EventKind::DomainFault { .. }))
+                .unwrap();
+            assert_eq!(
+                isolate.kind.to_string(),
+                format!("domain-isolate zone=a [{}, {}]", ids[0], ids[1])
+            );
+        });
+    }
+
+    #[test]
+    fn fault_stats() {
+        use crate::time::sleep_until;
+
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            let start = FaultStats::current();
+            net.disconnect2(id1, id2);
+            let send = node1.spawn(async move {
+                let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+                for _ in 0..3 {
+                    let payload = Payload::new_udp(Box::new(vec![1u8]));
+                    assert!(ep.send_to_raw(addr2, 1, payload).await.is_err());
+                }
+            });
+            send.await.unwrap();
+            sleep_until(start.at + Duration::from_secs(10)).await;
+            net.connect2(id1, id2);
+            net.disconnect(id2);
+            // the healed partitions are only kept in the totals.
+            let open = net.partitions();
+            assert_eq!(open.len(), 1);
+            assert_eq!(open[0].target, PartitionTarget::Node(id2));
+            let middle = FaultStats::current();
+            sleep(Duration::from_secs(5)).await;
+            Handle::current().kill(id2);
+            Handle::current().restart(id2);
+
+            // the clog of the node is still in progress.
+            let stats = FaultStats::current().since(&start);
+            assert_eq!(stats.dropped, BTreeMap::from([((id1, id2), 3)]));
+            // restarting the killed node is not another crash.
+            assert_eq!(stats.crashes, BTreeMap::from([(id2, 1)]));
+            // reading the clock takes a little time.
+            assert_eq!(stats.partitions, 3);
+            assert_eq!(stats.partition_time.as_millis(), 25000);
+            assert_eq!(stats.open_partitions, 1);
+            assert!(stats
+                .to_string()
+                .starts_with("3 messages dropped, 1 crashes, 3 partitions lasting 25.0"));
+            let later = FaultStats::current().since(&middle);
+            assert!(later.dropped.is_empty());
+            assert_eq!(later.partitions, 1);
+            assert_eq!(later.partition_time.as_millis(), 5000);
+
+            let secs = Duration::from_secs;
+            let bounds = FaultBounds::new().crashes(1..).partition_time(secs(20)..);
+            bounds.check(&stats).unwrap();
+            let bounds = FaultBounds::new().partition_time(..secs(20));
+            assert!(bounds.check(&stats).is_err());
+            let e = FaultBounds::new()
+                .dropped(..3)
+                .crashes((Bound::Excluded(1), Bound::Included(3)))
+                .check(&stats)
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "injected faults out of bounds: expected ..3 dropped messages, got 3, \
+                 expected 1<..=3 crashes, got 1"
+            );
+
+            let text = "# a comment\ncrashes 1..\n\npartition-time 10s..=1m\n";
+            assert_eq!(
+                text.parse::<FaultBounds>().unwrap(),
+                FaultBounds::new()
+                    .crashes(1..)
+                    .partition_time(secs(10)..=secs(60))
+            );
+            assert!("crashes many".parse::<FaultBounds>().is_err());
+            let e = "dropped ..10\nfloods 1.."
+                .parse::<FaultBounds>()
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "line 2: expected `<fault> <range>`, got `floods 1..`"
+            );
+        });
+    }
+
+    #[test]
+    fn intensity() {
+        let none = GrayFailure::new(0.0);
+        assert_eq!(none.network().packet_loss_rate, 0.0);
+        assert_eq!(
+            none.disk_latency(),
+            LatencyDistribution::Constant(Duration::ZERO)
+        );
+        assert_eq!(GrayFailure::new(1.0).network().packet_loss_rate, 0.2);
+    }
+}
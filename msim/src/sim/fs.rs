@@ -1,36 +1,44 @@
 //! Asynchronous file system.
+//!
+//! The disk of a node can be made read-only with [`FsSim::set_read_only`], or for a window of
+//! virtual time with [`FsSim::read_only_between`], like a cloud volume that is detached or
+//! remounted read-only after errors. Writes then fail with `EROFS` until the disk recovers.
 
 use std::{
     collections::HashMap,
     io::{Error, ErrorKind, Result},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
 };
 use tracing::*;
 
 use crate::{
+    net::LatencyDistribution,
     plugin::{node, simulator, Simulator},
     rand::GlobalRng,
     task::NodeId,
-    time::TimeHandle,
+    time::{Instant, TimeHandle},
     SimConfig,
 };
 
 /// File system simulator.
 #[cfg_attr(docsrs, doc(cfg(msim)))]
-#[derive(Default)]
 pub struct FsSim {
+    rand: GlobalRng,
+    time: TimeHandle,
     handles: Mutex<HashMap<NodeId, FsNodeHandle>>,
 }
 
 impl Simulator for FsSim {
-    fn new(_rand: &GlobalRng, _time: &TimeHandle, _config: &SimConfig) -> Self {
-        Default::default()
-    }
-
-    fn create_node(&self, id: NodeId) {
-        let mut handles = self.handles.lock().unwrap();
-        handles.insert(id, FsNodeHandle::new(id));
+    fn new(rand: &GlobalRng, time: &TimeHandle, _config: &SimConfig) -> Self {
+        FsSim {
+            rand: rand.clone(),
+            time: time.clone(),
+            handles: Default::default(),
+        }
     }
 
     fn reset_node(&self, id: NodeId) {
@@ -43,10 +51,25 @@ impl Simulator for FsSim {
 }
 
 impl FsSim {
-    /// Return a handle of the specified node.
-    fn get_node(&self, id: NodeId) -> FsNodeHandle {
-        let handles = self.handles.lock().unwrap();
-        handles[&id].clone()
+    /// Return a handle of the specified node, or `None` if the node does not exist.
+    ///
+    /// The file system of a node is only allocated when it is first used, since most nodes of a
+    /// large simulation never touch it.
+    fn get_node(&self, id: NodeId) -> Option<FsNodeHandle> {
+        if !crate::context::current(|h| h.task.get_node(id).is_some()) {
+            return None;
+        }
+        let mut handles = self.handles.lock().unwrap();
+        let handle = handles
+            .entry(id)
+            .or_insert_with(|| FsNodeHandle::new(id, self.rand.clone()));
+        Some(handle.clone())
+    }
+
+    /// Like [`get_node`](Self::get_node), but fails with `NotFound` if the node does not exist.
+    fn try_get_node(&self, id: NodeId) -> Result<FsNodeHandle> {
+        self.get_node(id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("node not found: {id}")))
     }
 
     /// Simulate a power failure. All data that does not reach the disk will be lost.
@@ -57,13 +80,59 @@ impl FsSim {
     /// Get the size of given file.
     pub fn get_file_size(&self, node: NodeId, path: impl AsRef<Path>) -> Result<u64> {
         let path = path.as_ref();
-        let handle = self.handles.lock().unwrap()[&node].clone();
+        let handle = self.try_get_node(node)?;
         let fs = handle.fs.lock().unwrap();
         let inode = fs.get(path).ok_or_else(|| {
             Error::new(ErrorKind::NotFound, format!("file not found: {:?}", path))
         })?;
         Ok(inode.metadata().len())
     }
+
+    /// Set the latency of every disk operation on the node, simulating a slow disk.
+    ///
+    /// Pass `None` to restore normal behavior.
+    pub fn set_io_latency(&self, node: NodeId, latency: Option<LatencyDistribution>) {
+        if let Some(handle) = self.get_node(node) {
+            *handle.io.latency.lock().unwrap() = latency;
+        }
+    }
+
+    /// Make the disk of the node read-only, or writable again.
+    ///
+    /// While read-only, creating files and writing, resizing or syncing open files fail with
+    /// `EROFS`. Reads are not affected. The mode survives restarts of the node.
+    pub fn set_read_only(&self, node: NodeId, read_only: bool) {
+        debug!("fs({node}): read_only={read_only}");
+        if let Some(handle) = self.get_node(node) {
+            handle.read_only.store(read_only, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns true if the disk of the node is read-only.
+    pub fn is_read_only(&self, node: NodeId) -> bool {
+        let handle = self.get_node(node);
+        handle.is_some_and(|handle| handle.read_only.load(Ordering::Relaxed))
+    }
+
+    /// Make the disk of the node read-only at `start`, and writable again at `end`, if any.
+    pub fn read_only_between(&self, node: NodeId, start: Instant, end: Option<Instant>) {
+        let Some(FsNodeHandle { read_only, .. }) = self.get_node(node) else {
+            return;
+        };
+        // run on the main node, so that restarts of the node do not cancel the timers.
+        let flag = read_only.clone();
+        self.time
+            .add_timer_for_node(NodeId::zero(), start, move || {
+                debug!("fs({node}): read_only=true");
+                flag.store(true, Ordering::Relaxed);
+            });
+        if let Some(end) = end {
+            self.time.add_timer_for_node(NodeId::zero(), end, move || {
+                debug!("fs({node}): read_only=false");
+                read_only.store(false, Ordering::Relaxed);
+            });
+        }
+    }
 }
 
 /// File system simulator for a node.
@@ -71,19 +140,45 @@ impl FsSim {
 struct FsNodeHandle {
     node: NodeId,
     fs: Arc<Mutex<HashMap<PathBuf, Arc<INode>>>>,
+    io: IoDelay,
+    read_only: Arc<AtomicBool>,
+}
+
+/// Delay applied to disk operations of a node.
+#[derive(Clone)]
+struct IoDelay {
+    rand: GlobalRng,
+    latency: Arc<Mutex<Option<LatencyDistribution>>>,
+}
+
+impl IoDelay {
+    async fn wait(&self) {
+        let latency = self.latency.lock().unwrap().clone();
+        if let Some(latency) = latency {
+            let delay = latency.sample(&mut self.rand.clone());
+            crate::time::sleep(delay).await;
+        }
+    }
 }
 
 impl FsNodeHandle {
-    fn new(node: NodeId) -> Self {
+    fn new(node: NodeId, rand: GlobalRng) -> Self {
         trace!("fs: new at {}", node);
         FsNodeHandle {
             node,
             fs: Arc::new(Mutex::new(HashMap::new())),
+            io: IoDelay {
+                rand,
+                latency: Default::default(),
+            },
+            read_only: Default::default(),
         }
     }
 
     fn current() -> Self {
-        simulator::<FsSim>().get_node(node())
+        simulator::<FsSim>()
+            .get_node(node())
+            .expect("the current node exists")
     }
 
     async fn open(&self, path: impl AsRef<Path>) -> Result<File> {
@@ -97,12 +192,15 @@ impl FsNodeHandle {
         Ok(File {
             inode,
             can_write: false,
+            io: self.io.clone(),
+            read_only: self.read_only.clone(),
         })
     }
 
     async fn create(&self, path: impl AsRef<Path>) -> Result<File> {
         let path = path.as_ref();
         trace!("fs({}): create at {:?}", self.node, path);
+        check_writable(&self.read_only)?;
         let mut fs = self.fs.lock().unwrap();
         let inode = fs
             .entry(path.into())
@@ -112,6 +210,8 @@ impl FsNodeHandle {
         Ok(File {
             inode,
             can_write: true,
+            io: self.io.clone(),
+            read_only: self.read_only.clone(),
         })
     }
 
@@ -125,6 +225,14 @@ impl FsNodeHandle {
     }
 }
 
+/// Fail with `EROFS` if the disk is read-only.
+fn check_writable(read_only: &AtomicBool) -> Result<()> {
+    if read_only.load(Ordering::Relaxed) {
+        return Err(Error::from_raw_os_error(libc::EROFS));
+    }
+    Ok(())
+}
+
 struct INode {
     path: PathBuf,
     data: RwLock<Vec<u8>>,
@@ -153,6 +261,8 @@ impl INode {
 pub struct File {
     inode: Arc<INode>,
     can_write: bool,
+    io: IoDelay,
+    read_only: Arc<AtomicBool>,
 }
 
 impl File {
@@ -178,11 +288,11 @@ impl File {
             offset,
             buf.len()
         );
+        self.io.wait().await;
         let data = self.inode.data.read().unwrap();
         let end = data.len().min(offset as usize + buf.len());
         let len = end - offset as usize;
         buf[..len].copy_from_slice(&data[offset as usize..end]);
-        // TODO: random delay
         Ok(len)
     }
 
@@ -200,6 +310,8 @@ impl File {
                 "the file is read only",
             ));
         }
+        self.io.wait().await;
+        check_writable(&self.read_only)?;
         let mut data = self.inode.data.write().unwrap();
         let end = data.len().min(offset as usize + buf.len());
         let len = end - offset as usize;
@@ -207,7 +319,6 @@ impl File {
         if len < buf.len() {
             data.extend_from_slice(&buf[len..]);
         }
-        // TODO: random delay
         // TODO: simulate buffer, write will not take effect until flush or close
         Ok(())
     }
@@ -215,16 +326,20 @@ impl File {
     /// Truncates or extends the underlying file, updating the size of this file to become `size`.
     pub async fn set_len(&self, size: u64) -> Result<()> {
         trace!("file({:?}): set_len={}", self.inode.path, size);
+        self.io.wait().await;
+        check_writable(&self.read_only)?;
         let mut data = self.inode.data.write().unwrap();
         data.resize(size as usize, 0);
-        // TODO: random delay
         Ok(())
     }
 
     /// Attempts to sync all OS-internal metadata to disk.
     pub async fn sync_all(&self) -> Result<()> {
         trace!("file({:?}): sync_all", self.inode.path);
-        // TODO: random delay
+        self.io.wait().await;
+        if self.can_write {
+            check_writable(&self.read_only)?;
+        }
         Ok(())
     }
 
@@ -238,8 +353,8 @@ impl File {
 pub async fn read(path: impl AsRef<Path>) -> Result<Vec<u8>> {
     let handle = FsNodeHandle::current();
     let file = handle.open(path).await?;
+    file.io.wait().await;
     let data = file.inode.data.read().unwrap().clone();
-    // TODO: random delay
     Ok(data)
 }
 
@@ -299,4 +414,65 @@ mod tests {
         });
         runtime.block_on(f).unwrap();
     }
+
+    #[test]
+    fn read_only() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let id = node.id();
+        let f = node.spawn(async move {
+            let fs = simulator::<FsSim>();
+            let file = File::create("file").await.unwrap();
+            file.write_all_at(b"hello", 0).await.unwrap();
+
+            let now = Instant::now();
+            let secs = std::time::Duration::from_secs;
+            fs.read_only_between(id, now + secs(1), Some(now + secs(2)));
+            assert!(!fs.is_read_only(id));
+
+            crate::time::sleep(secs(1)).await;
+            assert!(fs.is_read_only(id));
+            let erofs = |err: Error| err.raw_os_error() == Some(libc::EROFS);
+            assert!(erofs(file.write_all_at(b"x", 0).await.unwrap_err()));
+            assert!(erofs(file.set_len(0).await.unwrap_err()));
+            assert!(erofs(file.sync_all().await.unwrap_err()));
+            assert!(erofs(File::create("other").await.err().unwrap()));
+            // reads still work
+            assert_eq!(read("file").await.unwrap(), b"hello");
+            File::open("file").await.unwrap().sync_all().await.unwrap();
+
+            crate::time::sleep(secs(1)).await;
+            assert!(!fs.is_read_only(id));
+            file.write_all_at(b"j", 0).await.unwrap();
+            assert_eq!(read("file").await.unwrap(), b"jello");
+
+            fs.set_read_only(id, true);
+            assert!(erofs(file.write_all_at(b"x", 0).await.unwrap_err()));
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn deleted_node() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let id = node.id();
+        let f = node.spawn(async move {
+            File::create("file").await.unwrap();
+        });
+        runtime.block_on(f).unwrap();
+
+        runtime.block_on(async move {
+            let fs = simulator::<FsSim>();
+            assert_eq!(fs.get_file_size(id, "file").unwrap(), 0);
+            crate::runtime::Handle::current().delete_node(id);
+            // the file system of a deleted node is not allocated again.
+            assert!(fs.get_node(id).is_none());
+            fs.set_read_only(id, true);
+            assert!(!fs.is_read_only(id));
+            let err = fs.get_file_size(id, "file").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            assert!(fs.handles.lock().unwrap().get(&id).is_none());
+        });
+    }
 }
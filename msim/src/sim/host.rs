@@ -0,0 +1,452 @@
+//! Characteristics of the simulated hosts.
+//!
+//! Every node runs in the same process, so by default they all report the characteristics of
+//! the machine running the test: its number of processors, its page size and its kernel. Only
+//! the hostname differs: each node reports its name, see
+//! [`NodeBuilder::name`](crate::runtime::NodeBuilder::name). Code that sizes thread pools from
+//! the number of processors, embeds the hostname in identities, or parses the kernel version
+//! then behaves the same on every node, and the same as on the developer's machine, which hides
+//! its dependencies on the environment.
+//!
+//! [`HostConfig::vary`] gives every node its own characteristics, chosen from the seed, so that
+//! such dependencies surface during simulation. They can also be set per node:
+//!
+//! ```ignore
+//! let node = handle
+//!     .create_node()
+//!     .host(HostInfo {
+//!         page_size: Some(65536),
+//!         ..Default::default()
+//!     })
+//!     .build();
+//! ```
+//!
+//! Nodes report their characteristics through the intercepted `gethostname()`, `uname()`,
+//! `getpagesize()` and `sysconf()`, and on Linux `sched_getaffinity()` and `get_nprocs()`.
+//! Characteristics belong to the host, so they are kept when a node restarts. The page size
+//! returned by `sysconf(_SC_PAGESIZE)` is always the real one, as allocators and the standard
+//! library map memory with it.
+//!
+//! The number of processors is what `std::thread::available_parallelism()` and the `num_cpus`
+//! crate return, so code sizing its thread pools from it can be tested with many cores on a
+//! small machine, e.g. with [`NodeBuilder::cpus`](crate::runtime::NodeBuilder::cpus). Both also
+//! read the cpu quota of the cgroup of the process, which is not simulated and still caps the
+//! result.
+
+use crate::{
+    context, define_bypass, define_sys_interceptor,
+    rand::{Rng, SeedableRng},
+    runtime::Handle,
+    sim::intercept::set_errno,
+    task::NodeId,
+};
+use rand::rngs::SmallRng;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Host characteristics configuration.
+#[derive(Debug, Clone, Default)]
+pub struct HostConfig {
+    /// Give every node random characteristics, chosen from the seed and the node, instead of the
+    /// characteristics of the machine running the test. Characteristics set with
+    /// [`NodeBuilder::host`](crate::runtime::NodeBuilder::host), and names given with
+    /// [`NodeBuilder::name`](crate::runtime::NodeBuilder::name), take precedence.
+    pub vary: bool,
+}
+
+/// The characteristics a node reports about its host.
+///
+/// Characteristics left to `None` are those of the machine running the test.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostInfo {
+    /// The name returned by `gethostname()`, and as the node name by `uname()`. Set to the name
+    /// of the node when it is created, unless given.
+    pub hostname: Option<String>,
+    /// The number of processors returned by `sysconf(_SC_NPROCESSORS_ONLN)` and
+    /// `sysconf(_SC_NPROCESSORS_CONF)`, `get_nprocs()`, and the processors the calling thread
+    /// may run on returned by `sched_getaffinity()`.
+    pub cpus: Option<usize>,
+    /// The page size returned by `getpagesize()`.
+    pub page_size: Option<usize>,
+    /// The kernel release returned by `uname()`.
+    pub kernel_release: Option<String>,
+}
+
+impl HostInfo {
+    /// Random characteristics, as given to every node with [`HostConfig::vary`].
+    pub fn random(rng: &mut impl Rng) -> Self {
+        const CPUS: [usize; 9] = [1, 2, 3, 4, 8, 16, 48, 96, 128];
+        const PAGE_SIZES: [usize; 3] = [4096, 16384, 65536];
+        const RELEASES: [&str; 5] = [
+            "4.19.0-25-amd64",
+            "5.4.0-1103-aws",
+            "5.15.0-91-generic",
+            "6.1.0-17-cloud-amd64",
+            "6.8.0-1009-gcp",
+        ];
+        HostInfo {
+            hostname: Some(format!("host-{:08x}", rng.gen::<u32>())),
+            cpus: Some(CPUS[rng.gen_range(0..CPUS.len())]),
+            page_size: Some(PAGE_SIZES[rng.gen_range(0..PAGE_SIZES.len())]),
+            kernel_release: Some(RELEASES[rng.gen_range(0..RELEASES.len())].to_string()),
+        }
+    }
+
+    /// The characteristics of `self`, with those it leaves unset taken from `other`.
+    fn or(self, other: HostInfo) -> Self {
+        HostInfo {
+            hostname: self.hostname.or(other.hostname),
+            cpus: self.cpus.or(other.cpus),
+            page_size: self.page_size.or(other.page_size),
+            kernel_release: self.kernel_release.or(other.kernel_release),
+        }
+    }
+}
+
+/// The characteristics of every node.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostStore {
+    /// The seed of the random characteristics, if they vary.
+    vary: Option<u64>,
+    hosts: Arc<Mutex<BTreeMap<NodeId, HostInfo>>>,
+}
+
+impl HostStore {
+    pub fn new(seed: u64, config: &HostConfig) -> Self {
+        HostStore {
+            vary: config.vary.then_some(seed),
+            hosts: Default::default(),
+        }
+    }
+
+    /// Register a node. Its hostname is the one of `info`, or else the name given to the node,
+    /// or else a random one if the characteristics vary, or else the default name of the node.
+    pub fn create_node(
+        &self,
+        node: NodeId,
+        given_name: Option<String>,
+        default_name: String,
+        mut info: HostInfo,
+    ) {
+        info.hostname = info.hostname.or(given_name);
+        let mut info = match self.vary {
+            Some(seed) => {
+                let mut rng = SmallRng::seed_from_u64(seed ^ node.0.rotate_left(32));
+                info.or(HostInfo::random(&mut rng))
+            }
+            None => info,
+        };
+        info.hostname.get_or_insert(default_name);
+        self.hosts.lock().unwrap().insert(node, info);
+    }
+
+    pub fn delete_node(&self, node: NodeId) {
+        self.hosts.lock().unwrap().remove(&node);
+    }
+
+    /// Read the characteristics of a node. This runs in interceptors, so `f` must not allocate.
+    fn read<T>(&self, node: NodeId, f: impl FnOnce(&HostInfo) -> Option<T>) -> Option<T> {
+        f(self.hosts.lock().unwrap().get(&node)?)
+    }
+
+    fn with<T>(&self, node: NodeId, f: impl FnOnce(&mut HostInfo) -> T) -> T {
+        let mut hosts = self.hosts.lock().unwrap();
+        f(hosts.entry(node).or_default())
+    }
+}
+
+/// The characteristics a node reports.
+pub fn get(node: NodeId) -> HostInfo {
+    Handle::current().hosts.with(node, |info| info.clone())
+}
+
+/// Set the characteristics a node reports. Returns the previous ones.
+pub fn set(node: NodeId, info: HostInfo) -> HostInfo {
+    Handle::current()
+        .hosts
+        .with(node, |old| std::mem::replace(old, info))
+}
+
+/// Read a characteristic of the current node, if it has one.
+fn current<T>(f: impl FnOnce(&HostInfo) -> Option<T>) -> Option<T> {
+    let node = context::try_current_task()?.node();
+    if node == NodeId::zero() {
+        return None;
+    }
+    context::try_current(|h| h.hosts.read(node, f))?
+}
+
+/// Copy `s` into the C string buffer `buf` of `len` bytes, truncating it. Returns whether it fit.
+unsafe fn copy_c_str(s: &str, buf: *mut libc::c_char, len: usize) -> bool {
+    if len == 0 {
+        return false;
+    }
+    let n = s.len().min(len - 1);
+    std::ptr::copy_nonoverlapping(s.as_ptr() as *const libc::c_char, buf, n);
+    *buf.add(n) = 0;
+    n == s.len()
+}
+
+define_bypass!(bypass_sysconf, fn sysconf(name: libc::c_int) -> libc::c_long);
+define_bypass!(bypass_getpagesize, fn getpagesize() -> libc::c_int);
+define_bypass!(bypass_gethostname,
+    fn gethostname(name: *mut libc::c_char, len: libc::size_t) -> libc::c_int);
+define_bypass!(bypass_uname, fn uname(buf: *mut libc::utsname) -> libc::c_int);
+
+define_sys_interceptor!(
+    fn sysconf(name: libc::c_int) -> libc::c_long {
+        let value = match name {
+            libc::_SC_NPROCESSORS_ONLN | libc::_SC_NPROCESSORS_CONF => current(|host| host.cpus),
+            _ => None,
+        };
+        match value {
+            Some(value) => value as libc::c_long,
+            None => bypass_sysconf(name),
+        }
+    }
+);
+
+#[cfg(target_os = "linux")]
+define_bypass!(bypass_sched_getaffinity,
+    fn sched_getaffinity(
+        pid: libc::pid_t,
+        cpusetsize: libc::size_t,
+        mask: *mut libc::cpu_set_t,
+    ) -> libc::c_int);
+
+#[cfg(target_os = "linux")]
+define_sys_interceptor!(
+    fn sched_getaffinity(
+        pid: libc::pid_t,
+        cpusetsize: libc::size_t,
+        mask: *mut libc::cpu_set_t,
+    ) -> libc::c_int {
+        // only the calling thread runs on the simulated host.
+        let cpus = current(|host| host.cpus).filter(|_| pid == 0);
+        let Some(cpus) = cpus else {
+            return bypass_sched_getaffinity(pid, cpusetsize, mask);
+        };
+        if cpus > cpusetsize * 8 {
+            set_errno(libc::EINVAL);
+            return -1;
+        }
+        let bytes = std::slice::from_raw_parts_mut(mask as *mut u8, cpusetsize);
+        bytes.fill(0);
+        for cpu in 0..cpus {
+            bytes[cpu / 8] |= 1 << (cpu % 8);
+        }
+        0
+    }
+);
+
+#[cfg(target_os = "linux")]
+define_bypass!(bypass_get_nprocs, fn get_nprocs() -> libc::c_int);
+#[cfg(target_os = "linux")]
+define_bypass!(bypass_get_nprocs_conf, fn get_nprocs_conf() -> libc::c_int);
+
+#[cfg(target_os = "linux")]
+define_sys_interceptor!(
+    fn get_nprocs() -> libc::c_int {
+        match current(|host| host.cpus) {
+            Some(cpus) => cpus as libc::c_int,
+            None => bypass_get_nprocs(),
+        }
+    }
+);
+
+#[cfg(target_os = "linux")]
+define_sys_interceptor!(
+    fn get_nprocs_conf() -> libc::c_int {
+        match current(|host| host.cpus) {
+            Some(cpus) => cpus as libc::c_int,
+            None => bypass_get_nprocs_conf(),
+        }
+    }
+);
+
+define_sys_interceptor!(
+    fn getpagesize() -> libc::c_int {
+        match current(|host| host.page_size) {
+            Some(page_size) => page_size as libc::c_int,
+            None => bypass_getpagesize(),
+        }
+    }
+);
+
+define_sys_interceptor!(
+    fn gethostname(name: *mut libc::c_char, len: libc::size_t) -> libc::c_int {
+        let copied = current(|host| {
+            let hostname = host.hostname.as_ref()?;
+            Some(copy_c_str(hostname, name, len))
+        });
+        match copied {
+            Some(true) => 0,
+            Some(false) => {
+                set_errno(libc::ENAMETOOLONG);
+                -1
+            }
+            None => bypass_gethostname(name, len),
+        }
+    }
+);
+
+define_sys_interceptor!(
+    fn uname(buf: *mut libc::utsname) -> libc::c_int {
+        let ret = bypass_uname(buf);
+        if ret != 0 {
+            return ret;
+        }
+        let buf = &mut *buf;
+        current(|host| {
+            if let Some(hostname) = &host.hostname {
+                copy_c_str(hostname, buf.nodename.as_mut_ptr(), buf.nodename.len());
+            }
+            if let Some(release) = &host.kernel_release {
+                copy_c_str(release, buf.release.as_mut_ptr(), buf.release.len());
+            }
+            Some(())
+        });
+        0
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, SimConfig};
+    use std::ffi::CStr;
+
+    /// The characteristics reported by the intercepted calls.
+    fn reported() -> (String, usize, usize, usize, String) {
+        unsafe {
+            let mut name = [0 as libc::c_char; 256];
+            assert_eq!(libc::gethostname(name.as_mut_ptr(), name.len()), 0);
+            let mut uts: libc::utsname = std::mem::zeroed();
+            assert_eq!(libc::uname(&mut uts), 0);
+            let nodename = CStr::from_ptr(uts.nodename.as_ptr()).to_str().unwrap();
+            let hostname = CStr::from_ptr(name.as_ptr()).to_str().unwrap();
+            assert_eq!(hostname, nodename);
+            (
+                hostname.to_string(),
+                libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as usize,
+                libc::sysconf(libc::_SC_PAGESIZE) as usize,
+                getpagesize() as usize,
+                CStr::from_ptr(uts.release.as_ptr())
+                    .to_string_lossy()
+                    .into(),
+            )
+        }
+    }
+
+    #[test]
+    fn vary() {
+        let run = |seed| {
+            let mut config = SimConfig::default();
+            config.host.vary = true;
+            let runtime = Runtime::with_seed_and_config(seed, config);
+            let host = HostInfo {
+                page_size: Some(4096),
+                ..Default::default()
+            };
+            let nodes: Vec<_> = (0..8)
+                .map(|_| runtime.create_node().host(host.clone()).build())
+                .collect();
+            let named = runtime.create_node().name("validator-1").build();
+            runtime.block_on(async move {
+                let real = reported();
+                let hostname = get(named.id()).hostname;
+                assert_eq!(hostname.as_deref(), Some("validator-1"));
+                let mut hosts = vec![];
+                for node in nodes {
+                    let info = get(node.id());
+                    let (hostname, cpus, page_size, pagesize, release) =
+                        node.spawn(async { reported() }).await.unwrap();
+                    assert_eq!(Some(hostname), info.hostname);
+                    assert_eq!(Some(cpus), info.cpus);
+                    // allocators rely on sysconf(_SC_PAGESIZE), which is not simulated
+                    assert_eq!((page_size, pagesize), (real.2, 4096));
+                    assert_eq!(Some(release), info.kernel_release);
+                    hosts.push(info);
+                }
+                // the supervisor reports the machine running the test
+                assert_eq!(reported(), real);
+                hosts
+            })
+        };
+        let hosts = run(1);
+        assert_eq!(hosts, run(1));
+        assert_ne!(hosts, run(2));
+        assert!(hosts.iter().any(|host| host.cpus != hosts[0].cpus));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn cpus() {
+        /// The processors reported by sched_getaffinity(), get_nprocs() and sysconf().
+        fn reported_cpus() -> (usize, usize, usize) {
+            unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                let size = std::mem::size_of::<libc::cpu_set_t>();
+                assert_eq!(libc::sched_getaffinity(0, size, &mut set), 0);
+                (
+                    libc::CPU_COUNT(&set) as usize,
+                    get_nprocs() as usize,
+                    libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as usize,
+                )
+            }
+        }
+
+        let runtime = Runtime::new();
+        let node = runtime.create_node().cpus(96).build();
+        runtime.block_on(async move {
+            let real = reported_cpus();
+            let cpus = node.spawn(async { reported_cpus() }).await.unwrap();
+            assert_eq!(cpus, (96, 96, 96));
+            assert_eq!(reported_cpus(), real);
+
+            // a set too small for the processors
+            let err = node.spawn(async {
+                let mut set = [0u8; 8];
+                unsafe { libc::sched_getaffinity(0, set.len(), set.as_mut_ptr() as _) }
+            });
+            assert_eq!(err.await.unwrap(), -1);
+        });
+    }
+
+    #[test]
+    fn set_host() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let named = runtime.create_node().name("validator-1").build();
+        runtime.block_on(async move {
+            let real = reported();
+            let hostname = |name: &str| (name.to_string(), real.1, real.2, real.3, real.4.clone());
+            assert_eq!(
+                node.spawn(async { reported() }).await.unwrap(),
+                hostname(&format!("node-{}", node.id().0))
+            );
+            assert_eq!(
+                named.spawn(async { reported() }).await.unwrap(),
+                hostname("validator-1")
+            );
+
+            let info = HostInfo {
+                hostname: Some("validator-3".into()),
+                cpus: Some(2),
+                ..Default::default()
+            };
+            let old = set(node.id(), info.clone());
+            assert_eq!(old.hostname, Some(format!("node-{}", node.id().0)));
+            let (hostname, cpus, page_size, _, release) =
+                node.spawn(async { reported() }).await.unwrap();
+            assert_eq!((hostname.as_str(), cpus), ("validator-3", 2));
+            assert_eq!((page_size, release), (real.2, real.4));
+
+            // kept across restarts
+            crate::runtime::Handle::current().restart(node.id());
+            assert_eq!(get(node.id()), info);
+        });
+    }
+}
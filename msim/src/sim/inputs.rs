@@ -0,0 +1,414 @@
+//! Recording and replaying the external inputs of a test.
+//!
+//! The seed reproduces everything that happens inside the simulation, but not what a test reads
+//! from outside of it: environment variables, command-line arguments, or scenario files that may
+//! have changed since the failure. Reading them through this module records them, and the
+//! recorded inputs are included in [failure reports](crate::report) as `inputs.txt`:
+//!
+//! ```ignore
+//! let nodes: usize = msim::inputs::env("NODES").map_or(4, |n| n.parse().unwrap());
+//! let scenario = msim::inputs::read_to_string("scenarios/partition.toml")?;
+//! ```
+//!
+//! To replay a run, set `MSIM_REPLAY_INPUTS` to the directory of its failure report, or to an
+//! inputs file, or set [`InputConfig::replay`]. Inputs are then read from the recording instead
+//! of the outside world, and only inputs that were not recorded are read live. Replayed
+//! environment variables are only served by [`env`]: the process environment is left alone, so
+//! code that reads it directly sees the live values, and the seed still has to be set with
+//! `MSIM_TEST_SEED`.
+//!
+//! The `MSIM_*` environment variables are recorded when the runtime is created, so the recording
+//! also shows the seed and configuration overrides of the run.
+
+use super::utils::off_thread;
+use crate::context;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// Input recording configuration.
+#[derive(Debug, Clone, Default)]
+pub struct InputConfig {
+    /// Replay the inputs recorded in this file, or in the `inputs.txt` of this report directory.
+    /// Overridden by the `MSIM_REPLAY_INPUTS` environment variable.
+    pub replay: Option<PathBuf>,
+}
+
+/// The kind of an external input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputKind {
+    /// An environment variable.
+    Env,
+    /// A command-line argument, named by its index.
+    Arg,
+    /// The contents of a file, named by its path.
+    File,
+    /// Any other input, see [`input`].
+    Other,
+}
+
+impl InputKind {
+    const ALL: [InputKind; 4] = [
+        InputKind::Env,
+        InputKind::Arg,
+        InputKind::File,
+        InputKind::Other,
+    ];
+}
+
+impl fmt::Display for InputKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputKind::Env => "env",
+            InputKind::Arg => "arg",
+            InputKind::File => "file",
+            InputKind::Other => "input",
+        })
+    }
+}
+
+/// A set of recorded inputs, in the order they were first read.
+///
+/// Displayed as one input per line: the kind, the name and the value separated by tabs, with
+/// backslashes, tabs and line breaks escaped. The value is omitted if the input was absent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inputs {
+    entries: Vec<(InputKind, String, Option<String>)>,
+}
+
+impl Inputs {
+    /// Get the value of an input: `None` if it was not recorded, `Some(None)` if it was absent.
+    pub fn get(&self, kind: InputKind, name: &str) -> Option<Option<&str>> {
+        self.entries
+            .iter()
+            .find(|(k, n, _)| *k == kind && n == name)
+            .map(|(_, _, value)| value.as_deref())
+    }
+
+    /// Iterate over the recorded inputs.
+    pub fn iter(&self) -> impl Iterator<Item = (InputKind, &str, Option<&str>)> {
+        self.entries
+            .iter()
+            .map(|(kind, name, value)| (*kind, name.as_str(), value.as_deref()))
+    }
+
+    /// The number of recorded inputs.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no input was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Parse inputs in the format they are displayed in.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid input line: {line:?}"),
+            )
+        };
+        let mut inputs = Inputs::default();
+        for line in s.lines().filter(|line| !line.is_empty()) {
+            let mut fields = line.split('\t');
+            let kind = fields.next().unwrap();
+            let kind = InputKind::ALL
+                .into_iter()
+                .find(|k| k.to_string() == kind)
+                .ok_or_else(|| invalid(line))?;
+            let name = unescape(fields.next().ok_or_else(|| invalid(line))?);
+            let value = fields.next().map(unescape);
+            if fields.next().is_some() {
+                return Err(invalid(line));
+            }
+            inputs.entries.push((kind, name, value));
+        }
+        Ok(inputs)
+    }
+
+    /// Load inputs from a file, or from the `inputs.txt` of a failure report directory.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let path = if path.is_dir() {
+            path.join("inputs.txt")
+        } else {
+            path.to_path_buf()
+        };
+        Self::parse(&off_thread(|| fs::read_to_string(path))?)
+    }
+}
+
+impl fmt::Display for Inputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (kind, name, value) in &self.entries {
+            write!(f, "{kind}\t{}", escape(name))?;
+            if let Some(value) = value {
+                write!(f, "\t{}", escape(value))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// The inputs recorded by a runtime, and the inputs it replays.
+#[derive(Clone)]
+pub(crate) struct InputStore {
+    replay: Option<Arc<Inputs>>,
+    recorded: Arc<Mutex<Inputs>>,
+}
+
+impl InputStore {
+    /// Create a store replaying the configured inputs, and record the `MSIM_*` variables.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the inputs to replay cannot be loaded.
+    pub(crate) fn new(config: &InputConfig) -> Self {
+        let path = std::env::var_os("MSIM_REPLAY_INPUTS")
+            .map(PathBuf::from)
+            .or_else(|| config.replay.clone());
+        let replay = path.map(|path| match Inputs::load(&path) {
+            Ok(inputs) => Arc::new(inputs),
+            Err(e) => panic!("failed to load inputs from {}: {e}", path.display()),
+        });
+        let store = InputStore {
+            replay,
+            recorded: Default::default(),
+        };
+        let mut vars: Vec<_> = std::env::vars()
+            .filter(|(name, _)| name.starts_with("MSIM_"))
+            .collect();
+        vars.sort();
+        for (name, value) in vars {
+            store.get(InputKind::Env, name, || Some(value));
+        }
+        store
+    }
+
+    /// Get an input, from the recording if it was already read or is replayed, or from `live`.
+    pub(crate) fn get(
+        &self,
+        kind: InputKind,
+        name: String,
+        live: impl FnOnce() -> Option<String>,
+    ) -> Option<String> {
+        let mut recorded = self.recorded.lock().unwrap();
+        if let Some(value) = recorded.get(kind, &name) {
+            return value.map(str::to_string);
+        }
+        let value = match self.replay.as_ref().and_then(|r| r.get(kind, &name)) {
+            Some(value) => value.map(str::to_string),
+            None => {
+                if self.replay.is_some() {
+                    warn!("input {kind} {name:?} was not recorded, reading it live");
+                }
+                live()
+            }
+        };
+        recorded.entries.push((kind, name, value.clone()));
+        value
+    }
+
+    /// The inputs read so far.
+    pub(crate) fn recorded(&self) -> Inputs {
+        self.recorded.lock().unwrap().clone()
+    }
+}
+
+/// Read an environment variable.
+///
+/// Returns `None` if the variable is not set or is not valid unicode.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn env(name: &str) -> Option<String> {
+    context::current(|h| {
+        h.inputs
+            .get(InputKind::Env, name.into(), || std::env::var(name).ok())
+    })
+}
+
+/// The command-line arguments of the process, starting with the program.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn args() -> Vec<String> {
+    let live: Vec<String> = std::env::args().collect();
+    context::current(|h| {
+        (0..)
+            .map_while(|i| {
+                h.inputs
+                    .get(InputKind::Arg, i.to_string(), || live.get(i).cloned())
+            })
+            .collect()
+    })
+}
+
+/// Read the contents of a file into a string.
+///
+/// When replaying, a file that was missing when the inputs were recorded is reported as not
+/// found.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    let path = path.as_ref();
+    let mut error = None;
+    let contents = context::current(|h| {
+        h.inputs
+            .get(InputKind::File, path.display().to_string(), || {
+                fs::read_to_string(path).map_err(|e| error = Some(e)).ok()
+            })
+    });
+    contents.ok_or_else(|| {
+        error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} was not found when inputs were recorded", path.display()),
+            )
+        })
+    })
+}
+
+/// Record any other external input, computed by `live` unless it is replayed.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn input(name: &str, live: impl FnOnce() -> Option<String>) -> Option<String> {
+    context::current(|h| h.inputs.get(InputKind::Other, name.into(), live))
+}
+
+/// The inputs recorded by the current runtime so far.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn recorded() -> Inputs {
+    context::current(|h| h.inputs.recorded())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, SimConfig};
+
+    #[test]
+    fn record_and_replay() {
+        let runtime = Runtime::new();
+        let recording = runtime.block_on(async {
+            assert_eq!(env("MSIM_INPUTS_TEST_UNSET"), None);
+            assert_eq!(
+                input("shards", || Some("4\tfast\n".into())),
+                Some("4\tfast\n".into())
+            );
+            // read once, then served from the recording
+            assert_eq!(input("shards", || None), Some("4\tfast\n".into()));
+            assert!(!args().is_empty());
+            let e = read_to_string("/nonexistent/scenario.toml").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+            recorded()
+        });
+        assert_eq!(
+            recording.get(InputKind::Other, "shards"),
+            Some(Some("4\tfast\n"))
+        );
+        assert_eq!(
+            recording.get(InputKind::Env, "MSIM_INPUTS_TEST_UNSET"),
+            Some(None)
+        );
+        assert_eq!(recording.get(InputKind::Env, "PATH"), None);
+
+        let text = recording.to_string();
+        assert!(text.contains("input\tshards\t4\\tfast\\n\n"));
+        assert!(text.contains("env\tMSIM_INPUTS_TEST_UNSET\n"));
+        let mut parsed = Inputs::parse(&text).unwrap();
+        assert_eq!(parsed, recording);
+
+        parsed.entries.push((
+            InputKind::File,
+            "scenario.toml".into(),
+            Some("partition = true".into()),
+        ));
+        parsed.entries.push((
+            InputKind::Env,
+            "MSIM_INPUTS_TEST_REPLAYED".into(),
+            Some("1".into()),
+        ));
+        let dir = std::env::temp_dir().join(format!("msim-inputs-{}", std::process::id()));
+        let path = dir.join("inputs.txt");
+        let contents = parsed.to_string();
+        // syscalls are intercepted on runtime threads, so write the file from another one.
+        let dir_ = dir.clone();
+        std::thread::spawn(move || {
+            fs::create_dir_all(&dir_).unwrap();
+            fs::write(&path, contents).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let mut config = SimConfig::default();
+        config.inputs.replay = Some(dir.clone());
+        let runtime = Runtime::with_seed_and_config(0, config);
+        runtime.block_on(async {
+            assert_eq!(
+                input("shards", || Some("8".into())),
+                Some("4\tfast\n".into())
+            );
+            assert_eq!(read_to_string("scenario.toml").unwrap(), "partition = true");
+            let e = read_to_string("/nonexistent/scenario.toml").unwrap_err();
+            assert!(e.to_string().contains("when inputs were recorded"));
+            // not recorded, read live
+            assert_eq!(input("replicas", || Some("3".into())), Some("3".into()));
+            // replayed variables are served without changing the process environment
+            assert_eq!(env("MSIM_INPUTS_TEST_REPLAYED"), Some("1".into()));
+            assert!(std::env::var("MSIM_INPUTS_TEST_REPLAYED").is_err());
+        });
+        std::thread::spawn(move || fs::remove_dir_all(dir))
+            .join()
+            .unwrap()
+            .unwrap();
+    }
+}
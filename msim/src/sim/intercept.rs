@@ -1,5 +1,6 @@
-use std::cell::Cell;
-use tracing::info;
+use crate::{context, runtime::Handle, task::NodeId};
+use std::{cell::Cell, fmt};
+use tracing::{info, warn};
 
 thread_local! {
     static INTERCEPTS_ENABLED: Cell<bool> = const { Cell::new(false) };
@@ -21,6 +22,93 @@ pub(crate) fn intercepts_enabled() -> bool {
     INTERCEPTS_ENABLED.with(|e| e.get())
 }
 
+/// How intercepted library calls handle the cases the simulator does not support, see
+/// [`SimConfig::unsupported`](crate::SimConfig::unsupported).
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsupportedPolicy {
+    /// Panic with an [`Unsupported`] diagnostic. The panic cannot unwind out of the call, so
+    /// the test aborts after printing it.
+    #[default]
+    Strict,
+    /// Log the diagnostic and fail the call with an errno, as a kernel without the feature would.
+    /// The diagnostics are kept, see
+    /// [`Handle::unsupported_calls`](crate::runtime::Handle::unsupported_calls).
+    Lenient,
+}
+
+/// A library call the simulator does not support.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    /// The intercepted function, e.g. `setsockopt`.
+    pub call: &'static str,
+    /// The node that made the call, if any.
+    pub node: Option<NodeId>,
+    /// What is not supported.
+    pub detail: String,
+    /// How to avoid the call.
+    pub hint: &'static str,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported {}()", self.call)?;
+        if let Some(node) = self.node {
+            write!(f, " on {node}")?;
+        }
+        write!(f, ": {} (hint: {})", self.detail, self.hint)
+    }
+}
+
+/// Handle a call the simulator does not support, according to the [`UnsupportedPolicy`] of the
+/// runtime, and return `errno` for the call to fail with.
+pub(crate) fn unsupported(
+    call: &'static str,
+    detail: impl fmt::Display,
+    hint: &'static str,
+    errno: libc::c_int,
+) -> libc::c_int {
+    let policy = Handle::try_current().map_or(UnsupportedPolicy::Strict, |h| h.config.unsupported);
+    unsupported_with(policy, call, detail, hint, errno)
+}
+
+/// Handle a call the simulator does not support according to `policy`, see [`unsupported`].
+pub(crate) fn unsupported_with(
+    policy: UnsupportedPolicy,
+    call: &'static str,
+    detail: impl fmt::Display,
+    hint: &'static str,
+    errno: libc::c_int,
+) -> libc::c_int {
+    let unsupported = Unsupported {
+        call,
+        node: context::try_current_task()
+            .map(|task| task.node())
+            .filter(|node| *node != NodeId::zero()),
+        detail: detail.to_string(),
+        hint,
+    };
+    match Handle::try_current() {
+        Some(handle) if policy == UnsupportedPolicy::Lenient => {
+            warn!("{unsupported}");
+            handle.unsupported.lock().unwrap().push(unsupported);
+            errno
+        }
+        _ => panic!("{unsupported}"),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn set_errno(err: libc::c_int) {
+    *libc::__error() = err;
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn set_errno(err: libc::c_int) {
+    *libc::__errno_location() = err;
+}
+
 /// Cache and call a library function via dlsym()
 #[macro_export]
 macro_rules! define_sys_interceptor {
@@ -47,7 +135,9 @@ macro_rules! define_sys_interceptor {
                 return NEXT_DL_SYM($($param),*);
             }
 
-            $($body)*
+            $crate::sim::perf::measure($crate::sim::perf::Section::Interceptor, || {
+                $($body)*
+            })
         }
     }
 }
@@ -75,3 +165,42 @@ macro_rules! define_bypass {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, SimConfig};
+
+    #[test]
+    fn unsupported_call() {
+        let runtime = Runtime::new();
+        runtime.block_on(async {
+            let result = std::panic::catch_unwind(|| unsupported("foo", "bar", "baz", libc::EIO));
+            let message = *result.unwrap_err().downcast::<String>().unwrap();
+            assert_eq!(message, "unsupported foo(): bar (hint: baz)");
+        });
+
+        let config = SimConfig {
+            unsupported: UnsupportedPolicy::Lenient,
+            ..Default::default()
+        };
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let node = runtime.create_node().build();
+        let f = node.spawn(async {
+            let mut ts = std::mem::MaybeUninit::<libc::timespec>::uninit();
+            let ret = unsafe { libc::clock_gettime(1234, ts.as_mut_ptr()) };
+            assert_eq!(ret, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EINVAL)
+            );
+        });
+        runtime.block_on(f).unwrap();
+
+        let calls = runtime.handle().unsupported_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].call, "clock_gettime");
+        assert_eq!(calls[0].node, Some(node.id()));
+        assert_eq!(calls[0].detail, "clock id 1234");
+    }
+}
@@ -0,0 +1,395 @@
+//! Capturing the log output of each node.
+//!
+//! When log capture is enabled, every `tracing` event emitted from a node is formatted and kept
+//! in an in-memory buffer of that node, so tests can make assertions on what a node logged
+//! without scraping the global log output:
+//!
+//! ```ignore
+//! node.logs().assert_contains("fell back to leader election");
+//! ```
+//!
+//! Events are captured in addition to being passed to the global subscriber, if any. The recent
+//! lines of each node are also included in [failure reports](crate::report).
+//!
+//! Log capture is disabled by default, enable it with [`LogConfig`].
+
+use crate::{task::NodeId, time::TimeHandle};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+    Dispatch, Event, Level, Metadata, Subscriber,
+};
+
+/// Log capture configuration.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Capture the log output of nodes.
+    pub enabled: bool,
+    /// The most verbose level captured.
+    pub level: LevelFilter,
+    /// The number of lines kept for each node. Older lines are discarded, and none are kept if
+    /// it is zero.
+    pub capacity: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            enabled: false,
+            level: LevelFilter::INFO,
+            capacity: 1000,
+        }
+    }
+}
+
+/// A captured log line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    /// Virtual time since the start of the simulation.
+    pub time: Duration,
+    /// The level of the event.
+    pub level: Level,
+    /// The target of the event, usually the module it was emitted from.
+    pub target: String,
+    /// The message of the event, followed by its other fields.
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>12.6}s {:>5} {}: {}",
+            self.time.as_secs_f64(),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+/// The captured log lines of all nodes of a runtime.
+#[derive(Clone)]
+pub(crate) struct LogStore {
+    time: TimeHandle,
+    config: LogConfig,
+    nodes: Arc<Mutex<HashMap<NodeId, VecDeque<LogLine>>>>,
+}
+
+impl LogStore {
+    pub(crate) fn new(time: TimeHandle, config: &LogConfig) -> Self {
+        LogStore {
+            time,
+            config: config.clone(),
+            nodes: Default::default(),
+        }
+    }
+
+    /// Run `f` while capturing the events emitted on this thread, if enabled.
+    pub(crate) fn capture<T>(&self, f: impl FnOnce() -> T) -> T {
+        if !self.config.enabled {
+            return f();
+        }
+        let inner = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let dispatch = Dispatch::new(Capture {
+            inner,
+            store: self.clone(),
+        });
+        tracing::dispatcher::with_default(&dispatch, f)
+    }
+
+    /// Get the lines captured from a node.
+    pub(crate) fn node(&self, node: NodeId) -> Logs {
+        let nodes = self.nodes.lock().unwrap();
+        let lines = nodes
+            .get(&node)
+            .map(|lines| lines.iter().cloned().collect());
+        Logs {
+            node,
+            lines: lines.unwrap_or_default(),
+        }
+    }
+
+    /// Get the lines captured from all nodes, ordered by node id.
+    pub(crate) fn nodes(&self) -> Vec<Logs> {
+        let mut ids: Vec<_> = self.nodes.lock().unwrap().keys().copied().collect();
+        ids.sort();
+        ids.into_iter().map(|id| self.node(id)).collect()
+    }
+
+    fn push(&self, node: NodeId, line: LogLine) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        let lines = nodes.entry(node).or_default();
+        if lines.len() == self.config.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+/// Get the lines captured from `node` by the current runtime.
+///
+/// # Panics
+///
+/// Panics if called outside of a runtime.
+pub fn node(node: NodeId) -> Logs {
+    crate::context::current(|h| h.logs.node(node))
+}
+
+/// A snapshot of the lines captured from a node, oldest first.
+#[derive(Debug, Clone)]
+pub struct Logs {
+    node: NodeId,
+    lines: Vec<LogLine>,
+}
+
+impl Logs {
+    /// The node the lines were captured from.
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    /// The captured lines.
+    pub fn lines(&self) -> &[LogLine] {
+        &self.lines
+    }
+
+    /// Returns the lines whose message contains `pattern`.
+    pub fn matching<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item = &'a LogLine> + 'a {
+        self.lines
+            .iter()
+            .filter(move |line| line.message.contains(pattern))
+    }
+
+    /// Returns true if the message of a line contains `pattern`.
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.matching(pattern).next().is_some()
+    }
+
+    /// Panics if no line contains `pattern`.
+    #[track_caller]
+    pub fn assert_contains(&self, pattern: &str) {
+        if !self.contains(pattern) {
+            panic!(
+                "no log line of node {} contains {pattern:?}, captured:\n{self}",
+                self.node
+            );
+        }
+    }
+
+    /// Panics if a line contains `pattern`.
+    #[track_caller]
+    pub fn assert_not_contains(&self, pattern: &str) {
+        if let Some(line) = self.matching(pattern).next() {
+            panic!(
+                "a log line of node {} contains {pattern:?}: {line}",
+                self.node
+            );
+        }
+    }
+}
+
+impl fmt::Display for Logs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A subscriber that captures the events of nodes, and forwards everything to `inner`.
+struct Capture {
+    inner: Dispatch,
+    store: LogStore,
+}
+
+impl Capture {
+    fn captures(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_event() && self.store.config.level >= *metadata.level()
+    }
+}
+
+impl Subscriber for Capture {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let interest = self.inner.register_callsite(metadata);
+        if self.captures(metadata) && !interest.is_always() {
+            Interest::sometimes()
+        } else {
+            interest
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.captures(metadata) || self.inner.enabled(metadata)
+    }
+
+    fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+        self.inner.new_span(span)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        self.inner.record(span, values)
+    }
+
+    fn record_follows_from(&self, span: &span::Id, follows: &span::Id) {
+        self.inner.record_follows_from(span, follows)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        if self.captures(metadata) {
+            if let Some(task) = crate::context::try_current_task() {
+                let mut visitor = MessageVisitor::default();
+                event.record(&mut visitor);
+                let line = LogLine {
+                    time: self.store.time.time_since_clock_base(),
+                    level: *metadata.level(),
+                    target: metadata.target().into(),
+                    message: visitor.message + &visitor.fields,
+                };
+                self.store.push(task.node(), line);
+            }
+        }
+        if self.inner.enabled(metadata) {
+            self.inner.event(event);
+        }
+    }
+
+    fn enter(&self, span: &span::Id) {
+        self.inner.enter(span)
+    }
+
+    fn exit(&self, span: &span::Id) {
+        self.inner.exit(span)
+    }
+
+    fn clone_span(&self, id: &span::Id) -> span::Id {
+        self.inner.clone_span(id)
+    }
+
+    fn try_close(&self, id: span::Id) -> bool {
+        self.inner.try_close(id)
+    }
+
+    fn current_span(&self) -> tracing_core::span::Current {
+        self.inner.current_span()
+    }
+}
+
+/// Formats the message of an event, and its other fields as ` key=value`.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            write!(self.fields, " {}={value}", field.name()).unwrap();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{value:?}").unwrap();
+        } else {
+            write!(self.fields, " {}={value:?}", field.name()).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, time::sleep, SimConfig};
+    use tracing::{debug, info, warn};
+
+    #[test]
+    fn capture() {
+        let mut config = SimConfig::default();
+        config.logs.enabled = true;
+        config.logs.capacity = 3;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let node1 = runtime.create_node().build();
+        let node2 = runtime.create_node().build();
+
+        node1.spawn(async {
+            info!("fell back to leader election");
+            debug!("not captured");
+        });
+        node2.spawn(async {
+            for i in 0..5 {
+                sleep(Duration::from_secs(1)).await;
+                warn!(attempt = i, "retrying");
+            }
+        });
+
+        runtime.block_on(async {
+            sleep(Duration::from_secs(10)).await;
+        });
+
+        let logs = node1.logs();
+        logs.assert_contains("leader election");
+        logs.assert_not_contains("not captured");
+        assert_eq!(logs.lines().len(), 1);
+        assert_eq!(logs.lines()[0].level, Level::INFO);
+        assert!(logs.lines()[0].target.ends_with("logs::tests"));
+
+        let logs = node2.logs();
+        logs.assert_not_contains("leader election");
+        let lines: Vec<_> = logs.lines().iter().map(|l| l.message.as_str()).collect();
+        assert_eq!(
+            lines,
+            [
+                "retrying attempt=2",
+                "retrying attempt=3",
+                "retrying attempt=4"
+            ]
+        );
+        assert_eq!(logs.lines()[0].time.as_secs_f64().round(), 3.0);
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut config = SimConfig::default();
+        config.logs.enabled = true;
+        config.logs.capacity = 0;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let node = runtime.create_node().build();
+        node.spawn(async { info!("started") });
+        runtime.block_on(async {
+            sleep(Duration::from_secs(1)).await;
+        });
+        assert!(node.logs().lines().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "no log line of node")]
+    fn assert_contains() {
+        let mut config = SimConfig::default();
+        config.logs.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let node = runtime.create_node().build();
+        node.spawn(async { info!("started") });
+        runtime.block_on(async {
+            sleep(Duration::from_secs(1)).await;
+        });
+        node.logs().assert_contains("stopped");
+    }
+}
@@ -7,16 +7,36 @@ pub(crate) use self::runtime::context;
 #[cfg_attr(docsrs, doc(cfg(feature = "macros")))]
 pub use msim_macros::{main, sim_test, test};
 
+pub mod checkpoint;
 pub mod collections;
 mod config;
+pub mod corpus;
+pub mod cron;
+pub mod explore;
+pub mod fault;
 pub mod fs;
+pub mod host;
+pub mod inputs;
 mod intercept;
+pub mod logs;
 pub mod net;
+pub mod node_config;
+pub mod orchestrator;
+pub mod perf;
 #[cfg_attr(docsrs, doc(cfg(msim)))]
 pub mod plugin;
+pub mod profile;
+pub mod progress;
 pub mod rand;
+pub mod report;
+pub mod rollout;
 #[cfg_attr(docsrs, doc(cfg(msim)))]
 pub mod runtime;
+pub mod scenario;
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+pub mod sync;
 pub mod task;
 pub mod time;
+pub mod trace;
 mod utils;
+pub mod workload;
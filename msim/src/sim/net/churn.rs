@@ -0,0 +1,290 @@
+//! Churn of mobile and edge peers.
+//!
+//! Light clients on phones and laptops come and go: they lose connectivity, and come back with
+//! another address handed out by DHCP or a carrier NAT. [`NodeChurn`] drives a node through
+//! such online and offline periods, sampled from seeded distributions, and gives it a new ip
+//! from a pool each time it reconnects. Peers then see it vanish and reappear as a stranger,
+//! which stresses peer-table management: stale entries, duplicate identities, reconnect storms.
+//!
+//! ```ignore
+//! let profile = MobileProfile::new("10.64.0.0".parse().unwrap(), 256)
+//!     .online(LatencyDistribution::uniform(Duration::from_secs(30)..Duration::from_secs(600)))
+//!     .offline(LatencyDistribution::uniform(Duration::from_secs(1)..Duration::from_secs(60)));
+//! let churn = NodeChurn::start(light_client.id(), profile);
+//! ```
+//!
+//! While offline, the node is disconnected from the network, but keeps running. Its sockets
+//! keep their old address, so connections opened before a reconnect are broken: peers reply to
+//! an address that no longer exists. The churn clogs the node on its own, so coming back online
+//! does not lift a [`NetSim::disconnect`] of the test.
+
+use super::{network::PartitionTarget, LatencyDistribution, NetSim};
+use crate::{plugin::simulator, rand::Rng, task::NodeId, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::*;
+
+/// How a mobile node comes and goes, see the [module](self) documentation.
+#[derive(Debug, Clone)]
+pub struct MobileProfile {
+    /// First address of the pool.
+    first_ip: Ipv4Addr,
+    /// Number of addresses in the pool.
+    pool_size: u32,
+    online: LatencyDistribution,
+    offline: LatencyDistribution,
+}
+
+impl MobileProfile {
+    /// A profile taking addresses from the `pool_size` addresses starting at `first_ip`.
+    ///
+    /// By default, the node stays online for a minute, and offline for 10 seconds.
+    pub fn new(first_ip: Ipv4Addr, pool_size: u32) -> Self {
+        assert!(pool_size > 0, "the address pool is empty");
+        MobileProfile {
+            first_ip,
+            pool_size,
+            online: LatencyDistribution::Constant(Duration::from_secs(60)),
+            offline: LatencyDistribution::Constant(Duration::from_secs(10)),
+        }
+    }
+
+    /// Sample the time the node stays online from `online`.
+    pub fn online(mut self, online: LatencyDistribution) -> Self {
+        self.online = online;
+        self
+    }
+
+    /// Sample the time the node stays offline from `offline`.
+    pub fn offline(mut self, offline: LatencyDistribution) -> Self {
+        self.offline = offline;
+        self
+    }
+}
+
+struct ChurnState {
+    node: NodeId,
+    profile: MobileProfile,
+    stopped: AtomicBool,
+    /// Whether the node is offline, i.e. the churn clogs it.
+    offline: AtomicBool,
+    /// The addresses the node had, in order.
+    ips: Mutex<Vec<IpAddr>>,
+    /// The number of times the node came back online.
+    reconnects: AtomicUsize,
+}
+
+/// Handle to a churning node, see the [module](self) documentation.
+///
+/// Dropping the handle stops the churn, and brings the node back online.
+#[must_use = "the node stops churning when the handle is dropped"]
+pub struct NodeChurn {
+    net: Arc<NetSim>,
+    state: Arc<ChurnState>,
+}
+
+impl NodeChurn {
+    /// Start churning `node`, which starts online with its current address, if it has one.
+    pub fn start(node: NodeId, profile: MobileProfile) -> Self {
+        let net = simulator::<NetSim>();
+        let state = Arc::new(ChurnState {
+            node,
+            profile,
+            stopped: AtomicBool::new(false),
+            offline: AtomicBool::new(false),
+            ips: Mutex::new(net.get_ip(node).into_iter().collect()),
+            reconnects: AtomicUsize::new(0),
+        });
+        schedule(net.clone(), state.clone(), true);
+        NodeChurn { net, state }
+    }
+
+    /// The addresses the node had, in order. The last one is its current address.
+    pub fn ips(&self) -> Vec<IpAddr> {
+        self.state.ips.lock().unwrap().clone()
+    }
+
+    /// The number of times the node came back online.
+    pub fn reconnects(&self) -> usize {
+        self.state.reconnects.load(Ordering::SeqCst)
+    }
+
+    /// Stop churning and bring the node back online.
+    pub fn stop(self) {}
+}
+
+impl Drop for NodeChurn {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        let mut network = self.net.lock_network();
+        if self.state.offline.swap(false, Ordering::SeqCst) {
+            network.fault_unclog(PartitionTarget::Node(self.state.node));
+        }
+    }
+}
+
+/// Take the node offline after its online period if `online`, or back online otherwise.
+fn schedule(net: Arc<NetSim>, state: Arc<ChurnState>, online: bool) {
+    let mut rng = net.churn_rand.clone();
+    let dist = if online {
+        &state.profile.online
+    } else {
+        &state.profile.offline
+    };
+    let deadline = net.time.now_instant() + dist.sample(&mut rng);
+    let time = net.time.clone();
+    // run on the main node so that the timer survives restarts of the churning node.
+    time.add_timer_for_node(NodeId::zero(), deadline, move || {
+        if state.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let online = {
+            let mut network = net.lock_network();
+            let node = state.node;
+            if !network.contains_node(node) {
+                return;
+            }
+            if online {
+                debug!("churn: {node} offline");
+                network.fault_clog(PartitionTarget::Node(node));
+                state.offline.store(true, Ordering::SeqCst);
+                false
+            } else {
+                let (first, size) = (u32::from(state.profile.first_ip), state.profile.pool_size);
+                // a random free address of the pool, like a DHCP lease.
+                let start = rng.gen_range(0..size);
+                let free = (0..size)
+                    .map(|i| IpAddr::from(Ipv4Addr::from(first + (start + i) % size)))
+                    .find(|ip| !network.ip_in_use(ip));
+                match free {
+                    Some(ip) => {
+                        debug!("churn: {node} online as {ip}");
+                        network.set_ip(node, ip);
+                        network.fault_unclog(PartitionTarget::Node(node));
+                        state.offline.store(false, Ordering::SeqCst);
+                        state.ips.lock().unwrap().push(ip);
+                        state.reconnects.fetch_add(1, Ordering::SeqCst);
+                        true
+                    }
+                    None => {
+                        warn!("churn: no free address for {node}, staying offline");
+                        false
+                    }
+                }
+            }
+        };
+        schedule(net, state, online);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{network::Payload, Endpoint},
+        runtime::Runtime,
+        time::{sleep, timeout, Instant},
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn churn() {
+        let runtime = Runtime::new();
+        let server = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let server_node = runtime.create_node().ip(server.ip()).build();
+        let phone = runtime
+            .create_node()
+            .ip("10.64.0.100".parse().unwrap())
+            .build();
+        let phone_id = phone.id();
+
+        // the server records where pings come from
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_ = seen.clone();
+        server_node.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, server).await.unwrap();
+            loop {
+                let (_, from) = ep.recv_from_raw(1).await.unwrap();
+                seen_.lock().unwrap().push((Instant::now(), from.ip()));
+            }
+        });
+        // the phone pings every second, from a new socket each time
+        phone.spawn(async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+                let ep = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:0").await.unwrap();
+                let data = Payload::new_udp(Box::new(()));
+                let _ = timeout(Duration::from_secs(1), ep.send_to_raw(server, 1, data)).await;
+            }
+        });
+
+        runtime.block_on(async move {
+            let start = Instant::now();
+            let profile = MobileProfile::new("10.64.0.0".parse().unwrap(), 4)
+                .online(LatencyDistribution::Constant(Duration::from_secs(10)))
+                .offline(LatencyDistribution::Constant(Duration::from_secs(5)));
+            let churn = NodeChurn::start(phone_id, profile);
+            sleep(Duration::from_millis(32500)).await;
+
+            let ips = churn.ips();
+            assert_eq!(ips.len(), 3);
+            assert_eq!(churn.reconnects(), 2);
+            assert_eq!(simulator::<NetSim>().get_ip(phone_id), ips.last().copied());
+            for ip in &ips[1..] {
+                let octets = match ip {
+                    IpAddr::V4(ip) => ip.octets(),
+                    _ => unreachable!(),
+                };
+                assert_eq!(octets[..3], [10, 64, 0]);
+                assert!(octets[3] < 4);
+            }
+            // silent while offline, and seen with the address of the period otherwise
+            let seen = seen.lock().unwrap().clone();
+            for (time, ip) in seen {
+                let secs = (time - start).as_secs_f64();
+                let period = secs as u64 / 15;
+                assert!(secs % 15.0 < 10.1, "ping at {secs}s while offline");
+                assert_eq!(ip, ips[period as usize], "at {secs}s");
+            }
+
+            drop(churn);
+            sleep(Duration::from_secs(60)).await;
+            assert_eq!(simulator::<NetSim>().get_ip(phone_id), ips.last().copied());
+        });
+    }
+
+    #[test]
+    fn user_clog() {
+        let runtime = Runtime::new();
+        let phone = runtime
+            .create_node()
+            .ip("10.64.0.1".parse().unwrap())
+            .build();
+        let phone_id = phone.id();
+
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            let clogged = || net.lock_network().is_clogged(phone_id);
+            let profile = MobileProfile::new("10.64.0.0".parse().unwrap(), 4)
+                .online(LatencyDistribution::Constant(Duration::from_secs(10)))
+                .offline(LatencyDistribution::Constant(Duration::from_secs(5)));
+            let churn = NodeChurn::start(phone_id, profile);
+            sleep(Duration::from_secs(12)).await;
+            assert!(clogged());
+            // the test disconnects the phone while it is offline, which outlasts the churn
+            net.disconnect(phone_id);
+            sleep(Duration::from_secs(5)).await;
+            assert_eq!(churn.reconnects(), 1);
+            assert!(clogged());
+            drop(churn);
+            assert!(clogged());
+            net.connect(phone_id);
+            assert!(!clogged());
+        });
+    }
+}
@@ -4,9 +4,15 @@ use rand::{
     distributions::{Distribution, Uniform},
     seq::SliceRandom,
 };
-use std::{collections::HashMap, ops::Range, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    ops::Range,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{rand::*, task::NodeId};
+use crate::{rand::*, task::NodeId, UnsupportedPolicy};
 
 /// Defines a latency distribution.
 #[derive(Debug, PartialEq, Clone, Hash)]
@@ -62,12 +68,33 @@ impl LatencyDistribution {
             }
         }
     }
+
+    /// The mean latency.
+    pub fn mean(&self) -> Duration {
+        match self {
+            Self::Constant(dur) => *dur,
+            Self::Uniform(range) => (range.start + range.end) / 2,
+            Self::Compound(sub_distributions) => {
+                let total: u32 = sub_distributions.iter().map(|(w, _)| w).sum();
+                let secs: f64 = (sub_distributions.iter())
+                    .map(|(w, dist)| *w as f64 * dist.mean().as_secs_f64())
+                    .sum();
+                Duration::from_secs_f64(secs / total as f64)
+            }
+        }
+    }
 }
 
 /// Trait for defining latency between two nodes
 pub trait InterNodeLatency {
     /// Get the latency between a and b
     fn sample(&self, rng: &mut GlobalRng, a: NodeId, b: NodeId) -> Option<Duration>;
+
+    /// Get the mean latency between a and b, e.g. to route to the closest member of an anycast
+    /// address. The default latency is assumed if `None`.
+    fn mean(&self, _a: NodeId, _b: NodeId) -> Option<Duration> {
+        None
+    }
 }
 
 impl std::fmt::Debug for dyn InterNodeLatency + Send + Sync + 'static {
@@ -93,6 +120,11 @@ impl InterNodeLatency for InterNodeLatencyMap {
 
         None
     }
+
+    fn mean(&self, a: NodeId, b: NodeId) -> Option<Duration> {
+        let dist = self.0.get(&(a, b)).or_else(|| self.0.get(&(b, a)));
+        dist.map(LatencyDistribution::mean)
+    }
 }
 
 /// Defines latency from a given node to anywhere else. If a and b are
@@ -112,6 +144,11 @@ impl InterNodeLatency for NodeLatencyMap {
             (None, None) => None,
         }
     }
+
+    fn mean(&self, a: NodeId, b: NodeId) -> Option<Duration> {
+        let means = [a, b].map(|id| self.0.get(&id).map(LatencyDistribution::mean));
+        means.into_iter().flatten().max()
+    }
 }
 
 /// Latency configuration
@@ -143,6 +180,15 @@ impl LatencyConfig {
                 .unwrap()
         }
     }
+
+    /// Get the mean latency between two nodes.
+    pub fn mean_latency(&self, a: NodeId, b: NodeId) -> Duration {
+        if a == b {
+            return self.loopback_latency.mean();
+        }
+        let mean = (self.inter_node_latency.as_ref()).and_then(|lat| lat.mean(a, b));
+        mean.unwrap_or_else(|| self.default_latency.mean())
+    }
 }
 
 impl Default for LatencyConfig {
@@ -215,6 +261,189 @@ impl PacketLossConfig {
     }
 }
 
+/// Returns an `InvalidData` error unless `p`, the value of `name`, is a probability.
+pub(crate) fn check_probability(name: &str, p: f64) -> io::Result<()> {
+    if (0.0..=1.0).contains(&p) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid {name}: {p} is not a probability"),
+        ))
+    }
+}
+
+/// A two-state Gilbert–Elliott model of bursty packet loss.
+///
+/// A link is either in the good or in the bad state, and loses packets with a different
+/// probability in each. Before every packet, the link moves to the other state with the
+/// probability of the transition, so the time spent in the bad state forms loss bursts of
+/// geometrically distributed length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GilbertElliott {
+    /// Probability of moving from the good to the bad state before a packet.
+    pub p_good_to_bad: f64,
+    /// Probability of moving from the bad to the good state before a packet.
+    pub p_bad_to_good: f64,
+    /// Loss probability in the good state.
+    pub loss_good: f64,
+    /// Loss probability in the bad state.
+    pub loss_bad: f64,
+}
+
+impl GilbertElliott {
+    /// Create a model that loses no packets in the good state and `loss_bad` of the packets in
+    /// the bad state (the Gilbert model, if `loss_bad` is 1).
+    pub fn new(p_good_to_bad: f64, p_bad_to_good: f64, loss_bad: f64) -> Self {
+        for p in [p_good_to_bad, p_bad_to_good, loss_bad] {
+            assert!((0.0..=1.0).contains(&p), "invalid probability: {p}");
+        }
+        Self {
+            p_good_to_bad,
+            p_bad_to_good,
+            loss_good: 0.0,
+            loss_bad,
+        }
+    }
+
+    /// Returns an `InvalidData` error unless all the parameters are probabilities.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        check_probability("p_good_to_bad", self.p_good_to_bad)?;
+        check_probability("p_bad_to_good", self.p_bad_to_good)?;
+        check_probability("loss_good", self.loss_good)?;
+        check_probability("loss_bad", self.loss_bad)
+    }
+
+    /// The long-run fraction of packets lost.
+    pub fn mean_loss_rate(&self) -> f64 {
+        let transitions = self.p_good_to_bad + self.p_bad_to_good;
+        if transitions == 0.0 {
+            return self.loss_good;
+        }
+        let bad = self.p_good_to_bad / transitions;
+        (1.0 - bad) * self.loss_good + bad * self.loss_bad
+    }
+
+    /// The mean number of consecutive packets sent in the bad state.
+    pub fn mean_burst_length(&self) -> f64 {
+        1.0 / self.p_bad_to_good
+    }
+
+    /// Advance the state of a link before a packet, and return whether the packet is lost.
+    pub(crate) fn sample(&self, rng: &mut GlobalRng, bad: &mut bool) -> bool {
+        let p_switch = if *bad {
+            self.p_bad_to_good
+        } else {
+            self.p_good_to_bad
+        };
+        if rng.gen_bool(p_switch) {
+            *bad = !*bad;
+        }
+        rng.gen_bool(if *bad { self.loss_bad } else { self.loss_good })
+    }
+}
+
+/// Degraded network behavior applied to all traffic sent or received by a node.
+///
+/// Unlike a clean partition, a degraded node stays reachable but is slow and unreliable, see
+/// [`NetSim::degrade_node`](crate::net::NetSim::degrade_node).
+#[derive(Debug, Clone)]
+pub struct Degradation {
+    /// Extra latency added to every packet.
+    pub extra_latency: LatencyDistribution,
+
+    /// Probability of dropping a udp packet. Tcp packets are not dropped, as that would reset the
+    /// connection.
+    pub packet_loss_rate: f64,
+
+    /// Probability of stalling a packet, i.e. delaying it by a sample of `stall_latency`, which
+    /// typically causes the peer to time out.
+    pub stall_rate: f64,
+
+    /// Latency of a stalled packet.
+    pub stall_latency: LatencyDistribution,
+}
+
+impl Default for Degradation {
+    fn default() -> Self {
+        Self {
+            extra_latency: LatencyDistribution::Constant(Duration::ZERO),
+            packet_loss_rate: 0.0,
+            stall_rate: 0.0,
+            stall_latency: LatencyDistribution::Constant(Duration::ZERO),
+        }
+    }
+}
+
+/// Faults applied to the messages with a tag in a range, see
+/// [`NetSim::set_tag_fault`](crate::net::NetSim::set_tag_fault).
+///
+/// This degrades one protocol while keeping the others healthy, e.g. slowing down consensus
+/// messages without affecting state sync.
+#[derive(Debug, Clone)]
+pub struct TagFault {
+    /// Extra latency added to every message.
+    pub extra_latency: LatencyDistribution,
+
+    /// Probability of dropping a udp message. Tcp messages are not dropped, as that would reset
+    /// the connection.
+    pub packet_loss_rate: f64,
+
+    /// Probability of delivering a copy of a message, a sample of `duplicate_delay` after the
+    /// original. Only byte payloads are duplicated.
+    pub duplicate_rate: f64,
+
+    /// Delay of a copy after the original.
+    pub duplicate_delay: LatencyDistribution,
+}
+
+impl TagFault {
+    /// Returns an `InvalidData` error unless the rates are probabilities.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        check_probability("packet_loss_rate", self.packet_loss_rate)?;
+        check_probability("duplicate_rate", self.duplicate_rate)
+    }
+}
+
+impl Default for TagFault {
+    fn default() -> Self {
+        Self {
+            extra_latency: LatencyDistribution::Constant(Duration::ZERO),
+            packet_loss_rate: 0.0,
+            duplicate_rate: 0.0,
+            duplicate_delay: LatencyDistribution::Constant(Duration::ZERO),
+        }
+    }
+}
+
+/// Overrides of the delivery of a single message, see
+/// [`Endpoint::send_to_with`](crate::net::Endpoint::send_to_with).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryOverride {
+    /// Deliver the message after this latency, instead of a sampled latency. Extra latency, e.g.
+    /// from degraded nodes, is still added.
+    pub latency: Option<Duration>,
+
+    /// Drop the message, as if it was lost. Random packet loss still applies to messages that
+    /// are not force dropped.
+    pub force_drop: bool,
+
+    /// Deliver a copy of the message this long after the original. Payloads of
+    /// [`PayloadData::Any`](crate::net::PayloadData::Any) cannot be duplicated.
+    pub duplicate: Option<Duration>,
+}
+
+/// A wide area link between two clusters, see
+/// [`NetSim::add_cluster`](crate::net::NetSim::add_cluster).
+#[derive(Debug, Clone)]
+pub struct WanLink {
+    /// Latency of every packet crossing the link.
+    pub latency: LatencyDistribution,
+
+    /// Probability of dropping a udp packet crossing the link.
+    pub packet_loss_rate: f64,
+}
+
 /// Network configurations.
 #[cfg_attr(docsrs, doc(cfg(msim)))]
 #[derive(Debug, Clone, Default)]
@@ -225,6 +454,356 @@ pub struct NetworkConfig {
     /// Packet loss for tcp causes the connection to be reset, hence has a different config.
     pub tcp_packet_loss: PacketLossConfig,
 
+    /// Bursty udp packet loss applied to every link, instead of the independent loss of
+    /// `packet_loss`. Each direction of each link has its own state. See also
+    /// [`NetSim::set_one_way_loss_model`](crate::net::NetSim::set_one_way_loss_model).
+    pub loss_model: Option<GilbertElliott>,
+
     /// Latency configuraion
     pub latency: LatencyConfig,
+
+    /// Order in which messages from different senders, queued at the same socket, are received.
+    pub delivery_order: DeliveryOrder,
+
+    /// Attach a checksum to every byte payload when it is sent, and verify it on delivery and
+    /// when it is copied out by intercepted socket calls. A mismatch panics with the metadata of
+    /// the message, to catch buffer handling bugs in the simulator.
+    pub checksum: bool,
+
+    /// Limit the flows whose statistics are kept, see
+    /// [`NetSim::flows`](crate::net::NetSim::flows). Once the limit is reached, the statistics of
+    /// the flows with no packets in flight are dropped to make room for new flows. `None` is
+    /// 65536 flows.
+    pub flow_limit: Option<usize>,
+
+    /// Limit the egress bandwidth of every node. Bandwidth is unlimited if `None`.
+    pub bandwidth: Option<BandwidthConfig>,
+
+    /// Compress the messages sent to other nodes. Messages are sent uncompressed if `None`.
+    pub compression: Option<CompressionConfig>,
+
+    /// Limit the udp messages queued at every socket. Queues are unbounded if `None`.
+    pub recv_buffer: Option<RecvBufferConfig>,
+
+    /// Drop the connection requests that arrive at a listener whose accept queue already holds
+    /// as many connections as the backlog passed to `listen()`, as a kernel drops the SYNs it
+    /// has no room for. The connecting end times out, or is refused if it connects with the
+    /// intercepted `connect()`. The accept queue is unbounded if `false`.
+    pub listen_backlog: bool,
+
+    /// How long [`Endpoint::connect`](crate::net::Endpoint::connect) retransmits a tcp
+    /// connection request that gets no answer, because the destination is partitioned or gone,
+    /// before failing with `TimedOut`, and how long an intercepted `connect()` blocks before
+    /// failing with `ETIMEDOUT`. `None` is 127 seconds, the default of Linux.
+    pub connect_timeout: Option<Duration>,
+
+    /// How `setsockopt()` and `getsockopt()` handle the socket options the simulator does not
+    /// know. Strict policies reveal exactly which socket features a stack depends on.
+    pub unknown_sockopt: SockoptPolicy,
+
+    /// Policies for individual unknown socket options, by level and name, overriding
+    /// `unknown_sockopt`. E.g. fail on a single option, or allow a few in a strict test.
+    pub sockopt_policies: BTreeMap<(libc::c_int, libc::c_int), SockoptPolicy>,
+}
+
+impl NetworkConfig {
+    /// The policy for the unknown socket option `name` at `level`.
+    pub fn sockopt_policy(&self, level: libc::c_int, name: libc::c_int) -> SockoptPolicy {
+        let policy = self.sockopt_policies.get(&(level, name));
+        policy.copied().unwrap_or(self.unknown_sockopt)
+    }
+}
+
+/// How an intercepted socket call handles a socket option the simulator does not know.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SockoptPolicy {
+    /// Log a warning and report success, without emulating the option.
+    #[default]
+    Warn,
+    /// Accept the option silently, without emulating it.
+    Ignore,
+    /// Report the option as an [unsupported call](crate::Unsupported) with the given policy:
+    /// panic under [`UnsupportedPolicy::Strict`], or fail with `ENOPROTOOPT` as a kernel that
+    /// does not support the option under [`UnsupportedPolicy::Lenient`].
+    Unsupported(UnsupportedPolicy),
+}
+
+/// Egress bandwidth of every node, with priority lanes for classes of messages.
+///
+/// Each node sends its messages to other nodes one at a time, taking `size / bytes_per_sec` per
+/// message, and each message then travels with the latency of its link. Messages waiting to be
+/// sent are queued in lanes by tag: a message is only sent once the lanes before its own are
+/// empty, so that control messages overtake queued bulk transfers. The message being sent is not
+/// interrupted. Without lanes, all messages share a single first-in first-out queue, and a
+/// large state sync delays everything sent after it.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandwidthConfig {
+    /// Egress bandwidth of every node, in bytes per second.
+    pub bytes_per_sec: u64,
+
+    /// Tag ranges of the priority lanes, highest priority first. Messages with a tag in none of
+    /// the ranges are queued in a last, lowest priority lane.
+    pub lanes: Vec<Range<u64>>,
+}
+
+impl BandwidthConfig {
+    /// Limit the egress bandwidth to `bytes_per_sec`, with a single lane.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "bandwidth must be positive");
+        Self {
+            bytes_per_sec,
+            lanes: Vec::new(),
+        }
+    }
+
+    /// Add a lane for the messages with a tag in `tags`, with a lower priority than the lanes
+    /// added before.
+    pub fn lane(mut self, tags: Range<u64>) -> Self {
+        self.lanes.push(tags);
+        self
+    }
+
+    /// The index of the lane of a message, lower is higher priority.
+    pub(crate) fn lane_of(&self, tag: u64) -> usize {
+        let lane = self.lanes.iter().position(|tags| tags.contains(&tag));
+        lane.unwrap_or(self.lanes.len())
+    }
+
+    /// The time taken to send a message of `size` bytes.
+    pub(crate) fn transmit_time(&self, size: u64) -> Duration {
+        Duration::from_secs_f64(size as f64 / self.bytes_per_sec as f64)
+    }
+}
+
+/// Compression of the messages sent to other nodes, see [`NetworkConfig::compression`].
+///
+/// A compressed message takes `ratio` of its size on the wire, which shortens its transmission
+/// under a [`BandwidthConfig`], and is delayed by the CPU time to compress it at the sender and
+/// decompress it at the receiver, charged per byte of the uncompressed message. Payloads are not
+/// actually compressed. Compressed messages are counted in [`Stat`](crate::net::Stat).
+///
+/// ```
+/// use msim::net::CompressionConfig;
+///
+/// // halve blocks and state syncs, at 4ns per byte to compress and 1ns per byte to decompress.
+/// let config = CompressionConfig::new(0.5)
+///     .cost(4.0, 1.0)
+///     .min_size(1024)
+///     .tags(0x200..0x400);
+/// ```
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    /// The size of a compressed message relative to its size, between 0 and 1.
+    pub ratio: f64,
+
+    /// CPU time to compress a byte, in nanoseconds.
+    pub compress_ns_per_byte: f64,
+
+    /// CPU time to decompress a byte, in nanoseconds.
+    pub decompress_ns_per_byte: f64,
+
+    /// Messages smaller than `min_size` bytes are sent uncompressed.
+    pub min_size: u64,
+
+    /// Tag ranges of the compressed messages. All messages are compressed if empty.
+    pub tags: Vec<Range<u64>>,
+}
+
+impl CompressionConfig {
+    /// Compress all messages to `ratio` of their size, without CPU cost.
+    pub fn new(ratio: f64) -> Self {
+        assert!(ratio > 0.0 && ratio <= 1.0, "invalid ratio: {ratio}");
+        Self {
+            ratio,
+            compress_ns_per_byte: 0.0,
+            decompress_ns_per_byte: 0.0,
+            min_size: 0,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Set the CPU time to compress and to decompress a byte, in nanoseconds.
+    pub fn cost(mut self, compress_ns_per_byte: f64, decompress_ns_per_byte: f64) -> Self {
+        assert!(
+            compress_ns_per_byte >= 0.0 && decompress_ns_per_byte >= 0.0,
+            "cost must not be negative"
+        );
+        self.compress_ns_per_byte = compress_ns_per_byte;
+        self.decompress_ns_per_byte = decompress_ns_per_byte;
+        self
+    }
+
+    /// Send the messages smaller than `bytes` uncompressed.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Compress the messages with a tag in `tags`, in addition to the ranges added before.
+    pub fn tags(mut self, tags: Range<u64>) -> Self {
+        self.tags.push(tags);
+        self
+    }
+
+    /// The size on the wire and the CPU time of a message of `size` bytes, if it is compressed.
+    pub(crate) fn compress(&self, tag: u64, size: u64) -> Option<(u64, Duration)> {
+        let compressed = size > 0
+            && size >= self.min_size
+            && (self.tags.is_empty() || self.tags.iter().any(|tags| tags.contains(&tag)));
+        if !compressed {
+            return None;
+        }
+        let wire_size = (size as f64 * self.ratio).ceil() as u64;
+        let ns = size as f64 * (self.compress_ns_per_byte + self.decompress_ns_per_byte);
+        Some((wire_size, Duration::from_nanos(ns.round() as u64)))
+    }
+}
+
+/// The receive buffer of sockets, see [`NetworkConfig::recv_buffer`].
+///
+/// A socket holds at most `bytes` of udp messages that arrived but were not received yet.
+/// Messages are sorted into drop precedence classes by their tag, and the messages of a class
+/// are dropped once the buffer is filled past the share of the class, so that under overload
+/// low priority traffic, e.g. gossip, is shed before critical traffic, e.g. consensus votes,
+/// which can still use the rest of the buffer. Drops are counted per class, see
+/// [`NetSim::recv_buffer_drops`](crate::net::NetSim::recv_buffer_drops).
+///
+/// Tcp messages are never dropped, as tcp relies on flow control instead.
+///
+/// ```
+/// use msim::net::RecvBufferConfig;
+///
+/// // votes may fill the whole buffer, blocks 3/4 of it, and everything else half of it.
+/// let config = RecvBufferConfig::new(1 << 20)
+///     .class(0x100..0x200, 1.0)
+///     .class(0x200..0x300, 0.75)
+///     .default_share(0.5);
+/// ```
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecvBufferConfig {
+    /// The size of the buffer of each socket, in bytes.
+    pub bytes: u64,
+
+    /// Tag ranges of the drop precedence classes, with the share of the buffer messages of the
+    /// class may fill, between 0 and 1. A message is in the first class whose range contains its
+    /// tag.
+    pub classes: Vec<(Range<u64>, f64)>,
+
+    /// The share of the buffer messages in none of the classes may fill. They form the last
+    /// class.
+    pub default_share: f64,
+}
+
+impl RecvBufferConfig {
+    /// A buffer of `bytes`, which all messages may fill.
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            bytes,
+            classes: Vec::new(),
+            default_share: 1.0,
+        }
+    }
+
+    /// Add a class for the messages with a tag in `tags`, which may fill `share` of the buffer.
+    pub fn class(mut self, tags: Range<u64>, share: f64) -> Self {
+        assert!((0.0..=1.0).contains(&share), "invalid share: {share}");
+        self.classes.push((tags, share));
+        self
+    }
+
+    /// Set the share of the buffer messages in none of the classes may fill.
+    pub fn default_share(mut self, share: f64) -> Self {
+        assert!((0.0..=1.0).contains(&share), "invalid share: {share}");
+        self.default_share = share;
+        self
+    }
+
+    /// The index of the class of a message, and the bytes messages of the class may fill.
+    pub(crate) fn class_of(&self, tag: u64) -> (usize, u64) {
+        let class = self
+            .classes
+            .iter()
+            .position(|(tags, _)| tags.contains(&tag));
+        let share = class.map_or(self.default_share, |i| self.classes[i].1);
+        let class = class.unwrap_or(self.classes.len());
+        (class, (self.bytes as f64 * share) as u64)
+    }
+}
+
+/// A cap on the traffic a node sends, see
+/// [`NetSim::police_egress`](crate::net::NetSim::police_egress).
+///
+/// Unlike [`BandwidthConfig`], which queues messages while the link is busy, a policer lets
+/// traffic through at full speed until it exceeds the cap, like the rate limits of a cloud
+/// provider or an OS traffic policer. The cap is enforced by a token bucket of `burst` units,
+/// refilled at `rate` units per second. Udp packets that find the bucket empty are dropped, and
+/// tcp messages are held back until the bucket refills, as retransmissions would.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EgressPolicy {
+    /// What the bucket counts.
+    pub unit: PolicyUnit,
+    /// Units per second the node may send.
+    pub rate: u64,
+    /// Units the node may send at once, after being idle.
+    pub burst: u64,
+}
+
+/// What an [`EgressPolicy`] counts.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyUnit {
+    /// Messages, whatever their size.
+    Messages,
+    /// Bytes. A udp packet larger than the burst is always dropped.
+    Bytes,
+}
+
+impl EgressPolicy {
+    /// Allow `rate` messages per second, and bursts of `burst` messages.
+    pub fn messages(rate: u64, burst: u64) -> Self {
+        Self::new(PolicyUnit::Messages, rate, burst)
+    }
+
+    /// Allow `rate` bytes per second, and bursts of `burst` bytes.
+    pub fn bytes(rate: u64, burst: u64) -> Self {
+        Self::new(PolicyUnit::Bytes, rate, burst)
+    }
+
+    fn new(unit: PolicyUnit, rate: u64, burst: u64) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        assert!(burst > 0, "burst must be positive");
+        Self { unit, rate, burst }
+    }
+
+    /// The units taken by a message of `size` bytes.
+    pub(crate) fn cost(&self, size: u64) -> f64 {
+        match self.unit {
+            PolicyUnit::Messages => 1.0,
+            PolicyUnit::Bytes => size as f64,
+        }
+    }
+}
+
+/// Order in which messages queued at a socket are received, when they come from several senders.
+///
+/// This only matters when messages arrive faster than they are received, so that several of them
+/// are queued at the receiving socket.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeliveryOrder {
+    /// Queued messages are received in an arbitrary (but deterministic) order.
+    #[default]
+    Arbitrary,
+    /// Senders are served round-robin: the next message comes from the sender that was served
+    /// least recently. Messages from the same sender are received in arrival order.
+    Fair,
+    /// Adversarial: the socket keeps receiving from the sender it last received from for as long
+    /// as that sender has queued messages, then switches to the sender with the largest backlog.
+    /// A sender that keeps its backlog non-empty starves all others.
+    Unfair,
 }
@@ -0,0 +1,488 @@
+//! A simulated service discovery service.
+//!
+//! [`DiscoveryServer`] keeps a registry of `name -> SocketAddr` entries with a time to live, and
+//! serves it over the simulated network, so requests are subject to latency, packet loss and
+//! partitions like any other traffic. [`DiscoveryClient`] registers and looks up entries.
+//!
+//! Staleness can be injected with [`DiscoveryServer::set_staleness`]: lookups then return the
+//! registry as it was some time ago, including entries that have since expired or changed.
+//!
+//! [`CachingResolver`] caches the answers of a client on a node, like the stub resolver of a
+//! host: entries are kept for the rest of their time to live, negative answers for a fixed
+//! time, and expired entries can be served while the server is unreachable. Changing a
+//! registration is then only seen by the node once its cached entry expires, as with a DNS
+//! flip.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::net::discovery::{DiscoveryClient, DiscoveryServer};
+//!
+//! let server = DiscoveryServer::start(&handle, "10.0.0.100".parse().unwrap());
+//! let addr = server.addr();
+//!
+//! // on a node
+//! let client = DiscoveryClient::new(addr).await?;
+//! client.register("db", "10.0.0.1:5432".parse()?, Duration::from_secs(30)).await?;
+//! let db = client.lookup("db").await?;
+//! ```
+
+use super::{rpc, Endpoint};
+use crate::{
+    runtime::Handle,
+    time::{Duration, Instant},
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tracing::*;
+
+/// The port the discovery service listens on.
+pub const DISCOVERY_PORT: u16 = 53;
+
+/// Tag of requests sent to the server.
+const REQUEST_TAG: u64 = 0xd15c_0000_0000_0000;
+
+#[derive(Debug)]
+enum Request {
+    Register {
+        name: String,
+        addr: SocketAddr,
+        ttl: Duration,
+    },
+    Deregister {
+        name: String,
+    },
+    Lookup {
+        name: String,
+    },
+}
+
+#[derive(Debug)]
+enum Response {
+    Ok,
+    /// The address and remaining time to live of the entry.
+    Lookup(Option<(SocketAddr, Duration)>),
+}
+
+/// A registration, kept after it expires so that stale views can be served.
+#[derive(Debug, Clone)]
+struct Record {
+    addr: Option<SocketAddr>,
+    registered_at: Instant,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Registry {
+    records: HashMap<String, Vec<Record>>,
+    staleness: Duration,
+}
+
+impl Registry {
+    /// Look up `name` as the registry was at time `at`, with the time to live it had left.
+    fn lookup_at(&self, name: &str, at: Instant) -> Option<(SocketAddr, Duration)> {
+        let record = self
+            .records
+            .get(name)?
+            .iter()
+            .rev()
+            .find(|r| r.registered_at <= at)?;
+        // deregistrations have neither an address nor an expiry.
+        let (addr, expires_at) = (record.addr?, record.expires_at?);
+        (expires_at > at).then(|| (addr, expires_at - at))
+    }
+
+    fn push(&mut self, name: String, record: Record) {
+        self.records.entry(name).or_default().push(record);
+    }
+}
+
+/// The discovery server.
+///
+/// The registry is shared by all clones of the server and survives restarts of the node it runs
+/// on.
+#[derive(Clone)]
+pub struct DiscoveryServer {
+    addr: SocketAddr,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl DiscoveryServer {
+    /// Create a server listening on `ip`. Call [`DiscoveryServer::serve`] on the node owning the
+    /// ip to start serving requests.
+    pub fn new(ip: IpAddr) -> Self {
+        DiscoveryServer {
+            addr: SocketAddr::new(ip, DISCOVERY_PORT),
+            registry: Default::default(),
+        }
+    }
+
+    /// Create a node named "discovery" with the given ip, serving the registry.
+    pub fn start(handle: &Handle, ip: IpAddr) -> Self {
+        let server = Self::new(ip);
+        let server_ = server.clone();
+        handle
+            .create_node()
+            .name("discovery")
+            .ip(ip)
+            .init(move || {
+                let server = server_.clone();
+                async move {
+                    if let Err(e) = server.serve().await {
+                        error!("discovery server failed: {e}");
+                    }
+                }
+            })
+            .build();
+        server
+    }
+
+    /// The address of the server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Serve requests until the node is killed.
+    pub async fn serve(&self) -> io::Result<()> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, self.addr).await?;
+        rpc::serve(&ep, REQUEST_TAG, "discovery request", |_, request| {
+            self.handle(request)
+        })
+        .await
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let now = Instant::now();
+        let mut registry = self.registry.lock().unwrap();
+        match request {
+            Request::Register { name, addr, ttl } => {
+                let record = Record {
+                    addr: Some(addr),
+                    registered_at: now,
+                    expires_at: Some(now + ttl),
+                };
+                registry.push(name, record);
+                Response::Ok
+            }
+            Request::Deregister { name } => {
+                let record = Record {
+                    addr: None,
+                    registered_at: now,
+                    expires_at: None,
+                };
+                registry.push(name, record);
+                Response::Ok
+            }
+            Request::Lookup { name } => {
+                let at = now.checked_sub(registry.staleness).unwrap_or(now);
+                Response::Lookup(registry.lookup_at(&name, at))
+            }
+        }
+    }
+
+    /// Serve lookups from the registry as it was `staleness` ago.
+    ///
+    /// Set to zero to serve fresh results again.
+    pub fn set_staleness(&self, staleness: Duration) {
+        self.registry.lock().unwrap().staleness = staleness;
+    }
+
+    /// Look up `name` directly in the registry, bypassing the network and staleness.
+    pub fn get(&self, name: &str) -> Option<SocketAddr> {
+        let registry = self.registry.lock().unwrap();
+        let (addr, _) = registry.lookup_at(name, Instant::now())?;
+        Some(addr)
+    }
+}
+
+/// A client of the discovery service.
+pub struct DiscoveryClient {
+    ep: Endpoint,
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl DiscoveryClient {
+    /// Create a client of the server at `server`.
+    pub async fn new(server: SocketAddr) -> io::Result<Self> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:0").await?;
+        Ok(DiscoveryClient {
+            ep,
+            server,
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    /// Set the timeout of requests. Defaults to one second.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Register `name` at `addr` for `ttl`.
+    pub async fn register(&self, name: &str, addr: SocketAddr, ttl: Duration) -> io::Result<()> {
+        let request = Request::Register {
+            name: name.into(),
+            addr,
+            ttl,
+        };
+        self.call(request).await.map(|_| ())
+    }
+
+    /// Remove the registration of `name`.
+    pub async fn deregister(&self, name: &str) -> io::Result<()> {
+        let request = Request::Deregister { name: name.into() };
+        self.call(request).await.map(|_| ())
+    }
+
+    /// Look up the address registered for `name`.
+    pub async fn lookup(&self, name: &str) -> io::Result<Option<SocketAddr>> {
+        let entry = self.lookup_with_ttl(name).await?;
+        Ok(entry.map(|(addr, _)| addr))
+    }
+
+    /// Look up the address registered for `name`, and the time to live its registration has
+    /// left.
+    pub async fn lookup_with_ttl(&self, name: &str) -> io::Result<Option<(SocketAddr, Duration)>> {
+        let request = Request::Lookup { name: name.into() };
+        match self.call(request).await? {
+            Response::Lookup(entry) => Ok(entry),
+            Response::Ok => unreachable!("unexpected discovery response"),
+        }
+    }
+
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        rpc::call(
+            &self.ep,
+            self.server,
+            REQUEST_TAG,
+            request,
+            self.timeout,
+            "discovery request",
+        )
+        .await
+    }
+}
+
+/// Configuration of a [`CachingResolver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// How long to cache that a name is not registered. Defaults to 5 seconds.
+    pub negative_ttl: Duration,
+    /// Cap on the time to live of cached entries.
+    pub max_ttl: Option<Duration>,
+    /// How long after expiring an entry may still be served, when the server cannot be reached.
+    /// Stale entries are not served by default.
+    pub serve_stale: Option<Duration>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            negative_ttl: Duration::from_secs(5),
+            max_ttl: None,
+            serve_stale: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    addr: Option<SocketAddr>,
+    expires_at: Instant,
+}
+
+/// A resolver caching the answers of a [`DiscoveryClient`] in virtual time, see the
+/// [module](self) documentation.
+///
+/// The cache lives in the memory of the node, and is lost when the node restarts.
+pub struct CachingResolver {
+    client: DiscoveryClient,
+    config: ResolverConfig,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl CachingResolver {
+    /// Create a resolver sending the lookups it cannot answer from its cache to `client`.
+    pub fn new(client: DiscoveryClient, config: ResolverConfig) -> Self {
+        CachingResolver {
+            client,
+            config,
+            cache: Default::default(),
+        }
+    }
+
+    /// Look up the address registered for `name`.
+    ///
+    /// Fails if the entry is not cached and the server cannot be reached, unless an expired
+    /// entry may be served, see [`ResolverConfig::serve_stale`].
+    pub async fn lookup(&self, name: &str) -> io::Result<Option<SocketAddr>> {
+        let now = Instant::now();
+        let cached = self.cache.lock().unwrap().get(name).copied();
+        if let Some(entry) = cached.filter(|entry| entry.expires_at > now) {
+            trace!("resolver: cache hit for {name}: {:?}", entry.addr);
+            return Ok(entry.addr);
+        }
+        match self.client.lookup_with_ttl(name).await {
+            Ok(answer) => {
+                let ttl = match answer {
+                    Some((_, ttl)) => self.config.max_ttl.map_or(ttl, |max| ttl.min(max)),
+                    None => self.config.negative_ttl,
+                };
+                let addr = answer.map(|(addr, _)| addr);
+                let entry = CacheEntry {
+                    addr,
+                    expires_at: Instant::now() + ttl,
+                };
+                self.cache.lock().unwrap().insert(name.into(), entry);
+                Ok(addr)
+            }
+            Err(e) => {
+                let stale = cached.filter(|entry| {
+                    self.config
+                        .serve_stale
+                        .is_some_and(|window| Instant::now() < entry.expires_at + window)
+                });
+                match stale {
+                    Some(entry) => {
+                        debug!("resolver: serving stale entry for {name} after error: {e}");
+                        Ok(entry.addr)
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Drop all cached entries.
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{net::NetSim, plugin::simulator, runtime::Runtime, time::sleep};
+
+    #[test]
+    fn register_lookup() {
+        let runtime = Runtime::new();
+        let server = DiscoveryServer::start(runtime.handle(), "10.0.0.100".parse().unwrap());
+        let addr = server.addr();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let db = "10.0.0.2:5432".parse::<SocketAddr>().unwrap();
+        let db2 = "10.0.0.3:5432".parse::<SocketAddr>().unwrap();
+
+        let f = node.spawn(async move {
+            // wait for the server to start
+            sleep(Duration::from_millis(1)).await;
+            let client = DiscoveryClient::new(addr).await.unwrap();
+            assert_eq!(client.lookup("db").await.unwrap(), None);
+
+            client
+                .register("db", db, Duration::from_secs(10))
+                .await
+                .unwrap();
+            assert_eq!(client.lookup("db").await.unwrap(), Some(db));
+
+            // entries expire
+            sleep(Duration::from_secs(11)).await;
+            assert_eq!(client.lookup("db").await.unwrap(), None);
+
+            client
+                .register("db", db2, Duration::from_secs(60))
+                .await
+                .unwrap();
+            sleep(Duration::from_secs(1)).await;
+            client.deregister("db").await.unwrap();
+            assert_eq!(client.lookup("db").await.unwrap(), None);
+        });
+        runtime.block_on(f).unwrap();
+
+        // a stale view from before the deregistration still sees db2
+        server.set_staleness(Duration::from_millis(500));
+        let f = node.spawn(async move {
+            let client = DiscoveryClient::new(addr).await.unwrap();
+            assert_eq!(client.lookup("db").await.unwrap(), Some(db2));
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn partition() {
+        let runtime = Runtime::new();
+        let server = DiscoveryServer::start(runtime.handle(), "10.0.0.100".parse().unwrap());
+        let addr = server.addr();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let id = node.id();
+
+        let f = node.spawn(async move {
+            let client = DiscoveryClient::new(addr).await.unwrap();
+            let err = client.lookup("db").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+        runtime.block_on(async move {
+            simulator::<NetSim>().disconnect(id);
+            f.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn caching_resolver() {
+        let runtime = Runtime::new();
+        let server = DiscoveryServer::start(runtime.handle(), "10.0.0.100".parse().unwrap());
+        let addr = server.addr();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let id = node.id();
+        let db = "10.0.0.2:5432".parse::<SocketAddr>().unwrap();
+        let db2 = "10.0.0.3:5432".parse::<SocketAddr>().unwrap();
+
+        let f = node.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let admin = DiscoveryClient::new(addr).await.unwrap();
+            let config = ResolverConfig {
+                serve_stale: Some(Duration::from_secs(30)),
+                ..Default::default()
+            };
+            let resolver = CachingResolver::new(DiscoveryClient::new(addr).await.unwrap(), config);
+
+            // negative answers are cached for `negative_ttl`
+            assert_eq!(resolver.lookup("db").await.unwrap(), None);
+            let ttl = Duration::from_secs(10);
+            admin.register("db", db, ttl).await.unwrap();
+            assert_eq!(resolver.lookup("db").await.unwrap(), None);
+            sleep(Duration::from_secs(5)).await;
+            assert_eq!(resolver.lookup("db").await.unwrap(), Some(db));
+
+            // a flip is seen once the cached entry expires
+            admin.register("db", db2, ttl).await.unwrap();
+            sleep(Duration::from_secs(4)).await;
+            assert_eq!(resolver.lookup("db").await.unwrap(), Some(db));
+            sleep(Duration::from_secs(2)).await;
+            assert_eq!(resolver.lookup("db").await.unwrap(), Some(db2));
+            resolver.flush();
+
+            // expired entries are served while the server is unreachable
+            assert_eq!(resolver.lookup("db").await.unwrap(), Some(db2));
+            simulator::<NetSim>().disconnect(id);
+            sleep(Duration::from_secs(20)).await;
+            assert_eq!(resolver.lookup("db").await.unwrap(), Some(db2));
+            sleep(Duration::from_secs(30)).await;
+            resolver.lookup("db").await.unwrap_err();
+        });
+        runtime.block_on(f).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Causes of simulated network failures.
+
+use std::{fmt, io, net::SocketAddr};
+
+/// The cause of a simulated network failure.
+///
+/// The network returns these errors as [`io::Error`]s with the matching [`io::ErrorKind`], so
+/// that code written against real sockets handles them as usual. Tests can recover the precise
+/// cause with [`Error::from_io`]:
+///
+/// ```ignore
+/// let err = ep.send_to(dst, 1, payload).await.unwrap_err();
+/// assert_eq!(net::Error::from_io(&err), Some(&net::Error::LinkClogged(dst)));
+/// ```
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// No running node has the address of the destination.
+    NodeDown(SocketAddr),
+    /// The link to the destination is clogged or partitioned, or has no route.
+    LinkClogged(SocketAddr),
+    /// No endpoint is bound to the destination address, or it does not accept the tag.
+    NoEndpoint(SocketAddr),
+    /// The socket buffer is full, or empty when receiving: a nonblocking call would block.
+    BufferFull,
+    /// The connection to the destination was reset or hung up.
+    Reset(SocketAddr),
+    /// A connection request got no answer, because the destination is unreachable.
+    TimedOut(SocketAddr),
+    /// The destination bans the source, see [`NetSim::ban`](crate::net::NetSim::ban).
+    Banned(SocketAddr),
+    /// The socket is not bound, or not connected to a peer.
+    NotConnected,
+    /// The socket was closed, or its node was killed, while receiving.
+    Closed,
+}
+
+impl Error {
+    /// The kind of the [`io::Error`] this error is converted into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::NodeDown(_)
+            | Error::LinkClogged(_)
+            | Error::NoEndpoint(_)
+            | Error::Banned(_) => io::ErrorKind::ConnectionRefused,
+            Error::BufferFull => io::ErrorKind::WouldBlock,
+            Error::Reset(_) => io::ErrorKind::ConnectionReset,
+            Error::TimedOut(_) => io::ErrorKind::TimedOut,
+            Error::NotConnected => io::ErrorKind::NotConnected,
+            Error::Closed => io::ErrorKind::BrokenPipe,
+        }
+    }
+
+    /// The cause of an [`io::Error`] returned by the simulated network, if it has one.
+    pub fn from_io(err: &io::Error) -> Option<&Error> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NodeDown(dst) => write!(f, "host unreachable: {dst}"),
+            Error::LinkClogged(dst) => write!(f, "link clogged: {dst}"),
+            Error::NoEndpoint(dst) => write!(f, "connection refused: {dst}"),
+            Error::BufferFull => write!(f, "operation would block"),
+            Error::Reset(dst) => write!(f, "connection reset: {dst}"),
+            Error::TimedOut(dst) => write!(f, "connection timed out: {dst}"),
+            Error::Banned(dst) => write!(f, "connection refused, banned by {dst}"),
+            Error::NotConnected => write!(f, "socket is not connected"),
+            Error::Closed => write!(f, "socket closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
@@ -0,0 +1,347 @@
+//! Capture filters: select the messages that are recorded, in the spirit of BPF expressions.
+//!
+//! High-volume simulations send far more messages than anyone wants to read. A [`CaptureFilter`]
+//! restricts the messages recorded in the [trace](crate::trace) and by
+//! [`NetSim::track_messages`](super::NetSim::track_messages) to an interesting subset. Filters
+//! can be set for the whole network with [`NetSim::set_capture_filter`](super::NetSim::set_capture_filter),
+//! or for the messages sent or received by one node with
+//! [`NetSim::set_node_capture_filter`](super::NetSim::set_node_capture_filter).
+//!
+//! # Syntax
+//!
+//! A filter is a boolean expression of predicates on a message:
+//!
+//! | predicate | matches messages |
+//! |-----------|------------------|
+//! | `host IP`, `src IP`, `dst IP` | from or to, from, or to the ip |
+//! | `port N`, `src port N`, `dst port N` | from or to, from, or to the port |
+//! | `tag N`, `tag A..B` | with the tag, or with a tag in the range |
+//! | `len OP N` | whose payload size compares to `N`, with `OP` one of `<`, `<=`, `>`, `>=`, `=`, `!=` |
+//! | `tcp`, `udp` | of the protocol |
+//!
+//! Predicates are combined with `and`, `or`, `not` (or `&&`, `||`, `!`) and parentheses, with
+//! the usual precedence. Numbers may be decimal or hexadecimal with a `0x` prefix.
+//!
+//! ```
+//! use msim::net::filter::CaptureFilter;
+//!
+//! let filter = CaptureFilter::parse("dst port 9000 and (tag 1..8 or len > 1024)").unwrap();
+//! assert!(CaptureFilter::parse("port").is_err());
+//! ```
+
+use super::Flow;
+use std::{fmt, io, net::IpAddr, ops::Range, str::FromStr};
+
+/// A filter on messages, see the [module documentation](self) for the syntax.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureFilter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Host(Dir, IpAddr),
+    Port(Dir, u16),
+    Tag(Range<u64>),
+    Len(Cmp, u64),
+    Proto(libc::c_int),
+}
+
+/// Which address of a message a predicate applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CaptureFilter {
+    /// Parse a filter expression.
+    ///
+    /// Returns an `InvalidInput` error describing the first problem in the expression.
+    pub fn parse(source: &str) -> io::Result<Self> {
+        let tokens = tokenize(source);
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(invalid(format!("unexpected `{token}`")));
+        }
+        Ok(CaptureFilter {
+            source: source.into(),
+            expr,
+        })
+    }
+
+    /// Returns `true` if a message of `len` bytes with `tag` on `flow` matches the filter.
+    pub fn matches(&self, flow: &Flow, tag: u64, len: u64) -> bool {
+        self.expr.matches(flow, tag, len)
+    }
+}
+
+impl FromStr for CaptureFilter {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for CaptureFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expr {
+    fn matches(&self, flow: &Flow, tag: u64, len: u64) -> bool {
+        let dir = |dir: Dir, f: &dyn Fn(&std::net::SocketAddr) -> bool| match dir {
+            Dir::Src => f(&flow.src),
+            Dir::Dst => f(&flow.dst),
+            Dir::Any => f(&flow.src) || f(&flow.dst),
+        };
+        match self {
+            Expr::And(a, b) => a.matches(flow, tag, len) && b.matches(flow, tag, len),
+            Expr::Or(a, b) => a.matches(flow, tag, len) || b.matches(flow, tag, len),
+            Expr::Not(a) => !a.matches(flow, tag, len),
+            Expr::Host(d, ip) => dir(*d, &|addr| addr.ip() == *ip),
+            Expr::Port(d, port) => dir(*d, &|addr| addr.port() == *port),
+            Expr::Tag(range) => range.contains(&tag),
+            Expr::Len(cmp, n) => match cmp {
+                Cmp::Lt => len < *n,
+                Cmp::Le => len <= *n,
+                Cmp::Gt => len > *n,
+                Cmp::Ge => len >= *n,
+                Cmp::Eq => len == *n,
+                Cmp::Ne => len != *n,
+            },
+            Expr::Proto(proto) => flow.proto == *proto,
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid filter: {msg}"),
+    )
+}
+
+/// Split an expression into words, parentheses and operators.
+fn tokenize(source: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' => tokens.push(c.to_string()),
+            '<' | '>' | '=' | '!' => {
+                let mut op = c.to_string();
+                if chars.next_if_eq(&'=').is_some() {
+                    op.push('=');
+                }
+                tokens.push(op);
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"()<>=!".contains(*c))
+                {
+                    word.push(c);
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|s| s.as_str())
+    }
+
+    fn next(&mut self, expected: &str) -> io::Result<String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| invalid(format!("expected {expected}, found end of expression")))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, words: &[&str]) -> bool {
+        let found = self.peek().is_some_and(|t| words.contains(&t));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> io::Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat(&["or", "||"]) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> io::Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat(&["and", "&&"]) {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> io::Result<Expr> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.eat(&["("]) {
+            let expr = self.or()?;
+            let token = self.next("`)`")?;
+            if token != ")" {
+                return Err(invalid(format!("expected `)`, found `{token}`")));
+            }
+            return Ok(expr);
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> io::Result<Expr> {
+        let word = self.next("a predicate")?;
+        match word.as_str() {
+            "src" | "dst" | "host" | "port" => {
+                let dir = match word.as_str() {
+                    "src" => Dir::Src,
+                    "dst" => Dir::Dst,
+                    _ => Dir::Any,
+                };
+                let what = if word == "src" || word == "dst" {
+                    match self.peek() {
+                        Some("port") | Some("host") => self.next("`port` or `host`")?,
+                        _ => "host".into(),
+                    }
+                } else {
+                    word
+                };
+                if what == "port" {
+                    let port = self.next("a port")?;
+                    let port = parse_number(&port)?;
+                    let port = u16::try_from(port)
+                        .map_err(|_| invalid(format!("port out of range: {port}")))?;
+                    Ok(Expr::Port(dir, port))
+                } else {
+                    let ip = self.next("an ip address")?;
+                    let ip = ip
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid ip address `{ip}`")))?;
+                    Ok(Expr::Host(dir, ip))
+                }
+            }
+            "tag" => {
+                let tag = self.next("a tag")?;
+                let range = match tag.split_once("..") {
+                    Some((start, end)) => parse_number(start)?..parse_number(end)?,
+                    None => {
+                        let tag = parse_number(&tag)?;
+                        tag..tag.saturating_add(1)
+                    }
+                };
+                Ok(Expr::Tag(range))
+            }
+            "len" => {
+                let op = self.next("a comparison")?;
+                let cmp = match op.as_str() {
+                    "<" => Cmp::Lt,
+                    "<=" => Cmp::Le,
+                    ">" => Cmp::Gt,
+                    ">=" => Cmp::Ge,
+                    "=" | "==" => Cmp::Eq,
+                    "!=" => Cmp::Ne,
+                    _ => return Err(invalid(format!("expected a comparison, found `{op}`"))),
+                };
+                let n = self.next("a length")?;
+                Ok(Expr::Len(cmp, parse_number(&n)?))
+            }
+            "tcp" => Ok(Expr::Proto(libc::SOCK_STREAM)),
+            "udp" => Ok(Expr::Proto(libc::SOCK_DGRAM)),
+            _ => Err(invalid(format!("unknown predicate `{word}`"))),
+        }
+    }
+}
+
+fn parse_number(s: &str) -> io::Result<u64> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    parsed.map_err(|_| invalid(format!("invalid number `{s}`")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let flow = Flow {
+            proto: libc::SOCK_DGRAM,
+            src: "10.0.0.1:1000".parse().unwrap(),
+            dst: "10.0.0.2:2000".parse().unwrap(),
+        };
+        let matches =
+            |expr: &str, tag, len| CaptureFilter::parse(expr).unwrap().matches(&flow, tag, len);
+
+        assert!(matches("src 10.0.0.1", 0, 0));
+        assert!(!matches("dst 10.0.0.1", 0, 0));
+        assert!(matches("host 10.0.0.2 and dst port 2000", 0, 0));
+        assert!(!matches("src port 2000", 0, 0));
+        assert!(matches("port 1000 && udp", 0, 0));
+        assert!(!matches("tcp", 0, 0));
+        assert!(matches("tag 0x10", 16, 0));
+        assert!(matches("tag 1..8", 7, 0));
+        assert!(!matches("tag 1..8", 8, 0));
+        assert!(matches("len>100", 0, 101));
+        assert!(!matches("len <= 100", 0, 101));
+        assert!(matches("not tag 1 or len = 0", 1, 0));
+        assert!(!matches("!(tag 1 || tag 2) and len != 0", 2, 1));
+        // `and` binds tighter than `or`
+        assert!(matches("tag 1 or tag 2 and tcp", 1, 0));
+        assert!(!matches("(tag 1 or tag 2) and tcp", 1, 0));
+
+        for (expr, err) in [
+            ("", "expected a predicate"),
+            ("port", "expected a port"),
+            ("port 70000", "port out of range"),
+            ("src 10.0.0", "invalid ip address"),
+            ("len ~ 3", "expected a comparison"),
+            ("(tag 1", "expected `)`"),
+            ("tag 1 tag 2", "unexpected `tag`"),
+            ("foo", "unknown predicate `foo`"),
+        ] {
+            let msg = CaptureFilter::parse(expr).unwrap_err().to_string();
+            assert!(msg.contains(err), "{expr}: {msg}");
+        }
+    }
+}
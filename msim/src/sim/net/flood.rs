@@ -0,0 +1,245 @@
+//! Connection floods, to test the admission control of servers.
+//!
+//! A [`ConnectFlood`] opens connections to a listener from many client nodes at a steady rate,
+//! like a SYN flood or a herd of clients reconnecting at once, and reports how the listener
+//! copes: how long the connections waited to be accepted, and how many were refused or never
+//! accepted. Enable [`NetworkConfig::listen_backlog`] to drop the requests that overflow the
+//! accept queue of the listener, as a kernel would.
+//!
+//! ```ignore
+//! let flood = ConnectFlood::new(&handle, server_addr, 100, "10.1.0.1".parse().unwrap());
+//! let report = flood.run(1000.0, Duration::from_secs(10)).await;
+//! assert!(report.accept_latency(0.99).unwrap() < Duration::from_millis(200));
+//! ```
+//!
+//! The clients speak the handshake of the simulated tokio `TcpStream`, and close each
+//! connection once it is accepted.
+//!
+//! [`NetworkConfig::listen_backlog`]: super::NetworkConfig::listen_backlog
+
+use super::{network::Payload, Endpoint};
+use crate::{
+    runtime::{Handle, NodeHandle},
+    time::{sleep_until, timeout, Duration, Instant},
+};
+use std::net::{Ipv4Addr, SocketAddr};
+use tracing::*;
+
+/// Tag of the connection requests of the tcp handshake.
+const CONNECT_TAG: u64 = 0;
+
+/// The outcome of the attempts of a [`ConnectFlood`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FloodReport {
+    /// Number of connection attempts.
+    pub attempts: u64,
+    /// The time each accepted connection waited to be accepted, in the order of the attempts.
+    pub accepted: Vec<Duration>,
+    /// Attempts that failed, e.g. because nothing listens on the target.
+    pub refused: u64,
+    /// Attempts that were not accepted in time, e.g. because the accept queue was full or the
+    /// target is unreachable.
+    pub timed_out: u64,
+}
+
+impl FloodReport {
+    /// The `quantile` (between 0 and 1) of the time connections waited to be accepted.
+    pub fn accept_latency(&self, quantile: f64) -> Option<Duration> {
+        assert!(
+            (0.0..=1.0).contains(&quantile),
+            "invalid quantile: {quantile}"
+        );
+        let mut latencies = self.accepted.clone();
+        latencies.sort();
+        let index = (quantile * (latencies.len() as f64 - 1.0)).round() as usize;
+        latencies.get(index).copied()
+    }
+
+    /// The mean time connections waited to be accepted.
+    pub fn mean_accept_latency(&self) -> Option<Duration> {
+        if self.accepted.is_empty() {
+            return None;
+        }
+        Some(self.accepted.iter().sum::<Duration>() / self.accepted.len() as u32)
+    }
+}
+
+enum Outcome {
+    Accepted(Duration),
+    Refused,
+    TimedOut,
+}
+
+/// Clients flooding a listener with connection attempts, see the [module](self) documentation.
+pub struct ConnectFlood {
+    target: SocketAddr,
+    nodes: Vec<NodeHandle>,
+    timeout: Duration,
+}
+
+impl ConnectFlood {
+    /// Create `clients` nodes named `flood-{i}`, with consecutive ips from `first_ip`, to connect
+    /// to `target`.
+    pub fn new(handle: &Handle, target: SocketAddr, clients: usize, first_ip: Ipv4Addr) -> Self {
+        assert!(clients > 0, "a flood needs clients");
+        let nodes = (0..clients)
+            .map(|i| {
+                let ip = Ipv4Addr::from(u32::from(first_ip) + i as u32);
+                handle
+                    .create_node()
+                    .name(format!("flood-{i}"))
+                    .ip(ip.into())
+                    .build()
+            })
+            .collect();
+        ConnectFlood {
+            target,
+            nodes,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Give up on connections that are not accepted within `timeout`. 1 second by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The client nodes.
+    pub fn nodes(&self) -> &[NodeHandle] {
+        &self.nodes
+    }
+
+    /// Attempt `rate` connections per second for `duration`, from the clients in turn, and wait
+    /// for the outcome of every attempt.
+    pub async fn run(&self, rate: f64, duration: Duration) -> FloodReport {
+        assert!(rate > 0.0, "rate must be positive: {rate}");
+        let interval = Duration::from_secs_f64(1.0 / rate);
+        let start = Instant::now();
+        let mut attempts = vec![];
+        for (i, node) in self.nodes.iter().cycle().enumerate() {
+            let at = start + interval.mul_f64(i as f64);
+            if at - start >= duration {
+                break;
+            }
+            sleep_until(at).await;
+            attempts.push(node.spawn(connect(self.target, self.timeout)));
+        }
+        let mut report = FloodReport::default();
+        for attempt in attempts {
+            report.attempts += 1;
+            match attempt.await {
+                Ok(Outcome::Accepted(latency)) => report.accepted.push(latency),
+                Ok(Outcome::TimedOut) => report.timed_out += 1,
+                // the client node was killed
+                Ok(Outcome::Refused) | Err(_) => report.refused += 1,
+            }
+        }
+        debug!(
+            "flood of {}: {} attempts, {} accepted, {} refused, {} timed out",
+            self.target,
+            report.attempts,
+            report.accepted.len(),
+            report.refused,
+            report.timed_out
+        );
+        report
+    }
+}
+
+/// Open a connection to `target` and close it once accepted.
+async fn connect(target: SocketAddr, wait: Duration) -> Outcome {
+    // an unreachable target does not answer the connection request at all.
+    let ep = match timeout(wait, Endpoint::connect(libc::SOCK_STREAM, target)).await {
+        Ok(Ok(ep)) => ep,
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::TimedOut => return Outcome::TimedOut,
+        Err(_) => return Outcome::TimedOut,
+        Ok(Err(e)) => {
+            trace!("connection to {target} failed: {e}");
+            return Outcome::Refused;
+        }
+    };
+    let id = ep.allocate_local_tcp_id();
+    let start = Instant::now();
+    let outcome = match ep
+        .send_to_raw(target, CONNECT_TAG, Payload::tcp_connect(id))
+        .await
+    {
+        Ok(()) => match timeout(wait, ep.recv_from_raw((id as u64) << 32)).await {
+            Ok(Ok(_)) => Outcome::Accepted(start.elapsed()),
+            Ok(Err(e)) => {
+                trace!("connection to {target} failed: {e}");
+                Outcome::Refused
+            }
+            Err(_) => Outcome::TimedOut,
+        },
+        Err(e) => {
+            trace!("connection to {target} refused: {e}");
+            Outcome::Refused
+        }
+    };
+    ep.deregister_tcp_id(&target, id);
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{get_endpoint_from_socket, NetSim, PayloadData},
+        plugin::simulator,
+        runtime::Runtime,
+        time::sleep,
+    };
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn flood() {
+        let runtime = Runtime::new();
+        let server = "10.0.0.1:80".parse::<SocketAddr>().unwrap();
+        let node = runtime.create_node().ip(server.ip()).build();
+        // accepts 10 connections per second, with room for 4 in the accept queue.
+        node.spawn(async move {
+            let socket =
+                socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+            socket.bind(&server.into()).unwrap();
+            socket.listen(4).unwrap();
+            let ep = get_endpoint_from_socket(socket.as_raw_fd()).unwrap();
+            loop {
+                let (payload, from) = ep.recv_from_raw(CONNECT_TAG).await.unwrap();
+                let PayloadData::TcpConnect(id) = payload.data else {
+                    panic!("not a connection request");
+                };
+                sleep(Duration::from_millis(100)).await;
+                let reply = Payload::tcp_connect(0);
+                let _ = ep.send_to_raw(from, (id as u64) << 32, reply).await;
+            }
+        });
+        let handle = runtime.handle();
+        let flood = ConnectFlood::new(handle, server, 10, "10.1.0.1".parse().unwrap());
+        let closed = "10.0.0.1:81".parse().unwrap();
+        let refused = ConnectFlood::new(handle, closed, 1, "10.2.0.1".parse().unwrap());
+        let nowhere = "10.0.0.2:80".parse().unwrap();
+        let unreachable = ConnectFlood::new(handle, nowhere, 1, "10.3.0.1".parse().unwrap());
+
+        runtime.block_on(async move {
+            simulator::<NetSim>().update_config(|cfg| cfg.listen_backlog = true);
+            let report = flood.run(50.0, Duration::from_secs(2)).await;
+            assert_eq!(report.attempts, 100);
+            assert_eq!(report.refused, 0);
+            assert_eq!(report.accepted.len() as u64 + report.timed_out, 100);
+            let accepted = report.accepted.len();
+            assert!((15..=30).contains(&accepted), "{accepted} accepted");
+            // no connection waits behind more than 4 others
+            let max = report.accept_latency(1.0).unwrap();
+            assert!(max < Duration::from_millis(600), "{max:?}");
+
+            let report = refused.run(10.0, Duration::from_secs(1)).await;
+            assert_eq!((report.attempts, report.refused), (10, 10));
+            assert_eq!(report.mean_accept_latency(), None);
+
+            let report = unreachable.run(10.0, Duration::from_secs(1)).await;
+            assert_eq!((report.attempts, report.timed_out), (10, 10));
+        });
+    }
+}
@@ -0,0 +1,271 @@
+//! An oracle for at-most-once processing.
+//!
+//! Code that must not apply a message twice usually deduplicates by a message id of its own.
+//! When the invariant breaks, it helps to know whether the extra copy came from the network,
+//! which duplicates messages when configured to (see [`TagFault`](super::TagFault) and
+//! [`DeliveryOverride`](super::DeliveryOverride)), from a retry of the sender, or both. The
+//! [`IdempotencyOracle`] is told by the application when it sends, receives and processes each
+//! message, and reports for every id how many copies the network added and how many times the
+//! message was processed, and where:
+//!
+//! ```ignore
+//! let oracle = IdempotencyOracle::new();
+//!
+//! // sender
+//! oracle.sent(req.id);
+//! // receiver, before deduplication
+//! oracle.received(req.id);
+//! if !seen.insert(req.id) { continue; }
+//! oracle.processed(req.id);
+//!
+//! // test
+//! oracle.assert_at_most_once();
+//! ```
+
+use crate::{plugin, task::NodeId, time::TimeHandle};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Tracks the messages of an application by their id, see the [module](self) documentation.
+///
+/// Clones share the same records, so that one oracle can be given to every node.
+pub struct IdempotencyOracle<K> {
+    records: Arc<Mutex<BTreeMap<K, Deliveries>>>,
+}
+
+impl<K> Clone for IdempotencyOracle<K> {
+    fn clone(&self) -> Self {
+        IdempotencyOracle {
+            records: self.records.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone + fmt::Debug> Default for IdempotencyOracle<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone + fmt::Debug> IdempotencyOracle<K> {
+    /// Create an oracle with no records.
+    pub fn new() -> Self {
+        IdempotencyOracle {
+            records: Default::default(),
+        }
+    }
+
+    fn update(&self, id: K, f: impl FnOnce(&mut Deliveries)) {
+        f(self.records.lock().unwrap().entry(id).or_default());
+    }
+
+    /// Record that the message `id` was sent, or sent again by a retry.
+    pub fn sent(&self, id: K) {
+        self.update(id, |d| d.sent += 1);
+    }
+
+    /// Record that a copy of the message `id` was received, before any deduplication.
+    pub fn received(&self, id: K) {
+        self.update(id, |d| d.received += 1);
+    }
+
+    /// Record that the message `id` was processed by the current node.
+    pub fn processed(&self, id: K) {
+        let node = plugin::node();
+        let at = TimeHandle::current().time_since_clock_base();
+        self.update(id, |d| d.processed.push((node, at)));
+    }
+
+    /// The deliveries of the message `id`, if it was recorded.
+    pub fn get(&self, id: &K) -> Option<Deliveries> {
+        self.records.lock().unwrap().get(id).cloned()
+    }
+
+    /// Report the messages that the network duplicated or that were processed more than once.
+    pub fn report(&self) -> IdempotencyReport<K> {
+        let records = self.records.lock().unwrap();
+        let filter = |f: fn(&Deliveries) -> bool| {
+            records
+                .iter()
+                .filter(|(_, d)| f(d))
+                .map(|(id, d)| (id.clone(), d.clone()))
+                .collect()
+        };
+        IdempotencyReport {
+            messages: records.len(),
+            duplicated: filter(|d| d.network_duplicates() > 0),
+            processed_twice: filter(|d| d.processed.len() > 1),
+        }
+    }
+
+    /// Panics with the [report](Self::report) if a message was processed more than once.
+    #[track_caller]
+    pub fn assert_at_most_once(&self) {
+        let report = self.report();
+        if !report.processed_twice.is_empty() {
+            panic!("at-most-once processing violated:\n{report}");
+        }
+    }
+}
+
+/// What happened to a message, see [`IdempotencyOracle`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Deliveries {
+    /// Number of times the message was sent, including retries.
+    pub sent: usize,
+    /// Number of copies of the message that were received.
+    pub received: usize,
+    /// The nodes that processed the message and when, as virtual time since the start of the
+    /// simulation, in order.
+    pub processed: Vec<(NodeId, Duration)>,
+}
+
+impl Deliveries {
+    /// Number of copies received beyond the copies sent, which the network must have added.
+    pub fn network_duplicates(&self) -> usize {
+        self.received.saturating_sub(self.sent)
+    }
+
+    /// Number of times the message was sent again.
+    pub fn retries(&self) -> usize {
+        self.sent.saturating_sub(1)
+    }
+}
+
+impl fmt::Display for Deliveries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} ({} retries), received {} ({} network duplicates), processed {}",
+            self.sent,
+            self.retries(),
+            self.received,
+            self.network_duplicates(),
+            self.processed.len()
+        )?;
+        for (node, at) in &self.processed {
+            write!(f, "\n    by {node} at {at:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The messages recorded by an [`IdempotencyOracle`] that need attention, ordered by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyReport<K> {
+    /// Number of messages recorded.
+    pub messages: usize,
+    /// Messages of which the network delivered more copies than were sent.
+    pub duplicated: Vec<(K, Deliveries)>,
+    /// Messages that were processed more than once.
+    pub processed_twice: Vec<(K, Deliveries)>,
+}
+
+impl<K: fmt::Debug> fmt::Display for IdempotencyReport<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages, {} duplicated by the network, {} processed more than once",
+            self.messages,
+            self.duplicated.len(),
+            self.processed_twice.len()
+        )?;
+        for (id, deliveries) in &self.processed_twice {
+            let cause = match (deliveries.network_duplicates(), deliveries.retries()) {
+                (0, 0) => "processed twice from a single copy",
+                (_, 0) => "network duplicate",
+                (0, _) => "retry",
+                _ => "network duplicate and retry",
+            };
+            write!(f, "\n  {id:?}: {cause}: {deliveries}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{DeliveryOverride, Endpoint},
+        runtime::Runtime,
+        time::sleep,
+    };
+    use std::{collections::HashSet, net::SocketAddr};
+
+    #[test]
+    fn oracle() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let oracle = IdempotencyOracle::<u64>::new();
+
+        // the receiver deduplicates every message but 3
+        let oracle_ = oracle.clone();
+        node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut seen = HashSet::new();
+            let mut buf = [0; 8];
+            loop {
+                ep.recv_from(1, &mut buf).await.unwrap();
+                let id = u64::from_be_bytes(buf);
+                oracle_.received(id);
+                if seen.insert(id) || id == 3 {
+                    oracle_.processed(id);
+                }
+            }
+        });
+
+        let oracle_ = oracle.clone();
+        let f = node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            let duplicated = DeliveryOverride {
+                duplicate: Some(Duration::from_millis(10)),
+                ..Default::default()
+            };
+            for id in 1..=4u64 {
+                let payload = crate::net::network::Payload::udp(id.to_be_bytes().to_vec());
+                oracle_.sent(id);
+                if id % 2 == 1 {
+                    ep.send_to_with(addr2, 1, payload, duplicated.clone())
+                        .await
+                        .unwrap();
+                } else {
+                    ep.send_to_raw(addr2, 1, payload).await.unwrap();
+                }
+            }
+            // 4 is retried
+            oracle_.sent(4);
+            let payload = crate::net::network::Payload::udp(4u64.to_be_bytes().to_vec());
+            ep.send_to_raw(addr2, 1, payload).await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+        });
+        runtime.block_on(f).unwrap();
+
+        let report = oracle.report();
+        assert_eq!(report.messages, 4);
+        let ids: Vec<_> = report.duplicated.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(report.processed_twice.len(), 1);
+        let (id, deliveries) = &report.processed_twice[0];
+        assert_eq!(*id, 3);
+        assert_eq!(deliveries.network_duplicates(), 1);
+        assert_eq!(deliveries.processed.len(), 2);
+        assert_eq!(deliveries.processed[0].0, node2.id());
+        let retried = oracle.get(&4).unwrap();
+        assert_eq!((retried.retries(), retried.processed.len()), (1, 1));
+
+        runtime.block_on(async move {
+            let err = std::panic::catch_unwind(|| oracle.assert_at_most_once()).unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.contains("3: network duplicate: sent 1"), "{msg}");
+        });
+    }
+}
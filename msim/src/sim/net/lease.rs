@@ -0,0 +1,543 @@
+//! A simulated lease service, for testing leader election.
+//!
+//! [`LeaseServer`] grants named leases with a time to live, measured in virtual time, and serves
+//! them over the simulated network. [`LeaseClient`] acquires, renews and releases leases. Each
+//! grant carries a fencing token that increases with every new holder of a lease.
+//!
+//! The server can be made to misbehave in the ways real lock services do:
+//! - [`LeaseServer::set_clock_rate`]: the clock of the server runs faster or slower than the
+//!   clocks of its clients, so leases expire earlier or later than their holders expect.
+//! - [`LeaseServer::set_expiry_delay`]: expired leases are only released after a delay.
+//! - [`LeaseServer::set_double_grant_rate`]: a lease that is already held is granted to another
+//!   client, leaving two holders.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::net::lease::{LeaseClient, LeaseServer};
+//!
+//! let server = LeaseServer::start(&handle, "10.0.0.100".parse().unwrap());
+//! let addr = server.addr();
+//!
+//! // on a node
+//! let client = LeaseClient::new(addr, "node-1").await?;
+//! if let Some(lease) = client.acquire("leader", Duration::from_secs(10)).await? {
+//!     // lead, and renew before the lease expires
+//!     let lease = client.renew(&lease).await?;
+//! }
+//! ```
+
+use super::{rpc, Endpoint};
+use crate::{
+    rand::{thread_rng, Rng},
+    runtime::Handle,
+    time::{Duration, Instant},
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tracing::*;
+
+/// The port the lease service listens on.
+pub const LEASE_PORT: u16 = 2379;
+
+/// Tag of requests sent to the server.
+const REQUEST_TAG: u64 = 0x1ea5_0000_0000_0000;
+
+#[derive(Debug)]
+enum Request {
+    Acquire {
+        name: String,
+        holder: String,
+        ttl: Duration,
+    },
+    Renew {
+        name: String,
+        token: u64,
+        ttl: Duration,
+    },
+    Release {
+        name: String,
+        token: u64,
+    },
+    Holders {
+        name: String,
+    },
+}
+
+#[derive(Debug)]
+enum Response {
+    Granted(Option<u64>),
+    Released,
+    Holders(Vec<String>),
+}
+
+/// A lease held by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// The name of the lease.
+    pub name: String,
+    /// The fencing token of the grant, greater than the tokens of all previous holders.
+    pub token: u64,
+    /// The time to live of the lease.
+    pub ttl: Duration,
+    /// When the request that granted or renewed the lease was sent, on the clock of the client.
+    pub renewed_at: Instant,
+}
+
+impl Lease {
+    /// Returns `true` if the lease has not expired, as far as the client can tell.
+    ///
+    /// The client counts the time to live from when it sent its request, so it considers the
+    /// lease expired no later than the server does, unless the clock of the server runs fast.
+    pub fn is_valid(&self) -> bool {
+        Instant::now() < self.renewed_at + self.ttl
+    }
+}
+
+/// A grant of a lease, as recorded by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    /// The client the lease was granted to.
+    pub holder: String,
+    /// The fencing token of the grant.
+    pub token: u64,
+    /// When the lease was granted.
+    pub granted_at: Instant,
+    /// When the lease expires, on the clock of the simulation. Renewals push it back.
+    pub expires_at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// The current grants of each lease. More than one if the lease was double-granted.
+    grants: HashMap<String, Vec<Grant>>,
+    /// All grants of each lease, in the order they were made.
+    history: HashMap<String, Vec<Grant>>,
+    next_token: u64,
+    clock_rate: f64,
+    expiry_delay: Duration,
+    double_grant_rate: f64,
+}
+
+impl State {
+    /// Drop the expired grants of `name`, and return the remaining ones.
+    fn live(&mut self, name: &str, now: Instant) -> &mut Vec<Grant> {
+        let delay = self.expiry_delay;
+        let grants = self.grants.entry(name.into()).or_default();
+        grants.retain(|grant| now < grant.expires_at + delay);
+        grants
+    }
+
+    /// The virtual time a lease with `ttl` lasts, on the clock of the server.
+    fn lifetime(&self, ttl: Duration) -> Duration {
+        ttl.div_f64(self.clock_rate)
+    }
+}
+
+/// The lease server.
+///
+/// The leases and the settings are shared by all clones of the server and survive restarts of
+/// the node it runs on.
+#[derive(Clone)]
+pub struct LeaseServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl LeaseServer {
+    /// Create a server listening on `ip`. Call [`LeaseServer::serve`] on the node owning the ip to
+    /// start serving requests.
+    pub fn new(ip: IpAddr) -> Self {
+        let state = State {
+            next_token: 1,
+            clock_rate: 1.0,
+            ..Default::default()
+        };
+        LeaseServer {
+            addr: SocketAddr::new(ip, LEASE_PORT),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Create a node named "lease" with the given ip, serving the leases.
+    pub fn start(handle: &Handle, ip: IpAddr) -> Self {
+        let server = Self::new(ip);
+        let server_ = server.clone();
+        handle
+            .create_node()
+            .name("lease")
+            .ip(ip)
+            .init(move || {
+                let server = server_.clone();
+                async move {
+                    if let Err(e) = server.serve().await {
+                        error!("lease server failed: {e}");
+                    }
+                }
+            })
+            .build();
+        server
+    }
+
+    /// The address of the server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Run the clock of the server `rate` times as fast as virtual time, e.g. 2.0 to expire
+    /// leases after half their time to live. Applies to leases granted or renewed afterwards.
+    pub fn set_clock_rate(&self, rate: f64) {
+        assert!(rate > 0.0, "invalid clock rate: {rate}");
+        self.state.lock().unwrap().clock_rate = rate;
+    }
+
+    /// Keep expired leases for `delay` before granting them to other clients.
+    pub fn set_expiry_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().expiry_delay = delay;
+    }
+
+    /// Grant a lease that is held by another client with probability `rate`, without revoking
+    /// the current holder.
+    pub fn set_double_grant_rate(&self, rate: f64) {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "invalid double grant rate: {rate}"
+        );
+        self.state.lock().unwrap().double_grant_rate = rate;
+    }
+
+    /// The current holders of `name`, directly from the server, bypassing the network.
+    pub fn holders(&self, name: &str) -> Vec<Grant> {
+        self.state
+            .lock()
+            .unwrap()
+            .live(name, Instant::now())
+            .clone()
+    }
+
+    /// All grants of `name` so far, in the order they were made.
+    ///
+    /// Leader election tests can check that the grants of a lease never overlap.
+    pub fn history(&self, name: &str) -> Vec<Grant> {
+        let state = self.state.lock().unwrap();
+        state.history.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Serve requests until the node is killed.
+    pub async fn serve(&self) -> io::Result<()> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, self.addr).await?;
+        rpc::serve(&ep, REQUEST_TAG, "lease request", |_, request| {
+            self.handle(request)
+        })
+        .await
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match request {
+            Request::Acquire { name, holder, ttl } => {
+                let expires_at = now + state.lifetime(ttl);
+                let double_grant = state.double_grant_rate;
+                let grants = state.live(&name, now);
+                if let Some(grant) = grants.iter_mut().find(|g| g.holder == holder) {
+                    grant.expires_at = expires_at;
+                    return Response::Granted(Some(grant.token));
+                }
+                if !grants.is_empty() && !thread_rng().gen_bool(double_grant) {
+                    return Response::Granted(None);
+                }
+                if !grants.is_empty() {
+                    warn!("lease {name} double-granted to {holder}");
+                }
+                let grant = Grant {
+                    holder,
+                    token: state.next_token,
+                    granted_at: now,
+                    expires_at,
+                };
+                state.next_token += 1;
+                state.live(&name, now).push(grant.clone());
+                state.history.entry(name).or_default().push(grant.clone());
+                Response::Granted(Some(grant.token))
+            }
+            Request::Renew { name, token, ttl } => {
+                let expires_at = now + state.lifetime(ttl);
+                let grants = state.live(&name, now);
+                match grants.iter_mut().find(|g| g.token == token) {
+                    Some(grant) => {
+                        grant.expires_at = expires_at;
+                        Response::Granted(Some(token))
+                    }
+                    None => Response::Granted(None),
+                }
+            }
+            Request::Release { name, token } => {
+                state.live(&name, now).retain(|g| g.token != token);
+                Response::Released
+            }
+            Request::Holders { name } => {
+                let grants = state.live(&name, now);
+                Response::Holders(grants.iter().map(|g| g.holder.clone()).collect())
+            }
+        }
+    }
+}
+
+/// A client of the lease service.
+pub struct LeaseClient {
+    ep: Endpoint,
+    server: SocketAddr,
+    holder: String,
+    timeout: Duration,
+}
+
+impl LeaseClient {
+    /// Create a client of the server at `server`, that holds leases as `holder`.
+    pub async fn new(server: SocketAddr, holder: &str) -> io::Result<Self> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:0").await?;
+        Ok(LeaseClient {
+            ep,
+            server,
+            holder: holder.into(),
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    /// Set the timeout of requests. Defaults to one second.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The name this client holds leases as.
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Acquire `name` for `ttl`. Returns `None` if it is held by another client.
+    ///
+    /// Acquiring a lease this client already holds renews it.
+    pub async fn acquire(&self, name: &str, ttl: Duration) -> io::Result<Option<Lease>> {
+        let renewed_at = Instant::now();
+        let request = Request::Acquire {
+            name: name.into(),
+            holder: self.holder.clone(),
+            ttl,
+        };
+        Ok(self.grant(request).await?.map(|token| Lease {
+            name: name.into(),
+            token,
+            ttl,
+            renewed_at,
+        }))
+    }
+
+    /// Renew `lease` for its time to live. Returns `None` if the lease was lost, e.g. because it
+    /// expired.
+    pub async fn renew(&self, lease: &Lease) -> io::Result<Option<Lease>> {
+        let renewed_at = Instant::now();
+        let request = Request::Renew {
+            name: lease.name.clone(),
+            token: lease.token,
+            ttl: lease.ttl,
+        };
+        Ok(self.grant(request).await?.map(|_| Lease {
+            renewed_at,
+            ..lease.clone()
+        }))
+    }
+
+    /// Release `lease`, so that other clients can acquire it.
+    pub async fn release(&self, lease: Lease) -> io::Result<()> {
+        let request = Request::Release {
+            name: lease.name,
+            token: lease.token,
+        };
+        self.call(request).await.map(|_| ())
+    }
+
+    /// The clients holding `name`.
+    pub async fn holders(&self, name: &str) -> io::Result<Vec<String>> {
+        let request = Request::Holders { name: name.into() };
+        match self.call(request).await? {
+            Response::Holders(holders) => Ok(holders),
+            _ => unreachable!("unexpected lease response"),
+        }
+    }
+
+    async fn grant(&self, request: Request) -> io::Result<Option<u64>> {
+        match self.call(request).await? {
+            Response::Granted(token) => Ok(token),
+            _ => unreachable!("unexpected lease response"),
+        }
+    }
+
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        rpc::call(
+            &self.ep,
+            self.server,
+            REQUEST_TAG,
+            request,
+            self.timeout,
+            "lease request",
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{LatencyDistribution, NetSim},
+        plugin::simulator,
+        runtime::Runtime,
+        time::sleep,
+    };
+
+    #[test]
+    fn lease() {
+        let runtime = Runtime::new();
+        let server = LeaseServer::start(runtime.handle(), "10.0.0.100".parse().unwrap());
+        let addr = server.addr();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let ttl = Duration::from_secs(10);
+
+        let f = node.spawn(async move {
+            // wait for the server to start
+            sleep(Duration::from_millis(1)).await;
+            let a = LeaseClient::new(addr, "a").await.unwrap();
+            let b = LeaseClient::new(addr, "b").await.unwrap();
+
+            let lease = a.acquire("leader", ttl).await.unwrap().unwrap();
+            assert!(lease.is_valid());
+            assert_eq!(b.acquire("leader", ttl).await.unwrap(), None);
+            sleep(Duration::from_secs(8)).await;
+            let lease = a.renew(&lease).await.unwrap().unwrap();
+            sleep(Duration::from_secs(8)).await;
+            assert_eq!(b.acquire("leader", ttl).await.unwrap(), None);
+
+            // the lease expires, and goes to b with a greater token
+            sleep(Duration::from_secs(3)).await;
+            assert!(!lease.is_valid());
+            assert_eq!(a.renew(&lease).await.unwrap(), None);
+            let lease_b = b.acquire("leader", ttl).await.unwrap().unwrap();
+            assert!(lease_b.token > lease.token);
+            assert_eq!(a.holders("leader").await.unwrap(), ["b"]);
+            b.release(lease_b).await.unwrap();
+            assert!(a.holders("leader").await.unwrap().is_empty());
+        });
+        runtime.block_on(f).unwrap();
+        assert_eq!(server.history("leader").len(), 2);
+    }
+
+    #[test]
+    fn late_reply() {
+        let runtime = Runtime::new();
+        let server = LeaseServer::new("10.0.0.100".parse().unwrap());
+        let addr = server.addr();
+        let server_node = runtime
+            .create_node()
+            .ip(addr.ip())
+            .init(move || {
+                let server = server.clone();
+                async move { server.serve().await.unwrap() }
+            })
+            .build();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let (server_id, node_id) = (server_node.id(), node.id());
+
+        let f = node.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let net = simulator::<NetSim>();
+            net.set_one_way_latency(
+                server_id,
+                node_id,
+                Some(LatencyDistribution::Constant(Duration::from_secs(2))),
+            );
+            let a = LeaseClient::new(addr, "a").await.unwrap();
+            let ttl = Duration::from_secs(10);
+            let e = a.acquire("leader", ttl).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+            // the reply arrives after the timeout, and is dropped
+            sleep(Duration::from_secs(2)).await;
+            let local = a.ep.local_addr().unwrap();
+            let queued = net
+                .lock_network()
+                .queue_depth(node_id, libc::SOCK_DGRAM, local, |_| true);
+            assert_eq!(queued, Some((0, 0)));
+
+            net.set_one_way_latency(server_id, node_id, None);
+            a.acquire("leader", ttl).await.unwrap().unwrap();
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn faults() {
+        let runtime = Runtime::new();
+        let server = LeaseServer::start(runtime.handle(), "10.0.0.100".parse().unwrap());
+        let addr = server.addr();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let ttl = Duration::from_secs(10);
+
+        // late expiry
+        server.set_expiry_delay(Duration::from_secs(5));
+        let f = node.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let a = LeaseClient::new(addr, "a").await.unwrap();
+            let b = LeaseClient::new(addr, "b").await.unwrap();
+            a.acquire("leader", ttl).await.unwrap().unwrap();
+            sleep(Duration::from_secs(12)).await;
+            assert_eq!(b.acquire("leader", ttl).await.unwrap(), None);
+            sleep(Duration::from_secs(4)).await;
+            b.acquire("leader", ttl).await.unwrap().unwrap();
+        });
+        runtime.block_on(f).unwrap();
+
+        // a fast server clock expires leases early
+        server.set_expiry_delay(Duration::ZERO);
+        server.set_clock_rate(2.0);
+        let f = node.spawn(async move {
+            let a = LeaseClient::new(addr, "a").await.unwrap();
+            let lease = a.acquire("fast", ttl).await.unwrap().unwrap();
+            sleep(Duration::from_secs(6)).await;
+            assert!(lease.is_valid());
+            assert_eq!(a.renew(&lease).await.unwrap(), None);
+        });
+        runtime.block_on(f).unwrap();
+
+        // double grant
+        server.set_clock_rate(1.0);
+        server.set_double_grant_rate(1.0);
+        let f = node.spawn(async move {
+            let a = LeaseClient::new(addr, "a").await.unwrap();
+            let b = LeaseClient::new(addr, "b").await.unwrap();
+            a.acquire("split", ttl).await.unwrap().unwrap();
+            b.acquire("split", ttl).await.unwrap().unwrap();
+        });
+        runtime.block_on(async move {
+            f.await.unwrap();
+            let holders: Vec<_> = server
+                .holders("split")
+                .into_iter()
+                .map(|g| g.holder)
+                .collect();
+            assert_eq!(holders, ["a", "b"]);
+        });
+    }
+}
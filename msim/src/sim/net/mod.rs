@@ -35,31 +35,58 @@
 //! runtime.block_on(f);
 //! ```
 
+use bytes::Buf;
+use futures::{channel::mpsc, Stream};
 use std::{
-    collections::{HashMap, HashSet},
+    any::TypeId,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     io,
     net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    ops::Range,
     os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    pin::Pin,
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
     },
-    task::Context,
+    task::{Context, Poll},
 };
 use tap::TapFallible;
 use tracing::*;
 
+pub mod churn;
 pub mod config;
 pub use config::*;
-
-pub use self::network::Stat;
-use self::network::{Network, Payload};
+pub mod discovery;
+mod error;
+pub use self::error::Error;
+pub mod filter;
+pub mod flood;
+pub mod idempotency;
+pub mod lease;
+pub mod object_store;
+pub mod probe;
+pub mod relay;
+mod rpc;
+pub mod rtt;
+pub mod stream;
+pub mod topology;
+
+pub use self::network::{
+    DropReason, EgressStat, Flow, FlowStat, ForbidId, MsgHandle, MsgId, MsgRecord, MsgStatus,
+    Partition, PartitionTarget, PayloadData, Stat, Tag, TamperDetection, TamperRecord, TamperScore,
+};
+use self::network::{Network, Payload, PendingConnection};
+use crate::sim::intercept::{set_errno, unsupported, unsupported_with};
 use crate::{
-    define_bypass, define_sys_interceptor, plugin,
-    rand::{GlobalRng, Rng},
+    define_bypass, define_sys_interceptor,
+    perf::{Measured, Section},
+    plugin,
+    profile::Category,
+    rand::{GlobalRng, Rng, RngStream},
     return_if_killed,
     task::NodeId,
-    time::{Duration, TimeHandle},
+    time::{Duration, Instant, TimeHandle},
 };
 
 /// network module
@@ -72,8 +99,14 @@ pub struct NetSim {
     network: Mutex<Network>,
     host_state: Mutex<HostNetworkState>,
     rand: GlobalRng,
+    /// Reply tags of the simulated services, see [`rpc`].
+    rpc_rand: GlobalRng,
+    /// Online and offline periods and addresses of churning nodes, see [`churn`].
+    churn_rand: GlobalRng,
     time: TimeHandle,
     next_tcp_id: AtomicU32, // We always allocate new globally unique tcp id.
+    /// Names of message tags, see [`NetSim::register_tag`].
+    tag_names: Mutex<HashMap<u64, String>>,
 }
 
 #[derive(Debug)]
@@ -81,6 +114,7 @@ struct PlaceholderFileDes(libc::c_int);
 
 impl Drop for PlaceholderFileDes {
     fn drop(&mut self) {
+        SOCKET_FDS.lock().unwrap().remove(&self.0);
         unsafe {
             debug!("closing {:?}", self);
             if bypass_close(self.0) != 0 {
@@ -140,15 +174,41 @@ impl Drop for OwnedFd {
 fn alloc_fd() -> libc::c_int {
     let fd = unsafe { libc::dup(0) };
     debug!("allocated fd {}", fd);
+    SOCKET_FDS.lock().unwrap().insert(fd);
     fd
 }
 
+/// The file descriptors allocated for simulated sockets by all runtimes, which are unique in the
+/// process, so that the calls made on other files can be told apart without locking a network.
+static SOCKET_FDS: Mutex<BTreeSet<libc::c_int>> = Mutex::new(BTreeSet::new());
+
 #[derive(Debug)]
 struct SocketState {
+    domain: libc::c_int,
     ty: libc::c_int,
     _placeholder_file: PlaceholderFileDes,
     endpoint: Option<Arc<Endpoint>>,
     listening: bool,
+    conn: Option<StreamConn>,
+}
+
+/// A connection of a stream socket made with `connect()` or `accept()`, whose data is read and
+/// written by the libc calls, without tokio.
+///
+/// Each write is sent as a tcp segment tagged with the id of the receiving end and a sequence
+/// number, so that the reader consumes the segments in order whatever their latency.
+#[derive(Debug)]
+struct StreamConn {
+    /// The endpoint the connection sends and receives with: the listening endpoint for an
+    /// accepted connection, as the connecting end sends to it.
+    ep: Arc<Endpoint>,
+    peer: SocketAddr,
+    local_id: u32,
+    remote_id: u32,
+    send_seq: u32,
+    recv_seq: u32,
+    /// The rest of the last received segment, not yet read.
+    unread: bytes::Bytes,
 }
 
 #[derive(Default)]
@@ -174,11 +234,23 @@ impl HostNetworkState {
         let node_id = plugin::node();
         let mut host_state = net.host_state.lock().unwrap();
 
-        let res = host_state.sockets.remove(&(node_id, fd)).is_some();
-        if res {
-            trace!("closing socket {}.{}", node_id, fd);
+        let Some(socket) = host_state.sockets.remove(&(node_id, fd)) else {
+            return false;
+        };
+        trace!("closing socket {}.{}", node_id, fd);
+        drop(host_state);
+        if let Some(conn) = socket.conn {
+            // the peer reads the end of the stream once it has read the data sent before.
+            let local = conn.ep.addr;
+            net.lock_network().deregister_tcp_id(
+                node_id,
+                conn.ep.proto,
+                &local,
+                &conn.peer,
+                conn.local_id,
+            );
         }
-        res
+        true
     }
 
     fn with_socket<T>(fd: libc::c_int, cb: impl Fn(&mut SocketState) -> T) -> io::Result<T> {
@@ -188,10 +260,31 @@ impl HostNetworkState {
         let socket = host_state
             .sockets
             .get_mut(&(node_id, fd))
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no such socket"))?;
+            .ok_or(Error::NotConnected)?;
         Ok(cb(socket))
     }
 
+    /// Like [`Self::with_socket`], but returns `None` if `fd` is not a simulated socket, for the
+    /// calls that are mostly made on other file descriptors, like `write()`. A simulated socket
+    /// that is not open on the current node fails with `EBADF`.
+    fn try_with_socket(
+        fd: libc::c_int,
+        cb: impl FnOnce(&mut SocketState) -> CResult<libc::ssize_t>,
+    ) -> Option<CResult<libc::ssize_t>> {
+        if !SOCKET_FDS.lock().unwrap().contains(&fd) {
+            return None;
+        }
+        let Some(task) = crate::context::try_current_task() else {
+            return Some(Err((-1, libc::EBADF)));
+        };
+        let net = plugin::simulator::<NetSim>();
+        let mut host_state = net.host_state.lock().unwrap();
+        Some(match host_state.sockets.get_mut(&(task.node(), fd)) {
+            Some(socket) => cb(socket),
+            None => Err((-1, libc::EBADF)),
+        })
+    }
+
     fn delete_node(&mut self, id: NodeId) {
         let to_remove: Vec<_> = self
             .sockets
@@ -210,6 +303,65 @@ impl HostNetworkState {
     }
 }
 
+/// The state of a socket, see [`SocketInfo`].
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketStatus {
+    /// Created but not bound.
+    Unbound,
+    /// Bound, but neither listening nor connected.
+    Bound,
+    /// Listening for tcp connections.
+    Listening,
+    /// Connected to a peer.
+    Connected,
+}
+
+/// A socket of a node, as seen by the simulated kernel, see [`NetSim::dump_sockets`].
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketInfo {
+    /// The file descriptor of the socket.
+    pub fd: libc::c_int,
+    /// The socket type, `SOCK_STREAM` or `SOCK_DGRAM`.
+    pub ty: libc::c_int,
+    /// The state of the socket.
+    pub status: SocketStatus,
+    /// The bound address.
+    pub local: Option<SocketAddr>,
+    /// The connected peer.
+    pub peer: Option<SocketAddr>,
+    /// Messages received by the network but not yet read by the socket. For listening sockets,
+    /// connections waiting to be accepted.
+    pub rx_queue: usize,
+}
+
+impl std::fmt::Display for SocketInfo {
+    /// Format the socket like a line of `ss -a`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let netid = match self.ty {
+            libc::SOCK_STREAM => "tcp",
+            libc::SOCK_DGRAM => "udp",
+            _ => "???",
+        };
+        let state = match (self.status, self.ty) {
+            (SocketStatus::Listening, _) => "LISTEN",
+            (SocketStatus::Connected, _) => "ESTAB",
+            (_, libc::SOCK_STREAM) => "CLOSE",
+            _ => "UNCONN",
+        };
+        let addr = |addr: Option<SocketAddr>| addr.map_or("*:*".to_string(), |a| a.to_string());
+        write!(
+            f,
+            "{netid:<5} {state:<7} {:<6} fd={:<5} {:<21} {}",
+            self.rx_queue,
+            self.fd,
+            addr(self.local),
+            addr(self.peer)
+        )
+    }
+}
+
 /// Get the Endpoint of a socket, if it is bound.
 pub fn try_get_endpoint_from_socket(fd: libc::c_int) -> io::Result<Option<Arc<Endpoint>>> {
     HostNetworkState::with_socket(fd, |socket| socket.endpoint.as_ref().map(|ep| ep.clone()))
@@ -217,8 +369,7 @@ pub fn try_get_endpoint_from_socket(fd: libc::c_int) -> io::Result<Option<Arc<En
 
 /// Get the Endpoint of a bound socket.
 pub fn get_endpoint_from_socket(fd: libc::c_int) -> io::Result<Arc<Endpoint>> {
-    try_get_endpoint_from_socket(fd)?
-        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "socket has not been bound"))
+    try_get_endpoint_from_socket(fd)?.ok_or_else(|| Error::NotConnected.into())
 }
 
 unsafe fn make_sockaddr(sock_addr: *const libc::sockaddr, addr_len: libc::socklen_t) -> SocketAddr {
@@ -251,6 +402,51 @@ unsafe fn write_socket_addr(
     *addr_len = len;
 }
 
+/// Translate an address passed to a socket of the given domain to the ipv4 address used by the
+/// simulator. Dual-stack ipv6 sockets may use v4-mapped addresses (`::ffff:a.b.c.d`), and the
+/// unspecified address `::`. Returns `None` for other ipv6 addresses, which are not supported.
+fn to_sim_addr(domain: libc::c_int, addr: SocketAddr) -> Option<SocketAddr> {
+    match addr {
+        SocketAddr::V4(_) => Some(addr),
+        SocketAddr::V6(v6) if domain == libc::AF_INET6 => {
+            let ip = if v6.ip().is_unspecified() {
+                Ipv4Addr::UNSPECIFIED
+            } else {
+                v6.ip().to_ipv4_mapped()?
+            };
+            Some(SocketAddr::new(ip.into(), v6.port()))
+        }
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// Translate an address of the simulator to the form reported by a socket of the given domain,
+/// i.e. the v4-mapped address for ipv6 sockets.
+fn from_sim_addr(domain: libc::c_int, addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if domain == libc::AF_INET6 => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Look up the name of a message tag in the network of the current runtime.
+///
+/// Returns `None` outside of a runtime, or if the simulators are locked by the caller.
+fn current_tag_name(tag: u64) -> Option<String> {
+    crate::context::try_current(|h| {
+        let net = h
+            .sims
+            .try_lock()
+            .ok()?
+            .get(&TypeId::of::<NetSim>())?
+            .clone();
+        net.downcast_ref::<NetSim>()?.tag_name(tag)
+    })
+    .flatten()
+}
+
 define_bypass!(bypass_close, fn close(fd: libc::c_int) -> libc::c_int);
 
 define_sys_interceptor!(
@@ -263,28 +459,29 @@ define_sys_interceptor!(
     }
 );
 
-#[cfg(target_os = "macos")]
-unsafe fn set_errno(err: libc::c_int) {
-    *libc::__error() = err;
-}
+/// The largest accept queue, the default `net.core.somaxconn` of Linux.
+const SOMAXCONN: usize = 4096;
 
-#[cfg(target_os = "linux")]
-unsafe fn set_errno(err: libc::c_int) {
-    *libc::__errno_location() = err;
-}
+/// How long an unanswered connection request is retransmitted, the default of Linux with
+/// `net.ipv4.tcp_syn_retries = 6`.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(127);
 
 define_sys_interceptor!(
-    fn listen(sock_fd: libc::c_int, _backlog: libc::c_int) -> libc::c_int {
+    fn listen(sock_fd: libc::c_int, backlog: libc::c_int) -> libc::c_int {
         HostNetworkState::with_socket(sock_fd, |socket| {
             assert_eq!(
                 socket.ty,
                 libc::SOCK_STREAM,
                 "only TCP is supported for listen()"
             );
-            if socket.endpoint.is_none() {
+            let Some(ep) = &socket.endpoint else {
                 set_errno(libc::EDESTADDRREQ);
                 return -1;
-            }
+            };
+            let backlog = usize::try_from(backlog).unwrap_or(0).clamp(1, SOMAXCONN);
+            ep.net
+                .lock_network()
+                .listen(socket.ty, ep.node, ep.addr, backlog);
             socket.listening = true;
             0
         })
@@ -321,39 +518,46 @@ define_sys_interceptor!(
     }
 );
 
+/// The domain and type of a socket.
+type SocketType = (libc::c_int, libc::c_int);
+
 unsafe fn accept_impl(
     sock_fd: libc::c_int,
     address: *mut libc::sockaddr,
     address_len: *mut libc::socklen_t,
 ) -> libc::c_int {
-    let result = HostNetworkState::with_socket(
-        sock_fd,
-        |socket| -> Result<(SocketAddr, libc::c_int), (libc::c_int, libc::c_int)> {
-            let node = plugin::node();
-            let net = plugin::simulator::<NetSim>();
-            let network = net.network.lock().unwrap();
-
-            let endpoint = socket.endpoint.as_ref().ok_or((-1, libc::EINVAL))?;
-
-            if endpoint.peer.is_some() {
-                // attempt to accept on a socket that is already connected.
-                return Err((-1, libc::EINVAL));
-            }
+    let result =
+        HostNetworkState::with_socket(
+            sock_fd,
+            |socket| -> Result<
+                (PendingConnection, Arc<Endpoint>, SocketType),
+                (libc::c_int, libc::c_int),
+            > {
+                let node = plugin::node();
+                let net = plugin::simulator::<NetSim>();
+                let network = net.lock_network();
+
+                let endpoint = socket.endpoint.as_ref().ok_or((-1, libc::EINVAL))?;
+
+                if endpoint.peer.is_some() {
+                    // attempt to accept on a socket that is already connected.
+                    return Err((-1, libc::EINVAL));
+                }
 
-            // We can't simulate blocking accept in a single-threaded simulator, so if there is no
-            // connection waiting for us, just bail.
-            network
-                .accept_connect(socket.ty, node, endpoint.addr)
-                .map(|addr| (addr, socket.ty))
-                .ok_or((-1, libc::ECONNABORTED))
-        },
-    )
-    .unwrap_or_else(|e| {
-        trace!("socket not found: {}", e);
-        Result::Err((-1, libc::ENOTSOCK))
-    });
+                // We can't simulate blocking accept in a single-threaded simulator, so if there is no
+                // connection waiting for us, just bail.
+                network
+                    .accept_connect(socket.ty, node, endpoint.addr)
+                    .map(|conn| (conn, endpoint.clone(), (socket.domain, socket.ty)))
+                    .ok_or((-1, libc::ECONNABORTED))
+            },
+        )
+        .unwrap_or_else(|e| {
+            trace!("socket not found: {}", e);
+            Result::Err((-1, libc::ENOTSOCK))
+        });
 
-    let (remote_addr, proto) = match result {
+    let (pending, listener, (domain, proto)) = match result {
         Err((ret, err)) => {
             trace!("error status: {} {}", ret, err);
             set_errno(err);
@@ -361,18 +565,31 @@ unsafe fn accept_impl(
         }
         Ok(res) => res,
     };
+    let remote_addr = pending.addr;
 
-    write_socket_addr(address, address_len, remote_addr);
+    write_socket_addr(address, address_len, from_sim_addr(domain, remote_addr));
 
     let endpoint = Endpoint::connect_sync(proto, remote_addr)
         .expect("connection failure should already have been detected");
 
+    let conn = pending.ids.map(|(connecting, accepting)| StreamConn {
+        ep: listener,
+        peer: remote_addr,
+        local_id: accepting,
+        remote_id: connecting,
+        send_seq: 0,
+        recv_seq: 0,
+        unread: Default::default(),
+    });
+
     let fd = alloc_fd();
     let socket = SocketState {
+        domain,
         ty: libc::SOCK_STREAM,
         _placeholder_file: PlaceholderFileDes(fd),
         endpoint: Some(Arc::new(endpoint)),
         listening: false,
+        conn,
     };
 
     HostNetworkState::add_socket(fd, socket);
@@ -389,14 +606,13 @@ define_sys_interceptor!(
         let socket_addr = make_sockaddr(sock_addr, addr_len);
         trace!("bind({}, {:?})", sock_fd, socket_addr);
 
-        if socket_addr.is_ipv6() {
-            warn!("ipv6 not supported in simulator");
-            set_errno(libc::EADDRNOTAVAIL);
-            return -1;
-        }
-
         HostNetworkState::with_socket(sock_fd, |socket| {
             assert!(socket.endpoint.is_none(), "socket already bound");
+            let Some(socket_addr) = to_sim_addr(socket.domain, socket_addr) else {
+                warn!("ipv6 not supported in simulator");
+                set_errno(libc::EADDRNOTAVAIL);
+                return -1;
+            };
             match Endpoint::bind_sync(socket.ty, socket_addr) {
                 Ok(ep) => {
                     socket.endpoint = Some(Arc::new(ep));
@@ -439,6 +655,17 @@ define_sys_interceptor!(
                 return Err((-1, libc::EISCONN));
             }
 
+            let sock_addr = to_sim_addr(socket.domain, sock_addr).ok_or_else(|| {
+                warn!("ipv6 not supported in simulator");
+                (-1, libc::ENETUNREACH)
+            })?;
+            let sock_addr = match socket.ty {
+                libc::SOCK_STREAM => {
+                    plugin::simulator::<NetSim>().resolve_anycast(socket.ty, sock_addr)
+                }
+                _ => sock_addr,
+            };
+
             let ep = Endpoint::connect_sync(socket.ty, sock_addr).map_err(|e| match e.kind() {
                 io::ErrorKind::AddrInUse => (-1, libc::EADDRINUSE),
                 io::ErrorKind::AddrNotAvailable => (-1, libc::EADDRNOTAVAIL),
@@ -453,12 +680,41 @@ define_sys_interceptor!(
             // single-threaded simulator, nor do we need to. (The connection can just fail later if
             // the other end goes away).
             let net = plugin::simulator::<NetSim>();
-            let network = net.network.lock().unwrap();
-            if !network.signal_connect(socket.ty, ep.addr, sock_addr) {
+            let ids =
+                (socket.ty == libc::SOCK_STREAM).then(|| (net.next_tcp_id(), net.next_tcp_id()));
+            let mut network = net.lock_network();
+            if socket.ty == libc::SOCK_STREAM {
+                match network.check_connect(plugin::node(), socket.ty, sock_addr) {
+                    Err(Error::TimedOut(_)) => {
+                        // nothing runs while the call blocks, so the retransmissions of the
+                        // request are lost too: the whole timeout passes at once.
+                        let timeout = network.config().connect_timeout;
+                        drop(network);
+                        net.time.advance_blocked(timeout.unwrap_or(CONNECT_TIMEOUT));
+                        return Err((-1, libc::ETIMEDOUT));
+                    }
+                    Err(_) => return Err((-1, libc::ECONNREFUSED)),
+                    Ok(()) => {}
+                }
+            }
+            if !network.signal_connect(socket.ty, ep.addr, sock_addr, ids) {
                 return Err((-1, libc::ECONNREFUSED));
             }
 
-            socket.endpoint = Some(Arc::new(ep));
+            let ep = Arc::new(ep);
+            if let Some((connecting, accepting)) = ids {
+                network.register_tcp_id(plugin::node(), connecting);
+                socket.conn = Some(StreamConn {
+                    ep: ep.clone(),
+                    peer: sock_addr,
+                    local_id: connecting,
+                    remote_id: accepting,
+                    send_seq: 0,
+                    recv_seq: 0,
+                    unread: Default::default(),
+                });
+            }
+            socket.endpoint = Some(ep);
             Ok(0)
         })
         .unwrap_or_else(|e| {
@@ -494,10 +750,12 @@ define_sys_interceptor!(
         let fd = alloc_fd();
 
         let socket = SocketState {
+            domain,
             ty,
             _placeholder_file: PlaceholderFileDes(fd),
             endpoint: None,
             listening: false,
+            conn: None,
         };
 
         HostNetworkState::add_socket(fd, socket);
@@ -516,7 +774,8 @@ define_sys_interceptor!(
         // getsockname() on an un-bound socket does not actually return an error - instead it has
         // unspecified behavior. But we can just panic, since doing this would be a bug anyway.
         let addr: socket2::SockAddr = HostNetworkState::with_socket(sock_fd, |socket| {
-            socket.endpoint.as_ref().map(|ep| ep.local_addr().unwrap())
+            let ep = socket.endpoint.as_ref()?;
+            Some(from_sim_addr(socket.domain, ep.local_addr().unwrap()))
         })
         .expect("no such socket")
         .expect("getsockname() on un-bound socket")
@@ -533,6 +792,37 @@ define_sys_interceptor!(
     }
 );
 
+const UNKNOWN_SOCKOPT_HINT: &str = "allow the option with NetworkConfig::sockopt_policies";
+
+/// Handle a socket option the simulator does not know, according to its [`SockoptPolicy`].
+unsafe fn unknown_sockopt(
+    call: &'static str,
+    level: libc::c_int,
+    name: libc::c_int,
+    hint: &'static str,
+) -> libc::c_int {
+    let net = plugin::simulator::<NetSim>();
+    let policy = net.lock_network().config().sockopt_policy(level, name);
+    match policy {
+        SockoptPolicy::Warn => {
+            warn!("unhandled {} {} {}", call, level, name);
+            0
+        }
+        SockoptPolicy::Ignore => 0,
+        SockoptPolicy::Unsupported(policy) => {
+            let detail = format!("socket option level {level}, name {name}");
+            set_errno(unsupported_with(
+                policy,
+                call,
+                detail,
+                hint,
+                libc::ENOPROTOOPT,
+            ));
+            -1
+        }
+    }
+}
+
 define_sys_interceptor!(
     fn getsockopt(
         socket: libc::c_int,
@@ -547,10 +837,7 @@ define_sys_interceptor!(
             // skip returning any value here since Sui only uses it to log an error anyway
             (libc::SOL_SOCKET, libc::SO_RCVBUF) | (libc::SOL_SOCKET, libc::SO_SNDBUF) => 0,
 
-            _ => {
-                warn!("unhandled getsockopt {} {}", level, name);
-                0
-            }
+            _ => unknown_sockopt("getsockopt", level, name, UNKNOWN_SOCKOPT_HINT),
         }
     }
 );
@@ -565,7 +852,13 @@ define_sys_interceptor!(
     ) -> libc::c_int {
         trace!("setsockopt({}, {}, {})", socket, level, name);
         match (level, name) {
-            (libc::IPPROTO_IPV6, _) => unimplemented!("ipv6 not supported"),
+            // ipv6 sockets are always dual-stack in the simulator, so that v4-mapped addresses
+            // can be used.
+            (libc::IPPROTO_IPV6, libc::IPV6_V6ONLY) => 0,
+            (libc::IPPROTO_IPV6, _) => {
+                let hint = "ipv6 sockets are dual-stack, and only support IPV6_V6ONLY";
+                unknown_sockopt("setsockopt", level, name, hint)
+            }
 
             // called by rust std::net::TcpListener::bind
             // No need to actually emulate SO_REUSEADDR behavior (for now).
@@ -604,10 +897,7 @@ define_sys_interceptor!(
             #[cfg(target_os = "macos")]
             (libc::IPPROTO_TCP, libc::TCP_KEEPALIVE) => 0,
 
-            _ => {
-                warn!("unhandled setsockopt {} {}", level, name);
-                0
-            }
+            _ => unknown_sockopt("setsockopt", level, name, UNKNOWN_SOCKOPT_HINT),
         }
     }
 );
@@ -619,7 +909,31 @@ define_sys_interceptor!(
         len: libc::size_t,
         flags: libc::c_int,
     ) -> libc::ssize_t {
-        unimplemented!("simulator error: send() should have been handled by tokio");
+        let iov = libc::iovec {
+            iov_base: buf as *mut libc::c_void,
+            iov_len: len,
+        };
+        HostNetworkState::with_socket(sockfd, |socket| -> CResult<libc::ssize_t> {
+            if socket.ty == libc::SOCK_STREAM {
+                return stream_write(socket, std::slice::from_ref(&iov));
+            }
+            let peer = socket
+                .endpoint
+                .as_ref()
+                .and_then(|ep| ep.peer)
+                .ok_or((-1, libc::EDESTADDRREQ))?;
+            // there are no signals to suppress in the simulator.
+            Ok(send_impl(socket, &peer, flags & !libc::MSG_NOSIGNAL, &iov))
+        })
+        .unwrap_or_else(|e| {
+            trace!("socket not found: {}", e);
+            CResult::Err((-1, libc::ENOTSOCK))
+        })
+        .unwrap_or_else(|(ret, err)| {
+            trace!("error status: {} {}", ret, err);
+            set_errno(err);
+            ret
+        })
     }
 );
 
@@ -632,26 +946,17 @@ define_sys_interceptor!(
         dest_addr: *const libc::sockaddr,
         addrlen: libc::socklen_t,
     ) -> libc::ssize_t {
-        unimplemented!("simulator error: sendto() should have been handled by tokio");
+        let hint = "send with the simulated tokio sockets, or with sendmsg()";
+        set_errno(unsupported(
+            "sendto",
+            "sendto() is not simulated",
+            hint,
+            libc::EOPNOTSUPP,
+        ));
+        -1
     }
 );
 
-enum UDPMessage {
-    Payload(Vec<u8>),
-}
-
-impl UDPMessage {
-    fn payload(v: Vec<u8>) -> Box<UDPMessage> {
-        Box::new(UDPMessage::Payload(v))
-    }
-
-    fn into_payload(self) -> Vec<u8> {
-        match self {
-            Self::Payload(v) => v,
-        }
-    }
-}
-
 unsafe fn msg_hdr_to_socket(msg: &libc::msghdr) -> SocketAddr {
     socket2::SockAddr::try_init(|storage, len| {
         std::ptr::copy_nonoverlapping(
@@ -689,7 +994,6 @@ unsafe fn send_impl(
     // interfaces/ip addresses. However, simulated nodes don't have multiple IPs, so this doesn't
     // affect us.
     let slice = std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len);
-    let msg = UDPMessage::payload(slice.into());
 
     // If we need to handle sending from unconnected sockets, we can make an ephemeral
     // endpoint.
@@ -698,7 +1002,14 @@ unsafe fn send_impl(
         .as_ref()
         .expect("sendmsg on unconnected sockets not supported");
 
-    ep.send_to_raw_sync(*dst_addr, dst_addr.port().into(), Payload::new_udp(msg))
+    let Some(dst_addr) = to_sim_addr(socket.domain, *dst_addr) else {
+        trace!("udp send to unsupported ipv6 address {}", dst_addr);
+        // udp sends to unreachable destinations are silently dropped.
+        return slice.len() as libc::ssize_t;
+    };
+
+    let payload = Payload::udp(bytes::Bytes::copy_from_slice(slice));
+    ep.send_to_raw_sync(dst_addr, dst_addr.port().into(), payload)
         .tap_err(|e| {
             trace!("udp send error: {}", e);
         })
@@ -764,55 +1075,69 @@ define_sys_interceptor!(
 
 type CResult<T> = Result<T, (T, libc::c_int)>;
 
-fn validate_recv(socket: &SocketState, flags: libc::c_int) -> Arc<Endpoint> {
+fn validate_recv(socket: &SocketState, flags: libc::c_int) -> (Arc<Endpoint>, libc::c_int) {
     assert_eq!(
         socket.ty,
         libc::SOCK_DGRAM,
-        "only UDP is supported in recvmsg/recvmmsg {:?}",
+        "only UDP is supported in recv/recvfrom/recvmsg/recvmmsg {:?}",
         socket
     );
 
-    if flags != 0 {
-        warn!("unsupported flags to sendmsg/sendmmsg: {:x}", flags);
+    // receive calls never block, so MSG_DONTWAIT makes no difference.
+    if flags & !libc::MSG_DONTWAIT != 0 {
+        warn!(
+            "unsupported flags to recv/recvfrom/recvmsg/recvmmsg: {:x}",
+            flags
+        );
     }
 
     // i'm not exactly clear what errno should be returned if you call recvmsg() without
     // bind(), so just assert. Working code won't trigger this.
-    socket
+    let ep = socket
         .endpoint
         .as_ref()
         .expect("recvmsg on un-bound socket")
-        .clone()
+        .clone();
+    (ep, socket.domain)
 }
 
-unsafe fn recv_impl(ep: &Endpoint, msg: *mut libc::msghdr) -> CResult<libc::ssize_t> {
+unsafe fn recv_impl(
+    ep: &Endpoint,
+    domain: libc::c_int,
+    msg: *mut libc::msghdr,
+) -> CResult<libc::ssize_t> {
     let udp_tag = ep.udp_tag().expect("recvmsg on un-bound socket");
 
     let (payload, from) = ep
         .recv_from_raw_sync(udp_tag)
         .map_err(|err| match err.kind() {
             io::ErrorKind::WouldBlock => (-1, libc::EAGAIN),
-            _ => todo!("unhandled error case"),
+            _ => {
+                let detail = format!("receive error: {err}");
+                let hint = "the error has no errno equivalent in the simulator yet";
+                (-1, unsupported("recvmsg", detail, hint, libc::EIO))
+            }
         })?;
 
     let msg = &mut *msg;
 
     if !msg.msg_name.is_null() {
-        let from: socket2::SockAddr = from.into();
-        std::ptr::copy_nonoverlapping(
-            from.as_ptr() as *const u8,
-            msg.msg_name as *mut u8,
-            from.len() as usize,
+        write_socket_addr(
+            msg.msg_name as *const libc::sockaddr,
+            &mut msg.msg_namelen,
+            from_sim_addr(domain, from),
         );
     }
 
     assert!(payload.is_udp());
 
-    let payload = payload
-        .data
-        .downcast::<UDPMessage>()
-        .expect("message was not UDPMessage")
-        .into_payload();
+    // verify the whole payload, and then the copy of the part that fits, which may be truncated.
+    let describe = || format!("{from} -> {}, tag={}, in recvmsg", ep.addr, Tag(udp_tag));
+    if let Some(bytes) = payload.bytes() {
+        payload.verify_checksum(bytes, describe);
+    }
+    let verify_copy = payload.checksum().is_some();
+    let payload = payload.into_bytes().expect("udp message is not bytes");
 
     assert_eq!(msg.msg_iovlen, 1, "scatter/gather unsupported");
 
@@ -822,6 +1147,11 @@ unsafe fn recv_impl(ep: &Endpoint, msg: *mut libc::msghdr) -> CResult<libc::ssiz
         msg.msg_flags |= libc::MSG_TRUNC;
     }
     std::ptr::copy_nonoverlapping(payload.as_ptr(), iov.iov_base as *mut u8, copy_len);
+    if verify_copy {
+        let copied = std::slice::from_raw_parts(iov.iov_base as *const u8, copy_len);
+        let expected = network::checksum(&payload[..copy_len]);
+        network::verify_checksum(Some(expected), copied, describe);
+    }
 
     // TODO: create control messages (e.g. original destination addr)
     msg.msg_control = std::ptr::null_mut();
@@ -833,8 +1163,8 @@ unsafe fn recv_impl(ep: &Endpoint, msg: *mut libc::msghdr) -> CResult<libc::ssiz
 define_sys_interceptor!(
     fn recvmsg(sockfd: libc::c_int, msg: *mut libc::msghdr, flags: libc::c_int) -> libc::ssize_t {
         HostNetworkState::with_socket(sockfd, |socket| -> CResult<libc::ssize_t> {
-            let ep = validate_recv(socket, flags);
-            recv_impl(&ep, msg)
+            let (ep, domain) = validate_recv(socket, flags);
+            recv_impl(&ep, domain, msg)
         })
         .unwrap_or_else(|e| {
             trace!("socket not found: {}", e);
@@ -849,6 +1179,239 @@ define_sys_interceptor!(
     }
 );
 
+/// Receive into a single buffer, for the receive calls that do not take a `msghdr`.
+unsafe fn recv_buf_impl(
+    ep: &Endpoint,
+    domain: libc::c_int,
+    buf: *mut libc::c_void,
+    len: libc::size_t,
+    src_addr: *mut libc::sockaddr,
+    addrlen: *mut libc::socklen_t,
+) -> CResult<libc::ssize_t> {
+    let mut iov = libc::iovec {
+        iov_base: buf,
+        iov_len: len,
+    };
+    let mut msg: libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !src_addr.is_null() && !addrlen.is_null() {
+        msg.msg_name = src_addr as *mut libc::c_void;
+        msg.msg_namelen = *addrlen;
+    }
+    let copied = recv_impl(ep, domain, &mut msg)?;
+    if !msg.msg_name.is_null() {
+        *addrlen = msg.msg_namelen;
+    }
+    Ok(copied)
+}
+
+define_sys_interceptor!(
+    fn recvfrom(
+        sockfd: libc::c_int,
+        buf: *mut libc::c_void,
+        len: libc::size_t,
+        flags: libc::c_int,
+        src_addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t,
+    ) -> libc::ssize_t {
+        HostNetworkState::with_socket(sockfd, |socket| -> CResult<libc::ssize_t> {
+            if socket.ty == libc::SOCK_STREAM {
+                return stream_read(
+                    socket,
+                    &[libc::iovec {
+                        iov_base: buf,
+                        iov_len: len,
+                    }],
+                );
+            }
+            let (ep, domain) = validate_recv(socket, flags);
+            recv_buf_impl(&ep, domain, buf, len, src_addr, addrlen)
+        })
+        .unwrap_or_else(|e| {
+            trace!("socket not found: {}", e);
+            CResult::Err((-1, libc::ENOTSOCK))
+        })
+        .unwrap_or_else(|(ret, err)| {
+            trace!("error status: {} {}", ret, err);
+            set_errno(err);
+            ret
+        })
+    }
+);
+
+define_sys_interceptor!(
+    fn recv(
+        sockfd: libc::c_int,
+        buf: *mut libc::c_void,
+        len: libc::size_t,
+        flags: libc::c_int,
+    ) -> libc::ssize_t {
+        HostNetworkState::with_socket(sockfd, |socket| -> CResult<libc::ssize_t> {
+            if socket.ty == libc::SOCK_STREAM {
+                return stream_read(
+                    socket,
+                    &[libc::iovec {
+                        iov_base: buf,
+                        iov_len: len,
+                    }],
+                );
+            }
+            let (ep, domain) = validate_recv(socket, flags);
+            let (src_addr, addrlen) = (std::ptr::null_mut(), std::ptr::null_mut());
+            recv_buf_impl(&ep, domain, buf, len, src_addr, addrlen)
+        })
+        .unwrap_or_else(|e| {
+            trace!("socket not found: {}", e);
+            CResult::Err((-1, libc::ENOTSOCK))
+        })
+        .unwrap_or_else(|(ret, err)| {
+            trace!("error status: {} {}", ret, err);
+            set_errno(err);
+            ret
+        })
+    }
+);
+
+/// Write `bufs` to the connection of a stream socket, as a single segment.
+unsafe fn stream_write(socket: &mut SocketState, bufs: &[libc::iovec]) -> CResult<libc::ssize_t> {
+    let conn = socket.conn.as_mut().ok_or((-1, libc::ENOTCONN))?;
+    let mut data = Vec::with_capacity(bufs.iter().map(|iov| iov.iov_len).sum());
+    for iov in bufs.iter().filter(|iov| iov.iov_len > 0) {
+        data.extend_from_slice(std::slice::from_raw_parts(
+            iov.iov_base as *const u8,
+            iov.iov_len,
+        ));
+    }
+    if data.is_empty() {
+        return Ok(0);
+    }
+    let len = data.len();
+    let tag = (conn.remote_id as u64) << 32 | conn.send_seq as u64;
+    conn.ep
+        .send_to_raw_sync(conn.peer, tag, Payload::tcp_data(conn.send_seq, data))
+        .map_err(|e| {
+            trace!("stream write error: {}", e);
+            // the write can't wait for a clogged link to recover, as it would on a real host.
+            match Error::from_io(&e) {
+                Some(Error::LinkClogged(_)) => (-1, libc::EHOSTUNREACH),
+                _ => (-1, libc::ECONNRESET),
+            }
+        })?;
+    conn.send_seq += 1;
+    Ok(len as _)
+}
+
+/// Read from the connection of a stream socket into `bufs`.
+///
+/// Like the other receive calls, reads never block: they fail with `EAGAIN` until the next
+/// segment arrives, and return 0 once the peer has closed the connection and the segments it
+/// sent before that were read.
+unsafe fn stream_read(socket: &mut SocketState, bufs: &[libc::iovec]) -> CResult<libc::ssize_t> {
+    let conn = socket.conn.as_mut().ok_or((-1, libc::ENOTCONN))?;
+    if conn.unread.is_empty() {
+        let ep = &conn.ep;
+        let tag = (conn.local_id as u64) << 32 | conn.recv_seq as u64;
+        let mut network = ep.net.lock_network();
+        match network.recv_sync(ep.node, ep.proto, ep.addr, tag) {
+            Some(msg) => {
+                conn.recv_seq += 1;
+                conn.unread = msg.data.into_bytes().expect("tcp segment is not bytes");
+            }
+            None => {
+                let flow = Flow {
+                    proto: ep.proto,
+                    src: ep.addr,
+                    dst: conn.peer,
+                };
+                if network.is_tcp_session_live(&flow, conn.remote_id) {
+                    return Err((-1, libc::EAGAIN));
+                }
+                // later segments of a closed connection are still queued while the next one is
+                // delayed, unless the connection was broken, which loses them.
+                let local_id = conn.local_id as u64;
+                let queued =
+                    network.queue_depth(ep.node, ep.proto, ep.addr, |tag| tag >> 32 == local_id);
+                if queued.is_some_and(|(msgs, _)| msgs > 0) && !network.is_broken(&flow) {
+                    return Err((-1, libc::EAGAIN));
+                }
+                trace!("stream {} closed", flow);
+                return Ok(0);
+            }
+        }
+    }
+    let mut copied = 0;
+    for iov in bufs.iter().filter(|iov| iov.iov_len > 0) {
+        let len = std::cmp::min(iov.iov_len, conn.unread.len());
+        std::ptr::copy_nonoverlapping(conn.unread.as_ptr(), iov.iov_base as *mut u8, len);
+        conn.unread.advance(len);
+        copied += len;
+    }
+    Ok(copied as _)
+}
+
+/// Forward the calls on file descriptors that are not simulated sockets to libc.
+macro_rules! stream_or_forward {
+    ($fd:expr, $call:ident($($arg:expr),*), $forward:expr) => {
+        match HostNetworkState::try_with_socket($fd, |socket| $call(socket, $($arg),*)) {
+            Some(res) => res.unwrap_or_else(|(ret, err)| {
+                trace!("error status: {} {}", ret, err);
+                set_errno(err);
+                ret
+            }),
+            None => $forward,
+        }
+    };
+}
+
+define_sys_interceptor!(
+    fn write(fd: libc::c_int, buf: *const libc::c_void, count: libc::size_t) -> libc::ssize_t {
+        let iov = libc::iovec {
+            iov_base: buf as *mut libc::c_void,
+            iov_len: count,
+        };
+        stream_or_forward!(
+            fd,
+            stream_write(std::slice::from_ref(&iov)),
+            NEXT_DL_SYM(fd, buf, count)
+        )
+    }
+);
+
+define_sys_interceptor!(
+    fn writev(fd: libc::c_int, iov: *const libc::iovec, iovcnt: libc::c_int) -> libc::ssize_t {
+        stream_or_forward!(
+            fd,
+            stream_write(std::slice::from_raw_parts(iov, iovcnt as usize)),
+            NEXT_DL_SYM(fd, iov, iovcnt)
+        )
+    }
+);
+
+define_sys_interceptor!(
+    fn read(fd: libc::c_int, buf: *mut libc::c_void, count: libc::size_t) -> libc::ssize_t {
+        let iov = libc::iovec {
+            iov_base: buf,
+            iov_len: count,
+        };
+        stream_or_forward!(
+            fd,
+            stream_read(std::slice::from_ref(&iov)),
+            NEXT_DL_SYM(fd, buf, count)
+        )
+    }
+);
+
+define_sys_interceptor!(
+    fn readv(fd: libc::c_int, iov: *const libc::iovec, iovcnt: libc::c_int) -> libc::ssize_t {
+        stream_or_forward!(
+            fd,
+            stream_read(std::slice::from_raw_parts(iov, iovcnt as usize)),
+            NEXT_DL_SYM(fd, iov, iovcnt)
+        )
+    }
+);
+
 #[cfg(target_os = "linux")]
 define_sys_interceptor!(
     fn recvmmsg(
@@ -859,13 +1422,13 @@ define_sys_interceptor!(
         timeout: *mut libc::timespec,
     ) -> libc::c_int {
         HostNetworkState::with_socket(sockfd, |socket| -> CResult<libc::c_int> {
-            let ep = validate_recv(socket, flags);
+            let (ep, domain) = validate_recv(socket, flags);
             assert!(vlen >= 1);
 
             let msgvec = &mut *msgvec;
             let msgs = std::slice::from_raw_parts_mut(msgvec as *mut libc::mmsghdr, vlen as _);
 
-            msgs[0].msg_len = recv_impl(&ep, &mut msgs[0].msg_hdr as *mut libc::msghdr)
+            msgs[0].msg_len = recv_impl(&ep, domain, &mut msgs[0].msg_hdr as *mut libc::msghdr)
                 .map_err(|(ret, errno)| (ret.try_into().unwrap(), errno))?
                 .try_into()
                 .unwrap();
@@ -890,15 +1453,18 @@ impl plugin::Simulator for NetSim {
         NetSim {
             network: Mutex::new(Network::new(rand.clone(), time.clone(), config.net.clone())),
             rand: rand.clone(),
+            rpc_rand: rand.stream(RngStream::Named("rpc")),
+            churn_rand: rand.stream(RngStream::Named("churn")),
             time: time.clone(),
             host_state: Default::default(),
             // tcp ids start at 1, 0 is used for new connections (see poll_accept_internal)
             next_tcp_id: AtomicU32::new(1),
+            tag_names: Default::default(),
         }
     }
 
     fn create_node(&self, id: NodeId) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.insert_node(id);
     }
 
@@ -914,12 +1480,12 @@ impl plugin::Simulator for NetSim {
 impl NetSim {
     /// Get the statistics.
     pub fn stat(&self) -> Stat {
-        self.network.lock().unwrap().stat().clone()
+        self.lock_network().stat().clone()
     }
 
     /// Update network configurations.
     pub fn update_config(&self, f: impl FnOnce(&mut NetworkConfig)) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.update_config(f);
     }
 
@@ -927,14 +1493,78 @@ impl NetSim {
     ///
     /// All connections will be closed.
     pub fn reset_node(&self, id: NodeId) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.reset_node(id);
     }
 
+    /// List the sockets of a node, ordered by file descriptor, like `ss` would on a real host.
+    ///
+    /// This shows the simulated kernel's view of the node, e.g. to debug a stuck network stack:
+    /// which sockets are open, what they are bound and connected to, and how many messages
+    /// are waiting to be read. Sockets created with [`Endpoint`] directly have no file
+    /// descriptor and are not listed.
+    pub fn dump_sockets(&self, node: NodeId) -> Vec<SocketInfo> {
+        let sockets: Vec<_> = {
+            let host_state = self.host_state.lock().unwrap();
+            host_state
+                .sockets
+                .iter()
+                .filter(|((id, _), _)| *id == node)
+                .map(|((_, fd), socket)| {
+                    (*fd, socket.ty, socket.listening, socket.endpoint.clone())
+                })
+                .collect()
+        };
+        let network = self.lock_network();
+        let mut infos: Vec<_> = sockets
+            .into_iter()
+            .map(|(fd, ty, listening, endpoint)| {
+                let Some(ep) = endpoint else {
+                    return SocketInfo {
+                        fd,
+                        ty,
+                        status: SocketStatus::Unbound,
+                        local: None,
+                        peer: None,
+                        rx_queue: 0,
+                    };
+                };
+                let status = if listening {
+                    SocketStatus::Listening
+                } else if ep.peer.is_some() {
+                    SocketStatus::Connected
+                } else {
+                    SocketStatus::Bound
+                };
+                let live_tcp_ids = ep.live_tcp_ids.lock().unwrap();
+                let owns = |tag: u64| match &ep.tags {
+                    Some(tags) => tags.contains(&tag),
+                    None if ty == libc::SOCK_STREAM && !listening => {
+                        live_tcp_ids.contains(&((tag >> 32) as u32))
+                    }
+                    None => true,
+                };
+                let (msgs, connections) = network
+                    .queue_depth(node, ep.proto, ep.addr, owns)
+                    .unwrap_or_default();
+                SocketInfo {
+                    fd,
+                    ty,
+                    status,
+                    local: Some(ep.addr),
+                    peer: ep.peer,
+                    rx_queue: if listening { connections } else { msgs },
+                }
+            })
+            .collect();
+        infos.sort_by_key(|info| info.fd);
+        infos
+    }
+
     /// Delete a node.
     pub fn delete_node(&self, id: NodeId) {
         debug!("delete_node {id}");
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.delete_node(id);
         drop(network);
 
@@ -947,510 +1577,3399 @@ impl NetSim {
 
     /// Set IP address of a node.
     pub fn set_ip(&self, node: NodeId, ip: IpAddr) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.set_ip(node, ip);
     }
 
     /// Get IP address of a node.
     pub fn get_ip(&self, node: NodeId) -> Option<IpAddr> {
-        let network = self.network.lock().unwrap();
+        let network = self.lock_network();
         network.get_ip(node)
     }
 
     /// Connect a node to the network.
     pub fn connect(&self, id: NodeId) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.unclog_node(id);
     }
 
     /// Disconnect a node from the network.
     pub fn disconnect(&self, id: NodeId) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.clog_node(id);
     }
 
     /// Connect a pair of nodes.
     pub fn connect2(&self, node1: NodeId, node2: NodeId) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.unclog_link(node1, node2);
         network.unclog_link(node2, node1);
     }
 
     /// Disconnect a pair of nodes.
+    ///
+    /// This clogs the link in each direction, which [`partitions`](Self::partitions) lists as
+    /// two partitions.
     pub fn disconnect2(&self, node1: NodeId, node2: NodeId) {
-        let mut network = self.network.lock().unwrap();
+        let mut network = self.lock_network();
         network.clog_link(node1, node2);
         network.clog_link(node2, node1);
     }
 
-    async fn rand_delay(&self) {
-        let delay = Duration::from_micros(self.rand.with(|rng| rng.gen_range(0..5)));
-        self.time.sleep(delay).await;
+    /// Connect the link from `src` to `dst`, leaving the reverse direction untouched.
+    pub fn connect_one_way(&self, src: NodeId, dst: NodeId) {
+        let mut network = self.lock_network();
+        network.unclog_link(src, dst);
     }
 
-    /// Get the next unused tcp id.
-    pub fn next_tcp_id(&self) -> u32 {
-        self.next_tcp_id.fetch_add(1, Ordering::SeqCst)
+    /// Disconnect the link from `src` to `dst`, leaving the reverse direction untouched.
+    ///
+    /// Packets sent by `src` to `dst` are refused, while `dst` can still reach `src`.
+    pub fn disconnect_one_way(&self, src: NodeId, dst: NodeId) {
+        let mut network = self.lock_network();
+        network.clog_link(src, dst);
     }
-}
-
-/// An endpoint.
-pub struct Endpoint {
-    net: Arc<NetSim>,
-    node: NodeId,
-    addr: SocketAddr,
-    proto: libc::c_int,
-    peer: Option<SocketAddr>,
-    live_tcp_ids: Mutex<HashSet<u32>>,
-}
 
-impl std::fmt::Debug for Endpoint {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        fmt.debug_struct("Endpoint")
-            .field("node", &self.node)
-            .field("addr", &self.addr)
-            .field("peer", &self.peer)
-            .finish()
+    /// Override the latency of packets sent from `src` to `dst`.
+    ///
+    /// The reverse direction is not affected. Pass `None` to fall back to the configured latency.
+    pub fn set_one_way_latency(
+        &self,
+        src: NodeId,
+        dst: NodeId,
+        latency: Option<LatencyDistribution>,
+    ) {
+        let mut network = self.lock_network();
+        network.set_link_latency(src, dst, latency);
     }
-}
 
-impl Endpoint {
-    /// Bind synchronously
-    pub fn bind_sync(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
-        let net = plugin::simulator::<NetSim>();
-        let node = plugin::node();
-        let addr = addr.to_socket_addrs()?.next().unwrap();
-        let addr = net.network.lock().unwrap().bind(node, proto, addr)?;
-        let ep = Endpoint {
-            net,
-            node,
-            addr,
-            proto,
-            peer: None,
-            live_tcp_ids: Default::default(),
-        };
-        trace!("Endpoint::bind_sync() -> {:?}", ep);
-        Ok(ep)
+    /// Override the packet loss rate of packets sent from `src` to `dst`.
+    ///
+    /// The rate replaces both the udp and tcp loss rates from the [`NetworkConfig`]. The reverse
+    /// direction is not affected. Pass `None` to fall back to the configured rates. Returns an
+    /// `InvalidData` error if the rate is not a probability.
+    pub fn set_one_way_packet_loss(
+        &self,
+        src: NodeId,
+        dst: NodeId,
+        rate: Option<f64>,
+    ) -> io::Result<()> {
+        if let Some(rate) = rate {
+            config::check_probability("packet loss rate", rate)?;
+        }
+        let mut network = self.lock_network();
+        network.set_link_packet_loss(src, dst, rate);
+        Ok(())
     }
 
-    /// return the tag used to send to this endpooint, for udp connections only.
-    /// (It is the same as the udp port number).
-    /// port is 0 we panic
-    pub fn udp_tag(&self) -> io::Result<u64> {
-        let port = self.addr.port();
-        if port == 0 {
-            Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                "endpoint is not bound",
-            ))
-        } else {
-            Ok(port as u64)
+    /// Use a bursty loss model for udp packets sent from `src` to `dst`.
+    ///
+    /// The model replaces the udp loss rates and loss model from the [`NetworkConfig`], and the
+    /// rate set with [`set_one_way_packet_loss`](Self::set_one_way_packet_loss). The reverse
+    /// direction is not affected. Pass `None` to fall back to the other settings. Returns an
+    /// `InvalidData` error if a parameter of the model is not a probability.
+    pub fn set_one_way_loss_model(
+        &self,
+        src: NodeId,
+        dst: NodeId,
+        model: Option<GilbertElliott>,
+    ) -> io::Result<()> {
+        if let Some(model) = &model {
+            model.validate()?;
         }
+        let mut network = self.lock_network();
+        network.set_link_loss_model(src, dst, model);
+        Ok(())
     }
 
-    /// Creates a [`Endpoint`] from the given address.
-    pub async fn bind(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
-        let net = plugin::simulator::<NetSim>();
-        let node = plugin::node();
-        let addr = addr.to_socket_addrs()?.next().unwrap();
-        net.rand_delay().await;
-        let addr = net.network.lock().unwrap().bind(node, proto, addr)?;
-        Ok(Endpoint {
-            net,
-            node,
-            addr,
-            proto,
-            peer: None,
-            live_tcp_ids: Default::default(),
-        })
+    /// Add a cluster of nodes with its own network configuration, or replace the configuration of
+    /// an existing cluster.
+    ///
+    /// Clusters compose independently configured systems in one simulation, e.g. a validator
+    /// cluster and an indexer cluster, sharing the same clock. The latency and packet loss
+    /// settings of `config` apply to packets between the nodes of the cluster, which are added
+    /// with [`set_cluster`](Self::set_cluster). Clusters are isolated from each other unless they
+    /// are linked with [`set_wan_link`](Self::set_wan_link). Nodes outside of all clusters use the
+    /// global configuration and reach every node.
+    ///
+    /// Per-link overrides such as [`set_one_way_latency`](Self::set_one_way_latency) take
+    /// precedence over the cluster settings.
+    pub fn add_cluster(&self, name: &str, config: NetworkConfig) {
+        let mut network = self.lock_network();
+        network.add_cluster(name, config);
     }
 
-    /// Connects this [`Endpoint`] to a remote address.
-    pub async fn connect(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
-        let net = plugin::simulator::<NetSim>();
-        net.rand_delay().await;
-        Self::connect_sync(proto, addr)
+    /// Move a node into a cluster, or out of all clusters with `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cluster was not added with [`add_cluster`](Self::add_cluster).
+    pub fn set_cluster(&self, node: NodeId, cluster: Option<&str>) {
+        let mut network = self.lock_network();
+        network.set_cluster(node, cluster);
     }
 
-    /// For libc::connect()
-    pub fn connect_sync(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
-        let net = plugin::simulator::<NetSim>();
-        let node = plugin::node();
-        let peer = addr.to_socket_addrs()?.next().unwrap();
-        let addr = if peer.ip().is_loopback() {
-            SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
-        } else {
-            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
-        };
-        let addr = net.network.lock().unwrap().bind(node, proto, addr)?;
-        Ok(Endpoint {
-            net,
-            node,
-            addr,
-            proto,
-            peer: Some(peer),
-            live_tcp_ids: Default::default(),
-        })
+    /// Get the cluster of a node.
+    pub fn cluster(&self, node: NodeId) -> Option<String> {
+        let network = self.lock_network();
+        network.cluster(node)
     }
 
-    /// Allocate a new tcp id number for this node. Ids are never reused.
-    pub fn allocate_local_tcp_id(&self) -> u32 {
-        let id = self.net.next_tcp_id();
-        trace!(
-            "Allocate local tcp id {} to node {} address {}",
-            id,
-            self.node,
-            self.addr.ip()
-        );
-        self.live_tcp_ids.lock().unwrap().insert(id);
-        self.net
-            .network
-            .lock()
-            .unwrap()
-            .register_tcp_id(self.node, id);
-        id
+    /// Link two clusters, in both directions. Pass `None` to isolate them again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either cluster was not added with [`add_cluster`](Self::add_cluster).
+    pub fn set_wan_link(&self, cluster1: &str, cluster2: &str, link: Option<WanLink>) {
+        let mut network = self.lock_network();
+        network.set_wan_link(cluster1, cluster2, link);
     }
 
-    /// Remove a tcp id number from this node.
-    pub fn deregister_tcp_id(&self, remote_sock: &SocketAddr, id: u32) {
+    /// Make the link between two nodes flap, alternating between connected and disconnected.
+    ///
+    /// The link starts connected; the time spent in each state is sampled from `up` and `down`
+    /// respectively. Flapping stops, leaving the link connected, when the returned
+    /// [`LinkFlapper`] is dropped or either node is deleted. The flapper clogs the link on its
+    /// own, so a link the test disconnected stays disconnected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `up` or `down` is always zero, which would flap forever at the same instant.
+    pub fn flap_link(
+        self: &Arc<Self>,
+        node1: NodeId,
+        node2: NodeId,
+        up: LatencyDistribution,
+        down: LatencyDistribution,
+    ) -> LinkFlapper {
         assert!(
-            self.live_tcp_ids.lock().unwrap().remove(&id),
-            "unknown tcp id {}",
-            id
+            !up.mean().is_zero() && !down.mean().is_zero(),
+            "a link cannot flap with zero durations"
         );
-        self.net
-            .network
-            .lock()
-            .unwrap()
-            .deregister_tcp_id(self.node, self.proto, remote_sock, id);
+        let state = Arc::new(FlapState {
+            node1,
+            node2,
+            up,
+            down,
+            stopped: AtomicBool::new(false),
+            down_now: AtomicBool::new(false),
+        });
+        Self::schedule_flap(self.clone(), state.clone(), true);
+        LinkFlapper {
+            net: self.clone(),
+            state,
+        }
     }
 
-    /// Returns the local socket address.
-    pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        Ok(self.addr)
+    fn schedule_flap(net: Arc<Self>, state: Arc<FlapState>, up: bool) {
+        let dist = if up { &state.up } else { &state.down };
+        let deadline = net.time.now_instant() + dist.sample(&mut net.rand.clone());
+        let time = net.time.clone();
+        // run on the main node so that the timer survives restarts of the flapping nodes.
+        time.add_timer_for_node(NodeId::zero(), deadline, move || {
+            if state.stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            {
+                let mut network = net.lock_network();
+                if !network.contains_node(state.node1) || !network.contains_node(state.node2) {
+                    return;
+                }
+                if up {
+                    debug!("flap down: {} <-> {}", state.node1, state.node2);
+                    network.fault_clog(PartitionTarget::Link(state.node1, state.node2));
+                    network.fault_clog(PartitionTarget::Link(state.node2, state.node1));
+                } else {
+                    debug!("flap up: {} <-> {}", state.node1, state.node2);
+                    network.fault_unclog(PartitionTarget::Link(state.node1, state.node2));
+                    network.fault_unclog(PartitionTarget::Link(state.node2, state.node1));
+                }
+                state.down_now.store(up, Ordering::SeqCst);
+            }
+            Self::schedule_flap(net, state, !up);
+        });
     }
 
-    /// Returns the socket address of the remote peer this socket was connected to.
-    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.peer
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not connected"))
+    /// Break a single tcp connection, identified by its flow in either direction, without
+    /// affecting other connections between the same nodes.
+    ///
+    /// Reads and writes on both ends of the connection then fail with `ConnectionReset`, so that
+    /// the application has to reconnect. Data still in flight, or sent but not yet read, is lost.
+    pub fn break_connection(&self, flow: &Flow) {
+        let mut network = self.lock_network();
+        network.break_connection(flow);
     }
 
-    /// Sends data with tag on the socket to the given address.
+    /// Break a tcp connection once `bytes` bytes have been sent in the direction of `flow`.
     ///
-    /// # Example
-    /// ```
-    /// use msim::{runtime::Runtime, net::{Endpoint, network::Payload}};
+    /// Writes are never split: all writes that fit in `bytes` are delivered, and the first write
+    /// that does not fit fails and breaks the connection, see [`break_connection`].
     ///
-    /// Runtime::new().block_on(async {
-    ///     let net = Endpoint::bind(libc::SOCK_STREAM, "127.0.0.1:4242").await.unwrap();
-    ///     net.send_to("127.0.0.1:4242", 0, Payload::new_udp(Box::new([0; 10]))).await.expect("couldn't send data");
-    /// });
-    /// ```
-    pub async fn send_to(
-        &self,
-        dst: impl ToSocketAddrs,
-        tag: u64,
-        payload: Payload,
-    ) -> io::Result<()> {
-        let dst = dst.to_socket_addrs()?.next().unwrap();
-        self.send_to_raw(dst, tag, payload).await
+    /// [`break_connection`]: NetSim::break_connection
+    pub fn break_connection_after(&self, flow: &Flow, bytes: u64) {
+        let mut network = self.lock_network();
+        network.break_connection_after(flow, bytes);
     }
 
-    /// Receives a single message with given tag on the socket.
-    /// On success, returns the number of bytes read and the origin.
+    /// Break a tcp connection at `deadline` in virtual time, see [`break_connection`].
     ///
-    /// # Example
-    /// ```no_run
-    /// use msim::{runtime::Runtime, net::Endpoint};
+    /// [`break_connection`]: NetSim::break_connection
+    pub fn break_connection_at(self: &Arc<Self>, flow: Flow, deadline: Instant) {
+        let net = self.clone();
+        // run on the main node so that the timer survives restarts of either end.
+        self.time
+            .add_timer_for_node(NodeId::zero(), deadline, move || {
+                net.lock_network().break_connection(&flow);
+            });
+    }
+
+    /// Degrade a node, making all traffic it sends or receives slow and unreliable.
     ///
-    /// Runtime::new().block_on(async {
-    ///     let net = Endpoint::bind(libc::SOCK_STREAM, "127.0.0.1:0").await.unwrap();
-    ///     let mut buf = [0; 10];
-    ///     let (len, src) = net.recv_from(0, &mut buf).await.expect("couldn't receive data");
-    /// });
-    /// ```
-    pub async fn recv_from(&self, tag: u64, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        let (payload, from) = self.recv_from_raw(tag).await?;
-        // copy to buffer
-        let data = payload
-            .data
-            .downcast::<Vec<u8>>()
-            .expect("message is not data");
-        let len = buf.len().min(data.len());
-        buf[..len].copy_from_slice(&data[..len]);
-        Ok((len, from))
+    /// Pass `None` to restore normal behavior.
+    pub fn degrade_node(&self, id: NodeId, degradation: Option<Degradation>) {
+        let mut network = self.lock_network();
+        network.degrade_node(id, degradation);
     }
 
-    /// Sends data on the socket to the remote address to which it is connected.
-    pub async fn send(&self, tag: u64, payload: Payload) -> io::Result<()> {
-        let peer = self.peer_addr()?;
-        self.send_to(peer, tag, payload).await
+    /// Degrade a node on top of [`degrade_node`](Self::degrade_node) until
+    /// [`remove_degradation`](Self::remove_degradation) is called with the returned id, so that
+    /// overlapping faults do not clear each other.
+    pub(crate) fn add_degradation(&self, id: NodeId, degradation: Degradation) -> u64 {
+        self.lock_network().add_degradation(id, degradation)
     }
 
-    /// Receives a single datagram message on the socket from the remote address to which it is connected.
-    /// On success, returns the number of bytes read.
-    pub async fn recv(&self, tag: u64, buf: &mut [u8]) -> io::Result<usize> {
-        let peer = self.peer_addr()?;
-        let (len, from) = self.recv_from(tag, buf).await?;
-        assert_eq!(
-            from, peer,
-            "receive a message but not from the connected address"
-        );
-        Ok(len)
+    /// Remove a degradation added by [`add_degradation`](Self::add_degradation).
+    pub(crate) fn remove_degradation(&self, id: NodeId, overlay: u64) {
+        self.lock_network().remove_degradation(id, overlay);
     }
 
-    /// Sends a raw message.
+    /// Forbid sending the messages matching `filter` for `duration`, e.g. votes while a node
+    /// believes it is syncing.
     ///
-    /// NOTE: Applications should not use this function!
-    /// It is provided for use by other simulators.
-    #[cfg_attr(docsrs, doc(cfg(msim)))]
-    pub async fn send_to_raw(&self, dst: SocketAddr, tag: u64, data: Payload) -> io::Result<()> {
-        self.send_to_raw_sync(dst, tag, data)?;
-        self.net.rand_delay().await;
+    /// Sending a forbidden message panics in the sending task, with the node, flow and tag of the
+    /// message. Pass `Duration::MAX` to keep the window open until [`NetSim::allow`] closes it.
+    pub fn forbid(&self, filter: filter::CaptureFilter, duration: Duration) -> ForbidId {
+        let mut network = self.lock_network();
+        network.forbid(filter, duration)
+    }
+
+    /// Close a window opened by [`NetSim::forbid`] before its end. Returns false if it was
+    /// already closed.
+    pub fn allow(&self, id: ForbidId) -> bool {
+        let mut network = self.lock_network();
+        network.allow(id)
+    }
+
+    /// Cap the traffic a node sends to other nodes, see [`EgressPolicy`].
+    ///
+    /// The bucket starts full. Pass `None` to remove the cap.
+    pub fn police_egress(&self, id: NodeId, policy: Option<EgressPolicy>) {
+        let mut network = self.lock_network();
+        network.police_egress(id, policy);
+    }
+
+    /// The number of udp messages dropped by full receive buffers, indexed by the drop
+    /// precedence class of [`NetworkConfig::recv_buffer`], the last one being the default class.
+    pub fn recv_buffer_drops(&self) -> Vec<u64> {
+        let network = self.lock_network();
+        network.recv_buffer_drops()
+    }
+
+    /// The number of udp packets the egress policer of a node dropped since it was set.
+    pub fn policed(&self, id: NodeId) -> u64 {
+        let network = self.lock_network();
+        network.policed(id)
+    }
+
+    /// Make `ip` an anycast address of `members`, or remove it if `members` is empty.
+    ///
+    /// Each datagram and each connection to an anycast address is routed to the healthy member
+    /// with the lowest mean latency from the sender, as computed when it is sent: a member is
+    /// healthy if it has a socket bound to the destination port, and is neither clogged nor
+    /// partitioned from the sender. Faults thus move traffic to the next closest member.
+    /// Connections stay with the member they were established with, whose address is the peer
+    /// address of the connection. Replies come from the address of the member.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ip` is the address of a node.
+    pub fn set_anycast(&self, ip: IpAddr, members: impl IntoIterator<Item = NodeId>) {
+        let mut network = self.lock_network();
+        network.set_anycast(ip, members.into_iter().collect());
+    }
+
+    /// The member of an anycast address that traffic from `src` to `dst` is routed to, see
+    /// [`set_anycast`](NetSim::set_anycast). `None` if no member is healthy.
+    pub fn anycast_member(
+        &self,
+        src: NodeId,
+        proto: libc::c_int,
+        dst: SocketAddr,
+    ) -> Option<NodeId> {
+        let network = self.lock_network();
+        network.anycast_member(src, proto, dst)
+    }
+
+    /// The address the current node connects to for a connection to `dst`, see
+    /// [`set_anycast`](NetSim::set_anycast).
+    fn resolve_anycast(&self, proto: libc::c_int, dst: SocketAddr) -> SocketAddr {
+        let network = self.lock_network();
+        network.resolve_anycast(plugin::node(), proto, dst)
+    }
+
+    /// Make a node refuse all traffic from `peer`, like a firewall rule banning its IP, e.g. to
+    /// emulate the ban list of a peer scoring system. Pass a `duration` for a temporary ban.
+    ///
+    /// Connection requests from the peer are refused with [`Error::Banned`], messages on its
+    /// established tcp connections reset the connection, and its udp messages are dropped.
+    /// Traffic the node sends to the peer is not affected. The ban outlives restarts of the node.
+    pub fn ban(&self, id: NodeId, peer: IpAddr, duration: Option<Duration>) {
+        let mut network = self.lock_network();
+        network.ban(id, peer, duration);
+    }
+
+    /// Lift the ban of `peer` by a node. Returns whether the node banned the peer.
+    pub fn unban(&self, id: NodeId, peer: IpAddr) -> bool {
+        let mut network = self.lock_network();
+        network.unban(id, peer)
+    }
+
+    /// The peers a node bans, with the time left until their ban expires.
+    pub fn bans(&self, id: NodeId) -> Vec<(IpAddr, Option<Duration>)> {
+        let network = self.lock_network();
+        network.bans(id)
+    }
+
+    /// The number of messages and connection requests from `peer` refused by the bans of a
+    /// node, e.g. to check how hard a banned peer keeps reconnecting.
+    pub fn ban_refused(&self, id: NodeId, peer: IpAddr) -> u64 {
+        let network = self.lock_network();
+        network.ban_refused(id, peer)
+    }
+
+    /// Apply faults to the messages with a tag in `tags`, in addition to all other faults.
+    ///
+    /// Faults of overlapping ranges add up. Setting a fault for a range replaces the fault of the
+    /// same range, and `None` removes it. Returns an `InvalidData` error if a rate of the fault
+    /// is not a probability.
+    pub fn set_tag_fault(&self, tags: Range<u64>, fault: Option<TagFault>) -> io::Result<()> {
+        if let Some(fault) = &fault {
+            fault.validate()?;
+        }
+        let mut network = self.lock_network();
+        network.set_tag_fault(tags, fault);
         Ok(())
     }
 
-    /// Sends a raw message.
+    /// Enable or disable message tracking.
     ///
-    /// NOTE: Applications should not use this function!
-    /// It is provided for use by other simulators.
-    #[cfg_attr(docsrs, doc(cfg(msim)))]
-    pub fn send_to_raw_sync(&self, dst: SocketAddr, tag: u64, data: Payload) -> io::Result<()> {
-        trace!(
-            "send_to_raw {} -> {}, {:x} {:?}",
-            self.addr,
-            dst,
-            tag,
-            data.ty
-        );
-        self.net
-            .network
-            .lock()
-            .unwrap()
-            .send(plugin::node(), self.proto, self.addr, dst, tag, data)
+    /// While enabled, the network records for every sent message whether it was delivered, or
+    /// why it was dropped. Records are kept until tracking is disabled, so this is intended for
+    /// debugging rather than for long running tests. Use [`Endpoint::last_msg_id`] to get the
+    /// id of a message sent from an endpoint.
+    pub fn track_messages(&self, enabled: bool) {
+        let mut network = self.lock_network();
+        network.track_messages(enabled);
     }
 
-    /// Receives a raw message.
+    /// Get the record of a message sent while tracking was enabled.
+    pub fn message(&self, id: MsgId) -> Option<MsgRecord> {
+        let network = self.lock_network();
+        network.message(id)
+    }
+
+    /// Get the records of all messages sent while tracking was enabled, ordered by id.
+    pub fn messages(&self) -> Vec<(MsgId, MsgRecord)> {
+        let network = self.lock_network();
+        network.messages()
+    }
+
+    /// Only record the messages matching `filter` in the trace and the message log, or all
+    /// messages if `None`.
     ///
-    /// NOTE: Applications should not use this function!
-    /// It is provided for use by other simulators.
-    #[cfg_attr(docsrs, doc(cfg(msim)))]
-    pub async fn recv_from_raw(&self, tag: u64) -> io::Result<(Payload, SocketAddr)> {
-        trace!("awaiting recv: {} tag={:x}", self.addr, tag);
-        let recver =
-            self.net
-                .network
-                .lock()
-                .unwrap()
-                .recv(plugin::node(), self.proto, self.addr, tag);
-        let msg = recver
-            .await
-            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "network is down"))?;
-        self.net.rand_delay().await;
+    /// Messages that match the filter of their source or destination node, see
+    /// [`NetSim::set_node_capture_filter`], are recorded too. Filters do not change how messages
+    /// are delivered.
+    pub fn set_capture_filter(&self, filter: Option<filter::CaptureFilter>) {
+        let mut network = self.lock_network();
+        network.set_capture_filter(filter);
+    }
 
-        trace!("recv: {} <- {}, tag={:x}", self.addr, msg.from, msg.tag);
-        Ok((msg.data, msg.from))
+    /// Record the messages sent or received by `node` that match `filter`, or remove the filter
+    /// of `node` if `None`.
+    ///
+    /// Once any filter is set, messages that match none of the filters that apply to them are
+    /// not recorded. See [`NetSim::set_capture_filter`].
+    pub fn set_node_capture_filter(&self, node: NodeId, filter: Option<filter::CaptureFilter>) {
+        let mut network = self.lock_network();
+        network.set_node_capture_filter(node, filter);
     }
 
-    /// Receive a raw message, synchronously
-    pub fn recv_from_raw_sync(&self, tag: u64) -> io::Result<(Payload, SocketAddr)> {
-        let msg = self
-            .net
-            .network
+    /// Enable or disable manual delivery mode.
+    ///
+    /// In manual delivery mode, messages are not delivered after their latency. Instead they are
+    /// held until the test picks one of the [`NetSim::deliverable`] messages and delivers it with
+    /// [`NetSim::deliver`], to craft adversarial schedules by hand. Messages still go through
+    /// partitions, drops and the other faults when they are sent, and through the mailboxes of
+    /// the receiving sockets when they are delivered. Note that stream messages can be reordered
+    /// too, which a real tcp connection never does.
+    ///
+    /// Disabling manual delivery mode delivers the held messages right away, in the order they
+    /// were sent.
+    pub fn set_manual_delivery(&self, enabled: bool) {
+        let mut network = self.lock_network();
+        network.set_manual_delivery(enabled);
+    }
+
+    /// Get the messages held in manual delivery mode, in the order they were sent.
+    pub fn deliverable(&self) -> Vec<MsgHandle> {
+        let network = self.lock_network();
+        network.deliverable()
+    }
+
+    /// Deliver a message held in manual delivery mode.
+    ///
+    /// Returns `false` if the message is not held, e.g. it was already delivered.
+    pub fn deliver(&self, msg: &MsgHandle) -> bool {
+        let deliver = self.lock_network().take_held(msg);
+        match deliver {
+            Some(deliver) => {
+                deliver();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop a message held in manual delivery mode, as if it was lost in the network.
+    ///
+    /// Returns `false` if the message is not held.
+    pub fn drop_held(&self, msg: &MsgHandle) -> bool {
+        let deliver = self.lock_network().take_held(msg);
+        deliver.is_some()
+    }
+
+    /// Set a tamperer that mutates messages in flight, to simulate Byzantine nodes or links.
+    ///
+    /// The tamperer is called with the source and destination nodes and the tag of every
+    /// message that arrives, duplicates included, and may change its payload. It returns `true`
+    /// if it tampered with the message. Byte payloads that were changed count as tampered even if
+    /// it returns `false`. It is called on arrival, outside of the network, so it may use the
+    /// simulator, e.g. to send messages.
+    ///
+    /// If checksums are enabled, messages are sealed before the tamperer sees them, so that the
+    /// receiver can detect the tampering with [`Payload::intact`](network::Payload::intact).
+    pub fn set_tamperer(
+        &self,
+        tamperer: impl FnMut(NodeId, NodeId, u64, &mut PayloadData) -> bool + Send + 'static,
+    ) {
+        let mut network = self.lock_network();
+        network.set_tamperer(Some(Box::new(tamperer)));
+    }
+
+    /// Remove the tamperer, see [`set_tamperer`](Self::set_tamperer).
+    pub fn clear_tamperer(&self) {
+        let mut network = self.lock_network();
+        network.set_tamperer(None);
+    }
+
+    /// Enable or disable tracking of tampered deliveries.
+    ///
+    /// While enabled, the network records every delivery of a message that the
+    /// [tamperer](Self::set_tamperer) tampered with. This is the ground truth to score the
+    /// tampering detection of the system under test with [`score_tampering`](Self::score_tampering).
+    pub fn track_tampering(&self, enabled: bool) {
+        let mut network = self.lock_network();
+        network.track_tampering(enabled);
+    }
+
+    /// Get the deliveries of tampered messages while tracking was enabled, in delivery order.
+    pub fn tampered(&self) -> Vec<TamperRecord> {
+        let network = self.lock_network();
+        network.tampered()
+    }
+
+    /// Score the tampered messages detected by the system under test against the tampered
+    /// deliveries, see [`track_tampering`](Self::track_tampering).
+    pub fn score_tampering(
+        &self,
+        detections: impl IntoIterator<Item = TamperDetection>,
+    ) -> TamperScore {
+        TamperScore::new(&self.tampered(), detections)
+    }
+
+    /// Register a human-readable name for a message tag.
+    ///
+    /// Traces, logs and message records show the name instead of the raw tag, see [`Tag`].
+    pub fn register_tag(&self, tag: u64, name: impl Into<String>) {
+        self.tag_names.lock().unwrap().insert(tag, name.into());
+    }
+
+    /// Get the name registered for a message tag.
+    pub fn tag_name(&self, tag: u64) -> Option<String> {
+        self.tag_names.lock().unwrap().get(&tag).cloned()
+    }
+
+    /// Get the names of all registered message tags, ordered by tag.
+    pub fn tag_names(&self) -> Vec<(u64, String)> {
+        let mut names: Vec<_> = self
+            .tag_names
             .lock()
             .unwrap()
-            .recv_sync(plugin::node(), self.proto, self.addr, tag)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "recv call would blck"))?;
+            .iter()
+            .map(|(tag, name)| (*tag, name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
 
-        trace!(
-            "recv sync: {} <- {}, tag={:x}",
-            self.addr,
-            msg.from,
-            msg.tag
-        );
+    /// Get the statistics of all flows, ordered by flow.
+    pub fn flows(&self) -> Vec<(Flow, FlowStat)> {
+        let network = self.lock_network();
+        network.flows()
+    }
+
+    /// Get the statistics of a flow.
+    pub fn flow(&self, flow: &Flow) -> Option<FlowStat> {
+        let network = self.lock_network();
+        network.flow(flow)
+    }
+
+    /// Get the number of messages dropped by injected faults on each link, keyed by (src, dst)
+    /// node, see [`DropReason::is_fault`].
+    pub fn fault_drops(&self) -> BTreeMap<(NodeId, NodeId), u64> {
+        let network = self.lock_network();
+        network.fault_drops().into_iter().collect()
+    }
+
+    /// Get the nodes and links that are clogged, e.g. by [`disconnect`](Self::disconnect), in
+    /// the order they were clogged.
+    pub fn partitions(&self) -> Vec<Partition> {
+        let network = self.lock_network();
+        network.partitions()
+    }
+
+    /// Get the number of partitions started since the start of the simulation, and their total
+    /// duration, including the partitions in progress until now.
+    ///
+    /// Ended partitions are only kept in these totals.
+    pub fn partition_totals(&self) -> (u64, Duration) {
+        let network = self.lock_network();
+        network.partition_totals()
+    }
+
+    /// Get the packets and bytes sent between the locations of nodes given by their `label`,
+    /// such as their region, ordered by location.
+    ///
+    /// Every packet a node puts on the wire to another node is counted, as a cloud provider
+    /// bills egress. Packets that are dropped before they are sent, e.g. by an egress policer,
+    /// by packet loss or because no socket is bound to the destination, are not. Traffic within
+    /// a location, and to or from nodes without the label or that were deleted, is not
+    /// included. Nodes are counted in the location of their current label.
+    pub fn egress_by_label(&self, label: &str) -> Vec<EgressStat> {
+        let locations: HashMap<NodeId, String> = crate::runtime::Handle::current()
+            .nodes()
+            .filter_map(|node| Some((node.id, node.label(label)?.to_string())))
+            .collect();
+        let network = self.lock_network();
+        let mut egress: Vec<EgressStat> = vec![];
+        for ((src, dst), (packets, bytes)) in network.traffic() {
+            let (Some(from), Some(to)) = (locations.get(src), locations.get(dst)) else {
+                continue;
+            };
+            if from == to {
+                continue;
+            }
+            match egress.iter_mut().find(|e| e.from == *from && e.to == *to) {
+                Some(stat) => {
+                    stat.packets += packets;
+                    stat.bytes += bytes;
+                }
+                None => egress.push(EgressStat {
+                    from: from.clone(),
+                    to: to.clone(),
+                    packets: *packets,
+                    bytes: *bytes,
+                }),
+            }
+        }
+        egress.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        egress
+    }
+
+    /// Get the bytes sent from the nodes labeled with `label` = `from` to the nodes labeled with
+    /// `label` = `to`, see [`egress_by_label`](Self::egress_by_label).
+    pub fn egress_bytes(&self, label: &str, from: &str, to: &str) -> u64 {
+        self.egress_by_label(label)
+            .into_iter()
+            .find(|e| e.from == from && e.to == to)
+            .map_or(0, |e| e.bytes)
+    }
+
+    /// Estimate the round trip time of a flow, from the mean latencies of the flow and of its
+    /// reverse. Returns `None` unless packets have been delivered in both directions.
+    pub fn rtt(&self, flow: &Flow) -> Option<Duration> {
+        let network = self.lock_network();
+        let forward = network.flow(flow)?.mean_latency()?;
+        let backward = network.flow(&flow.reverse())?.mean_latency()?;
+        Some(forward + backward)
+    }
+
+    /// Lock the network, measuring the time spent waiting for and holding the lock.
+    fn lock_network(&self) -> Measured<MutexGuard<'_, Network>> {
+        Measured::new(Section::NetworkLock, || self.network.lock().unwrap())
+    }
+
+    /// Send a connection request to `dst` until it is answered, see [`Endpoint::connect`].
+    async fn send_syn(&self, proto: libc::c_int, dst: SocketAddr) -> io::Result<()> {
+        let node = plugin::node();
+        let timeout = (self.lock_network().config().connect_timeout).unwrap_or(CONNECT_TIMEOUT);
+        let start = self.time.now_instant();
+        let mut rto = Duration::from_secs(1);
+        loop {
+            match self.lock_network().check_connect(node, proto, dst) {
+                Err(Error::TimedOut(_)) => {}
+                res => return res.map_err(Into::into),
+            }
+            let elapsed = self.time.now_instant() - start;
+            if elapsed >= timeout {
+                debug!("connection to {dst} timed out");
+                return Err(Error::TimedOut(dst).into());
+            }
+            trace!("connection request to {dst} lost, retrying in {rto:?}");
+            self.time.sleep(rto.min(timeout - elapsed)).await;
+            rto *= 2;
+        }
+    }
+
+    async fn rand_delay(&self) {
+        let delay = Duration::from_micros(self.rand.with(|rng| rng.gen_range(0..5)));
+        self.time.sleep(delay).with_category(Category::Jitter).await;
+    }
+
+    /// Get the next unused tcp id.
+    pub fn next_tcp_id(&self) -> u32 {
+        self.next_tcp_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+struct FlapState {
+    node1: NodeId,
+    node2: NodeId,
+    up: LatencyDistribution,
+    down: LatencyDistribution,
+    stopped: AtomicBool,
+    /// Whether the link is down, i.e. the flapper clogs it.
+    down_now: AtomicBool,
+}
+
+/// Handle to a flapping link, see [`NetSim::flap_link`].
+///
+/// Dropping the handle stops the flapping and reconnects the link.
+#[must_use = "the link stops flapping when the handle is dropped"]
+pub struct LinkFlapper {
+    net: Arc<NetSim>,
+    state: Arc<FlapState>,
+}
+
+impl LinkFlapper {
+    /// Stop flapping and reconnect the link.
+    pub fn stop(self) {}
+}
+
+impl Drop for LinkFlapper {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        let mut network = self.net.lock_network();
+        let (node1, node2) = (self.state.node1, self.state.node2);
+        if self.state.down_now.swap(false, Ordering::SeqCst) {
+            network.fault_unclog(PartitionTarget::Link(node1, node2));
+            network.fault_unclog(PartitionTarget::Link(node2, node1));
+        }
+    }
+}
+
+/// An endpoint.
+pub struct Endpoint {
+    net: Arc<NetSim>,
+    node: NodeId,
+    addr: SocketAddr,
+    proto: libc::c_int,
+    peer: Option<SocketAddr>,
+    live_tcp_ids: Mutex<HashSet<u32>>,
+    last_msg_id: Mutex<Option<MsgId>>,
+    /// The tags this endpoint owns, if it shares its address, see [`Endpoint::bind_shared`].
+    tags: Option<Range<u64>>,
+    /// The state of the features most endpoints do not use, see [`EndpointExtras`].
+    extras: OnceLock<Arc<EndpointExtras>>,
+    /// Whether the socket belongs to another endpoint, which closes it, see `Endpoint::alias`.
+    alias: bool,
+}
+
+/// A message sent or received by an [`Endpoint`], passed to its hooks.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug)]
+pub struct EndpointMsg<'a> {
+    /// The destination of a sent message, or the source of a received message.
+    pub peer: SocketAddr,
+    /// The message tag.
+    pub tag: u64,
+    /// The message.
+    pub payload: &'a Payload,
+}
+
+type EndpointHook = Arc<dyn Fn(&EndpointMsg<'_>) + Send + Sync>;
+
+#[derive(Default)]
+struct EndpointHooks {
+    send: Vec<EndpointHook>,
+    recv: Vec<EndpointHook>,
+}
+
+/// The state of an endpoint for the features that most endpoints do not use, allocated when
+/// first used and shared with the aliases of the endpoint.
+#[derive(Default)]
+struct EndpointExtras {
+    /// The receiver tasks of the streams accepted by `recv_stream`, by stream tag.
+    streams: Mutex<HashMap<u64, crate::task::JoinHandle<()>>>,
+    /// Hooks called on each message sent or received, see [`Endpoint::on_send`].
+    hooks: Mutex<EndpointHooks>,
+}
+
+impl std::fmt::Debug for Endpoint {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("Endpoint")
+            .field("node", &self.node)
+            .field("addr", &self.addr)
+            .field("peer", &self.peer)
+            .field("tags", &self.tags)
+            .finish()
+    }
+}
+
+impl Endpoint {
+    /// Bind synchronously
+    pub fn bind_sync(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let net = plugin::simulator::<NetSim>();
+        let node = plugin::node();
+        let addr = addr.to_socket_addrs()?.next().unwrap();
+        let addr = net.lock_network().bind(node, proto, addr)?;
+        let ep = Endpoint {
+            net,
+            node,
+            addr,
+            proto,
+            peer: None,
+            live_tcp_ids: Default::default(),
+            last_msg_id: Default::default(),
+            tags: None,
+            extras: Default::default(),
+            alias: false,
+        };
+        trace!("Endpoint::bind_sync() -> {:?}", ep);
+        Ok(ep)
+    }
+
+    /// return the tag used to send to this endpooint, for udp connections only.
+    /// (It is the same as the udp port number).
+    /// port is 0 we panic
+    pub fn udp_tag(&self) -> io::Result<u64> {
+        let port = self.addr.port();
+        if port == 0 {
+            Err(Error::NotConnected.into())
+        } else {
+            Ok(port as u64)
+        }
+    }
+
+    /// Creates a [`Endpoint`] from the given address.
+    pub async fn bind(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let net = plugin::simulator::<NetSim>();
+        let node = plugin::node();
+        let addr = addr.to_socket_addrs()?.next().unwrap();
+        net.rand_delay().await;
+        let addr = net.lock_network().bind(node, proto, addr)?;
+        Ok(Endpoint {
+            net,
+            node,
+            addr,
+            proto,
+            peer: None,
+            live_tcp_ids: Default::default(),
+            last_msg_id: Default::default(),
+            tags: None,
+            extras: Default::default(),
+            alias: false,
+        })
+    }
+
+    /// Creates an [`Endpoint`] sharing its address with other endpoints, like `SO_REUSEPORT`.
+    ///
+    /// Endpoints sharing an address own disjoint ranges of tags: the endpoint can only receive
+    /// messages with tags in `tags`, and binding fails with `AddrInUse` if the range overlaps
+    /// with the range of another endpoint at the address, or if the address is bound without
+    /// sharing. Messages sent to the address with a tag that no endpoint owns are dropped, as if
+    /// the port was unreachable. The socket is closed when the last endpoint sharing it is
+    /// dropped.
+    pub async fn bind_shared(
+        proto: libc::c_int,
+        addr: impl ToSocketAddrs,
+        tags: Range<u64>,
+    ) -> io::Result<Self> {
+        let net = plugin::simulator::<NetSim>();
+        let node = plugin::node();
+        let addr = addr.to_socket_addrs()?.next().unwrap();
+        net.rand_delay().await;
+        let addr = net
+            .lock_network()
+            .bind_shared(node, proto, addr, tags.clone())?;
+        Ok(Endpoint {
+            net,
+            node,
+            addr,
+            proto,
+            peer: None,
+            live_tcp_ids: Default::default(),
+            last_msg_id: Default::default(),
+            tags: Some(tags),
+            extras: Default::default(),
+            alias: false,
+        })
+    }
+
+    /// Another endpoint on the same socket, for tasks that send and receive on behalf of this
+    /// one. It does not close the socket when dropped, and must not outlive this endpoint.
+    fn alias(&self) -> Endpoint {
+        Endpoint {
+            net: self.net.clone(),
+            node: self.node,
+            addr: self.addr,
+            proto: self.proto,
+            peer: self.peer,
+            live_tcp_ids: Default::default(),
+            last_msg_id: Default::default(),
+            tags: self.tags.clone(),
+            extras: OnceLock::from(self.extras().clone()),
+            alias: true,
+        }
+    }
+
+    fn extras(&self) -> &Arc<EndpointExtras> {
+        self.extras.get_or_init(Default::default)
+    }
+
+    /// Returns an error if the endpoint shares its address and does not own `tag`.
+    fn check_tag(&self, tag: u64) -> io::Result<()> {
+        match &self.tags {
+            Some(tags) if !tags.contains(&tag) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tag {} is not in the namespace {tags:?}", Tag(tag)),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Connects this [`Endpoint`] to a remote address.
+    ///
+    /// A stream connection is refused if nothing listens at the address. If the destination is
+    /// unreachable, e.g. partitioned away, the connection request is retransmitted with an
+    /// exponential backoff like a tcp SYN, and the connection fails with `TimedOut` after
+    /// [`NetworkConfig::connect_timeout`] if it is still unreachable.
+    pub async fn connect(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let net = plugin::simulator::<NetSim>();
+        net.rand_delay().await;
+        let mut peer = addr.to_socket_addrs()?.next().unwrap();
+        if proto == libc::SOCK_STREAM {
+            peer = net.resolve_anycast(proto, peer);
+            net.send_syn(proto, peer).await?;
+        }
+        Self::connect_sync(proto, peer)
+    }
+
+    /// For libc::connect()
+    pub fn connect_sync(proto: libc::c_int, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let net = plugin::simulator::<NetSim>();
+        let node = plugin::node();
+        let peer = addr.to_socket_addrs()?.next().unwrap();
+        let addr = if peer.ip().is_loopback() {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+        } else {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        };
+        let addr = net.lock_network().bind(node, proto, addr)?;
+        Ok(Endpoint {
+            net,
+            node,
+            addr,
+            proto,
+            peer: Some(peer),
+            live_tcp_ids: Default::default(),
+            last_msg_id: Default::default(),
+            tags: None,
+            extras: Default::default(),
+            alias: false,
+        })
+    }
+
+    /// Allocate a new tcp id number for this node. Ids are never reused.
+    pub fn allocate_local_tcp_id(&self) -> u32 {
+        let id = self.net.next_tcp_id();
+        trace!(
+            "Allocate local tcp id {} to node {} address {}",
+            id,
+            self.node,
+            self.addr.ip()
+        );
+        self.live_tcp_ids.lock().unwrap().insert(id);
+        self.net.lock_network().register_tcp_id(self.node, id);
+        id
+    }
+
+    /// Remove a tcp id number from this node.
+    pub fn deregister_tcp_id(&self, remote_sock: &SocketAddr, id: u32) {
+        assert!(
+            self.live_tcp_ids.lock().unwrap().remove(&id),
+            "unknown tcp id {}",
+            id
+        );
+        self.net.lock_network().deregister_tcp_id(
+            self.node,
+            self.proto,
+            &self.addr,
+            remote_sock,
+            id,
+        );
+    }
+
+    /// Returns the local socket address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    /// Returns the socket address of the remote peer this socket was connected to.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer.ok_or_else(|| Error::NotConnected.into())
+    }
+
+    /// Sends data with tag on the socket to the given address.
+    ///
+    /// # Example
+    /// ```
+    /// use msim::{runtime::Runtime, net::{Endpoint, network::Payload}};
+    ///
+    /// Runtime::new().block_on(async {
+    ///     let net = Endpoint::bind(libc::SOCK_STREAM, "127.0.0.1:4242").await.unwrap();
+    ///     net.send_to("127.0.0.1:4242", 0, Payload::new_udp(Box::new([0; 10]))).await.expect("couldn't send data");
+    /// });
+    /// ```
+    pub async fn send_to(
+        &self,
+        dst: impl ToSocketAddrs,
+        tag: u64,
+        payload: Payload,
+    ) -> io::Result<()> {
+        let dst = dst.to_socket_addrs()?.next().unwrap();
+        self.send_to_raw(dst, tag, payload).await
+    }
+
+    /// Sends a message with the given tag, overriding how this message is delivered.
+    ///
+    /// This shapes individual critical messages, e.g. delaying a vote past a timeout, without
+    /// changing the network configuration. Returns an `InvalidInput` error if a duplicate is
+    /// requested for a [`PayloadData::Any`] payload.
+    pub async fn send_to_with(
+        &self,
+        dst: impl ToSocketAddrs,
+        tag: u64,
+        payload: Payload,
+        delivery: DeliveryOverride,
+    ) -> io::Result<()> {
+        let dst = dst.to_socket_addrs()?.next().unwrap();
+        self.send_to_raw_sync_with(dst, tag, payload, &delivery)?;
+        self.net.rand_delay().await;
+        Ok(())
+    }
+
+    /// Receives a single message with given tag on the socket.
+    /// On success, returns the number of bytes read and the origin.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use msim::{runtime::Runtime, net::Endpoint};
+    ///
+    /// Runtime::new().block_on(async {
+    ///     let net = Endpoint::bind(libc::SOCK_STREAM, "127.0.0.1:0").await.unwrap();
+    ///     let mut buf = [0; 10];
+    ///     let (len, src) = net.recv_from(0, &mut buf).await.expect("couldn't receive data");
+    /// });
+    /// ```
+    pub async fn recv_from(&self, tag: u64, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (payload, from) = self.recv_from_raw(tag).await?;
+        // copy to buffer
+        let data = payload.into_bytes()?;
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    /// Sends data on the socket to the remote address to which it is connected.
+    pub async fn send(&self, tag: u64, payload: Payload) -> io::Result<()> {
+        let peer = self.peer_addr()?;
+        self.send_to(peer, tag, payload).await
+    }
+
+    /// Receives a single datagram message on the socket from the remote address to which it is connected.
+    /// On success, returns the number of bytes read.
+    pub async fn recv(&self, tag: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let peer = self.peer_addr()?;
+        let (len, from) = self.recv_from(tag, buf).await?;
+        assert_eq!(
+            from, peer,
+            "receive a message but not from the connected address"
+        );
+        Ok(len)
+    }
+
+    /// Sends a raw message.
+    ///
+    /// NOTE: Applications should not use this function!
+    /// It is provided for use by other simulators.
+    #[cfg_attr(docsrs, doc(cfg(msim)))]
+    pub async fn send_to_raw(&self, dst: SocketAddr, tag: u64, data: Payload) -> io::Result<()> {
+        self.send_to_raw_sync(dst, tag, data)?;
+        self.net.rand_delay().await;
+        Ok(())
+    }
+
+    /// Sends a raw message.
+    ///
+    /// NOTE: Applications should not use this function!
+    /// It is provided for use by other simulators.
+    #[cfg_attr(docsrs, doc(cfg(msim)))]
+    pub fn send_to_raw_sync(&self, dst: SocketAddr, tag: u64, data: Payload) -> io::Result<()> {
+        trace!(
+            "send_to_raw {} -> {}, {:x} {:?}",
+            self.addr,
+            dst,
+            tag,
+            data.ty
+        );
+        self.send_to_raw_sync_with(dst, tag, data, &DeliveryOverride::default())
+    }
+
+    fn send_to_raw_sync_with(
+        &self,
+        dst: SocketAddr,
+        tag: u64,
+        data: Payload,
+        delivery: &DeliveryOverride,
+    ) -> io::Result<()> {
+        self.run_hooks(|hooks| &hooks.send, dst, tag, &data);
+        let mut network = self.net.lock_network();
+        let flow = Flow {
+            proto: self.proto,
+            src: self.addr,
+            dst,
+        };
+        let len = data.size() as u64;
+        if let Some(violation) = network.check_forbidden(plugin::node(), &flow, tag, len) {
+            // do not poison the network.
+            drop(network);
+            panic!("{violation}");
+        }
+        let res = network.send(plugin::node(), flow, tag, data, delivery);
+        *self.last_msg_id.lock().unwrap() = network.last_msg_id();
+        res
+    }
+
+    /// Call `hook` on every message sent from this endpoint, before it is handed to the network.
+    ///
+    /// This lets a test double count or classify the traffic of one service without setting up
+    /// a [capture filter](NetSim::set_capture_filter) for the whole network. Hooks only observe
+    /// messages, and are called in the order they were added.
+    pub fn on_send(&self, hook: impl Fn(&EndpointMsg<'_>) + Send + Sync + 'static) {
+        self.extras()
+            .hooks
+            .lock()
+            .unwrap()
+            .send
+            .push(Arc::new(hook));
+    }
+
+    /// Call `hook` on every message received by this endpoint, see [`on_send`](Self::on_send).
+    pub fn on_recv(&self, hook: impl Fn(&EndpointMsg<'_>) + Send + Sync + 'static) {
+        self.extras()
+            .hooks
+            .lock()
+            .unwrap()
+            .recv
+            .push(Arc::new(hook));
+    }
+
+    fn run_hooks(
+        &self,
+        which: fn(&EndpointHooks) -> &Vec<EndpointHook>,
+        peer: SocketAddr,
+        tag: u64,
+        payload: &Payload,
+    ) {
+        let Some(extras) = self.extras.get() else {
+            return;
+        };
+        // hooks may use the endpoint, so they are called without holding the lock.
+        let hooks = which(&extras.hooks.lock().unwrap()).clone();
+        let msg = EndpointMsg { peer, tag, payload };
+        for hook in hooks {
+            hook(&msg);
+        }
+    }
+
+    /// The id of the last message sent from this endpoint.
+    ///
+    /// See [`NetSim::track_messages`].
+    pub fn last_msg_id(&self) -> Option<MsgId> {
+        *self.last_msg_id.lock().unwrap()
+    }
+
+    /// Receives a raw message.
+    ///
+    /// NOTE: Applications should not use this function!
+    /// It is provided for use by other simulators.
+    #[cfg_attr(docsrs, doc(cfg(msim)))]
+    pub async fn recv_from_raw(&self, tag: u64) -> io::Result<(Payload, SocketAddr)> {
+        trace!("awaiting recv: {} tag={}", self.addr, Tag(tag));
+        self.check_tag(tag)?;
+        let recver = self
+            .net
+            .lock_network()
+            .recv(plugin::node(), self.proto, self.addr, tag);
+        let msg = recver.await.map_err(|_| Error::Closed)?;
+        self.net.rand_delay().await;
+
+        trace!("recv: {} <- {}, tag={}", self.addr, msg.from, Tag(msg.tag));
+        self.run_hooks(|hooks| &hooks.recv, msg.from, msg.tag, &msg.data);
+        Ok((msg.data, msg.from))
+    }
+
+    /// Receive a raw message, synchronously
+    pub fn recv_from_raw_sync(&self, tag: u64) -> io::Result<(Payload, SocketAddr)> {
+        self.check_tag(tag)?;
+        let msg = self
+            .net
+            .lock_network()
+            .recv_sync(plugin::node(), self.proto, self.addr, tag)
+            .ok_or(Error::BufferFull)?;
+
+        trace!(
+            "recv sync: {} <- {}, tag={}",
+            self.addr,
+            msg.from,
+            Tag(msg.tag)
+        );
+        self.run_hooks(|hooks| &hooks.recv, msg.from, msg.tag, &msg.data);
         Ok((msg.data, msg.from))
     }
 
-    /// Sends a raw message. to the connected remote address.
-    ///
-    /// NOTE: Applications should not use this function!
-    /// It is provided for use by other simulators.
-    #[cfg_attr(docsrs, doc(cfg(msim)))]
-    pub async fn send_raw(&self, tag: u64, data: Payload) -> io::Result<()> {
-        let peer = self.peer_addr()?;
-        self.send_to_raw(peer, tag, data).await
+    /// Drop the next message with `tag`, queued or yet to arrive, e.g. the reply to a request
+    /// that timed out.
+    pub(crate) fn discard_raw(&self, tag: u64) {
+        self.net
+            .lock_network()
+            .discard(plugin::node(), self.proto, self.addr, tag);
+    }
+
+    /// Sends a raw message. to the connected remote address.
+    ///
+    /// NOTE: Applications should not use this function!
+    /// It is provided for use by other simulators.
+    #[cfg_attr(docsrs, doc(cfg(msim)))]
+    pub async fn send_raw(&self, tag: u64, data: Payload) -> io::Result<()> {
+        let peer = self.peer_addr()?;
+        self.send_to_raw(peer, tag, data).await
+    }
+
+    /// Receives a raw message from the connected remote address.
+    ///
+    /// NOTE: Applications should not use this function!
+    /// It is provided for use by other simulators.
+    #[cfg_attr(docsrs, doc(cfg(msim)))]
+    pub async fn recv_raw(&self, tag: u64) -> io::Result<Payload> {
+        let peer = self.peer_addr()?;
+        let (msg, from) = self.recv_from_raw(tag).await?;
+        assert_eq!(
+            from, peer,
+            "receive a message but not from the connected address"
+        );
+        Ok(msg)
+    }
+
+    /// Check if the peer of a TCP connection has hung up.
+    pub fn is_peer_live(&self, peer: Option<SocketAddr>, remote_tcp_id: u32) -> bool {
+        let peer = peer.as_ref().unwrap_or_else(|| {
+            self.peer
+                .as_ref()
+                .expect("is_peer_live called without peer")
+        });
+        let flow = Flow {
+            proto: self.proto,
+            src: self.addr,
+            dst: *peer,
+        };
+        self.net
+            .lock_network()
+            .is_tcp_session_live(&flow, remote_tcp_id)
+    }
+
+    /// Check if there is a message waiting that can be received without blocking.
+    /// If not, schedule a wakeup using the context.
+    pub fn recv_ready(&self, cx: Option<&mut Context<'_>>, tag: u64) -> io::Result<bool> {
+        self.check_tag(tag)?;
+        Ok(self
+            .net
+            .lock_network()
+            .recv_ready(cx, plugin::node(), self.proto, self.addr, tag))
+    }
+
+    /// Get notified of the tag and sender of each message waiting to be received on this
+    /// endpoint, starting with the messages already waiting.
+    ///
+    /// This lets a single task multiplex many tags: wait for a notification, then receive the
+    /// message with its tag. Messages handed directly to a pending receive call are not
+    /// reported. A notified message may be received by another task before the notification is
+    /// handled, so receiving it may still have to wait.
+    pub fn incoming_tags(&self) -> IncomingTags {
+        IncomingTags(self.net.lock_network().incoming_tags(
+            plugin::node(),
+            self.proto,
+            self.addr,
+            self.tags.clone(),
+        ))
+    }
+}
+
+/// A stream of the `(tag, from)` of the messages arriving on an endpoint, see
+/// [`Endpoint::incoming_tags`].
+///
+/// The stream ends when the endpoint is closed or its node is reset.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+pub struct IncomingTags(mpsc::UnboundedReceiver<(u64, SocketAddr)>);
+
+impl Stream for IncomingTags {
+    type Item = (u64, SocketAddr);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        return_if_killed!();
+        if self.alias {
+            return;
+        }
+        // stream receivers use the socket, so stop them before it is closed.
+        if let Some(extras) = self.extras.get() {
+            for (_, task) in extras.streams.lock().unwrap().drain() {
+                task.abort();
+            }
+        }
+
+        // all tcp sessions should already be deregistered.
+        assert!(self.live_tcp_ids.get_mut().unwrap().is_empty());
+
+        // avoid panic on panicking
+        if let Ok(mut network) = self.net.network.lock() {
+            match &self.tags {
+                Some(tags) => network.close_shared(self.proto, self.node, self.addr, tags),
+                None => network.close(self.proto, self.node, self.addr),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::network::Payload,
+        plugin::simulator,
+        runtime::{init_logger, Handle, Runtime},
+        time::*,
+    };
+    use tokio::sync::Barrier;
+
+    macro_rules! payload {
+        ($e: expr) => {{
+            let v: Vec<u8> = $e;
+            Payload::udp(v)
+        }};
+    }
+
+    #[test]
+    fn send_recv() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            barrier_.wait().await;
+
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+
+            sleep(Duration::from_secs(1)).await;
+            net.send_to(addr2, 2, payload!(vec![2])).await.unwrap();
+        });
+
+        let f = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            barrier.wait().await;
+
+            let mut buf = vec![0; 0x10];
+            let (len, from) = net.recv_from(2, &mut buf).await.unwrap();
+            assert_eq!(len, 1);
+            assert_eq!(from, addr1);
+            assert_eq!(buf[0], 2);
+
+            let (len, from) = net.recv_from(1, &mut buf).await.unwrap();
+            assert_eq!(len, 1);
+            assert_eq!(from, addr1);
+            assert_eq!(buf[0], 1);
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn receiver_drop() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            barrier_.wait().await;
+
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+        });
+
+        let f = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            let mut buf = vec![0; 0x10];
+            timeout(Duration::from_secs(1), net.recv_from(1, &mut buf))
+                .await
+                .err()
+                .unwrap();
+            // timeout and receiver dropped here
+            barrier.wait().await;
+
+            // receive again should success
+            let (len, from) = net.recv_from(1, &mut buf).await.unwrap();
+            assert_eq!(len, 1);
+            assert_eq!(from, addr1);
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn reset() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            let err = net.recv_from(1, &mut []).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+            // FIXME: should still error
+            // let err = net.recv_from(1, &mut []).await.unwrap_err();
+            // assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        });
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(1)).await;
+            simulator::<NetSim>().reset_node(node1.id());
+            f.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn one_way_link() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        let f1 = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            barrier_.wait().await;
+
+            let err = net.send_to(addr2, 1, payload!(vec![1])).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+            let start = Instant::now();
+            let mut buf = vec![0; 0x10];
+            let (_, from) = net.recv_from(2, &mut buf).await.unwrap();
+            assert_eq!(from, addr2);
+            assert!(start.elapsed() >= Duration::from_secs(1));
+        });
+
+        let f2 = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            barrier.wait().await;
+            net.send_to(addr1, 2, payload!(vec![2])).await.unwrap();
+        });
+
+        let (id1, id2) = (node1.id(), node2.id());
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            net.disconnect_one_way(id1, id2);
+            net.set_one_way_latency(
+                id2,
+                id1,
+                Some(LatencyDistribution::Constant(Duration::from_secs(1))),
+            );
+            f2.await.unwrap();
+            f1.await.unwrap();
+
+            net.connect_one_way(id1, id2);
+            net.set_one_way_latency(id2, id1, None);
+        });
+    }
+
+    #[test]
+    fn clusters() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let addr3 = "10.1.0.1:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let node3 = runtime.create_node().ip(addr3.ip()).build();
+        for (node, addr) in [(&node2, addr2), (&node3, addr3)] {
+            node.spawn(async move {
+                let ep = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                loop {
+                    let (_, from) = ep.recv_from_raw(1).await.unwrap();
+                    ep.send_to_raw(from, 2, payload!(vec![2])).await.unwrap();
+                }
+            });
+        }
+
+        let config = |latency| {
+            let mut config = NetworkConfig::default();
+            config.latency.default_latency = LatencyDistribution::Constant(latency);
+            config
+        };
+        let net = runtime.handle().simulator::<NetSim>();
+        net.add_cluster("main", config(Duration::from_millis(1)));
+        net.add_cluster("indexer", config(Duration::from_millis(5)));
+        net.set_cluster(node1.id(), Some("main"));
+        net.set_cluster(node2.id(), Some("main"));
+        net.set_cluster(node3.id(), Some("indexer"));
+        assert_eq!(net.cluster(node3.id()).as_deref(), Some("indexer"));
+
+        let f = node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let rtt = |dst| {
+                let ep = &ep;
+                async move {
+                    let start = Instant::now();
+                    ep.send_to_raw(dst, 1, payload!(vec![1])).await?;
+                    ep.recv_from_raw(2).await?;
+                    std::io::Result::Ok(start.elapsed())
+                }
+            };
+            let lan = rtt(addr2).await.unwrap();
+            assert!(lan >= Duration::from_millis(2) && lan < Duration::from_millis(3));
+
+            // the clusters are isolated until they are linked
+            let err = rtt(addr3).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+            let net = simulator::<NetSim>();
+            let link = WanLink {
+                latency: LatencyDistribution::Constant(Duration::from_millis(50)),
+                packet_loss_rate: 0.0,
+            };
+            net.set_wan_link("main", "indexer", Some(link));
+            let wan = rtt(addr3).await.unwrap();
+            assert!(wan >= Duration::from_millis(100) && wan < Duration::from_millis(101));
+
+            net.set_wan_link("main", "indexer", None);
+            rtt(addr3).await.unwrap_err();
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn payload_data() {
+        let payloads = [
+            Payload::udp(vec![1, 2, 3]).with_size(10),
+            Payload::tcp_connect(7),
+            Payload::tcp_data(3, vec![4, 5]),
+        ];
+        for payload in payloads {
+            let encoded = payload.encode().unwrap();
+            let decoded = Payload::decode(&encoded).unwrap();
+            assert_eq!(decoded.ty, payload.ty);
+            assert_eq!(decoded.size(), payload.size());
+            assert_eq!(decoded.bytes(), payload.bytes());
+            assert_eq!(decoded.encode().unwrap(), encoded);
+        }
+        Payload::decode(&[9; 10]).unwrap_err();
+
+        // the compatibility constructors recognize bytes
+        let payload = Payload::new_udp(Box::new(vec![1u8, 2]));
+        assert_eq!(payload.bytes(), Some(&[1, 2][..]));
+        assert_eq!(payload.downcast::<Vec<u8>>().unwrap(), [1, 2]);
+
+        // mismatched types are errors rather than panics
+        let payload = Payload::new_udp(Box::new("hello"));
+        assert!(payload.encode().is_none());
+        let err = payload.downcast::<u32>().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = Payload::tcp_connect(1).into_bytes().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn delivery_override() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let net = runtime.handle().simulator::<NetSim>();
+        net.update_config(|cfg| cfg.checksum = true);
+        // the extra latency of a degraded node adds to the overridden latency.
+        net.degrade_node(
+            node1.id(),
+            Some(Degradation {
+                extra_latency: LatencyDistribution::Constant(Duration::from_millis(200)),
+                ..Default::default()
+            }),
+        );
+
+        let f = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut received = vec![];
+            let start = Instant::now();
+            while let Ok(Ok((payload, _))) =
+                timeout(Duration::from_secs(10), ep.recv_from_raw(1)).await
+            {
+                // duplicates are sealed like the original.
+                assert_eq!(payload.intact(), Some(true));
+                let data = payload.into_bytes().unwrap();
+                received.push((data[0], start.elapsed()));
+            }
+            received
+        });
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let send =
+                |data: u8, delivery| ep.send_to_with(addr2, 1, payload!(vec![data]), delivery);
+            let dropped = DeliveryOverride {
+                force_drop: true,
+                ..Default::default()
+            };
+            sleep(Duration::from_millis(1)).await;
+            send(1, dropped).await.unwrap();
+            let delayed = DeliveryOverride {
+                latency: Some(Duration::from_secs(2)),
+                ..Default::default()
+            };
+            send(2, delayed).await.unwrap();
+            let duplicated = DeliveryOverride {
+                latency: Some(Duration::from_secs(1)),
+                duplicate: Some(Duration::from_secs(2)),
+                ..Default::default()
+            };
+            send(3, duplicated.clone()).await.unwrap();
+
+            let payload = Payload::udp(PayloadData::Any(Box::new(3u32)));
+            let err = ep
+                .send_to_with(addr2, 1, payload, duplicated)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        });
+
+        let received = runtime.block_on(f).unwrap();
+        let order: Vec<_> = received.iter().map(|(data, _)| *data).collect();
+        assert_eq!(order, [3, 2, 3]);
+        let millis = |d: Duration| (d.as_secs_f64() * 10.0).round() as u64 * 100;
+        let times: Vec<_> = received.iter().map(|(_, t)| millis(*t)).collect();
+        assert_eq!(times, [1200, 2200, 3200]);
+    }
+
+    #[test]
+    fn tampering() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+
+        let net = runtime.handle().simulator::<NetSim>();
+        net.track_tampering(true);
+        // corrupt the check byte of odd messages
+        net.set_tamperer(|_, to, _, data| {
+            // the tamperer may use the network
+            assert!(simulator::<NetSim>().get_ip(to).is_some());
+            if let PayloadData::Bytes(bytes) = data {
+                if bytes[0] % 2 == 1 {
+                    *bytes = vec![bytes[0], !bytes[1]].into();
+                }
+            }
+            false
+        });
+
+        let f = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut detections = vec![];
+            for i in 0..10 {
+                let (payload, _) = ep.recv_from_raw(10 + i).await.unwrap();
+                let data = payload.into_bytes().unwrap();
+                // a flawed detector: misses message 9, and flags message 2
+                if (data[0] != data[1] && data[0] != 9) || data[0] == 2 {
+                    detections.push(TamperDetection {
+                        from: id1,
+                        to: id2,
+                        tag: Some(10 + i),
+                    });
+                }
+            }
+            detections
+        });
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            for i in 0..10u8 {
+                ep.send_to_raw(addr2, 10 + i as u64, payload!(vec![i, i]))
+                    .await
+                    .unwrap();
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        let detections = runtime.block_on(f).unwrap();
+        let tampered = net.tampered();
+        assert_eq!(tampered.len(), 5);
+        assert!(tampered
+            .iter()
+            .all(|r| r.from == id1 && r.to == id2 && r.tag % 2 == 1));
+        let score = net.score_tampering(detections);
+        assert_eq!(
+            score,
+            TamperScore {
+                true_positives: 4,
+                false_positives: 1,
+                false_negatives: 1,
+            }
+        );
+        assert_eq!(score.precision(), Some(0.8));
+        assert_eq!(score.recall(), Some(0.8));
+
+        net.clear_tamperer();
+        net.track_tampering(false);
+        assert!(net.tampered().is_empty());
+    }
+
+    #[test]
+    fn dump_sockets() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let id2 = node2.id();
+
+        node2.spawn(async move {
+            let _udp = std::net::UdpSocket::bind(addr2).unwrap();
+            let _tcp = std::net::TcpListener::bind(addr2).unwrap();
+            std::future::pending::<()>().await;
+        });
+        node1.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            ep.send_to(addr2, 0, payload!(b"ping".to_vec()))
+                .await
+                .unwrap();
+            ep.send_to(addr2, 0, payload!(b"ping".to_vec()))
+                .await
+                .unwrap();
+            let _tcp = std::net::TcpStream::connect(addr2).unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(1)).await;
+            let sockets = simulator::<NetSim>().dump_sockets(id2);
+            assert_eq!(sockets.len(), 2);
+            let (udp, tcp) = (&sockets[0], &sockets[1]);
+            assert_eq!(udp.ty, libc::SOCK_DGRAM);
+            assert_eq!(udp.status, SocketStatus::Bound);
+            assert_eq!(udp.local, Some(addr2));
+            assert_eq!(udp.rx_queue, 2);
+            assert_eq!(tcp.status, SocketStatus::Listening);
+            assert_eq!(tcp.rx_queue, 1);
+            let line = tcp.to_string();
+            assert!(line.starts_with("tcp   LISTEN  1"), "{line}");
+            assert!(line.contains("10.0.0.2:1"), "{line}");
+        });
+    }
+
+    #[test]
+    fn recv_from_std_socket() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let f = node2.spawn(async move {
+            let socket = std::net::UdpSocket::bind(addr2).unwrap();
+            let mut buf = [0; 16];
+            // nothing was sent yet
+            let err = socket.recv_from(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+            sleep(Duration::from_secs(1)).await;
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!((&buf[..len], from), (&b"ping"[..], addr1));
+            // the datagram is truncated to the buffer
+            let len = socket.recv(&mut buf[..2]).unwrap();
+            assert_eq!(&buf[..len], b"po");
+            let err = socket.recv(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        });
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            for msg in [b"ping", b"pong"] {
+                ep.send_to(addr2, 1, payload!(msg.to_vec())).await.unwrap();
+                sleep(Duration::from_millis(1)).await;
+            }
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn strict_sockopt() {
+        let runtime = Runtime::new();
+        let addr = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let node = runtime.create_node().ip(addr.ip()).build();
+
+        let f = node.spawn(async move {
+            let net = simulator::<NetSim>();
+            let socket = std::net::UdpSocket::bind(addr).unwrap();
+            // unknown options are accepted by default
+            socket.set_broadcast(true).unwrap();
+
+            net.update_config(|cfg| {
+                cfg.unknown_sockopt = SockoptPolicy::Unsupported(crate::UnsupportedPolicy::Lenient)
+            });
+            let err = socket.set_broadcast(true).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOPROTOOPT));
+            // known options are still supported
+            socket.set_ttl(64).unwrap();
+            // unknown ipv6 options follow the same policy
+            let group = "ff02::1".parse().unwrap();
+            let err = socket.join_multicast_v6(&group, 0).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOPROTOOPT));
+            let calls = crate::runtime::Handle::current().unsupported_calls();
+            let calls: Vec<_> = calls.iter().map(|call| call.call).collect();
+            assert_eq!(calls, ["setsockopt", "setsockopt"]);
+
+            let broadcast = (libc::SOL_SOCKET, libc::SO_BROADCAST);
+            net.update_config(|cfg| {
+                cfg.sockopt_policies
+                    .insert(broadcast, SockoptPolicy::Ignore);
+            });
+            socket.set_broadcast(true).unwrap();
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn std_tcp_stream() {
+        use std::io::{IoSlice, IoSliceMut, Read, Write};
+
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let f = node2.spawn(async move {
+            let listener = std::net::TcpListener::bind(addr2).unwrap();
+            sleep(Duration::from_secs(1)).await;
+            let (mut stream, from) = listener.accept().unwrap();
+            assert_eq!(from.ip(), addr1.ip());
+
+            let mut buf = [0; 16];
+            let len = stream.read(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"hello");
+            // a segment is read across several calls
+            let (mut head, mut tail) = ([0; 3], [0; 16]);
+            let mut bufs = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)];
+            let len = stream.read_vectored(&mut bufs).unwrap();
+            assert_eq!(len, 8);
+            assert_eq!((&head, &tail[..5]), (b"wor", &b"ld!!!"[..]));
+            let err = stream.read(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+            stream.write_all(b"bye").unwrap();
+            sleep(Duration::from_secs(1)).await;
+            // the client has closed the connection
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+            stream.write_all(b"?").unwrap_err();
+        });
+        node1.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let mut stream = std::net::TcpStream::connect(addr2).unwrap();
+            stream.write_all(b"hello").unwrap();
+            let bufs = [IoSlice::new(b"world"), IoSlice::new(b"!!!")];
+            assert_eq!(stream.write_vectored(&bufs).unwrap(), 8);
+
+            sleep(Duration::from_millis(1500)).await;
+            let mut buf = [0; 16];
+            let len = stream.read(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"bye");
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn std_tcp_stream_drain() {
+        use std::io::{Read, Write};
+
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let f = node2.spawn(async move {
+            let listener = std::net::TcpListener::bind(addr2).unwrap();
+            sleep(Duration::from_secs(1)).await;
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // the client has closed the connection, but its first segment is delayed.
+            sleep(Duration::from_millis(1500)).await;
+            let mut buf = [0; 16];
+            let err = stream.read(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(stream.read(&mut buf).unwrap(), 2);
+            assert_eq!(&buf[..2], b"ab");
+            assert_eq!(stream.read(&mut buf).unwrap(), 2);
+            assert_eq!(&buf[..2], b"cd");
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        });
+        node1.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let mut stream = std::net::TcpStream::connect(addr2).unwrap();
+            sleep(Duration::from_millis(1500)).await;
+            stream.write_all(b"ab").unwrap();
+            stream.write_all(b"cd").unwrap();
+        });
+
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            sleep(Duration::from_millis(1200)).await;
+            net.set_manual_delivery(true);
+            sleep(Duration::from_millis(800)).await;
+            let held = net.deliverable();
+            assert_eq!(held.len(), 2);
+            assert!(net.deliver(&held[1]));
+            sleep(Duration::from_secs(1)).await;
+            assert!(net.deliver(&held[0]));
+            net.set_manual_delivery(false);
+            f.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn tag_fault() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let net = runtime.handle().simulator::<NetSim>();
+        // the consensus plane is slow and duplicates messages
+        net.set_tag_fault(
+            0..10,
+            Some(TagFault {
+                extra_latency: LatencyDistribution::Constant(Duration::from_secs(2)),
+                duplicate_rate: 1.0,
+                duplicate_delay: LatencyDistribution::Constant(Duration::from_secs(1)),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        // the gossip plane loses everything
+        net.set_tag_fault(
+            10..20,
+            Some(TagFault {
+                packet_loss_rate: 1.0,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        let invalid = TagFault {
+            duplicate_rate: 1.5,
+            ..Default::default()
+        };
+        let err = net.set_tag_fault(20..30, Some(invalid)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let f = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let start = Instant::now();
+            let mut received = vec![];
+            for tag in [100, 1, 1] {
+                let recv = timeout(Duration::from_secs(10), ep.recv_from_raw(tag));
+                recv.await.unwrap().unwrap();
+                received.push((tag, start.elapsed().as_secs_f64().round() as u64));
+            }
+            let lost = timeout(Duration::from_secs(10), ep.recv_from_raw(15)).await;
+            assert!(lost.is_err());
+            received
+        });
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            for tag in [1, 15, 100] {
+                ep.send_to(addr2, tag, payload!(vec![1])).await.unwrap();
+            }
+        });
+
+        let received = runtime.block_on(f).unwrap();
+        // the healthy state sync plane is not affected
+        assert_eq!(received, [(100, 0), (1, 2), (1, 3)]);
+
+        net.set_tag_fault(0..10, None).unwrap();
+        net.set_tag_fault(10..20, None).unwrap();
+    }
+
+    #[test]
+    fn degraded_node() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            barrier_.wait().await;
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+        });
+
+        let f = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            barrier.wait().await;
+            let start = Instant::now();
+            let mut buf = vec![0; 0x10];
+            net.recv_from(1, &mut buf).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_secs(10));
+        });
+
+        let id1 = node1.id();
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            net.degrade_node(
+                id1,
+                Some(Degradation {
+                    stall_rate: 1.0,
+                    stall_latency: LatencyDistribution::Constant(Duration::from_secs(10)),
+                    ..Default::default()
+                }),
+            );
+            // the end of an overlapping fault does not clear the degradation.
+            let overlay = net.add_degradation(id1, Degradation::default());
+            net.remove_degradation(id1, overlay);
+            f.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn police_egress() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+        let (tx, rx) = futures::channel::oneshot::channel();
+
+        node2.spawn(async move {
+            let udp = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let tcp = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            let tag = (tcp.allocate_local_tcp_id() as u64) << 32;
+            tx.send(tag).unwrap();
+            let mut received = 0;
+            while timeout(Duration::from_millis(100), udp.recv_from_raw(1))
+                .await
+                .is_ok()
+            {
+                received += 1;
+            }
+            assert_eq!(received, 5);
+            // tcp messages over the cap arrive late instead of being lost
+            tcp.recv_from_raw(tag).await.unwrap();
+            let start = Instant::now();
+            for _ in 1..15 {
+                tcp.recv_from_raw(tag).await.unwrap();
+            }
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+            tcp.deregister_tcp_id(&addr1, (tag >> 32) as u32);
+        });
+
+        let f = node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            sim.police_egress(id1, Some(EgressPolicy::messages(10, 5)));
+            // connections are refused until node2 listens
+            sleep(Duration::from_millis(1)).await;
+            let udp = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let tcp = Endpoint::connect(libc::SOCK_STREAM, addr2).await.unwrap();
+            let tag = rx.await.unwrap();
+            // messages dropped after the policer leave its budget alone
+            let closed = SocketAddr::new(addr2.ip(), 9);
+            for _ in 0..10 {
+                udp.send_to(closed, 1, payload!(vec![1])).await.unwrap_err();
+            }
+            for _ in 0..20 {
+                udp.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+            }
+            assert_eq!(sim.policed(id1), 15);
+            sleep(Duration::from_secs(1)).await;
+            for _ in 0..15 {
+                let data = Payload::new_tcp_data(Box::new(vec![1u8])).with_size(1);
+                tcp.send_to_raw(addr2, tag, data).await.unwrap();
+            }
+            assert_eq!(sim.policed(id1), 15);
+            sleep(Duration::from_secs(2)).await;
+            // only the admitted messages are billed: 5 udp and 15 tcp messages of 1 byte.
+            let traffic = sim.lock_network().traffic()[&(id1, id2)];
+            assert_eq!(traffic, (20, 20));
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn anycast() {
+        let runtime = Runtime::new();
+        let anycast = "10.0.9.9:53".parse::<SocketAddr>().unwrap();
+        let client = runtime.create_node().ip([10, 0, 0, 1].into()).build();
+        let near = runtime.create_node().ip([10, 0, 1, 1].into()).build();
+        let far = runtime.create_node().ip([10, 0, 1, 2].into()).build();
+        let (client_id, near_id, far_id) = (client.id(), near.id(), far.id());
+
+        // each member answers with its own id.
+        for node in [&near, &far] {
+            let id = node.id().0 as u8;
+            node.spawn(async move {
+                let udp = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:53")
+                    .await
+                    .unwrap();
+                let _tcp = Endpoint::bind(libc::SOCK_STREAM, "0.0.0.0:53")
+                    .await
+                    .unwrap();
+                loop {
+                    let (_, from) = udp.recv_from_raw(1).await.unwrap();
+                    udp.send_to(from, 2, payload!(vec![id])).await.unwrap();
+                }
+            });
+        }
+
+        let f = client.spawn(async move {
+            let sim = simulator::<NetSim>();
+            sim.set_anycast(anycast.ip(), [far_id, near_id]);
+            let latency = |ms| Some(LatencyDistribution::Constant(Duration::from_millis(ms)));
+            sim.set_one_way_latency(client_id, far_id, latency(50));
+            sim.set_one_way_latency(client_id, near_id, latency(10));
+            sleep(Duration::from_millis(1)).await;
+
+            let udp = Endpoint::bind(libc::SOCK_DGRAM, "10.0.0.1:1")
+                .await
+                .unwrap();
+            let query = || async {
+                udp.send_to(anycast, 1, payload!(vec![0])).await.unwrap();
+                let (msg, _) = udp.recv_from_raw(2).await.unwrap();
+                msg.downcast::<Vec<u8>>().unwrap()[0] as u64
+            };
+            assert_eq!(query().await, near_id.0);
+
+            // traffic moves to the next closest member when the closest one is unreachable.
+            sim.disconnect2(client_id, near_id);
+            assert_eq!(
+                sim.anycast_member(client_id, libc::SOCK_DGRAM, anycast),
+                Some(far_id)
+            );
+            assert_eq!(query().await, far_id.0);
+            sim.connect2(client_id, near_id);
+            assert_eq!(query().await, near_id.0);
+
+            // connections are established with the closest member.
+            let tcp = Endpoint::connect(libc::SOCK_STREAM, anycast).await.unwrap();
+            assert_eq!(tcp.peer_addr().unwrap(), "10.0.1.1:53".parse().unwrap());
+
+            // no member is healthy.
+            sim.disconnect(near_id);
+            sim.disconnect(far_id);
+            assert_eq!(
+                sim.anycast_member(client_id, libc::SOCK_DGRAM, anycast),
+                None
+            );
+            let err = udp
+                .send_to(anycast, 1, payload!(vec![0]))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn ban() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let id2 = node2.id();
+        let (tx, rx) = futures::channel::oneshot::channel();
+
+        let server = node2.spawn(async move {
+            let udp = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let tcp = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            let tag = (tcp.allocate_local_tcp_id() as u64) << 32;
+            tx.send(tag).unwrap();
+            let mut received = 0;
+            while timeout(Duration::from_secs(20), udp.recv_from_raw(1))
+                .await
+                .is_ok()
+            {
+                received += 1;
+            }
+            // only the messages sent while not banned arrive.
+            assert_eq!(received, 2);
+            tcp.deregister_tcp_id(&addr1, (tag >> 32) as u32);
+        });
+
+        let f = node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            let udp = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let tag = rx.await.unwrap();
+            let tcp = Endpoint::connect(libc::SOCK_STREAM, addr2).await.unwrap();
+            udp.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+
+            sim.ban(id2, addr1.ip(), Some(Duration::from_secs(10)));
+            assert_eq!(sim.bans(id2), [(addr1.ip(), Some(Duration::from_secs(10)))]);
+            // udp messages are dropped.
+            udp.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+            // the established connection is reset.
+            let data = Payload::new_tcp_data(Box::new(vec![1u8])).with_size(1);
+            let err = tcp.send_to_raw(addr2, tag, data).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            // reconnecting is refused.
+            let err = Endpoint::connect(libc::SOCK_STREAM, addr2)
+                .await
+                .unwrap_err();
+            assert_eq!(Error::from_io(&err), Some(&Error::Banned(addr2)));
+            assert_eq!(sim.ban_refused(id2, addr1.ip()), 3);
+
+            // the ban expires.
+            sleep(Duration::from_secs(10)).await;
+            assert!(sim.bans(id2).is_empty());
+            Endpoint::connect(libc::SOCK_STREAM, addr2).await.unwrap();
+            udp.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+
+            sim.ban(id2, addr1.ip(), None);
+            assert!(sim.unban(id2, addr1.ip()));
+            assert!(!sim.unban(id2, addr1.ip()));
+            assert_eq!(sim.ban_refused(id2, addr1.ip()), 3);
+        });
+        runtime.block_on(async move {
+            f.await.unwrap();
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn recv_buffer() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (gossip, vote) = (1, 2);
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        let f = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            barrier_.wait().await;
+            // the buffer fills while nothing is received
+            barrier_.wait().await;
+            let mut received = vec![];
+            for tag in [gossip, vote] {
+                while let Ok(Ok(_)) =
+                    timeout(Duration::from_millis(1), net.recv_from_raw(tag)).await
+                {
+                    received.push(tag);
+                }
+            }
+            received
+        });
+
+        node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            sim.update_config(|cfg| {
+                cfg.recv_buffer = Some(
+                    RecvBufferConfig::new(100)
+                        .class(vote..vote + 1, 1.0)
+                        .default_share(0.5),
+                );
+            });
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            barrier.wait().await;
+            let send = |tag| {
+                let data = Payload::new_udp(Box::new(())).with_size(10);
+                net.send_to_raw_sync(addr2, tag, data).unwrap();
+            };
+            (0..10).for_each(|_| send(gossip));
+            sleep(Duration::from_millis(100)).await;
+            (0..6).for_each(|_| send(vote));
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(sim.recv_buffer_drops(), [1, 5]);
+            // duplicates do not fit either
+            let duplicate = DeliveryOverride {
+                duplicate: Some(Duration::from_millis(1)),
+                ..Default::default()
+            };
+            net.send_to_with(addr2, gossip, payload!(vec![0; 10]), duplicate)
+                .await
+                .unwrap();
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(sim.recv_buffer_drops(), [1, 7]);
+            barrier.wait().await;
+        });
+        let received = runtime.block_on(f).unwrap();
+        assert_eq!(received.iter().filter(|t| **t == gossip).count(), 5);
+        assert_eq!(received.iter().filter(|t| **t == vote).count(), 5);
+    }
+
+    #[test]
+    fn flap_link() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+
+        node2.spawn(async move {
+            let _net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            sleep(Duration::from_secs(100)).await;
+        });
+
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            let flapper = simulator::<NetSim>().flap_link(
+                id1,
+                id2,
+                LatencyDistribution::Constant(Duration::from_secs(1)),
+                LatencyDistribution::Constant(Duration::from_secs(1)),
+            );
+
+            let mut results = vec![];
+            for _ in 0..4 {
+                sleep(Duration::from_millis(500)).await;
+                results.push(net.send_to(addr2, 1, payload!(vec![1])).await.is_ok());
+                sleep(Duration::from_millis(500)).await;
+            }
+            assert_eq!(results, vec![true, false, true, false]);
+
+            sleep(Duration::from_millis(500)).await;
+            drop(flapper);
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+
+            // a link disconnected by the test stays down when it flaps up, and once it stops
+            let sim = simulator::<NetSim>();
+            let second = LatencyDistribution::Constant(Duration::from_secs(1));
+            let flapper = sim.flap_link(id1, id2, second.clone(), second);
+            sim.disconnect_one_way(id1, id2);
+            sleep(Duration::from_millis(2500)).await;
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap_err();
+            drop(flapper);
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap_err();
+            sim.connect_one_way(id1, id2);
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "zero durations")]
+    fn flap_link_zero() {
+        let runtime = Runtime::new();
+        let node1 = runtime.create_node().build();
+        let node2 = runtime.create_node().build();
+        let zero = LatencyDistribution::Constant(Duration::ZERO);
+        let second = LatencyDistribution::Constant(Duration::from_secs(1));
+        let (id1, id2) = (node1.id(), node2.id());
+        runtime.block_on(async move {
+            let _flapper = simulator::<NetSim>().flap_link(id1, id2, second, zero);
+        });
+    }
+
+    #[test]
+    fn track_messages() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let id2 = node2.id();
+
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut buf = vec![0; 0x10];
+            net.recv_from(1, &mut buf).await.unwrap();
+        });
+
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let sim = simulator::<NetSim>();
+            sim.track_messages(true);
+            sleep(Duration::from_millis(1)).await;
+
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+            let delivered = net.last_msg_id().unwrap();
+            net.send_to(addr2.ip().to_string() + ":2", 1, payload!(vec![1]))
+                .await
+                .unwrap_err();
+            let refused = net.last_msg_id().unwrap();
+            sim.disconnect(id2);
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap_err();
+            let clogged = net.last_msg_id().unwrap();
+            sleep(Duration::from_secs(1)).await;
+
+            let status = |id| sim.message(id).unwrap().status;
+            assert_eq!(status(delivered), MsgStatus::Delivered);
+            assert_eq!(
+                status(refused),
+                MsgStatus::Dropped(DropReason::PortUnreachable)
+            );
+            assert_eq!(status(clogged), MsgStatus::Dropped(DropReason::Clogged));
+            assert_eq!(sim.message(delivered).unwrap().dst, addr2);
+            assert_eq!(sim.messages().len(), 3);
+
+            sim.track_messages(false);
+            assert!(sim.message(delivered).is_none());
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "forbidden message sent by Node(2) (client)")]
+    fn forbid() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().name("client").ip(addr2.ip()).build();
+
+        node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            loop {
+                net.recv_from_raw(1).await.unwrap();
+            }
+        });
+
+        let f = node2.spawn(async move {
+            let sim = simulator::<NetSim>();
+            let votes = "dst port 1 and tag 1".parse().unwrap();
+            sleep(Duration::from_millis(1)).await;
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            // the window closes after its duration, or when lifted.
+            sim.forbid(votes, Duration::from_secs(1));
+            net.send_to(addr1, 2, payload!(vec![1])).await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+            net.send_to(addr1, 1, payload!(vec![1])).await.unwrap();
+            let id = sim.forbid("tag 1".parse().unwrap(), Duration::MAX);
+            assert!(sim.allow(id));
+            assert!(!sim.allow(id));
+            net.send_to(addr1, 1, payload!(vec![1])).await.unwrap();
+
+            sim.forbid("tag 1".parse().unwrap(), Duration::MAX);
+            net.send_to(addr1, 1, payload!(vec![1])).await.unwrap();
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn capture_filter() {
+        use crate::trace::{self, EventKind};
+        use filter::CaptureFilter;
+
+        let mut config = crate::SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let addr3 = "10.0.0.3:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let node3 = runtime.create_node().ip(addr3.ip()).build();
+        let (id2, id3) = (node2.id(), node3.id());
+
+        for (node, addr) in [(&node2, addr2), (&node3, addr3)] {
+            node.spawn(async move {
+                let net = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                loop {
+                    net.recv_from_raw(0x10).await.unwrap();
+                }
+            });
+        }
+
+        let f = node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            sim.track_messages(true);
+            sim.set_capture_filter(Some(
+                CaptureFilter::parse("dst 10.0.0.2 and len > 1").unwrap(),
+            ));
+            sim.set_node_capture_filter(id3, Some("tag 0x10".parse().unwrap()));
+            sleep(Duration::from_millis(1)).await;
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            // captured by the global filter, and by the filter of node3
+            net.send_to(addr2, 0x10, payload!(vec![1, 2]))
+                .await
+                .unwrap();
+            net.send_to(addr3, 0x10, payload!(vec![1])).await.unwrap();
+            // not captured
+            net.send_to(addr2, 0x10, payload!(vec![1])).await.unwrap();
+            net.send_to(addr3, 0x11, payload!(vec![1, 2]))
+                .await
+                .unwrap();
+            sleep(Duration::from_secs(1)).await;
+
+            let logged: Vec<_> = sim.messages().into_iter().map(|(_, r)| r.dst).collect();
+            assert_eq!(logged, [addr2, addr3]);
+            let timeline = trace::timeline();
+            let sent: Vec<_> = timeline
+                .events()
+                .iter()
+                .filter_map(|e| match e.kind {
+                    EventKind::MsgSent { to, tag, .. } => Some((to, tag)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(sent, [(id2, 0x10), (id3, 0x10)]);
+
+            // without filters, everything is captured again
+            sim.set_capture_filter(None);
+            sim.set_node_capture_filter(id3, None);
+            net.send_to(addr3, 0x11, payload!(vec![1])).await.unwrap();
+            assert_eq!(sim.messages().len(), 3);
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn manual_delivery() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (tx, rx) = futures::channel::oneshot::channel();
+
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut received = vec![];
+            for _ in 0..2 {
+                let (msg, _) = net.recv_from_raw(1).await.unwrap();
+                received.push(msg.downcast::<Vec<u8>>().unwrap()[0]);
+            }
+            tx.send(received).unwrap();
+        });
+
+        let f = node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            sim.set_manual_delivery(true);
+            sleep(Duration::from_millis(1)).await;
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            for i in 0..3u8 {
+                net.send_to(addr2, 1, payload!(vec![i])).await.unwrap();
+            }
+            // nothing is delivered until the test says so
+            sleep(Duration::from_secs(10)).await;
+            let held = sim.deliverable();
+            assert_eq!(held.len(), 3);
+            assert!(held.iter().all(|msg| msg.dst == addr2 && msg.tag == 1));
+
+            assert!(sim.drop_held(&held[0]));
+            assert!(sim.deliver(&held[2]));
+            assert!(!sim.deliver(&held[2]));
+            assert_eq!(sim.deliverable(), [held[1].clone()]);
+            sim.set_manual_delivery(false);
+            assert!(sim.deliverable().is_empty());
+            rx.await.unwrap()
+        });
+
+        assert_eq!(runtime.block_on(f).unwrap(), [2, 1]);
+    }
+
+    #[test]
+    fn incoming_tags() {
+        use futures::StreamExt;
+
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let f = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+            // one task serves all tags, starting with the messages already waiting
+            let mut incoming = net.incoming_tags();
+            let mut received = vec![];
+            for _ in 0..3 {
+                let (tag, from) = incoming.next().await.unwrap();
+                assert_eq!(from, addr1);
+                let (msg, _) = net.recv_from_raw(tag).await.unwrap();
+                received.push((tag, msg.downcast::<Vec<u8>>().unwrap()[0]));
+            }
+            drop(net);
+            assert_eq!(incoming.next().await, None);
+            received
+        });
+
+        node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            net.send_to(addr2, 7, payload!(vec![1])).await.unwrap();
+            net.send_to(addr2, 3, payload!(vec![2])).await.unwrap();
+            sleep(Duration::from_secs(2)).await;
+            net.send_to(addr2, 5, payload!(vec![3])).await.unwrap();
+        });
+
+        let mut received = runtime.block_on(f).unwrap();
+        // the first two messages race
+        received[..2].sort();
+        assert_eq!(received, [(3, 2), (7, 1), (5, 3)]);
+    }
+
+    /// Send ten 100KB bulk messages and then a control message over a 1MB/s link, and return
+    /// when the control message is received.
+    fn control_latency(bandwidth: BandwidthConfig) -> Duration {
+        let mut config = crate::SimConfig::default();
+        config.net.bandwidth = Some(bandwidth);
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (tx, rx) = futures::channel::oneshot::channel();
+
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            net.recv_from_raw(1).await.unwrap();
+            tx.send(Instant::now()).unwrap();
+        });
+
+        let f = node1.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let start = Instant::now();
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            for _ in 0..10 {
+                net.send_to(addr2, 2, payload!(vec![0; 100_000]))
+                    .await
+                    .unwrap();
+            }
+            net.send_to(addr2, 1, payload!(vec![0])).await.unwrap();
+            rx.await.unwrap() - start
+        });
+
+        runtime.block_on(f).unwrap()
+    }
+
+    #[test]
+    fn bandwidth_lanes() {
+        // without lanes, the control message waits for all bulk messages to be sent.
+        let latency = control_latency(BandwidthConfig::new(1_000_000));
+        assert_eq!((latency.as_secs_f64() * 10.0).round(), 10.0);
+        // with a control lane, it only waits for the bulk message being sent.
+        let latency = control_latency(BandwidthConfig::new(1_000_000).lane(1..2));
+        assert_eq!((latency.as_secs_f64() * 10.0).round(), 1.0);
+    }
+
+    /// Send a 1MB message over a 1MB/s link, and return when it is received.
+    fn bulk_latency(compression: Option<CompressionConfig>) -> (Duration, Stat) {
+        let mut config = crate::SimConfig::default();
+        config.net.bandwidth = Some(BandwidthConfig::new(1_000_000));
+        config.net.compression = compression;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let received = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            net.recv_from_raw(1).await.unwrap();
+            Instant::now()
+        });
+
+        let f = node1.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let start = Instant::now();
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            net.send_to(addr2, 1, payload!(vec![0; 1_000_000]))
+                .await
+                .unwrap();
+            received.await.unwrap() - start
+        });
+
+        runtime.block_on(async move {
+            let latency = f.await.unwrap();
+            (latency, plugin::simulator::<NetSim>().stat())
+        })
+    }
+
+    #[test]
+    fn compression() {
+        // 1s to transmit, plus the latency of the link.
+        let (latency, stat) = bulk_latency(None);
+        assert!((1000..1010).contains(&latency.as_millis()));
+        assert_eq!(stat.compressed_count, 0);
+
+        // a quarter of the transmission time, plus 0.5s of cpu time.
+        let compression = CompressionConfig::new(0.25).cost(400.0, 100.0);
+        let (latency, stat) = bulk_latency(Some(compression.clone()));
+        assert!((750..760).contains(&latency.as_millis()));
+        assert_eq!(stat.compressed_count, 1);
+        assert_eq!(stat.compression_saved_bytes, 750_000);
+        assert_eq!(stat.compression_time, Duration::from_millis(500));
+
+        // messages with other tags are not compressed.
+        let (latency, stat) = bulk_latency(Some(compression.tags(2..3)));
+        assert!((1000..1010).contains(&latency.as_millis()));
+        assert_eq!(stat.compressed_count, 0);
+    }
+
+    #[test]
+    fn break_connection() {
+        let runtime = Runtime::new();
+        let server_addr = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let node2 = runtime.create_node().ip(server_addr.ip()).build();
+        let (tx, rx) = futures::channel::oneshot::channel();
+
+        node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_STREAM, server_addr)
+                .await
+                .unwrap();
+            // the server side ids of three connections.
+            let ids: Vec<u32> = (0..3).map(|_| ep.allocate_local_tcp_id()).collect();
+            tx.send(ids).unwrap();
+            sleep(Duration::from_secs(100)).await;
+            drop(ep);
+        });
+
+        let f = node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            let ids = rx.await.unwrap();
+            let mut conns = vec![];
+            for id in ids {
+                let ep = Endpoint::connect(libc::SOCK_STREAM, server_addr)
+                    .await
+                    .unwrap();
+                let flow = Flow {
+                    proto: libc::SOCK_STREAM,
+                    src: ep.local_addr().unwrap(),
+                    dst: server_addr,
+                };
+                conns.push((ep, flow, id));
+            }
+            let send = |i: usize| {
+                let (ep, _, id) = &conns[i];
+                let data = Payload::new_tcp_data(Box::new(vec![0u8; 10])).with_size(10);
+                ep.send_to_raw_sync(server_addr, (*id as u64) << 32, data)
+            };
+
+            // break the first connection after 15 bytes: the second write does not fit.
+            sim.break_connection_after(&conns[0].1, 15);
+            send(0).unwrap();
+            let err = send(0).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert!(!conns[0].0.is_peer_live(Some(server_addr), conns[0].2));
+            // the other connections are not affected.
+            send(1).unwrap();
+            assert!(conns[1].0.is_peer_live(Some(server_addr), conns[1].2));
+
+            // break the last connection at a given time.
+            let deadline = Instant::now() + Duration::from_secs(1);
+            sim.break_connection_at(conns[2].1.reverse(), deadline);
+            send(2).unwrap();
+            sleep(Duration::from_secs(2)).await;
+            send(2).unwrap_err();
+            send(1).unwrap();
+
+            // a message in flight when its connection breaks is dropped.
+            sleep(Duration::from_secs(1)).await;
+            let dropped = || sim.flow(&conns[1].1).unwrap().packets_dropped;
+            let before = dropped();
+            send(1).unwrap();
+            sim.break_connection(&conns[1].1);
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(dropped(), before + 1);
+
+            // closing the connection forgets that it broke.
+            let (ep, flow, _) = &conns[1];
+            let id = ep.allocate_local_tcp_id();
+            ep.deregister_tcp_id(&server_addr, id);
+            assert!(!sim.lock_network().is_broken(flow));
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn shared_endpoints() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        let f = node2.spawn(async move {
+            let a = Endpoint::bind_shared(libc::SOCK_DGRAM, addr2, 0..100)
+                .await
+                .unwrap();
+            let b = Endpoint::bind_shared(libc::SOCK_DGRAM, addr2, 100..200)
+                .await
+                .unwrap();
+            let err = Endpoint::bind_shared(libc::SOCK_DGRAM, addr2, 150..250)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+            let err = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+            let err = a.recv_from(150, &mut []).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            barrier_.wait().await;
+
+            let mut buf = [0; 1];
+            a.recv_from(1, &mut buf).await.unwrap();
+            assert_eq!(buf, [1]);
+            b.recv_from(101, &mut buf).await.unwrap();
+            assert_eq!(buf, [2]);
+
+            // the socket stays open until the last endpoint is dropped.
+            drop(a);
+            barrier_.wait().await;
+            b.recv_from(101, &mut buf).await.unwrap();
+            drop(b);
+            Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+        });
+
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            barrier.wait().await;
+            ep.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+            ep.send_to(addr2, 101, payload!(vec![2])).await.unwrap();
+            // no endpoint owns the tag.
+            let err = ep.send_to(addr2, 500, payload!(vec![3])).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+            barrier.wait().await;
+            ep.send_to(addr2, 1, payload!(vec![4])).await.unwrap_err();
+            ep.send_to(addr2, 101, payload!(vec![5])).await.unwrap();
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn bursty_loss() {
+        let mut config = crate::SimConfig::default();
+        // the reverse direction loses everything, from the first packet.
+        config.net.loss_model = Some(GilbertElliott::new(1.0, 0.0, 1.0));
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+
+        let f = node1.spawn(async move {
+            let sim = simulator::<NetSim>();
+            let model = GilbertElliott::new(0.1, 0.25, 1.0);
+            assert!((model.mean_loss_rate() - 0.1 / 0.35).abs() < 1e-9);
+            assert_eq!(model.mean_burst_length(), 4.0);
+            sim.set_one_way_loss_model(id1, id2, Some(model)).unwrap();
+            let invalid = GilbertElliott {
+                loss_good: -0.1,
+                ..model
+            };
+            let err = sim.set_one_way_loss_model(id1, id2, Some(invalid));
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            let err = sim.set_one_way_packet_loss(id1, id2, Some(f64::NAN));
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            sim.track_messages(true);
+
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let n = 10000;
+            for _ in 0..n {
+                ep.send_to_raw_sync(addr2, 1, payload!(vec![1])).unwrap();
+            }
+            let lost: Vec<bool> = sim
+                .messages()
+                .into_iter()
+                .map(|(_, record)| record.status == MsgStatus::Dropped(DropReason::PacketLoss))
+                .collect();
+            let rate = lost.iter().filter(|lost| **lost).count() as f64 / n as f64;
+            let bursts = lost.windows(2).filter(|w| !w[0] && w[1]).count();
+            let burst_length = rate * n as f64 / bursts as f64;
+            assert!((0.25..0.32).contains(&rate), "{rate}");
+            assert!((3.5..4.5).contains(&burst_length), "{burst_length}");
+            sim.track_messages(false);
+        });
+        node2.spawn(async move {
+            let _ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            sleep(Duration::from_secs(100)).await;
+        });
+        runtime.block_on(f).unwrap();
+
+        let f = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, "10.0.0.2:2")
+                .await
+                .unwrap();
+            let sim = simulator::<NetSim>();
+            sim.track_messages(true);
+            for _ in 0..10 {
+                ep.send_to_raw_sync(addr1, 1, payload!(vec![1])).unwrap();
+            }
+            assert!(sim
+                .messages()
+                .iter()
+                .all(|(_, r)| r.status == MsgStatus::Dropped(DropReason::PacketLoss)));
+        });
+        runtime.block_on(f).unwrap();
+    }
+
+    #[test]
+    fn tag_names() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let sim = simulator::<NetSim>();
+            sim.register_tag(1, "vote");
+            sim.track_messages(true);
+            assert_eq!(sim.tag_name(1).as_deref(), Some("vote"));
+            assert_eq!(sim.tag_names(), [(1, "vote".to_string())]);
+            assert_eq!(Tag(1).to_string(), "vote");
+            assert_eq!(Tag(2).to_string(), "0x2");
+
+            // nothing is bound at the destination port
+            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap_err();
+            let id = net.last_msg_id().unwrap();
+            let record = sim.message(id).unwrap().to_string();
+            assert_eq!(
+                record,
+                "10.0.0.1:1 -> 10.0.0.2:1 tag=vote dropped (PortUnreachable)"
+            );
+
+            let sent = crate::trace::EventKind::MsgSent {
+                from: id1,
+                to: id2,
+                tag: 1,
+            };
+            assert_eq!(
+                sent.to_string(),
+                format!("msg-sent {id1} -> {id2} tag=vote")
+            );
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
+    /// Two senders each queue three messages at a receiver; return the order they are received.
+    fn received_order(order: DeliveryOrder) -> Vec<(u8, u8)> {
+        let runtime = Runtime::new();
+        let addr = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let receiver = runtime.create_node().ip(addr.ip()).build();
+        for i in 2..4u8 {
+            let sender = runtime.create_node().ip([10, 0, 0, i].into()).build();
+            sender.spawn(async move {
+                sleep(Duration::from_millis(1)).await;
+                let net = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:1").await.unwrap();
+                for j in 0..3 {
+                    net.send_to(addr, 1, payload!(vec![i, j])).await.unwrap();
+                }
+            });
+        }
+
+        let f = receiver.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+            let mut received = vec![];
+            for _ in 0..6 {
+                let mut buf = [0; 2];
+                net.recv_from(1, &mut buf).await.unwrap();
+                received.push((buf[0], buf[1]));
+            }
+            received
+        });
+        runtime.block_on(async move {
+            simulator::<NetSim>().update_config(|cfg| {
+                cfg.latency.default_latency =
+                    LatencyDistribution::Constant(Duration::from_millis(1));
+                cfg.delivery_order = order;
+            });
+            f.await.unwrap()
+        })
     }
 
-    /// Receives a raw message from the connected remote address.
-    ///
-    /// NOTE: Applications should not use this function!
-    /// It is provided for use by other simulators.
-    #[cfg_attr(docsrs, doc(cfg(msim)))]
-    pub async fn recv_raw(&self, tag: u64) -> io::Result<Payload> {
-        let peer = self.peer_addr()?;
-        let (msg, from) = self.recv_from_raw(tag).await?;
-        assert_eq!(
-            from, peer,
-            "receive a message but not from the connected address"
-        );
-        Ok(msg)
+    #[test]
+    fn delivery_order() {
+        let fair = received_order(DeliveryOrder::Fair);
+        let senders: Vec<_> = fair.iter().map(|(i, _)| *i).collect();
+        assert!(senders.windows(2).all(|w| w[0] != w[1]), "{fair:?}");
+
+        let unfair = received_order(DeliveryOrder::Unfair);
+        let senders: Vec<_> = unfair.iter().map(|(i, _)| *i).collect();
+        assert!(senders[..3].iter().all(|i| *i == senders[0]), "{unfair:?}");
+        assert!(senders[3..].iter().all(|i| *i != senders[0]), "{unfair:?}");
     }
 
-    /// Check if the peer of a TCP connection has hung up.
-    pub fn is_peer_live(&self, peer: Option<SocketAddr>, remote_tcp_id: u32) -> bool {
-        let peer = peer.as_ref().unwrap_or_else(|| {
-            self.peer
-                .as_ref()
-                .expect("is_peer_live called without peer")
+    #[test]
+    fn egress_by_label() {
+        let runtime = Runtime::new();
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        // (region, destination, size, count)
+        let plan = [("us", 1, 100, 3), ("eu", 2, 50, 1), ("eu", 0, 10, 1)];
+        let mut sends = vec![];
+        for (i, (region, dst, size, count)) in plan.into_iter().enumerate() {
+            let node = runtime
+                .create_node()
+                .ip(addrs[i].ip())
+                .label("region", region)
+                .build();
+            let (addr, dst) = (addrs[i], addrs[dst]);
+            sends.push(node.spawn(async move {
+                let net = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                sleep(Duration::from_millis(1)).await;
+                for _ in 0..count {
+                    net.send_to(dst, 1, payload!(vec![0; size])).await.unwrap();
+                }
+                // keep the socket open for the messages of the other nodes
+                sleep(Duration::from_secs(1)).await;
+            }));
+        }
+
+        runtime.block_on(async move {
+            for send in sends {
+                send.await.unwrap();
+            }
+            let sim = simulator::<NetSim>();
+            let egress = sim.egress_by_label("region");
+            let stat = |from: &str, to: &str, packets, bytes| EgressStat {
+                from: from.into(),
+                to: to.into(),
+                packets,
+                bytes,
+            };
+            // the traffic within eu is not included
+            assert_eq!(egress, [stat("eu", "us", 1, 10), stat("us", "eu", 3, 300)]);
+            assert_eq!(sim.egress_bytes("region", "us", "eu"), 300);
+            assert_eq!(sim.egress_bytes("region", "us", "ap"), 0);
+            assert!(sim.egress_by_label("zone").is_empty());
         });
-        self.net
-            .network
-            .lock()
-            .unwrap()
-            .is_tcp_session_live(peer, remote_tcp_id)
     }
 
-    /// Check if there is a message waiting that can be received without blocking.
-    /// If not, schedule a wakeup using the context.
-    pub fn recv_ready(&self, cx: Option<&mut Context<'_>>, tag: u64) -> io::Result<bool> {
-        Ok(self.net.network.lock().unwrap().recv_ready(
-            cx,
-            plugin::node(),
-            self.proto,
-            self.addr,
-            tag,
-        ))
+    #[test]
+    fn structured_errors() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+
+        let f = node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let cause = |err: io::Error| {
+                let cause = Error::from_io(&err).cloned().unwrap();
+                assert_eq!(err.kind(), cause.kind());
+                cause
+            };
+
+            let err = ep.send_to(addr2, 1, payload!(vec![])).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert_eq!(cause(err), Error::NoEndpoint(addr2));
+
+            let nowhere = "10.0.0.3:1".parse().unwrap();
+            let err = ep.send_to(nowhere, 1, payload!(vec![])).await.unwrap_err();
+            assert_eq!(cause(err), Error::NodeDown(nowhere));
+
+            simulator::<NetSim>().disconnect2(id1, id2);
+            let err = ep.send_to(addr2, 1, payload!(vec![])).await.unwrap_err();
+            assert_eq!(cause(err), Error::LinkClogged(addr2));
+
+            let err = ep.recv_from_raw_sync(1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+            assert_eq!(cause(err), Error::BufferFull);
+
+            assert_eq!(cause(ep.peer_addr().unwrap_err()), Error::NotConnected);
+
+            // errors that are not caused by the network have no cause
+            let err = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+            assert_eq!(Error::from_io(&err), None);
+        });
+
+        runtime.block_on(f).unwrap();
     }
-}
 
-impl Drop for Endpoint {
-    fn drop(&mut self) {
-        return_if_killed!();
+    #[test]
+    fn flow_stats() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
 
-        // all tcp sessions should already be deregistered.
-        assert!(self.live_tcp_ids.get_mut().unwrap().is_empty());
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut buf = vec![0; 0x10];
+            loop {
+                let (len, from) = net.recv_from(1, &mut buf).await.unwrap();
+                net.send_to(from, 1, payload!(buf[..len].to_vec()))
+                    .await
+                    .unwrap();
+            }
+        });
 
-        // avoid panic on panicking
-        if let Ok(mut network) = self.net.network.lock() {
-            network.close(self.proto, self.node, self.addr);
-        }
-    }
-}
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            let mut buf = vec![0; 0x10];
+            for _ in 0..3 {
+                net.send_to(addr2, 1, payload!(vec![0; 4])).await.unwrap();
+                net.recv_from(1, &mut buf).await.unwrap();
+            }
+            // no socket on port 2
+            net.send_to("10.0.0.2:2", 1, payload!(vec![0; 4]))
+                .await
+                .unwrap_err();
+        });
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        net::network::Payload,
-        plugin::simulator,
-        runtime::{init_logger, Handle, Runtime},
-        time::*,
-    };
-    use tokio::sync::Barrier;
+        runtime.block_on(async move {
+            let sim = simulator::<NetSim>();
+            sim.update_config(|cfg| {
+                cfg.latency.default_latency =
+                    LatencyDistribution::Constant(Duration::from_millis(10));
+            });
+            f.await.unwrap();
 
-    macro_rules! payload {
-        ($e: expr) => {{
-            let v: Vec<u8> = $e;
-            Payload::new_udp(Box::new(v))
-        }};
+            let flow = Flow {
+                proto: libc::SOCK_DGRAM,
+                src: addr1,
+                dst: addr2,
+            };
+            let stat = sim.flow(&flow).unwrap();
+            assert_eq!(stat.packets_sent, 3);
+            assert_eq!(stat.bytes_sent, 12);
+            assert_eq!(stat.packets_delivered, 3);
+            assert_eq!(stat.packets_dropped, 0);
+            assert_eq!(stat.mean_latency(), Some(Duration::from_millis(10)));
+            assert_eq!(sim.rtt(&flow), Some(Duration::from_millis(20)));
+
+            let refused = Flow {
+                dst: "10.0.0.2:2".parse().unwrap(),
+                ..flow
+            };
+            let stat = sim.flow(&refused).unwrap();
+            assert_eq!((stat.packets_sent, stat.packets_dropped), (1, 1));
+            assert_eq!(sim.flows().len(), 3);
+        });
     }
 
     #[test]
-    fn send_recv() {
+    fn flow_limit() {
         let runtime = Runtime::new();
         let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
         let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
         let node1 = runtime.create_node().ip(addr1.ip()).build();
         let node2 = runtime.create_node().ip(addr2.ip()).build();
-        let barrier = Arc::new(Barrier::new(2));
+        let (id1, id2) = (node1.id(), node2.id());
 
-        let barrier_ = barrier.clone();
-        node1.spawn(async move {
-            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
-            barrier_.wait().await;
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut buf = vec![0; 0x10];
+            loop {
+                net.recv_from(1, &mut buf).await.unwrap();
+            }
+        });
 
-            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+        let f = node1.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            for port in 1..=4 {
+                let net = Endpoint::bind(libc::SOCK_DGRAM, ("10.0.0.1", port))
+                    .await
+                    .unwrap();
+                net.send_to(addr2, 1, payload!(vec![0; 4])).await.unwrap();
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
 
-            sleep(Duration::from_secs(1)).await;
-            net.send_to(addr2, 2, payload!(vec![2])).await.unwrap();
+        runtime.block_on(async move {
+            let sim = simulator::<NetSim>();
+            sim.update_config(|cfg| cfg.flow_limit = Some(2));
+            f.await.unwrap();
+
+            // the flows of the first ports were dropped to make room.
+            let flows = sim.flows();
+            assert!(flows.len() <= 2);
+            let last = flows.last().unwrap();
+            assert_eq!(last.0.src, "10.0.0.1:4".parse().unwrap());
+            assert_eq!(last.1.packets_delivered, 1);
+
+            // deleting a node drops its flows and its state.
+            Handle::current().delete_node(id2);
+            assert!(sim.flows().is_empty());
+            assert!(!sim.lock_network().traffic().contains_key(&(id1, id2)));
         });
+    }
 
-        let f = node2.spawn(async move {
-            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
-            barrier.wait().await;
+    #[test]
+    fn endpoint_hooks() {
+        use std::sync::atomic::{AtomicU64, Ordering};
 
-            let mut buf = vec![0; 0x10];
-            let (len, from) = net.recv_from(2, &mut buf).await.unwrap();
-            assert_eq!(len, 1);
-            assert_eq!(from, addr1);
-            assert_eq!(buf[0], 2);
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
 
-            let (len, from) = net.recv_from(1, &mut buf).await.unwrap();
-            assert_eq!(len, 1);
-            assert_eq!(from, addr1);
-            assert_eq!(buf[0], 1);
+        node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut buf = vec![0; 0x10];
+            loop {
+                let (len, from) = ep.recv_from(1, &mut buf).await.unwrap();
+                ep.send_to(from, 2, payload!(buf[..len].to_vec()))
+                    .await
+                    .unwrap();
+            }
         });
 
+        let f = node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            let sent = Arc::new(Mutex::new(vec![]));
+            let received = Arc::new(AtomicU64::new(0));
+            let sent_ = sent.clone();
+            ep.on_send(move |msg| sent_.lock().unwrap().push((msg.peer, msg.tag)));
+            let received_ = received.clone();
+            ep.on_recv(move |msg| {
+                assert_eq!(msg.peer, addr2);
+                received_.fetch_add(msg.payload.size() as u64, Ordering::SeqCst);
+            });
+
+            sleep(Duration::from_millis(1)).await;
+            let mut buf = vec![0; 0x10];
+            for len in 1..=3 {
+                ep.send_to(addr2, 1, payload!(vec![0; len])).await.unwrap();
+                ep.recv_from(2, &mut buf).await.unwrap();
+            }
+            assert_eq!(*sent.lock().unwrap(), vec![(addr2, 1); 3]);
+            assert_eq!(received.load(Ordering::SeqCst), 6);
+        });
         runtime.block_on(f).unwrap();
     }
 
     #[test]
-    fn receiver_drop() {
+    fn checksum() {
         let runtime = Runtime::new();
         let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
         let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
         let node1 = runtime.create_node().ip(addr1.ip()).build();
         let node2 = runtime.create_node().ip(addr2.ip()).build();
-        let barrier = Arc::new(Barrier::new(2));
 
-        let barrier_ = barrier.clone();
         node1.spawn(async move {
-            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
-            barrier_.wait().await;
-
-            net.send_to(addr2, 1, payload!(vec![1])).await.unwrap();
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            net.send_to(addr2, 1, payload!(vec![1, 2, 3]))
+                .await
+                .unwrap();
         });
 
         let f = node2.spawn(async move {
-            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
-            let mut buf = vec![0; 0x10];
-            timeout(Duration::from_secs(1), net.recv_from(1, &mut buf))
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let (payload, _) = net.recv_from_raw(1).await.unwrap();
+            assert!(payload.checksum().is_some());
+            payload.verify_checksum(&[1, 2, 3], String::new);
+
+            let corrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                payload.verify_checksum(&[1, 2, 4], || "test message".into());
+            }));
+            assert!(corrupted.is_err());
+        });
+
+        runtime.block_on(async move {
+            simulator::<NetSim>().update_config(|cfg| cfg.checksum = true);
+            f.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn checksum_tampering() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let net = runtime.handle().simulator::<NetSim>();
+        net.update_config(|cfg| cfg.checksum = true);
+        // flip the last byte of messages with tag 2
+        net.set_tamperer(|_, _, tag, data| {
+            if let PayloadData::Bytes(bytes) = data {
+                if tag == 2 {
+                    *bytes = vec![bytes[0], bytes[1], !bytes[2]].into();
+                }
+            }
+            tag == 2
+        });
+
+        node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            net.send_to(addr2, 1, payload!(vec![1, 2, 3]))
                 .await
-                .err()
                 .unwrap();
-            // timeout and receiver dropped here
-            barrier.wait().await;
+            net.send_to(addr2, 2, payload!(vec![1, 2, 3]))
+                .await
+                .unwrap();
+        });
 
-            // receive again should success
-            let (len, from) = net.recv_from(1, &mut buf).await.unwrap();
-            assert_eq!(len, 1);
-            assert_eq!(from, addr1);
+        let f = node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let (payload, _) = net.recv_from_raw(1).await.unwrap();
+            assert_eq!(payload.intact(), Some(true));
+
+            let (payload, _) = net.recv_from_raw(2).await.unwrap();
+            assert_eq!(payload.intact(), Some(false));
+            assert_eq!(payload.checksum(), Some(network::checksum(&[1, 2, 3])));
+            // the tampering was injected on purpose, so copies of it still verify
+            payload.verify_checksum(&[1, 2, !3], String::new);
         });
 
         runtime.block_on(f).unwrap();
     }
 
     #[test]
-    fn reset() {
+    fn dual_stack() {
+        use socket2::{Domain, MaybeUninitSlice, Socket, Type};
+        use std::{io::IoSlice, mem::MaybeUninit};
+
         let runtime = Runtime::new();
         let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let mapped2 = "[::ffff:10.0.0.2]:1".parse::<SocketAddr>().unwrap();
         let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
 
-        let f = node1.spawn(async move {
-            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
-            let err = net.recv_from(1, &mut []).await.unwrap_err();
-            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
-            // FIXME: should still error
-            // let err = net.recv_from(1, &mut []).await.unwrap_err();
-            // assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let (payload, from) = net.recv_from_raw(1).await.unwrap();
+            assert_eq!(from, addr1);
+            net.send_to_raw(from, 1, payload).await.unwrap();
         });
 
-        runtime.block_on(async move {
+        let f = node1.spawn(async move {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, None).unwrap();
+            socket.set_only_v6(false).unwrap();
+            socket
+                .bind(&"[::]:1".parse::<SocketAddr>().unwrap().into())
+                .unwrap();
+            assert_eq!(
+                socket.local_addr().unwrap().as_socket().unwrap(),
+                "[::ffff:10.0.0.1]:1".parse::<SocketAddr>().unwrap()
+            );
+
+            sleep(Duration::from_millis(1)).await;
+            socket
+                .send_to_vectored(&[IoSlice::new(&[1, 2, 3])], &mapped2.into())
+                .unwrap();
+
             sleep(Duration::from_secs(1)).await;
-            simulator::<NetSim>().reset_node(node1.id());
-            f.await.unwrap();
+            let mut buf = [MaybeUninit::new(0u8); 16];
+            let (len, _, from) = socket
+                .recv_from_vectored(&mut [MaybeUninitSlice::new(&mut buf)])
+                .unwrap();
+            let buf: Vec<u8> = buf[..len]
+                .iter()
+                .map(|b| unsafe { b.assume_init() })
+                .collect();
+            assert_eq!(buf, [1, 2, 3]);
+            assert_eq!(from.as_socket().unwrap(), mapped2);
+
+            // other ipv6 addresses are not supported
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, None).unwrap();
+            let err = socket
+                .bind(&"[::1]:2".parse::<SocketAddr>().unwrap().into())
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EADDRNOTAVAIL));
         });
+
+        runtime.block_on(f).unwrap();
     }
 
     #[test]
@@ -1589,6 +5108,75 @@ mod tests {
         runtime.block_on(f).unwrap();
     }
 
+    #[test]
+    fn connect_unreachable() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier_ = barrier.clone();
+        node1.spawn(async move {
+            let _ep = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            barrier_.wait().await;
+            sleep(Duration::from_secs(1000)).await;
+        });
+
+        let f = node2.spawn(async move {
+            let sim = simulator::<NetSim>();
+            let cause = |err: io::Error| Error::from_io(&err).cloned().unwrap();
+            let secs = |start: Instant| start.elapsed().as_secs_f64().round() as u64;
+            barrier.wait().await;
+
+            // nothing listens on the port
+            let refused = SocketAddr::new(addr1.ip(), 2);
+            let err = Endpoint::connect(libc::SOCK_STREAM, refused)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert_eq!(cause(err), Error::NoEndpoint(refused));
+
+            // the requests are lost until the timeout
+            sim.disconnect2(id1, id2);
+            let start = Instant::now();
+            let err = Endpoint::connect(libc::SOCK_STREAM, addr1)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(cause(err), Error::TimedOut(addr1));
+            assert_eq!(secs(start), 127);
+            let start = Instant::now();
+            let err = std::net::TcpStream::connect(addr1).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+            assert_eq!(secs(start), 127);
+            // udp has no handshake
+            Endpoint::connect(libc::SOCK_DGRAM, addr1).await.unwrap();
+
+            // a retransmission gets through once the partition heals
+            let start = Instant::now();
+            crate::task::spawn(async move {
+                sleep(Duration::from_secs(10)).await;
+                simulator::<NetSim>().connect2(id1, id2);
+            });
+            Endpoint::connect(libc::SOCK_STREAM, addr1).await.unwrap();
+            assert_eq!(secs(start), 15);
+
+            sim.update_config(|config| config.connect_timeout = Some(Duration::from_secs(5)));
+            let start = Instant::now();
+            let nowhere = "10.0.0.9:1".parse().unwrap();
+            let err = Endpoint::connect(libc::SOCK_STREAM, nowhere)
+                .await
+                .unwrap_err();
+            assert_eq!(cause(err), Error::TimedOut(nowhere));
+            assert_eq!(secs(start), 5);
+        });
+
+        runtime.block_on(f).unwrap();
+    }
+
     #[test]
     fn test_std_connect() {
         use std::net::{TcpListener, TcpStream};
@@ -1,13 +1,27 @@
-use super::config::NetworkConfig;
-use crate::{plugin, rand::*, task::NodeId, time::TimeHandle};
-use futures::channel::oneshot;
+use super::config::{
+    Degradation, DeliveryOrder, DeliveryOverride, EgressPolicy, GilbertElliott,
+    LatencyDistribution, NetworkConfig, TagFault, WanLink,
+};
+use super::{error::Error, filter::CaptureFilter, topology::TopologyLink};
+use crate::{
+    plugin,
+    profile::Category,
+    rand::*,
+    task::NodeId,
+    time::{Instant, TimeHandle},
+    trace::{EventKind, Trace},
+};
+use bytes::{Buf, BufMut, Bytes};
+use futures::channel::{mpsc, oneshot};
 use std::{
-    any::Any,
-    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    any::{Any, TypeId},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     io,
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
+    ops::Range,
+    sync::{Arc, Mutex, Weak},
     task::{Context, Waker},
+    time::Duration,
 };
 
 use tap::TapOptional;
@@ -24,9 +38,332 @@ pub(crate) struct Network {
     addr_to_node: HashMap<IpAddr, NodeId>,
     clogged_node: HashSet<NodeId>,
     clogged_link: HashSet<(NodeId, NodeId)>,
+    /// Nodes and links clogged by faults that come and go on their own, like churn, with the
+    /// number of faults clogging each. Kept apart from the clogs of the user, which these
+    /// faults never lift.
+    fault_clogs: HashMap<PartitionTarget, usize>,
+    /// One-way latency overrides, keyed by (src, dst).
+    link_latency: HashMap<(NodeId, NodeId), LatencyDistribution>,
+    /// One-way packet loss overrides, keyed by (src, dst).
+    link_packet_loss: HashMap<(NodeId, NodeId), f64>,
+    /// One-way loss model overrides, keyed by (src, dst).
+    link_loss_model: HashMap<(NodeId, NodeId), GilbertElliott>,
+    /// Whether a link is in the bad state of its loss model, keyed by (src, dst).
+    link_loss_bad: HashMap<(NodeId, NodeId), bool>,
+    /// Degraded nodes.
+    degraded_node: HashMap<NodeId, Degradation>,
+    /// Degradations added on top of `degraded_node` by faults, keyed by an id to remove them.
+    degradation_overlays: HashMap<NodeId, BTreeMap<u64, Degradation>>,
+    next_overlay: u64,
+    /// Messages that must not be sent, see `NetSim::forbid`.
+    forbidden: Vec<Forbidden>,
+    next_forbid_id: u64,
+    /// Udp messages dropped by full receive buffers, by drop precedence class, see
+    /// `NetworkConfig::recv_buffer`.
+    recv_buffer_drops: RecvBufferDrops,
+    /// Egress policers of the nodes, see `NetSim::police_egress`.
+    policers: HashMap<NodeId, Policer>,
+    /// Peers banned by each node, keyed by (node, peer ip), with when the ban expires, see
+    /// `NetSim::ban`.
+    bans: HashMap<(NodeId, IpAddr), Option<Duration>>,
+    /// Messages and connection requests refused by bans, keyed by (node, peer ip).
+    ban_refused: HashMap<(NodeId, IpAddr), u64>,
+    /// Members of the anycast addresses, see `NetSim::set_anycast`.
+    anycast: HashMap<IpAddr, Vec<NodeId>>,
+    /// Faults of the messages with a tag in a range, in the order they were set.
+    tag_faults: Vec<(Range<u64>, TagFault)>,
+    next_msg_id: u64,
+    /// Id of the last message passed to `send`.
+    last_msg_id: Option<MsgId>,
+    /// Fate of each sent message, if tracking is enabled.
+    msg_log: Option<MsgLog>,
+    /// Per-flow statistics.
+    flows: FlowLog,
+    /// Messages dropped by injected faults, see [`DropReason::is_fault`].
+    fault_drops: FaultDropLog,
+    /// Clogged nodes and links, with when they were clogged.
+    partitions: HashMap<PartitionTarget, Instant>,
+    /// The number of partitions started, and the total duration of those that ended.
+    partition_totals: (u64, Duration),
+    /// Broken tcp connections, keyed by their normalized flow, until either end closes. Shared
+    /// with the messages in flight, which are dropped if their connection breaks before they
+    /// arrive.
+    broken_conns: BrokenConns,
+    /// Bytes that may still be sent in a flow before its tcp connection is broken.
+    conn_break_after: HashMap<Flow, u64>,
+    /// Names and network configurations of the clusters, indexed by cluster id.
+    clusters: Vec<(String, Arc<NetworkConfig>)>,
+    /// The cluster of each node that is in one.
+    node_cluster: HashMap<NodeId, usize>,
+    /// Links between clusters, keyed by (src, dst) cluster ids.
+    wan_links: HashMap<(usize, usize), WanLink>,
+    /// Mutates messages in flight, see `NetSim::set_tamperer`. Called on delivery, without the
+    /// network lock, so that it can use the network.
+    tamperer: Option<Arc<Mutex<Tamperer>>>,
+    /// Tampered messages that were delivered, if tracking is enabled.
+    tamper_log: Option<TamperLog>,
+    /// Filter of the messages recorded in the trace and the message log, for all nodes.
+    capture_filter: Option<CaptureFilter>,
+    /// Filters of the messages sent or received by a node.
+    node_capture_filters: HashMap<NodeId, CaptureFilter>,
+    /// Messages waiting for the test to deliver them, in manual delivery mode.
+    held: Option<Vec<(MsgHandle, Delivery)>>,
+    /// Messages waiting to be sent by each node, when bandwidth is limited.
+    egress: HashMap<NodeId, Arc<Mutex<Egress>>>,
+    /// Packets and bytes sent from a node to another, keyed by (src, dst).
+    traffic: HashMap<(NodeId, NodeId), (u64, u64)>,
+}
+
+/// Delivers a message to its mailbox.
+type Delivery = Box<dyn FnOnce() + Send + Sync>;
+
+/// The messages waiting to be sent by a node, see [`BandwidthConfig`](super::BandwidthConfig).
+struct Egress {
+    time: TimeHandle,
+    node: NodeId,
+    /// Whether a message is being sent.
+    busy: bool,
+    /// Messages by lane, with their transmit time and the callback to run once they are sent.
+    lanes: Vec<VecDeque<(Duration, Delivery)>>,
+}
+
+impl Egress {
+    fn enqueue(this: &Arc<Mutex<Self>>, lane: usize, transmit: Duration, sent: Delivery) {
+        let mut egress = this.lock().unwrap();
+        if egress.lanes.len() <= lane {
+            egress.lanes.resize_with(lane + 1, VecDeque::new);
+        }
+        egress.lanes[lane].push_back((transmit, sent));
+        if !egress.busy {
+            drop(egress);
+            Self::send_next(this);
+        }
+    }
+
+    /// Start sending the first message of the highest priority lane.
+    fn send_next(this: &Arc<Mutex<Self>>) {
+        let mut egress = this.lock().unwrap();
+        let next = egress.lanes.iter_mut().find_map(|lane| lane.pop_front());
+        egress.busy = next.is_some();
+        let Some((transmit, sent)) = next else {
+            return;
+        };
+        let (time, node) = (egress.time.clone(), egress.node);
+        drop(egress);
+        let this = this.clone();
+        let deadline = time.now_instant() + transmit;
+        time.add_timer_for_node(node, deadline, move || {
+            sent();
+            Self::send_next(&this);
+        });
+    }
+}
+
+/// Identifies a window in which some messages must not be sent, see
+/// [`NetSim::forbid`](super::NetSim::forbid).
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ForbidId(u64);
+
+/// Messages that must not be sent until a deadline.
+struct Forbidden {
+    id: ForbidId,
+    filter: CaptureFilter,
+    /// When the window was armed.
+    since: Duration,
+    /// When the window closes, `None` if it stays open until lifted.
+    until: Option<Duration>,
+}
+
+/// The token bucket enforcing the [`EgressPolicy`] of a node.
+struct Policer {
+    policy: EgressPolicy,
+    /// Units left in the bucket, negative while tcp messages are held back.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled: Duration,
+    /// Udp packets dropped.
+    dropped: u64,
+}
+
+impl Policer {
+    /// Check whether a message of `size` bytes sent at `now` passes the policer.
+    ///
+    /// Returns `None` if the message should be dropped, or the time it is held back otherwise.
+    /// Its units are only taken by [`Self::take`], once nothing else drops it.
+    fn admit(&mut self, now: Duration, udp: bool, size: u64) -> Option<Duration> {
+        let rate = self.policy.rate as f64;
+        let refill = (now - self.refilled).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(self.policy.burst as f64);
+        self.refilled = now;
+        let cost = self.policy.cost(size);
+        if udp && self.tokens < cost {
+            self.dropped += 1;
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (cost - self.tokens).max(0.0) / rate,
+        ))
+    }
+
+    /// Take the units of an admitted message of `size` bytes.
+    fn take(&mut self, size: u64) {
+        self.tokens -= self.policy.cost(size);
+    }
+}
+
+/// How packets between two nodes are carried, see `NetSim::add_cluster`.
+enum Route {
+    /// Within a cluster, or to or from a node outside of all clusters, which use the global
+    /// configuration.
+    Lan(Option<Arc<NetworkConfig>>),
+    /// Between two clusters.
+    Wan(WanLink),
+    /// Between two clusters that are not linked.
+    Unreachable,
+}
+
+/// The tampering of a message on delivery, see `NetSim::set_tamperer`.
+#[derive(Clone)]
+struct Tamper {
+    tamperer: Option<Arc<Mutex<Tamperer>>>,
+    log: Option<TamperLog>,
+    record: TamperRecord,
+}
+
+impl Tamper {
+    /// Pass a message through the tamperer. Returns `true` if the message was tampered with.
+    fn apply(&self, data: &mut Payload) -> bool {
+        let Some(tamperer) = &self.tamperer else {
+            return false;
+        };
+        let before = match &data.data {
+            PayloadData::Bytes(bytes) => Some(bytes.clone()),
+            _ => None,
+        };
+        let TamperRecord { from, to, tag, .. } = self.record;
+        let reported = (tamperer.lock().unwrap())(from, to, tag, &mut data.data);
+        // the ground truth does not rely on the tamperer alone: changed bytes are tampered.
+        let changed = match (&before, &data.data) {
+            (Some(before), PayloadData::Bytes(after)) => before != after,
+            (before, _) => before.is_some(),
+        };
+        let tampered = reported || changed;
+        if tampered {
+            trace!("tampered");
+            data.tampered();
+        }
+        tampered
+    }
+
+    /// Record the delivery of the tampered message.
+    fn delivered(&self) {
+        if let Some(log) = &self.log {
+            log.lock().unwrap().push(self.record);
+        }
+    }
+}
+
+/// The arrival of a message or of its duplicate at the socket it was sent to.
+#[derive(Clone)]
+struct Arrival {
+    handle: MsgHandle,
+    mailbox: Weak<Mutex<Mailbox>>,
+    /// The set of broken tcp connections and the flow of the message, for tcp messages.
+    broken: Option<(BrokenConns, Flow)>,
+    listen_backlog: bool,
+    /// The class and size limit of the receive buffer, and the drops of each class, for udp
+    /// messages when receive buffers are limited.
+    recv_buffer: Option<((usize, u64), RecvBufferDrops)>,
+    recorder: Option<Trace>,
+    tamper: Tamper,
+}
+
+impl Arrival {
+    /// Deliver `msg` unless its connection broke, its socket was closed or the socket has no
+    /// room for it, and record the outcome in `record`.
+    fn deliver_checked(&self, mut msg: Message, record: &SendRecord, latency: Duration) {
+        let MsgHandle {
+            id,
+            from,
+            to,
+            src,
+            dst,
+            tag,
+            duplicate,
+        } = self.handle.clone();
+        let what = if duplicate {
+            "deliver duplicate"
+        } else {
+            "deliver"
+        };
+        if let Some((broken, flow)) = &self.broken {
+            if broken.lock().unwrap().contains(flow) {
+                trace!("{what}: tcp connection {flow} broke in flight");
+                record.dropped(DropReason::ConnectionClosed);
+                return;
+            }
+        }
+        let Some(mailbox) = self.mailbox.upgrade() else {
+            trace!("{what}: mailbox was destroyed before delivery");
+            record.dropped(DropReason::SocketClosed);
+            return;
+        };
+        let tampered = self.tamper.apply(&mut msg.data);
+        let mut mailbox = mailbox.lock().unwrap();
+        if self.listen_backlog && mailbox.accept_queue_full(&msg) {
+            trace!("{what}: accept queue of {dst} is full, tag={}", Tag(tag));
+            record.dropped(DropReason::AcceptQueueFull);
+            return;
+        }
+        if let Some(((class, limit), drops)) = &self.recv_buffer {
+            if mailbox.overflows(&msg, *limit) {
+                trace!("{what}: receive buffer of {dst} is full, tag={}", Tag(tag));
+                let mut drops = drops.lock().unwrap();
+                if drops.len() <= *class {
+                    drops.resize(class + 1, 0);
+                }
+                drops[*class] += 1;
+                record.dropped(DropReason::RecvBufferFull);
+                return;
+            }
+        }
+        trace!(
+            "{what}: {src}(node: {from}) -> {dst}(node: {to}), tag={}",
+            Tag(tag)
+        );
+        if let Some(recorder) = &self.recorder {
+            recorder.record(EventKind::MsgDelivered { from, to, tag });
+        }
+        if let Some(bytes) = msg.data.bytes() {
+            msg.data.verify_checksum(bytes, || {
+                format!("{id} {src} -> {dst}, tag={}, on delivery", Tag(tag))
+            });
+        }
+        mailbox.deliver(msg);
+        drop(mailbox);
+        record.delivered(latency);
+        if tampered {
+            self.tamper.delivered();
+        }
+    }
 }
 
+type MsgLog = Arc<Mutex<HashMap<MsgId, MsgRecord>>>;
+type TamperLog = Arc<Mutex<Vec<TamperRecord>>>;
+pub(crate) type Tamperer = Box<dyn FnMut(NodeId, NodeId, u64, &mut PayloadData) -> bool + Send>;
+type FlowLog = Arc<Mutex<HashMap<Flow, FlowStat>>>;
+type FaultDropLog = Arc<Mutex<HashMap<(NodeId, NodeId), u64>>>;
+type BrokenConns = Arc<Mutex<HashSet<Flow>>>;
+type RecvBufferDrops = Arc<Mutex<Vec<u64>>>;
+
+/// The flows whose statistics are kept, if [`NetworkConfig::flow_limit`] is not set.
+const FLOW_LIMIT: usize = 1 << 16;
+
 /// Network for a node.
+///
+/// Large simulations have many idle nodes, so the collections that most nodes leave empty are
+/// ordered ones, which are half the size of hash maps.
 struct Node {
     /// IP address of the node.
     ///
@@ -34,9 +371,11 @@ struct Node {
     ip: Option<IpAddr>,
     /// Sockets in the node.
     sockets: HashMap<SocketKey, Arc<Mutex<Mailbox>>>,
+    /// Tag namespaces of the endpoints sharing a socket, see `bind_shared`.
+    shared: BTreeMap<SocketKey, Vec<Range<u64>>>,
 
     /// live tcp connections.
-    live_tcp_ids: HashSet<u32>,
+    live_tcp_ids: BTreeSet<u32>,
 
     /// Next ephemeral port. There is some code in sui/narwhal that wants to pick ports in advance
     /// and then bind to them later. This is done in narwhal by binding to an ephemeral port,
@@ -48,12 +387,36 @@ struct Node {
     next_ephemeral_port: u16,
 }
 
+impl Node {
+    /// Resolve the IP of an address to bind, checking that it belongs to the node.
+    fn resolve_ip(&self, mut addr: SocketAddr) -> io::Result<SocketAddr> {
+        if addr.ip().is_unspecified() {
+            if let Some(ip) = self.ip {
+                addr.set_ip(ip);
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("cannot bind {addr}: the node has no IP"),
+                ));
+            }
+        } else if addr.ip().is_loopback() {
+        } else if addr.ip() != self.ip.expect("node IP is unset") {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("invalid address: {addr}"),
+            ));
+        }
+        Ok(addr)
+    }
+}
+
 impl Default for Node {
     fn default() -> Self {
         Self {
             ip: None,
             sockets: HashMap::new(),
-            live_tcp_ids: HashSet::new(),
+            shared: BTreeMap::new(),
+            live_tcp_ids: BTreeSet::new(),
             next_ephemeral_port: 0x8000,
         }
     }
@@ -65,9 +428,456 @@ impl Default for Node {
 pub struct Stat {
     /// Total number of messages.
     pub msg_count: u64,
+    /// Number of messages compressed, see [`NetworkConfig::compression`].
+    pub compressed_count: u64,
+    /// Bytes not sent thanks to compression.
+    pub compression_saved_bytes: u64,
+    /// CPU time spent compressing and decompressing messages.
+    pub compression_time: Duration,
+}
+
+/// Identifies a message sent through the network.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MsgId(pub u64);
+
+impl std::fmt::Display for MsgId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "msg#{}", self.0)
+    }
+}
+
+/// A message in flight, held until the test delivers it in
+/// [manual delivery mode](super::NetSim::set_manual_delivery).
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MsgHandle {
+    /// The id of the message.
+    pub id: MsgId,
+    /// The sending node.
+    pub from: NodeId,
+    /// The receiving node.
+    pub to: NodeId,
+    /// The source address.
+    pub src: SocketAddr,
+    /// The destination address.
+    pub dst: SocketAddr,
+    /// The message tag.
+    pub tag: u64,
+    /// Whether this is a duplicate of the message, injected by the network.
+    pub duplicate: bool,
+}
+
+impl std::fmt::Display for MsgHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} tag={}",
+            self.id,
+            self.src,
+            self.dst,
+            Tag(self.tag)
+        )?;
+        if self.duplicate {
+            write!(f, " (duplicate)")?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+/// A message tag, displayed with the name registered with [`NetSim::register_tag`], or in hex
+/// if it has no name.
+///
+/// [`NetSim::register_tag`]: super::NetSim::register_tag
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tag(pub u64);
+
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match super::current_tag_name(self.0) {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Why a message was dropped.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// No node has the destination address, or it is in a cluster that is not linked to the
+    /// cluster of the source.
+    HostUnreachable,
+    /// The source node, destination node or link was clogged.
+    Clogged,
+    /// Lost to random packet loss, including loss from a degraded node.
+    PacketLoss,
+    /// The tcp connection had already been closed by the peer.
+    ConnectionClosed,
+    /// No socket was bound to the destination port.
+    PortUnreachable,
+    /// The destination socket was closed while the message was in flight.
+    SocketClosed,
+    /// A connection request arrived at a listener whose accept queue was full, see
+    /// [`NetworkConfig::listen_backlog`].
+    AcceptQueueFull,
+    /// The receive buffer of the destination socket was filled past the share of the drop
+    /// precedence class of the message, see [`NetworkConfig::recv_buffer`].
+    RecvBufferFull,
+    /// The source node exceeded its egress policy, see
+    /// [`NetSim::police_egress`](super::NetSim::police_egress).
+    Policed,
+    /// The destination node bans the source node, see [`NetSim::ban`](super::NetSim::ban).
+    Banned,
+}
+
+impl DropReason {
+    /// Whether the message was dropped by an injected fault: a clog, or packet loss.
+    pub fn is_fault(&self) -> bool {
+        matches!(self, DropReason::Clogged | DropReason::PacketLoss)
+    }
+}
+
+/// What a [`Partition`] cut off from the network.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PartitionTarget {
+    /// All traffic of a node, see [`NetSim::disconnect`](super::NetSim::disconnect).
+    Node(NodeId),
+    /// The link from a node to another, see
+    /// [`NetSim::disconnect_one_way`](super::NetSim::disconnect_one_way).
+    /// [`NetSim::disconnect2`](super::NetSim::disconnect2) partitions both directions, one
+    /// partition each.
+    Link(NodeId, NodeId),
+}
+
+impl PartitionTarget {
+    /// Whether the target is `node`, or a link from or to it.
+    fn involves(self, node: NodeId) -> bool {
+        match self {
+            PartitionTarget::Node(id) => id == node,
+            PartitionTarget::Link(src, dst) => src == node || dst == node,
+        }
+    }
+}
+
+/// A node or a link that is clogged.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// The clogged node or link.
+    pub target: PartitionTarget,
+    /// When the node or link was clogged.
+    pub start: Instant,
+}
+
+impl Partition {
+    /// How long the partition has lasted until `now`.
+    pub fn duration(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.start)
+    }
+}
+
+/// The fate of a sent message.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgStatus {
+    /// The message is in flight, or was discarded because the destination node was killed
+    /// before it arrived.
+    InFlight,
+    /// The message was delivered to the destination socket.
+    Delivered,
+    /// The message was dropped.
+    Dropped(DropReason),
+}
+
+/// A message recorded while message tracking is enabled.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MsgRecord {
+    /// The source address.
+    pub src: SocketAddr,
+    /// The destination address.
+    pub dst: SocketAddr,
+    /// The message tag.
+    pub tag: u64,
+    /// What happened to the message.
+    pub status: MsgStatus,
+}
+
+impl std::fmt::Display for MsgRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} tag={} ", self.src, self.dst, Tag(self.tag))?;
+        match self.status {
+            MsgStatus::InFlight => write!(f, "in-flight"),
+            MsgStatus::Delivered => write!(f, "delivered"),
+            MsgStatus::Dropped(reason) => write!(f, "dropped ({reason:?})"),
+        }
+    }
+}
+
+/// A delivery of a message that was tampered with in flight, see [`NetSim::set_tamperer`].
+///
+/// [`NetSim::set_tamperer`]: super::NetSim::set_tamperer
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TamperRecord {
+    /// The id of the message.
+    pub id: MsgId,
+    /// The source node.
+    pub from: NodeId,
+    /// The destination node.
+    pub to: NodeId,
+    /// The message tag.
+    pub tag: u64,
+}
+
+/// A tampered message reported by the system under test, see [`NetSim::score_tampering`].
+///
+/// [`NetSim::score_tampering`]: super::NetSim::score_tampering
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TamperDetection {
+    /// The node the message claimed to come from.
+    pub from: NodeId,
+    /// The node that detected the tampering.
+    pub to: NodeId,
+    /// The message tag, or `None` to match a message with any tag.
+    pub tag: Option<u64>,
+}
+
+impl TamperDetection {
+    fn matches(&self, record: &TamperRecord) -> bool {
+        self.from == record.from
+            && self.to == record.to
+            && (self.tag.is_none() || self.tag == Some(record.tag))
+    }
+}
+
+/// How well the system under test detected tampered messages.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TamperScore {
+    /// Detections that match a tampered delivery.
+    pub true_positives: usize,
+    /// Detections that match no tampered delivery.
+    pub false_positives: usize,
+    /// Tampered deliveries that were not detected.
+    pub false_negatives: usize,
+}
+
+impl TamperScore {
+    /// Score `detections` against the tampered deliveries in `truth`.
+    ///
+    /// Every detection matches at most one delivery, and every delivery at most one detection,
+    /// in order.
+    pub fn new(
+        truth: &[TamperRecord],
+        detections: impl IntoIterator<Item = TamperDetection>,
+    ) -> Self {
+        let mut unmatched = truth.to_vec();
+        let mut score = TamperScore::default();
+        for detection in detections {
+            match unmatched.iter().position(|r| detection.matches(r)) {
+                Some(i) => {
+                    unmatched.remove(i);
+                    score.true_positives += 1;
+                }
+                None => score.false_positives += 1,
+            }
+        }
+        score.false_negatives = unmatched.len();
+        score
+    }
+
+    /// The fraction of detections that were right, or `None` if nothing was detected.
+    pub fn precision(&self) -> Option<f64> {
+        let detected = self.true_positives + self.false_positives;
+        (detected > 0).then(|| self.true_positives as f64 / detected as f64)
+    }
+
+    /// The fraction of tampered deliveries that were detected, or `None` if none were tampered.
+    pub fn recall(&self) -> Option<f64> {
+        let tampered = self.true_positives + self.false_negatives;
+        (tampered > 0).then(|| self.true_positives as f64 / tampered as f64)
+    }
+}
+
+/// A flow, identified by its protocol and endpoint addresses.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Flow {
+    /// The socket type, `SOCK_STREAM` or `SOCK_DGRAM`.
+    pub proto: libc::c_int,
+    /// The source address.
+    pub src: SocketAddr,
+    /// The destination address.
+    pub dst: SocketAddr,
+}
+
+impl Flow {
+    /// The flow in the opposite direction.
+    pub fn reverse(&self) -> Self {
+        Flow {
+            proto: self.proto,
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+
+    /// The same flow for both directions of a connection.
+    fn normalized(&self) -> Self {
+        if self.src <= self.dst {
+            *self
+        } else {
+            self.reverse()
+        }
+    }
+}
+
+impl std::fmt::Display for Flow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} -> {}", proto_str(self.proto), self.src, self.dst)
+    }
+}
+
+/// Statistics of a flow.
+///
+/// The simulated network does not retransmit: a lost tcp packet resets the connection, so every
+/// packet is counted exactly once, as delivered, dropped or still in flight.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FlowStat {
+    /// Number of packets sent.
+    pub packets_sent: u64,
+    /// Number of bytes sent.
+    pub bytes_sent: u64,
+    /// Number of packets delivered.
+    pub packets_delivered: u64,
+    /// Number of bytes delivered.
+    pub bytes_delivered: u64,
+    /// Number of packets dropped.
+    pub packets_dropped: u64,
+    /// Smallest one-way latency of a delivered packet.
+    pub min_latency: Option<Duration>,
+    /// Largest one-way latency of a delivered packet.
+    pub max_latency: Option<Duration>,
+    /// Sum of the one-way latencies of all delivered packets.
+    pub total_latency: Duration,
+}
+
+impl FlowStat {
+    /// Mean one-way latency of delivered packets.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        if self.packets_delivered == 0 {
+            return None;
+        }
+        Some(self.total_latency / self.packets_delivered as u32)
+    }
+
+    /// Number of packets sent that were neither delivered nor dropped yet.
+    pub fn packets_in_flight(&self) -> u64 {
+        self.packets_sent
+            .saturating_sub(self.packets_delivered + self.packets_dropped)
+    }
+}
+
+/// The traffic sent from the nodes of a location to the nodes of another, see
+/// [`NetSim::egress_by_label`](super::NetSim::egress_by_label).
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EgressStat {
+    /// The location the traffic is sent from.
+    pub from: String,
+    /// The location the traffic is sent to.
+    pub to: String,
+    /// Number of packets sent.
+    pub packets: u64,
+    /// Number of bytes sent.
+    pub bytes: u64,
+}
+
+/// Bookkeeping for a message passed to [`Network::send`].
+#[derive(Clone)]
+struct SendRecord {
+    id: MsgId,
+    flow: Flow,
+    tag: u64,
+    size: u64,
+    msg_log: Option<MsgLog>,
+    flows: FlowLog,
+    /// The flows whose statistics are kept, see [`NetworkConfig::flow_limit`].
+    flow_limit: usize,
+    /// The source and destination nodes, if the destination was found.
+    link: Option<(NodeId, NodeId)>,
+    fault_drops: FaultDropLog,
+}
+
+impl SendRecord {
+    fn set_status(&self, status: MsgStatus) {
+        if let Some(log) = &self.msg_log {
+            let record = MsgRecord {
+                src: self.flow.src,
+                dst: self.flow.dst,
+                tag: self.tag,
+                status,
+            };
+            log.lock().unwrap().insert(self.id, record);
+        }
+    }
+
+    /// Update the statistics of the flow. The flow is only added on the `first` event of a
+    /// message, and only if there is room for it.
+    fn update_flow(&self, first: bool, f: impl FnOnce(&mut FlowStat)) {
+        let mut flows = self.flows.lock().unwrap();
+        if first && flows.len() >= self.flow_limit && !flows.contains_key(&self.flow) {
+            // make room by dropping the flows that are done for now.
+            flows.retain(|_, stat| stat.packets_in_flight() > 0);
+        }
+        if first && flows.len() < self.flow_limit {
+            f(flows.entry(self.flow).or_default());
+        } else if let Some(stat) = flows.get_mut(&self.flow) {
+            f(stat);
+        }
+    }
+
+    fn in_flight(&self) {
+        self.set_status(MsgStatus::InFlight);
+        self.update_flow(true, |stat| {
+            stat.packets_sent += 1;
+            stat.bytes_sent += self.size;
+        });
+    }
+
+    fn delivered(&self, latency: Duration) {
+        self.set_status(MsgStatus::Delivered);
+        self.update_flow(false, |stat| {
+            stat.packets_delivered += 1;
+            stat.bytes_delivered += self.size;
+            stat.min_latency = Some(stat.min_latency.map_or(latency, |l| l.min(latency)));
+            stat.max_latency = Some(stat.max_latency.map_or(latency, |l| l.max(latency)));
+            stat.total_latency += latency;
+        });
+    }
+
+    fn dropped(&self, reason: DropReason) {
+        self.set_status(MsgStatus::Dropped(reason));
+        self.update_flow(reason != DropReason::SocketClosed, |stat| {
+            // a message dropped in flight has already been counted as sent.
+            if reason != DropReason::SocketClosed {
+                stat.packets_sent += 1;
+                stat.bytes_sent += self.size;
+            }
+            stat.packets_dropped += 1;
+        });
+        if let Some(link) = self.link.filter(|_| reason.is_fault()) {
+            *self.fault_drops.lock().unwrap().entry(link).or_default() += 1;
+        }
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 struct SocketKey(u16, libc::c_int);
 
 fn proto_str(proto: libc::c_int) -> &'static str {
@@ -89,6 +899,41 @@ impl Network {
             addr_to_node: HashMap::new(),
             clogged_node: HashSet::new(),
             clogged_link: HashSet::new(),
+            fault_clogs: HashMap::new(),
+            link_latency: HashMap::new(),
+            link_packet_loss: HashMap::new(),
+            link_loss_model: HashMap::new(),
+            link_loss_bad: HashMap::new(),
+            degraded_node: HashMap::new(),
+            degradation_overlays: HashMap::new(),
+            next_overlay: 0,
+            forbidden: Vec::new(),
+            next_forbid_id: 0,
+            recv_buffer_drops: Default::default(),
+            policers: HashMap::new(),
+            bans: HashMap::new(),
+            ban_refused: HashMap::new(),
+            anycast: HashMap::new(),
+            tag_faults: Vec::new(),
+            next_msg_id: 0,
+            last_msg_id: None,
+            msg_log: None,
+            flows: Default::default(),
+            fault_drops: Default::default(),
+            partitions: HashMap::new(),
+            partition_totals: (0, Duration::ZERO),
+            broken_conns: Default::default(),
+            conn_break_after: HashMap::new(),
+            clusters: Vec::new(),
+            node_cluster: HashMap::new(),
+            wan_links: HashMap::new(),
+            tamperer: None,
+            tamper_log: None,
+            capture_filter: None,
+            node_capture_filters: HashMap::new(),
+            held: None,
+            egress: HashMap::new(),
+            traffic: HashMap::new(),
         }
     }
 
@@ -104,10 +949,241 @@ impl Network {
         f(&mut self.config);
     }
 
+    pub fn config(&self) -> &NetworkConfig {
+        &self.config
+    }
+
     pub fn stat(&self) -> &Stat {
         &self.stat
     }
 
+    pub fn track_messages(&mut self, enabled: bool) {
+        match (enabled, &self.msg_log) {
+            (true, None) => self.msg_log = Some(Default::default()),
+            (false, Some(_)) => self.msg_log = None,
+            _ => {}
+        }
+    }
+
+    pub fn last_msg_id(&self) -> Option<MsgId> {
+        self.last_msg_id
+    }
+
+    pub fn set_tamperer(&mut self, tamperer: Option<Tamperer>) {
+        self.tamperer = tamperer.map(|tamperer| Arc::new(Mutex::new(tamperer)));
+    }
+
+    pub fn track_tampering(&mut self, enabled: bool) {
+        match (enabled, &self.tamper_log) {
+            (true, None) => self.tamper_log = Some(Default::default()),
+            (false, Some(_)) => self.tamper_log = None,
+            _ => {}
+        }
+    }
+
+    pub fn tampered(&self) -> Vec<TamperRecord> {
+        match &self.tamper_log {
+            Some(log) => log.lock().unwrap().clone(),
+            None => vec![],
+        }
+    }
+
+    pub fn set_capture_filter(&mut self, filter: Option<CaptureFilter>) {
+        self.capture_filter = filter;
+    }
+
+    pub fn set_node_capture_filter(&mut self, id: NodeId, filter: Option<CaptureFilter>) {
+        match filter {
+            Some(filter) => self.node_capture_filters.insert(id, filter),
+            None => self.node_capture_filters.remove(&id),
+        };
+    }
+
+    /// Whether a message is recorded in the trace and the message log.
+    ///
+    /// Without filters, every message is. Otherwise, a message is if it matches the global filter
+    /// or the filter of its source or destination node.
+    fn captures(
+        &self,
+        src_node: NodeId,
+        dst_node: Option<NodeId>,
+        flow: &Flow,
+        tag: u64,
+        len: u64,
+    ) -> bool {
+        if self.capture_filter.is_none() && self.node_capture_filters.is_empty() {
+            return true;
+        }
+        let node_filter = |node| self.node_capture_filters.get(&node);
+        self.capture_filter
+            .iter()
+            .chain(node_filter(src_node))
+            .chain(dst_node.and_then(node_filter))
+            .any(|filter| filter.matches(flow, tag, len))
+    }
+
+    pub fn set_manual_delivery(&mut self, enabled: bool) {
+        match (enabled, self.held.take()) {
+            (true, held) => self.held = Some(held.unwrap_or_default()),
+            (false, Some(held)) => {
+                // release the held messages, in the order they were sent.
+                let now = self.time.now_instant();
+                for (handle, deliver) in held {
+                    self.time.add_timer_for_node(handle.to, now, deliver);
+                }
+            }
+            (false, None) => {}
+        }
+    }
+
+    pub fn deliverable(&self) -> Vec<MsgHandle> {
+        let held = self.held.iter().flatten();
+        held.map(|(handle, _)| handle.clone()).collect()
+    }
+
+    /// Remove a held message, to deliver it or drop it.
+    pub fn take_held(&mut self, handle: &MsgHandle) -> Option<Delivery> {
+        let held = self.held.as_mut()?;
+        let index = held.iter().position(|(h, _)| h == handle)?;
+        Some(held.remove(index).1)
+    }
+
+    /// Deliver a message after `latency`, or hold it in manual delivery mode.
+    ///
+    /// If bandwidth is limited, a message of `size` bytes to another node is first queued until
+    /// the sending node has sent it.
+    fn schedule(
+        &mut self,
+        handle: MsgHandle,
+        size: Option<u64>,
+        latency: Duration,
+        deliver: impl FnOnce() + Send + Sync + 'static,
+    ) {
+        if let Some(held) = &mut self.held {
+            trace!("hold: {handle}");
+            held.push((handle, Box::new(deliver)));
+            return;
+        }
+        let time = self.time.clone();
+        let bandwidth = self.config.bandwidth.as_ref();
+        match (bandwidth, size) {
+            (Some(bandwidth), Some(size)) if handle.from != handle.to => {
+                let lane = bandwidth.lane_of(handle.tag);
+                let transmit = bandwidth.transmit_time(size);
+                let egress = self.egress.entry(handle.from).or_insert_with(|| {
+                    Arc::new(Mutex::new(Egress {
+                        time: time.clone(),
+                        node: handle.from,
+                        busy: false,
+                        lanes: Vec::new(),
+                    }))
+                });
+                let sent = move || {
+                    let deadline = time.now_instant() + latency;
+                    time.add_timer_for_node(handle.to, deadline, deliver);
+                };
+                Egress::enqueue(egress, lane, transmit, Box::new(sent));
+            }
+            _ => {
+                let deadline = time.now_instant() + latency;
+                time.add_timer_for_node(handle.to, deadline, deliver);
+            }
+        }
+    }
+
+    pub fn message(&self, id: MsgId) -> Option<MsgRecord> {
+        let log = self.msg_log.as_ref()?.lock().unwrap();
+        log.get(&id).cloned()
+    }
+
+    pub fn messages(&self) -> Vec<(MsgId, MsgRecord)> {
+        let Some(log) = &self.msg_log else {
+            return vec![];
+        };
+        let mut messages: Vec<_> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, record)| (*id, record.clone()))
+            .collect();
+        messages.sort_by_key(|(id, _)| *id);
+        messages
+    }
+
+    pub fn flows(&self) -> Vec<(Flow, FlowStat)> {
+        let flows = self.flows.lock().unwrap();
+        let mut flows: Vec<_> = flows.iter().map(|(f, s)| (*f, s.clone())).collect();
+        flows.sort_by_key(|(f, _)| *f);
+        flows
+    }
+
+    pub fn flow(&self, flow: &Flow) -> Option<FlowStat> {
+        self.flows.lock().unwrap().get(flow).cloned()
+    }
+
+    /// The links between nodes that have an override or carried traffic, ordered by (src, dst).
+    ///
+    /// The statistics of a link add up the flows between the addresses of its nodes. Flows over
+    /// loopback, or of nodes that were deleted, are not included.
+    pub fn links(&self) -> Vec<TopologyLink> {
+        let mut links: BTreeMap<(NodeId, NodeId), TopologyLink> = BTreeMap::new();
+        fn link(
+            links: &mut BTreeMap<(NodeId, NodeId), TopologyLink>,
+            src: NodeId,
+            dst: NodeId,
+        ) -> &mut TopologyLink {
+            links.entry((src, dst)).or_insert_with(|| TopologyLink {
+                src,
+                dst,
+                ..Default::default()
+            })
+        }
+        let fault_clogged = self.fault_clogs.keys().filter_map(|target| match *target {
+            PartitionTarget::Link(src, dst) => Some((src, dst)),
+            PartitionTarget::Node(_) => None,
+        });
+        for (src, dst) in self.clogged_link.iter().copied().chain(fault_clogged) {
+            link(&mut links, src, dst).clogged = true;
+        }
+        for (&(src, dst), latency) in &self.link_latency {
+            link(&mut links, src, dst).latency = Some(latency.clone());
+        }
+        for (&(src, dst), &rate) in &self.link_packet_loss {
+            link(&mut links, src, dst).packet_loss = Some(rate);
+        }
+        for (flow, stat) in self.flows.lock().unwrap().iter() {
+            let (Some(&src), Some(&dst)) = (
+                self.addr_to_node.get(&flow.src.ip()),
+                self.addr_to_node.get(&flow.dst.ip()),
+            ) else {
+                continue;
+            };
+            let total = &mut link(&mut links, src, dst).stat;
+            total.packets_sent += stat.packets_sent;
+            total.bytes_sent += stat.bytes_sent;
+            total.packets_delivered += stat.packets_delivered;
+            total.bytes_delivered += stat.bytes_delivered;
+            total.packets_dropped += stat.packets_dropped;
+            total.min_latency = [total.min_latency, stat.min_latency]
+                .into_iter()
+                .flatten()
+                .min();
+            total.max_latency = total.max_latency.max(stat.max_latency);
+            total.total_latency += stat.total_latency;
+        }
+        links.into_values().collect()
+    }
+
+    /// The packets and bytes sent from a node to another, keyed by (src, dst). Packets dropped
+    /// before they are sent are not included.
+    pub fn traffic(&self) -> &HashMap<(NodeId, NodeId), (u64, u64)> {
+        &self.traffic
+    }
+
+    pub fn contains_node(&self, id: NodeId) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
     pub fn insert_node(&mut self, id: NodeId) {
         debug!("insert: {id}");
         self.nodes.insert(id, Default::default());
@@ -118,16 +1194,33 @@ impl Network {
         let node = self.nodes.get_mut(&id).expect("node not found");
         // close all sockets
         node.sockets.clear();
+        node.shared.clear();
+        // messages waiting to be sent are lost, with the timers of the node.
+        self.egress.remove(&id);
     }
 
+    /// Remove a node, with all the state the network keeps about it.
     pub fn delete_node(&mut self, id: NodeId) {
         debug!("delete: {id}");
         let node = self.nodes.remove(&id).expect("node not found");
-
-        if let Some(ip) = &node.ip {
-            self.addr_to_node.remove(ip);
+        self.egress.remove(&id);
+
+        if let Some(ip) = node.ip {
+            self.addr_to_node.remove(&ip);
+            let of_node = |flow: &Flow| flow.src.ip() == ip || flow.dst.ip() == ip;
+            (self.flows.lock().unwrap()).retain(|flow, _| !of_node(flow));
+            (self.broken_conns.lock().unwrap()).retain(|flow| !of_node(flow));
+            self.conn_break_after.retain(|flow, _| !of_node(flow));
         }
         self.clogged_node.remove(&id);
+        self.degraded_node.remove(&id);
+        self.degradation_overlays.remove(&id);
+        self.policers.remove(&id);
+        self.node_capture_filters.remove(&id);
+        if let Some(held) = &mut self.held {
+            held.retain(|(handle, _)| handle.from != id && handle.to != id);
+        }
+        self.traffic.retain(|(a, b), _| *a != id && *b != id);
 
         let to_remove: Vec<_> = self
             .clogged_link
@@ -144,6 +1237,81 @@ impl Network {
         for k in &to_remove {
             self.clogged_link.remove(k);
         }
+        self.fault_clogs.retain(|target, _| !target.involves(id));
+        self.end_partitions(|target| target.involves(id));
+        self.link_latency.retain(|(a, b), _| *a != id && *b != id);
+        self.link_packet_loss
+            .retain(|(a, b), _| *a != id && *b != id);
+        self.link_loss_model
+            .retain(|(a, b), _| *a != id && *b != id);
+        self.link_loss_bad.retain(|(a, b), _| *a != id && *b != id);
+        self.node_cluster.remove(&id);
+        self.bans.retain(|(node, _), _| *node != id);
+        self.ban_refused.retain(|(node, _), _| *node != id);
+        for members in self.anycast.values_mut() {
+            members.retain(|member| *member != id);
+        }
+    }
+
+    fn cluster_id(&self, name: &str) -> Option<usize> {
+        self.clusters.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn add_cluster(&mut self, name: &str, config: NetworkConfig) {
+        debug!("add cluster {name}");
+        match self.cluster_id(name) {
+            Some(id) => self.clusters[id].1 = Arc::new(config),
+            None => self.clusters.push((name.to_string(), Arc::new(config))),
+        }
+    }
+
+    pub fn set_cluster(&mut self, id: NodeId, cluster: Option<&str>) {
+        assert!(self.nodes.contains_key(&id));
+        match cluster {
+            Some(name) => {
+                let cluster = self
+                    .cluster_id(name)
+                    .unwrap_or_else(|| panic!("unknown cluster: {name}"));
+                self.node_cluster.insert(id, cluster);
+            }
+            None => {
+                self.node_cluster.remove(&id);
+            }
+        }
+    }
+
+    pub fn cluster(&self, id: NodeId) -> Option<String> {
+        let cluster = *self.node_cluster.get(&id)?;
+        Some(self.clusters[cluster].0.clone())
+    }
+
+    pub fn set_wan_link(&mut self, a: &str, b: &str, link: Option<WanLink>) {
+        let [a, b] = [a, b].map(|name| {
+            self.cluster_id(name)
+                .unwrap_or_else(|| panic!("unknown cluster: {name}"))
+        });
+        debug!("wan link {a} <-> {b}: {link:?}");
+        match link {
+            Some(link) => {
+                self.wan_links.insert((a, b), link.clone());
+                self.wan_links.insert((b, a), link);
+            }
+            None => {
+                self.wan_links.remove(&(a, b));
+                self.wan_links.remove(&(b, a));
+            }
+        }
+    }
+
+    fn route(&self, src: NodeId, dst: NodeId) -> Route {
+        match (self.node_cluster.get(&src), self.node_cluster.get(&dst)) {
+            (Some(a), Some(b)) if a == b => Route::Lan(Some(self.clusters[*a].1.clone())),
+            (Some(a), Some(b)) => match self.wan_links.get(&(*a, *b)) {
+                Some(link) => Route::Wan(link.clone()),
+                None => Route::Unreachable,
+            },
+            _ => Route::Lan(None),
+        }
     }
 
     pub fn set_ip(&mut self, id: NodeId, ip: IpAddr) {
@@ -159,59 +1327,507 @@ impl Network {
         // TODO: what if we change the IP when there are opening sockets?
     }
 
+    pub fn set_anycast(&mut self, ip: IpAddr, members: Vec<NodeId>) {
+        assert!(
+            !self.addr_to_node.contains_key(&ip),
+            "IP conflict: {ip} is the address of a node"
+        );
+        debug!("anycast: {ip}: {members:?}");
+        if members.is_empty() {
+            self.anycast.remove(&ip);
+        } else {
+            self.anycast.insert(ip, members);
+        }
+    }
+
+    /// The member of the anycast address of `dst` that traffic from `src` is routed to: the
+    /// member with the lowest mean latency among those that are reachable and have a socket
+    /// bound to the port of `dst`, the first one on ties.
+    pub fn anycast_member(
+        &self,
+        src: NodeId,
+        proto: libc::c_int,
+        dst: SocketAddr,
+    ) -> Option<NodeId> {
+        let members = self.anycast.get(&dst.ip())?;
+        let reachable =
+            |a, b| !self.is_link_clogged(a, b) && !matches!(self.route(a, b), Route::Unreachable);
+        let healthy = |member: &NodeId| {
+            let bound = (self.nodes.get(member))
+                .is_some_and(|node| node.sockets.contains_key(&SocketKey(dst.port(), proto)));
+            bound && !self.is_clogged(*member) && reachable(src, *member) && reachable(*member, src)
+        };
+        let member = (members.iter().copied())
+            .filter(healthy)
+            .min_by_key(|member| self.mean_latency(src, *member));
+        trace!("anycast: {src} -> {dst} is routed to {member:?}");
+        member
+    }
+
+    /// The address of the member of the anycast address of `dst` that `src` connects to, or
+    /// `dst` if it is not an anycast address or has no healthy member.
+    pub fn resolve_anycast(&self, src: NodeId, proto: libc::c_int, dst: SocketAddr) -> SocketAddr {
+        let member = self.anycast_member(src, proto, dst);
+        match member.and_then(|member| self.nodes[&member].ip) {
+            Some(ip) => SocketAddr::new(ip, dst.port()),
+            None => dst,
+        }
+    }
+
+    /// The mean one-way latency from `src` to `dst`, including the degradation of either.
+    fn mean_latency(&self, src: NodeId, dst: NodeId) -> Duration {
+        let latency = match self.link_latency.get(&(src, dst)) {
+            Some(dist) => dist.mean(),
+            None => match self.route(src, dst) {
+                Route::Wan(link) => link.latency.mean(),
+                Route::Lan(Some(config)) => config.latency.mean_latency(src, dst),
+                Route::Lan(None) | Route::Unreachable => self.config.latency.mean_latency(src, dst),
+            },
+        };
+        let degraded = [src, dst].into_iter().flat_map(|id| self.degradations(id));
+        latency + degraded.map(|d| d.extra_latency.mean()).sum::<Duration>()
+    }
+
+    /// Whether a node has the address `ip`.
+    pub fn ip_in_use(&self, ip: &IpAddr) -> bool {
+        self.addr_to_node.contains_key(ip)
+    }
+
     pub fn get_ip(&self, id: NodeId) -> Option<IpAddr> {
         let node = self.nodes.get(&id).expect("node not found");
         node.ip
     }
 
+    /// Whether a node is clogged, by the user or by a fault.
+    pub fn is_clogged(&self, id: NodeId) -> bool {
+        self.clogged_node.contains(&id)
+            || (self.fault_clogs).contains_key(&PartitionTarget::Node(id))
+    }
+
+    /// Whether the link from `src` to `dst` is clogged, by the user or by a fault.
+    fn is_link_clogged(&self, src: NodeId, dst: NodeId) -> bool {
+        self.clogged_link.contains(&(src, dst))
+            || (self.fault_clogs).contains_key(&PartitionTarget::Link(src, dst))
+    }
+
+    fn is_target_clogged(&self, target: PartitionTarget) -> bool {
+        match target {
+            PartitionTarget::Node(id) => self.is_clogged(id),
+            PartitionTarget::Link(src, dst) => self.is_link_clogged(src, dst),
+        }
+    }
+
     pub fn clog_node(&mut self, id: NodeId) {
         assert!(self.nodes.contains_key(&id));
         debug!("clog: {id}");
-        self.clogged_node.insert(id);
+        let clogged = self.is_clogged(id);
+        if self.clogged_node.insert(id) && !clogged {
+            self.start_partition(PartitionTarget::Node(id));
+        }
     }
 
     pub fn unclog_node(&mut self, id: NodeId) {
         assert!(self.nodes.contains_key(&id));
         debug!("unclog: {id}");
-        self.clogged_node.remove(&id);
+        if self.clogged_node.remove(&id) && !self.is_clogged(id) {
+            self.end_partitions(|target| target == PartitionTarget::Node(id));
+        }
     }
 
     pub fn clog_link(&mut self, src: NodeId, dst: NodeId) {
         assert!(self.nodes.contains_key(&src));
         assert!(self.nodes.contains_key(&dst));
         debug!("clog: {src} -> {dst}");
-        self.clogged_link.insert((src, dst));
+        let clogged = self.is_link_clogged(src, dst);
+        if self.clogged_link.insert((src, dst)) && !clogged {
+            self.start_partition(PartitionTarget::Link(src, dst));
+        }
     }
 
     pub fn unclog_link(&mut self, src: NodeId, dst: NodeId) {
         assert!(self.nodes.contains_key(&src));
         assert!(self.nodes.contains_key(&dst));
         debug!("unclog: {src} -> {dst}");
-        self.clogged_link.remove(&(src, dst));
+        if self.clogged_link.remove(&(src, dst)) && !self.is_link_clogged(src, dst) {
+            self.end_partitions(|target| target == PartitionTarget::Link(src, dst));
+        }
+    }
+
+    /// Clog a node or link on behalf of a fault, until a matching [`Self::fault_unclog`].
+    /// Clogs of faults and of the user overlap: the target is clogged while any of them is.
+    pub fn fault_clog(&mut self, target: PartitionTarget) {
+        debug!("fault clog: {target:?}");
+        if !self.is_target_clogged(target) {
+            self.start_partition(target);
+        }
+        *self.fault_clogs.entry(target).or_default() += 1;
+    }
+
+    /// Lift a clog of [`Self::fault_clog`].
+    pub fn fault_unclog(&mut self, target: PartitionTarget) {
+        debug!("fault unclog: {target:?}");
+        let Entry::Occupied(mut count) = self.fault_clogs.entry(target) else {
+            return;
+        };
+        *count.get_mut() -= 1;
+        if *count.get() == 0 {
+            count.remove();
+        }
+        if !self.is_target_clogged(target) {
+            self.end_partitions(|t| t == target);
+        }
+    }
+
+    fn start_partition(&mut self, target: PartitionTarget) {
+        self.partitions.insert(target, self.time.now_instant());
+        self.partition_totals.0 += 1;
+    }
+
+    /// End the partitions in progress whose target matches `f`.
+    fn end_partitions(&mut self, f: impl Fn(PartitionTarget) -> bool) {
+        let now = self.time.now_instant();
+        let totals = &mut self.partition_totals;
+        self.partitions.retain(|target, start| {
+            if !f(*target) {
+                return true;
+            }
+            totals.1 += now.saturating_duration_since(*start);
+            false
+        });
+    }
+
+    /// The clogged nodes and links, in the order they were clogged.
+    pub fn partitions(&self) -> Vec<Partition> {
+        let partitions = self.partitions.iter().map(|(target, start)| Partition {
+            target: *target,
+            start: *start,
+        });
+        let mut partitions: Vec<_> = partitions.collect();
+        partitions.sort_by_key(|p| (p.start, p.target));
+        partitions
+    }
+
+    /// The number of partitions started, and their total duration until now, including the
+    /// partitions in progress.
+    pub fn partition_totals(&self) -> (u64, Duration) {
+        let now = self.time.now_instant();
+        let (count, ended) = self.partition_totals;
+        let open = self
+            .partitions
+            .values()
+            .map(|start| now.saturating_duration_since(*start));
+        (count, ended + open.sum::<Duration>())
+    }
+
+    /// The messages dropped by injected faults, keyed by (src, dst) node.
+    pub fn fault_drops(&self) -> HashMap<(NodeId, NodeId), u64> {
+        self.fault_drops.lock().unwrap().clone()
+    }
+
+    pub fn set_link_latency(
+        &mut self,
+        src: NodeId,
+        dst: NodeId,
+        latency: Option<LatencyDistribution>,
+    ) {
+        assert!(self.nodes.contains_key(&src));
+        assert!(self.nodes.contains_key(&dst));
+        debug!("link latency: {src} -> {dst}: {latency:?}");
+        match latency {
+            Some(latency) => self.link_latency.insert((src, dst), latency),
+            None => self.link_latency.remove(&(src, dst)),
+        };
+    }
+
+    pub fn set_link_packet_loss(&mut self, src: NodeId, dst: NodeId, rate: Option<f64>) {
+        assert!(self.nodes.contains_key(&src));
+        assert!(self.nodes.contains_key(&dst));
+        debug!("link packet loss: {src} -> {dst}: {rate:?}");
+        match rate {
+            Some(rate) => self.link_packet_loss.insert((src, dst), rate),
+            None => self.link_packet_loss.remove(&(src, dst)),
+        };
+    }
+
+    pub fn set_link_loss_model(&mut self, src: NodeId, dst: NodeId, model: Option<GilbertElliott>) {
+        assert!(self.nodes.contains_key(&src));
+        assert!(self.nodes.contains_key(&dst));
+        debug!("link loss model: {src} -> {dst}: {model:?}");
+        match model {
+            Some(model) => self.link_loss_model.insert((src, dst), model),
+            None => self.link_loss_model.remove(&(src, dst)),
+        };
+    }
+
+    /// Returns whether a udp packet sent from `src` to `dst` is lost.
+    fn udp_packet_lost(&mut self, src: NodeId, dst: NodeId) -> bool {
+        let model = match self.link_loss_model.get(&(src, dst)) {
+            Some(model) => Some(*model),
+            // the rate override of a link also replaces the loss model of the config.
+            None if self.link_packet_loss.contains_key(&(src, dst)) || src == dst => None,
+            None => match self.route(src, dst) {
+                Route::Lan(Some(config)) => config.loss_model,
+                Route::Lan(None) => self.config.loss_model,
+                Route::Wan(_) | Route::Unreachable => None,
+            },
+        };
+        if let Some(model) = model {
+            let bad = self.link_loss_bad.entry((src, dst)).or_default();
+            return model.sample(&mut self.rand, bad);
+        }
+        let plr = self.packet_loss_rate(false, src, dst);
+        self.rand.gen_bool(plr)
+    }
+
+    pub fn degrade_node(&mut self, id: NodeId, degradation: Option<Degradation>) {
+        assert!(self.nodes.contains_key(&id));
+        debug!("degrade: {id}: {degradation:?}");
+        match degradation {
+            Some(degradation) => self.degraded_node.insert(id, degradation),
+            None => self.degraded_node.remove(&id),
+        };
+    }
+
+    /// Add a degradation of a node on top of the one of [`degrade_node`](Self::degrade_node),
+    /// until it is removed with the returned id.
+    pub fn add_degradation(&mut self, id: NodeId, degradation: Degradation) -> u64 {
+        assert!(self.nodes.contains_key(&id));
+        debug!("add degradation: {id}: {degradation:?}");
+        self.next_overlay += 1;
+        let overlays = self.degradation_overlays.entry(id).or_default();
+        overlays.insert(self.next_overlay, degradation);
+        self.next_overlay
+    }
+
+    /// Remove a degradation added by [`add_degradation`](Self::add_degradation).
+    pub fn remove_degradation(&mut self, id: NodeId, overlay: u64) {
+        let Some(overlays) = self.degradation_overlays.get_mut(&id) else {
+            return;
+        };
+        overlays.remove(&overlay);
+        if overlays.is_empty() {
+            self.degradation_overlays.remove(&id);
+        }
+    }
+
+    /// The degradations of a node, see [`degrade_node`](Self::degrade_node) and
+    /// [`add_degradation`](Self::add_degradation).
+    fn degradations(&self, id: NodeId) -> impl Iterator<Item = &Degradation> {
+        let overlays = self.degradation_overlays.get(&id).into_iter();
+        let overlays = overlays.flat_map(|overlays| overlays.values());
+        self.degraded_node.get(&id).into_iter().chain(overlays)
+    }
+
+    pub fn police_egress(&mut self, id: NodeId, policy: Option<EgressPolicy>) {
+        assert!(self.nodes.contains_key(&id));
+        debug!("police egress: {id}: {policy:?}");
+        match policy {
+            Some(policy) => {
+                let policer = Policer {
+                    policy,
+                    tokens: policy.burst as f64,
+                    refilled: self.time.elapsed(),
+                    dropped: 0,
+                };
+                self.policers.insert(id, policer);
+            }
+            None => {
+                self.policers.remove(&id);
+            }
+        }
+    }
+
+    pub fn ban(&mut self, id: NodeId, peer: IpAddr, duration: Option<Duration>) {
+        assert!(self.nodes.contains_key(&id));
+        debug!("ban: {id} bans {peer} for {duration:?}");
+        let expires = duration.map(|duration| self.time.elapsed() + duration);
+        self.bans.insert((id, peer), expires);
+    }
+
+    pub fn unban(&mut self, id: NodeId, peer: IpAddr) -> bool {
+        debug!("unban: {id} unbans {peer}");
+        self.bans.remove(&(id, peer)).is_some()
+    }
+
+    /// The peers a node bans, with when their ban expires.
+    pub fn bans(&self, id: NodeId) -> Vec<(IpAddr, Option<Duration>)> {
+        let now = self.time.elapsed();
+        let mut bans: Vec<_> = (self.bans.iter())
+            .filter(|((node, _), expires)| *node == id && expires.map_or(true, |t| t > now))
+            .map(|((_, peer), expires)| (*peer, expires.map(|t| t - now)))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// The messages and connection requests from `peer` refused by the bans of a node.
+    pub fn ban_refused(&self, id: NodeId, peer: IpAddr) -> u64 {
+        self.ban_refused.get(&(id, peer)).copied().unwrap_or(0)
+    }
+
+    /// Whether `node` refuses the traffic from `src`, counting the refusal.
+    fn refuses(&mut self, node: NodeId, src: NodeId) -> bool {
+        let Some(ip) = self
+            .nodes
+            .get(&src)
+            .and_then(|n| n.ip)
+            .filter(|_| node != src)
+        else {
+            return false;
+        };
+        let key = (node, ip);
+        let now = self.time.elapsed();
+        match self.bans.get(&key) {
+            Some(expires) if expires.map_or(true, |t| t > now) => {}
+            Some(_) => {
+                self.bans.remove(&key);
+                return false;
+            }
+            None => return false,
+        }
+        *self.ban_refused.entry(key).or_default() += 1;
+        true
+    }
+
+    /// The udp messages dropped by full receive buffers, indexed by drop precedence class.
+    pub fn recv_buffer_drops(&self) -> Vec<u64> {
+        self.recv_buffer_drops.lock().unwrap().clone()
+    }
+
+    /// The udp packets dropped by the egress policer of a node.
+    pub fn policed(&self, id: NodeId) -> u64 {
+        self.policers.get(&id).map_or(0, |p| p.dropped)
+    }
+
+    pub fn forbid(&mut self, filter: CaptureFilter, duration: Duration) -> ForbidId {
+        let id = ForbidId(self.next_forbid_id);
+        self.next_forbid_id += 1;
+        let since = self.time.elapsed();
+        debug!("forbid: {id:?}: `{filter}` for {duration:?}");
+        self.forbidden.push(Forbidden {
+            id,
+            filter,
+            since,
+            until: since.checked_add(duration),
+        });
+        id
+    }
+
+    /// Close a forbidden window early. Returns false if it was already closed.
+    pub fn allow(&mut self, id: ForbidId) -> bool {
+        let len = self.forbidden.len();
+        self.forbidden.retain(|f| f.id != id);
+        self.forbidden.len() != len
+    }
+
+    /// Describe the violation if a message sent by `node` now is forbidden.
+    pub fn check_forbidden(
+        &mut self,
+        node: NodeId,
+        flow: &Flow,
+        tag: u64,
+        len: u64,
+    ) -> Option<String> {
+        if self.forbidden.is_empty() {
+            return None;
+        }
+        let now = self.time.elapsed();
+        self.forbidden
+            .retain(|f| f.until.map_or(true, |until| now < until));
+        let forbidden = (self.forbidden.iter()).find(|f| f.filter.matches(flow, tag, len))?;
+        let name = crate::context::try_current_task().map_or_else(String::new, |t| t.name());
+        Some(format!(
+            "forbidden message sent by {node} ({name}): {flow}, tag={}, len={len}, matching `{}`, \
+             {:?} after the window opened",
+            Tag(tag),
+            forbidden.filter,
+            now - forbidden.since,
+        ))
+    }
+
+    pub fn set_tag_fault(&mut self, tags: Range<u64>, fault: Option<TagFault>) {
+        debug!("tag fault: {tags:?}: {fault:?}");
+        self.tag_faults.retain(|(range, _)| *range != tags);
+        if let Some(fault) = fault {
+            self.tag_faults.push((tags, fault));
+        }
+    }
+
+    /// Sample the effects of the tag faults on a packet with `tag`.
+    ///
+    /// Returns `None` if the packet should be dropped, or the extra latency to add and the delay
+    /// of a duplicate otherwise.
+    fn sample_tag_faults(&mut self, udp: bool, tag: u64) -> Option<(Duration, Option<Duration>)> {
+        let mut extra = Duration::ZERO;
+        let mut duplicate = None;
+        for (tags, fault) in &self.tag_faults {
+            if !tags.contains(&tag) {
+                continue;
+            }
+            if udp && self.rand.gen_bool(fault.packet_loss_rate) {
+                return None;
+            }
+            extra += fault.extra_latency.sample(&mut self.rand);
+            if duplicate.is_none() && self.rand.gen_bool(fault.duplicate_rate) {
+                duplicate = Some(fault.duplicate_delay.sample(&mut self.rand));
+            }
+        }
+        Some((extra, duplicate))
+    }
+
+    /// Sample the effects of node degradation on a packet from `src` to `dst`.
+    ///
+    /// Returns `None` if the packet should be dropped, or the extra latency to add otherwise.
+    fn sample_degradation(&mut self, udp: bool, src: NodeId, dst: NodeId) -> Option<Duration> {
+        let mut extra = Duration::ZERO;
+        if src == dst {
+            return Some(extra);
+        }
+        for id in [src, dst] {
+            let overlays = self.degradation_overlays.get(&id).into_iter();
+            let overlays = overlays.flat_map(|overlays| overlays.values());
+            for d in self.degraded_node.get(&id).into_iter().chain(overlays) {
+                if udp && self.rand.gen_bool(d.packet_loss_rate) {
+                    return None;
+                }
+                extra += d.extra_latency.sample(&mut self.rand);
+                if self.rand.gen_bool(d.stall_rate) {
+                    trace!("stall");
+                    extra += d.stall_latency.sample(&mut self.rand);
+                }
+            }
+        }
+        Some(extra)
+    }
+
+    fn packet_loss_rate(&mut self, tcp: bool, src: NodeId, dst: NodeId) -> f64 {
+        if let Some(rate) = self.link_packet_loss.get(&(src, dst)) {
+            return *rate;
+        }
+        let cluster = match self.route(src, dst) {
+            // tcp packets are not lost on wan links, as that would reset the connection.
+            Route::Wan(link) => return if tcp { 0.0 } else { link.packet_loss_rate },
+            Route::Lan(cluster) => cluster,
+            Route::Unreachable => None,
+        };
+        let config = cluster.as_deref().unwrap_or(&self.config);
+        let config = if tcp {
+            &config.tcp_packet_loss
+        } else {
+            &config.packet_loss
+        };
+        config.packet_loss_rate(&mut self.rand, src, dst)
     }
 
     pub fn bind(
         &mut self,
         node_id: NodeId,
         proto: libc::c_int,
-        mut addr: SocketAddr,
+        addr: SocketAddr,
     ) -> io::Result<SocketAddr> {
         debug!("binding ({}): {addr} -> {node_id}", proto_str(proto));
         let node = self.nodes.get_mut(&node_id).expect("node not found");
-        // resolve IP if unspecified
-        if addr.ip().is_unspecified() {
-            if let Some(ip) = node.ip {
-                addr.set_ip(ip);
-            } else {
-                todo!("try to bind 0.0.0.0, but the node IP is also unspecified");
-            }
-        } else if addr.ip().is_loopback() {
-        } else if addr.ip() != node.ip.expect("node IP is unset") {
-            return Err(io::Error::new(
-                io::ErrorKind::AddrNotAvailable,
-                format!("invalid address: {addr}"),
-            ));
-        }
+        let mut addr = node.resolve_ip(addr)?;
         // resolve port if unspecified
         if addr.port() == 0 {
             let next_ephemeral_port = node.next_ephemeral_port;
@@ -242,6 +1858,51 @@ impl Network {
         Ok(addr)
     }
 
+    /// Bind an endpoint owning the tags in `tags` to an address that other endpoints may share,
+    /// as long as their tag namespaces are disjoint.
+    pub fn bind_shared(
+        &mut self,
+        node_id: NodeId,
+        proto: libc::c_int,
+        addr: SocketAddr,
+        tags: Range<u64>,
+    ) -> io::Result<SocketAddr> {
+        if tags.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("empty tag namespace: {tags:?}"),
+            ));
+        }
+        let node = self.nodes.get_mut(&node_id).expect("node not found");
+        let key = SocketKey(addr.port(), proto);
+        if addr.port() != 0 && node.sockets.contains_key(&key) {
+            let addr = node.resolve_ip(addr)?;
+            let Some(namespaces) = node.shared.get_mut(&key) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("address already in use: {addr}"),
+                ));
+            };
+            if let Some(other) = namespaces
+                .iter()
+                .find(|other| other.start < tags.end && tags.start < other.end)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("tags {tags:?} overlap with {other:?} at {addr}"),
+                ));
+            }
+            debug!("sharing {addr} with tags {tags:?} -> {node_id}");
+            namespaces.push(tags);
+            return Ok(addr);
+        }
+        let addr = self.bind(node_id, proto, addr)?;
+        let node = self.nodes.get_mut(&node_id).unwrap();
+        node.shared
+            .insert(SocketKey(addr.port(), proto), vec![tags]);
+        Ok(addr)
+    }
+
     pub fn register_tcp_id(&mut self, node_id: NodeId, tcp_id: u32) {
         trace!("registering tcp id {} for node {}", tcp_id, node_id);
         assert!(
@@ -259,11 +1920,22 @@ impl Network {
         &mut self,
         node: NodeId,
         proto: libc::c_int,
+        local_addr: &SocketAddr,
         remote_addr: &SocketAddr,
         tcp_id: u32,
     ) {
         trace!("deregistering tcp id {} for node {}", tcp_id, node);
 
+        // the connection is gone, a later one between the same addresses is not broken.
+        let flow = Flow {
+            proto,
+            src: *local_addr,
+            dst: *remote_addr,
+        };
+        self.broken_conns.lock().unwrap().remove(&flow.normalized());
+        self.conn_break_after.remove(&flow);
+        self.conn_break_after.remove(&flow.reverse());
+
         // node may have been deleted
         if let Some(node) = self.nodes.get_mut(&node) {
             // remove id from node
@@ -288,21 +1960,96 @@ impl Network {
             .tap_none(|| debug!("No node found for {node_id}"))
             .flatten()
         {
-            socket.lock().unwrap().wake_tcp_connection(tcp_id);
+            socket.lock().unwrap().wake_tcp_connection(tcp_id);
+        }
+    }
+
+    pub fn is_tcp_session_live(&self, flow: &Flow, tcp_id: u32) -> bool {
+        if self.is_broken(flow) {
+            return false;
+        }
+        if let Some(node_id) = self.get_node_for_addr(&flow.dst.ip()) {
+            self.nodes[&node_id].live_tcp_ids.contains(&tcp_id)
+        } else {
+            // the node does not exist, it may have been killed / restarted.
+            debug!("could not find node for id: {tcp_id}");
+            false
+        }
+    }
+
+    /// Whether the tcp connection of a flow was broken.
+    pub fn is_broken(&self, flow: &Flow) -> bool {
+        self.broken_conns
+            .lock()
+            .unwrap()
+            .contains(&flow.normalized())
+    }
+
+    /// Break the tcp connection of a flow, waking both ends so that they notice.
+    pub fn break_connection(&mut self, flow: &Flow) {
+        debug!("break connection: {flow}");
+        self.broken_conns.lock().unwrap().insert(flow.normalized());
+        self.conn_break_after.remove(flow);
+        self.conn_break_after.remove(&flow.reverse());
+        for addr in [flow.src, flow.dst] {
+            let socket = self
+                .get_node_for_addr(&addr.ip())
+                .and_then(|node| self.nodes.get(&node))
+                .and_then(|node| node.sockets.get(&SocketKey(addr.port(), flow.proto)));
+            if let Some(socket) = socket {
+                socket.lock().unwrap().wake_all();
+            }
+        }
+    }
+
+    /// Break the tcp connection of a flow once more than `bytes` bytes are sent in the flow.
+    pub fn break_connection_after(&mut self, flow: &Flow, bytes: u64) {
+        self.conn_break_after.insert(*flow, bytes);
+    }
+
+    /// Check whether a connection request from `node` to `dst` would be answered.
+    ///
+    /// Returns [`Error::TimedOut`] if the request or its answer would be lost, because no node
+    /// has the address, or the link is clogged or has no route, in either direction. Returns
+    /// [`Error::NoEndpoint`] if the destination node answers that nothing listens on the port.
+    pub fn check_connect(
+        &mut self,
+        node: NodeId,
+        proto: libc::c_int,
+        dst: SocketAddr,
+    ) -> Result<(), Error> {
+        let Some(dst_node) = self.get_node_for_addr(&dst.ip()) else {
+            return Err(Error::TimedOut(dst));
+        };
+        if self.is_clogged(node)
+            || self.is_clogged(dst_node)
+            || self.is_link_clogged(node, dst_node)
+            || self.is_link_clogged(dst_node, node)
+            || matches!(self.route(node, dst_node), Route::Unreachable)
+            || matches!(self.route(dst_node, node), Route::Unreachable)
+        {
+            return Err(Error::TimedOut(dst));
         }
-    }
-
-    pub fn is_tcp_session_live(&self, peer: &SocketAddr, tcp_id: u32) -> bool {
-        if let Some(node_id) = self.get_node_for_addr(&peer.ip()) {
-            self.nodes[&node_id].live_tcp_ids.contains(&tcp_id)
-        } else {
-            // the node does not exist, it may have been killed / restarted.
-            debug!("could not find node for id: {tcp_id}");
-            false
+        if self.refuses(dst_node, node) {
+            debug!("connection to {dst} refused: banned");
+            return Err(Error::Banned(dst));
+        }
+        match self.nodes.get(&dst_node) {
+            Some(n) if n.sockets.contains_key(&SocketKey(dst.port(), proto)) => Ok(()),
+            _ => Err(Error::NoEndpoint(dst)),
         }
     }
 
-    pub fn signal_connect(&self, proto: libc::c_int, src: SocketAddr, dst: SocketAddr) -> bool {
+    /// Signal a connection from `src` to the socket listening on `dst`. For stream sockets,
+    /// `ids` are the tcp ids of the connection, and the id of the accepting end is registered on
+    /// the node of `dst`.
+    pub fn signal_connect(
+        &mut self,
+        proto: libc::c_int,
+        src: SocketAddr,
+        dst: SocketAddr,
+        ids: Option<(u32, u32)>,
+    ) -> bool {
         let node = self.get_node_for_addr(&dst.ip());
         if node.is_none() {
             return false;
@@ -312,19 +2059,40 @@ impl Network {
         let dst_socket = self.nodes[&node].sockets.get(&SocketKey(dst.port(), proto));
 
         if let Some(dst_socket) = dst_socket {
-            dst_socket.lock().unwrap().signal_connect(src);
+            let mut dst_socket = dst_socket.lock().unwrap();
+            let backlog = dst_socket.backlog().filter(|_| self.config.listen_backlog);
+            if backlog.is_some_and(|backlog| dst_socket.pending_connections() >= backlog) {
+                debug!("accept queue of {dst} is full, refusing connection from {src}");
+                return false;
+            }
+            dst_socket.signal_connect(PendingConnection { addr: src, ids });
+            drop(dst_socket);
+            if let Some((_, accepting)) = ids {
+                self.register_tcp_id(node, accepting);
+            }
             true
         } else {
             false
         }
     }
 
+    /// Start listening on the socket bound to `addr`, with an accept queue of `backlog`
+    /// connections.
+    pub fn listen(&self, proto: libc::c_int, node: NodeId, addr: SocketAddr, backlog: usize) {
+        if let Some(socket) = self.nodes[&node]
+            .sockets
+            .get(&SocketKey(addr.port(), proto))
+        {
+            socket.lock().unwrap().accept_queue().backlog = Some(backlog);
+        }
+    }
+
     pub fn accept_connect(
         &self,
         proto: libc::c_int,
         node: NodeId,
         listening: SocketAddr,
-    ) -> Option<SocketAddr> {
+    ) -> Option<PendingConnection> {
         let socket = self.nodes[&node]
             .sockets
             .get(&SocketKey(listening.port(), proto))
@@ -340,62 +2108,140 @@ impl Network {
         }
     }
 
+    /// Close an endpoint bound with `bind_shared`, closing the socket when it was the last one.
+    pub fn close_shared(
+        &mut self,
+        proto: libc::c_int,
+        node_id: NodeId,
+        addr: SocketAddr,
+        tags: &Range<u64>,
+    ) {
+        if let Some(node) = self.nodes.get_mut(&node_id) {
+            let key = SocketKey(addr.port(), proto);
+            let Some(namespaces) = node.shared.get_mut(&key) else {
+                return;
+            };
+            namespaces.retain(|other| other != tags);
+            if namespaces.is_empty() {
+                node.shared.remove(&key);
+                self.close(proto, node_id, addr);
+            }
+        }
+    }
+
     pub fn send(
         &mut self,
         node_id: NodeId,
-        proto: libc::c_int,
-        src: SocketAddr,
-        dst: SocketAddr,
+        flow: Flow,
         tag: u64,
-        data: Payload,
+        mut data: Payload,
+        delivery: &DeliveryOverride,
     ) -> io::Result<()> {
-        trace!("send: {node_id} {src} -> {dst}, tag={tag:x}");
+        let Flow { proto, src, dst } = flow;
+        trace!("send: {node_id} {src} -> {dst}, tag={}", Tag(tag));
+        let msg_id = MsgId(self.next_msg_id);
+        self.next_msg_id += 1;
+        self.last_msg_id = Some(msg_id);
+        let size = data.size() as u64;
         let dst_node = if dst.ip().is_loopback() {
-            node_id
-        } else if let Some(x) = self.addr_to_node.get(&dst.ip()) {
-            *x
+            Some(node_id)
         } else {
+            (self.addr_to_node.get(&dst.ip()).copied())
+                .or_else(|| self.anycast_member(node_id, proto, dst))
+        };
+        let captured = self.captures(node_id, dst_node, &flow, tag, size);
+        let record = SendRecord {
+            id: msg_id,
+            flow,
+            tag,
+            size,
+            msg_log: self.msg_log.clone().filter(|_| captured),
+            flows: self.flows.clone(),
+            flow_limit: self.config.flow_limit.unwrap_or(FLOW_LIMIT),
+            link: dst_node.map(|dst_node| (node_id, dst_node)),
+            fault_drops: self.fault_drops.clone(),
+        };
+
+        let Some(dst_node) = dst_node else {
             trace!("destination not found: {dst}");
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                format!("host unreachable: {dst}"),
-            ));
+            record.dropped(DropReason::HostUnreachable);
+            return Err(Error::NodeDown(dst).into());
         };
-        if self.clogged_node.contains(&node_id)
-            || self.clogged_node.contains(&dst_node)
-            || self.clogged_link.contains(&(node_id, dst_node))
+        if self.is_clogged(node_id)
+            || self.is_clogged(dst_node)
+            || self.is_link_clogged(node_id, dst_node)
         {
             trace!("clogged");
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                format!("host unreachable: {dst}"),
-            ));
+            record.dropped(DropReason::Clogged);
+            return Err(Error::LinkClogged(dst).into());
+        }
+        // the size on the wire, and the cpu time to compress and decompress the message.
+        let compressed = match &self.config.compression {
+            Some(compression) if dst_node != node_id => compression.compress(tag, size),
+            _ => None,
+        };
+        let (wire_size, compression_time) = compressed.unwrap_or((size, Duration::ZERO));
+        if matches!(self.route(node_id, dst_node), Route::Unreachable) {
+            trace!("no wan link to {dst}");
+            record.dropped(DropReason::HostUnreachable);
+            return Err(Error::LinkClogged(dst).into());
+        }
+
+        let udp = matches!(data.ty, PayloadType::Udp);
+        let now = self.time.elapsed();
+        let held_back = match self.policers.get_mut(&node_id) {
+            Some(policer) if dst_node != node_id => policer.admit(now, udp, wire_size),
+            _ => Some(Duration::ZERO),
+        };
+        let Some(held_back) = held_back else {
+            trace!("policed");
+            record.dropped(DropReason::Policed);
+            return Ok(());
+        };
+        if self.refuses(dst_node, node_id) {
+            trace!("banned by {dst}");
+            record.dropped(DropReason::Banned);
+            return match data.ty {
+                PayloadType::Udp => Ok(()),
+                PayloadType::TcpSignalConnect => Err(Error::Banned(dst).into()),
+                PayloadType::TcpData => {
+                    self.break_connection(&flow);
+                    Err(Error::Reset(dst).into())
+                }
+            };
         }
 
         match data.ty {
             PayloadType::Udp => {
-                let plr =
-                    self.config
-                        .packet_loss
-                        .packet_loss_rate(&mut self.rand, node_id, dst_node);
-                if self.rand.gen_bool(plr) {
+                if self.udp_packet_lost(node_id, dst_node) {
                     trace!("packet loss");
+                    record.dropped(DropReason::PacketLoss);
                     return Ok(());
                 }
             }
             PayloadType::TcpSignalConnect | PayloadType::TcpData => {
-                let plr =
-                    self.config
-                        .tcp_packet_loss
-                        .packet_loss_rate(&mut self.rand, node_id, dst_node);
+                let plr = self.packet_loss_rate(true, node_id, dst_node);
                 if self.rand.gen_bool(plr) {
                     debug!("tcp connection failure");
-                    return Err(io::Error::new(
-                        io::ErrorKind::ConnectionReset,
-                        format!("peer hung up: {dst}"),
-                    ));
+                    record.dropped(DropReason::PacketLoss);
+                    return Err(Error::Reset(dst).into());
+                }
+            }
+        }
+
+        if !matches!(data.ty, PayloadType::Udp) {
+            let flow = record.flow;
+            if let Some(remaining) = self.conn_break_after.get_mut(&flow) {
+                match remaining.checked_sub(data.size() as u64) {
+                    Some(left) => *remaining = left,
+                    None => self.break_connection(&flow),
                 }
             }
+            if self.is_broken(&flow) {
+                debug!("tcp connection {flow} is broken");
+                record.dropped(DropReason::ConnectionClosed);
+                return Err(Error::Reset(dst).into());
+            }
         }
 
         let node = &self.nodes[&dst_node];
@@ -407,46 +2253,180 @@ impl Network {
             // more physically-based tcp simulator.
             if !node.live_tcp_ids.contains(&id) {
                 debug!("tcp session to {dst} has ended");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionReset,
-                    format!("peer hung up: {dst}"),
-                ));
+                record.dropped(DropReason::ConnectionClosed);
+                return Err(Error::Reset(dst).into());
             }
         }
 
-        let mailbox = match node.sockets.get(&SocketKey(dst.port(), proto)) {
+        let key = SocketKey(dst.port(), proto);
+        let owned = match node.shared.get(&key) {
+            Some(namespaces) => namespaces.iter().any(|tags| tags.contains(&tag)),
+            None => true,
+        };
+        let mailbox = match node.sockets.get(&key).filter(|_| owned) {
             Some(mailbox) => Arc::downgrade(mailbox),
             None => {
                 debug!("destination port not available: {dst}");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    format!("connection refused: {dst}"),
-                ));
+                record.dropped(DropReason::PortUnreachable);
+                return Err(Error::NoEndpoint(dst).into());
             }
         };
 
+        let Some(extra_latency) = self.sample_degradation(udp, node_id, dst_node) else {
+            trace!("packet loss (degraded)");
+            record.dropped(DropReason::PacketLoss);
+            return Ok(());
+        };
+        let Some((tag_latency, tag_duplicate)) = self.sample_tag_faults(udp, tag) else {
+            trace!("packet loss (tag fault)");
+            record.dropped(DropReason::PacketLoss);
+            return Ok(());
+        };
+        let extra_latency = extra_latency + tag_latency + held_back;
+        if delivery.force_drop {
+            trace!("packet loss (forced)");
+            record.dropped(DropReason::PacketLoss);
+            return Ok(());
+        }
+        // the checksum is of the message as sent, so that tampering shows in it.
+        if self.config.checksum {
+            data.seal();
+        }
+        let tamper = Tamper {
+            tamperer: self.tamperer.clone(),
+            log: self.tamper_log.clone(),
+            record: TamperRecord {
+                id: msg_id,
+                from: node_id,
+                to: dst_node,
+                tag,
+            },
+        };
+        let duplicate = match (delivery.duplicate, tag_duplicate) {
+            (Some(delay), _) => Some((
+                delay,
+                data.try_clone().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "typed payloads cannot be duplicated",
+                    )
+                })?,
+            )),
+            (None, Some(delay)) => data.try_clone().map(|data| (delay, data)),
+            (None, None) => None,
+        };
+        // only the messages that leave the node are policed and billed.
+        if dst_node != node_id {
+            if let Some(policer) = self.policers.get_mut(&node_id) {
+                policer.take(wire_size);
+            }
+            let (packets, bytes) = self.traffic.entry((node_id, dst_node)).or_default();
+            *packets += 1;
+            *bytes += wire_size;
+        }
+
         let msg = Message {
             tag,
             data,
             from: src,
         };
-        let latency = self
-            .config
-            .latency
-            .get_latency(&mut self.rand, node_id, dst_node);
+
+        let mut latency = match self.link_latency.get(&(node_id, dst_node)) {
+            Some(dist) => dist.sample(&mut self.rand),
+            None => match self.route(node_id, dst_node) {
+                Route::Wan(link) => link.latency.sample(&mut self.rand),
+                Route::Lan(Some(config)) => {
+                    config
+                        .latency
+                        .get_latency(&mut self.rand, node_id, dst_node)
+                }
+                Route::Lan(None) | Route::Unreachable => {
+                    self.config
+                        .latency
+                        .get_latency(&mut self.rand, node_id, dst_node)
+                }
+            },
+        };
+        // while exploring, the latency is one of the explored choices.
+        if let Some(explored) = crate::explore::delivery_latency() {
+            latency = explored;
+        }
+        if let Some(exact) = delivery.latency {
+            latency = exact;
+        }
+        latency += extra_latency + compression_time;
         trace!("delay: {latency:?}");
         self.time
-            .add_timer_for_node(dst_node, self.time.now_instant() + latency, move || {
-                if let Some(mailbox) = mailbox.upgrade() {
-                    trace!(
-                        "deliver: {src}(node: {node_id}) -> {dst}(node: {dst_node}), tag={tag:x}"
-                    );
-                    mailbox.lock().unwrap().deliver(msg);
-                } else {
-                    trace!("deliver: mailbox was destroyed before delivery");
-                }
+            .profiler()
+            .record(Category::NetworkLatency, latency);
+        record.in_flight();
+        let recorder = crate::context::try_current(|h| h.trace.clone()).filter(|_| captured);
+        let broken = (!udp).then(|| (self.broken_conns.clone(), record.flow.normalized()));
+        let recv_buffer = (self.config.recv_buffer.as_ref())
+            .filter(|_| udp)
+            .map(|config| (config.class_of(tag), self.recv_buffer_drops.clone()));
+        if let Some(recorder) = &recorder {
+            recorder.record(EventKind::MsgSent {
+                from: node_id,
+                to: dst_node,
+                tag,
+            });
+        }
+        let handle = MsgHandle {
+            id: msg_id,
+            from: node_id,
+            to: dst_node,
+            src,
+            dst,
+            tag,
+            duplicate: false,
+        };
+        let duplicate_handle = MsgHandle {
+            duplicate: true,
+            ..handle.clone()
+        };
+        let arrival = Arrival {
+            handle: handle.clone(),
+            mailbox,
+            broken,
+            listen_backlog: self.config.listen_backlog,
+            recv_buffer,
+            recorder,
+            tamper,
+        };
+        let duplicate = duplicate.map(|(delay, data)| {
+            let msg = Message {
+                tag,
+                data,
+                from: src,
+            };
+            // the duplicate is another packet of the flow, and leaves the message log to the
+            // original.
+            let record = SendRecord {
+                msg_log: None,
+                ..record.clone()
+            };
+            record.in_flight();
+            let arrival = Arrival {
+                handle: duplicate_handle.clone(),
+                ..arrival.clone()
+            };
+            (delay, msg, record, arrival)
+        });
+        self.schedule(handle, Some(wire_size), latency, move || {
+            arrival.deliver_checked(msg, &record, latency)
+        });
+        if let Some((delay, msg, record, arrival)) = duplicate {
+            self.schedule(duplicate_handle, None, latency + delay, move || {
+                arrival.deliver_checked(msg, &record, latency + delay)
             });
+        }
         self.stat.msg_count += 1;
+        if compressed.is_some() {
+            self.stat.compressed_count += 1;
+            self.stat.compression_saved_bytes += size - wire_size;
+            self.stat.compression_time += compression_time;
+        }
 
         Ok(())
     }
@@ -461,7 +2441,7 @@ impl Network {
         self.nodes[&node].sockets[&SocketKey(dst.port(), proto)]
             .lock()
             .unwrap()
-            .recv(tag)
+            .recv(tag, self.config.delivery_order)
     }
 
     pub fn recv_sync(
@@ -474,7 +2454,54 @@ impl Network {
         self.nodes[&node].sockets[&SocketKey(dst.port(), proto)]
             .lock()
             .unwrap()
-            .recv_sync(tag)
+            .recv_sync(tag, self.config.delivery_order)
+    }
+
+    /// Drop the next message with `tag` to the socket bound to `addr`, whether it is queued or
+    /// still on its way. Used for replies that are no longer waited for.
+    pub fn discard(&mut self, node: NodeId, proto: libc::c_int, addr: SocketAddr, tag: u64) {
+        if let Some(socket) = self
+            .nodes
+            .get(&node)
+            .and_then(|node| node.sockets.get(&SocketKey(addr.port(), proto)))
+        {
+            socket.lock().unwrap().discard(tag);
+        }
+    }
+
+    /// Count the messages waiting in the socket bound to `addr` whose tag satisfies `filter`, and
+    /// the connections waiting to be accepted. Returns `None` if no socket is bound to `addr`.
+    pub fn queue_depth(
+        &self,
+        node: NodeId,
+        proto: libc::c_int,
+        addr: SocketAddr,
+        filter: impl Fn(u64) -> bool,
+    ) -> Option<(usize, usize)> {
+        let mailbox = self
+            .nodes
+            .get(&node)?
+            .sockets
+            .get(&SocketKey(addr.port(), proto))?
+            .lock()
+            .unwrap();
+        let msgs = mailbox.msgs.iter().filter(|msg| filter(msg.tag)).count();
+        Some((msgs, mailbox.pending_connections()))
+    }
+
+    /// Get notified of the tag and sender of each message queued in the socket bound to `addr`,
+    /// starting with the messages already waiting. Only tags in `tags` are reported, if given.
+    pub fn incoming_tags(
+        &self,
+        node: NodeId,
+        proto: libc::c_int,
+        addr: SocketAddr,
+        tags: Option<Range<u64>>,
+    ) -> mpsc::UnboundedReceiver<(u64, SocketAddr)> {
+        self.nodes[&node].sockets[&SocketKey(addr.port(), proto)]
+            .lock()
+            .unwrap()
+            .watch(tags)
     }
 
     pub fn recv_ready(
@@ -492,46 +2519,300 @@ impl Network {
     }
 }
 
+/// A connection signaled by `connect()`, waiting to be accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingConnection {
+    /// The address of the connecting socket.
+    pub addr: SocketAddr,
+    /// The tcp ids of the connecting and the accepting end of a stream connection. The segments
+    /// sent to each end are tagged with its id.
+    pub ids: Option<(u32, u32)>,
+}
+
 pub struct Message {
     pub tag: u64,
     pub data: Payload,
     pub from: SocketAddr,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PayloadType {
     TcpSignalConnect,
     TcpData,
     Udp,
 }
 
+/// The data carried by a [`Payload`].
+///
+/// Simulators should use the byte and tcp variants when they can: such payloads can be
+/// checksummed, duplicated and serialized with [`Payload::encode`].
+pub enum PayloadData {
+    /// Raw bytes, e.g. a udp datagram.
+    Bytes(Bytes),
+    /// A tcp connection request, carrying the id of the connection.
+    TcpConnect(u32),
+    /// A segment of a tcp connection: a sequence number and the data.
+    TcpSegment(u32, Bytes),
+    /// Any other value, for simulators exchanging typed messages.
+    Any(Box<dyn Any + Send + Sync>),
+}
+
+impl PayloadData {
+    /// Wrap a boxed value, recognizing byte vectors and [`Bytes`].
+    pub fn from_any(data: Box<dyn Any + Send + Sync>) -> Self {
+        match data.downcast::<Vec<u8>>() {
+            Ok(v) => Self::Bytes((*v).into()),
+            Err(data) => match data.downcast::<Bytes>() {
+                Ok(b) => Self::Bytes(*b),
+                Err(data) => Self::Any(data),
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for PayloadData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes(b) => write!(f, "Bytes({} bytes)", b.len()),
+            Self::TcpConnect(id) => write!(f, "TcpConnect({id})"),
+            Self::TcpSegment(seq, b) => write!(f, "TcpSegment({seq}, {} bytes)", b.len()),
+            Self::Any(_) => write!(f, "Any"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for PayloadData {
+    fn from(v: Vec<u8>) -> Self {
+        Self::Bytes(v.into())
+    }
+}
+
+impl From<Bytes> for PayloadData {
+    fn from(b: Bytes) -> Self {
+        Self::Bytes(b)
+    }
+}
+
+#[derive(Debug)]
 pub struct Payload {
     pub ty: PayloadType,
-    pub data: Box<dyn Any + Send + Sync>,
+    pub data: PayloadData,
+    /// Size of the payload in bytes, for flow statistics.
+    size: Option<usize>,
+    /// Checksum of the payload bytes as sent, if checksums are enabled.
+    checksum: Option<u64>,
+    /// Checksum of the payload bytes after they were tampered with on purpose, which copies of
+    /// the payload are verified against instead, see [`NetSim::set_tamperer`](super::NetSim::set_tamperer).
+    tampered_checksum: Option<u64>,
 }
 
 impl Payload {
-    pub fn new_udp(data: Box<dyn Any + Send + Sync>) -> Self {
+    fn new(ty: PayloadType, data: PayloadData) -> Self {
         Self {
-            ty: PayloadType::Udp,
+            ty,
             data,
+            size: None,
+            checksum: None,
+            tampered_checksum: None,
         }
     }
 
+    /// A udp payload.
+    pub fn udp(data: impl Into<PayloadData>) -> Self {
+        Self::new(PayloadType::Udp, data.into())
+    }
+
+    /// A tcp connection request for the connection `id`.
+    pub fn tcp_connect(id: u32) -> Self {
+        Self::new(PayloadType::TcpSignalConnect, PayloadData::TcpConnect(id))
+    }
+
+    /// A segment of a tcp connection.
+    pub fn tcp_data(seq: u32, data: impl Into<Bytes>) -> Self {
+        Self::new(
+            PayloadType::TcpData,
+            PayloadData::TcpSegment(seq, data.into()),
+        )
+    }
+
+    /// A udp payload of a boxed value. Byte vectors and [`Bytes`] become byte payloads.
+    pub fn new_udp(data: Box<dyn Any + Send + Sync>) -> Self {
+        Self::new(PayloadType::Udp, PayloadData::from_any(data))
+    }
+
+    /// A tcp connection request of a boxed value, prefer [`Payload::tcp_connect`].
     pub fn new_tcp_connect(data: Box<dyn Any + Send + Sync>) -> Self {
-        Self {
-            ty: PayloadType::TcpSignalConnect,
-            data,
-        }
+        Self::new(PayloadType::TcpSignalConnect, PayloadData::from_any(data))
     }
 
+    /// A tcp segment of a boxed value, prefer [`Payload::tcp_data`].
     pub fn new_tcp_data(data: Box<dyn Any + Send + Sync>) -> Self {
-        Self {
-            ty: PayloadType::TcpData,
+        Self::new(PayloadType::TcpData, PayloadData::from_any(data))
+    }
+
+    /// Set the size of the payload in bytes, as reported in flow statistics.
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// The size of the payload in bytes.
+    ///
+    /// Defaults to the length of the data if it is made of bytes, and zero otherwise.
+    pub fn size(&self) -> usize {
+        self.size
+            .or_else(|| self.bytes().map(|b| b.len()))
+            .unwrap_or(0)
+    }
+
+    /// Copy a payload, unless it is a [`PayloadData::Any`].
+    pub(crate) fn try_clone(&self) -> Option<Self> {
+        let data = match &self.data {
+            PayloadData::Bytes(b) => PayloadData::Bytes(b.clone()),
+            PayloadData::TcpConnect(id) => PayloadData::TcpConnect(*id),
+            PayloadData::TcpSegment(seq, b) => PayloadData::TcpSegment(*seq, b.clone()),
+            PayloadData::Any(_) => return None,
+        };
+        Some(Self { data, ..*self })
+    }
+
+    /// The data as bytes, if it is made of bytes.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.data {
+            PayloadData::Bytes(b) | PayloadData::TcpSegment(_, b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Take the data as bytes.
+    ///
+    /// Returns an `InvalidData` error if the payload is not made of bytes.
+    pub fn into_bytes(self) -> io::Result<Bytes> {
+        match self.data {
+            PayloadData::Bytes(b) | PayloadData::TcpSegment(_, b) => Ok(b),
+            data => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("payload is not bytes: {data:?}"),
+            )),
+        }
+    }
+
+    /// Take the data as a value of type `T`.
+    ///
+    /// Byte payloads can be taken as a `Vec<u8>` or [`Bytes`]. Returns an `InvalidData` error
+    /// if the payload has another type, instead of panicking when two simulators disagree on
+    /// the type of their messages.
+    pub fn downcast<T: Any>(self) -> io::Result<T> {
+        let data: Box<dyn Any + Send + Sync> = match self.data {
+            PayloadData::Any(data) => data,
+            PayloadData::Bytes(b) if TypeId::of::<T>() == TypeId::of::<Vec<u8>>() => {
+                Box::new(Vec::from(b))
+            }
+            PayloadData::Bytes(b) => Box::new(b),
+            data => Box::new(data),
+        };
+        data.downcast::<T>().map(|data| *data).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("payload is not a {}", std::any::type_name::<T>()),
+            )
+        })
+    }
+
+    /// Serialize the payload, e.g. to capture or replay it.
+    ///
+    /// Returns `None` for a [`PayloadData::Any`]. The checksum is not included.
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let mut buf = vec![];
+        buf.put_u8(self.ty as u8);
+        buf.put_u64(self.size.map_or(u64::MAX, |size| size as u64));
+        match &self.data {
+            PayloadData::Bytes(b) => {
+                buf.put_u8(0);
+                buf.put_slice(b);
+            }
+            PayloadData::TcpConnect(id) => {
+                buf.put_u8(1);
+                buf.put_u32(*id);
+            }
+            PayloadData::TcpSegment(seq, b) => {
+                buf.put_u8(2);
+                buf.put_u32(*seq);
+                buf.put_slice(b);
+            }
+            PayloadData::Any(_) => return None,
+        }
+        Some(buf)
+    }
+
+    /// Deserialize a payload serialized with [`Payload::encode`].
+    pub fn decode(mut buf: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid payload encoding");
+        if buf.remaining() < 10 {
+            return Err(invalid());
+        }
+        let ty = match buf.get_u8() {
+            0 => PayloadType::TcpSignalConnect,
+            1 => PayloadType::TcpData,
+            2 => PayloadType::Udp,
+            _ => return Err(invalid()),
+        };
+        let size = buf.get_u64();
+        let data = match buf.get_u8() {
+            0 => PayloadData::Bytes(Bytes::copy_from_slice(buf)),
+            1 if buf.remaining() == 4 => PayloadData::TcpConnect(buf.get_u32()),
+            2 if buf.remaining() >= 4 => {
+                let seq = buf.get_u32();
+                PayloadData::TcpSegment(seq, Bytes::copy_from_slice(buf))
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            ty,
             data,
+            size: (size != u64::MAX).then_some(size as usize),
+            checksum: None,
+            tampered_checksum: None,
+        })
+    }
+
+    /// Compute the checksum of the payload bytes, if it has not been computed yet.
+    fn seal(&mut self) {
+        if self.checksum.is_none() {
+            self.checksum = self.bytes().map(checksum);
+        }
+    }
+
+    /// Record that the bytes of a sealed payload were tampered with on purpose.
+    fn tampered(&mut self) {
+        if self.checksum.is_some() {
+            self.tampered_checksum = self.bytes().map(checksum);
         }
     }
 
+    /// The checksum computed when the payload was sent, if checksums are enabled.
+    pub fn checksum(&self) -> Option<u64> {
+        self.checksum
+    }
+
+    /// Whether the bytes of the payload are still those that were sent, as a real checksum would
+    /// tell. Returns `None` if checksums are disabled.
+    ///
+    /// Unlike [`verify_checksum`](Self::verify_checksum), this shows the changes made by a
+    /// [tamperer](super::NetSim::set_tamperer), so that the system under test can detect them.
+    pub fn intact(&self) -> Option<bool> {
+        Some(self.checksum? == checksum(self.bytes()?))
+    }
+
+    /// Verify `bytes` against the checksum of the payload, panicking with the description of the
+    /// message given by `describe` if they do not match.
+    ///
+    /// Changes made by a [tamperer](super::NetSim::set_tamperer) are injected on purpose, and
+    /// pass the verification.
+    pub fn verify_checksum(&self, bytes: &[u8], describe: impl FnOnce() -> String) {
+        verify_checksum(self.tampered_checksum.or(self.checksum), bytes, describe);
+    }
+
     pub fn is_udp(&self) -> bool {
         matches!(self.ty, PayloadType::Udp)
     }
@@ -545,6 +2826,60 @@ impl Payload {
     }
 }
 
+/// FNV-1a checksum of a payload.
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) fn verify_checksum(
+    expected: Option<u64>,
+    bytes: &[u8],
+    describe: impl FnOnce() -> String,
+) {
+    let Some(expected) = expected else {
+        return;
+    };
+    let actual = checksum(bytes);
+    assert_eq!(
+        expected,
+        actual,
+        "payload corrupted: {}, len={}",
+        describe(),
+        bytes.len()
+    );
+}
+
+type Watcher = mpsc::UnboundedSender<(u64, SocketAddr)>;
+
+/// Pick the sender of the next message of `msgs` with `tag` to receive.
+fn pick_sender(
+    msgs: &[Message],
+    fairness: &Fairness,
+    tag: u64,
+    order: DeliveryOrder,
+) -> Option<SocketAddr> {
+    // senders with queued messages, in order of their first queued message.
+    let mut backlog: Vec<(SocketAddr, usize)> = vec![];
+    for msg in msgs.iter().filter(|msg| msg.tag == tag) {
+        match backlog.iter_mut().find(|(from, _)| *from == msg.from) {
+            Some((_, count)) => *count += 1,
+            None => backlog.push((msg.from, 1)),
+        }
+    }
+    let last_served = |from: &SocketAddr| fairness.last_served(from);
+    let (from, _) = match order {
+        DeliveryOrder::Fair => backlog.iter().min_by_key(|(from, _)| last_served(from)),
+        // `max_by_key` returns the last maximum, so reverse to break ties by arrival.
+        _ => backlog
+            .iter()
+            .rev()
+            .max_by_key(|(from, count)| (last_served(from) == fairness.recv_count, *count)),
+    }?;
+    Some(*from)
+}
+
 /// Tag message mailbox for an endpoint.
 #[derive(Default)]
 struct Mailbox {
@@ -556,12 +2891,85 @@ struct Mailbox {
     /// Wakers for async io waiting for packets.
     wakers: Vec<(u64, Waker)>,
 
+    /// Streams notified of the messages queued with a tag in the range, or with any tag.
+    watchers: Vec<(Option<Range<u64>>, Watcher)>,
+
+    /// The accept queue, allocated once the socket listens or gets a connection.
+    accept: Option<Box<AcceptQueue>>,
+
+    /// The senders received from, allocated once a message is received in a
+    /// [`DeliveryOrder`] other than `Arbitrary`.
+    fairness: Option<Box<Fairness>>,
+
+    /// Tags of messages to drop on arrival, oldest first, see [`Mailbox::discard`].
+    discarded: VecDeque<u64>,
+}
+
+/// The tags kept in [`Mailbox::discarded`], past which the oldest ones are forgotten.
+const DISCARD_LIMIT: usize = 1024;
+
+/// The senders kept in [`Fairness`], past which the least recently served ones are forgotten.
+const FAIRNESS_LIMIT: usize = 1024;
+
+/// The senders a socket received from, to pick the next one to serve.
+#[derive(Default)]
+struct Fairness {
+    /// Number of messages received so far.
+    recv_count: u64,
+    /// The value of `recv_count` when each sender was last received from, for the most recently
+    /// served senders.
+    last_served: HashMap<SocketAddr, u64>,
+}
+
+impl Fairness {
+    fn served(&mut self, from: SocketAddr) {
+        self.recv_count += 1;
+        self.last_served.insert(from, self.recv_count);
+        if self.last_served.len() > FAIRNESS_LIMIT {
+            // forget the least recently served half, which is served first either way.
+            let mut counts: Vec<u64> = self.last_served.values().copied().collect();
+            let (_, &mut median, _) = counts.select_nth_unstable(FAIRNESS_LIMIT / 2);
+            self.last_served.retain(|_, count| *count >= median);
+        }
+    }
+
+    fn last_served(&self, from: &SocketAddr) -> u64 {
+        self.last_served.get(from).copied().unwrap_or(0)
+    }
+}
+
+/// The accept queue of a listening socket.
+#[derive(Default)]
+struct AcceptQueue {
     /// tcp connections (via connect/accept) are signaled synchronously, out of band from the
     /// normal network simulation, in order to support blocking connect/accept.
-    sync_connections: VecDeque<SocketAddr>,
+    sync_connections: VecDeque<PendingConnection>,
+
+    /// The number of connections that can wait to be accepted, if `listen()` was called.
+    backlog: Option<usize>,
 }
 
 impl Mailbox {
+    fn accept_queue(&mut self) -> &mut AcceptQueue {
+        self.accept.get_or_insert_with(Default::default)
+    }
+
+    fn backlog(&self) -> Option<usize> {
+        self.accept.as_ref()?.backlog
+    }
+
+    fn pending_connections(&self) -> usize {
+        self.accept
+            .as_ref()
+            .map_or(0, |accept| accept.sync_connections.len())
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
     fn wake_tcp_connection(&mut self, tcp_id: u32) {
         for i in (0..self.wakers.len()).rev() {
             if (self.wakers[i].0 >> 32) == tcp_id as u64 {
@@ -572,6 +2980,11 @@ impl Mailbox {
     }
 
     fn deliver(&mut self, msg: Message) {
+        if let Some(i) = self.discarded.iter().position(|tag| *tag == msg.tag) {
+            trace!("drop discarded message: tag={}", Tag(msg.tag));
+            self.discarded.remove(i);
+            return;
+        }
         for i in (0..self.wakers.len()).rev() {
             if self.wakers[i].0 == msg.tag {
                 let (_, waker) = self.wakers.swap_remove(i);
@@ -585,8 +2998,14 @@ impl Mailbox {
             if matches!(&msg, Some(msg) if msg.tag == self.registered[i].0) {
                 // tag match, take and try send
                 let (_, sender) = self.registered.swap_remove(i);
+                let from = msg.as_ref().unwrap().from;
                 msg = match sender.send(msg.take().unwrap()) {
-                    Ok(_) => return,
+                    Ok(_) => {
+                        if let Some(fairness) = &mut self.fairness {
+                            fairness.served(from);
+                        }
+                        return;
+                    }
                     Err(m) => Some(m),
                 };
                 // failed to send, try next
@@ -596,7 +3015,56 @@ impl Mailbox {
             }
         }
         // failed to match awaiting recv, save
-        self.msgs.push(msg.unwrap());
+        let msg = msg.unwrap();
+        let (tag, from) = (msg.tag, msg.from);
+        self.watchers.retain(|(tags, watcher)| match tags {
+            Some(tags) if !tags.contains(&tag) => !watcher.is_closed(),
+            _ => watcher.unbounded_send((tag, from)).is_ok(),
+        });
+        self.msgs.push(msg);
+    }
+
+    /// Drop the next message with `tag`: the first one queued, or else the next one to arrive.
+    fn discard(&mut self, tag: u64) {
+        self.registered
+            .retain(|(t, tx)| *t != tag || !tx.is_canceled());
+        if let Some(idx) = self.msgs.iter().position(|msg| msg.tag == tag) {
+            self.msgs.remove(idx);
+            return;
+        }
+        self.discarded.push_back(tag);
+        if self.discarded.len() > DISCARD_LIMIT {
+            self.discarded.pop_front();
+        }
+    }
+
+    /// Whether queueing `msg` would fill the udp messages queued past `limit` bytes. A message
+    /// that a receiver is waiting for is not queued.
+    fn overflows(&self, msg: &Message, limit: u64) -> bool {
+        if self
+            .registered
+            .iter()
+            .any(|(tag, tx)| *tag == msg.tag && !tx.is_canceled())
+        {
+            return false;
+        }
+        let udp = self
+            .msgs
+            .iter()
+            .filter(|m| matches!(m.data.ty, PayloadType::Udp));
+        let queued: u64 = udp.map(|m| m.data.size() as u64).sum();
+        queued + msg.data.size() as u64 > limit
+    }
+
+    fn watch(&mut self, tags: Option<Range<u64>>) -> mpsc::UnboundedReceiver<(u64, SocketAddr)> {
+        let (tx, rx) = mpsc::unbounded();
+        for msg in &self.msgs {
+            if tags.as_ref().map_or(true, |tags| tags.contains(&msg.tag)) {
+                tx.unbounded_send((msg.tag, msg.from)).unwrap();
+            }
+        }
+        self.watchers.push((tags, tx));
+        rx
     }
 
     fn recv_ready(&mut self, cx: Option<&mut Context<'_>>, tag: u64) -> bool {
@@ -607,18 +3075,33 @@ impl Mailbox {
         ready
     }
 
-    fn recv_sync(&mut self, tag: u64) -> Option<Message> {
-        if let Some(idx) = self.msgs.iter().position(|msg| tag == msg.tag) {
-            let msg = self.msgs.swap_remove(idx);
-            Some(msg)
-        } else {
-            None
+    fn recv_sync(&mut self, tag: u64, order: DeliveryOrder) -> Option<Message> {
+        let msg = match order {
+            DeliveryOrder::Arbitrary => {
+                let idx = self.msgs.iter().position(|msg| tag == msg.tag)?;
+                self.msgs.swap_remove(idx)
+            }
+            DeliveryOrder::Fair | DeliveryOrder::Unfair => {
+                let fairness = self.fairness.get_or_insert_with(Default::default);
+                let from = pick_sender(&self.msgs, fairness, tag, order)?;
+                let idx = self
+                    .msgs
+                    .iter()
+                    .position(|msg| tag == msg.tag && msg.from == from)
+                    .unwrap();
+                // keep the arrival order of the remaining messages.
+                self.msgs.remove(idx)
+            }
+        };
+        if let Some(fairness) = &mut self.fairness {
+            fairness.served(msg.from);
         }
+        Some(msg)
     }
 
-    fn recv(&mut self, tag: u64) -> oneshot::Receiver<Message> {
+    fn recv(&mut self, tag: u64, order: DeliveryOrder) -> oneshot::Receiver<Message> {
         let (tx, rx) = oneshot::channel();
-        if let Some(msg) = self.recv_sync(tag) {
+        if let Some(msg) = self.recv_sync(tag, order) {
             tx.send(msg).ok().unwrap();
         } else {
             self.registered.push((tag, tx));
@@ -626,11 +3109,23 @@ impl Mailbox {
         rx
     }
 
-    fn signal_connect(&mut self, src_addr: SocketAddr) {
-        self.sync_connections.push_back(src_addr);
+    /// Whether `msg` is a connection request that does not fit in the accept queue. Requests
+    /// that a pending `accept()` is waiting for are never queued.
+    fn accept_queue_full(&self, msg: &Message) -> bool {
+        let is_connect =
+            |msg: &Message| msg.tag == 0 && msg.data.ty == PayloadType::TcpSignalConnect;
+        if !is_connect(msg) || self.registered.iter().any(|(tag, _)| *tag == 0) {
+            return false;
+        }
+        let queued = || self.msgs.iter().filter(|msg| is_connect(msg)).count();
+        self.backlog().is_some_and(|backlog| queued() >= backlog)
+    }
+
+    fn signal_connect(&mut self, conn: PendingConnection) {
+        self.accept_queue().sync_connections.push_back(conn);
     }
 
-    fn accept_connect(&mut self) -> Option<SocketAddr> {
-        self.sync_connections.pop_front()
+    fn accept_connect(&mut self) -> Option<PendingConnection> {
+        self.accept.as_mut()?.sync_connections.pop_front()
     }
 }
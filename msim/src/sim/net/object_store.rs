@@ -0,0 +1,373 @@
+//! A simulated object storage service, in the spirit of S3.
+//!
+//! [`ObjectStore`] stores blobs by key and serves them over the simulated network to
+//! [`ObjectStoreClient`]s, so that the nodes of a system share it the way they share a bucket
+//! in production, and lose access to it in partitions.
+//!
+//! The store can show the anomalies of real object storage:
+//! - [`ObjectStore::set_read_delay`]: a write only becomes visible to reads after a delay, so a
+//!   read that follows a write may return the previous version of the object, or no object.
+//! - [`ObjectStore::set_list_delay`]: listings lag behind writes, independently of reads.
+//! - [`ObjectStore::set_error_rate`]: requests fail with a server error, like the 500 and 503
+//!   responses clients of S3 have to retry. A failed request has no effect.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::net::object_store::{ObjectStore, ObjectStoreClient};
+//!
+//! let store = ObjectStore::start(&handle, "10.0.0.200".parse().unwrap());
+//! store.set_read_delay(LatencyDistribution::uniform(Duration::ZERO..Duration::from_secs(2)));
+//! let addr = store.addr();
+//!
+//! // on a node
+//! let client = ObjectStoreClient::new(addr).await?;
+//! client.put("snapshots/42", data).await?;
+//! let keys = client.list("snapshots/").await?;
+//! ```
+
+use super::{rpc, Endpoint, LatencyDistribution};
+use crate::{
+    rand::{thread_rng, Rng},
+    runtime::Handle,
+    time::{Duration, Instant},
+};
+use bytes::Bytes;
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tracing::*;
+
+/// The port the object store listens on.
+pub const OBJECT_STORE_PORT: u16 = 9000;
+
+/// Tag of requests sent to the server.
+const REQUEST_TAG: u64 = 0x0b1e_0000_0000_0000;
+
+#[derive(Debug)]
+enum Request {
+    Put { key: String, data: Bytes },
+    Get { key: String },
+    Delete { key: String },
+    List { prefix: String },
+}
+
+#[derive(Debug)]
+enum Response {
+    Done,
+    Object(Option<Bytes>),
+    Keys(Vec<String>),
+    ServerError,
+}
+
+/// A write of an object, `None` for a deletion.
+struct Version {
+    data: Option<Bytes>,
+    /// When reads see the write.
+    readable_at: Instant,
+    /// When listings see the write.
+    listed_at: Instant,
+}
+
+struct State {
+    /// The versions of each object, in the order they were written.
+    objects: BTreeMap<String, Vec<Version>>,
+    read_delay: LatencyDistribution,
+    list_delay: LatencyDistribution,
+    error_rate: f64,
+}
+
+impl State {
+    /// The latest version of `key` that is visible at `now`, according to `visible_at`.
+    fn visible(
+        &self,
+        key: &str,
+        now: Instant,
+        visible_at: impl Fn(&Version) -> Instant,
+    ) -> Option<&Bytes> {
+        let versions = self.objects.get(key)?;
+        let version = versions.iter().rev().find(|v| visible_at(v) <= now)?;
+        version.data.as_ref()
+    }
+
+    fn write(&mut self, key: String, data: Option<Bytes>, now: Instant) {
+        let mut rng = thread_rng();
+        let version = Version {
+            data,
+            readable_at: now + self.read_delay.sample(&mut rng),
+            listed_at: now + self.list_delay.sample(&mut rng),
+        };
+        let versions = self.objects.entry(key).or_default();
+        // versions older than one that is visible everywhere are never seen again.
+        if let Some(i) = versions
+            .iter()
+            .rposition(|v| v.readable_at <= now && v.listed_at <= now)
+        {
+            versions.drain(..i);
+        }
+        versions.push(version);
+    }
+}
+
+/// The object store.
+///
+/// The objects and the settings are shared by all clones of the store and survive restarts of
+/// the node it runs on.
+#[derive(Clone)]
+pub struct ObjectStore {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl ObjectStore {
+    /// Create a store listening on `ip`. Call [`ObjectStore::serve`] on the node owning the ip to
+    /// start serving requests.
+    pub fn new(ip: IpAddr) -> Self {
+        let state = State {
+            objects: BTreeMap::new(),
+            read_delay: LatencyDistribution::Constant(Duration::ZERO),
+            list_delay: LatencyDistribution::Constant(Duration::ZERO),
+            error_rate: 0.0,
+        };
+        ObjectStore {
+            addr: SocketAddr::new(ip, OBJECT_STORE_PORT),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Create a node named "object-store" with the given ip, serving the objects.
+    pub fn start(handle: &Handle, ip: IpAddr) -> Self {
+        let store = Self::new(ip);
+        let store_ = store.clone();
+        handle
+            .create_node()
+            .name("object-store")
+            .ip(ip)
+            .init(move || {
+                let store = store_.clone();
+                async move {
+                    if let Err(e) = store.serve().await {
+                        error!("object store failed: {e}");
+                    }
+                }
+            })
+            .build();
+        store
+    }
+
+    /// The address of the store.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Make each write visible to reads after a sample of `delay`. Writes are immediately
+    /// visible by default.
+    pub fn set_read_delay(&self, delay: LatencyDistribution) {
+        self.state.lock().unwrap().read_delay = delay;
+    }
+
+    /// Make each write visible to listings after a sample of `delay`. Writes are immediately
+    /// visible by default.
+    pub fn set_list_delay(&self, delay: LatencyDistribution) {
+        self.state.lock().unwrap().list_delay = delay;
+    }
+
+    /// Fail requests with a server error with probability `rate`.
+    pub fn set_error_rate(&self, rate: f64) {
+        assert!((0.0..=1.0).contains(&rate), "invalid error rate: {rate}");
+        self.state.lock().unwrap().error_rate = rate;
+    }
+
+    /// The latest written data of `key`, directly from the store, bypassing the network and the
+    /// read delay.
+    pub fn latest(&self, key: &str) -> Option<Bytes> {
+        let state = self.state.lock().unwrap();
+        let versions = state.objects.get(key)?;
+        versions.last()?.data.clone()
+    }
+
+    /// The keys of the objects that exist, directly from the store, bypassing the network and
+    /// the list delay.
+    pub fn keys(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let exists = |versions: &Vec<Version>| versions.last().is_some_and(|v| v.data.is_some());
+        let objects = state
+            .objects
+            .iter()
+            .filter(|(_, versions)| exists(versions));
+        objects.map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Serve requests until the node is killed.
+    pub async fn serve(&self) -> io::Result<()> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, self.addr).await?;
+        rpc::serve(&ep, REQUEST_TAG, "object store request", |_, request| {
+            self.handle(request)
+        })
+        .await
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if thread_rng().gen_bool(state.error_rate) {
+            debug!("object store failed request {request:?}");
+            return Response::ServerError;
+        }
+        match request {
+            Request::Put { key, data } => {
+                state.write(key, Some(data), now);
+                Response::Done
+            }
+            Request::Delete { key } => {
+                state.write(key, None, now);
+                Response::Done
+            }
+            Request::Get { key } => {
+                Response::Object(state.visible(&key, now, |v| v.readable_at).cloned())
+            }
+            Request::List { prefix } => {
+                let keys = state
+                    .objects
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .filter(|key| state.visible(key, now, |v| v.listed_at).is_some());
+                Response::Keys(keys.cloned().collect())
+            }
+        }
+    }
+}
+
+/// A client of the object store.
+pub struct ObjectStoreClient {
+    ep: Endpoint,
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl ObjectStoreClient {
+    /// Create a client of the store at `server`.
+    pub async fn new(server: SocketAddr) -> io::Result<Self> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:0").await?;
+        Ok(ObjectStoreClient {
+            ep,
+            server,
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    /// Set the timeout of requests. Defaults to one second.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Store `data` as `key`, replacing the previous object.
+    pub async fn put(&self, key: &str, data: impl Into<Bytes>) -> io::Result<()> {
+        let request = Request::Put {
+            key: key.into(),
+            data: data.into(),
+        };
+        self.call(request).await.map(|_| ())
+    }
+
+    /// Get the object stored as `key`, if there is one.
+    pub async fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        match self.call(Request::Get { key: key.into() }).await? {
+            Response::Object(data) => Ok(data),
+            _ => unreachable!("unexpected object store response"),
+        }
+    }
+
+    /// Delete the object stored as `key`. Deleting an object that does not exist succeeds.
+    pub async fn delete(&self, key: &str) -> io::Result<()> {
+        self.call(Request::Delete { key: key.into() })
+            .await
+            .map(|_| ())
+    }
+
+    /// The keys of the objects starting with `prefix`, in lexicographic order.
+    pub async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let request = Request::List {
+            prefix: prefix.into(),
+        };
+        match self.call(request).await? {
+            Response::Keys(keys) => Ok(keys),
+            _ => unreachable!("unexpected object store response"),
+        }
+    }
+
+    async fn call(&self, request: Request) -> io::Result<Response> {
+        let response = rpc::call(
+            &self.ep,
+            self.server,
+            REQUEST_TAG,
+            request,
+            self.timeout,
+            "object store request",
+        );
+        match response.await? {
+            Response::ServerError => Err(io::Error::other("object store server error (503)")),
+            response => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{net::NetSim, plugin::simulator, runtime::Runtime, time::sleep};
+
+    #[test]
+    fn object_store() {
+        let runtime = Runtime::new();
+        let store = ObjectStore::start(runtime.handle(), "10.0.0.200".parse().unwrap());
+        let addr = store.addr();
+        let node = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let id = node.id();
+
+        store.set_read_delay(LatencyDistribution::Constant(Duration::from_secs(1)));
+        store.set_list_delay(LatencyDistribution::Constant(Duration::from_secs(5)));
+        let store_ = store.clone();
+        let f = node.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let client = ObjectStoreClient::new(addr).await.unwrap();
+            client.put("a/1", b"one".to_vec()).await.unwrap();
+            client.put("b/1", b"other".to_vec()).await.unwrap();
+            // the write is not visible yet
+            assert_eq!(client.get("a/1").await.unwrap(), None);
+            assert_eq!(store_.latest("a/1").unwrap(), &b"one"[..]);
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(client.get("a/1").await.unwrap().unwrap(), &b"one"[..]);
+            // an overwrite reads as the previous version until it is visible
+            client.put("a/1", b"two".to_vec()).await.unwrap();
+            assert_eq!(client.get("a/1").await.unwrap().unwrap(), &b"one"[..]);
+            assert!(client.list("a/").await.unwrap().is_empty());
+            sleep(Duration::from_secs(5)).await;
+            assert_eq!(client.get("a/1").await.unwrap().unwrap(), &b"two"[..]);
+            assert_eq!(client.list("a/").await.unwrap(), ["a/1"]);
+
+            client.delete("a/1").await.unwrap();
+            assert!(client.get("a/1").await.unwrap().is_some());
+            sleep(Duration::from_secs(5)).await;
+            assert_eq!(client.get("a/1").await.unwrap(), None);
+            assert_eq!(client.list("").await.unwrap(), ["b/1"]);
+
+            store_.set_error_rate(1.0);
+            let e = client.put("c/1", b"lost".to_vec()).await.unwrap_err();
+            assert!(e.to_string().contains("server error"), "{e}");
+            store_.set_error_rate(0.0);
+
+            // the store is unreachable in a partition
+            simulator::<NetSim>().disconnect(id);
+            client.get("b/1").await.unwrap_err();
+        });
+        runtime.block_on(f).unwrap();
+        assert_eq!(store.keys(), ["b/1"]);
+    }
+}
@@ -0,0 +1,268 @@
+//! Liveness probes, to measure how long the network takes to heal.
+//!
+//! A [`LivenessProbe`] pings every pair of a set of nodes over the simulated network, in rounds,
+//! and reports which pairs could reach each other. Tests use it to assert healing times
+//! declaratively, rather than through the symptoms of the system under test:
+//!
+//! ```ignore
+//! let probe = LivenessProbe::new(nodes).interval(Duration::from_millis(500));
+//! net.disconnect2(a, b);
+//! sleep(Duration::from_secs(10)).await;
+//! assert!(!probe.round().await.is_full());
+//! net.connect2(a, b);
+//! let healed = probe.wait_connected(Duration::from_secs(60)).await.unwrap();
+//! assert!(healed < Duration::from_secs(1));
+//! ```
+//!
+//! Each round, every node binds a udp endpoint on the probe port, pings the other nodes, and
+//! answers their pings. A pair is connected when the ping and the reply both went through. Paused
+//! and killed nodes neither ping nor answer.
+
+use super::{network::Payload, Endpoint, NetSim};
+use crate::{
+    plugin::simulator,
+    runtime::NodeHandle,
+    task::NodeId,
+    time::{sleep, timeout, Duration, Instant},
+};
+use futures::future::join_all;
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddr},
+};
+use tracing::*;
+
+/// The port probes are sent to, unless set with [`LivenessProbe::port`].
+pub const PROBE_PORT: u16 = 7946;
+
+const PING_TAG: u64 = 0x9109_0000_0000_0001;
+const PONG_TAG: u64 = 0x9109_0000_0000_0002;
+
+/// Time for all nodes to bind their endpoint before pinging.
+const SETTLE: Duration = Duration::from_millis(1);
+
+/// The outcome of a round of probes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connectivity {
+    nodes: Vec<NodeId>,
+    /// The (pinging, pinged) pairs that got a reply.
+    reached: BTreeSet<(NodeId, NodeId)>,
+}
+
+impl Connectivity {
+    /// The probed nodes.
+    pub fn nodes(&self) -> &[NodeId] {
+        &self.nodes
+    }
+
+    /// Whether a ping from `from` to `to`, and the reply, went through.
+    pub fn reaches(&self, from: NodeId, to: NodeId) -> bool {
+        self.reached.contains(&(from, to))
+    }
+
+    /// The (pinging, pinged) pairs that did not get a reply.
+    pub fn unreachable(&self) -> Vec<(NodeId, NodeId)> {
+        let pairs = self
+            .nodes
+            .iter()
+            .flat_map(|a| self.nodes.iter().map(|b| (*a, *b)));
+        pairs
+            .filter(|(a, b)| a != b && !self.reached.contains(&(*a, *b)))
+            .collect()
+    }
+
+    /// Whether every node reaches every other node.
+    pub fn is_full(&self) -> bool {
+        self.unreachable().is_empty()
+    }
+}
+
+/// Pings between nodes, see the [module](self) documentation.
+pub struct LivenessProbe {
+    nodes: Vec<NodeHandle>,
+    interval: Duration,
+    timeout: Duration,
+    port: u16,
+}
+
+impl LivenessProbe {
+    /// Probe the connectivity between `nodes`.
+    pub fn new(nodes: impl IntoIterator<Item = NodeHandle>) -> Self {
+        LivenessProbe {
+            nodes: nodes.into_iter().collect(),
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_millis(200),
+            port: PROBE_PORT,
+        }
+    }
+
+    /// Start a round every `interval` while waiting. 1 second by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Wait `timeout` for replies to pings. 200ms by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send probes to `port`, which must be free on all nodes.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Run a round of probes.
+    pub async fn round(&self) -> Connectivity {
+        let net = simulator::<NetSim>();
+        let peers: Vec<_> = (self.nodes.iter())
+            .filter_map(|node| {
+                let ip = net.get_ip(node.id())?;
+                Some((node.id(), SocketAddr::new(ip, self.port)))
+            })
+            .collect();
+        let rounds = self.nodes.iter().map(|node| {
+            let (id, peers) = (node.id(), peers.clone());
+            let task = node.spawn(probe(id, self.port, peers, self.timeout));
+            // a paused node never completes its round.
+            async move {
+                let replies = timeout(SETTLE + self.timeout * 2, task).await;
+                let replies = replies.ok().and_then(Result::ok).unwrap_or_default();
+                replies.into_iter().map(move |to| (id, to))
+            }
+        });
+        let reached = join_all(rounds).await.into_iter().flatten().collect();
+        Connectivity {
+            nodes: self.nodes.iter().map(|node| node.id()).collect(),
+            reached,
+        }
+    }
+
+    /// Run rounds until one satisfies `predicate`, for at most `within`.
+    ///
+    /// Returns the time from the call to the start of the first satisfying round, or `None` if
+    /// no round did. The time is accurate to the interval between rounds.
+    pub async fn wait_until(
+        &self,
+        within: Duration,
+        predicate: impl Fn(&Connectivity) -> bool,
+    ) -> Option<Duration> {
+        let start = Instant::now();
+        loop {
+            let round = Instant::now();
+            if round - start > within {
+                return None;
+            }
+            let connectivity = self.round().await;
+            if predicate(&connectivity) {
+                return Some(round - start);
+            }
+            trace!("unreachable: {:?}", connectivity.unreachable());
+            sleep((round + self.interval).saturating_duration_since(Instant::now())).await;
+        }
+    }
+
+    /// Run rounds until every node reaches every other node, for at most `within`, see
+    /// [`LivenessProbe::wait_until`].
+    pub async fn wait_connected(&self, within: Duration) -> Option<Duration> {
+        self.wait_until(within, Connectivity::is_full).await
+    }
+}
+
+/// Ping `peers` from node `id`, and answer their pings. Returns the peers that replied.
+async fn probe(
+    id: NodeId,
+    port: u16,
+    peers: Vec<(NodeId, SocketAddr)>,
+    wait: Duration,
+) -> Vec<NodeId> {
+    let ep = match Endpoint::bind(libc::SOCK_DGRAM, (Ipv4Addr::UNSPECIFIED, port)).await {
+        Ok(ep) => ep,
+        Err(e) => {
+            warn!("probe of node {id} cannot bind port {port}: {e}");
+            return vec![];
+        }
+    };
+    let replied = RefCell::new(vec![]);
+    let answer = async {
+        loop {
+            let Ok((_, from)) = ep.recv_from_raw(PING_TAG).await else {
+                return;
+            };
+            let _ = ep
+                .send_to_raw(from, PONG_TAG, Payload::new_udp(Box::new(())))
+                .await;
+        }
+    };
+    let ping = async {
+        sleep(SETTLE).await;
+        for (peer, addr) in &peers {
+            if *peer != id {
+                let _ = ep
+                    .send_to_raw(*addr, PING_TAG, Payload::new_udp(Box::new(())))
+                    .await;
+            }
+        }
+        loop {
+            let Ok((_, from)) = ep.recv_from_raw(PONG_TAG).await else {
+                return;
+            };
+            let peer = peers.iter().find(|(_, addr)| addr.ip() == from.ip());
+            replied.borrow_mut().extend(peer.map(|(peer, _)| *peer));
+        }
+    };
+    let _ = timeout(SETTLE + wait, futures::future::join(answer, ping)).await;
+    replied.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn heal() {
+        let runtime = Runtime::new();
+        let nodes: Vec<_> = (1..=3)
+            .map(|i| {
+                runtime
+                    .create_node()
+                    .ip(format!("10.0.0.{i}").parse().unwrap())
+                    .build()
+            })
+            .collect();
+        let (a, b, c) = (nodes[0].id(), nodes[1].id(), nodes[2].id());
+        let probe = LivenessProbe::new(nodes).interval(Duration::from_millis(500));
+
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            assert!(probe.round().await.is_full());
+
+            net.disconnect2(a, b);
+            let connectivity = probe.round().await;
+            assert_eq!(connectivity.unreachable(), [(a, b), (b, a)]);
+            assert!(connectivity.reaches(a, c) && connectivity.reaches(c, b));
+
+            crate::task::spawn(async move {
+                sleep(Duration::from_secs(5)).await;
+                simulator::<NetSim>().connect2(a, b);
+            });
+            let healed = probe.wait_connected(Duration::from_secs(30)).await.unwrap();
+            assert!(
+                (Duration::from_secs(5)..Duration::from_millis(5500)).contains(&healed),
+                "{healed:?}"
+            );
+
+            net.disconnect(c);
+            let isolated = |conn: &Connectivity| conn.reaches(a, b) && !conn.reaches(a, c);
+            assert_eq!(
+                probe.wait_until(Duration::ZERO, isolated).await,
+                Some(Duration::ZERO)
+            );
+            assert_eq!(probe.wait_connected(Duration::from_secs(2)).await, None);
+        });
+    }
+}
@@ -0,0 +1,388 @@
+//! A simulated relay, in the spirit of TURN.
+//!
+//! Peers that cannot reach each other directly, e.g. because of NAT, fall back to exchanging
+//! datagrams through a relay. [`RelayServer`] forwards datagrams between the peers registered
+//! with it, and adds its own latency and loss on top of the simulated network. [`RelayClient`]
+//! registers a peer and sends and receives datagrams through the relay.
+//!
+//! Partition the peers from each other, e.g. with [`NetSim::disconnect2`], to exercise the
+//! fallback path of the system under test.
+//!
+//! [`NetSim::disconnect2`]: super::NetSim::disconnect2
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::net::relay::{RelayClient, RelayServer};
+//!
+//! let relay = RelayServer::start(&handle, "10.0.0.100".parse().unwrap());
+//! relay.set_latency(Duration::from_millis(20));
+//! let addr = relay.addr();
+//!
+//! // on a node
+//! let client = RelayClient::register(addr, "alice").await?;
+//! client.send_to("bob", 1, b"hello".to_vec()).await?;
+//! let (from, data) = client.recv(1).await?;
+//! ```
+
+use super::{network::Payload, rpc, Endpoint};
+use crate::{
+    rand::{thread_rng, Rng},
+    runtime::Handle,
+    task::spawn,
+    time::{sleep, Duration},
+};
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tracing::*;
+
+/// The port the relay listens on.
+pub const RELAY_PORT: u16 = 3478;
+
+/// Tag of registrations sent to the relay.
+const REGISTER_TAG: u64 = 0x7e1a_0000_0000_0000;
+/// Tag of datagrams sent to the relay for forwarding.
+const FORWARD_TAG: u64 = 0x7e1a_0000_0000_0001;
+
+/// A registration, acknowledged with `()`.
+#[derive(Debug)]
+struct Register {
+    name: String,
+}
+
+/// A datagram to forward to a peer.
+#[derive(Debug)]
+struct Forward {
+    to: String,
+    tag: u64,
+    data: Bytes,
+}
+
+/// A datagram forwarded by the relay, received with its original tag.
+#[derive(Debug)]
+struct Relayed {
+    from: String,
+    data: Bytes,
+}
+
+/// Counters of a relay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    /// Datagrams forwarded to their destination.
+    pub forwarded: u64,
+    /// Datagrams dropped by the loss of the relay.
+    pub dropped: u64,
+    /// Datagrams addressed to a peer that is not registered.
+    pub unknown_peer: u64,
+    /// Datagrams the relay could not send, e.g. because the peer is disconnected.
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<String, SocketAddr>,
+    /// The name of the peer registered at each address.
+    names: HashMap<SocketAddr, String>,
+    latency: Duration,
+    loss_rate: f64,
+    stats: RelayStats,
+}
+
+/// The relay server.
+///
+/// The registered peers and the settings are shared by all clones of the server and survive
+/// restarts of the node it runs on.
+#[derive(Clone)]
+pub struct RelayServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl RelayServer {
+    /// Create a relay listening on `ip`. Call [`RelayServer::serve`] on the node owning the ip to
+    /// start relaying.
+    pub fn new(ip: IpAddr) -> Self {
+        RelayServer {
+            addr: SocketAddr::new(ip, RELAY_PORT),
+            state: Default::default(),
+        }
+    }
+
+    /// Create a node named "relay" with the given ip, running the relay.
+    pub fn start(handle: &Handle, ip: IpAddr) -> Self {
+        let server = Self::new(ip);
+        let server_ = server.clone();
+        handle
+            .create_node()
+            .name("relay")
+            .ip(ip)
+            .init(move || {
+                let server = server_.clone();
+                async move {
+                    if let Err(e) = server.serve().await {
+                        error!("relay failed: {e}");
+                    }
+                }
+            })
+            .build();
+        server
+    }
+
+    /// The address of the relay.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Delay every forwarded datagram by `latency`, in addition to the latency of the network.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Drop forwarded datagrams with probability `rate`, in addition to the loss of the network.
+    pub fn set_loss_rate(&self, rate: f64) {
+        assert!((0.0..=1.0).contains(&rate), "invalid loss rate: {rate}");
+        self.state.lock().unwrap().loss_rate = rate;
+    }
+
+    /// The address a peer registered from.
+    pub fn peer(&self, name: &str) -> Option<SocketAddr> {
+        self.state.lock().unwrap().peers.get(name).copied()
+    }
+
+    /// The counters of the relay.
+    pub fn stats(&self) -> RelayStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Relay datagrams until the node is killed.
+    pub async fn serve(&self) -> io::Result<()> {
+        let ep = Arc::new(Endpoint::bind(libc::SOCK_DGRAM, self.addr).await?);
+        let ep_ = ep.clone();
+        let state = self.state.clone();
+        spawn(async move {
+            let _ = rpc::serve(
+                &ep_,
+                REGISTER_TAG,
+                "relay registration",
+                |from, register| {
+                    let Register { name } = register;
+                    debug!("relay registration of {name} at {from}");
+                    let mut state = state.lock().unwrap();
+                    if let Some(old) = state.peers.insert(name.clone(), from) {
+                        state.names.remove(&old);
+                    }
+                    state.names.insert(from, name);
+                },
+            )
+            .await;
+        });
+
+        loop {
+            let (payload, from) = ep.recv_from_raw(FORWARD_TAG).await?;
+            let forward = match payload.downcast::<Forward>() {
+                Ok(forward) => forward,
+                Err(e) => {
+                    warn!("invalid relay datagram from {from}: {e}");
+                    continue;
+                }
+            };
+            let (dst, sender, latency) = {
+                let mut state = self.state.lock().unwrap();
+                let sender = state.names.get(&from).cloned();
+                let (Some(dst), Some(sender)) = (state.peers.get(&forward.to).copied(), sender)
+                else {
+                    trace!("relay datagram from {from} to unknown peer {}", forward.to);
+                    state.stats.unknown_peer += 1;
+                    continue;
+                };
+                let loss_rate = state.loss_rate;
+                if thread_rng().gen_bool(loss_rate) {
+                    trace!("relay dropped datagram from {sender} to {}", forward.to);
+                    state.stats.dropped += 1;
+                    continue;
+                }
+                (dst, sender, state.latency)
+            };
+            let (ep, state) = (ep.clone(), self.state.clone());
+            spawn(async move {
+                sleep(latency).await;
+                let relayed = Relayed {
+                    from: sender,
+                    data: forward.data,
+                };
+                let sent = ep
+                    .send_to_raw(dst, forward.tag, Payload::new_udp(Box::new(relayed)))
+                    .await;
+                let mut state = state.lock().unwrap();
+                match sent {
+                    Ok(()) => state.stats.forwarded += 1,
+                    Err(e) => {
+                        trace!("relay failed to forward a datagram to {dst}: {e}");
+                        state.stats.failed += 1;
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// A peer registered with a relay.
+pub struct RelayClient {
+    ep: Endpoint,
+    server: SocketAddr,
+    name: String,
+}
+
+impl RelayClient {
+    /// Register with the relay at `server` as `name`, from a new endpoint.
+    ///
+    /// A peer that registers again under the same name replaces the previous registration.
+    /// Returns a `TimedOut` error if the relay does not acknowledge within one second.
+    pub async fn register(server: SocketAddr, name: &str) -> io::Result<Self> {
+        let ep = Endpoint::bind(libc::SOCK_DGRAM, "0.0.0.0:0").await?;
+        let register = Register { name: name.into() };
+        let wait = Duration::from_secs(1);
+        let () = rpc::call(
+            &ep,
+            server,
+            REGISTER_TAG,
+            register,
+            wait,
+            "relay registration",
+        )
+        .await?;
+        Ok(RelayClient {
+            ep,
+            server,
+            name: name.into(),
+        })
+    }
+
+    /// The name this peer registered as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The local address of this peer.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.ep.local_addr()
+    }
+
+    /// Send `data` with `tag` to the peer registered as `to`, through the relay.
+    ///
+    /// Like any datagram, it may be lost on the way, or dropped by the relay.
+    pub async fn send_to(&self, to: &str, tag: u64, data: impl Into<Bytes>) -> io::Result<()> {
+        let forward = Forward {
+            to: to.into(),
+            tag,
+            data: data.into(),
+        };
+        self.ep
+            .send_to_raw(
+                self.server,
+                FORWARD_TAG,
+                Payload::new_udp(Box::new(forward)),
+            )
+            .await
+    }
+
+    /// Receive a datagram with `tag` through the relay. Returns the name of the sender and the
+    /// data.
+    pub async fn recv(&self, tag: u64) -> io::Result<(String, Bytes)> {
+        let (payload, _) = self.ep.recv_from_raw(tag).await?;
+        let relayed = payload.downcast::<Relayed>()?;
+        Ok((relayed.from, relayed.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::NetSim,
+        plugin::simulator,
+        runtime::Runtime,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn relay() {
+        let runtime = Runtime::new();
+        let relay = RelayServer::start(runtime.handle(), "10.0.0.100".parse().unwrap());
+        relay.set_latency(Duration::from_secs(1));
+        let addr = relay.addr();
+        let alice = runtime
+            .create_node()
+            .ip("10.0.0.1".parse().unwrap())
+            .build();
+        let bob = runtime
+            .create_node()
+            .ip("10.0.0.2".parse().unwrap())
+            .build();
+        let (alice_id, bob_id) = (alice.id(), bob.id());
+
+        let f = bob.spawn(async move {
+            sleep(Duration::from_millis(1)).await;
+            let client = RelayClient::register(addr, "bob").await.unwrap();
+            let start = Instant::now();
+            let (from, data) = client.recv(1).await.unwrap();
+            assert_eq!((from.as_str(), &data[..]), ("alice", &b"hello"[..]));
+            assert!(start.elapsed() >= Duration::from_secs(1));
+            client.send_to("alice", 2, b"hi".to_vec()).await.unwrap();
+        });
+        let g = alice.spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            let client = RelayClient::register(addr, "alice").await.unwrap();
+            // alice and bob cannot reach each other, only the relay
+            client.send_to("bob", 1, b"hello".to_vec()).await.unwrap();
+            client.send_to("carol", 1, b"hello".to_vec()).await.unwrap();
+            let (from, data) = client.recv(2).await.unwrap();
+            assert_eq!((from.as_str(), &data[..]), ("bob", &b"hi"[..]));
+        });
+
+        runtime.block_on(async move {
+            simulator::<NetSim>().disconnect2(alice_id, bob_id);
+            f.await.unwrap();
+            g.await.unwrap();
+        });
+        assert!(relay.peer("alice").is_some());
+        assert_eq!(
+            relay.stats(),
+            RelayStats {
+                forwarded: 2,
+                dropped: 0,
+                unknown_peer: 1,
+                failed: 0,
+            }
+        );
+
+        // a lossy relay drops everything
+        relay.set_loss_rate(1.0);
+        let f = alice.spawn(async move {
+            let client = RelayClient::register(addr, "alice").await.unwrap();
+            client.send_to("bob", 1, b"lost".to_vec()).await.unwrap();
+            sleep(Duration::from_secs(2)).await;
+        });
+        runtime.block_on(f).unwrap();
+        assert_eq!(relay.stats().dropped, 1);
+
+        // datagrams to a disconnected peer are not forwarded
+        relay.set_loss_rate(0.0);
+        let f = alice.spawn(async move {
+            simulator::<NetSim>().disconnect(bob_id);
+            let client = RelayClient::register(addr, "alice").await.unwrap();
+            client
+                .send_to("bob", 1, b"unreachable".to_vec())
+                .await
+                .unwrap();
+            sleep(Duration::from_secs(2)).await;
+        });
+        runtime.block_on(f).unwrap();
+        assert_eq!((relay.stats().forwarded, relay.stats().failed), (2, 1));
+    }
+}
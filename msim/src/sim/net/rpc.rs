@@ -0,0 +1,76 @@
+//! Requests and replies over the simulated network, shared by the simulated services.
+//!
+//! A client sends each request to the server with the request tag of the service, along with a
+//! random tag for the reply. Late replies to timed out requests are dropped when they arrive.
+//! Reply tags are drawn from their own random stream, so that services do not consume the
+//! randomness of the application.
+
+use super::{network::Payload, Endpoint, NetSim};
+use crate::{
+    plugin::simulator,
+    rand::Rng,
+    time::{timeout, Duration},
+};
+use std::{any::Any, fmt::Debug, io, net::SocketAddr};
+use tracing::*;
+
+/// Send `request` with `request_tag` to `server`, and wait up to `wait` for the reply.
+///
+/// `what` names the requests in errors, e.g. "discovery request".
+pub(crate) async fn call<Req, Resp>(
+    ep: &Endpoint,
+    server: SocketAddr,
+    request_tag: u64,
+    request: Req,
+    wait: Duration,
+    what: &str,
+) -> io::Result<Resp>
+where
+    Req: Send + Sync + 'static,
+    Resp: Any,
+{
+    let reply_tag = simulator::<NetSim>().rpc_rand.clone().gen::<u64>() & !request_tag;
+    let payload = Payload::new_udp(Box::new((reply_tag, request)));
+    ep.send_to_raw(server, request_tag, payload).await?;
+    let Ok(reply) = timeout(wait, ep.recv_from_raw(reply_tag)).await else {
+        ep.discard_raw(reply_tag);
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{what} timed out"),
+        ));
+    };
+    let (payload, _) = reply?;
+    payload.downcast::<Resp>()
+}
+
+/// Answer the requests sent with `request_tag` to `ep` with the reply of `handle`, until the
+/// endpoint fails.
+///
+/// Requests that are not a `Req` are logged and ignored.
+pub(crate) async fn serve<Req, Resp>(
+    ep: &Endpoint,
+    request_tag: u64,
+    what: &str,
+    mut handle: impl FnMut(SocketAddr, Req) -> Resp,
+) -> io::Result<()>
+where
+    Req: Debug + Any,
+    Resp: Send + Sync + 'static,
+{
+    loop {
+        let (payload, from) = ep.recv_from_raw(request_tag).await?;
+        let (reply_tag, request) = match payload.downcast::<(u64, Req)>() {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("invalid {what} from {from}: {e}");
+                continue;
+            }
+        };
+        trace!("{what} from {from}: {request:?}");
+        let response = handle(from, request);
+        // the client may be gone, or the network may be down.
+        let _ = ep
+            .send_to_raw(from, reply_tag, Payload::new_udp(Box::new(response)))
+            .await;
+    }
+}
@@ -0,0 +1,347 @@
+//! Round trip times and loss rates measured in real deployments.
+//!
+//! An [`RttMatrix`] holds the round trip time and loss rate between named locations, such as
+//! the regions of a cloud provider, loaded from the output of a measurement tool instead of
+//! being transcribed by hand. [`NetSim::apply_rtt_matrix`] applies it to the nodes labeled
+//! with those locations:
+//!
+//! ```ignore
+//! let matrix = RttMatrix::load("tests/data/aws-inter-region.csv")?.jitter(0.1);
+//! net.apply_rtt_matrix(&matrix, "region");
+//! ```
+//!
+//! CSV files have one row per pair of locations, with the columns `from`, `to`, `rtt_ms` and
+//! an optional `loss`. A header row may name the columns in any order; `src`, `dst` and `rtt`
+//! are accepted as well. JSON files hold an array of objects with the same fields.
+
+use super::{config::check_probability, LatencyDistribution, NetSim};
+use crate::{runtime::Handle, sim::utils::off_thread};
+use serde::Deserialize;
+use std::{fs, io, path::Path, time::Duration};
+
+/// The measured round trip time and loss rate between two locations.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RttEntry {
+    /// The location packets are sent from.
+    #[serde(alias = "src")]
+    pub from: String,
+    /// The location packets are sent to.
+    #[serde(alias = "dst")]
+    pub to: String,
+    /// The round trip time, in milliseconds.
+    #[serde(alias = "rtt")]
+    pub rtt_ms: f64,
+    /// The probability of losing a packet in each direction.
+    #[serde(default)]
+    pub loss: f64,
+}
+
+impl RttEntry {
+    /// Returns an `InvalidData` error if the round trip time is negative or not finite, or if
+    /// the loss is not a probability.
+    fn validate(&self) -> io::Result<()> {
+        if !(self.rtt_ms.is_finite() && self.rtt_ms >= 0.0) {
+            return Err(invalid(format!("invalid rtt_ms: {}", self.rtt_ms)));
+        }
+        check_probability("loss", self.loss)
+    }
+}
+
+/// Round trip times and loss rates between locations, see the [module](self) documentation.
+///
+/// An entry from `a` to `b` also applies from `b` to `a`, unless there is an entry for that
+/// direction.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RttMatrix {
+    entries: Vec<RttEntry>,
+    jitter: f64,
+}
+
+impl RttMatrix {
+    /// Create an empty matrix.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the round trip time and loss rate from `from` to `to`, replacing a previous entry.
+    ///
+    /// Panics if `loss` is not a probability.
+    pub fn insert(&mut self, from: &str, to: &str, rtt: Duration, loss: f64) {
+        let entry = RttEntry {
+            from: from.into(),
+            to: to.into(),
+            rtt_ms: rtt.as_secs_f64() * 1000.0,
+            loss,
+        };
+        entry.validate().unwrap_or_else(|e| panic!("{e}"));
+        self.entries.retain(|e| !(e.from == from && e.to == to));
+        self.entries.push(entry);
+    }
+
+    /// Vary the latency of each packet uniformly by up to `fraction` of the mean, in both
+    /// directions. Zero by default.
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        assert!((0.0..1.0).contains(&fraction), "jitter must be in [0, 1)");
+        self.jitter = fraction;
+        self
+    }
+
+    /// Get the entry from `from` to `to`, or from `to` to `from` if there is none.
+    pub fn get(&self, from: &str, to: &str) -> Option<&RttEntry> {
+        let find =
+            |from: &str, to: &str| self.entries.iter().find(|e| e.from == from && e.to == to);
+        find(from, to).or_else(|| find(to, from))
+    }
+
+    /// The entries, in the order they were added.
+    pub fn entries(&self) -> &[RttEntry] {
+        &self.entries
+    }
+
+    /// The distribution of the one way latency from `from` to `to`: half the round trip time,
+    /// varied by the jitter.
+    pub fn latency(&self, from: &str, to: &str) -> Option<LatencyDistribution> {
+        let one_way = Duration::from_secs_f64(self.get(from, to)?.rtt_ms / 2000.0);
+        if self.jitter == 0.0 || one_way.is_zero() {
+            return Some(LatencyDistribution::Constant(one_way));
+        }
+        Some(LatencyDistribution::uniform(
+            one_way.mul_f64(1.0 - self.jitter)..one_way.mul_f64(1.0 + self.jitter),
+        ))
+    }
+
+    /// Parse a matrix from CSV.
+    pub fn from_csv(csv: &str) -> io::Result<Self> {
+        let mut rows = csv
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| {
+                let fields = line
+                    .split(',')
+                    .map(|f| f.trim().trim_matches('"'))
+                    .collect();
+                (i, fields)
+            })
+            .peekable();
+        // the positions of from, to, rtt_ms and loss
+        let mut columns = [Some(0), Some(1), Some(2), Some(3)];
+        if let Some((_, header)) = rows.peek() {
+            let header: &Vec<&str> = header;
+            if header.get(2).is_some_and(|f| f.parse::<f64>().is_err()) {
+                let find = |names: &[&str]| {
+                    header
+                        .iter()
+                        .position(|f| names.contains(&f.to_ascii_lowercase().as_str()))
+                };
+                columns = [
+                    find(&["from", "src"]),
+                    find(&["to", "dst"]),
+                    find(&["rtt_ms", "rtt"]),
+                    find(&["loss"]),
+                ];
+                if columns[..3].iter().any(Option::is_none) {
+                    return Err(invalid(format!(
+                        "missing from, to or rtt_ms column in {header:?}"
+                    )));
+                }
+                rows.next();
+            }
+        }
+        let mut matrix = RttMatrix::new();
+        for (line, fields) in rows {
+            let field = |column: Option<usize>| column.and_then(|c| fields.get(c).copied());
+            let number = |column: Option<usize>, name: &str| -> io::Result<Option<f64>> {
+                field(column)
+                    .filter(|f| !f.is_empty())
+                    .map(|f| f.parse())
+                    .transpose()
+                    .map_err(|_| invalid(format!("invalid {name} on line {line}")))
+            };
+            let (Some(from), Some(to), Some(rtt_ms)) = (
+                field(columns[0]),
+                field(columns[1]),
+                number(columns[2], "rtt_ms")?,
+            ) else {
+                return Err(invalid(format!("missing fields on line {line}")));
+            };
+            let entry = RttEntry {
+                from: from.into(),
+                to: to.into(),
+                rtt_ms,
+                loss: number(columns[3], "loss")?.unwrap_or(0.0),
+            };
+            entry
+                .validate()
+                .map_err(|e| invalid(format!("{e} on line {line}")))?;
+            matrix.entries.push(entry);
+        }
+        Ok(matrix)
+    }
+
+    /// Parse a matrix from a JSON array of entries.
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        let entries: Vec<RttEntry> =
+            serde_json::from_str(json).map_err(|e| invalid(e.to_string()))?;
+        for (i, entry) in entries.iter().enumerate() {
+            entry
+                .validate()
+                .map_err(|e| invalid(format!("{e} in entry {i}")))?;
+        }
+        Ok(RttMatrix {
+            entries,
+            jitter: 0.0,
+        })
+    }
+
+    /// Load a matrix from a `.json` file, or from a CSV file otherwise.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = off_thread(|| fs::read_to_string(path))?;
+        let matrix = match path.extension() {
+            Some(ext) if ext == "json" => Self::from_json(&contents),
+            _ => Self::from_csv(&contents),
+        };
+        matrix.map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl NetSim {
+    /// Set the latency and packet loss between the nodes labeled with `label`, from the entries
+    /// of `matrix` between their values.
+    ///
+    /// The settings are applied as [one way overrides](Self::set_one_way_latency) to the nodes
+    /// existing now; nodes created later are not affected. Pairs of locations without an entry
+    /// keep their current settings. Returns the number of links that were set.
+    pub fn apply_rtt_matrix(&self, matrix: &RttMatrix, label: &str) -> usize {
+        let nodes: Vec<_> = Handle::current()
+            .nodes()
+            .filter_map(|node| Some((node.id, node.label(label)?.to_string())))
+            .collect();
+        let mut links = 0;
+        for (src, from) in &nodes {
+            for (dst, to) in &nodes {
+                if src == dst {
+                    continue;
+                }
+                let Some(latency) = matrix.latency(from, to) else {
+                    continue;
+                };
+                let loss = matrix.get(from, to).unwrap().loss;
+                self.set_one_way_latency(*src, *dst, Some(latency));
+                self.set_one_way_packet_loss(*src, *dst, Some(loss))
+                    .expect("the loss of the entries is validated");
+                links += 1;
+            }
+        }
+        links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{network::Payload, Endpoint},
+        plugin::simulator,
+        runtime::Runtime,
+        time::{sleep, Instant},
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn parse() {
+        let csv = "\
+# measured with ping
+src,dst,loss,rtt
+us-east,eu-west,0.01,80
+us-east,ap-south,,200.5
+";
+        let matrix = RttMatrix::from_csv(csv).unwrap();
+        assert_eq!(matrix.entries().len(), 2);
+        let entry = matrix.get("eu-west", "us-east").unwrap();
+        assert_eq!((entry.rtt_ms, entry.loss), (80.0, 0.01));
+        assert_eq!(
+            matrix.latency("ap-south", "us-east"),
+            Some(LatencyDistribution::Constant(Duration::from_micros(100250)))
+        );
+        assert_eq!(matrix.get("eu-west", "ap-south"), None);
+
+        let headerless = RttMatrix::from_csv("us-east,eu-west,80,0.01\n").unwrap();
+        assert_eq!(headerless.entries(), &matrix.entries()[..1]);
+
+        let json = r#"[{"from": "us-east", "to": "eu-west", "rtt_ms": 80, "loss": 0.01},
+                      {"src": "us-east", "dst": "ap-south", "rtt": 200.5}]"#;
+        assert_eq!(RttMatrix::from_json(json).unwrap(), matrix);
+
+        let e = RttMatrix::from_csv("a,b,fast\n").unwrap_err();
+        assert!(e.to_string().contains("missing from, to or rtt_ms"));
+        let e = RttMatrix::from_csv("from,to,rtt_ms\na,b,1\na,b,x\n").unwrap_err();
+        assert!(e.to_string().contains("invalid rtt_ms on line 3"));
+        let e = RttMatrix::from_csv("a,b,10,1.5\n").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("invalid loss: 1.5"), "{e}");
+        let e = RttMatrix::from_json(r#"[{"from": "a", "to": "b", "rtt_ms": -1}]"#).unwrap_err();
+        assert!(
+            e.to_string().contains("invalid rtt_ms: -1 in entry 0"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn apply() {
+        let runtime = Runtime::new();
+        let mut matrix = RttMatrix::new();
+        matrix.insert("us", "eu", Duration::from_millis(80), 0.0);
+        matrix.insert("eu", "eu", Duration::from_millis(2), 0.0);
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let mut nodes = vec![];
+        for (addr, region) in addrs.iter().zip(["us", "eu", "eu"]) {
+            let node = runtime
+                .create_node()
+                .ip(addr.ip())
+                .label("region", region)
+                .build();
+            nodes.push(node);
+        }
+
+        let server = addrs[1];
+        nodes[1].spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, server).await.unwrap();
+            loop {
+                let (_, from) = net.recv_from_raw(1).await.unwrap();
+                net.send_to(from, 2, Payload::udp(vec![])).await.unwrap();
+            }
+        });
+        let mut clients = vec![];
+        for (node, addr) in [(&nodes[0], addrs[0]), (&nodes[2], addrs[2])] {
+            clients.push(node.spawn(async move {
+                let net = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                sleep(Duration::from_millis(1)).await;
+                let start = Instant::now();
+                net.send_to(server, 1, Payload::udp(vec![])).await.unwrap();
+                net.recv_from_raw(2).await.unwrap();
+                start.elapsed().as_secs_f64() * 1000.0
+            }));
+        }
+
+        runtime.block_on(async move {
+            // us-eu in both directions, and eu-eu
+            assert_eq!(simulator::<NetSim>().apply_rtt_matrix(&matrix, "region"), 6);
+            let mut rtts = vec![];
+            for client in clients {
+                rtts.push(client.await.unwrap().round());
+            }
+            assert_eq!(rtts, [80.0, 2.0]);
+        });
+    }
+}
@@ -0,0 +1,512 @@
+//! Streaming of large payloads over the tag API.
+//!
+//! [`Endpoint::send_stream`] splits the contents of a reader into chunks of at most
+//! [`STREAM_CHUNK_SIZE`] bytes and sends them as individual messages, so that moving large blobs
+//! neither needs one contiguous buffer nor arrives in a single instantaneous message. Chunks are
+//! subject to latency and packet loss like any other message; lost chunks are retransmitted, and
+//! at most [`STREAM_WINDOW`] chunks are in flight or waiting to be read at any time.
+//!
+//! On the receiving side, [`Endpoint::recv_stream`] accepts a stream sent to a tag and returns a
+//! [`RecvStream`] yielding the chunks in order. Chunks are acknowledged by a task of the endpoint
+//! as they arrive, so that a slow reader only holds back the sender, and is not mistaken for
+//! packet loss. The task outlives the end of the stream for a while, to acknowledge the
+//! retransmissions of a sender that missed the last acknowledgements.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // sender
+//! ep.send_stream(dst, 1, &mut file).await?;
+//!
+//! // receiver
+//! let mut stream = ep.recv_stream(1).await?;
+//! while let Some(chunk) = stream.chunk().await? {
+//!     file.write_all(&chunk).await?;
+//! }
+//! ```
+
+use super::{network::Payload, Endpoint};
+use crate::{
+    rand::{thread_rng, Rng},
+    time::{timeout, Duration},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{channel::mpsc, StreamExt};
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::*;
+
+/// Maximum number of payload bytes in a chunk.
+pub const STREAM_CHUNK_SIZE: usize = 1400;
+
+/// Maximum number of chunks sent but not yet read by the receiver.
+pub const STREAM_WINDOW: u64 = 64;
+
+/// Initial retransmission timeout. Doubled after every retransmission without progress.
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Number of retransmissions without progress after which the sender gives up.
+const MAX_RETRIES: u32 = 5;
+
+/// Time without receiving any chunk after which the receiver gives up.
+const RECV_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time without receiving any retransmission after which the receiver forgets a finished stream.
+/// Longer than the sender retransmits for.
+const LINGER: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+enum StreamMsg {
+    /// Sent to the stream tag to open a stream.
+    Open { stream_tag: u64, ack_tag: u64 },
+    /// Sent to `stream_tag`. The last chunk is empty and has `fin` set.
+    Chunk { seq: u64, data: Bytes, fin: bool },
+    /// Sent to `stream_tag` while the window is closed, to check that the receiver is still
+    /// there.
+    Probe,
+    /// Sent to `ack_tag`: the receiver has all chunks before `next`, and the sender may send the
+    /// chunks before `window`.
+    Ack { next: u64, window: u64 },
+}
+
+impl StreamMsg {
+    fn into_payload(self) -> Payload {
+        let size = match &self {
+            StreamMsg::Chunk { data, .. } => data.len(),
+            _ => 0,
+        };
+        Payload::new_udp(Box::new(self)).with_size(size)
+    }
+
+    fn from_payload(payload: Payload) -> io::Result<Self> {
+        payload.downcast::<StreamMsg>()
+    }
+}
+
+fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("stream {what} timed out"))
+}
+
+impl Endpoint {
+    /// Send the contents of `reader` as a stream to `dst`, where it is accepted with
+    /// [`Endpoint::recv_stream`] on `tag`.
+    ///
+    /// Returns once the receiver has received the whole stream, or with a `TimedOut` error if
+    /// the receiver stops acknowledging chunks. A receiver that is slow to read holds the sender
+    /// back for as long as it takes.
+    pub async fn send_stream(
+        &self,
+        dst: SocketAddr,
+        tag: u64,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> io::Result<()> {
+        let (stream_tag, ack_tag) = thread_rng().gen::<(u64, u64)>();
+        let open = || {
+            StreamMsg::Open {
+                stream_tag,
+                ack_tag,
+            }
+            .into_payload()
+        };
+
+        // open the stream, retransmitting until the receiver acknowledges it.
+        let mut rto = INITIAL_RTO;
+        let mut retries = 0;
+        self.send_to_raw(dst, tag, open()).await?;
+        while timeout(rto, self.recv_from_raw(ack_tag)).await.is_err() {
+            retries += 1;
+            if retries > MAX_RETRIES {
+                return Err(timed_out("open"));
+            }
+            rto *= 2;
+            self.send_to_raw(dst, tag, open()).await?;
+        }
+
+        // chunks sent but not received yet, starting at `acked`.
+        let mut unacked: VecDeque<(Bytes, bool)> = VecDeque::new();
+        let mut acked = 0;
+        let mut window = STREAM_WINDOW;
+        let (mut eof, mut fin_sent) = (false, false);
+        let mut rto = INITIAL_RTO;
+        let mut retries = 0;
+        loop {
+            while !fin_sent && acked + (unacked.len() as u64) < window {
+                let seq = acked + unacked.len() as u64;
+                if eof {
+                    // the end of the stream is marked by an empty chunk.
+                    unacked.push_back((Bytes::new(), true));
+                    self.send_chunk(dst, stream_tag, seq, Bytes::new(), true)
+                        .await?;
+                    fin_sent = true;
+                    break;
+                }
+                let mut buf = BytesMut::with_capacity(STREAM_CHUNK_SIZE);
+                while !eof && buf.len() < STREAM_CHUNK_SIZE {
+                    let limit = STREAM_CHUNK_SIZE - buf.len();
+                    eof = reader.read_buf(&mut (&mut buf).limit(limit)).await? == 0;
+                }
+                if !buf.is_empty() {
+                    let data = buf.freeze();
+                    unacked.push_back((data.clone(), false));
+                    self.send_chunk(dst, stream_tag, seq, data, false).await?;
+                }
+            }
+            if fin_sent && unacked.is_empty() {
+                return Ok(());
+            }
+
+            // while chunks are in flight, the timer detects their loss. Once the receiver has
+            // all of them, the sender waits for the reader to open the window, and the timer
+            // only checks that the receiver is still there.
+            match timeout(rto, self.recv_from_raw(ack_tag)).await {
+                Ok(res) => {
+                    let (payload, _) = res?;
+                    let StreamMsg::Ack { next, window: w } = StreamMsg::from_payload(payload)?
+                    else {
+                        panic!("unexpected stream message");
+                    };
+                    let progress = next > acked || w > window;
+                    if next > acked {
+                        unacked.drain(..(next - acked) as usize);
+                        acked = next;
+                    }
+                    window = window.max(w);
+                    // an answered probe shows that the receiver is still there.
+                    if progress || unacked.is_empty() {
+                        rto = INITIAL_RTO;
+                        retries = 0;
+                    }
+                }
+                Err(_) => {
+                    retries += 1;
+                    if retries > MAX_RETRIES {
+                        return Err(timed_out("send"));
+                    }
+                    rto *= 2;
+                    if unacked.is_empty() {
+                        self.send_to_raw(dst, stream_tag, StreamMsg::Probe.into_payload())
+                            .await?;
+                        continue;
+                    }
+                    // the receiver discards chunks after a lost one, so retransmit all
+                    // unacknowledged chunks.
+                    debug!("stream {stream_tag:x}: retransmitting from {acked}");
+                    for (seq, (data, fin)) in (acked..).zip(unacked.clone()) {
+                        self.send_chunk(dst, stream_tag, seq, data, fin).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_chunk(
+        &self,
+        dst: SocketAddr,
+        stream_tag: u64,
+        seq: u64,
+        data: Bytes,
+        fin: bool,
+    ) -> io::Result<()> {
+        let chunk = StreamMsg::Chunk { seq, data, fin };
+        self.send_to_raw(dst, stream_tag, chunk.into_payload())
+            .await
+    }
+
+    /// Accept a stream sent to `tag` with [`Endpoint::send_stream`].
+    pub async fn recv_stream(&self, tag: u64) -> io::Result<RecvStream<'_>> {
+        loop {
+            let (payload, from) = self.recv_from_raw(tag).await?;
+            let StreamMsg::Open {
+                stream_tag,
+                ack_tag,
+            } = StreamMsg::from_payload(payload)?
+            else {
+                panic!("unexpected stream message");
+            };
+            let progress = Arc::new(Progress::default());
+            // the sender may be gone, it will retransmit otherwise.
+            let _ = self.send_to_raw(from, ack_tag, progress.ack()).await;
+            let mut streams = self.extras().streams.lock().unwrap();
+            // a retransmitted open for a stream that was already accepted.
+            if streams.contains_key(&stream_tag) {
+                continue;
+            }
+            let (tx, chunks) = mpsc::unbounded();
+            let receiver = Receiver {
+                ep: self.alias(),
+                peer: from,
+                stream_tag,
+                ack_tag,
+                progress: progress.clone(),
+                chunks: tx,
+            };
+            streams.insert(stream_tag, crate::task::spawn(receiver.run()));
+            return Ok(RecvStream {
+                ep: self,
+                peer: from,
+                ack_tag,
+                progress,
+                chunks,
+                done: false,
+            });
+        }
+    }
+}
+
+/// How far a stream was received and read.
+#[derive(Debug, Default)]
+struct Progress {
+    /// The number of chunks received in order.
+    received: AtomicU64,
+    /// The number of chunks read.
+    read: AtomicU64,
+}
+
+impl Progress {
+    fn ack(&self) -> Payload {
+        StreamMsg::Ack {
+            next: self.received.load(Ordering::SeqCst),
+            window: self.read.load(Ordering::SeqCst) + STREAM_WINDOW,
+        }
+        .into_payload()
+    }
+}
+
+/// The task receiving the chunks of a stream, until some time after its end.
+struct Receiver {
+    ep: Endpoint,
+    peer: SocketAddr,
+    stream_tag: u64,
+    ack_tag: u64,
+    progress: Arc<Progress>,
+    /// The chunks in order, with `None` at the end of the stream.
+    chunks: mpsc::UnboundedSender<io::Result<Option<Bytes>>>,
+}
+
+impl Receiver {
+    async fn run(self) {
+        let Receiver { ep, progress, .. } = &self;
+        let mut done = false;
+        loop {
+            let wait = if done { LINGER } else { RECV_TIMEOUT };
+            let msg = match timeout(wait, ep.recv_from_raw(self.stream_tag)).await {
+                Ok(res) => res.and_then(|(payload, _)| StreamMsg::from_payload(payload)),
+                Err(_) if done => break,
+                Err(_) => Err(timed_out("receive")),
+            };
+            // a reader that went away before the end does not need the rest.
+            if !done && self.chunks.is_closed() {
+                break;
+            }
+            match msg {
+                Ok(StreamMsg::Chunk { seq, data, fin }) => {
+                    // chunks after a lost one, or beyond the window, are discarded, and
+                    // retransmitted in order by the sender.
+                    let received = progress.received.load(Ordering::SeqCst);
+                    let window = progress.read.load(Ordering::SeqCst) + STREAM_WINDOW;
+                    if seq == received && seq < window {
+                        progress.received.store(received + 1, Ordering::SeqCst);
+                        let _ = self.chunks.unbounded_send(Ok((!fin).then_some(data)));
+                        done |= fin;
+                    }
+                }
+                Ok(StreamMsg::Probe) => {}
+                Ok(_) => panic!("unexpected stream message"),
+                Err(e) => {
+                    let _ = self.chunks.unbounded_send(Err(e));
+                    break;
+                }
+            }
+            let _ = ep
+                .send_to_raw(self.peer, self.ack_tag, progress.ack())
+                .await;
+        }
+        ep.extras().streams.lock().unwrap().remove(&self.stream_tag);
+    }
+}
+
+/// A stream accepted with [`Endpoint::recv_stream`].
+#[derive(Debug)]
+pub struct RecvStream<'a> {
+    ep: &'a Endpoint,
+    peer: SocketAddr,
+    ack_tag: u64,
+    progress: Arc<Progress>,
+    chunks: mpsc::UnboundedReceiver<io::Result<Option<Bytes>>>,
+    done: bool,
+}
+
+impl RecvStream<'_> {
+    /// The address of the sender.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Read the next chunk. Returns `None` at the end of the stream.
+    pub async fn chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if self.done {
+            return Ok(None);
+        }
+        // the receiver task only stops early when the endpoint is closed.
+        let chunk = self.chunks.next().await;
+        let chunk = chunk.unwrap_or_else(|| Err(timed_out("receive")))?;
+        self.done = chunk.is_none();
+        if !self.done {
+            // reading a chunk opens the window of the sender.
+            self.progress.read.fetch_add(1, Ordering::SeqCst);
+            let ack = self.progress.ack();
+            let _ = self.ep.send_to_raw(self.peer, self.ack_tag, ack).await;
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{net::NetSim, plugin::simulator, runtime::Runtime, time::Instant};
+
+    fn transfer(len: usize, loss: f64) -> Duration {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let expected = data.clone();
+        node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            crate::time::sleep(Duration::from_millis(1)).await;
+            ep.send_stream(addr2, 1, &data[..]).await.unwrap();
+        });
+
+        let f = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let start = Instant::now();
+            let mut stream = ep.recv_stream(1).await.unwrap();
+            assert_eq!(stream.peer_addr(), addr1);
+            let mut received = vec![];
+            while let Some(chunk) = stream.chunk().await.unwrap() {
+                assert!(chunk.len() <= STREAM_CHUNK_SIZE);
+                received.extend_from_slice(&chunk);
+            }
+            assert!(received == expected);
+            start.elapsed()
+        });
+
+        runtime.block_on(async move {
+            simulator::<NetSim>().update_config(|cfg| {
+                cfg.packet_loss.default_packet_loss_rate = loss;
+            });
+            f.await.unwrap()
+        })
+    }
+
+    #[test]
+    fn send_recv_stream() {
+        transfer(0, 0.0);
+        let small = transfer(STREAM_CHUNK_SIZE, 0.0);
+        let large = transfer(1 << 20, 0.0);
+        // a large stream needs many round trips
+        assert!(large > small * 10, "{small:?} {large:?}");
+    }
+
+    #[test]
+    fn lossy_stream() {
+        transfer(100 * STREAM_CHUNK_SIZE + 1, 0.1);
+    }
+
+    #[test]
+    fn slow_reader() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        let data = vec![1u8; 2 * STREAM_WINDOW as usize * STREAM_CHUNK_SIZE];
+        let len = data.len();
+        let sender = node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            crate::time::sleep(Duration::from_millis(1)).await;
+            ep.send_stream(addr2, 1, &data[..]).await
+        });
+        let receiver = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut stream = ep.recv_stream(1).await.unwrap();
+            let mut received = 0;
+            while let Some(chunk) = stream.chunk().await.unwrap() {
+                // much longer than the sender retransmits for without progress.
+                if received == 0 {
+                    crate::time::sleep(Duration::from_secs(300)).await;
+                }
+                received += chunk.len();
+            }
+            received
+        });
+        runtime.block_on(async move {
+            sender.await.unwrap().unwrap();
+            assert_eq!(receiver.await.unwrap(), len);
+        });
+    }
+
+    #[test]
+    fn lost_final_ack() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let id2 = node2.id();
+
+        let sender = node1.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            crate::time::sleep(Duration::from_millis(1)).await;
+            ep.send_stream(addr2, 1, &[1u8; 3 * STREAM_CHUNK_SIZE][..])
+                .await
+        });
+        let receiver = node2.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            let mut stream = ep.recv_stream(1).await.unwrap();
+            while stream.chunk().await.unwrap().is_some() {}
+            drop(stream);
+            // the endpoint still acknowledges the retransmissions of the stream, for a while.
+            assert_eq!(ep.extras().streams.lock().unwrap().len(), 1);
+            crate::time::sleep(LINGER * 2).await;
+            assert!(ep.extras().streams.lock().unwrap().is_empty());
+        });
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            net.set_manual_delivery(true);
+            let start = Instant::now();
+            let mut acks = 0;
+            // let the open through, and lose all acknowledgements of chunks for 5s.
+            while !sender.is_finished() {
+                for msg in net.deliverable() {
+                    if msg.from == id2 && start.elapsed() < Duration::from_secs(5) {
+                        acks += 1;
+                        if acks > 1 {
+                            net.drop_held(&msg);
+                            continue;
+                        }
+                    }
+                    net.deliver(&msg);
+                }
+                crate::time::sleep(Duration::from_millis(1)).await;
+            }
+            assert!(acks > 4, "{acks}");
+            sender.await.unwrap().unwrap();
+            net.set_manual_delivery(false);
+            receiver.await.unwrap();
+        });
+    }
+}
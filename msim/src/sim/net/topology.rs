@@ -0,0 +1,337 @@
+//! Snapshots of the network topology, for visual inspection.
+//!
+//! A [`Topology`] describes the nodes of the network and the links between them: the overrides
+//! set on each link, and the latency and loss observed by the packets that went through it.
+//! It can be rendered as a Graphviz graph with [`Topology::to_dot`], where links are colored
+//! from green to red by their mean latency and dashed when clogged, or as JSON with
+//! [`Topology::to_json`], in the `nodes`/`links` shape used by D3 force layouts:
+//!
+//! ```ignore
+//! sleep_until(start + Duration::from_secs(30)).await;
+//! let topology = simulator::<NetSim>().topology();
+//! std::fs::write("topology.dot", topology.to_dot())?;
+//! ```
+//!
+//! Statistics accumulate from the start of the simulation, so a snapshot taken at a later time
+//! includes everything before it.
+
+use super::{FlowStat, LatencyDistribution, NetSim};
+use crate::{runtime::Handle, task::NodeId};
+use serde_json::json;
+use std::{fmt::Write, net::IpAddr, time::Duration};
+
+/// A node of a [`Topology`].
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyNode {
+    /// The node.
+    pub id: NodeId,
+    /// The name of the node.
+    pub name: String,
+    /// The ip address of the node, if it has one.
+    pub ip: Option<IpAddr>,
+    /// The cluster of the node, see [`NetSim::add_cluster`].
+    pub cluster: Option<String>,
+    /// Whether all traffic of the node is clogged.
+    pub clogged: bool,
+}
+
+/// A one-way link between two nodes of a [`Topology`].
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyLink {
+    /// The sending node.
+    pub src: NodeId,
+    /// The receiving node.
+    pub dst: NodeId,
+    /// Whether the link is clogged, see [`NetSim::disconnect_one_way`].
+    pub clogged: bool,
+    /// The latency override of the link, see [`NetSim::set_one_way_latency`].
+    pub latency: Option<LatencyDistribution>,
+    /// The packet loss override of the link.
+    pub packet_loss: Option<f64>,
+    /// The traffic observed on the link.
+    pub stat: FlowStat,
+}
+
+impl Default for TopologyLink {
+    fn default() -> Self {
+        TopologyLink {
+            src: NodeId::zero(),
+            dst: NodeId::zero(),
+            clogged: false,
+            latency: None,
+            packet_loss: None,
+            stat: FlowStat::default(),
+        }
+    }
+}
+
+impl TopologyLink {
+    /// The fraction of the packets sent on the link that were dropped, or `None` if no packet
+    /// was sent.
+    pub fn loss_rate(&self) -> Option<f64> {
+        let sent = self.stat.packets_sent;
+        (sent > 0).then(|| self.stat.packets_dropped as f64 / sent as f64)
+    }
+}
+
+/// The nodes and links of the network at a point in virtual time, see the [module](self)
+/// documentation.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    /// The virtual time since the start of the simulation when the snapshot was taken.
+    pub elapsed: Duration,
+    /// The nodes, ordered by id.
+    pub nodes: Vec<TopologyNode>,
+    /// The links that have an override or carried traffic, ordered by (src, dst).
+    pub links: Vec<TopologyLink>,
+}
+
+impl Topology {
+    /// Get the link from `src` to `dst`, if it has an override or carried traffic.
+    pub fn link(&self, src: NodeId, dst: NodeId) -> Option<&TopologyLink> {
+        self.links.iter().find(|l| l.src == src && l.dst == dst)
+    }
+
+    /// Render the topology as a Graphviz `digraph`.
+    ///
+    /// Nodes of a cluster are grouped in a subgraph. Links are labeled with their mean latency
+    /// and loss rate, colored from green (fastest) to red (slowest), and their width grows with
+    /// the bytes they carried.
+    pub fn to_dot(&self) -> String {
+        let slowest = self
+            .links
+            .iter()
+            .filter_map(|l| l.stat.mean_latency())
+            .max()
+            .unwrap_or_default();
+        let heaviest = self.links.iter().map(|l| l.stat.bytes_sent).max();
+        let heaviest = heaviest.unwrap_or_default().max(1) as f64;
+
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph topology {{");
+        let _ = writeln!(dot, "  label=\"t={:?}\";", self.elapsed);
+        let _ = writeln!(dot, "  node [shape=box];");
+        let mut clusters: Vec<_> = self
+            .nodes
+            .iter()
+            .filter_map(|n| n.cluster.as_ref())
+            .collect();
+        clusters.sort();
+        clusters.dedup();
+        for cluster in clusters {
+            let _ = writeln!(dot, "  subgraph {:?} {{", format!("cluster_{cluster}"));
+            let _ = writeln!(dot, "    label={cluster:?};");
+            for node in self.nodes.iter() {
+                if node.cluster.as_ref() == Some(cluster) {
+                    let _ = writeln!(dot, "  {}", dot_node(node));
+                }
+            }
+            let _ = writeln!(dot, "  }}");
+        }
+        for node in self.nodes.iter().filter(|n| n.cluster.is_none()) {
+            let _ = writeln!(dot, "{}", dot_node(node));
+        }
+        for link in &self.links {
+            let mut label = vec![];
+            let mut attrs = vec![];
+            if let Some(mean) = link.stat.mean_latency() {
+                label.push(format!("{mean:?}"));
+                let heat = if slowest.is_zero() {
+                    0.0
+                } else {
+                    mean.as_secs_f64() / slowest.as_secs_f64()
+                };
+                attrs.push(format!("color=\"{:.3} 1.000 0.850\"", (1.0 - heat) / 3.0));
+                let width = 1.0 + 4.0 * link.stat.bytes_sent as f64 / heaviest;
+                attrs.push(format!("penwidth={width:.2}"));
+            }
+            if let Some(loss) = link.loss_rate().filter(|&loss| loss > 0.0) {
+                label.push(format!("loss {:.1}%", loss * 100.0));
+            }
+            if link.clogged {
+                label.push("clogged".into());
+                attrs.push("style=dashed".into());
+            }
+            attrs.push(format!("label={:?}", label.join("\n")));
+            let _ = writeln!(
+                dot,
+                "  {} -> {} [{}];",
+                link.src.0,
+                link.dst.0,
+                attrs.join(", ")
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the topology as JSON, with a `nodes` array and a `links` array whose `source` and
+    /// `target` are node ids. Latencies are in milliseconds.
+    pub fn to_json(&self) -> String {
+        let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+        let nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                json!({
+                    "id": node.id.0,
+                    "name": node.name,
+                    "ip": node.ip.map(|ip| ip.to_string()),
+                    "cluster": node.cluster,
+                    "clogged": node.clogged,
+                })
+            })
+            .collect();
+        let links: Vec<_> = self
+            .links
+            .iter()
+            .map(|link| {
+                json!({
+                    "source": link.src.0,
+                    "target": link.dst.0,
+                    "clogged": link.clogged,
+                    "latency_override": link.latency.as_ref().map(|l| format!("{l:?}")),
+                    "packet_loss_override": link.packet_loss,
+                    "packets_sent": link.stat.packets_sent,
+                    "packets_delivered": link.stat.packets_delivered,
+                    "packets_dropped": link.stat.packets_dropped,
+                    "bytes_sent": link.stat.bytes_sent,
+                    "loss_rate": link.loss_rate(),
+                    "mean_latency_ms": ms(link.stat.mean_latency()),
+                    "min_latency_ms": ms(link.stat.min_latency),
+                    "max_latency_ms": ms(link.stat.max_latency),
+                })
+            })
+            .collect();
+        let topology = json!({
+            "elapsed_ms": self.elapsed.as_secs_f64() * 1000.0,
+            "nodes": nodes,
+            "links": links,
+        });
+        serde_json::to_string_pretty(&topology).expect("topology is valid json")
+    }
+}
+
+fn dot_node(node: &TopologyNode) -> String {
+    let mut label = node.name.clone();
+    if let Some(ip) = node.ip {
+        let _ = write!(label, "\n{ip}");
+    }
+    let style = if node.clogged { ", style=dashed" } else { "" };
+    format!("  {} [label={label:?}{style}];", node.id.0)
+}
+
+impl NetSim {
+    /// Take a snapshot of the topology of the network and the traffic observed so far, see the
+    /// [module](self) documentation.
+    pub fn topology(&self) -> Topology {
+        let handle = Handle::current();
+        // describing the nodes locks the network to read their ip.
+        let nodes: Vec<_> = handle.nodes().collect();
+        let network = self.lock_network();
+        let nodes = nodes
+            .into_iter()
+            .filter(|node| network.contains_node(node.id))
+            .map(|node| TopologyNode {
+                id: node.id,
+                clogged: network.is_clogged(node.id),
+                cluster: network.cluster(node.id),
+                name: node.name,
+                ip: node.ip,
+            })
+            .collect();
+        Topology {
+            elapsed: handle.time().time_since_clock_base(),
+            nodes,
+            links: network.links(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{network::Payload, Endpoint},
+        plugin::simulator,
+        runtime::Runtime,
+        time::sleep,
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn topology() {
+        let runtime = Runtime::new();
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|i| format!("10.0.0.{i}:1").parse().unwrap())
+            .collect();
+        let nodes: Vec<_> = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                runtime
+                    .create_node()
+                    .name(format!("n{i}"))
+                    .ip(addr.ip())
+                    .build()
+            })
+            .collect();
+        let ids: Vec<_> = nodes.iter().map(|n| n.id()).collect();
+
+        let (a, b) = (addrs[0], addrs[1]);
+        nodes[1].spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, b).await.unwrap();
+            let mut buf = vec![0; 0x10];
+            loop {
+                ep.recv_from(1, &mut buf).await.unwrap();
+            }
+        });
+        let f = nodes[0].spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, a).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            for _ in 0..4 {
+                ep.send_to(b, 1, Payload::udp(vec![0; 8])).await.unwrap();
+                sleep(Duration::from_millis(100)).await;
+            }
+        });
+
+        runtime.block_on(async move {
+            let net = simulator::<NetSim>();
+            net.set_one_way_latency(
+                ids[0],
+                ids[1],
+                Some(LatencyDistribution::Constant(Duration::from_millis(30))),
+            );
+            net.disconnect_one_way(ids[2], ids[0]);
+            f.await.unwrap();
+
+            let topology = net.topology();
+            assert_eq!(topology.nodes.len(), 3);
+            assert_eq!(topology.nodes[1].name, "n1");
+            assert_eq!(topology.nodes[1].ip, Some(b.ip()));
+            assert_eq!(topology.links.len(), 2);
+
+            let link = topology.link(ids[0], ids[1]).unwrap();
+            assert_eq!(link.stat.packets_delivered, 4);
+            assert_eq!(link.stat.bytes_sent, 32);
+            assert_eq!(link.stat.mean_latency(), Some(Duration::from_millis(30)));
+            assert_eq!(link.loss_rate(), Some(0.0));
+            assert!(topology.link(ids[2], ids[0]).unwrap().clogged);
+            assert!(topology.link(ids[1], ids[0]).is_none());
+
+            let dot = topology.to_dot();
+            assert!(dot.starts_with("digraph topology {"), "{dot}");
+            let edge = format!("  {} -> {} [", ids[0].0, ids[1].0);
+            assert!(dot.contains(&edge), "{dot}");
+            assert!(dot.contains("style=dashed"), "{dot}");
+
+            let json: serde_json::Value = serde_json::from_str(&topology.to_json()).unwrap();
+            assert_eq!(json["nodes"].as_array().unwrap().len(), 3);
+            assert_eq!(json["links"][0]["source"], ids[0].0);
+            assert_eq!(json["links"][0]["mean_latency_ms"], 30.0);
+        });
+    }
+}
@@ -0,0 +1,122 @@
+//! Per-node behavior flags.
+//!
+//! Every node has a key/value store of flags that application code can read, and that the test
+//! scenario can set. This makes it possible to simulate mixed-version clusters and upgrades
+//! without building several binaries: code paths that differ between versions check a flag, and
+//! the scenario changes the flag of a node before restarting it.
+//!
+//! Flags are kept when a node is restarted, and dropped when it is deleted: a deleted node has
+//! no flags.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // in the scenario
+//! let node = handle.create_node().config("protocol_version", "1").init(run).build();
+//! // ... later, upgrade the node
+//! node_config::set(node.id(), "protocol_version", "2");
+//! handle.restart(node.id());
+//!
+//! // in the application
+//! let version: u32 = node_config::get_parsed("protocol_version").unwrap_or(1);
+//! ```
+
+use crate::{context, runtime::Handle, task::NodeId};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// Get a flag of the current node.
+///
+/// Returns `None` if the flag is not set, or if called outside of a node.
+pub fn get(key: &str) -> Option<String> {
+    let node = context::try_current_task()?.node();
+    if node == NodeId::zero() {
+        return None;
+    }
+    get_for(node, key)
+}
+
+/// Get a flag of the current node, parsed as `T`.
+///
+/// Returns `None` if the flag is not set or cannot be parsed.
+pub fn get_parsed<T: FromStr>(key: &str) -> Option<T> {
+    get(key)?.parse().ok()
+}
+
+/// Get a flag of a node.
+pub fn get_for(node: NodeId, key: &str) -> Option<String> {
+    Handle::current()
+        .task
+        .with_flags(node, |flags| flags.get(key).cloned())?
+}
+
+/// Get all flags of a node.
+pub fn all(node: NodeId) -> BTreeMap<String, String> {
+    Handle::current()
+        .task
+        .with_flags(node, |flags| flags.clone())
+        .unwrap_or_default()
+}
+
+/// Set a flag of a node. Returns the previous value.
+///
+/// Does nothing if the node was deleted.
+pub fn set(node: NodeId, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+    Handle::current()
+        .task
+        .with_flags(node, |flags| flags.insert(key.into(), value.into()))?
+}
+
+/// Remove a flag of a node. Returns the previous value.
+pub fn remove(node: NodeId, key: &str) -> Option<String> {
+    Handle::current()
+        .task
+        .with_flags(node, |flags| flags.remove(key))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, time::*};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn flags() {
+        let runtime = Runtime::new();
+        let versions = Arc::new(Mutex::new(vec![]));
+        let versions_ = versions.clone();
+        let node = runtime
+            .create_node()
+            .config("protocol_version", "1")
+            .init(move || {
+                let versions = versions_.clone();
+                async move {
+                    let version: u32 = get_parsed("protocol_version").unwrap();
+                    versions.lock().unwrap().push(version);
+                    assert_eq!(get("missing"), None);
+                }
+            })
+            .build();
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(get("protocol_version"), None);
+            assert_eq!(
+                set(node.id(), "protocol_version", "2").as_deref(),
+                Some("1")
+            );
+            Handle::current().restart(node.id());
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(*versions.lock().unwrap(), [1, 2]);
+
+            assert_eq!(remove(node.id(), "protocol_version").as_deref(), Some("2"));
+            assert!(all(node.id()).is_empty());
+
+            // a deleted node has no flags
+            set(node.id(), "protocol_version", "3");
+            Handle::current().delete_node(node.id());
+            assert_eq!(get_for(node.id(), "protocol_version"), None);
+            assert_eq!(set(node.id(), "protocol_version", "4"), None);
+            assert!(all(node.id()).is_empty());
+        });
+    }
+}
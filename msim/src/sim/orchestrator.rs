@@ -0,0 +1,206 @@
+//! Adaptive scenarios driven by simulation events.
+//!
+//! An [`Orchestrator`] runs outside of nodes, e.g. in the future given to
+//! [`Runtime::block_on`](crate::runtime::Runtime::block_on), and receives every [`Event`] of the
+//! simulation as it happens, whether tracing is enabled or not: nodes becoming ready, faults
+//! being injected, invariants reported violated with [`invariant_failed`], and the other events
+//! of the [`trace`](crate::trace) module. It controls the simulation through its
+//! [`Handle`] and the simulators, so that scenarios reacting to the system are written as plain
+//! async code:
+//!
+//! ```ignore
+//! use msim::{orchestrator::Orchestrator, trace::*};
+//!
+//! let mut orchestrator = Orchestrator::new();
+//! orchestrator.wait_for(&node_ready(leader)).await;
+//! // keep partitioning the leader until the first view change
+//! let view_change = orchestrator
+//!     .run_until(&custom("view-change"), async {
+//!         loop {
+//!             net.disconnect(leader);
+//!             sleep(Duration::from_secs(5)).await;
+//!             net.connect(leader);
+//!             sleep(Duration::from_secs(5)).await;
+//!         }
+//!     })
+//!     .await;
+//! ```
+//!
+//! There is at most one orchestrator per runtime at a time.
+
+use crate::{
+    context,
+    runtime::Handle,
+    task::NodeId,
+    trace::{Event, EventKind, Pattern},
+};
+use futures::{channel::mpsc, future::Either, pin_mut, Stream, StreamExt};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tracing::warn;
+
+/// Receives the events of the simulation, see the [module](self) documentation.
+pub struct Orchestrator {
+    handle: Handle,
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Orchestrator {
+    /// Start receiving the events of the current runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within a node, or if another orchestrator of the runtime exists.
+    pub fn new() -> Self {
+        assert!(
+            context::try_current_task().map_or(true, |task| task.node() == NodeId::zero()),
+            "an orchestrator must run outside of nodes"
+        );
+        let handle = Handle::current();
+        let events = handle
+            .trace()
+            .subscribe()
+            .expect("an orchestrator exists already");
+        Orchestrator { handle, events }
+    }
+
+    /// The handle of the runtime, to control the simulation.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// Wait for the next event.
+    pub async fn next_event(&mut self) -> Event {
+        self.events.next().await.expect("runtime dropped")
+    }
+
+    /// Returns the next event if one happened already.
+    pub fn try_next_event(&mut self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+
+    /// Wait for the next event matching `pattern`, skipping the others.
+    pub async fn wait_for(&mut self, pattern: &Pattern) -> Event {
+        loop {
+            let event = self.next_event().await;
+            if pattern.matches(&event) {
+                return event;
+            }
+        }
+    }
+
+    /// Run `future` until an event matches `pattern`.
+    ///
+    /// Returns the event, or `None` if `future` completed first.
+    pub async fn run_until<F: Future>(&mut self, pattern: &Pattern, future: F) -> Option<Event> {
+        let wait = self.wait_for(pattern);
+        pin_mut!(wait, future);
+        match futures::future::select(wait, future).await {
+            Either::Left((event, _)) => Some(event),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+impl Default for Orchestrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for Orchestrator {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+/// Report that the invariant `name` does not hold, with what was observed.
+///
+/// The violation is logged, recorded in the [trace](crate::trace) and sent to the
+/// [`Orchestrator`], which may e.g. stop injecting faults or end the test.
+pub fn invariant_failed(name: impl Into<String>, message: impl Into<String>) {
+    let (name, message) = (name.into(), message.into());
+    let node = context::current_node();
+    warn!("invariant {name} failed on {node}: {message}");
+    Handle::current()
+        .trace()
+        .record(EventKind::InvariantFailed {
+            node,
+            name,
+            message,
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::Runtime,
+        time::{sleep, Duration},
+        trace::{invariant_failed as invariant, node_pause, node_ready},
+    };
+
+    #[test]
+    fn orchestrate() {
+        let runtime = Runtime::new();
+        let server = runtime
+            .create_node()
+            .name("server")
+            .ready_when(|| sleep(Duration::from_secs(1)))
+            .build();
+        let checker = runtime
+            .create_node()
+            .name("checker")
+            .init(|| async {
+                sleep(Duration::from_secs(3)).await;
+                invariant_failed("progress", "stalled");
+            })
+            .build();
+
+        runtime.block_on(async move {
+            let mut orchestrator = Orchestrator::new();
+            let handle = orchestrator.handle().clone();
+
+            // events are received although tracing is disabled
+            let event = orchestrator.wait_for(&node_ready(server.id())).await;
+            assert_eq!(event.time.as_secs(), 1);
+
+            // keep pausing the server until the invariant fails
+            let event = orchestrator
+                .run_until(&invariant("progress"), async {
+                    loop {
+                        handle.pause(server.id());
+                        sleep(Duration::from_millis(300)).await;
+                        handle.resume(server.id());
+                        sleep(Duration::from_millis(300)).await;
+                    }
+                })
+                .await
+                .unwrap();
+            assert_eq!(event.time.as_secs(), 3);
+            assert_eq!(
+                event.kind,
+                EventKind::InvariantFailed {
+                    node: checker.id(),
+                    name: "progress".into(),
+                    message: "stalled".into(),
+                }
+            );
+
+            // skip the events that followed, e.g. the exit of the checker
+            while orchestrator.try_next_event().is_some() {}
+            handle.pause(server.id());
+            let event = orchestrator.try_next_event().unwrap();
+            assert!(node_pause(server.id()).matches(&event));
+
+            // a single orchestrator at a time
+            drop(orchestrator);
+            let _orchestrator = Orchestrator::new();
+        });
+    }
+}
@@ -0,0 +1,246 @@
+//! Wall clock profiling of the simulator itself.
+//!
+//! Large simulations can spend most of their real time inside the simulator rather than in the
+//! code under test. While enabled, the simulator measures the real time spent in each of its
+//! main components, so that bottlenecks can be identified and reported.
+//!
+//! Sections nest: time spent in the network lock, timers and interceptors while polling a task
+//! is also counted as executor time.
+//!
+//! Measurements are per thread, and reset when a runtime is created with profiling enabled, so
+//! they cover a single run. Enable it with [`ProfileConfig::wall_clock`] or the
+//! `MSIM_PROFILE_WALL_CLOCK` environment variable. Tests run with `#[sim_test]` print the report
+//! at the end of each run while it is enabled.
+//!
+//! [`ProfileConfig::wall_clock`]: crate::profile::ProfileConfig::wall_clock
+
+use crate::define_bypass;
+use std::{
+    cell::RefCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+/// A component of the simulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// Polling tasks.
+    Executor,
+    /// Advancing time, firing and inserting timers.
+    Timer,
+    /// Waiting for and holding the network lock.
+    NetworkLock,
+    /// Intercepted library calls.
+    Interceptor,
+}
+
+impl Section {
+    const ALL: [Section; 4] = [
+        Section::Executor,
+        Section::Timer,
+        Section::NetworkLock,
+        Section::Interceptor,
+    ];
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Executor => "executor",
+            Self::Timer => "timer",
+            Self::NetworkLock => "network lock",
+            Self::Interceptor => "interceptors",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Real time spent in a section.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of times the section was entered.
+    pub count: u64,
+    /// Total real time spent in the section.
+    pub total: Duration,
+}
+
+/// Wall clock measurements of a run.
+#[derive(Debug, Clone, Default)]
+pub struct PerfReport {
+    /// Real time since profiling was enabled.
+    pub elapsed: Duration,
+    usage: [Usage; 4],
+}
+
+impl PerfReport {
+    /// The real time spent in a section.
+    pub fn get(&self, section: Section) -> Usage {
+        self.usage[section as usize]
+    }
+}
+
+impl fmt::Display for PerfReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "simulator wall clock profile ({:.3?}):", self.elapsed)?;
+        for section in Section::ALL {
+            let usage = self.get(section);
+            writeln!(
+                f,
+                "{:>12.3?} {:>10}x  {}",
+                usage.total, usage.count, section
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    start: Duration,
+    usage: [Usage; 4],
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+define_bypass!(bypass_clock_gettime,
+    fn clock_gettime(clock_id: libc::clockid_t, ts: *mut libc::timespec) -> libc::c_int);
+
+/// Read the real monotonic clock, bypassing the simulated one.
+pub(crate) fn real_now() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { bypass_clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Start profiling on the current thread, discarding previous measurements.
+pub fn enable() {
+    let state = State {
+        start: real_now(),
+        usage: Default::default(),
+    };
+    STATE.with(|s| *s.borrow_mut() = Some(state));
+}
+
+/// Stop profiling on the current thread.
+pub fn disable() {
+    STATE.with(|s| *s.borrow_mut() = None);
+}
+
+/// Returns `true` if profiling is enabled on the current thread.
+pub fn is_enabled() -> bool {
+    // interceptors may run while thread locals are being destroyed.
+    STATE.try_with(|s| s.borrow().is_some()).unwrap_or(false)
+}
+
+/// Get the measurements of the current thread, if profiling is enabled.
+pub fn report() -> Option<PerfReport> {
+    let now = real_now();
+    STATE.with(|s| {
+        s.borrow().as_ref().map(|state| PerfReport {
+            elapsed: now - state.start,
+            usage: state.usage,
+        })
+    })
+}
+
+/// Start measuring a section, if profiling is enabled.
+fn start() -> Option<Duration> {
+    is_enabled().then(real_now)
+}
+
+fn finish(section: Section, start: Option<Duration>) {
+    let Some(start) = start else {
+        return;
+    };
+    let elapsed = real_now() - start;
+    let _ = STATE.try_with(|s| {
+        if let Some(state) = s.borrow_mut().as_mut() {
+            let usage = &mut state.usage[section as usize];
+            usage.count += 1;
+            usage.total += elapsed;
+        }
+    });
+}
+
+/// Run `f`, counting its real time towards `section`.
+pub(crate) fn measure<R>(section: Section, f: impl FnOnce() -> R) -> R {
+    let start = start();
+    let ret = f();
+    finish(section, start);
+    ret
+}
+
+/// A guard counting the real time from its creation until it is dropped towards a section.
+pub(crate) struct Measured<G> {
+    inner: G,
+    section: Section,
+    start: Option<Duration>,
+}
+
+impl<G> Measured<G> {
+    /// Measure the time to produce the guard, and the time it is held.
+    pub fn new(section: Section, f: impl FnOnce() -> G) -> Self {
+        let start = start();
+        Measured {
+            inner: f(),
+            section,
+            start,
+        }
+    }
+}
+
+impl<G> Deref for Measured<G> {
+    type Target = G;
+
+    fn deref(&self) -> &G {
+        &self.inner
+    }
+}
+
+impl<G> DerefMut for Measured<G> {
+    fn deref_mut(&mut self) -> &mut G {
+        &mut self.inner
+    }
+}
+
+impl<G> Drop for Measured<G> {
+    fn drop(&mut self) {
+        finish(self.section, self.start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{net::Endpoint, runtime::Runtime};
+
+    #[test]
+    fn sections() {
+        let runtime = Runtime::new();
+        enable();
+        let node = runtime.create_node().ip([10, 0, 0, 1].into()).build();
+        let f = node.spawn(async move {
+            let ep = Endpoint::bind(libc::SOCK_DGRAM, "10.0.0.1:1")
+                .await
+                .unwrap();
+            drop(ep);
+            crate::time::sleep(Duration::from_secs(1)).await;
+            // an intercepted call
+            let _ = std::time::Instant::now();
+        });
+        runtime.block_on(f).unwrap();
+
+        let report = runtime.perf_report().unwrap();
+        disable();
+        for section in Section::ALL {
+            assert!(report.get(section).count > 0, "{section}");
+        }
+        assert!(report.get(Section::Executor).total <= report.elapsed);
+        assert!(runtime.perf_report().is_none());
+    }
+}
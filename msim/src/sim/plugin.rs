@@ -1,9 +1,6 @@
 //! Simulator plugin framework.
 
-use std::{
-    any::{Any, TypeId},
-    sync::Arc,
-};
+use std::{any::Any, sync::Arc};
 
 use downcast_rs::{impl_downcast, DowncastSync};
 
@@ -32,14 +29,7 @@ impl_downcast!(sync Simulator);
 
 /// Get the simulator.
 pub fn simulator<S: Simulator>() -> Arc<S> {
-    crate::context::current(|h| {
-        let sims = h.sims.lock().unwrap();
-        sims[&TypeId::of::<S>()]
-            .clone()
-            .downcast_arc()
-            .ok()
-            .unwrap()
-    })
+    crate::context::current(|h| h.simulator::<S>())
 }
 
 /// Get the node ID of current task.
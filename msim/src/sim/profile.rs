@@ -0,0 +1,239 @@
+//! Virtual time profiling.
+//!
+//! When a simulated system takes much longer to converge than expected, it is often unclear where
+//! the virtual time went. While enabled, the [`Profiler`] attributes virtual time to categories:
+//! network latency, random jitter injected by the simulator, and sleeps and timers by call site.
+//!
+//! Time is attributed to each category independently, so concurrent waits overlap and the totals
+//! can add up to more than the elapsed time. The report is a ranking of contributors rather than
+//! a breakdown of the wall clock of the simulation.
+//!
+//! Profiling is disabled by default. Enable it with [`ProfileConfig`], [`Profiler::enable`] or
+//! the `MSIM_PROFILE` environment variable. Tests run with `#[sim_test]` print the top
+//! contributors at the end of each run while profiling is enabled.
+//!
+//! # Example
+//!
+//! ```ignore
+//! msim::profile::profiler().enable();
+//! // ... run the system ...
+//! println!("{}", msim::profile::profiler().profile());
+//! ```
+
+use crate::time::Duration;
+use std::{
+    collections::HashMap,
+    fmt,
+    panic::Location,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Number of entries shown when a [`Profile`] is displayed.
+const DISPLAY_LEN: usize = 10;
+
+/// Profiling configuration.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileConfig {
+    /// Profile from the start of the simulation.
+    pub enabled: bool,
+
+    /// Measure the real time spent in the simulator itself, see the [`perf`](crate::perf)
+    /// module.
+    pub wall_clock: bool,
+}
+
+/// What virtual time was spent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Latency of messages sent through the simulated network.
+    NetworkLatency,
+    /// Random delays injected by the simulator into network operations.
+    Jitter,
+    /// Sleeps, timeouts and intervals, by the call site that created them.
+    Timer(&'static Location<'static>),
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NetworkLatency => write!(f, "network latency"),
+            Self::Jitter => write!(f, "network jitter"),
+            Self::Timer(location) => write!(f, "timer at {location}"),
+        }
+    }
+}
+
+/// Virtual time attributed to a category.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of waits.
+    pub count: u64,
+    /// Total virtual time waited.
+    pub total: Duration,
+}
+
+/// Collects virtual time usage of a runtime.
+#[derive(Clone, Default)]
+pub struct Profiler {
+    enabled: Arc<AtomicBool>,
+    usage: Arc<Mutex<HashMap<Category, Usage>>>,
+}
+
+impl fmt::Debug for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Profiler")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl Profiler {
+    /// Start profiling.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop profiling. Usage recorded so far is kept.
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if profiling is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Discard all recorded usage.
+    pub fn clear(&self) {
+        self.usage.lock().unwrap().clear();
+    }
+
+    /// Take a snapshot of the usage recorded so far.
+    pub fn profile(&self) -> Profile {
+        let usage = self.usage.lock().unwrap();
+        let mut entries: Vec<_> = usage.iter().map(|(c, u)| (*c, *u)).collect();
+        // sort by descending total, then by name so that the order is deterministic.
+        entries.sort_by(|(c1, u1), (c2, u2)| {
+            u2.total
+                .cmp(&u1.total)
+                .then_with(|| c1.to_string().cmp(&c2.to_string()))
+        });
+        Profile { entries }
+    }
+
+    pub(crate) fn record(&self, category: Category, duration: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(category).or_default();
+        usage.count += 1;
+        usage.total += duration;
+    }
+}
+
+/// A snapshot of the virtual time usage, ordered from the largest contributor.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    entries: Vec<(Category, Usage)>,
+}
+
+impl Profile {
+    /// All entries, ordered from the largest contributor.
+    pub fn entries(&self) -> &[(Category, Usage)] {
+        &self.entries
+    }
+
+    /// The `n` largest contributors.
+    pub fn top(&self, n: usize) -> &[(Category, Usage)] {
+        &self.entries[..n.min(self.entries.len())]
+    }
+
+    /// The usage of a category.
+    pub fn get(&self, category: Category) -> Option<Usage> {
+        self.entries
+            .iter()
+            .find(|(c, _)| *c == category)
+            .map(|(_, u)| *u)
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "virtual time profile (top {DISPLAY_LEN}):")?;
+        for (category, usage) in self.top(DISPLAY_LEN) {
+            writeln!(
+                f,
+                "{:>12.3?} {:>8}x  {}",
+                usage.total, usage.count, category
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the profiler of the current runtime.
+pub fn profiler() -> Profiler {
+    crate::time::TimeHandle::current().profiler().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{network::Payload, Endpoint, LatencyDistribution, NetSim},
+        plugin::simulator,
+        runtime::Runtime,
+        time::{sleep, timeout},
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn categories() {
+        let runtime = Runtime::new();
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+        node2.spawn(async move {
+            let _net = Endpoint::bind(libc::SOCK_DGRAM, addr2).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_DGRAM, addr1).await.unwrap();
+            for _ in 0..3 {
+                sleep(Duration::from_secs(1)).await;
+            }
+            let _ = timeout(Duration::from_secs(5), std::future::pending::<()>()).await;
+            let payload = Payload::new_udp(Box::new(vec![1u8]));
+            net.send_to(addr2, 1, payload).await.unwrap();
+        });
+
+        runtime.block_on(async move {
+            simulator::<NetSim>().update_config(|cfg| {
+                cfg.latency.default_latency =
+                    LatencyDistribution::Constant(Duration::from_millis(10));
+            });
+            profiler().enable();
+            f.await.unwrap();
+
+            let profile = profiler().profile();
+            let entries = profile.entries();
+            // the timeout, then the sleeps
+            assert!(matches!(entries[0].0, Category::Timer(l) if l.file().ends_with("profile.rs")));
+            assert_eq!(entries[0].1.total, Duration::from_secs(5));
+            assert!(matches!(entries[1].0, Category::Timer(l) if l.file().ends_with("profile.rs")));
+            assert_eq!(entries[1].1.count, 3);
+            assert_eq!(entries[1].1.total, Duration::from_secs(3));
+
+            let latency = profile.get(Category::NetworkLatency).unwrap();
+            assert_eq!(latency.total, Duration::from_millis(10));
+            assert!(profile.get(Category::Jitter).is_some());
+        });
+    }
+}
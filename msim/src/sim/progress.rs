@@ -0,0 +1,224 @@
+//! Progress reporting for long runs.
+//!
+//! Soak tests can run for hours of virtual time, and take a long real time to do so. A progress
+//! reporter periodically reports how far the simulation got: the virtual and real time elapsed,
+//! how many tasks were polled and how fast, and the health of every node. This shows in CI logs
+//! that a run is alive, and where it slows down.
+//!
+//! Reporting is disabled by default. Enable it with [`ProgressConfig`], the `MSIM_PROGRESS`
+//! environment variable set to an interval in seconds, or [`Runtime::set_progress_reporter`].
+//! Reports are checked after every step of the executor, and at most once per interval of real
+//! time, so a run stuck in a single task is not reported.
+//!
+//! [`Runtime::set_progress_reporter`]: crate::runtime::Runtime::set_progress_reporter
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut rt = Runtime::new();
+//! rt.set_progress_reporter(
+//!     ProgressReporter::stderr(Duration::from_secs(30)).target(Duration::from_secs(6 * 3600)),
+//! );
+//! ```
+
+use crate::runtime::{NodeDescriptor, NodeState};
+use std::{fmt, time::Duration};
+
+/// Progress reporting configuration.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressConfig {
+    /// Print a progress report to stderr every `interval` of real time.
+    pub interval: Option<Duration>,
+
+    /// The virtual time the run is expected to reach, used to estimate the remaining real time.
+    pub target: Option<Duration>,
+}
+
+/// A progress report.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Virtual time since the start of the run.
+    pub virtual_time: Duration,
+    /// Real time since the start of the run.
+    pub wall_time: Duration,
+    /// Number of tasks polled since the start of the run.
+    pub events: u64,
+    /// Polls per second of real time since the previous report.
+    pub events_per_sec: f64,
+    /// Virtual time per real time since the previous report.
+    pub speed: f64,
+    /// Estimated real time until the [target](ProgressReporter::target) virtual time is reached.
+    pub eta: Option<Duration>,
+    /// The health of every node, ordered by id.
+    pub nodes: Vec<NodeDescriptor>,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "progress: virtual {:.1?}, wall {:.1?}, {} events ({:.0}/s), {:.1}x",
+            self.virtual_time, self.wall_time, self.events, self.events_per_sec, self.speed
+        )?;
+        if let Some(eta) = self.eta {
+            write!(f, ", eta {:.0?}", eta)?;
+        }
+        let running = self
+            .nodes
+            .iter()
+            .filter(|node| node.state == NodeState::Running)
+            .count();
+        write!(f, "\n  nodes: {running}/{} running", self.nodes.len())?;
+        for node in &self.nodes {
+            if node.state != NodeState::Running || node.restarts > 0 {
+                write!(
+                    f,
+                    "\n  {} {}: {}, {} restarts",
+                    node.id, node.name, node.state, node.restarts
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+type Callback = Box<dyn FnMut(&Progress) + Send>;
+
+/// Periodically reports the [`Progress`] of a run.
+pub struct ProgressReporter {
+    interval: Duration,
+    target: Option<Duration>,
+    callback: Callback,
+    /// Real time of the start of the run.
+    start: Option<Duration>,
+    /// Virtual time, real time and events of the previous report.
+    last: (Duration, Duration, u64),
+}
+
+impl fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressReporter")
+            .field("interval", &self.interval)
+            .field("target", &self.target)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProgressReporter {
+    /// Print a report to stderr every `interval` of real time.
+    pub fn stderr(interval: Duration) -> Self {
+        Self::callback(interval, |progress| eprintln!("{progress}"))
+    }
+
+    /// Call `callback` with a report every `interval` of real time.
+    pub fn callback(interval: Duration, callback: impl FnMut(&Progress) + Send + 'static) -> Self {
+        ProgressReporter {
+            interval,
+            target: None,
+            callback: Box::new(callback),
+            start: None,
+            last: Default::default(),
+        }
+    }
+
+    /// Estimate the real time until the run reaches `virtual_time`.
+    pub fn target(mut self, virtual_time: Duration) -> Self {
+        self.target = Some(virtual_time);
+        self
+    }
+
+    /// Create the reporter configured by `config` and the environment, if any.
+    pub(crate) fn from_config(config: &ProgressConfig) -> Option<Self> {
+        let interval = std::env::var("MSIM_PROGRESS")
+            .ok()
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+            .or(config.interval)?;
+        let mut reporter = Self::stderr(interval);
+        reporter.target = config.target;
+        Some(reporter)
+    }
+
+    /// Report if an interval passed since the previous report.
+    ///
+    /// `nodes` is only called when a report is made.
+    pub(crate) fn poll(
+        &mut self,
+        virtual_time: Duration,
+        events: u64,
+        nodes: impl FnOnce() -> Vec<NodeDescriptor>,
+    ) {
+        let now = crate::perf::real_now();
+        let wall_time = now - *self.start.get_or_insert(now);
+        let (last_virtual, last_wall, last_events) = self.last;
+        let real = wall_time.saturating_sub(last_wall);
+        if real < self.interval || real.is_zero() {
+            return;
+        }
+        let speed = virtual_time.saturating_sub(last_virtual).as_secs_f64() / real.as_secs_f64();
+        let eta = self.target.and_then(|target| {
+            let remaining = target.saturating_sub(virtual_time).as_secs_f64();
+            (speed > 0.0).then(|| Duration::from_secs_f64(remaining / speed))
+        });
+        let progress = Progress {
+            virtual_time,
+            wall_time,
+            events,
+            events_per_sec: (events - last_events) as f64 / real.as_secs_f64(),
+            speed,
+            eta,
+            nodes: nodes(),
+        };
+        (self.callback)(&progress);
+        self.last = (virtual_time, wall_time, events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::{Handle, Runtime},
+        time::sleep,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reporter() {
+        let mut runtime = Runtime::new();
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reports_ = reports.clone();
+        runtime.set_progress_reporter(
+            ProgressReporter::callback(Duration::ZERO, move |progress| {
+                reports_.lock().unwrap().push(progress.clone())
+            })
+            .target(Duration::from_secs(100)),
+        );
+        let node = runtime.create_node().name("server").build();
+        node.spawn(async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(10)).await;
+            Handle::current().pause(node.id());
+            sleep(Duration::from_secs(1)).await;
+        });
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 10);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].virtual_time <= w[1].virtual_time && w[0].events <= w[1].events));
+        let last = reports.last().unwrap();
+        assert!(last.virtual_time >= Duration::from_secs(10));
+        assert!(last.eta.is_some());
+        assert_eq!(last.nodes.len(), 1);
+        assert_eq!(last.nodes[0].state, NodeState::Paused);
+        let text = last.to_string();
+        assert!(text.contains("0/1 running"), "{text}");
+        assert!(text.contains("server: paused"), "{text}");
+    }
+}
@@ -18,6 +18,22 @@
 //! });
 //! ```
 //!
+//! Tests that want to explore different scenarios should use [`choose`] and [`maybe`] instead of
+//! drawing from [`thread_rng()`]. They draw from a dedicated stream, so that adding a choice does
+//! not change the behavior of the application for a given seed, and every choice is recorded in
+//! the [trace](crate::trace). A failing run can therefore be replayed with the same seed.
+//!
+//! ```
+//! use msim::{rand::{choose, maybe}, runtime::Runtime};
+//!
+//! Runtime::new().block_on(async {
+//!     let victim = *choose(&[1, 2, 3]);
+//!     if maybe(0.1) {
+//!         // inject a fault
+//!     }
+//! });
+//! ```
+//!
 //! [`rand`]: rand
 
 pub use rand;
@@ -26,6 +42,7 @@ use rand::{
     prelude::{Distribution, SmallRng},
 };
 
+use crate::{task::NodeId, trace::EventKind};
 use std::cell::Cell;
 use std::sync::{Arc, Mutex};
 
@@ -48,52 +65,144 @@ pub mod prelude {
 #[cfg_attr(docsrs, doc(cfg(msim)))]
 #[derive(Clone)]
 pub struct GlobalRng {
-    inner: Arc<Mutex<Inner>>,
+    seed: u64,
+    rng: Arc<Mutex<SmallRng>>,
+    check: Arc<Mutex<Check>>,
 }
 
-struct Inner {
-    rng: SmallRng,
+/// Determinism log and check state, shared by all streams derived from the same seed.
+#[derive(Default)]
+struct Check {
     log: Option<Vec<u8>>,
     check: Option<(Vec<u8>, usize)>,
+    /// Seed of the keys of std `RandomState` in this run.
+    hash_seed: u64,
+    /// Whether the checked log was recorded with different keys of std `RandomState`.
+    hash_seed_changed: bool,
+}
+
+/// Configuration of the keys of std `HashMap` and `HashSet`.
+///
+/// The simulator seeds the keys of std `RandomState` when a runtime is created, so that iterating
+/// a std map or set visits its entries in the same order for a given seed.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Default)]
+pub struct HashingConfig {
+    /// Seed of the keys of std `RandomState`. Defaults to the seed of the runtime.
+    ///
+    /// Changing it while keeping the seed of the runtime changes nothing but the iteration order
+    /// of std maps and sets. `#[sim_test(check_hash_order)]` does this to find code whose behavior
+    /// depends on that order.
+    pub seed: Option<u64>,
+
+    /// Panic when the keys cannot be seeded, instead of logging a warning.
+    ///
+    /// The keys are initialized once per thread, so they cannot be seeded if a std map or set was
+    /// created on the thread before the runtime.
+    pub strict: bool,
+}
+
+/// An independent stream of randomness, see [`GlobalRng::stream`].
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RngStream {
+    /// Task scheduling and timing jitter.
+    Scheduler,
+    /// Network and other simulators: latency, packet loss and injected faults.
+    Network,
+    /// Application code, i.e. [`thread_rng()`] and `getrandom`.
+    Application,
+    /// Choices made by the test scenario with [`choose`] and [`maybe`].
+    Scenario,
+    /// A custom stream, e.g. for a test harness component. Its name never selects a builtin
+    /// stream.
+    Named(&'static str),
+}
+
+impl RngStream {
+    fn salt(&self) -> u64 {
+        // FNV-1a, so that salts are stable across builds and platforms.
+        fn fnv<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u64 {
+            bytes.into_iter().fold(0xcbf29ce484222325, |h, b| {
+                (h ^ *b as u64).wrapping_mul(0x100000001b3)
+            })
+        }
+        match self {
+            Self::Scheduler => fnv(b"scheduler"),
+            Self::Network => fnv(b"network"),
+            Self::Application => fnv(b"application"),
+            Self::Scenario => fnv(b"scenario"),
+            // prefixed, so that e.g. `Named("network")` is not the network stream.
+            Self::Named(name) => fnv(b"named:".iter().chain(name.as_bytes())),
+        }
+    }
 }
 
 impl GlobalRng {
     /// Create a new RNG using the given seed.
     pub fn new_with_seed(seed: u64) -> Self {
-        // XXX: call this function to make sure it won't be gc.
-        unsafe { getentropy(std::ptr::null_mut(), 0) };
-        if !init_std_random_state(seed) {
-            tracing::warn!(
-                "failed to initialize std random state, std HashMap will not be deterministic"
-            );
-        }
+        seed_std_random_state(seed, false);
+        Self::new_with_hash_seed(seed, seed)
+    }
 
-        let inner = Inner {
-            rng: SeedableRng::seed_from_u64(seed),
-            log: None,
-            check: None,
+    /// Create a new RNG using the given seed, for a run where std `RandomState` was seeded with
+    /// `hash_seed`.
+    pub(crate) fn new_with_hash_seed(seed: u64, hash_seed: u64) -> Self {
+        let check = Check {
+            hash_seed,
+            ..Default::default()
         };
         GlobalRng {
-            inner: Arc::new(Mutex::new(inner)),
+            seed,
+            rng: Arc::new(Mutex::new(SeedableRng::seed_from_u64(seed))),
+            check: Arc::new(Mutex::new(check)),
+        }
+    }
+
+    /// Derive an independent stream of randomness from this RNG's seed.
+    ///
+    /// Values drawn from one stream do not affect the values drawn from another, so e.g. adding a
+    /// random call in application code does not change network latencies or task scheduling for
+    /// a given seed. Deriving the same stream twice gives two generators with the same sequence.
+    pub fn stream(&self, stream: RngStream) -> Self {
+        let seed = self.seed ^ stream.salt();
+        GlobalRng {
+            seed,
+            rng: Arc::new(Mutex::new(SeedableRng::seed_from_u64(seed))),
+            check: self.check.clone(),
         }
     }
 
     /// Call function on the inner RNG.
     pub(crate) fn with<T>(&self, f: impl FnOnce(&mut SmallRng) -> T) -> T {
-        let mut lock = self.inner.lock().unwrap();
-        let ret = f(&mut lock.rng);
+        let mut rng = self.rng.lock().unwrap();
+        let ret = f(&mut rng);
         // log or check
+        let mut lock = self.check.lock().unwrap();
         if lock.log.is_some() || lock.check.is_some() {
             let t = crate::time::TimeHandle::try_current().map(|t| t.elapsed());
             fn hash_u128(x: u128) -> u8 {
                 x.to_ne_bytes().iter().fold(0, |a, b| a ^ b)
             }
-            let v = lock.rng.clone().gen::<u8>() ^ hash_u128(t.unwrap_or_default().as_nanos());
+            let v = rng.clone().gen::<u8>() ^ hash_u128(t.unwrap_or_default().as_nanos());
             if let Some(log) = &mut lock.log {
                 log.push(v);
             }
             if let Some((check, i)) = &mut lock.check {
                 if check.get(*i) != Some(&v) {
+                    if lock.hash_seed_changed {
+                        // symbolizing the backtrace reads files, which must not be simulated.
+                        super::intercept::enable_intercepts(false);
+                        let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+                        super::intercept::enable_intercepts(true);
+                        panic!(
+                            "non-determinism detected at {:?} after changing the keys of std \
+                             HashMap and HashSet: the iteration order of a std map or set \
+                             affects the simulation. The diverging random value was drawn \
+                             at:\n{backtrace}",
+                            t.unwrap_or_default(),
+                        );
+                    }
                     if let Some(time) = t {
                         panic!("non-determinism detected at {:?}", time);
                     }
@@ -106,21 +215,22 @@ impl GlobalRng {
     }
 
     pub(crate) fn enable_check(&self, log: Log) {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.check.lock().unwrap();
+        lock.hash_seed_changed = log.1 != lock.hash_seed;
         lock.check = Some((log.0, 0));
     }
 
     pub(crate) fn enable_log(&self) {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.check.lock().unwrap();
         lock.log = Some(Vec::new());
     }
 
     pub(crate) fn take_log(&self) -> Option<Log> {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.check.lock().unwrap();
         lock.log
             .take()
             .or_else(|| lock.check.take().map(|(s, _)| s))
-            .map(Log)
+            .map(|log| Log(log, lock.hash_seed))
     }
 }
 
@@ -156,10 +266,71 @@ where
     thread_rng().gen()
 }
 
+/// Choose one of `options` at random, using the scenario stream.
+///
+/// The choice is recorded in the trace as an [`EventKind::Choice`].
+///
+/// # Panics
+///
+/// Panics if `options` is empty, or if called outside of a runtime.
+pub fn choose<T>(options: &[T]) -> &T {
+    assert!(!options.is_empty(), "no options to choose from");
+    let index = scenario_choice(options.len(), |rng| rng.gen_range(0..options.len()));
+    &options[index]
+}
+
+/// Returns `true` with probability `p`, using the scenario stream.
+///
+/// The choice is recorded in the trace as an [`EventKind::Choice`] between two options, where
+/// option 1 is `true`.
+///
+/// # Panics
+///
+/// Panics if `p` is not in `[0, 1]`, or if called outside of a runtime.
+pub fn maybe(p: f64) -> bool {
+    scenario_choice(2, |rng| rng.gen_bool(p) as usize) == 1
+}
+
+/// Draw a choice among `options` from the scenario stream and record it.
+fn scenario_choice(options: usize, f: impl FnOnce(&mut SmallRng) -> usize) -> usize {
+    let node = crate::context::try_current_task().map_or(NodeId::zero(), |task| task.node());
+    crate::context::current(|h| {
+        let index = match h.script.lock().unwrap().as_mut() {
+            Some(script) => script.next(options, || h.scenario_rand.with(f)),
+            None => h.scenario_rand.with(f),
+        };
+        h.trace.record(EventKind::Choice {
+            node,
+            index,
+            options,
+        });
+        index
+    })
+}
+
 /// Random log for determinism check.
 #[cfg_attr(docsrs, doc(cfg(msim)))]
 #[derive(Debug, PartialEq, Eq)]
-pub struct Log(Vec<u8>);
+pub struct Log(Vec<u8>, u64);
+
+/// Seed the keys of std `RandomState` on the current thread.
+///
+/// Panics if they were already initialized and `strict` is set, see [`HashingConfig::strict`].
+pub(crate) fn seed_std_random_state(seed: u64, strict: bool) {
+    // XXX: call this function to make sure it won't be gc.
+    unsafe { getentropy(std::ptr::null_mut(), 0) };
+    if !init_std_random_state(seed) {
+        if strict {
+            panic!(
+                "failed to initialize std random state: a std HashMap or HashSet was created on \
+                 this thread before the runtime, create the runtime on a new thread"
+            );
+        }
+        tracing::warn!(
+            "failed to initialize std random state, std HashMap will not be deterministic"
+        );
+    }
+}
 
 /// Initialize std `RandomState` with specified seed.
 ///
@@ -258,7 +429,7 @@ unsafe extern "C" fn getentropy(buf: *mut u8, buflen: usize) -> i32 {
 #[cfg(test)]
 mod tests {
     use crate::runtime::Runtime;
-    use std::collections::{BTreeSet, HashMap};
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
 
     #[test]
     // NOTE:
@@ -297,4 +468,159 @@ mod tests {
         }
         assert_eq!(seqs.len(), 3, "hashmap is not deterministic");
     }
+
+    #[test]
+    fn hash_order_check() {
+        use crate::{rand::HashingConfig, time, SimConfig};
+
+        // iterating a std map leaks its order into the timing of random draws
+        fn run<M>(hash_seed: u64, log: Option<super::Log>) -> Result<Option<super::Log>, String>
+        where
+            M: FromIterator<(u64, u64)> + IntoIterator<Item = (u64, u64)>,
+        {
+            std::thread::spawn(move || {
+                let config = SimConfig {
+                    hashing: HashingConfig {
+                        seed: Some(hash_seed),
+                        strict: true,
+                    },
+                    ..Default::default()
+                };
+                let runtime = Runtime::with_seed_and_config(1, config);
+                runtime.enable_determinism_check(log);
+                runtime.block_on(async {
+                    let map = (0..10).map(|i| (i, i)).collect::<M>();
+                    for (k, _) in map {
+                        time::sleep(time::Duration::from_millis(k)).await;
+                        super::random::<u64>();
+                    }
+                });
+                runtime.take_rand_log()
+            })
+            .join()
+            .map_err(|e| *e.downcast::<String>().unwrap())
+        }
+
+        let log = run::<HashMap<_, _>>(1, None).unwrap();
+        let err = run::<HashMap<_, _>>(2, log).unwrap_err();
+        assert!(
+            err.contains("after changing the keys of std HashMap"),
+            "{err}"
+        );
+        // followed by a backtrace, whose frames depend on the build.
+        assert!(err.contains("random value was drawn at:\n"), "{err}");
+
+        let log = run::<BTreeMap<_, _>>(1, None).unwrap();
+        run::<BTreeMap<_, _>>(2, log).unwrap();
+
+        // strict mode requires the keys to be seeded
+        let err = std::thread::spawn(|| {
+            let _ = HashMap::<u64, u64>::new();
+            let mut config = SimConfig::default();
+            config.hashing.strict = true;
+            Runtime::with_seed_and_config(1, config);
+        })
+        .join()
+        .unwrap_err();
+        let err = err.downcast::<&str>().unwrap();
+        assert!(
+            err.contains("failed to initialize std random state"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn scenario_choices() {
+        use super::{choose, maybe, random};
+        use crate::trace;
+
+        fn run() -> (Vec<u32>, u64, Vec<trace::Event>) {
+            let mut config = crate::SimConfig::default();
+            config.trace.enabled = true;
+            let runtime = Runtime::with_seed_and_config(3, config);
+            runtime.block_on(async {
+                let picks = (0..10).map(|_| *choose(&[1, 2, 3])).collect::<Vec<_>>();
+                assert!(maybe(1.0));
+                assert!(!maybe(0.0));
+                (picks, random(), trace::timeline().events().to_vec())
+            })
+        }
+
+        let (picks, app, events) = run();
+        assert!(picks.iter().all(|p| [1, 2, 3].contains(p)));
+        assert_eq!(events.len(), 12);
+        assert_eq!(
+            events[10].kind,
+            trace::EventKind::Choice {
+                node: crate::task::NodeId::zero(),
+                index: 1,
+                options: 2,
+            }
+        );
+        assert_eq!(run(), (picks, app, events));
+
+        // choices do not perturb the application stream
+        let runtime = Runtime::with_seed(3);
+        assert_eq!(runtime.block_on(async { random::<u64>() }), app);
+    }
+
+    #[test]
+    fn independent_streams() {
+        use super::{GlobalRng, Rng, RngStream};
+
+        let root = GlobalRng::new_with_seed(1);
+        let mut app = root.stream(RngStream::Application);
+        let mut net = root.stream(RngStream::Network);
+        let expected: Vec<u64> = (0..10).map(|_| net.gen()).collect();
+
+        let mut net = root.stream(RngStream::Network);
+        for _ in 0..100 {
+            app.gen::<u64>();
+        }
+        let actual: Vec<u64> = (0..10).map(|_| net.gen()).collect();
+        assert_eq!(expected, actual);
+        assert_ne!(expected[0], root.stream(RngStream::Scheduler).gen::<u64>());
+        let mut named = root.stream(RngStream::Named("network"));
+        assert_ne!(expected[0], named.gen::<u64>());
+    }
+
+    #[test]
+    fn application_rand_does_not_perturb_network() {
+        use crate::{
+            net::{network::Payload, Endpoint},
+            time::{Duration, Instant},
+        };
+        use std::net::SocketAddr;
+
+        fn run(app_draws: usize) -> Duration {
+            std::thread::spawn(move || {
+                let runtime = Runtime::with_seed(7);
+                let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+                let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+                let node1 = runtime.create_node().ip(addr1.ip()).build();
+                let node2 = runtime.create_node().ip(addr2.ip()).build();
+
+                let f = node2.spawn(async move {
+                    let ep = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+                    let start = Instant::now();
+                    ep.recv_from(1, &mut []).await.unwrap();
+                    start.elapsed()
+                });
+                node1.spawn(async move {
+                    for _ in 0..app_draws {
+                        super::random::<u64>();
+                    }
+                    let ep = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+                    ep.send_to(addr2, 1, Payload::new_udp(Box::new(Vec::<u8>::new())))
+                        .await
+                        .unwrap();
+                });
+                runtime.block_on(f).unwrap()
+            })
+            .join()
+            .unwrap()
+        }
+
+        assert_eq!(run(0), run(1000));
+    }
 }
@@ -0,0 +1,261 @@
+//! Failure reports.
+//!
+//! When a `#[sim_test]` fails, a [`FailureReport`] is collected from the runtime and written to
+//! disk as a directory of plain text files, so that the state of the simulation at the time of
+//! the failure can be inspected without re-running the test.
+//!
+//! If [tracing](crate::trace) is enabled, the last recorded events are included as well, and
+//! likewise the virtual time [profile](crate::profile) if profiling is enabled, and the dropped
+//! messages if message tracking is enabled, and the recent [log lines](crate::logs) of each node
+//! if log capture is enabled. The panics converted into node crashes by
+//! [`PanicPolicy::CrashNode`](crate::runtime::PanicPolicy::CrashNode) are listed, and the recorded
+//! [external inputs](crate::inputs) are included so that the run can be replayed with them.
+//!
+//! The report is written to `$MSIM_FAILURE_REPORT_DIR/<test>-<seed>` if the environment variable
+//! is set, or to `<temp dir>/msim-failures/<test>-<seed>` otherwise. Set
+//! `MSIM_DISABLE_FAILURE_REPORT` to disable writing reports.
+
+use crate::{
+    context,
+    net::{MsgStatus, NetSim},
+    runtime::Handle,
+};
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Maximum number of trace events included in a report.
+const TRACE_TAIL_LEN: usize = 1000;
+
+/// Maximum number of log lines of each node included in a report.
+const LOG_TAIL_LEN: usize = 200;
+
+/// A snapshot of the simulation state, collected when a test fails.
+#[derive(Debug, Clone)]
+pub struct FailureReport {
+    test_name: Option<String>,
+    seed: u64,
+    elapsed: Duration,
+    sections: Vec<(String, String)>,
+}
+
+impl FailureReport {
+    /// Collect a report from the given runtime handle.
+    pub(crate) fn collect(handle: &Handle) -> Self {
+        // enter the runtime so that message tags are shown with their registered names.
+        let _guard = context::enter(handle.clone());
+        let mut report = FailureReport {
+            test_name: None,
+            seed: handle.seed(),
+            elapsed: handle.time.time_since_clock_base(),
+            sections: vec![],
+        };
+
+        report.add_section("config.txt", format!("{:#?}\n", handle.config()));
+
+        let net = handle.simulator::<NetSim>();
+        let mut nodes = String::new();
+        for info in handle.task.node_infos() {
+            let ip = net
+                .get_ip(info.node())
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+            let state = if info.is_paused() {
+                "paused"
+            } else {
+                "running"
+            };
+            writeln!(
+                nodes,
+                "{}\t{}\t{}\t{}",
+                info.node().0,
+                info.name(),
+                ip,
+                state
+            )
+            .unwrap();
+        }
+        report.add_section("nodes.txt", nodes);
+
+        let mut tasks = String::new();
+        for info in handle
+            .task
+            .node_infos()
+            .into_iter()
+            .chain([handle.task.main_info()])
+        {
+            for task in info.live_tasks() {
+                writeln!(tasks, "{}\t{}\t{task}", info.node().0, info.name()).unwrap();
+            }
+        }
+        if !tasks.is_empty() {
+            report.add_section("tasks.txt", tasks);
+        }
+
+        let panics = handle.node_panics();
+        if !panics.is_empty() {
+            let mut list = String::new();
+            for panic in panics {
+                writeln!(list, "{panic}").unwrap();
+            }
+            report.add_section("panics.txt", list);
+        }
+        report.add_section("network.txt", format!("{:#?}\n", net.stat()));
+
+        let mut dropped = String::new();
+        for (id, record) in net.messages() {
+            if matches!(record.status, MsgStatus::Dropped(_)) {
+                writeln!(dropped, "{id}\t{record}").unwrap();
+            }
+        }
+        if !dropped.is_empty() {
+            report.add_section("dropped.txt", dropped);
+        }
+
+        let profiler = handle.time.profiler();
+        if profiler.is_enabled() {
+            report.add_section("profile.txt", profiler.profile().to_string());
+        }
+
+        let timeline = handle.trace().timeline();
+        let events = timeline.events();
+        if !events.is_empty() {
+            let mut tail = String::new();
+            for event in &events[events.len().saturating_sub(TRACE_TAIL_LEN)..] {
+                writeln!(tail, "{}", event).unwrap();
+            }
+            report.add_section("trace.txt", tail);
+        }
+
+        for logs in handle.logs.nodes() {
+            let lines = logs.lines();
+            let mut tail = String::new();
+            for line in &lines[lines.len().saturating_sub(LOG_TAIL_LEN)..] {
+                writeln!(tail, "{line}").unwrap();
+            }
+            report.add_section(format!("logs-{}.txt", logs.node()), tail);
+        }
+
+        let inputs = handle.inputs.recorded();
+        if !inputs.is_empty() {
+            report.add_section("inputs.txt", inputs.to_string());
+        }
+
+        report
+    }
+
+    /// Set the name of the failed test.
+    pub fn with_test_name(mut self, name: impl Into<String>) -> Self {
+        self.test_name = Some(name.into());
+        self
+    }
+
+    /// Add a section to the report. The section is written to a file named `name`.
+    pub fn add_section(&mut self, name: impl Into<String>, contents: impl Into<String>) {
+        self.sections.push((name.into(), contents.into()));
+    }
+
+    /// Get the contents of a section by name.
+    pub fn section(&self, name: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, contents)| contents.as_str())
+    }
+
+    /// The seed of the failed run.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// A short human readable summary of the failure.
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        if let Some(name) = &self.test_name {
+            writeln!(summary, "test: {}", name).unwrap();
+        }
+        writeln!(summary, "seed: {}", self.seed).unwrap();
+        writeln!(summary, "virtual time elapsed: {:?}", self.elapsed).unwrap();
+        writeln!(
+            summary,
+            "reproduce with: MSIM_TEST_SEED={} cargo test",
+            self.seed
+        )
+        .unwrap();
+        if self.section("inputs.txt").is_some() {
+            writeln!(
+                summary,
+                "replay external inputs with: MSIM_REPLAY_INPUTS=<report dir>"
+            )
+            .unwrap();
+        }
+        summary
+    }
+
+    /// Write the report into `dir`, creating it if necessary.
+    pub fn write_to(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("summary.txt"), self.summary())?;
+        for (name, contents) in &self.sections {
+            fs::write(dir.join(name), contents)?;
+        }
+        Ok(())
+    }
+
+    /// Write the report to the default location, as configured by the environment.
+    ///
+    /// Returns the directory the report was written to, or `None` if reports are disabled.
+    pub fn persist(&self) -> io::Result<Option<PathBuf>> {
+        if std::env::var("MSIM_DISABLE_FAILURE_REPORT").is_ok() {
+            return Ok(None);
+        }
+        let base = std::env::var_os("MSIM_FAILURE_REPORT_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("msim-failures"));
+        let name = self
+            .test_name
+            .as_deref()
+            .unwrap_or("test")
+            .replace("::", "-");
+        let dir = base.join(format!("{}-{}", name, self.seed));
+        self.write_to(&dir)?;
+        Ok(Some(dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn collect_and_write() {
+        let runtime = Runtime::with_seed(42);
+        runtime
+            .create_node()
+            .name("server")
+            .ip([10, 0, 0, 1].into())
+            .build();
+        let report = runtime
+            .failure_report()
+            .with_test_name("report::tests::collect");
+
+        assert_eq!(report.seed(), 42);
+        assert!(report.summary().contains("MSIM_TEST_SEED=42"));
+        let nodes = report.section("nodes.txt").unwrap();
+        assert!(nodes.contains("server\t10.0.0.1\trunning"));
+
+        // syscalls are intercepted on this thread, so write the report from another one.
+        std::thread::spawn(move || {
+            let dir = std::env::temp_dir().join(format!("msim-report-{}", std::process::id()));
+            report.write_to(&dir).unwrap();
+            let summary = std::fs::read_to_string(dir.join("summary.txt")).unwrap();
+            assert!(summary.contains("test: report::tests::collect"));
+            assert!(dir.join("network.txt").exists());
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+//! Rolling restarts of node groups.
+//!
+//! Upgrade procedures restart the nodes of a cluster in batches, waiting for restarted nodes to
+//! become ready before taking down the next batch, like a Kubernetes rolling update. A
+//! [`RollingRestart`] performs such a procedure in virtual time, so that tests can check that a
+//! system stays available while it is upgraded.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::rollout::RollingRestart;
+//!
+//! RollingRestart::new()
+//!     .max_unavailable_percent(25)
+//!     .ready_timeout(Duration::from_secs(60))
+//!     .run_label("app", "validator", |node| wait_until_synced(node))
+//!     .await?;
+//! ```
+
+use crate::{
+    runtime::Handle,
+    task::NodeId,
+    time::{sleep, timeout, Duration},
+};
+use futures::future::join_all;
+use std::{future::Future, io};
+use tracing::*;
+
+/// How many nodes may be down at the same time during a rolling restart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxUnavailable {
+    /// A number of nodes.
+    Count(usize),
+    /// A percentage of the nodes, rounded down.
+    Percent(u32),
+}
+
+impl MaxUnavailable {
+    /// The number of nodes restarted at once, at least one.
+    fn batch_size(&self, nodes: usize) -> usize {
+        let n = match *self {
+            MaxUnavailable::Count(n) => n,
+            MaxUnavailable::Percent(p) => nodes * p as usize / 100,
+        };
+        n.max(1)
+    }
+}
+
+/// A rolling restart of a group of nodes.
+///
+/// Nodes are restarted in batches of at most [`MaxUnavailable`] nodes, ordered by id. After each
+/// batch, the restart waits until the readiness future of every restarted node completes, and
+/// then for [`min_ready`](Self::min_ready), before the next batch.
+#[derive(Debug, Clone)]
+pub struct RollingRestart {
+    max_unavailable: MaxUnavailable,
+    graceful: bool,
+    ready_timeout: Option<Duration>,
+    min_ready: Duration,
+}
+
+impl Default for RollingRestart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RollingRestart {
+    /// Create a rolling restart that restarts one node at a time.
+    pub fn new() -> Self {
+        RollingRestart {
+            max_unavailable: MaxUnavailable::Count(1),
+            graceful: false,
+            ready_timeout: None,
+            min_ready: Duration::ZERO,
+        }
+    }
+
+    /// Restart at most `n` nodes at a time.
+    pub fn max_unavailable(mut self, n: usize) -> Self {
+        self.max_unavailable = MaxUnavailable::Count(n);
+        self
+    }
+
+    /// Restart at most `percent` percent of the nodes at a time, and at least one.
+    pub fn max_unavailable_percent(mut self, percent: u32) -> Self {
+        assert!(percent <= 100, "invalid percentage: {percent}");
+        self.max_unavailable = MaxUnavailable::Percent(percent);
+        self
+    }
+
+    /// Run the stop hooks of the nodes before restarting them, see
+    /// [`Handle::restart_graceful`].
+    pub fn graceful(mut self, graceful: bool) -> Self {
+        self.graceful = graceful;
+        self
+    }
+
+    /// Fail the rolling restart if a restarted node is not ready within `timeout`.
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = Some(timeout);
+        self
+    }
+
+    /// Wait for `duration` after a batch is ready before restarting the next one.
+    pub fn min_ready(mut self, duration: Duration) -> Self {
+        self.min_ready = duration;
+        self
+    }
+
+    /// Restart the nodes with a label, see [`Handle::nodes_with_label`].
+    pub async fn run_label<F, R>(&self, key: &str, value: &str, ready: F) -> io::Result<()>
+    where
+        F: FnMut(NodeId) -> R,
+        R: Future<Output = ()>,
+    {
+        let nodes = Handle::current().nodes_with_label(key, value);
+        self.run(&nodes, ready).await
+    }
+
+    /// Restart `nodes`. `ready` returns a future that completes when a restarted node is ready.
+    ///
+    /// Returns a `TimedOut` error if a node is not ready within the
+    /// [`ready_timeout`](Self::ready_timeout). The remaining nodes are not restarted then.
+    pub async fn run<F, R>(&self, nodes: &[NodeId], mut ready: F) -> io::Result<()>
+    where
+        F: FnMut(NodeId) -> R,
+        R: Future<Output = ()>,
+    {
+        let handle = Handle::current();
+        let mut nodes = nodes.to_vec();
+        nodes.sort();
+        let batch_size = self.max_unavailable.batch_size(nodes.len());
+
+        for batch in nodes.chunks(batch_size) {
+            debug!("rolling restart of {batch:?}");
+            if self.graceful {
+                join_all(batch.iter().map(|id| handle.restart_graceful(*id))).await;
+            } else {
+                for id in batch {
+                    handle.restart(*id);
+                }
+            }
+
+            let waits = batch.iter().map(|id| {
+                let (id, ready) = (*id, ready(*id));
+                async move {
+                    match self.ready_timeout {
+                        Some(limit) => timeout(limit, ready).await.map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                format!("node {id} not ready after {limit:?}"),
+                            )
+                        }),
+                        None => {
+                            ready.await;
+                            Ok(())
+                        }
+                    }
+                }
+            });
+            for res in join_all(waits).await {
+                res?;
+            }
+            sleep(self.min_ready).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, time::Instant};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn rolling_restart() {
+        let runtime = Runtime::new();
+        let starts = Arc::new(Mutex::new(vec![]));
+        for i in 0..5 {
+            let starts = starts.clone();
+            let group = if i < 4 { "a" } else { "b" };
+            runtime
+                .create_node()
+                .label("group", group)
+                .init(move || {
+                    let starts = starts.clone();
+                    async move { starts.lock().unwrap().push(Instant::now()) }
+                })
+                .build();
+        }
+
+        runtime.block_on(async move {
+            let handle = Handle::current();
+            let group = handle.nodes_with_label("group", "a");
+            assert_eq!(group.len(), 4);
+            // discard the initial starts
+            sleep(Duration::from_millis(1)).await;
+            starts.lock().unwrap().clear();
+
+            // two batches of two nodes, ready after 1s
+            let t0 = Instant::now();
+            RollingRestart::new()
+                .max_unavailable_percent(50)
+                .min_ready(Duration::from_secs(1))
+                .run_label("group", "a", |_| sleep(Duration::from_secs(1)))
+                .await
+                .unwrap();
+            let starts: Vec<_> = starts.lock().unwrap().drain(..).collect();
+            assert_eq!(starts.len(), 4);
+            assert!(starts[..2].iter().all(|t| *t - t0 < Duration::from_secs(1)));
+            assert!(starts[2..]
+                .iter()
+                .all(|t| *t - t0 >= Duration::from_secs(2)));
+            assert!(t0.elapsed() >= Duration::from_secs(4));
+
+            // a node that never becomes ready stops the rollout
+            let err = RollingRestart::new()
+                .ready_timeout(Duration::from_secs(5))
+                .run(&group, |_| std::future::pending())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        });
+    }
+}
@@ -0,0 +1,85 @@
+//! Runtime metrics of nodes.
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Counters of the tasks of a node, from when it was last (re)started.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    alive: AtomicUsize,
+    spawned: AtomicU64,
+    polls: AtomicU64,
+    forced_yields: AtomicU64,
+}
+
+impl Counters {
+    /// Count a spawned task, which is alive until the returned guard is dropped.
+    pub fn spawn(self: &Arc<Self>) -> AliveGuard {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        self.alive.fetch_add(1, Ordering::Relaxed);
+        AliveGuard(self.clone())
+    }
+
+    pub fn poll(&self) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn forced_yield(&self) {
+        self.forced_yields.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a task counted as alive, see [`Counters::spawn`].
+pub(crate) struct AliveGuard(Arc<Counters>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.alive.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of the runtime of a node, like `tokio::runtime::RuntimeMetrics`.
+///
+/// Code that tunes itself from the metrics of its tokio runtime reads these under simulation.
+/// Each node runs its tasks as if on a runtime with a single worker, and the metrics cover the
+/// tasks spawned since the node was last (re)started, as a restarted process has a new runtime.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone)]
+pub struct RuntimeMetrics {
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl RuntimeMetrics {
+    /// The number of worker threads, always 1.
+    pub fn num_workers(&self) -> usize {
+        1
+    }
+
+    /// The number of tasks that are alive: spawned, and not yet completed or cancelled.
+    pub fn num_alive_tasks(&self) -> usize {
+        self.counters.alive.load(Ordering::Relaxed)
+    }
+
+    /// The number of tasks spawned.
+    pub fn spawned_tasks_count(&self) -> u64 {
+        self.counters.spawned.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a task was polled by the worker.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `worker` is not 0.
+    pub fn worker_poll_count(&self, worker: usize) -> u64 {
+        assert_eq!(worker, 0, "the runtime of a node has a single worker");
+        self.counters.polls.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a task yielded because it exhausted its budget, see
+    /// [`consume_budget`](crate::task::consume_budget).
+    pub fn budget_forced_yield_count(&self) -> u64 {
+        self.counters.forced_yields.load(Ordering::Relaxed)
+    }
+}
@@ -4,23 +4,92 @@ use super::*;
 use crate::assert_send_sync;
 use crate::context::TaskEnterGuard;
 use crate::net::NetSim;
-use crate::task::{InitFn, JoinHandle, NodeId};
+use crate::task::{HookFn, InitFn, JoinHandle, NodeHooks, NodeId, NodeSpec};
 use ::rand::Rng;
 use std::{
     any::TypeId,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt,
     future::Future,
     io::Write,
     net::IpAddr,
+    ops::Range,
     sync::{Arc, Mutex, RwLock},
     time::Duration,
 };
 use tokio::sync::oneshot;
 
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 pub(crate) mod context;
+mod metrics;
+mod schedule;
+mod supervisor;
+
+pub(crate) use self::metrics::Counters;
+pub use self::metrics::RuntimeMetrics;
+pub use self::schedule::{ScheduleId, ScheduledEvent};
+pub use self::supervisor::{ExitStatus, NodePanic, PanicPolicy, RestartMode, SupervisorPolicy};
+
+/// Default virtual time budget of the lifecycle hooks of a node.
+pub const DEFAULT_HOOK_BUDGET: Duration = Duration::from_secs(10);
+
+/// How a node was terminated by [`Handle::kill_graceful`] or [`Handle::restart_graceful`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// All stop hooks finished within the budget.
+    Graceful,
+    /// The stop hooks exceeded the budget and were cancelled.
+    Forced,
+}
+
+/// The state of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeState {
+    /// The node is running.
+    Running,
+    /// The node is paused.
+    Paused,
+    /// The node terminated or crashed, and was not restarted.
+    Exited(ExitStatus),
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Running => write!(f, "running"),
+            Self::Paused => write!(f, "paused"),
+            Self::Exited(status) => write!(f, "{status}"),
+        }
+    }
+}
+
+/// A description of a node, see [`Handle::nodes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeDescriptor {
+    /// The node.
+    pub id: NodeId,
+    /// The name of the node.
+    pub name: String,
+    /// The ip address of the node, if it has one.
+    pub ip: Option<IpAddr>,
+    /// The labels of the node, in the order they were added.
+    pub labels: Vec<(String, String)>,
+    /// The state of the node.
+    pub state: NodeState,
+    /// Number of restarts by the supervisor of the node.
+    pub restarts: u32,
+}
+
+impl NodeDescriptor {
+    /// Get the value of a label.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
 
 /// The msim runtime.
 ///
@@ -35,6 +104,8 @@ pub(crate) mod context;
 /// [file system]: crate::fs
 pub struct Runtime {
     rand: rand::GlobalRng,
+    /// Random stream shared by all simulators.
+    net_rand: rand::GlobalRng,
     task: task::Executor,
     handle: Handle,
 }
@@ -60,17 +131,44 @@ impl Runtime {
 
     /// Create a new runtime instance with given seed and config.
     pub fn with_seed_and_config(seed: u64, config: SimConfig) -> Self {
-        let mut rand = rand::GlobalRng::new_with_seed(seed);
-        tokio::msim_adapter::util::reset_rng(rand.gen::<u64>());
-        let task = task::Executor::new(rand.clone());
+        let hash_seed = config.hashing.seed.unwrap_or(seed);
+        rand::seed_std_random_state(hash_seed, config.hashing.strict);
+        let rand = rand::GlobalRng::new_with_hash_seed(seed, hash_seed);
+        let mut app_rand = rand.stream(rand::RngStream::Application);
+        tokio::msim_adapter::util::reset_rng(app_rand.gen::<u64>());
+        let mut task = task::Executor::new(rand.stream(rand::RngStream::Scheduler));
+        task.set_progress_reporter(progress::ProgressReporter::from_config(&config.progress));
+        let net_rand = rand.stream(rand::RngStream::Network);
         let handle = Handle {
-            rand: rand.clone(),
+            seed,
+            rand: app_rand,
+            scenario_rand: rand.stream(rand::RngStream::Scenario),
+            script: Default::default(),
             time: task.time_handle().clone(),
             task: task.handle().clone(),
             sims: Default::default(),
+            trace: trace::Trace::new(task.time_handle().clone(), &config.trace),
+            logs: logs::LogStore::new(task.time_handle().clone(), &config.logs),
+            inputs: inputs::InputStore::new(&config.inputs),
+            hosts: host::HostStore::new(seed, &config.host),
+            scheduler: Default::default(),
+            panics: Default::default(),
+            unsupported: Default::default(),
+            crashes: Default::default(),
             config,
         };
-        let rt = Runtime { rand, task, handle };
+        if handle.config.profile.enabled || std::env::var("MSIM_PROFILE").is_ok() {
+            handle.time.profiler().enable();
+        }
+        if handle.config.profile.wall_clock || std::env::var("MSIM_PROFILE_WALL_CLOCK").is_ok() {
+            crate::perf::enable();
+        }
+        let rt = Runtime {
+            rand,
+            net_rand,
+            task,
+            handle,
+        };
         rt.add_simulator::<fs::FsSim>();
         rt.add_simulator::<net::NetSim>();
         intercept::enable_intercepts(true);
@@ -81,7 +179,7 @@ impl Runtime {
     pub fn add_simulator<S: plugin::Simulator>(&self) {
         let mut sims = self.handle.sims.lock().unwrap();
         let sim = Arc::new(S::new(
-            &self.handle.rand,
+            &self.net_rand,
             &self.handle.time,
             &self.handle.config,
         ));
@@ -108,6 +206,26 @@ impl Runtime {
         self.handle.create_node()
     }
 
+    /// Run `f` at a virtual time since the start of the simulation, see [`Handle::schedule_at`].
+    pub fn schedule_at(
+        &self,
+        at: Duration,
+        name: impl Into<String>,
+        f: impl FnOnce() + Send + 'static,
+    ) -> ScheduleId {
+        self.handle.schedule_at(at, name, f)
+    }
+
+    /// Run `f` every `interval`, see [`Handle::schedule_every`].
+    pub fn schedule_every(
+        &self,
+        interval: Duration,
+        name: impl Into<String>,
+        f: impl FnMut() + Send + 'static,
+    ) -> ScheduleId {
+        self.handle.schedule_every(interval, name, f)
+    }
+
     /// Run a future to completion on the runtime. This is the runtime’s entry point.
     ///
     /// This runs the given future on the current thread until it is complete.
@@ -134,7 +252,36 @@ impl Runtime {
     pub fn block_on<F: Future>(&self, future: F) -> F::Output {
         let _guard = crate::context::enter(self.handle.clone());
         crate::time::ensure_clocks();
-        self.task.block_on(future)
+        let panics = self.handle.panics.lock().unwrap().len();
+        let output = self.handle.logs.capture(|| self.task.block_on(future));
+        let panics = &self.handle.panics.lock().unwrap()[panics..];
+        if !panics.is_empty() {
+            let list: Vec<_> = panics.iter().map(|panic| format!("  {panic}")).collect();
+            warn!(
+                "{} node panics were converted into crashes:\n{}",
+                panics.len(),
+                list.join("\n")
+            );
+        }
+        output
+    }
+
+    /// Run the simulation until the earliest pending timer expires, and return its deadline,
+    /// see [`TimeHandle::advance_to_next_timer`](time::TimeHandle::advance_to_next_timer).
+    ///
+    /// Useful to step through TTL and lease expirations without guessing sleep durations.
+    pub fn advance_to_next_timer(&self) -> Option<time::Instant> {
+        self.block_on(self.handle.time.advance_to_next_timer())
+    }
+
+    /// List the pending timers of all nodes, earliest first.
+    pub fn upcoming_timers(&self) -> Vec<time::PendingTimer> {
+        self.handle.time.upcoming_timers()
+    }
+
+    /// Describe all nodes of the simulation, ordered by id, see [`Handle::nodes`].
+    pub fn nodes(&self) -> impl Iterator<Item = NodeDescriptor> {
+        self.handle.nodes()
     }
 
     /// Set a time limit of the execution.
@@ -157,6 +304,14 @@ impl Runtime {
         self.task.set_time_limit(limit);
     }
 
+    /// Periodically report the progress of the run, see the [`progress`](crate::progress)
+    /// module.
+    ///
+    /// Replaces the reporter configured with [`ProgressConfig`](crate::progress::ProgressConfig).
+    pub fn set_progress_reporter(&mut self, reporter: progress::ProgressReporter) {
+        self.task.set_progress_reporter(Some(reporter));
+    }
+
     /// Enable determinism check during the simulation.
     ///
     /// # Example
@@ -201,6 +356,32 @@ impl Runtime {
     pub fn take_rand_log(self) -> Option<rand::Log> {
         self.rand.take_log()
     }
+
+    /// Collect a [`FailureReport`] describing the current state of the simulation.
+    ///
+    /// This is called by `#[sim_test]` when a test panics, but can also be used to snapshot the
+    /// state of a run for debugging.
+    ///
+    /// [`FailureReport`]: crate::report::FailureReport
+    pub fn failure_report(&self) -> report::FailureReport {
+        report::FailureReport::collect(&self.handle)
+    }
+
+    /// Returns the virtual time profile of the run so far, if profiling is enabled.
+    ///
+    /// See the [`profile`](crate::profile) module.
+    pub fn profile(&self) -> Option<crate::profile::Profile> {
+        let profiler = self.handle.time.profiler();
+        profiler.is_enabled().then(|| profiler.profile())
+    }
+
+    /// Returns the real time spent in the simulator so far, if wall clock profiling is enabled
+    /// on the current thread.
+    ///
+    /// See the [`perf`](crate::perf) module.
+    pub fn perf_report(&self) -> Option<crate::perf::PerfReport> {
+        crate::perf::report()
+    }
 }
 
 /// Start a watch dog thread that will kill the test process in case of a deadlock.
@@ -279,10 +460,26 @@ fn start_watchdog_with(
 /// Supervisor handle to the runtime.
 #[derive(Clone)]
 pub struct Handle {
+    pub(crate) seed: u64,
     pub(crate) rand: rand::GlobalRng,
+    pub(crate) scenario_rand: rand::GlobalRng,
+    /// The choices to make while exploring, see [`crate::explore`].
+    pub(crate) script: Arc<Mutex<Option<crate::explore::Script>>>,
     pub(crate) time: time::TimeHandle,
     pub(crate) task: task::TaskHandle,
     pub(crate) sims: Arc<Mutex<HashMap<TypeId, Arc<dyn plugin::Simulator>>>>,
+    pub(crate) trace: trace::Trace,
+    pub(crate) logs: logs::LogStore,
+    pub(crate) inputs: inputs::InputStore,
+    /// The characteristics of the hosts of the nodes, see [`crate::host`].
+    pub(crate) hosts: host::HostStore,
+    pub(crate) scheduler: Arc<Mutex<schedule::Scheduler>>,
+    /// Panics converted into node crashes, see [`PanicPolicy::CrashNode`].
+    pub(crate) panics: Arc<Mutex<Vec<NodePanic>>>,
+    /// Unsupported library calls that failed, see [`UnsupportedPolicy::Lenient`].
+    pub(crate) unsupported: Arc<Mutex<Vec<Unsupported>>>,
+    /// Nodes killed or restarted by the test, with how many times, see [`Handle::crashes`].
+    pub(crate) crashes: Arc<Mutex<BTreeMap<NodeId, u64>>>,
     pub(crate) config: SimConfig,
 }
 
@@ -310,6 +507,22 @@ impl Handle {
     /// - All tasks spawned on this node will be killed immediately.
     /// - All data that has not been flushed to the disk will be lost.
     pub fn kill(&self, id: NodeId) {
+        self.count_crash(id);
+        self.kill_node(id);
+    }
+
+    /// Count a crash of the node, unless it already exited, e.g. when a killed node is
+    /// restarted.
+    fn count_crash(&self, id: NodeId) {
+        if self.task.exit_status(id).is_none() {
+            *self.crashes.lock().unwrap().entry(id).or_default() += 1;
+        }
+    }
+
+    /// Kill a node without counting it as a crash, when it exited or panicked.
+    fn kill_node(&self, id: NodeId) {
+        self.trace.record(trace::EventKind::NodeKill(id));
+        self.task.set_exit_status(id, ExitStatus::Killed);
         self.task.kill(id);
         for sim in self.sims.lock().unwrap().values() {
             sim.reset_node(id);
@@ -318,16 +531,169 @@ impl Handle {
 
     /// Restart a node。
     pub fn restart(&self, id: NodeId) {
+        self.count_crash(id);
+        self.restart_node(id);
+    }
+
+    /// Restart a node without counting it as a crash, when its supervisor restarts it.
+    fn restart_node(&self, id: NodeId) {
+        self.trace.record(trace::EventKind::NodeRestart(id));
         self.task.restart(id);
         for sim in self.sims.lock().unwrap().values() {
             sim.reset_node(id);
         }
+        if self.is_ready(id) {
+            self.trace.record(trace::EventKind::NodeReady(id));
+        }
+    }
+
+    /// Mark a node as ready.
+    fn set_ready(&self, id: NodeId) {
+        if !self.is_ready(id) {
+            self.trace.record(trace::EventKind::NodeReady(id));
+        }
+        self.task.set_ready(id, true);
+    }
+
+    /// Kill a node after running its stop hooks.
+    ///
+    /// The node is killed once all hooks finished, or when they exceed the hook budget of the
+    /// node. Must not be called from within the node itself.
+    pub async fn kill_graceful(&self, id: NodeId) -> Termination {
+        let termination = self.stop(id).await;
+        self.kill(id);
+        termination
+    }
+
+    /// Returns `true` if a node is ready, see [`NodeBuilder::ready_when`].
+    pub fn is_ready(&self, id: NodeId) -> bool {
+        self.task
+            .watch_ready(id)
+            .is_some_and(|ready| *ready.borrow())
+    }
+
+    /// Wait until a node is ready, see [`NodeBuilder::ready_when`].
+    ///
+    /// Waits forever for a node that does not exist.
+    pub async fn wait_ready(&self, id: NodeId) {
+        let Some(mut ready) = self.task.watch_ready(id) else {
+            return std::future::pending().await;
+        };
+        if ready.wait_for(|ready| *ready).await.is_err() {
+            // the node was deleted
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Restart a node after running its stop hooks, see [`Handle::kill_graceful`].
+    pub async fn restart_graceful(&self, id: NodeId) -> Termination {
+        let termination = self.stop(id).await;
+        self.restart(id);
+        termination
+    }
+
+    /// Get how a node terminated, if it has terminated since it was last (re)started.
+    ///
+    /// The initial task of a node returning gives it exit code 0, but only supervised nodes are
+    /// stopped when it does.
+    pub fn exit_status(&self, id: NodeId) -> Option<ExitStatus> {
+        self.task.exit_status(id)
+    }
+
+    /// Record that a node exited, and apply its supervisor policy.
+    pub(crate) fn node_exited(&self, id: NodeId, status: ExitStatus) {
+        if !self.task.set_exit_status(id, status.clone()) {
+            return;
+        }
+        self.trace.record(trace::EventKind::NodeExit {
+            node: id,
+            status: status.clone(),
+        });
+        let Some((policy, restarts)) = self.task.supervisor(id) else {
+            return;
+        };
+        self.kill_node(id);
+        if !policy.should_restart(&status) {
+            return;
+        }
+        let Some(backoff) = policy.backoff(restarts) else {
+            warn!("node {id} exited ({status}), giving up after {restarts} restarts");
+            self.trace.record(trace::EventKind::NodeGiveUp(id));
+            return;
+        };
+        info!("node {id} exited ({status}), restarting in {backoff:?}");
+        self.task.count_restart(id);
+        self.task.spawn_on_main(async move {
+            crate::time::sleep(backoff).await;
+            let handle = Handle::current();
+            // the node may have been restarted or deleted in the meantime.
+            if handle.exit_status(id).is_some() {
+                handle.restart_node(id);
+            }
+        });
+    }
+
+    async fn stop(&self, id: NodeId) -> Termination {
+        if self.task.stop(id).await {
+            Termination::Graceful
+        } else {
+            Termination::Forced
+        }
+    }
+
+    /// Record a panic of a task of a node, and crash the node, see [`PanicPolicy::CrashNode`].
+    pub(crate) fn node_panicked(&self, id: NodeId, message: String) {
+        warn!("node {id} crashed: a task panicked: {message}");
+        let name = (self.task.node_infos().into_iter())
+            .find(|info| info.node() == id)
+            .map_or_else(String::new, |info| info.name());
+        self.panics.lock().unwrap().push(NodePanic {
+            node: id,
+            name,
+            elapsed: self.time.time_since_clock_base(),
+            message: message.clone(),
+        });
+        let supervised = self.task.supervisor(id).is_some();
+        self.node_exited(id, ExitStatus::Panicked(message));
+        // supervised nodes are killed by their supervisor.
+        if !supervised {
+            self.kill_node(id);
+        }
+    }
+
+    /// The runtime metrics of a node, see [`RuntimeMetrics`].
+    pub fn metrics(&self, id: NodeId) -> Option<RuntimeMetrics> {
+        Some(RuntimeMetrics {
+            counters: self.task.counters(id)?,
+        })
+    }
+
+    /// The panics converted into node crashes so far, see [`PanicPolicy::CrashNode`].
+    pub fn node_panics(&self) -> Vec<NodePanic> {
+        self.panics.lock().unwrap().clone()
+    }
+
+    /// The unsupported library calls that failed so far, see [`UnsupportedPolicy::Lenient`].
+    pub fn unsupported_calls(&self) -> Vec<Unsupported> {
+        self.unsupported.lock().unwrap().clone()
+    }
+
+    /// The nodes crashed so far by [`kill`](Self::kill) or [`restart`](Self::restart), with how
+    /// many times.
+    ///
+    /// Nodes killed because they exited or panicked are not counted, and neither is restarting
+    /// a node that is not running.
+    pub fn crashes(&self) -> BTreeMap<NodeId, u64> {
+        self.crashes.lock().unwrap().clone()
     }
 
     /// Kill all tasks and delete the node.
     pub fn delete_node(&self, id: NodeId) {
         debug!("delete_node {id}");
+        self.trace.record(trace::EventKind::NodeDelete(id));
         self.task.delete_node(id);
+        self.time.remove_node_clock(id);
+        self.hosts.delete_node(id);
         for sim in self.sims.lock().unwrap().values() {
             sim.delete_node(id);
         }
@@ -335,14 +701,88 @@ impl Handle {
 
     /// Pause the execution of a node.
     pub fn pause(&self, id: NodeId) {
+        self.trace.record(trace::EventKind::NodePause(id));
         self.task.pause(id);
     }
 
     /// Resume the execution of a node.
+    ///
+    /// A node paused by a fault, e.g. a [`LatencySpike`](crate::fault::LatencySpike), stays
+    /// paused until the fault ends.
     pub fn resume(&self, id: NodeId) {
+        self.trace.record(trace::EventKind::NodeResume(id));
         self.task.resume(id);
     }
 
+    /// Pause a node for a fault, until [`resume_for_fault`](Self::resume_for_fault). Pauses of
+    /// faults and of [`pause`](Self::pause) overlap without resuming each other.
+    pub(crate) fn pause_for_fault(&self, id: NodeId) {
+        if self.task.pause_for_fault(id) {
+            self.trace.record(trace::EventKind::NodePause(id));
+        }
+    }
+
+    /// End a pause of [`pause_for_fault`](Self::pause_for_fault).
+    pub(crate) fn resume_for_fault(&self, id: NodeId) {
+        if self.task.resume_for_fault(id) {
+            self.trace.record(trace::EventKind::NodeResume(id));
+        }
+    }
+
+    /// Pin the order in which ready tasks are polled during a window of virtual time since the
+    /// start of the simulation, to reproduce a suspected interleaving without searching seeds.
+    ///
+    /// Each entry of `order` is either `node/task`, naming a task spawned with
+    /// [`spawn_named`](crate::task::spawn_named) on the node of that name, or `node`, for every
+    /// task of the node. Whenever the executor picks a ready task named by an entry, it polls
+    /// instead the ready task of the earliest entry, if any. Other tasks are scheduled at random
+    /// as usual. When windows overlap, a task is ordered by the first rule that names it. Node
+    /// names are resolved when the priority is pinned, so nodes created afterwards are not
+    /// ordered, and rules are dropped once their window has passed.
+    ///
+    /// ```ignore
+    /// // always poll node3/consensus before node3/network between 10s and 12s
+    /// handle.pin_priority(
+    ///     Duration::from_secs(10)..Duration::from_secs(12),
+    ///     ["node3/consensus", "node3/network"],
+    /// );
+    /// ```
+    pub fn pin_priority<S: Into<String>>(
+        &self,
+        window: Range<Duration>,
+        order: impl IntoIterator<Item = S>,
+    ) {
+        let order = order.into_iter().map(Into::into).collect();
+        self.task.pin_priority(window, order);
+    }
+
+    /// Remove all priorities pinned by [`pin_priority`](Self::pin_priority).
+    pub fn clear_priorities(&self) {
+        self.task.clear_priorities();
+    }
+
+    /// Record tasks and timers that wait longer than `threshold` in virtual time.
+    ///
+    /// A task starves when it is polled longer than `threshold` after it was woken, and a timer
+    /// when it fires longer than `threshold` after it expired. Each starvation is logged as a
+    /// warning and recorded with the tasks polled meanwhile, see [`starvations`](Self::starvations).
+    /// Starting an audit again clears the recorded starvations.
+    pub fn audit_starvation(&self, threshold: Duration) {
+        self.task.audit_starvation(threshold);
+    }
+
+    /// The starvations recorded since [`audit_starvation`](Self::audit_starvation) was called.
+    pub fn starvations(&self) -> Vec<crate::task::Starvation> {
+        self.task.starvations()
+    }
+
+    /// Set the clock personality of a node, see [`ClockPersonality`](time::ClockPersonality).
+    ///
+    /// The personality is kept when the node restarts, as it belongs to the host.
+    pub fn set_clock(&self, id: NodeId, personality: time::ClockPersonality) {
+        self.time.set_node_clock(id, personality, self.seed);
+    }
+
     /// Create a node which will be bound to the specified address.
     pub fn create_node(&self) -> NodeBuilder<'_> {
         NodeBuilder::new(self)
@@ -350,7 +790,27 @@ impl Handle {
 
     /// Return a handle of the specified node.
     pub fn get_node(&self, id: NodeId) -> Option<NodeHandle> {
-        self.task.get_node(id).map(|task| NodeHandle { task })
+        self.task.get_node(id).map(|task| NodeHandle {
+            task,
+            logs: self.logs.clone(),
+        })
+    }
+
+    /// Return the nodes with a label, ordered by id.
+    pub fn nodes_with_label(&self, key: &str, value: &str) -> Vec<NodeId> {
+        self.task.nodes_with_label(key, value)
+    }
+
+    /// Describe all nodes of the simulation, ordered by id. The main node is not included.
+    ///
+    /// This is a snapshot: nodes created or changed afterwards are not reflected.
+    pub fn nodes(&self) -> impl Iterator<Item = NodeDescriptor> {
+        let net = self.simulator::<NetSim>();
+        let mut nodes = self.task.describe_nodes();
+        for node in &mut nodes {
+            node.ip = net.get_ip(node.id);
+        }
+        nodes.into_iter()
     }
 
     /// Mark this Handle as the currently active one
@@ -362,6 +822,31 @@ impl Handle {
     pub fn time(&self) -> &time::TimeHandle {
         &self.time
     }
+
+    /// Get the seed the runtime was created with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Get the configuration the runtime was created with.
+    pub fn config(&self) -> &SimConfig {
+        &self.config
+    }
+
+    /// Get the event recorder of the runtime.
+    pub fn trace(&self) -> &trace::Trace {
+        &self.trace
+    }
+
+    /// Get a registered simulator.
+    pub(crate) fn simulator<S: plugin::Simulator>(&self) -> Arc<S> {
+        let sims = self.sims.lock().unwrap();
+        sims[&TypeId::of::<S>()]
+            .clone()
+            .downcast_arc()
+            .ok()
+            .unwrap()
+    }
 }
 
 /// Guard for entering handle
@@ -373,6 +858,14 @@ pub struct NodeBuilder<'a> {
     name: Option<String>,
     ip: Option<IpAddr>,
     init: Option<InitFn>,
+    hooks: NodeHooks,
+    supervisor: Option<SupervisorPolicy>,
+    labels: Vec<(String, String)>,
+    flags: BTreeMap<String, String>,
+    depends_on: Vec<NodeId>,
+    ready_when: Option<HookFn>,
+    clock: Option<time::ClockPersonality>,
+    host: host::HostInfo,
 }
 
 impl<'a> NodeBuilder<'a> {
@@ -382,12 +875,25 @@ impl<'a> NodeBuilder<'a> {
             name: None,
             ip: None,
             init: None,
+            hooks: NodeHooks {
+                on_start: vec![],
+                on_stop: vec![],
+                budget: DEFAULT_HOOK_BUDGET,
+            },
+            supervisor: None,
+            labels: vec![],
+            flags: BTreeMap::new(),
+            depends_on: vec![],
+            ready_when: None,
+            clock: None,
+            host: Default::default(),
         }
     }
 
     /// Names the node.
     ///
-    /// The default name is node ID.
+    /// The default name is node ID. The node reports its name as its hostname, see
+    /// [`crate::host`].
     pub fn name(mut self, name: impl Into<String>) -> Self {
         self.name = Some(name.into());
         self
@@ -401,11 +907,113 @@ impl<'a> NodeBuilder<'a> {
         F: Future + 'static,
     {
         self.init = Some(Arc::new(move |handle| {
-            handle.spawn_local(future());
+            handle.spawn_local(task::run_initial_task(handle.id(), future()));
         }));
         self
     }
 
+    /// Add a hook that runs in the node right after the initial task is spawned, when the node
+    /// is created and every time it is restarted.
+    ///
+    /// The hook is cancelled if it exceeds the hook budget of the node.
+    pub fn on_start<F>(mut self, hook: impl Fn() -> F + Send + Sync + 'static) -> Self
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.hooks.on_start.push(box_hook(hook));
+        self
+    }
+
+    /// Add a hook that runs in the node before it is killed or restarted with
+    /// [`Handle::kill_graceful`] or [`Handle::restart_graceful`], e.g. to flush state.
+    ///
+    /// Hooks do not run when the node is killed otherwise.
+    pub fn on_stop<F>(mut self, hook: impl Fn() -> F + Send + Sync + 'static) -> Self
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.hooks.on_stop.push(box_hook(hook));
+        self
+    }
+
+    /// Supervise the node: it stops when its initial task returns or panics, and is restarted
+    /// according to `policy`.
+    ///
+    /// Without a supervisor, a panic of the initial task fails the simulation.
+    pub fn supervisor(mut self, policy: SupervisorPolicy) -> Self {
+        self.supervisor = Some(policy);
+        self
+    }
+
+    /// Set the virtual time budget of the lifecycle hooks of the node.
+    ///
+    /// The default is [`DEFAULT_HOOK_BUDGET`].
+    pub fn hook_budget(mut self, budget: Duration) -> Self {
+        self.hooks.budget = budget;
+        self
+    }
+
+    /// Add a label to the node, to select it as part of a group with
+    /// [`Handle::nodes_with_label`].
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set a behavior flag of the node, see [`node_config`].
+    pub fn config(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.flags.insert(key.into(), value.into());
+        self
+    }
+
+    /// Start the node only once `node` is ready, see [`NodeBuilder::ready_when`].
+    ///
+    /// The initial task of the node is held back until all its dependencies are ready, when the
+    /// node is created and every time it is restarted. This replaces barriers and sleeps that
+    /// make sure servers are listening before clients connect.
+    pub fn depends_on(mut self, node: NodeId) -> Self {
+        self.depends_on.push(node);
+        self
+    }
+
+    /// Set the readiness probe of the node.
+    ///
+    /// The probe runs in the node after the initial task is spawned, and the node is ready when
+    /// it completes, or when the node calls [`notify_ready`]. A node without a probe and without
+    /// dependencies is ready as soon as it starts. A node is not ready after it is killed, until
+    /// it is restarted and ready again.
+    pub fn ready_when<F>(mut self, probe: impl Fn() -> F + Send + Sync + 'static) -> Self
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.ready_when = Some(box_hook(probe));
+        self
+    }
+
+    /// Place the node in a failure domain, see [`FailureDomain`](crate::fault::FailureDomain).
+    pub fn failure_domain(self, domain: crate::fault::FailureDomain) -> Self {
+        let key = domain.level().label();
+        self.label(key, domain.name())
+    }
+
+    /// Set the clock personality of the node, see [`Handle::set_clock`].
+    pub fn clock(mut self, personality: time::ClockPersonality) -> Self {
+        self.clock = Some(personality);
+        self
+    }
+
+    /// Set the characteristics the node reports about its host, see [`crate::host`].
+    pub fn host(mut self, info: host::HostInfo) -> Self {
+        self.host = info;
+        self
+    }
+
+    /// Set the number of processors the node reports, see [`crate::host`].
+    pub fn cpus(mut self, cpus: usize) -> Self {
+        self.host.cpus = Some(cpus);
+        self
+    }
+
     /// Set one IP address of the node.
     pub fn ip(mut self, ip: IpAddr) -> Self {
         self.ip = Some(ip);
@@ -414,7 +1022,43 @@ impl<'a> NodeBuilder<'a> {
 
     /// Build a node.
     pub fn build(self) -> NodeHandle {
-        let task = self.handle.task.create_node(self.name, self.init);
+        let auto_ready = self.depends_on.is_empty() && self.ready_when.is_none();
+        let mut hooks = self.hooks;
+        let init = if auto_ready {
+            self.init
+        } else {
+            // the start hooks run with the initial task, once the dependencies are ready.
+            let start = NodeHooks {
+                on_start: std::mem::take(&mut hooks.on_start),
+                on_stop: vec![],
+                budget: hooks.budget,
+            };
+            Some(staged_init(
+                self.init,
+                start,
+                self.depends_on,
+                self.ready_when,
+            ))
+        };
+        let given_name = self.name.clone();
+        let task = self.handle.task.create_node(NodeSpec {
+            name: self.name,
+            init,
+            hooks,
+            supervisor: self.supervisor,
+            labels: self.labels,
+            flags: self.flags,
+            auto_ready,
+        });
+        self.handle
+            .trace
+            .record(trace::EventKind::NodeCreate(task.id()));
+        if let Some(personality) = self.clock {
+            self.handle.set_clock(task.id(), personality);
+        }
+        self.handle
+            .hosts
+            .create_node(task.id(), given_name, task.name(), self.host);
         for sim in self.handle.sims.lock().unwrap().values() {
             sim.create_node(task.id());
             if let Some(ip) = self.ip {
@@ -423,10 +1067,55 @@ impl<'a> NodeBuilder<'a> {
                 }
             }
         }
-        NodeHandle { task }
+        if auto_ready {
+            self.handle
+                .trace
+                .record(trace::EventKind::NodeReady(task.id()));
+        }
+        NodeHandle {
+            task,
+            logs: self.handle.logs.clone(),
+        }
     }
 }
 
+/// Wrap the initial task and the start hooks of a node to wait for its dependencies, and to run
+/// its readiness probe.
+fn staged_init(
+    init: Option<InitFn>,
+    start: NodeHooks,
+    depends_on: Vec<NodeId>,
+    probe: Option<HookFn>,
+) -> InitFn {
+    Arc::new(move |handle| {
+        let (init, start) = (init.clone(), start.clone());
+        let (depends_on, probe) = (depends_on.clone(), probe.clone());
+        let node = handle.clone();
+        handle.spawn_local(async move {
+            let runtime = Handle::current();
+            for dep in depends_on {
+                runtime.wait_ready(dep).await;
+            }
+            debug!("dependencies of node {} are ready", node.id());
+            if let Some(init) = init {
+                init(&node);
+            }
+            start.start(&node);
+            if let Some(probe) = probe {
+                probe().await;
+            }
+            runtime.set_ready(node.id());
+        });
+    })
+}
+
+fn box_hook<F>(hook: impl Fn() -> F + Send + Sync + 'static) -> HookFn
+where
+    F: Future<Output = ()> + 'static,
+{
+    Arc::new(move || Box::pin(hook()))
+}
+
 /// Guard for entering a node context.
 #[must_use]
 pub struct NodeEnterGuard(#[allow(unused)] TaskEnterGuard);
@@ -435,6 +1124,7 @@ pub struct NodeEnterGuard(#[allow(unused)] TaskEnterGuard);
 #[derive(Clone)]
 pub struct NodeHandle {
     task: task::TaskNodeHandle,
+    logs: logs::LogStore,
 }
 
 impl NodeHandle {
@@ -446,7 +1136,8 @@ impl NodeHandle {
     /// Get handle to current Node if there is one
     pub fn try_current() -> Option<Self> {
         let task = task::TaskNodeHandle::try_current()?;
-        Some(Self { task })
+        let logs = context::try_current(|h| h.logs.clone())?;
+        Some(Self { task, logs })
     }
 
     /// Returns the node ID.
@@ -455,6 +1146,7 @@ impl NodeHandle {
     }
 
     /// Spawn a future onto the runtime.
+    #[track_caller]
     pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
     where
         F: Future + 'static,
@@ -473,6 +1165,7 @@ impl NodeHandle {
     }
 
     /// Spawn a on the local thread.
+    #[track_caller]
     pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
     where
         F: Future + 'static,
@@ -490,6 +1183,20 @@ impl NodeHandle {
         Ok(())
     }
 
+    /// The runtime metrics of the node, see [`RuntimeMetrics`].
+    pub fn metrics(&self) -> RuntimeMetrics {
+        context::try_current(|h| h.metrics(self.id()))
+            .flatten()
+            .unwrap_or_else(|| RuntimeMetrics {
+                counters: self.task.counters(),
+            })
+    }
+
+    /// Get the log lines captured from the node, see the [`logs`](crate::logs) module.
+    pub fn logs(&self) -> logs::Logs {
+        self.logs.node(self.id())
+    }
+
     /// Get ip of node
     pub fn ip(&self) -> Option<IpAddr> {
         let net = plugin::simulator::<NetSim>();
@@ -528,6 +1235,15 @@ impl Future for NodeHandle {
     }
 }
 
+/// Mark the current node as ready, see [`NodeBuilder::ready_when`].
+///
+/// Does nothing outside of a node.
+pub fn notify_ready() {
+    if let Some(task) = context::try_current_task() {
+        Handle::current().set_ready(task.node());
+    }
+}
+
 /// Checks if current task is killed (or non-existent) - only intended to be used by
 /// return_if_killed!
 pub fn is_current_task_killed() -> bool {
@@ -565,7 +1281,7 @@ pub fn init_logger() {
 
 #[cfg(test)]
 mod tests {
-    use super::start_watchdog_with;
+    use super::{start_watchdog_with, ExitStatus, Handle, NodeState};
     use crate::{runtime::Runtime, time};
     use std::{
         sync::{Arc, RwLock},
@@ -610,4 +1326,201 @@ mod tests {
         // verify that the deadline was reset after we came back after the timer reset
         assert!(now.elapsed() > Duration::from_millis(1500));
     }
+
+    #[test]
+    fn nodes() {
+        let runtime = Runtime::new();
+        let server = runtime
+            .create_node()
+            .name("server")
+            .ip("10.0.0.1".parse().unwrap())
+            .label("role", "server")
+            .build();
+        let client = runtime.create_node().name("client").build();
+        let nodes: Vec<_> = runtime.nodes().collect();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].id, server.id());
+        assert_eq!(nodes[0].name, "server");
+        assert_eq!(nodes[0].ip, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(nodes[0].label("role"), Some("server"));
+        assert_eq!(nodes[1].ip, None);
+        assert!(nodes.iter().all(|node| node.state == NodeState::Running));
+
+        runtime.block_on(async move {
+            let handle = Handle::current();
+            handle.pause(server.id());
+            handle.kill(client.id());
+            let states: Vec<_> = handle.nodes().map(|node| node.state).collect();
+            assert_eq!(
+                states,
+                [NodeState::Paused, NodeState::Exited(ExitStatus::Killed)]
+            );
+        });
+    }
+
+    #[test]
+    fn metrics() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let id = node.id();
+        let worker = node.spawn(async {
+            for _ in 0..300 {
+                crate::task::consume_budget().await;
+            }
+        });
+        node.spawn(std::future::pending::<()>());
+
+        runtime.block_on(async move {
+            worker.await.unwrap();
+            let metrics = node.metrics();
+            assert_eq!(metrics.num_workers(), 1);
+            assert_eq!(metrics.spawned_tasks_count(), 2);
+            assert_eq!(metrics.num_alive_tasks(), 1);
+            // 128 units per poll
+            assert_eq!(metrics.budget_forced_yield_count(), 2);
+            assert!(metrics.worker_poll_count(0) >= 3);
+
+            // a restarted node has a new runtime
+            let handle = Handle::current();
+            handle.kill(id);
+            time::sleep(Duration::from_millis(1)).await;
+            assert_eq!(metrics.num_alive_tasks(), 0);
+            handle.restart(id);
+            let metrics = handle.metrics(id).unwrap();
+            assert_eq!(metrics.spawned_tasks_count(), 0);
+            assert_eq!(metrics.budget_forced_yield_count(), 0);
+        });
+    }
+
+    #[test]
+    fn startup_order() {
+        let runtime = Runtime::new();
+        let starts = Arc::new(RwLock::new(vec![]));
+        let record = |name: &'static str| {
+            let starts = starts.clone();
+            move || {
+                let starts = starts.clone();
+                async move {
+                    starts.write().unwrap().push((name, time::Instant::now()));
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        // the server takes 5s to bind
+        let server = runtime
+            .create_node()
+            .init(record("server"))
+            .ready_when(|| time::sleep(Duration::from_secs(5)))
+            .build();
+        // the database notifies readiness itself after 2s
+        let db = runtime
+            .create_node()
+            .init(|| async {
+                time::sleep(Duration::from_secs(2)).await;
+                super::notify_ready();
+                std::future::pending::<()>().await;
+            })
+            .ready_when(std::future::pending)
+            .build();
+        let hook_start = Arc::new(RwLock::new(None));
+        let hook_start_ = hook_start.clone();
+        let client = runtime
+            .create_node()
+            .init(record("client"))
+            .on_start(move || {
+                let hook_start = hook_start_.clone();
+                async move {
+                    *hook_start.write().unwrap() = Some(time::Instant::now());
+                }
+            })
+            .depends_on(server.id())
+            .depends_on(db.id())
+            .build();
+
+        runtime.block_on(async move {
+            let handle = Handle::current();
+            let t0 = time::Instant::now();
+            handle.wait_ready(client.id()).await;
+            assert!(t0.elapsed() >= Duration::from_secs(5));
+            let names: Vec<_> = starts.read().unwrap().iter().map(|(n, _)| *n).collect();
+            assert_eq!(names, ["server", "client"]);
+            let client_start = starts.read().unwrap()[1].1;
+            assert!(client_start - t0 >= Duration::from_secs(5));
+            // the start hooks wait for the dependencies too.
+            time::sleep(Duration::from_millis(1)).await;
+            let hook_start = hook_start.read().unwrap().unwrap();
+            assert!(hook_start - t0 >= Duration::from_secs(5));
+            assert!(handle.is_ready(db.id()));
+
+            // a restarted node is not ready until its probe completes again
+            handle.restart(server.id());
+            assert!(!handle.is_ready(server.id()));
+            time::sleep(Duration::from_secs(6)).await;
+            assert!(handle.is_ready(server.id()));
+        });
+    }
+
+    /// Counts the bytes allocated by each thread, to measure the memory used by a runtime
+    /// whatever the other tests running in parallel allocate.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATED: std::cell::Cell<isize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn count(delta: isize) {
+        // the counter may be gone while the thread exits.
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + delta));
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            count(layout.size() as isize);
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            count(-(layout.size() as isize));
+            std::alloc::System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: std::alloc::Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            count(new_size as isize - layout.size() as isize);
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// The memory used by a large cluster of idle nodes, each with a socket and a task waiting
+    /// on it.
+    #[test]
+    fn idle_nodes_footprint() {
+        const NODES: u32 = 2_000;
+        let runtime = Runtime::new();
+        let before = ALLOCATED.with(|allocated| allocated.get());
+        let nodes: Vec<_> = (0..NODES)
+            .map(|i| {
+                let ip = std::net::Ipv4Addr::from(0x0a00_0001 + i);
+                let node = runtime.create_node().ip(ip.into()).build();
+                node.spawn(async move {
+                    let addr = std::net::SocketAddr::new(ip.into(), 1);
+                    let ep = crate::net::Endpoint::bind(libc::SOCK_DGRAM, addr).await;
+                    let mut buf = [0; 8];
+                    let _ = ep.unwrap().recv_from(1, &mut buf).await;
+                });
+                node
+            })
+            .collect();
+        runtime.block_on(async { time::sleep(Duration::from_secs(1)).await });
+        let used = ALLOCATED.with(|allocated| allocated.get()) - before;
+        let per_node = used / nodes.len() as isize;
+        assert!(per_node < 5 << 10, "an idle node uses {per_node} bytes");
+    }
 }
@@ -0,0 +1,209 @@
+//! Harness events scheduled at virtual instants, see [`Handle::schedule_at`].
+
+use super::Handle;
+use crate::{task::NodeId, trace::EventKind};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The id of an event scheduled with [`Handle::schedule_at`] or [`Handle::schedule_every`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScheduleId(u64);
+
+impl fmt::Display for ScheduleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sched#{}", self.0)
+    }
+}
+
+/// An event waiting to run, see [`Handle::scheduled`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledEvent {
+    /// The id of the event.
+    pub id: ScheduleId,
+    /// The name of the event, as recorded in the trace.
+    pub name: String,
+    /// Virtual time since the start of the simulation of the next run.
+    pub next: Duration,
+    /// The interval between runs of a periodic event.
+    pub every: Option<Duration>,
+    /// The number of times the event ran.
+    pub runs: u64,
+}
+
+type Job = Arc<Mutex<Box<dyn FnMut() + Send>>>;
+
+/// The events scheduled on a runtime.
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    next_id: u64,
+    pending: BTreeMap<ScheduleId, ScheduledEvent>,
+}
+
+impl Handle {
+    /// Run `f` at `at`, the virtual time since the start of the simulation, outside of any node.
+    ///
+    /// Unlike a task sleeping until then, a scheduled event does not belong to a node and does
+    /// not perturb the scheduling of tasks. Each run is recorded as an
+    /// [`EventKind::Scheduled`] in the trace. If `at` is in the past, `f` runs as soon as
+    /// possible.
+    pub fn schedule_at(
+        &self,
+        at: Duration,
+        name: impl Into<String>,
+        f: impl FnOnce() + Send + 'static,
+    ) -> ScheduleId {
+        let mut f = Some(f);
+        let job: Job = Arc::new(Mutex::new(Box::new(move || {
+            if let Some(f) = f.take() {
+                f();
+            }
+        })));
+        self.schedule(at, None, name.into(), job)
+    }
+
+    /// Run `f` every `interval`, starting one interval from now, outside of any node.
+    ///
+    /// See [`Handle::schedule_at`]. The event runs until it is cancelled with
+    /// [`Handle::cancel_scheduled`].
+    pub fn schedule_every(
+        &self,
+        interval: Duration,
+        name: impl Into<String>,
+        f: impl FnMut() + Send + 'static,
+    ) -> ScheduleId {
+        assert!(!interval.is_zero(), "interval must be positive");
+        let at = self.time.time_since_clock_base() + interval;
+        self.schedule(
+            at,
+            Some(interval),
+            name.into(),
+            Arc::new(Mutex::new(Box::new(f))),
+        )
+    }
+
+    /// Cancel a scheduled event. Returns `false` if it already ran or was cancelled.
+    pub fn cancel_scheduled(&self, id: ScheduleId) -> bool {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        scheduler.pending.remove(&id).is_some()
+    }
+
+    /// The events waiting to run, in the order they will run.
+    pub fn scheduled(&self) -> Vec<ScheduledEvent> {
+        let scheduler = self.scheduler.lock().unwrap();
+        let mut events: Vec<_> = scheduler.pending.values().cloned().collect();
+        events.sort_by_key(|event| (event.next, event.id));
+        events
+    }
+
+    fn schedule(
+        &self,
+        at: Duration,
+        every: Option<Duration>,
+        name: String,
+        job: Job,
+    ) -> ScheduleId {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let id = ScheduleId(scheduler.next_id);
+        scheduler.next_id += 1;
+        let event = ScheduledEvent {
+            id,
+            name,
+            next: at,
+            every,
+            runs: 0,
+        };
+        scheduler.pending.insert(id, event);
+        drop(scheduler);
+        self.arm(id, at, job);
+        id
+    }
+
+    /// Add the timer of the next run of an event.
+    fn arm(&self, id: ScheduleId, at: Duration, job: Job) {
+        let now = self.time.time_since_clock_base();
+        let deadline = self.time.now_instant() + at.saturating_sub(now);
+        let handle = self.clone();
+        self.time
+            .add_timer_for_node(NodeId::zero(), deadline, move || {
+                let _guard = handle.clone().enter();
+                let mut scheduler = handle.scheduler.lock().unwrap();
+                let Some(event) = scheduler.pending.get_mut(&id) else {
+                    // cancelled
+                    return;
+                };
+                event.runs += 1;
+                let name = event.name.clone();
+                let next = event.every.map(|every| event.next + every);
+                match next {
+                    Some(next) => event.next = next,
+                    None => drop(scheduler.pending.remove(&id)),
+                }
+                drop(scheduler);
+                handle.trace.record(EventKind::Scheduled { id, name });
+                if let Some(next) = next {
+                    handle.arm(id, next, job.clone());
+                }
+                (job.lock().unwrap())();
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::{NodeHandle, Runtime},
+        time::sleep,
+        trace::{scheduled, timeline},
+        SimConfig,
+    };
+
+    #[test]
+    fn schedule() {
+        let mut config = SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let runs = Arc::new(Mutex::new(vec![]));
+        let record = |name: &'static str| {
+            let runs = runs.clone();
+            let time = runtime.handle().time().clone();
+            move || {
+                assert!(NodeHandle::try_current().is_none());
+                let at = time.time_since_clock_base().as_secs_f64().round() as u64;
+                runs.lock().unwrap().push((name, at));
+            }
+        };
+        runtime.schedule_at(Duration::from_secs(5), "partition", record("partition"));
+        let cancelled = runtime.schedule_at(Duration::from_secs(1), "never", record("never"));
+        let tick = runtime.schedule_every(Duration::from_secs(2), "tick", record("tick"));
+        let handle = runtime.handle().clone();
+        assert!(handle.cancel_scheduled(cancelled));
+        assert!(!handle.cancel_scheduled(cancelled));
+        let pending: Vec<_> = handle.scheduled().into_iter().map(|e| e.name).collect();
+        assert_eq!(pending, ["tick", "partition"]);
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(7)).await;
+            let next = handle.scheduled();
+            assert_eq!(next.len(), 1);
+            assert_eq!((next[0].id, next[0].runs), (tick, 3));
+            assert_eq!(next[0].next, Duration::from_secs(8));
+            assert!(handle.cancel_scheduled(tick));
+            sleep(Duration::from_secs(2)).await;
+
+            timeline().assert(crate::seq!(
+                scheduled("tick"),
+                scheduled("partition"),
+                scheduled("tick")
+            ));
+        });
+        assert_eq!(
+            *runs.lock().unwrap(),
+            [("tick", 2), ("tick", 4), ("partition", 5), ("tick", 6)]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Exit status of nodes, supervisor policies and panic policies.
+
+use crate::task::NodeId;
+use std::{fmt, time::Duration};
+
+/// How a node terminated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The initial task returned, or the node called [`exit`](crate::task::exit) with a code.
+    Exited(i32),
+    /// The initial task panicked with a message.
+    Panicked(String),
+    /// The node was killed.
+    Killed,
+}
+
+impl ExitStatus {
+    /// Returns `true` if the node exited with code 0.
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Exited(0)
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exit code {code}"),
+            Self::Panicked(msg) => write!(f, "panicked: {msg}"),
+            Self::Killed => write!(f, "killed"),
+        }
+    }
+}
+
+/// When a supervised node is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartMode {
+    /// Never restart the node.
+    Never,
+    /// Restart the node if it exited with a non-zero code or panicked.
+    #[default]
+    OnFailure,
+    /// Restart the node whenever it exits.
+    Always,
+}
+
+/// Restart policy of a supervised node, similar to the restart policies of systemd and
+/// kubernetes.
+///
+/// A supervised node stops when its initial task returns or panics, or when it calls
+/// [`exit`](crate::task::exit), and is restarted according to the policy. Nodes killed with
+/// [`Handle::kill`](super::Handle::kill) are not restarted.
+#[derive(Debug, Clone)]
+pub struct SupervisorPolicy {
+    /// When to restart the node.
+    pub restart: RestartMode,
+    /// Delay before the first restart. Doubled after each restart.
+    pub initial_backoff: Duration,
+    /// Maximum delay before a restart.
+    pub max_backoff: Duration,
+    /// Give up after this many restarts. `None` means never give up.
+    pub max_restarts: Option<u32>,
+}
+
+impl Default for SupervisorPolicy {
+    fn default() -> Self {
+        SupervisorPolicy {
+            restart: RestartMode::OnFailure,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: Some(5),
+        }
+    }
+}
+
+impl SupervisorPolicy {
+    /// Returns `true` if a node that exited with `status` should be restarted.
+    pub(crate) fn should_restart(&self, status: &ExitStatus) -> bool {
+        match self.restart {
+            RestartMode::Never => false,
+            RestartMode::OnFailure => !status.success(),
+            RestartMode::Always => true,
+        }
+    }
+
+    /// Returns the delay before restarting a node after `restarts` previous restarts, or `None`
+    /// if the supervisor gives up.
+    pub(crate) fn backoff(&self, restarts: u32) -> Option<Duration> {
+        if self.max_restarts.is_some_and(|max| restarts >= max) {
+            return None;
+        }
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(restarts));
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+/// What happens when a task of a node panics.
+///
+/// Panics of the main task, which runs the test itself, always abort the test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Abort the test, unless the panic is in the initial task of a supervised node.
+    #[default]
+    Abort,
+    /// Crash the node whose task panicked, as if it was killed, and keep running the test.
+    ///
+    /// The crash is reported as [`ExitStatus::Panicked`], so a supervised node is restarted
+    /// according to its [`SupervisorPolicy`]. Every panic is recorded, see
+    /// [`Handle::node_panics`](super::Handle::node_panics), and listed when the test ends.
+    CrashNode,
+}
+
+/// A panic of a node task, converted into a crash of the node by [`PanicPolicy::CrashNode`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodePanic {
+    /// The node that crashed.
+    pub node: NodeId,
+    /// The name of the node.
+    pub name: String,
+    /// Virtual time since the start of the simulation when the task panicked.
+    pub elapsed: Duration,
+    /// The panic message.
+    pub message: String,
+}
+
+impl fmt::Display for NodePanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}\t{} ({})\tpanicked: {}",
+            self.elapsed, self.node, self.name, self.message
+        )
+    }
+}
@@ -0,0 +1,349 @@
+//! Composition of scenarios from reusable fragments.
+//!
+//! Test suites of a system share building blocks: a region outage, a rolling upgrade, a burst of
+//! traffic. A [`Scenario`] is such a named fragment, and fragments compose into larger scenarios
+//! that run them in sequence, in parallel, or repeatedly:
+//!
+//! ```ignore
+//! use msim::scenario::Scenario;
+//!
+//! let outage = Scenario::sequence("region-outage", [
+//!     Scenario::action("isolate", || { FailureDomain::region("us-east").isolate(); }),
+//!     Scenario::wait(Duration::from_secs(60)),
+//!     Scenario::action("heal", || { FailureDomain::region("us-east").heal(); }),
+//! ]);
+//! let upgrade = Scenario::new("rolling-upgrade", || {
+//!     RollingRestart::new().run_label("app", "validator", wait_until_synced)
+//! });
+//!
+//! Scenario::parallel("upgrade-during-outages", [upgrade, outage.repeat(3)]).run().await?;
+//! ```
+//!
+//! The start and the end of every fragment are [recorded](crate::trace::record) in the trace,
+//! named by the path of the fragment in the scenario, e.g.
+//! `scenario-start upgrade-during-outages/region-outage[1]/heal`, so that the symptoms of the
+//! system can be correlated with the step that caused them.
+//!
+//! A fragment can [expect](Scenario::expect_faults) an amount of injected faults, so that a
+//! misconfigured chaos scenario fails instead of silently injecting nothing. The bounds may come
+//! from a scenario file, read through [`inputs`](crate::inputs) so that they are recorded:
+//!
+//! ```ignore
+//! let bounds = msim::inputs::read_to_string("scenarios/outage.faults")?.parse()?;
+//! outage.expect_faults(bounds).run().await?;
+//! ```
+
+use crate::{
+    fault::{FaultBounds, FaultStats},
+    time::{sleep, Duration, Instant},
+};
+use futures::future::{join_all, FutureExt, LocalBoxFuture};
+use std::{fmt, future::Future, io, rc::Rc};
+use tracing::*;
+
+type Step = Rc<dyn Fn() -> LocalBoxFuture<'static, io::Result<()>>>;
+
+#[derive(Clone)]
+enum Kind {
+    Step(Step),
+    Sequence(Vec<Scenario>),
+    Parallel(Vec<Scenario>),
+    Repeat(Box<Scenario>, usize),
+}
+
+/// A named fragment of a scenario, see the [module](self) documentation.
+///
+/// Scenarios are cheap to clone, and can be run any number of times.
+#[derive(Clone)]
+pub struct Scenario {
+    name: String,
+    kind: Kind,
+    faults: Option<Rc<FaultBounds>>,
+}
+
+impl Scenario {
+    /// A fragment running the future returned by `f`.
+    pub fn new<F, Fut>(name: impl Into<String>, f: F) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = io::Result<()>> + 'static,
+    {
+        Scenario {
+            name: name.into(),
+            kind: Kind::Step(Rc::new(move || f().boxed_local())),
+            faults: None,
+        }
+    }
+
+    /// A fragment calling `f`, e.g. to inject or heal a fault.
+    pub fn action(name: impl Into<String>, f: impl Fn() + 'static) -> Self {
+        Self::new(name, move || {
+            f();
+            async { Ok(()) }
+        })
+    }
+
+    /// A fragment waiting for `duration` of virtual time.
+    pub fn wait(duration: Duration) -> Self {
+        Self::new(format!("wait-{duration:?}"), move || async move {
+            sleep(duration).await;
+            Ok(())
+        })
+    }
+
+    /// A fragment running `parts` one after the other. It fails on the first part that fails.
+    pub fn sequence(name: impl Into<String>, parts: impl IntoIterator<Item = Scenario>) -> Self {
+        Scenario {
+            name: name.into(),
+            kind: Kind::Sequence(parts.into_iter().collect()),
+            faults: None,
+        }
+    }
+
+    /// A fragment running `parts` concurrently, until all of them complete. It fails with the
+    /// error of the first failed part, in the order of `parts`.
+    pub fn parallel(name: impl Into<String>, parts: impl IntoIterator<Item = Scenario>) -> Self {
+        Scenario {
+            name: name.into(),
+            kind: Kind::Parallel(parts.into_iter().collect()),
+            faults: None,
+        }
+    }
+
+    /// A fragment running this one `times` times in sequence.
+    pub fn repeat(self, times: usize) -> Self {
+        Scenario {
+            name: self.name.clone(),
+            kind: Kind::Repeat(Box::new(self), times),
+            faults: None,
+        }
+    }
+
+    /// Fail the fragment if the faults injected while it ran are not within `bounds`, see
+    /// [`FaultStats`].
+    ///
+    /// Faults injected by anything running concurrently are counted as well. The bounds of a
+    /// repeated fragment are checked for each iteration.
+    pub fn expect_faults(mut self, bounds: FaultBounds) -> Self {
+        self.faults = Some(Rc::new(bounds));
+        self
+    }
+
+    /// The name of the fragment.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the scenario.
+    ///
+    /// An error is prefixed with the path of the fragment that failed.
+    pub async fn run(&self) -> io::Result<()> {
+        self.run_in(String::new()).await
+    }
+
+    fn run_in(&self, parent: String) -> LocalBoxFuture<'_, io::Result<()>> {
+        async move {
+            let path = if parent.is_empty() {
+                self.name.clone()
+            } else {
+                format!("{parent}/{}", self.name)
+            };
+            crate::trace::record(format!("scenario-start {path}"));
+            let start = Instant::now();
+            let faults = self.faults.as_ref().map(|_| FaultStats::current());
+            let mut res = match &self.kind {
+                Kind::Step(step) => step()
+                    .await
+                    .map_err(|e| io::Error::new(e.kind(), format!("{path}: {e}"))),
+                Kind::Sequence(parts) => {
+                    let mut res = Ok(());
+                    for part in parts {
+                        res = part.run_in(path.clone()).await;
+                        if res.is_err() {
+                            break;
+                        }
+                    }
+                    res
+                }
+                Kind::Parallel(parts) => {
+                    let results = join_all(parts.iter().map(|part| part.run_in(path.clone())));
+                    results.await.into_iter().collect()
+                }
+                Kind::Repeat(part, times) => {
+                    let mut res = Ok(());
+                    for i in 0..*times {
+                        // the iterations take the place of the repeat in the path.
+                        let iteration = Scenario {
+                            name: format!("{}[{i}]", part.name),
+                            kind: part.kind.clone(),
+                            faults: part.faults.clone(),
+                        };
+                        res = iteration.run_in(parent.clone()).await;
+                        if res.is_err() {
+                            break;
+                        }
+                    }
+                    res
+                }
+            };
+            if let (Ok(()), Some(bounds), Some(before)) = (&res, &self.faults, faults) {
+                let injected = FaultStats::current().since(&before);
+                debug!("scenario {path} injected {injected}");
+                res = bounds
+                    .check(&injected)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{path}: {e}")));
+            }
+            debug!("scenario {path} finished in {:?}", start.elapsed());
+            crate::trace::record(format!("scenario-end {path}"));
+            res
+        }
+        .boxed_local()
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}{}", "", self.name, indent = depth * 2)?;
+        let mut kind = &self.kind;
+        while let Kind::Repeat(part, times) = kind {
+            write!(f, " x{times}")?;
+            kind = &part.kind;
+        }
+        let parts = match kind {
+            Kind::Sequence(parts) => {
+                write!(f, " (sequence)")?;
+                parts.as_slice()
+            }
+            Kind::Parallel(parts) => {
+                write!(f, " (parallel)")?;
+                parts.as_slice()
+            }
+            _ => &[],
+        };
+        writeln!(f)?;
+        for part in parts {
+            part.fmt_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the tree of fragments, one per line.
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_tree(f, 0)
+    }
+}
+
+impl fmt::Debug for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scenario")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, trace::EventKind};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn compose() {
+        let mut config = crate::SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        runtime.block_on(async {
+            let start = Instant::now();
+            let log = Rc::new(RefCell::new(vec![]));
+            let step = |name: &'static str| {
+                let log = log.clone();
+                Scenario::action(name, move || {
+                    let secs = start.elapsed().as_secs_f64().round() as u64;
+                    log.borrow_mut().push((name, secs));
+                })
+            };
+            let outage = Scenario::sequence(
+                "outage",
+                [
+                    step("isolate"),
+                    Scenario::wait(Duration::from_secs(10)),
+                    step("heal"),
+                ],
+            );
+            let traffic = Scenario::sequence(
+                "traffic",
+                [Scenario::wait(Duration::from_secs(5)), step("burst")],
+            );
+            let scenario = Scenario::parallel("soak", [outage.repeat(2), traffic]);
+            assert_eq!(
+                scenario.to_string(),
+                "soak (parallel)\n  outage x2 (sequence)\n    isolate\n    wait-10s\n    heal\n  \
+                 traffic (sequence)\n    wait-5s\n    burst\n"
+            );
+
+            scenario.run().await.unwrap();
+            assert_eq!(
+                *log.borrow(),
+                [
+                    ("isolate", 0),
+                    ("burst", 5),
+                    ("heal", 10),
+                    ("isolate", 10),
+                    ("heal", 20)
+                ]
+            );
+            let ends: Vec<_> = crate::trace::timeline()
+                .events()
+                .iter()
+                .filter_map(|e| match &e.kind {
+                    EventKind::Custom { name, .. } => name
+                        .strip_prefix("scenario-end ")
+                        .map(|p| (p.to_string(), e.time.as_secs())),
+                    _ => None,
+                })
+                .collect();
+            assert!(
+                ends.contains(&("soak/outage[1]/heal".to_string(), 20)),
+                "{ends:?}"
+            );
+            assert_eq!(ends.last().unwrap(), &("soak".to_string(), 20));
+
+            // a failure stops the sequence, and names the failed fragment.
+            let fail = Scenario::new("upgrade", || async {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "node not ready"))
+            });
+            let e = Scenario::sequence("release", [fail, step("never")])
+                .run()
+                .await
+                .unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(e.to_string(), "release/upgrade: node not ready");
+            assert!(!log.borrow().iter().any(|(name, _)| *name == "never"));
+        });
+    }
+
+    #[test]
+    fn expect_faults() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let id = node.id();
+        runtime.block_on(async move {
+            let restart = Scenario::action("restart", move || {
+                crate::runtime::Handle::current().restart(id);
+            });
+            let chaos =
+                Scenario::sequence("chaos", [restart, Scenario::wait(Duration::from_secs(1))]);
+            let bounds = FaultBounds::new().crashes(1..=1);
+            chaos.expect_faults(bounds).repeat(2).run().await.unwrap();
+
+            // a chaos scenario that injected nothing fails.
+            let quiet = Scenario::wait(Duration::from_secs(1));
+            let quiet = quiet.expect_faults("crashes 1..".parse().unwrap());
+            let e = Scenario::sequence("soak", [quiet]).run().await.unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "soak/wait-1s: injected faults out of bounds: expected 1.. crashes, got 0"
+            );
+        });
+    }
+}
@@ -0,0 +1,219 @@
+//! A multi-producer, multi-consumer channel whose messages are delayed by a random jitter.
+//!
+//! Mirrors `tokio::sync::broadcast`. Every receiver gets every message, each after its own
+//! jitter, so two receivers of the same message may observe it at different times. A receiver
+//! still gets the messages in the order they were sent.
+
+use super::default_jitter;
+use crate::{
+    net::LatencyDistribution,
+    time::{Instant, TimeHandle},
+};
+use std::{fmt, sync::Arc};
+use tokio::sync::broadcast;
+
+#[doc(no_inline)]
+pub use tokio::sync::broadcast::error::{RecvError, SendError, TryRecvError};
+
+/// Create a channel holding up to `capacity` messages, whose messages are delayed by up to 1ms.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    channel_with_jitter(capacity, default_jitter())
+}
+
+/// Create a channel holding up to `capacity` messages, whose messages are delayed by a duration
+/// drawn from `jitter` for each receiver.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn channel_with_jitter<T: Clone>(
+    capacity: usize,
+    jitter: LatencyDistribution,
+) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = broadcast::channel(capacity);
+    let jitter = Arc::new(jitter);
+    let rx = Receiver {
+        inner: rx,
+        jitter: jitter.clone(),
+        pending: None,
+    };
+    (Sender { inner: tx, jitter }, rx)
+}
+
+/// Sends messages to the receivers of a [`channel`].
+pub struct Sender<T> {
+    inner: broadcast::Sender<(Instant, T)>,
+    jitter: Arc<LatencyDistribution>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            inner: self.inner.clone(),
+            jitter: self.jitter.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("receivers", &self.inner.receiver_count())
+            .finish()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send a message to all current receivers.
+    ///
+    /// Returns the number of receivers, or the message back if there are none.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let now = TimeHandle::current().now_instant();
+        self.inner
+            .send((now, value))
+            .map_err(|broadcast::error::SendError((_, value))| SendError(value))
+    }
+
+    /// Create a receiver of the messages sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        Receiver {
+            inner: self.inner.subscribe(),
+            jitter: self.jitter.clone(),
+            pending: None,
+        }
+    }
+
+    /// The number of receivers.
+    pub fn receiver_count(&self) -> usize {
+        self.inner.receiver_count()
+    }
+}
+
+/// Receives messages from a [`channel`].
+pub struct Receiver<T> {
+    inner: broadcast::Receiver<(Instant, T)>,
+    jitter: Arc<LatencyDistribution>,
+    /// The next message and when it is delivered, once taken from the channel.
+    pending: Option<(Instant, T)>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("pending", &self.pending.is_some())
+            .finish()
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Wait for the next message, and for its jitter.
+    ///
+    /// Returns an error if all senders were dropped, or if this receiver lagged behind and
+    /// messages were lost. This method is cancel safe.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        let deadline = match &self.pending {
+            Some((deadline, _)) => *deadline,
+            None => {
+                let (sent, value) = self.inner.recv().await?;
+                self.delay(sent, value)
+            }
+        };
+        crate::time::sleep_until(deadline).await;
+        Ok(self.pending.take().unwrap().1)
+    }
+
+    /// Take the next message, if its jitter has elapsed.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let deadline = match &self.pending {
+            Some((deadline, _)) => *deadline,
+            None => {
+                let (sent, value) = self.inner.try_recv()?;
+                self.delay(sent, value)
+            }
+        };
+        if deadline > TimeHandle::current().now_instant() {
+            return Err(TryRecvError::Empty);
+        }
+        Ok(self.pending.take().unwrap().1)
+    }
+
+    /// Create a receiver of the messages sent from now on.
+    pub fn resubscribe(&self) -> Self {
+        Receiver {
+            inner: self.inner.resubscribe(),
+            jitter: self.jitter.clone(),
+            pending: None,
+        }
+    }
+
+    /// Keep a message taken from the channel until its jitter elapsed.
+    fn delay(&mut self, sent: Instant, value: T) -> Instant {
+        let deadline = sent + self.jitter.sample(&mut crate::rand::thread_rng());
+        self.pending = Some((deadline, value));
+        deadline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{runtime::Runtime, sync::watch, time::sleep};
+    use std::{sync::Mutex, time::Duration};
+
+    #[test]
+    fn jitter() {
+        let runtime = Runtime::new();
+        runtime.block_on(async {
+            let jitter =
+                LatencyDistribution::uniform(Duration::from_millis(10)..Duration::from_millis(20));
+            let (tx, _) = channel_with_jitter(16, jitter.clone());
+            let (state, _) = watch::channel_with_jitter(0, jitter);
+            let log = Arc::new(Mutex::new(vec![]));
+            for i in 0..2 {
+                let (mut rx, mut state, log) = (tx.subscribe(), state.subscribe(), log.clone());
+                crate::task::spawn(async move {
+                    let t0 = Instant::now();
+                    for _ in 0..3 {
+                        let value = rx.recv().await.unwrap();
+                        log.lock().unwrap().push((i, value, t0.elapsed()));
+                    }
+                    state.changed().await.unwrap();
+                    let value = *state.borrow_and_update();
+                    log.lock().unwrap().push((i, value, t0.elapsed()));
+                });
+            }
+
+            sleep(Duration::from_millis(1)).await;
+            for value in 1..=3 {
+                assert_eq!(tx.send(value).unwrap(), 2);
+            }
+            state.send(4).unwrap();
+            let mut rx = tx.subscribe();
+            tx.send(5).unwrap();
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            // the value is visible before the notification
+            assert_eq!(*state.borrow(), 4);
+            sleep(Duration::from_millis(30)).await;
+            assert_eq!(rx.try_recv(), Ok(5));
+
+            let log = log.lock().unwrap();
+            assert_eq!(log.len(), 8);
+            let received = |i| -> Vec<_> { log.iter().filter(|e| e.0 == i).collect() };
+            let (first, second) = (received(0), received(1));
+            for received in [&first, &second] {
+                let values: Vec<_> = received.iter().map(|e| e.1).collect();
+                assert_eq!(values, [1, 2, 3, 4]);
+                for (_, _, delay) in received {
+                    assert!(*delay >= Duration::from_millis(10));
+                    assert!(*delay < Duration::from_millis(22));
+                }
+            }
+            // each receiver draws its own jitter
+            assert_ne!(first[0].2, second[0].2);
+        });
+    }
+}
@@ -0,0 +1,459 @@
+//! Synchronization of test phases across nodes.
+//!
+//! Tests often coordinate their phases between nodes: wait until every server is listening
+//! before starting clients, or start all clients at the same instant. The primitives in this
+//! module do this in the simulator itself, without sending messages through the simulated
+//! network, and without the random scheduling delays of the network, so that they do not
+//! perturb the system under test. Waiting tasks are woken in the order they started waiting.
+//!
+//! All primitives are cheap to clone, and clones refer to the same primitive, so they can be
+//! moved into the tasks of several nodes.
+//!
+//! The [`watch`] and [`broadcast`] channels serve the opposite purpose. They replace the
+//! channels of `tokio::sync` in the system under test, and delay each notification by a random
+//! jitter, so that components coupled through channels do not observe each other's updates
+//! instantaneously, which would hide races.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::sync::{Barrier, Latch};
+//!
+//! let listening = Barrier::new(2);
+//! let start = Latch::new(1);
+//! // on the server
+//! listening.wait().await;
+//! // on the client
+//! listening.wait().await;
+//! start.wait().await;
+//! // in the scenario, fire the starting gun
+//! start.count_down();
+//! ```
+
+pub mod broadcast;
+pub mod watch;
+
+use crate::net::LatencyDistribution;
+use futures::future::poll_fn;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+/// The delay of notifications of the [`watch`] and [`broadcast`] channels, unless specified.
+fn default_jitter() -> LatencyDistribution {
+    LatencyDistribution::uniform(Duration::ZERO..Duration::from_millis(1))
+}
+
+/// A reusable barrier for `n` tasks.
+///
+/// Unlike `tokio::sync::Barrier`, the tasks are released in the order they arrived, and the
+/// barrier can be cloned instead of being wrapped in an `Arc`.
+#[derive(Clone)]
+pub struct Barrier {
+    inner: Arc<Mutex<BarrierState>>,
+}
+
+struct BarrierState {
+    n: usize,
+    arrived: usize,
+    /// Incremented each time the barrier releases its tasks.
+    generation: u64,
+    /// The waiting tasks by the order they arrived in, with the id of their wait.
+    wakers: Vec<(u64, Waker)>,
+    next_wait: u64,
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.lock().unwrap();
+        f.debug_struct("Barrier")
+            .field("n", &state.n)
+            .field("arrived", &state.arrived)
+            .finish()
+    }
+}
+
+impl Barrier {
+    /// Create a barrier that releases its tasks when `n` tasks are waiting.
+    ///
+    /// A barrier for 0 tasks behaves like a barrier for 1 task.
+    pub fn new(n: usize) -> Self {
+        Barrier {
+            inner: Arc::new(Mutex::new(BarrierState {
+                n: n.max(1),
+                arrived: 0,
+                generation: 0,
+                wakers: vec![],
+                next_wait: 0,
+            })),
+        }
+    }
+
+    /// Wait until `n` tasks are waiting.
+    ///
+    /// Returns `true` in the task that arrived last, the leader, and `false` in the others. A
+    /// task that stops waiting before the barrier releases, e.g. on a timeout, no longer counts
+    /// as arrived.
+    pub async fn wait(&self) -> bool {
+        let (generation, id) = {
+            let mut state = self.inner.lock().unwrap();
+            state.arrived += 1;
+            if state.arrived == state.n {
+                state.arrived = 0;
+                state.generation += 1;
+                for (_, waker) in state.wakers.drain(..) {
+                    waker.wake();
+                }
+                return true;
+            }
+            state.next_wait += 1;
+            (state.generation, state.next_wait)
+        };
+        let _arrival = BarrierArrival {
+            barrier: self,
+            generation,
+            id,
+        };
+        poll_fn(|cx| {
+            let mut state = self.inner.lock().unwrap();
+            if state.generation != generation {
+                return Poll::Ready(false);
+            }
+            match state.wakers.iter_mut().find(|(wait, _)| *wait == id) {
+                Some((_, waker)) => {
+                    if !waker.will_wake(cx.waker()) {
+                        *waker = cx.waker().clone();
+                    }
+                }
+                None => state.wakers.push((id, cx.waker().clone())),
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Withdraws a task from a barrier if it stops waiting before the barrier releases.
+struct BarrierArrival<'a> {
+    barrier: &'a Barrier,
+    generation: u64,
+    id: u64,
+}
+
+impl Drop for BarrierArrival<'_> {
+    fn drop(&mut self) {
+        let mut state = self.barrier.inner.lock().unwrap();
+        if state.generation == self.generation {
+            state.arrived -= 1;
+            state.wakers.retain(|(wait, _)| *wait != self.id);
+        }
+    }
+}
+
+/// The tasks waiting on a [`Latch`] or a [`OnceBroadcast`], by the order they started waiting.
+#[derive(Default)]
+struct Waiters {
+    /// The waiting tasks, with the id of their wait.
+    wakers: Vec<(u64, Waker)>,
+    next_wait: u64,
+}
+
+impl Waiters {
+    fn next_wait(&mut self) -> u64 {
+        self.next_wait += 1;
+        self.next_wait
+    }
+
+    /// Set the waker of wait `id`, which is polled again.
+    fn register(&mut self, id: u64, cx_waker: &Waker) {
+        match self.wakers.iter_mut().find(|(wait, _)| *wait == id) {
+            Some((_, waker)) => {
+                if !waker.will_wake(cx_waker) {
+                    *waker = cx_waker.clone();
+                }
+            }
+            None => self.wakers.push((id, cx_waker.clone())),
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// The state of a primitive with [`Waiters`].
+trait Waitable {
+    fn waiters(&mut self) -> &mut Waiters;
+}
+
+/// Forgets a wait when it stops, e.g. when it loses a `select!`, so that waiting in a loop does
+/// not pile up wakers.
+struct Wait<'a, S: Waitable> {
+    state: &'a Mutex<S>,
+    id: u64,
+}
+
+impl<'a, S: Waitable> Wait<'a, S> {
+    fn new(state: &'a Mutex<S>) -> Self {
+        let id = state.lock().unwrap().waiters().next_wait();
+        Wait { state, id }
+    }
+}
+
+impl<S: Waitable> Drop for Wait<'_, S> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.waiters().wakers.retain(|(wait, _)| *wait != self.id);
+    }
+}
+
+/// A latch that releases its tasks when it is counted down to zero.
+///
+/// A latch with a count of 1 is a starting gun: any number of tasks wait for a single
+/// [`count_down`](Self::count_down). Once released, a latch stays open.
+#[derive(Clone)]
+pub struct Latch {
+    inner: Arc<Mutex<LatchState>>,
+}
+
+struct LatchState {
+    count: usize,
+    waiters: Waiters,
+}
+
+impl Waitable for LatchState {
+    fn waiters(&mut self) -> &mut Waiters {
+        &mut self.waiters
+    }
+}
+
+impl fmt::Debug for Latch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Latch")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+impl Latch {
+    /// Create a latch that is released after `count` calls to [`count_down`](Self::count_down).
+    pub fn new(count: usize) -> Self {
+        Latch {
+            inner: Arc::new(Mutex::new(LatchState {
+                count,
+                waiters: Waiters::default(),
+            })),
+        }
+    }
+
+    /// Decrement the count, releasing the waiting tasks when it reaches zero.
+    pub fn count_down(&self) {
+        let mut state = self.inner.lock().unwrap();
+        if state.count == 0 {
+            return;
+        }
+        state.count -= 1;
+        if state.count == 0 {
+            state.waiters.wake_all();
+        }
+    }
+
+    /// The remaining count.
+    pub fn count(&self) -> usize {
+        self.inner.lock().unwrap().count
+    }
+
+    /// Wait until the count reaches zero.
+    pub async fn wait(&self) {
+        let wait = Wait::new(&self.inner);
+        poll_fn(|cx| {
+            let mut state = self.inner.lock().unwrap();
+            if state.count == 0 {
+                return Poll::Ready(());
+            }
+            state.waiters.register(wait.id, cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// A value sent once to any number of receivers.
+pub struct OnceBroadcast<T> {
+    inner: Arc<Mutex<OnceBroadcastState<T>>>,
+}
+
+impl<T> Clone for OnceBroadcast<T> {
+    fn clone(&self) -> Self {
+        OnceBroadcast {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct OnceBroadcastState<T> {
+    value: Option<T>,
+    waiters: Waiters,
+}
+
+impl<T> Waitable for OnceBroadcastState<T> {
+    fn waiters(&mut self) -> &mut Waiters {
+        &mut self.waiters
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceBroadcast<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnceBroadcast")
+            .field("value", &self.inner.lock().unwrap().value)
+            .finish()
+    }
+}
+
+impl<T> Default for OnceBroadcast<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> OnceBroadcast<T> {
+    /// Create a broadcast with no value.
+    pub fn new() -> Self {
+        OnceBroadcast {
+            inner: Arc::new(Mutex::new(OnceBroadcastState {
+                value: None,
+                waiters: Waiters::default(),
+            })),
+        }
+    }
+
+    /// Send `value` to all current and future receivers.
+    ///
+    /// Returns the value back if one was already sent.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut state = self.inner.lock().unwrap();
+        if state.value.is_some() {
+            return Err(value);
+        }
+        state.value = Some(value);
+        state.waiters.wake_all();
+        Ok(())
+    }
+}
+
+impl<T: Clone> OnceBroadcast<T> {
+    /// The value, if it was sent.
+    pub fn try_recv(&self) -> Option<T> {
+        self.inner.lock().unwrap().value.clone()
+    }
+
+    /// Wait until the value is sent.
+    pub async fn recv(&self) -> T {
+        let wait = Wait::new(&self.inner);
+        poll_fn(|cx| {
+            let mut state = self.inner.lock().unwrap();
+            if let Some(value) = &state.value {
+                return Poll::Ready(value.clone());
+            }
+            state.waiters.register(wait.id, cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::Runtime,
+        time::{sleep, Duration, Instant},
+    };
+
+    #[test]
+    fn sync() {
+        let runtime = Runtime::new();
+        let barrier = Barrier::new(3);
+        let start = Latch::new(1);
+        let config = OnceBroadcast::new();
+        let log = Arc::new(Mutex::new(vec![]));
+
+        for i in 0..3u64 {
+            let (barrier, start, config, log) =
+                (barrier.clone(), start.clone(), config.clone(), log.clone());
+            runtime.create_node().build().spawn(async move {
+                sleep(Duration::from_secs(i)).await;
+                let leader = barrier.wait().await;
+                log.lock().unwrap().push((i, leader, Instant::now()));
+                start.wait().await;
+                assert_eq!(config.recv().await, "fast");
+                log.lock().unwrap().push((i, false, Instant::now()));
+                // the barrier can be reused
+                barrier.wait().await;
+            });
+        }
+
+        runtime.block_on(async move {
+            let t0 = Instant::now();
+            sleep(Duration::from_secs(5)).await;
+            config.send("fast").unwrap();
+            assert_eq!(config.send("slow"), Err("slow"));
+            start.count_down();
+            assert_eq!(start.count(), 0);
+            sleep(Duration::from_secs(1)).await;
+
+            let log = log.lock().unwrap();
+            assert_eq!(log.len(), 6);
+            let secs = |d: Duration| d.as_secs_f64().round() as u64;
+            // released in arrival order when the last task arrives, which is the leader
+            let released: Vec<_> = log[..3]
+                .iter()
+                .map(|(i, leader, _)| (*i, *leader))
+                .collect();
+            assert_eq!(released, [(2, true), (0, false), (1, false)]);
+            assert!(log[..3].iter().all(|(_, _, t)| secs(*t - t0) == 2));
+            assert!(log[3..].iter().all(|(_, _, t)| secs(*t - t0) == 5));
+        });
+    }
+
+    #[test]
+    fn cancelled_wait() {
+        let runtime = Runtime::new();
+        runtime.block_on(async move {
+            let barrier = Barrier::new(2);
+            let wait = crate::time::timeout(Duration::from_secs(1), barrier.wait());
+            assert!(wait.await.is_err());
+            assert_eq!(format!("{barrier:?}"), "Barrier { n: 2, arrived: 0 }");
+            let other = barrier.clone();
+            let waiting = crate::task::spawn(async move { other.wait().await });
+            sleep(Duration::from_secs(1)).await;
+            assert!(barrier.wait().await);
+            assert!(!waiting.await.unwrap());
+
+            // a broadcast of values that cannot be cloned can still be shared.
+            struct Token;
+            let once = OnceBroadcast::new();
+            let sender = once.clone();
+            assert!(sender.send(Token).is_ok());
+            assert!(once.send(Token).is_err());
+
+            // waits that stop early leave no waker behind
+            let start = Latch::new(1);
+            let config = OnceBroadcast::<u64>::new();
+            for _ in 0..10 {
+                tokio::select! {
+                    _ = start.wait() => unreachable!(),
+                    _ = config.recv() => unreachable!(),
+                    _ = sleep(Duration::from_millis(1)) => {}
+                }
+            }
+            assert!(start.inner.lock().unwrap().waiters.wakers.is_empty());
+            assert!(config.inner.lock().unwrap().waiters.wakers.is_empty());
+        });
+    }
+}
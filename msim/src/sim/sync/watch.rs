@@ -0,0 +1,174 @@
+//! A single-value channel whose change notifications are delayed by a random jitter.
+//!
+//! Mirrors `tokio::sync::watch`. The latest value can always be read with
+//! [`Receiver::borrow`], but [`Receiver::changed`] only returns once the jitter drawn for the
+//! change has elapsed, as if the notification took time to propagate to the receiving component.
+
+use super::default_jitter;
+use crate::{
+    net::LatencyDistribution,
+    time::{Instant, TimeHandle},
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+#[doc(no_inline)]
+pub use tokio::sync::watch::{
+    error::{RecvError, SendError},
+    Ref,
+};
+
+/// Create a channel holding `init`, whose notifications are delayed by up to 1ms.
+pub fn channel<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    channel_with_jitter(init, default_jitter())
+}
+
+/// Create a channel holding `init`, whose notifications are delayed by a duration drawn from
+/// `jitter` for each receiver.
+pub fn channel_with_jitter<T>(init: T, jitter: LatencyDistribution) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = watch::channel(init);
+    let shared = Arc::new(Shared {
+        jitter,
+        sent: Mutex::new(TimeHandle::current().now_instant()),
+    });
+    let rx = Receiver {
+        inner: rx,
+        shared: shared.clone(),
+        pending: None,
+    };
+    (Sender { inner: tx, shared }, rx)
+}
+
+struct Shared {
+    jitter: LatencyDistribution,
+    /// When the last value was sent.
+    sent: Mutex<Instant>,
+}
+
+/// Sends values to the receivers of a [`channel`].
+pub struct Sender<T> {
+    inner: watch::Sender<T>,
+    shared: Arc<Shared>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("value", &*self.inner.borrow())
+            .finish()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send a new value, and notify the receivers after their jitter.
+    ///
+    /// Returns the value back if there are no receivers.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.inner.send(value)?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Modify the value in place, and notify the receivers after their jitter.
+    pub fn send_modify(&self, modify: impl FnOnce(&mut T)) {
+        self.inner.send_modify(modify);
+        self.touch();
+    }
+
+    /// Replace the value, and notify the receivers after their jitter, even if there are none.
+    ///
+    /// Returns the previous value.
+    pub fn send_replace(&self, value: T) -> T {
+        let old = self.inner.send_replace(value);
+        self.touch();
+        old
+    }
+
+    /// The latest value.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.inner.borrow()
+    }
+
+    /// Create a receiver that has seen the latest value.
+    pub fn subscribe(&self) -> Receiver<T> {
+        Receiver {
+            inner: self.inner.subscribe(),
+            shared: self.shared.clone(),
+            pending: None,
+        }
+    }
+
+    /// The number of receivers.
+    pub fn receiver_count(&self) -> usize {
+        self.inner.receiver_count()
+    }
+
+    /// Returns true if all receivers were dropped.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn touch(&self) {
+        *self.shared.sent.lock().unwrap() = TimeHandle::current().now_instant();
+    }
+}
+
+/// Receives values from a [`channel`].
+pub struct Receiver<T> {
+    inner: watch::Receiver<T>,
+    shared: Arc<Shared>,
+    /// When the change that was seen but not returned yet is delivered.
+    pending: Option<Instant>,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+            pending: self.pending,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("value", &*self.inner.borrow())
+            .finish()
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The latest value, whether or not this receiver was notified of it.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.inner.borrow()
+    }
+
+    /// The latest value, marking it as seen.
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        self.pending = None;
+        self.inner.borrow_and_update()
+    }
+
+    /// Wait for a value that was not seen yet, and for the jitter of its notification.
+    ///
+    /// Returns an error if the sender was dropped. This method is cancel safe.
+    pub async fn changed(&mut self) -> Result<(), RecvError> {
+        let deadline = match self.pending {
+            Some(deadline) => deadline,
+            None => {
+                self.inner.changed().await?;
+                let sent = *self.shared.sent.lock().unwrap();
+                let jitter = self.shared.jitter.sample(&mut crate::rand::thread_rng());
+                *self.pending.insert(sent + jitter)
+            }
+        };
+        crate::time::sleep_until(deadline).await;
+        self.pending = None;
+        Ok(())
+    }
+}
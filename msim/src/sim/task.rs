@@ -1,47 +1,95 @@
 //! Asynchronous tasks executor.
 
 use super::{
-    context,
+    context, perf,
+    progress::ProgressReporter,
     rand::GlobalRng,
-    runtime,
+    runtime::{self, Counters, ExitStatus, PanicPolicy, SupervisorPolicy},
     time::{TimeHandle, TimeRuntime},
     utils::mpsc,
 };
 use crate::assert_send_sync;
 use async_task::{FallibleTask, Runnable};
 use erasable::{ErasablePtr, ErasedPtr};
-use futures::pin_mut;
+use futures::{pin_mut, FutureExt};
 use rand::Rng;
 use std::{
-    collections::HashMap,
-    fmt,
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write},
     future::Future,
-    ops::Deref,
-    panic::{RefUnwindSafe, UnwindSafe},
+    ops::{Deref, Range},
+    panic::{Location, RefUnwindSafe, UnwindSafe},
     pin::Pin,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, Once,
     },
     task::{Context, Poll},
     time::Duration,
 };
 
-use tracing::{error_span, info, trace, Span};
+use tracing::{error_span, info, trace, warn, Span};
 
 pub use tokio::msim_adapter::{join_error, runtime_task};
 pub use tokio::task::{yield_now, JoinError};
 pub use tokio::{select, sync::watch};
 
+mod fairness;
 pub mod join_set;
+pub use fairness::{BusyTask, Starvation, StarvationKind};
 pub use join_set::JoinSet;
 
+/// A task ready to run.
+struct Scheduled {
+    runnable: Runnable,
+    /// The node the task belongs to.
+    info: Arc<TaskInfo>,
+    /// The name of the task, see [`spawn_named`].
+    name: Option<Arc<str>>,
+    /// When the task was woken, in virtual time since the start of the simulation, if it was
+    /// woken within the runtime.
+    woken: Option<Duration>,
+}
+
+/// The sending half of the ready queue.
+#[derive(Clone)]
+struct ReadySender {
+    queue: mpsc::Sender<Scheduled>,
+    /// Whether starvation is audited. The clock is only read on wake-ups when it is.
+    auditing: Arc<AtomicBool>,
+}
+
+impl ReadySender {
+    fn send(
+        &self,
+        runnable: Runnable,
+        info: Arc<TaskInfo>,
+        name: Option<Arc<str>>,
+    ) -> Result<(), mpsc::SendError<Scheduled>> {
+        let woken = if self.auditing.load(Ordering::Relaxed) {
+            TimeHandle::try_current().map(|time| time.time_since_clock_base())
+        } else {
+            None
+        };
+        self.queue.send(Scheduled {
+            runnable,
+            info,
+            name,
+            woken,
+        })
+    }
+}
+
 pub(crate) struct Executor {
-    queue: mpsc::Receiver<(Runnable, Arc<TaskInfo>)>,
+    queue: mpsc::Receiver<Scheduled>,
     handle: TaskHandle,
     rand: GlobalRng,
     time: TimeRuntime,
     time_limit: Option<Duration>,
+    /// Number of tasks polled.
+    polls: AtomicU64,
+    progress: Mutex<Option<ProgressReporter>>,
 }
 
 /// A unique identifier for a node.
@@ -73,25 +121,76 @@ struct PanicWrapper {
     restart_after: Option<Duration>,
 }
 
-type PanicFn = Box<dyn Fn(&std::panic::PanicInfo<'_>) + Sync + Send + 'static>;
+/// The budget of a task each time it is polled, as in the cooperative scheduling of tokio.
+const BUDGET: u32 = 128;
 
-struct PanicHookGuard(Option<PanicFn>);
+thread_local! {
+    /// Whether this thread is running the tasks of a runtime.
+    static RUNNING_TASKS: Cell<bool> = const { Cell::new(false) };
 
-impl PanicHookGuard {
-    fn new() -> Self {
-        Self(Some(std::panic::take_hook()))
-    }
+    /// The budget left to the task being polled, `None` outside of the executor.
+    static BUDGET_LEFT: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Consume a unit of the budget of the current task, and yield if it is exhausted.
+///
+/// Like `tokio::task::consume_budget`, a task has a budget of 128 units each time it is polled,
+/// so that a task looping over operations that are always ready lets the other tasks run. Forced
+/// yields are counted in [`RuntimeMetrics::budget_forced_yield_count`].
+///
+/// [`RuntimeMetrics::budget_forced_yield_count`]: crate::runtime::RuntimeMetrics::budget_forced_yield_count
+pub async fn consume_budget() {
+    std::future::poll_fn(|cx| {
+        let exhausted = BUDGET_LEFT.with(|left| match left.get() {
+            Some(0) => true,
+            Some(n) => {
+                left.set(Some(n - 1));
+                false
+            }
+            None => false,
+        });
+        if !exhausted {
+            return Poll::Ready(());
+        }
+        if let Some(task) = context::try_current_task() {
+            task.counters.forced_yield();
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
 
-    fn call_hook(&self, info: &std::panic::PanicInfo<'_>) {
-        self.0.as_ref().unwrap()(info);
+/// Install the panic hook of the simulator, once per process.
+///
+/// The hook silences the panics used to kill nodes on the threads running tasks, and calls the
+/// previous hook otherwise. It does not depend on any runtime, so that runtimes can run on several
+/// threads of the same process in parallel, e.g. in tests run by the default test harness.
+fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            let running = RUNNING_TASKS.with(|running| running.get());
+            if !(running && panic_info.payload().is::<PanicWrapper>()) {
+                hook(panic_info);
+            }
+        }));
+    });
+}
+
+/// Marks the current thread as running tasks until dropped.
+struct RunningTasksGuard(bool);
+
+impl RunningTasksGuard {
+    fn new() -> Self {
+        Self(RUNNING_TASKS.with(|running| running.replace(true)))
     }
 }
 
-impl Drop for PanicHookGuard {
+impl Drop for RunningTasksGuard {
     fn drop(&mut self) {
-        if !std::thread::panicking() {
-            std::panic::set_hook(self.0.take().unwrap());
-        }
+        RUNNING_TASKS.with(|running| running.set(self.0));
     }
 }
 
@@ -123,6 +222,23 @@ pub fn shutdown_current_node() {
     kill_current_node_impl(runtime::Handle::current(), None);
 }
 
+/// Terminate the current node with an exit code, like `std::process::exit`.
+///
+/// A supervised node is restarted according to its [`SupervisorPolicy`], other nodes are shut
+/// down.
+///
+/// [`SupervisorPolicy`]: crate::runtime::SupervisorPolicy
+pub fn exit(code: i32) -> ! {
+    let handle = runtime::Handle::current();
+    let cur_node_id = context::current_node();
+    info!("node {} exited with code {}", cur_node_id, code);
+    handle.node_exited(cur_node_id, ExitStatus::Exited(code));
+    handle.kill(cur_node_id);
+    std::panic::panic_any(PanicWrapper {
+        restart_after: None,
+    });
+}
+
 fn kill_current_node_impl(handle: runtime::Handle, restart_after: Option<Duration>) {
     let cur_node_id = context::current_node();
 
@@ -139,12 +255,49 @@ fn kill_current_node_impl(handle: runtime::Handle, restart_after: Option<Duratio
     std::panic::panic_any(PanicWrapper { restart_after });
 }
 
+/// Run the initial task of a node, and report its exit status when it returns or panics.
+pub(crate) async fn run_initial_task<F: Future>(node: NodeId, future: F) {
+    let status = match std::panic::AssertUnwindSafe(future).catch_unwind().await {
+        Ok(_) => ExitStatus::Exited(0),
+        Err(payload) => {
+            // killed nodes, panics of unsupervised nodes, and panics converted into crashes are
+            // handled by the executor.
+            let handle = runtime::Handle::current();
+            let supervised = handle.task.supervisor(node).is_some();
+            if payload.is::<PanicWrapper>()
+                || !supervised
+                || handle.config.panic == PanicPolicy::CrashNode
+            {
+                std::panic::resume_unwind(payload);
+            }
+            ExitStatus::Panicked(panic_message(&*payload))
+        }
+    };
+    runtime::Handle::current().node_exited(node, status);
+}
+
+/// The message of a panic payload.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else {
+        "Box<dyn Any>".into()
+    }
+}
+
 pub(crate) struct TaskInfo {
     inner: Arc<NodeInfo>,
     /// A flag indicating that the task should be paused.
     paused: AtomicBool,
     /// A flag indicating that the task should no longer be executed.
     killed: watch::Sender<bool>,
+    /// Runtime metrics of the tasks, see [`runtime::RuntimeMetrics`].
+    counters: Arc<Counters>,
+    /// The tasks of the node that have not finished, by the order they were spawned in.
+    tasks: Mutex<BTreeMap<u64, Arc<LiveTask>>>,
+    next_task: AtomicU64,
 }
 
 impl TaskInfo {
@@ -158,6 +311,9 @@ impl TaskInfo {
             }),
             paused: AtomicBool::new(false),
             killed: watch::channel(false).0,
+            counters: Default::default(),
+            tasks: Default::default(),
+            next_task: AtomicU64::new(0),
         }
     }
 
@@ -176,6 +332,78 @@ impl TaskInfo {
     pub fn is_killed(&self) -> bool {
         *self.killed.borrow()
     }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// The tasks of the node that have not finished, by the order they were spawned in.
+    pub fn live_tasks(&self) -> Vec<Arc<LiveTask>> {
+        self.tasks.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Track a spawned task until the returned guard is dropped.
+    fn track(
+        self: &Arc<Self>,
+        name: Option<Arc<str>>,
+        spawned_at: &'static Location<'static>,
+    ) -> LiveTaskGuard {
+        let id = self.next_task.fetch_add(1, Ordering::Relaxed);
+        let task = Arc::new(LiveTask {
+            name,
+            spawned_at,
+            last_poll: Mutex::new(None),
+        });
+        self.tasks.lock().unwrap().insert(id, task.clone());
+        LiveTaskGuard {
+            info: self.clone(),
+            id,
+            task,
+        }
+    }
+}
+
+/// A task that has not finished, listed when the simulation hangs or exceeds a time bound.
+pub(crate) struct LiveTask {
+    name: Option<Arc<str>>,
+    spawned_at: &'static Location<'static>,
+    /// When the task was last polled, in virtual time.
+    last_poll: Mutex<Option<Duration>>,
+}
+
+impl fmt::Display for LiveTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "task {name:?}")?,
+            None => write!(f, "task")?,
+        }
+        write!(f, " spawned at {}", self.spawned_at)?;
+        match *self.last_poll.lock().unwrap() {
+            Some(at) => write!(f, ", last polled at {at:?}"),
+            None => write!(f, ", never polled"),
+        }
+    }
+}
+
+/// Keeps a task listed as live, see [`TaskInfo::track`].
+struct LiveTaskGuard {
+    info: Arc<TaskInfo>,
+    id: u64,
+    task: Arc<LiveTask>,
+}
+
+impl LiveTaskGuard {
+    fn polled(&self) {
+        if let Some(time) = TimeHandle::try_current() {
+            *self.task.last_poll.lock().unwrap() = Some(time.elapsed());
+        }
+    }
+}
+
+impl Drop for LiveTaskGuard {
+    fn drop(&mut self) {
+        self.info.tasks.lock().unwrap().remove(&self.id);
+    }
 }
 
 impl Executor {
@@ -185,12 +413,20 @@ impl Executor {
             queue,
             handle: TaskHandle {
                 nodes: Arc::new(Mutex::new(HashMap::new())),
-                sender,
+                sender: ReadySender {
+                    queue: sender,
+                    auditing: Default::default(),
+                },
                 next_node_id: Arc::new(AtomicU64::new(1)),
+                priorities: Default::default(),
+                auditor: Default::default(),
+                main: Arc::new(TaskInfo::new(NodeId::zero(), "main".into())),
             },
             time: TimeRuntime::new(&rand),
             rand,
             time_limit: None,
+            polls: AtomicU64::new(0),
+            progress: Mutex::new(None),
         }
     }
 
@@ -206,6 +442,10 @@ impl Executor {
         self.time_limit = Some(limit);
     }
 
+    pub fn set_progress_reporter(&mut self, reporter: Option<ProgressReporter>) {
+        *self.progress.get_mut().unwrap() = reporter;
+    }
+
     pub fn block_on<F: Future>(&self, future: F) -> F::Output {
         let mut task = self.spawn_on_main_task(future);
 
@@ -214,30 +454,42 @@ impl Executor {
         let mut cx = Context::from_waker(&waker);
 
         loop {
-            self.run_all_ready();
+            perf::measure(perf::Section::Executor, || self.run_all_ready());
             if let Poll::Ready(val) = Pin::new(&mut task).poll(&mut cx) {
                 return val;
             }
-            let going = self.time.advance_to_next_event();
-            assert!(going, "no events, the task will block forever");
+            let going = perf::measure(perf::Section::Timer, || self.time.advance_to_next_event());
+            assert!(
+                going,
+                "no events, the task will block forever, live tasks:{}",
+                self.handle.describe_tasks()
+            );
             if let Some(limit) = self.time_limit {
                 assert!(
                     self.time.handle().elapsed() < limit,
-                    "time limit exceeded: {:?}",
-                    limit
+                    "time limit exceeded: {:?}, live tasks:{}",
+                    limit,
+                    self.handle.describe_tasks()
                 )
             }
+            if let Some(reporter) = self.progress.lock().unwrap().as_mut() {
+                reporter.poll(
+                    self.time.handle().elapsed(),
+                    self.polls.load(Ordering::Relaxed),
+                    || runtime::Handle::current().nodes().collect(),
+                );
+            }
         }
     }
 
     fn spawn_on_main_task<F: Future>(&self, future: F) -> async_task::Task<F::Output> {
         let sender = self.handle.sender.clone();
-        let info = Arc::new(TaskInfo::new(NodeId(0), "main".into()));
+        let info = self.handle.main.clone();
         let (runnable, task) = unsafe {
             // Safety: The schedule is not Sync,
             // the task's Waker must be used and dropped on the original thread.
             async_task::spawn_unchecked(future, move |runnable| {
-                sender.send((runnable, info.clone())).unwrap();
+                sender.send(runnable, info.clone(), None).unwrap();
             })
         };
         runnable.schedule();
@@ -246,21 +498,22 @@ impl Executor {
 
     /// Drain all tasks from ready queue and run them.
     fn run_all_ready(&self) {
-        let hook_guard = Arc::new(PanicHookGuard::new());
-        let hook_guard_clone = Arc::downgrade(&hook_guard);
-        std::panic::set_hook(Box::new(move |panic_info| {
-            if panic_info
-                .payload()
-                .downcast_ref::<PanicWrapper>()
-                .is_none()
-            {
-                if let Some(old_hook) = hook_guard_clone.upgrade() {
-                    old_hook.call_hook(panic_info);
-                }
-            }
-        }));
+        install_panic_hook();
+        let _running = RunningTasksGuard::new();
 
-        while let Ok((runnable, info)) = self.queue.try_recv_random(&self.rand) {
+        let auditing = self.sender.auditing.load(Ordering::Relaxed);
+        if auditing {
+            if let Some(auditor) = self.auditor.lock().unwrap().as_mut() {
+                auditor.start_pass();
+            }
+        }
+        while let Ok(task) = self.next_ready() {
+            let Scheduled {
+                runnable,
+                info,
+                name,
+                woken,
+            } = task;
             if *info.killed.borrow() {
                 // killed task: must enter the task before dropping it, so that
                 // Drop impls can run.
@@ -270,17 +523,31 @@ impl Executor {
             } else if info.paused.load(Ordering::SeqCst) {
                 // paused task: push to waiting list
                 let mut nodes = self.nodes.lock().unwrap();
-                nodes.get_mut(&info.node()).unwrap().paused.push(runnable);
+                nodes
+                    .get_mut(&info.node())
+                    .unwrap()
+                    .paused
+                    .push((runnable, name));
                 continue;
             }
             // run task
             let node_id = info.node();
+            info.counters.poll();
+            if auditing {
+                if let Some(auditor) = self.auditor.lock().unwrap().as_mut() {
+                    let now = self.time.handle().time_since_clock_base();
+                    auditor.poll(now, woken, node_id, &name);
+                }
+            }
             let _guard = crate::context::enter_task(info);
             let panic_guard = PanicGuard(self);
 
+            self.polls.fetch_add(1, Ordering::Relaxed);
+            BUDGET_LEFT.with(|left| left.set(Some(BUDGET)));
             let result = std::panic::catch_unwind(|| {
                 runnable.run();
             });
+            BUDGET_LEFT.with(|left| left.set(None));
 
             if let Err(err) = result {
                 if let Some(panic_info) = err.downcast_ref::<PanicWrapper>() {
@@ -298,6 +565,10 @@ impl Executor {
 
                         task.fallible().detach();
                     }
+                } else if node_id != NodeId::zero()
+                    && runtime::Handle::current().config.panic == PanicPolicy::CrashNode
+                {
+                    runtime::Handle::current().node_panicked(node_id, panic_message(&*err));
                 } else {
                     std::panic::resume_unwind(err);
                 }
@@ -312,9 +583,59 @@ impl Executor {
             let dur = Duration::from_nanos(self.rand.with(|rng| rng.gen_range(50..100)));
             self.time.advance(dur);
         }
+        if auditing {
+            if let Some(auditor) = self.auditor.lock().unwrap().as_mut() {
+                auditor.end_pass(self.time.handle());
+            }
+        }
     }
 }
 
+impl Executor {
+    /// Take a random task from the ready queue, unless a pinned priority orders another ready
+    /// task before it, see [`TaskHandle::pin_priority`].
+    fn next_ready(&self) -> Result<Scheduled, mpsc::TryRecvError> {
+        let mut priorities = self.priorities.lock().unwrap();
+        if priorities.is_empty() {
+            return self.queue.try_recv_random(&self.rand);
+        }
+        let now = self.time.handle().time_since_clock_base();
+        priorities.retain(|rule| rule.window.end > now);
+        let active: Vec<_> = priorities
+            .iter()
+            .filter(|rule| rule.window.contains(&now))
+            .collect();
+        if active.is_empty() {
+            return self.queue.try_recv_random(&self.rand);
+        }
+        // the rank of a task in the first active rule that names it
+        let rank = |Scheduled { info, name, .. }: &Scheduled| {
+            let node = info.node();
+            active.iter().enumerate().find_map(|(i, rule)| {
+                let (pos, _, _) = rule.order.iter().find(|(_, id, task)| {
+                    *id == node
+                        && task
+                            .as_ref()
+                            .map_or(true, |task| name.as_ref() == Some(task))
+                })?;
+                Some((i, *pos))
+            })
+        };
+        self.queue.try_recv_random_by(
+            &self.rand,
+            |a, b| matches!((rank(a), rank(b)), (Some((i, x)), Some((j, y))) if i == j && x < y),
+        )
+    }
+}
+
+/// An order of named tasks pinned for a window of virtual time.
+struct PriorityRule {
+    window: Range<Duration>,
+    /// The position in the order, the node, and the name of the task, or `None` for every task
+    /// of the node. Nodes sharing a name share a position.
+    order: Vec<(usize, NodeId, Option<Arc<str>>)>,
+}
+
 struct PanicGuard<'a>(&'a Executor);
 impl<'a> Drop for PanicGuard<'a> {
     fn drop(&mut self) {
@@ -333,19 +654,81 @@ impl Deref for Executor {
 
 #[derive(Clone)]
 pub(crate) struct TaskHandle {
-    sender: mpsc::Sender<(Runnable, Arc<TaskInfo>)>,
+    sender: ReadySender,
     nodes: Arc<Mutex<HashMap<NodeId, Node>>>,
     next_node_id: Arc<AtomicU64>,
+    priorities: Arc<Mutex<Vec<PriorityRule>>>,
+    auditor: Arc<Mutex<Option<fairness::Auditor>>>,
+    /// The tasks that run outside of any node.
+    main: Arc<TaskInfo>,
 }
 assert_send_sync!(TaskHandle);
 
 pub(crate) type InitFn = Arc<dyn Fn(&TaskNodeHandle) + Send + Sync>;
 
+pub(crate) type HookFn = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = ()>>> + Send + Sync>;
+
+/// Lifecycle hooks of a node.
+#[derive(Clone)]
+pub(crate) struct NodeHooks {
+    /// Run after the initial task is spawned, when the node is created or restarted.
+    pub on_start: Vec<HookFn>,
+    /// Run before the node is killed or restarted gracefully.
+    pub on_stop: Vec<HookFn>,
+    /// Virtual time after which hooks are cancelled.
+    pub budget: Duration,
+}
+
+impl NodeHooks {
+    /// Spawn the start hooks in the node.
+    pub fn start(&self, handle: &TaskNodeHandle) {
+        for hook in &self.on_start {
+            let (node, budget) = (handle.id(), self.budget);
+            let fut = hook();
+            handle.spawn_local(async move {
+                if crate::time::timeout(budget, fut).await.is_err() {
+                    warn!("start hook of node {node} exceeded its budget of {budget:?}");
+                }
+            });
+        }
+    }
+}
+
+/// The configuration of a node, see [`TaskHandle::create_node`].
+pub(crate) struct NodeSpec {
+    pub name: Option<String>,
+    /// A function to spawn the initial task.
+    pub init: Option<InitFn>,
+    pub hooks: NodeHooks,
+    pub supervisor: Option<SupervisorPolicy>,
+    pub labels: Vec<(String, String)>,
+    pub flags: BTreeMap<String, String>,
+    /// Whether the node is ready as soon as it starts, see [`runtime::NodeBuilder::ready_when`].
+    pub auto_ready: bool,
+}
+
 struct Node {
     info: Arc<TaskInfo>,
-    paused: Vec<Runnable>,
+    paused: Vec<(Runnable, Option<Arc<str>>)>,
+    /// Whether the node was paused by [`TaskHandle::pause`].
+    user_paused: bool,
+    /// The number of faults pausing the node, see [`TaskHandle::pause_for_fault`].
+    fault_pauses: u32,
     /// A function to spawn the initial task.
     init: Option<InitFn>,
+    hooks: NodeHooks,
+    supervisor: Option<SupervisorPolicy>,
+    /// How the node terminated, cleared when it is restarted.
+    exit_status: Option<ExitStatus>,
+    /// Number of restarts by the supervisor.
+    restarts: u32,
+    labels: Vec<(String, String)>,
+    /// Behavior flags, see [`crate::node_config`]. Kept across restarts.
+    flags: BTreeMap<String, String>,
+    /// Whether the node is ready, see `NodeBuilder::ready_when`.
+    ready: watch::Sender<bool>,
+    /// Whether the node is ready as soon as it starts.
+    auto_ready: bool,
 }
 
 impl TaskHandle {
@@ -356,6 +739,9 @@ impl TaskHandle {
         let mut nodes = self.nodes.lock().unwrap();
         let node = nodes.get_mut(&id).expect("node not found");
         node.paused.clear();
+        node.user_paused = false;
+        node.fault_pauses = 0;
+        node.ready.send_replace(false);
         let new_info = Arc::new(TaskInfo::new(id, node.info.name()));
         let old_info = std::mem::replace(&mut node.info, new_info);
         old_info.killed.send_replace(true);
@@ -366,37 +752,255 @@ impl TaskHandle {
         self.kill(id);
         TimeHandle::current().enable_node(id);
 
+        let (handle, init, hooks) = {
+            let mut nodes = self.nodes.lock().unwrap();
+            let node = nodes.get_mut(&id).expect("node not found");
+            node.exit_status = None;
+            if node.auto_ready {
+                node.ready.send_replace(true);
+            }
+            let handle = TaskNodeHandle {
+                sender: self.sender.clone(),
+                info: node.info.clone(),
+            };
+            (handle, node.init.clone(), node.hooks.clone())
+        };
+        // without the lock, as user code may look up nodes.
+        if let Some(init) = &init {
+            init(&handle);
+        }
+        hooks.start(&handle);
+    }
+
+    /// Record how the node terminated. Returns `false` if it has terminated already.
+    pub fn set_exit_status(&self, id: NodeId, status: ExitStatus) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(node) = nodes.get_mut(&id) else {
+            return false;
+        };
+        if node.exit_status.is_some() {
+            return false;
+        }
+        node.exit_status = Some(status);
+        true
+    }
+
+    /// Get how the node terminated, if it has terminated since it was last (re)started.
+    pub fn exit_status(&self, id: NodeId) -> Option<ExitStatus> {
+        let nodes = self.nodes.lock().unwrap();
+        nodes.get(&id)?.exit_status.clone()
+    }
+
+    /// Get the supervisor policy of the node and the number of restarts so far.
+    pub fn supervisor(&self, id: NodeId) -> Option<(SupervisorPolicy, u32)> {
         let nodes = self.nodes.lock().unwrap();
-        let node = nodes.get(&id).expect("node not found");
-        if let Some(init) = &node.init {
-            init(&TaskNodeHandle {
+        let node = nodes.get(&id)?;
+        Some((node.supervisor.clone()?, node.restarts))
+    }
+
+    /// Get the nodes with a label, ordered by id.
+    pub fn nodes_with_label(&self, key: &str, value: &str) -> Vec<NodeId> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut ids: Vec<_> = nodes
+            .iter()
+            .filter(|(_, node)| node.labels.iter().any(|(k, v)| k == key && v == value))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Access the behavior flags of a node. Returns `None` if the node does not exist.
+    pub fn with_flags<T>(
+        &self,
+        id: NodeId,
+        f: impl FnOnce(&mut BTreeMap<String, String>) -> T,
+    ) -> Option<T> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Some(f(&mut nodes.get_mut(&id)?.flags))
+    }
+
+    /// Mark the node as ready or not.
+    pub fn set_ready(&self, id: NodeId, ready: bool) {
+        if let Some(node) = self.nodes.lock().unwrap().get(&id) {
+            node.ready.send_replace(ready);
+        }
+    }
+
+    /// Watch whether the node is ready.
+    pub fn watch_ready(&self, id: NodeId) -> Option<watch::Receiver<bool>> {
+        let nodes = self.nodes.lock().unwrap();
+        Some(nodes.get(&id)?.ready.subscribe())
+    }
+
+    /// Get the runtime metrics of the node since it was last (re)started.
+    pub fn counters(&self, id: NodeId) -> Option<Arc<Counters>> {
+        let nodes = self.nodes.lock().unwrap();
+        Some(nodes.get(&id)?.info.counters.clone())
+    }
+
+    /// Count a restart by the supervisor.
+    pub fn count_restart(&self, id: NodeId) {
+        if let Some(node) = self.nodes.lock().unwrap().get_mut(&id) {
+            node.restarts += 1;
+        }
+    }
+
+    /// Spawn a task outside of any node.
+    pub fn spawn_on_main<F>(&self, future: F)
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let handle = TaskNodeHandle {
+            sender: self.sender.clone(),
+            info: self.main.clone(),
+        };
+        // dropping the join handle detaches the task.
+        drop(handle.spawn_local(future));
+    }
+
+    /// Run the stop hooks of the node, and wait until they finish or exceed their budget.
+    ///
+    /// Returns `false` if the budget was exceeded.
+    pub async fn stop(&self, id: NodeId) -> bool {
+        let (handle, hooks) = {
+            let nodes = self.nodes.lock().unwrap();
+            let node = nodes.get(&id).expect("node not found");
+            let handle = TaskNodeHandle {
                 sender: self.sender.clone(),
                 info: node.info.clone(),
-            });
+            };
+            (handle, node.hooks.clone())
+        };
+        // without the lock, as hooks may look up nodes.
+        let handles: Vec<_> = (hooks.on_stop.iter())
+            .map(|hook| handle.spawn_local(hook()))
+            .collect();
+        let budget = hooks.budget;
+        let finished = crate::time::timeout(budget, futures::future::join_all(handles))
+            .await
+            .is_ok();
+        if !finished {
+            warn!("stop hooks of node {id} exceeded their budget of {budget:?}");
         }
+        finished
     }
 
     /// Pause all tasks of the node.
     pub fn pause(&self, id: NodeId) {
-        let nodes = self.nodes.lock().unwrap();
-        let node = nodes.get(&id).expect("node not found");
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(&id).expect("node not found");
+        node.user_paused = true;
         node.info.paused.store(true, Ordering::SeqCst);
     }
 
-    /// Resume the execution of the address.
+    /// Resume the execution of the address, unless a fault still pauses it.
     pub fn resume(&self, id: NodeId) {
         let mut nodes = self.nodes.lock().unwrap();
         let node = nodes.get_mut(&id).expect("node not found");
+        node.user_paused = false;
+        if node.fault_pauses == 0 {
+            self.resume_node(node);
+        }
+    }
+
+    /// Pause all tasks of the node until [`resume_for_fault`](Self::resume_for_fault) is called
+    /// as many times, independently of [`pause`](Self::pause) and [`resume`](Self::resume).
+    ///
+    /// Returns whether the node was running.
+    pub fn pause_for_fault(&self, id: NodeId) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(&id).expect("node not found");
+        node.fault_pauses += 1;
+        !node.info.paused.swap(true, Ordering::SeqCst)
+    }
+
+    /// End a pause of [`pause_for_fault`](Self::pause_for_fault). The node resumes when no fault
+    /// pauses it, unless it was paused by [`pause`](Self::pause).
+    ///
+    /// Returns whether the node resumed.
+    pub fn resume_for_fault(&self, id: NodeId) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(node) = nodes.get_mut(&id) else {
+            return false;
+        };
+        // the node may have been restarted since, which ends all pauses.
+        if node.fault_pauses == 0 {
+            return false;
+        }
+        node.fault_pauses -= 1;
+        if node.fault_pauses > 0 || node.user_paused {
+            return false;
+        }
+        self.resume_node(node);
+        true
+    }
+
+    fn resume_node(&self, node: &mut Node) {
         node.info.paused.store(false, Ordering::SeqCst);
 
         // take paused tasks from waiting list and push them to ready queue
-        for runnable in node.paused.drain(..) {
-            self.sender.send((runnable, node.info.clone())).unwrap();
+        for (runnable, name) in node.paused.drain(..) {
+            self.sender.send(runnable, node.info.clone(), name).unwrap();
         }
     }
 
+    /// Pin the order of named tasks during a window of virtual time, see
+    /// [`runtime::Handle::pin_priority`].
+    pub fn pin_priority(&self, window: Range<Duration>, order: Vec<String>) {
+        let nodes = self.nodes.lock().unwrap();
+        let mut resolved = vec![];
+        for (pos, pattern) in order.iter().enumerate() {
+            let (name, task) = match pattern.split_once('/') {
+                Some((name, task)) => (name, Some(Arc::<str>::from(task))),
+                None => (pattern.as_str(), None),
+            };
+            let matching = nodes.values().filter(|node| node.info.inner.name == name);
+            let before = resolved.len();
+            resolved.extend(matching.map(|node| (pos, node.info.node(), task.clone())));
+            if resolved.len() == before {
+                warn!("pinned priority names no node: {pattern}");
+            }
+        }
+        let rule = PriorityRule {
+            window,
+            order: resolved,
+        };
+        self.priorities.lock().unwrap().push(rule);
+    }
+
+    /// Remove all pinned priorities.
+    pub fn clear_priorities(&self) {
+        self.priorities.lock().unwrap().clear();
+    }
+
+    /// Start recording starvations longer than `threshold`, see
+    /// [`runtime::Handle::audit_starvation`].
+    pub fn audit_starvation(&self, threshold: Duration) {
+        *self.auditor.lock().unwrap() = Some(fairness::Auditor::new(threshold));
+        self.sender.auditing.store(true, Ordering::Relaxed);
+    }
+
+    /// The starvations recorded so far.
+    pub fn starvations(&self) -> Vec<Starvation> {
+        let auditor = self.auditor.lock().unwrap();
+        auditor
+            .as_ref()
+            .map_or_else(Vec::new, |auditor| auditor.starvations().to_vec())
+    }
+
     /// Create a new node.
-    pub fn create_node(&self, name: Option<String>, init: Option<InitFn>) -> TaskNodeHandle {
+    pub fn create_node(&self, spec: NodeSpec) -> TaskNodeHandle {
+        let NodeSpec {
+            name,
+            init,
+            hooks,
+            supervisor,
+            labels,
+            flags,
+            auto_ready,
+        } = spec;
         let id = NodeId(self.next_node_id.fetch_add(1, Ordering::SeqCst));
         let name = name.unwrap_or_else(|| format!("node-{}", id.0));
         let info = Arc::new(TaskInfo::new(id, name));
@@ -407,10 +1011,21 @@ impl TaskHandle {
         if let Some(init) = &init {
             init(&handle);
         }
+        hooks.start(&handle);
         let node = Node {
             info,
             paused: vec![],
+            user_paused: false,
+            fault_pauses: 0,
             init,
+            hooks,
+            supervisor,
+            exit_status: None,
+            restarts: 0,
+            labels,
+            flags,
+            ready: watch::channel(auto_ready).0,
+            auto_ready,
         };
         self.nodes.lock().unwrap().insert(id, node);
         handle
@@ -422,6 +1037,54 @@ impl TaskHandle {
         assert!(nodes.remove(&id).is_some());
     }
 
+    /// The tasks that run outside of any node.
+    pub(crate) fn main_info(&self) -> Arc<TaskInfo> {
+        self.main.clone()
+    }
+
+    /// Get the info of all nodes, ordered by id.
+    pub(crate) fn node_infos(&self) -> Vec<Arc<TaskInfo>> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut infos: Vec<_> = nodes.values().map(|node| node.info.clone()).collect();
+        infos.sort_by_key(|info| info.node());
+        infos
+    }
+
+    /// List the tasks that have not finished, one per line, with their node. The tasks outside
+    /// of any node are listed last.
+    pub(crate) fn describe_tasks(&self) -> String {
+        let mut tasks = String::new();
+        for info in self.node_infos().iter().chain([&self.main]) {
+            for task in info.live_tasks() {
+                let _ = write!(tasks, "\n  {} {}: {task}", info.node(), info.name());
+            }
+        }
+        tasks
+    }
+
+    /// Describe all nodes except the main node, ordered by id. The ips are not set.
+    pub(crate) fn describe_nodes(&self) -> Vec<runtime::NodeDescriptor> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut descriptors: Vec<_> = nodes
+            .iter()
+            .filter(|(id, _)| **id != NodeId::zero())
+            .map(|(id, node)| runtime::NodeDescriptor {
+                id: *id,
+                name: node.info.name(),
+                ip: None,
+                labels: node.labels.clone(),
+                state: match &node.exit_status {
+                    Some(status) => runtime::NodeState::Exited(status.clone()),
+                    None if node.info.is_paused() => runtime::NodeState::Paused,
+                    None => runtime::NodeState::Running,
+                },
+                restarts: node.restarts,
+            })
+            .collect();
+        descriptors.sort_by_key(|node| node.id);
+        descriptors
+    }
+
     /// Get the node handle.
     pub fn get_node(&self, id: NodeId) -> Option<TaskNodeHandle> {
         let nodes = self.nodes.lock().unwrap();
@@ -435,7 +1098,7 @@ impl TaskHandle {
 
 #[derive(Clone)]
 pub(crate) struct TaskNodeHandle {
-    sender: mpsc::Sender<(Runnable, Arc<TaskInfo>)>,
+    sender: ReadySender,
     info: Arc<TaskInfo>,
 }
 
@@ -456,6 +1119,15 @@ impl TaskNodeHandle {
         self.info.node()
     }
 
+    pub(crate) fn name(&self) -> String {
+        self.info.name()
+    }
+
+    pub(crate) fn counters(&self) -> Arc<Counters> {
+        self.info.counters.clone()
+    }
+
+    #[track_caller]
     pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
     where
         F: Future + 'static,
@@ -464,7 +1136,18 @@ impl TaskNodeHandle {
         self.spawn_local(future)
     }
 
+    #[track_caller]
     pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_named(None, future)
+    }
+
+    /// Spawn a task with a name, by which its priority can be pinned.
+    #[track_caller]
+    pub fn spawn_named<F>(&self, name: Option<Arc<str>>, future: F) -> JoinHandle<F::Output>
     where
         F: Future + 'static,
         F::Output: 'static,
@@ -472,35 +1155,46 @@ impl TaskNodeHandle {
         let sender = self.sender.clone();
         let info = self.info.clone();
         let mut killed_rx = info.killed.subscribe();
+        let alive = info.counters.spawn();
+        let live = info.track(name.clone(), Location::caller());
 
         let future = async move {
+            let _alive = alive;
             pin_mut!(future);
-            loop {
-                select! {
-                    _ = killed_rx.changed() => {
-                        if *killed_rx.borrow() {
-                            // when a cancelled task is run by run_all_ready(), it is dropped rather
-                            // than being executed. Therefore this should never run. However, we must
-                            // poll killed_rx in order to force this task to wake up when its node is
-                            // killed. (Otherwise the task will not be dropped until its next
-                            // scheduled wakeup, which may be never if it is listening for network
-                            // messages).
-                            panic!("killed task must not run!");
+            let future = async move {
+                loop {
+                    select! {
+                        _ = killed_rx.changed() => {
+                            if *killed_rx.borrow() {
+                                // when a cancelled task is run by run_all_ready(), it is dropped
+                                // rather than being executed. Therefore this should never run.
+                                // However, we must poll killed_rx in order to force this task to
+                                // wake up when its node is killed. (Otherwise the task will not be
+                                // dropped until its next scheduled wakeup, which may be never if it
+                                // is listening for network messages).
+                                panic!("killed task must not run!");
+                            }
                         }
-                    }
 
-                    output = &mut future => {
-                        break output;
+                        output = &mut future => {
+                            break output;
+                        }
                     }
                 }
-            }
+            };
+            pin_mut!(future);
+            std::future::poll_fn(|cx| {
+                live.polled();
+                future.as_mut().poll(cx)
+            })
+            .await
         };
 
         let (runnable, task) = unsafe {
             // Safety: The schedule is not Sync,
             // the task's Waker must be used and dropped on the original thread.
             async_task::spawn_unchecked(future, move |runnable| {
-                let _ = sender.send((runnable, info.clone()));
+                let _ = sender.send(runnable, info.clone(), name.clone());
             })
         };
         runnable.schedule();
@@ -549,6 +1243,7 @@ where
 }
 
 /// Spawns a new asynchronous task, returning a [`JoinHandle`] for it.
+#[track_caller]
 pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
 where
     F: Future + Send + 'static,
@@ -559,6 +1254,7 @@ where
 }
 
 /// Spawns a `!Send` future on the local task set.
+#[track_caller]
 pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
 where
     F: Future + 'static,
@@ -568,6 +1264,21 @@ where
     handle.spawn_local(future)
 }
 
+/// Spawns a new asynchronous task with a name, returning a [`JoinHandle`] for it.
+///
+/// The name identifies the task as `node/name` to [`Handle::pin_priority`].
+///
+/// [`Handle::pin_priority`]: crate::runtime::Handle::pin_priority
+#[track_caller]
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let handle = TaskNodeHandle::current();
+    handle.spawn_named(Some(name.into()), future)
+}
+
 /// Runs the provided closure on a thread where blocking is acceptable.
 pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
 where
@@ -811,6 +1522,243 @@ mod tests {
         });
     }
 
+    #[test]
+    fn parallel_runtimes() {
+        fn run(seed: u64) -> Vec<u64> {
+            let runtime = Runtime::with_seed(seed);
+            let log = Arc::new(Mutex::new(vec![]));
+            let log_ = log.clone();
+            runtime
+                .create_node()
+                .init(move || {
+                    let log = log_.clone();
+                    async move {
+                        loop {
+                            let ms = crate::rand::thread_rng().gen_range(1..1000);
+                            time::sleep(Duration::from_millis(ms)).await;
+                            log.lock().unwrap().push(ms);
+                            if ms % 3 == 0 {
+                                kill_current_node(Some(Duration::from_secs(1)));
+                            }
+                        }
+                    }
+                })
+                .build();
+            runtime.block_on(async move {
+                time::sleep(Duration::from_secs(20)).await;
+                log.lock().unwrap().clone()
+            })
+        }
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| std::thread::spawn(move || run(i % 2)))
+            .collect();
+        let logs: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        // nodes were killed and restarted
+        assert!(logs.iter().all(|log| log.iter().any(|ms| ms % 3 == 0)));
+        assert_eq!(logs[0], logs[2]);
+        assert_eq!(logs[1], logs[3]);
+        assert_ne!(logs[0], logs[1]);
+    }
+
+    #[test]
+    fn lifecycle_hooks() {
+        use crate::runtime::Termination;
+
+        let runtime = Runtime::new();
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let (log1, log2) = (log.clone(), log.clone());
+        let graceful = runtime
+            .create_node()
+            .on_start(move || {
+                let log = log1.clone();
+                // hooks may look up nodes, here while the node restarts.
+                if let Some(handle) = Handle::try_current() {
+                    handle.nodes_with_label("role", "none");
+                }
+                async move { log.lock().unwrap().push("start") }
+            })
+            .on_stop(move || {
+                let log = log2.clone();
+                Handle::current().nodes_with_label("role", "none");
+                async move {
+                    time::sleep(Duration::from_secs(1)).await;
+                    log.lock().unwrap().push("stop");
+                }
+            })
+            .build();
+
+        let log_ = log.clone();
+        let forced = runtime
+            .create_node()
+            .hook_budget(Duration::from_secs(2))
+            .on_stop(move || {
+                let log = log_.clone();
+                async move {
+                    time::sleep(Duration::from_secs(10)).await;
+                    log.lock().unwrap().push("never");
+                }
+            })
+            .build();
+
+        runtime.block_on(async move {
+            time::sleep(Duration::from_secs(1)).await;
+            let handle = Handle::current();
+
+            let t0 = time::Instant::now();
+            let termination = handle.restart_graceful(graceful.id()).await;
+            assert_eq!(termination, Termination::Graceful);
+            let elapsed = t0.elapsed();
+            assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(2));
+            time::sleep(Duration::from_secs(1)).await;
+            assert_eq!(*log.lock().unwrap(), ["start", "stop", "start"]);
+
+            let t0 = time::Instant::now();
+            let termination = handle.kill_graceful(forced.id()).await;
+            assert_eq!(termination, Termination::Forced);
+            let elapsed = t0.elapsed();
+            assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3));
+
+            // hooks do not run on a forced kill
+            handle.kill(graceful.id());
+            time::sleep(Duration::from_secs(20)).await;
+            assert_eq!(*log.lock().unwrap(), ["start", "stop", "start"]);
+        });
+    }
+
+    #[test]
+    fn supervisor() {
+        use crate::{
+            runtime::{ExitStatus, SupervisorPolicy},
+            trace,
+        };
+
+        let runtime = Runtime::new();
+        runtime.handle().trace().enable();
+        let policy = SupervisorPolicy {
+            max_restarts: Some(2),
+            ..Default::default()
+        };
+
+        // panics twice, then succeeds
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs_ = runs.clone();
+        let flaky = runtime
+            .create_node()
+            .supervisor(policy.clone())
+            .init(move || {
+                let runs = runs_.clone();
+                async move {
+                    time::sleep(Duration::from_secs(1)).await;
+                    if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                        panic!("flaky");
+                    }
+                }
+            })
+            .build();
+
+        // always fails
+        let failing = runtime
+            .create_node()
+            .supervisor(policy)
+            .init(|| async { exit(3) })
+            .build();
+
+        runtime.block_on(async move {
+            let handle = Handle::current();
+            // panics at 1s and 3s, restarted after backoffs of 1s and 2s, succeeds at 6s
+            time::sleep(Duration::from_millis(6500)).await;
+            assert_eq!(runs.load(Ordering::SeqCst), 3);
+            assert_eq!(handle.exit_status(flaky.id()), Some(ExitStatus::Exited(0)));
+            assert_eq!(
+                handle.exit_status(failing.id()),
+                Some(ExitStatus::Exited(3))
+            );
+
+            let timeline = handle.trace().timeline();
+            assert_eq!(timeline.count(&trace::node_exit(flaky.id())), 3);
+            assert_eq!(timeline.count(&trace::node_restart(flaky.id())), 2);
+            assert_eq!(timeline.count(&trace::node_give_up(flaky.id())), 0);
+            assert_eq!(timeline.count(&trace::node_restart(failing.id())), 2);
+            assert_eq!(timeline.count(&trace::node_give_up(failing.id())), 1);
+            assert!(timeline.events().iter().any(|e| e.kind
+                == trace::EventKind::NodeExit {
+                    node: flaky.id(),
+                    status: ExitStatus::Panicked("flaky".into()),
+                }));
+        });
+    }
+
+    #[test]
+    fn crash_on_panic() {
+        use crate::{
+            runtime::{ExitStatus, SupervisorPolicy},
+            SimConfig,
+        };
+
+        let config = SimConfig {
+            panic: PanicPolicy::CrashNode,
+            ..Default::default()
+        };
+        let runtime = Runtime::with_seed_and_config(0, config);
+
+        // a spawned task of an unsupervised node panics
+        let worker = runtime.create_node().name("worker").build();
+        worker.spawn(async {
+            time::sleep(Duration::from_secs(1)).await;
+            panic!("worker bug");
+        });
+        let alive = Arc::new(AtomicUsize::new(0));
+        let alive_ = alive.clone();
+        worker.spawn(async move {
+            loop {
+                time::sleep(Duration::from_millis(100)).await;
+                alive_.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        // the initial task of a supervised node panics once
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runs_ = runs.clone();
+        let server = runtime
+            .create_node()
+            .name("server")
+            .supervisor(SupervisorPolicy::default())
+            .init(move || {
+                let runs = runs_.clone();
+                async move {
+                    time::sleep(Duration::from_secs(2)).await;
+                    if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("server bug");
+                    }
+                    std::future::pending::<()>().await;
+                }
+            })
+            .build();
+
+        runtime.block_on(async move {
+            let handle = Handle::current();
+            time::sleep(Duration::from_millis(5500)).await;
+            // the other tasks of the crashed node were killed
+            assert!(alive.load(Ordering::SeqCst) < 10);
+            assert_eq!(
+                handle.exit_status(worker.id()),
+                Some(ExitStatus::Panicked("worker bug".into()))
+            );
+            // panicked at 2s, restarted at 3s, running again
+            assert_eq!(runs.load(Ordering::SeqCst), 2);
+            assert_eq!(handle.exit_status(server.id()), None);
+
+            let panics = handle.node_panics();
+            let crashed: Vec<_> = panics.iter().map(|p| (p.node, p.name.as_str())).collect();
+            assert_eq!(crashed, [(worker.id(), "worker"), (server.id(), "server")]);
+            assert_eq!(panics[1].message, "server bug");
+        });
+        let report = runtime.failure_report();
+        assert_eq!(report.section("panics.txt").unwrap().lines().count(), 2);
+    }
+
     #[test]
     fn pause_resume() {
         let runtime = Runtime::new();
@@ -991,4 +1939,95 @@ mod tests {
             assert!(flag.load(Ordering::Relaxed));
         });
     }
+
+    #[test]
+    fn pin_priority() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().name("n3").build();
+
+        // two tasks that are always ready, and log each time they are polled
+        let run = move || {
+            node.spawn(async move {
+                let log = Arc::new(Mutex::new(vec![]));
+                let tasks: Vec<_> = ["consensus", "network"]
+                    .into_iter()
+                    .map(|name| {
+                        let log = log.clone();
+                        spawn_named(name, async move {
+                            for _ in 0..50 {
+                                log.lock().unwrap().push(name);
+                                yield_now().await;
+                            }
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+                Arc::try_unwrap(log).unwrap().into_inner().unwrap()
+            })
+        };
+        let interleaved = |log: &[&str]| log.windows(2).any(|w| w == ["network", "consensus"]);
+
+        runtime.handle().pin_priority(
+            Duration::ZERO..Duration::from_secs(1),
+            ["n3/consensus", "n3/network"],
+        );
+        let log = runtime.block_on(run.clone()()).unwrap();
+        assert_eq!(log.len(), 100);
+        assert!(!interleaved(&log), "{log:?}");
+
+        // outside of the window
+        runtime.block_on(async { time::sleep(Duration::from_secs(1)).await });
+        let log = runtime.block_on(run()).unwrap();
+        assert!(interleaved(&log), "{log:?}");
+        // the rule expired with its window
+        assert!(runtime.handle().task.priorities.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn audit_starvation() {
+        let runtime = Runtime::new();
+        let node1 = runtime.create_node().name("n1").build();
+        let node2 = runtime.create_node().name("n2").build();
+        let handle = runtime.handle();
+        handle.audit_starvation(Duration::from_millis(1));
+        // the victim is only polled once the spinning task is done
+        handle.pin_priority(
+            Duration::ZERO..Duration::from_secs(1),
+            ["n1/spin", "n1/victim"],
+        );
+
+        node2.spawn(async { time::sleep(Duration::from_millis(1)).await });
+        let f = node1.spawn(async {
+            let spin = spawn_named("spin", async {
+                for _ in 0..100_000 {
+                    yield_now().await;
+                }
+            });
+            let victim = spawn_named("victim", async {});
+            spin.await.unwrap();
+            victim.await.unwrap();
+        });
+        runtime.block_on(f).unwrap();
+
+        let starvations = handle.starvations();
+        let kinds: Vec<_> = starvations.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, [StarvationKind::Task, StarvationKind::Timer]);
+        let (task, timer) = (&starvations[0], &starvations[1]);
+        assert_eq!((timer.node, timer.task.as_deref()), (node2.id(), None));
+        assert_eq!(
+            (task.node, task.task.as_deref()),
+            (node1.id(), Some("victim"))
+        );
+        for starvation in &starvations {
+            assert!(starvation.waited > Duration::from_millis(1));
+            let busy = &starvation.busy[0];
+            assert_eq!(
+                (busy.node, busy.task.as_deref()),
+                (node1.id(), Some("spin"))
+            );
+            assert!(busy.polls > 10_000, "{starvation}");
+        }
+    }
 }
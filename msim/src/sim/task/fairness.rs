@@ -0,0 +1,173 @@
+//! Starvation auditing.
+//!
+//! The executor polls the ready tasks in a random order until none is left, and only then fires
+//! the timers that expired meanwhile. Tasks that are always ready, e.g. looping over
+//! [`yield_now`](super::yield_now), delay the other ready tasks and every timer, which shows up
+//! in downstream code as timeouts. The [`Auditor`] measures in virtual time how long each task
+//! waits between being woken and being polled, and how late the earliest timer is at the end of
+//! each pass of the executor, and records a [`Starvation`] with the busiest tasks of the pass
+//! when either exceeds a threshold.
+
+use super::NodeId;
+use crate::time::TimeHandle;
+use std::{cmp::Reverse, collections::BTreeMap, fmt, sync::Arc, time::Duration};
+use tracing::warn;
+
+/// What starved, see [`Starvation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StarvationKind {
+    /// A task was ready but not polled.
+    Task,
+    /// A timer expired but did not fire.
+    Timer,
+}
+
+/// A task or a timer that waited longer than the threshold of the audit, see
+/// [`Handle::audit_starvation`](crate::runtime::Handle::audit_starvation).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Starvation {
+    /// Whether a task or a timer starved.
+    pub kind: StarvationKind,
+    /// The node of the task, or the node that set the timer.
+    pub node: NodeId,
+    /// The name of the task, if it was spawned with [`spawn_named`](super::spawn_named).
+    pub task: Option<String>,
+    /// When the task was woken or the timer expired, as virtual time since the start of the
+    /// simulation.
+    pub since: Duration,
+    /// How long it waited.
+    pub waited: Duration,
+    /// The tasks polled meanwhile, the five busiest first.
+    pub busy: Vec<BusyTask>,
+}
+
+/// A task polled while another starved, see [`Starvation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusyTask {
+    /// The node of the task.
+    pub node: NodeId,
+    /// The name of the task, if it was spawned with [`spawn_named`](super::spawn_named).
+    pub task: Option<String>,
+    /// Number of times the task was polled.
+    pub polls: u64,
+}
+
+fn fmt_task(f: &mut fmt::Formatter<'_>, node: NodeId, task: &Option<String>) -> fmt::Result {
+    match task {
+        Some(task) => write!(f, "{node}/{task}"),
+        None => write!(f, "{node}"),
+    }
+}
+
+impl fmt::Display for Starvation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            StarvationKind::Task => write!(f, "task ")?,
+            StarvationKind::Timer => write!(f, "timer of ")?,
+        }
+        fmt_task(f, self.node, &self.task)?;
+        write!(f, " starved for {:?} since {:?}", self.waited, self.since)?;
+        for (i, busy) in self.busy.iter().enumerate() {
+            f.write_str(if i == 0 { ", busy: " } else { ", " })?;
+            fmt_task(f, busy.node, &busy.task)?;
+            write!(f, " ({} polls)", busy.polls)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of busy tasks recorded with a starvation.
+const MAX_BUSY: usize = 5;
+
+/// A task as identified by the auditor.
+type TaskKey = (NodeId, Option<Arc<str>>);
+
+/// Records starvations longer than a threshold.
+pub(crate) struct Auditor {
+    threshold: Duration,
+    /// The tasks polled in the current pass of the executor, and when.
+    polls: Vec<(Duration, TaskKey)>,
+    starvations: Vec<Starvation>,
+}
+
+impl Auditor {
+    pub fn new(threshold: Duration) -> Self {
+        Auditor {
+            threshold,
+            polls: Vec::new(),
+            starvations: Vec::new(),
+        }
+    }
+
+    pub fn starvations(&self) -> &[Starvation] {
+        &self.starvations
+    }
+
+    /// Called when the executor starts polling the ready tasks.
+    pub fn start_pass(&mut self) {
+        self.polls.clear();
+    }
+
+    /// Called before a task woken at `woken` is polled at `now`.
+    pub fn poll(
+        &mut self,
+        now: Duration,
+        woken: Option<Duration>,
+        node: NodeId,
+        name: &Option<Arc<str>>,
+    ) {
+        if let Some(woken) = woken {
+            let waited = now.saturating_sub(woken);
+            if waited > self.threshold {
+                self.record(StarvationKind::Task, (node, name.clone()), woken, waited);
+            }
+        }
+        self.polls.push((now, (node, name.clone())));
+    }
+
+    /// Called when no task is ready, before the expired timers fire.
+    pub fn end_pass(&mut self, time: &TimeHandle) {
+        let Some(timer) = time.next_timer() else {
+            return;
+        };
+        let waited = time.now_instant().saturating_duration_since(timer.deadline);
+        if waited > self.threshold {
+            let since = time.time_since_clock_base() - waited;
+            self.record(StarvationKind::Timer, (timer.node, None), since, waited);
+        }
+    }
+
+    fn record(
+        &mut self,
+        kind: StarvationKind,
+        (node, task): TaskKey,
+        since: Duration,
+        waited: Duration,
+    ) {
+        let mut counts = BTreeMap::<&TaskKey, u64>::new();
+        for (_, key) in self.polls.iter().filter(|(at, _)| *at >= since) {
+            *counts.entry(key).or_default() += 1;
+        }
+        let mut busy: Vec<_> = counts
+            .into_iter()
+            .map(|((node, task), polls)| BusyTask {
+                node: *node,
+                task: task.as_deref().map(Into::into),
+                polls,
+            })
+            .collect();
+        // stable: ties stay ordered by task
+        busy.sort_by_key(|busy| Reverse(busy.polls));
+        busy.truncate(MAX_BUSY);
+        let starvation = Starvation {
+            kind,
+            node,
+            task: task.as_deref().map(Into::into),
+            since,
+            waited,
+            busy,
+        };
+        warn!("{starvation}");
+        self.starvations.push(starvation);
+    }
+}
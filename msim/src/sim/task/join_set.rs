@@ -36,6 +36,7 @@ impl<T> JoinSet<T> {
 }
 
 impl<T: 'static> JoinSet<T> {
+    #[track_caller]
     pub fn spawn<F>(&mut self, task: F) -> AbortHandle
     where
         F: Future<Output = T>,
@@ -45,6 +46,7 @@ impl<T: 'static> JoinSet<T> {
         self.insert(crate::task::spawn(task))
     }
 
+    #[track_caller]
     pub fn spawn_on<F>(&mut self, task: F, _handle: &Handle) -> AbortHandle
     where
         F: Future<Output = T>,
@@ -54,6 +56,7 @@ impl<T: 'static> JoinSet<T> {
         self.insert(crate::task::spawn(task))
     }
 
+    #[track_caller]
     pub fn spawn_local<F>(&mut self, task: F) -> AbortHandle
     where
         F: Future<Output = T>,
@@ -62,6 +65,7 @@ impl<T: 'static> JoinSet<T> {
         self.insert(crate::task::spawn_local(task))
     }
 
+    #[track_caller]
     pub fn spawn_local_on<F>(&mut self, task: F, _local_set: &LocalSet) -> AbortHandle
     where
         F: Future<Output = T>,
@@ -0,0 +1,305 @@
+//! Assertions on the virtual time taken by operations.
+
+use super::{Duration, TimeHandle};
+use crate::{runtime::Handle, task::NodeId};
+use std::{collections::BTreeMap, fmt::Write, future::Future, panic::Location};
+
+/// Run `future`, and panic if it does not complete within `limit` of virtual time.
+///
+/// The panic message describes the state of the simulation when the limit was exceeded: the
+/// state of every node, its pending timers, and its tasks that have not finished, with where they
+/// were spawned and when they were last polled. This turns latency objectives into one-liners:
+///
+/// ```ignore
+/// let reply = assert_completes_within(Duration::from_millis(500), client.call(req)).await;
+/// ```
+#[track_caller]
+pub fn assert_completes_within<F: Future>(
+    limit: Duration,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    let location = Location::caller();
+    async move {
+        let start = TimeHandle::current().elapsed();
+        match super::timeout(limit, future).await {
+            Ok(output) => output,
+            Err(_) => panic!(
+                "operation at {location} did not complete within {limit:?} \
+                 (started at {start:?}){}",
+                pending_report()
+            ),
+        }
+    }
+}
+
+/// Run `future`, and panic if it completes in less than `min` of virtual time.
+///
+/// This checks that timeouts, backoffs and rate limits actually hold the caller back. See
+/// [`assert_completes_within`] for the panic message.
+#[track_caller]
+pub fn assert_takes_at_least<F: Future>(
+    min: Duration,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    let location = Location::caller();
+    async move {
+        let time = TimeHandle::current();
+        let start = time.elapsed();
+        let output = future.await;
+        let elapsed = time.elapsed() - start;
+        if elapsed < min {
+            panic!(
+                "operation at {location} completed in {elapsed:?}, expected at least {min:?} \
+                 (started at {start:?}){}",
+                pending_report()
+            );
+        }
+        output
+    }
+}
+
+/// Describe the nodes of the simulation, their pending timers, and their tasks that have not
+/// finished.
+fn pending_report() -> String {
+    let handle = Handle::current();
+    let mut timers = TimeHandle::current().pending_timers();
+    let mut tasks: BTreeMap<_, _> = handle
+        .task
+        .node_infos()
+        .into_iter()
+        .chain([handle.task.main_info()])
+        .map(|info| (info.node(), info.live_tasks()))
+        .collect();
+    let mut report = String::new();
+    let mut describe_tasks = |report: &mut String, node: NodeId| {
+        for task in tasks.remove(&node).unwrap_or_default() {
+            let _ = write!(report, "\n    {task}");
+        }
+    };
+    for node in handle.nodes() {
+        let _ = write!(report, "\n  {} {}: {}", node.id, node.name, node.state);
+        match timers.remove(&node.id) {
+            Some((count, next)) => {
+                let _ = write!(report, ", {count} timers pending, next in {next:?}");
+            }
+            None => report.push_str(", no timers pending"),
+        }
+        describe_tasks(&mut report, node.id);
+    }
+    report.push_str("\n  main: ");
+    match timers.remove(&NodeId::zero()) {
+        Some((count, next)) => {
+            let _ = write!(report, "{count} timers pending, next in {next:?}");
+        }
+        None => report.push_str("no timers pending"),
+    }
+    describe_tasks(&mut report, NodeId::zero());
+    report
+}
+
+/// State of an [`eventually!`](crate::eventually) check. Not intended to be used directly.
+#[doc(hidden)]
+pub struct Eventually {
+    what: &'static str,
+    location: &'static str,
+    line: u32,
+    within: Duration,
+    poll: Duration,
+    start: Duration,
+    polls: u64,
+    observed: Option<String>,
+    /// The result and the observed value of each poll where either changed.
+    history: Vec<(Duration, bool, Option<String>)>,
+}
+
+impl Eventually {
+    pub fn new(what: &'static str, location: &'static str, line: u32) -> Self {
+        Eventually {
+            what,
+            location,
+            line,
+            within: Duration::from_secs(30),
+            poll: Duration::from_millis(100),
+            start: TimeHandle::current().elapsed(),
+            polls: 0,
+            observed: None,
+            history: vec![],
+        }
+    }
+
+    pub fn within(&mut self, within: Duration) {
+        self.within = within;
+    }
+
+    pub fn poll(&mut self, poll: Duration) {
+        self.poll = poll;
+    }
+
+    pub fn observe(&mut self, value: String) {
+        self.observed = Some(value);
+    }
+
+    /// Record the result of a poll. Returns `true` if the condition holds, panics if it did not
+    /// hold within the limit, and waits for the next poll otherwise.
+    pub async fn check(&mut self, ok: bool) -> bool {
+        let elapsed = TimeHandle::current().elapsed() - self.start;
+        self.polls += 1;
+        let observed = self.observed.take();
+        let changed = match self.history.last() {
+            Some((_, last_ok, last_observed)) => *last_ok != ok || *last_observed != observed,
+            None => true,
+        };
+        if changed {
+            self.history.push((elapsed, ok, observed));
+        }
+        if ok {
+            return true;
+        }
+        if elapsed >= self.within {
+            let mut report = String::new();
+            for (at, ok, observed) in &self.history {
+                let _ = write!(report, "\n  at {at:?}: {ok}");
+                if let Some(observed) = observed {
+                    let _ = write!(report, ", observed {observed}");
+                }
+            }
+            panic!(
+                "condition `{}` at {}:{} did not hold within {:?} ({} polls), history:{report}",
+                self.what, self.location, self.line, self.within, self.polls
+            );
+        }
+        super::sleep(self.poll).await;
+        false
+    }
+}
+
+/// Wait until a condition holds, polling it in virtual time, and panic if it does not hold in
+/// time.
+///
+/// Options are given as `key = value` after the condition:
+/// - `within`: how long to wait for the condition, 30 seconds by default.
+/// - `poll`: the interval between polls, 100 milliseconds by default.
+/// - `observe`: an expression evaluated at every poll, whose `Debug` value is reported with the
+///   history of the condition when it does not hold in time.
+///
+/// The condition and the observed expression may `.await`. Must be used in an async context.
+///
+/// ```ignore
+/// eventually!(
+///     node.height().await >= 10,
+///     within = Duration::from_secs(60),
+///     observe = node.height().await,
+/// );
+/// ```
+#[macro_export]
+macro_rules! eventually {
+    ($cond:expr $(, $key:ident = $value:expr)* $(,)?) => {{
+        let mut __eventually =
+            $crate::time::Eventually::new(stringify!($cond), file!(), line!());
+        $( $crate::eventually!(@set __eventually, $key, $value); )*
+        loop {
+            $( $crate::eventually!(@observe __eventually, $key, $value); )*
+            let ok: bool = $cond;
+            if __eventually.check(ok).await {
+                break;
+            }
+        }
+    }};
+    (@set $e:ident, within, $value:expr) => { $e.within($value) };
+    (@set $e:ident, poll, $value:expr) => { $e.poll($value) };
+    (@set $e:ident, observe, $value:expr) => {};
+    (@observe $e:ident, observe, $value:expr) => { $e.observe(format!("{:?}", $value)) };
+    (@observe $e:ident, $key:ident, $value:expr) => {};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::Runtime,
+        time::{sleep, Instant},
+    };
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    /// Run `f` in a new runtime with a node, and return its panic message.
+    fn panic_message<F: Future<Output = ()> + 'static>(f: impl FnOnce() -> F) -> String {
+        let err = catch_unwind(AssertUnwindSafe(|| {
+            let runtime = Runtime::new();
+            runtime.create_node().name("server").build();
+            runtime.block_on(async move { f().await })
+        }))
+        .unwrap_err();
+        err.downcast::<String>().map(|s| *s).unwrap()
+    }
+
+    #[test]
+    fn bounds() {
+        let runtime = Runtime::new();
+        runtime.block_on(async move {
+            let n = assert_completes_within(Duration::from_secs(2), async {
+                sleep(Duration::from_secs(1)).await;
+                1
+            })
+            .await;
+            assert_eq!(n, 1);
+            assert_takes_at_least(Duration::from_secs(1), sleep(Duration::from_secs(2))).await;
+
+            let counter = Arc::new(AtomicU64::new(0));
+            let counter_ = counter.clone();
+            crate::task::spawn(async move {
+                loop {
+                    sleep(Duration::from_secs(1)).await;
+                    counter_.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            let start = Instant::now();
+            crate::eventually!(counter.load(Ordering::SeqCst) >= 3);
+            assert!(start.elapsed() >= Duration::from_secs(3));
+        });
+
+        let msg = panic_message(|| {
+            assert_completes_within(Duration::from_secs(1), sleep(Duration::from_secs(2)))
+        });
+        assert!(msg.contains("did not complete within 1s"), "{msg}");
+        assert!(msg.contains("bounds.rs"), "{msg}");
+        assert!(msg.contains("server: running"), "{msg}");
+        assert!(msg.contains("main: "), "{msg}");
+
+        let msg = panic_message(|| async {
+            crate::task::spawn_named("ticker", async {
+                loop {
+                    sleep(Duration::from_millis(300)).await;
+                }
+            });
+            assert_completes_within(Duration::from_secs(1), sleep(Duration::from_secs(2))).await
+        });
+        let line = msg.lines().find(|line| line.contains("ticker")).unwrap();
+        assert!(line.contains("task \"ticker\" spawned at "), "{msg}");
+        assert!(line.contains("bounds.rs"), "{msg}");
+        assert!(line.contains("last polled at 86400.9"), "{msg}");
+
+        let msg = panic_message(|| {
+            assert_takes_at_least(Duration::from_secs(2), sleep(Duration::from_secs(1)))
+        });
+        assert!(msg.contains("expected at least 2s"), "{msg}");
+
+        let msg = panic_message(|| async {
+            let start = Instant::now();
+            crate::eventually!(
+                false,
+                within = Duration::from_secs(3),
+                poll = Duration::from_secs(1),
+                observe = start.elapsed().as_secs_f64().round() as u64,
+            );
+        });
+        assert!(msg.contains("condition `false`"), "{msg}");
+        assert!(msg.contains("did not hold within 3s (4 polls)"), "{msg}");
+        assert!(msg.contains("false, observed 2"), "{msg}");
+    }
+}
@@ -38,6 +38,9 @@ pub struct Instant {
 impl Instant {
     /// Returns an instant corresponding to "now".
     ///
+    /// Inside a node, this reads the clock of the node, see
+    /// [`ClockPersonality`](super::ClockPersonality).
+    ///
     /// # Examples
     ///
     /// ```ignore
@@ -47,7 +50,7 @@ impl Instant {
     /// ```
     pub fn now() -> Instant {
         let handle = super::TimeHandle::current();
-        handle.now_instant()
+        handle.read_instant()
     }
 
     /// Create a `msim::time::Instant` from a `std::time::Instant`.
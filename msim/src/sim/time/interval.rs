@@ -35,6 +35,7 @@ use std::task::{Context, Poll};
 use std::{convert::TryInto, future::Future};
 
 /// Creates new [`Interval`] that yields with interval of `period`.
+#[track_caller]
 pub fn interval(period: Duration) -> Interval {
     assert!(period > Duration::new(0, 0), "`period` must be non-zero.");
     internal_interval_at(Instant::now(), period)
@@ -42,11 +43,13 @@ pub fn interval(period: Duration) -> Interval {
 
 /// Creates new [`Interval`] that yields with interval of `period` with the
 /// first tick completing at `start`.
+#[track_caller]
 pub fn interval_at(start: Instant, period: Duration) -> Interval {
     assert!(period > Duration::new(0, 0), "`period` must be non-zero.");
     internal_interval_at(start, period)
 }
 
+#[track_caller]
 fn internal_interval_at(start: Instant, period: Duration) -> Interval {
     let delay = Box::pin(sleep_until(start));
 
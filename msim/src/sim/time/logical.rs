@@ -0,0 +1,165 @@
+use super::{Duration, Instant};
+use crate::trace::EventKind;
+use std::sync::{Arc, Mutex};
+
+/// A logical clock advanced explicitly by the test, such as the round or epoch of a protocol.
+///
+/// Every advance is stamped with the virtual time, and recorded in the [trace](crate::trace) as
+/// a clock event, so that protocol progress can be correlated with virtual time and with the
+/// other events of the simulation:
+///
+/// ```ignore
+/// let epoch = LogicalClock::new("epoch");
+/// // in the harness, when the system reports a new epoch
+/// epoch.advance_to(reported);
+///
+/// // epoch 3 started within a minute of the start, and before the partition
+/// assert!(epoch.time_of(3).unwrap() - start < Duration::from_secs(60));
+/// timeline().assert(msim::seq!(trace::clock("epoch").value(3), custom("partition")));
+/// ```
+///
+/// Clones share the same clock.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone)]
+pub struct LogicalClock {
+    name: Arc<str>,
+    /// Every value the clock took, with the time it was set.
+    history: Arc<Mutex<Vec<(u64, Instant)>>>,
+}
+
+impl LogicalClock {
+    /// Create a clock at 0.
+    pub fn new(name: impl Into<String>) -> Self {
+        LogicalClock {
+            name: name.into().into(),
+            history: Arc::new(Mutex::new(vec![(0, Instant::now())])),
+        }
+    }
+
+    /// The name of the clock.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The current value of the clock.
+    pub fn now(&self) -> u64 {
+        self.history.lock().unwrap().last().unwrap().0
+    }
+
+    /// Advance the clock by one. Returns the new value.
+    pub fn tick(&self) -> u64 {
+        let value = self.now() + 1;
+        self.advance_to(value);
+        value
+    }
+
+    /// Advance the clock to `value`. Setting the current value again does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` is lower than the current value: logical clocks never go backwards.
+    pub fn advance_to(&self, value: u64) {
+        let mut history = self.history.lock().unwrap();
+        let now = history.last().unwrap().0;
+        assert!(
+            value >= now,
+            "logical clock {} went backwards from {now} to {value}",
+            self.name
+        );
+        if value == now {
+            return;
+        }
+        history.push((value, Instant::now()));
+        drop(history);
+        crate::context::try_current(|h| {
+            h.trace.record(EventKind::Clock {
+                name: self.name.to_string(),
+                value,
+            })
+        });
+    }
+
+    /// The virtual time at which the clock reached `value` or a later value, if it did.
+    pub fn time_of(&self, value: u64) -> Option<Instant> {
+        let history = self.history.lock().unwrap();
+        let reached = history.iter().find(|(v, _)| *v >= value);
+        reached.map(|(_, time)| *time)
+    }
+
+    /// The value of the clock at virtual time `time`.
+    pub fn value_at(&self, time: Instant) -> u64 {
+        let history = self.history.lock().unwrap();
+        let before = history.iter().take_while(|(_, t)| *t <= time).last();
+        before.map_or(0, |(value, _)| *value)
+    }
+
+    /// How long the clock took to go from `from` to `to`, if it reached both.
+    pub fn elapsed_between(&self, from: u64, to: u64) -> Option<Duration> {
+        Some(self.time_of(to)? - self.time_of(from)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::Runtime,
+        time::sleep,
+        trace::{self, custom, record},
+    };
+
+    #[test]
+    fn logical_clock() {
+        let mut config = crate::SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        runtime.block_on(async {
+            let start = Instant::now();
+            let round = LogicalClock::new("round");
+            let round_ = round.clone();
+            crate::task::spawn(async move {
+                for _ in 0..5 {
+                    sleep(Duration::from_secs(2)).await;
+                    round_.tick();
+                }
+            });
+            sleep(Duration::from_secs(5)).await;
+            record("partition");
+            assert_eq!(round.now(), 2);
+            sleep(Duration::from_secs(10)).await;
+
+            assert_eq!(round.now(), 5);
+            let secs = |d: Duration| d.as_secs_f64().round() as u64;
+            assert_eq!(secs(round.time_of(3).unwrap() - start), 6);
+            assert_eq!(round.time_of(6), None);
+            assert_eq!(round.value_at(start + Duration::from_secs(7)), 3);
+            assert_eq!(round.elapsed_between(1, 4).map(secs), Some(6));
+            round.advance_to(9);
+            assert_eq!(round.time_of(7), round.time_of(9));
+
+            let timeline = trace::timeline();
+            timeline.assert(crate::seq!(
+                trace::clock("round").value(2),
+                custom("partition"),
+                trace::clock("round").value(3),
+            ));
+            assert_eq!(timeline.count(&trace::clock("round")), 6);
+            assert_eq!(timeline.clock_at("round", Duration::from_secs(5)), 2);
+            assert_eq!(
+                timeline.time_of_clock("round", 9),
+                timeline.events().last().map(|e| e.time)
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "logical clock epoch went backwards from 2 to 1")]
+    fn backwards() {
+        let runtime = Runtime::new();
+        runtime.block_on(async {
+            let epoch = LogicalClock::new("epoch");
+            epoch.advance_to(2);
+            epoch.advance_to(1);
+        });
+    }
+}
@@ -1,34 +1,66 @@
 //! Utilities for tracking time.
 //!
+//! [`Instant`] and [`SystemTime`] mirror the API of their `std::time` counterparts, but read the
+//! virtual clock of the simulation. Library crates can use them to run on virtual time without
+//! relying on the interception of `clock_gettime`, by selecting the implementation with a cargo
+//! feature and using it in place of `std::time`. The types only exist when building with
+//! `--cfg msim`, so the feature alone is not enough:
 //!
+//! ```toml
+//! [features]
+//! msim = ["dep:msim"]
+//! ```
+//!
+//! ```ignore
+//! // src/time.rs
+//! #[cfg(all(msim, feature = "msim"))]
+//! pub use msim::time::{Instant, SystemTime, UNIX_EPOCH};
+//! #[cfg(not(all(msim, feature = "msim")))]
+//! pub use std::time::{Instant, SystemTime, UNIX_EPOCH};
+//! ```
+//!
+//! Both types convert from and into their `std::time` counterparts with [`From`], to cross the
+//! boundary to code that uses `std::time`.
 
+use crate::profile::{Category, Profiler};
 use crate::rand::{GlobalRng, Rng};
+use crate::sim::intercept::{set_errno, unsupported};
 use crate::{context, define_bypass, define_sys_interceptor, task::NodeId};
 #[doc(no_inline)]
 pub use std::time::Duration;
 use std::{
+    collections::{BTreeMap, HashMap},
     future::Future,
     pin::Pin,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
-    time::SystemTime,
 };
 
 use pin_project_lite::pin_project;
 
 use tracing::{trace, warn};
 
+mod bounds;
 pub mod error;
 mod instant;
 mod interval;
+mod logical;
+mod personality;
 mod sleep;
+mod system_time;
 mod timer;
 
 use timer::Timer;
 
+#[doc(hidden)]
+pub use self::bounds::Eventually;
+pub use self::bounds::{assert_completes_within, assert_takes_at_least};
 pub use self::instant::Instant;
 pub use self::interval::{interval, interval_at, Interval, MissedTickBehavior};
+pub use self::logical::LogicalClock;
+pub use self::personality::ClockPersonality;
 pub use self::sleep::{sleep, sleep_until, Sleep};
+pub use self::system_time::{SystemTime, SystemTimeError, UNIX_EPOCH};
 
 pub(crate) struct TimeRuntime {
     handle: TimeHandle,
@@ -36,7 +68,7 @@ pub(crate) struct TimeRuntime {
 
 impl TimeRuntime {
     pub fn new(rand: &GlobalRng) -> Self {
-        let base_time = SystemTime::UNIX_EPOCH
+        let base_time = std::time::SystemTime::UNIX_EPOCH
             + match std::env::var("MSIM_BASE_TIME") {
                 Ok(base_time) => {
                     let base_time: u64 = match base_time.parse() {
@@ -58,6 +90,8 @@ impl TimeRuntime {
         let handle = TimeHandle {
             timer: Arc::new(Mutex::new(Timer::default())),
             clock: ClockHandle::new(base_time),
+            node_clocks: Default::default(),
+            profiler: Profiler::default(),
         };
         TimeRuntime { handle }
     }
@@ -77,8 +111,12 @@ impl TimeRuntime {
             // we should add eps to make sure 'now >= deadline' and avoid deadlock
             time += Duration::from_nanos(50);
 
-            timer.expire(time);
+            let expired = timer.expire(time);
             self.handle.clock.set_elapsed(time);
+            drop(timer);
+            for callback in expired {
+                callback(time);
+            }
             true
         } else {
             false
@@ -97,11 +135,23 @@ impl TimeRuntime {
     }
 }
 
+/// A pending timer, see [`TimeHandle::upcoming_timers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingTimer {
+    /// The node that set the timer, [`NodeId::zero`] for the main task.
+    pub node: NodeId,
+    /// The time at which the timer expires.
+    pub deadline: Instant,
+}
+
 /// Handle to a shared time source.
 #[derive(Clone)]
 pub struct TimeHandle {
     timer: Arc<Mutex<Timer>>,
     clock: ClockHandle,
+    /// The clock personality of nodes, with the seed of its deviations.
+    node_clocks: Arc<Mutex<HashMap<NodeId, (ClockPersonality, u64)>>>,
+    profiler: Profiler,
 }
 
 impl TimeHandle {
@@ -135,7 +185,7 @@ impl TimeHandle {
     }
 
     /// Return the current time.
-    pub fn now_time(&self) -> SystemTime {
+    pub fn now_time(&self) -> std::time::SystemTime {
         self.clock.now_time()
     }
 
@@ -144,21 +194,79 @@ impl TimeHandle {
         self.clock.elapsed()
     }
 
+    /// Let `duration` pass while the executor is blocked, e.g. in a blocking system call that
+    /// waits for a timeout. Timers that expire meanwhile fire late, once the executor is idle.
+    pub(crate) fn advance_blocked(&self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    /// Set the clock personality of a node, with deviations derived from `seed`.
+    pub(crate) fn set_node_clock(&self, node_id: NodeId, personality: ClockPersonality, seed: u64) {
+        let seed = seed ^ personality::mix(node_id.0);
+        let mut clocks = self.node_clocks.lock().unwrap();
+        clocks.insert(node_id, (personality, seed));
+    }
+
+    pub(crate) fn remove_node_clock(&self, node_id: NodeId) {
+        self.node_clocks.lock().unwrap().remove(&node_id);
+    }
+
+    fn current_node_clock(&self) -> Option<(ClockPersonality, u64)> {
+        let node = context::try_current_task()?.node();
+        self.node_clocks.lock().unwrap().get(&node).cloned()
+    }
+
+    /// Returns [`elapsed`](Self::elapsed) as read by the monotonic clock of the current node,
+    /// see [`ClockPersonality`].
+    pub(crate) fn read_elapsed(&self) -> Duration {
+        let elapsed = self.clock.elapsed();
+        match self.current_node_clock() {
+            Some((personality, seed)) => personality.monotonic(seed, elapsed),
+            None => elapsed,
+        }
+    }
+
+    /// Returns the current time as read by the monotonic clock of the current node.
+    pub(crate) fn read_instant(&self) -> Instant {
+        self.clock.base_instant() + self.read_elapsed()
+    }
+
+    /// Returns the current time as read by the wall clock of the current node.
+    pub(crate) fn read_time(&self) -> std::time::SystemTime {
+        let Some((personality, seed)) = self.current_node_clock() else {
+            return self.clock.now_time();
+        };
+        let elapsed = self.clock.elapsed();
+        let time = self.clock.base_time() + personality.monotonic(seed, elapsed);
+        let step = personality.wall_step(seed, elapsed);
+        let step_abs = Duration::from_nanos(step.unsigned_abs());
+        if step < 0 {
+            time - step_abs
+        } else {
+            time + step_abs
+        }
+    }
+
     /// Waits until `duration` has elapsed.
+    #[track_caller]
     pub fn sleep(&self, duration: Duration) -> Sleep {
         self.sleep_until(self.clock.now_instant() + duration)
     }
 
     /// Waits until `deadline` is reached.
+    #[track_caller]
     pub fn sleep_until(&self, deadline: Instant) -> Sleep {
         Sleep {
             handle: self.clone(),
             deadline,
+            start: self.clock.now_instant(),
+            category: Category::Timer(std::panic::Location::caller()),
         }
     }
 
     /// Require a `Future` to complete before the specified duration has elapsed.
     // TODO: make it Send
+    #[track_caller]
     pub fn timeout<T: Future>(&self, duration: Duration, future: T) -> Timeout<T> {
         let delay = self.sleep(duration);
         Timeout {
@@ -181,9 +289,11 @@ impl TimeHandle {
         deadline: Instant,
         callback: impl FnOnce() + Send + Sync + 'static,
     ) {
-        let mut timer = self.timer.lock().unwrap();
-        timer.add(node_id, deadline - self.clock.base_instant(), |_| {
-            callback()
+        crate::perf::measure(crate::perf::Section::Timer, || {
+            let mut timer = self.timer.lock().unwrap();
+            timer.add(node_id, deadline - self.clock.base_instant(), |_| {
+                callback()
+            });
         });
     }
 
@@ -192,6 +302,59 @@ impl TimeHandle {
         self.add_timer(deadline, || waker.wake());
     }
 
+    /// Get the number of pending timers of each node, and the time until the earliest of them.
+    pub(crate) fn pending_timers(&self) -> BTreeMap<NodeId, (usize, Duration)> {
+        let now = self.clock.elapsed();
+        let timer = self.timer.lock().unwrap();
+        let mut pending = timer.pending();
+        for (_, next) in pending.values_mut() {
+            *next = next.saturating_sub(now);
+        }
+        pending
+    }
+
+    /// List the pending timers of all nodes, earliest first.
+    ///
+    /// Timers of sleeps that were dropped before expiring stay pending until their deadline.
+    pub fn upcoming_timers(&self) -> Vec<PendingTimer> {
+        let base = self.clock.base_instant();
+        let timer = self.timer.lock().unwrap();
+        timer
+            .upcoming()
+            .into_iter()
+            .map(|(node, deadline)| PendingTimer {
+                node,
+                deadline: base + deadline,
+            })
+            .collect()
+    }
+
+    /// Returns the earliest pending timer, if any.
+    pub fn next_timer(&self) -> Option<PendingTimer> {
+        self.upcoming_timers().into_iter().next()
+    }
+
+    /// Waits until the earliest pending timer expires, and returns its deadline.
+    ///
+    /// Tasks woken by the timer are runnable when this returns, but may not have been polled
+    /// yet. Returns `None` immediately if no timer is pending.
+    ///
+    /// ```ignore
+    /// cache.insert("key", value, Duration::from_secs(30));
+    /// TimeHandle::current().advance_to_next_timer().await;
+    /// assert!(cache.get("key").is_none());
+    /// ```
+    pub async fn advance_to_next_timer(&self) -> Option<Instant> {
+        let next = self.next_timer()?;
+        self.sleep_until(next.deadline).await;
+        Some(next.deadline)
+    }
+
+    /// Returns the virtual time profiler.
+    pub fn profiler(&self) -> &Profiler {
+        &self.profiler
+    }
+
     // Get the elapsed time since the beginning of the test run - this should not be exposed to
     // test code.
     pub(crate) fn time_since_clock_base(&self) -> Duration {
@@ -248,17 +411,23 @@ where
 
 /// Supply tokio::time::advance() API (for compilation only - this method
 /// is meaningless inside the simulator).
+///
+/// Under [`UnsupportedPolicy::Lenient`](crate::UnsupportedPolicy::Lenient), the clock is left
+/// as is.
 pub async fn advance(_duration: Duration) {
-    unimplemented!("cannot advance clock in simulation - use sleep() instead");
+    let hint = "virtual time passes by itself, use sleep() instead";
+    unsupported("advance", "cannot advance the clock in simulation", hint, 0);
 }
 
 /// Require a `Future` to complete before the specified duration has elapsed.
+#[track_caller]
 pub fn timeout<T: Future>(duration: Duration, future: T) -> Timeout<T> {
     let handle = TimeHandle::current();
     handle.timeout(duration, future)
 }
 
 /// Require a `Future` to complete before the specified deadline.
+#[track_caller]
 pub fn timeout_at<T: Future>(deadline: Instant, future: T) -> Timeout<T> {
     let duration = deadline.saturating_duration_since(Instant::now());
     timeout(duration, future)
@@ -281,7 +450,7 @@ struct Clock {
 impl ClockHandle {
     const CLOCK_BASE: Duration = Duration::from_secs(86400);
 
-    fn new(base_time: SystemTime) -> Self {
+    fn new(base_time: std::time::SystemTime) -> Self {
         let base_instant: Instant = unsafe { std::mem::zeroed() };
         let clock = Clock {
             base_time,
@@ -321,12 +490,17 @@ impl ClockHandle {
         inner.base_instant
     }
 
+    fn base_time(&self) -> std::time::SystemTime {
+        let inner = self.inner.lock().unwrap();
+        inner.base_time
+    }
+
     fn now_instant(&self) -> Instant {
         let inner = self.inner.lock().unwrap();
         inner.base_instant + inner.elapsed_time
     }
 
-    fn now_time(&self) -> SystemTime {
+    fn now_time(&self) -> std::time::SystemTime {
         let inner = self.inner.lock().unwrap();
         inner.base_time + inner.elapsed_time
     }
@@ -383,7 +557,7 @@ define_sys_interceptor!(
         }
         let time = TimeHandle::current();
         let dur = time
-            .now_time()
+            .read_time()
             .duration_since(std::time::SystemTime::UNIX_EPOCH)
             .unwrap();
         tp.write(libc::timeval {
@@ -445,7 +619,7 @@ define_sys_interceptor!(
             }
         };
 
-        let elapsed = time.elapsed();
+        let elapsed = time.read_elapsed();
         let nanos = elapsed.as_nanos().try_into().unwrap();
 
         // convert nanos back to mach_absolute_time units
@@ -480,7 +654,7 @@ define_sys_interceptor!(
                 }
 
                 let dur = time
-                    .now_time()
+                    .read_time()
                     .duration_since(std::time::SystemTime::UNIX_EPOCH)
                     .unwrap();
                 ts.write(libc::timespec {
@@ -491,7 +665,7 @@ define_sys_interceptor!(
 
             // used by Instant
             libc::CLOCK_MONOTONIC | libc::CLOCK_UPTIME_RAW | libc::CLOCK_MONOTONIC_RAW => {
-                let dur = time.elapsed();
+                let dur = time.read_elapsed();
                 ts.write(libc::timespec {
                     tv_sec: dur.as_secs() as _,
                     tv_nsec: dur.subsec_nanos() as _,
@@ -503,7 +677,12 @@ define_sys_interceptor!(
                 return bypass_clock_gettime(clock_id, ts);
             }
 
-            _ => panic!("unsupported clockid: {}", clock_id),
+            _ => {
+                let detail = format!("clock id {clock_id}");
+                let hint = "only the realtime, monotonic and cpu-time clocks are supported";
+                set_errno(unsupported("clock_gettime", detail, hint, libc::EINVAL));
+                return -1;
+            }
         }
         0
     }
@@ -531,7 +710,7 @@ define_sys_interceptor!(
                 }
 
                 let dur = time
-                    .now_time()
+                    .read_time()
                     .duration_since(std::time::SystemTime::UNIX_EPOCH)
                     .unwrap();
                 ts.write(libc::timespec {
@@ -544,7 +723,7 @@ define_sys_interceptor!(
             libc::CLOCK_MONOTONIC | libc::CLOCK_MONOTONIC_RAW | libc::CLOCK_MONOTONIC_COARSE => {
                 // Instant is the same layout as timespec on linux
                 #[allow(clippy::missing_transmute_annotations)]
-                ts.write(std::mem::transmute(time.now_instant()));
+                ts.write(std::mem::transmute(time.read_instant()));
             }
 
             // Used by rocksdb performance timers.
@@ -552,7 +731,12 @@ define_sys_interceptor!(
                 return bypass_clock_gettime(clock_id, ts);
             }
 
-            _ => panic!("unsupported clockid: {}", clock_id),
+            _ => {
+                let detail = format!("clock id {clock_id}");
+                let hint = "only the realtime, monotonic and cpu-time clocks are supported";
+                set_errno(unsupported("clock_gettime", detail, hint, libc::EINVAL));
+                return -1;
+            }
         }
         0
     }
@@ -630,4 +814,110 @@ mod tests {
             println!("{:?} {:?}", t0, s0);
         });
     }
+
+    #[test]
+    fn system_time() {
+        let runtime = Runtime::new();
+        runtime.block_on(async {
+            let t0 = SystemTime::now();
+            assert!(t0.duration_since(UNIX_EPOCH).unwrap() > Duration::from_secs(1 << 30));
+            sleep(Duration::from_secs(5)).await;
+            assert_eq!(t0.elapsed().unwrap().as_secs_f64().round(), 5.0);
+
+            let std: std::time::SystemTime = t0.into();
+            assert_eq!(SystemTime::from(std), t0);
+            assert!(t0.duration_since(t0 + Duration::from_secs(1)).is_err());
+            assert_eq!(
+                t0.checked_sub(Duration::from_secs(1)),
+                Some(t0 - Duration::from_secs(1))
+            );
+        });
+    }
+
+    #[test]
+    fn clock_personality() {
+        let runtime = Runtime::new();
+        let node = |personality| runtime.create_node().clock(personality).build();
+        let coarse = node(ClockPersonality::coarse());
+        let vm = node(ClockPersonality::jumpy_vm());
+
+        // sample the clocks of a node every millisecond for a minute, and return the true time
+        // and the readings.
+        let sample = |node: &crate::runtime::NodeHandle| {
+            node.spawn(async {
+                let time = TimeHandle::current();
+                let mut samples = vec![];
+                for _ in 0..60_000 {
+                    let readings = (Instant::now(), SystemTime::now().into_std());
+                    samples.push((time.now_instant(), time.now_time(), readings));
+                    sleep(Duration::from_millis(1)).await;
+                }
+                samples
+            })
+        };
+        let (coarse, vm) = (sample(&coarse), sample(&vm));
+
+        runtime.block_on(async move {
+            let coarse = coarse.await.unwrap();
+            for w in coarse.windows(2) {
+                let (now, _, (instant, _)) = w[1];
+                assert!(instant <= now && instant >= w[0].2 .0);
+                assert!(now - instant < Duration::from_millis(10));
+            }
+            let distinct: std::collections::HashSet<_> =
+                coarse.iter().map(|(_, _, (instant, _))| *instant).collect();
+            assert!(distinct.len() <= 6001, "{}", distinct.len());
+
+            let vm = vm.await.unwrap();
+            let mut longest_freeze = Duration::ZERO;
+            let mut wall_stepped = false;
+            for (now, time, (instant, system_time)) in &vm {
+                assert!(instant <= now);
+                longest_freeze = longest_freeze.max(*now - *instant);
+                wall_stepped |= system_time < time;
+            }
+            assert!(longest_freeze > Duration::from_millis(200));
+            assert!(longest_freeze <= Duration::from_millis(250));
+            assert!(wall_stepped);
+        });
+    }
+
+    #[test]
+    fn advance_to_next_timer() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        let expired = Arc::new(AtomicUsize::new(0));
+        let expired2 = expired.clone();
+        node.spawn(async move {
+            for ttl in [30, 60] {
+                sleep(Duration::from_secs(ttl)).await;
+                expired2.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let t0 = runtime.block_on(async {
+            sleep(Duration::from_millis(1)).await;
+            let time = TimeHandle::current();
+            let timers = time.upcoming_timers();
+            assert_eq!(timers.len(), 1, "{timers:?}");
+            assert_eq!(timers[0].node, node.id());
+            assert_eq!(time.next_timer(), Some(timers[0]));
+
+            let deadline = time.advance_to_next_timer().await.unwrap();
+            assert_eq!(deadline, timers[0].deadline);
+            assert!(Instant::now() >= deadline);
+            sleep(Duration::from_millis(1)).await;
+            assert_eq!(expired.load(Ordering::SeqCst), 1);
+            Instant::now()
+        });
+
+        let deadline = runtime.advance_to_next_timer().unwrap();
+        assert_eq!((deadline - t0).as_secs(), 59);
+        runtime.block_on(async { sleep(Duration::from_millis(1)).await });
+        assert_eq!(expired.load(Ordering::SeqCst), 2);
+        assert!(runtime.upcoming_timers().is_empty());
+        assert_eq!(runtime.advance_to_next_timer(), None);
+    }
 }
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+/// How the clocks of a node deviate from the true time of the simulation.
+///
+/// Real deployments read time from very different clock sources: bare-metal hosts have precise
+/// and stable clocks, virtual machines freeze when the hypervisor deschedules them and get their
+/// wall clock stepped when it is corrected, and some platforms only offer a coarse clock. A
+/// personality only changes what a node reads from [`Instant`](super::Instant),
+/// [`SystemTime`](super::SystemTime) and the intercepted system clocks; timers still fire at the
+/// true time.
+///
+/// Readings of the monotonic clock never go backwards, and never run ahead of the true time.
+/// The deviations are a deterministic function of the seed, the node and the time, so they do
+/// not depend on how often the clock is read.
+#[cfg_attr(docsrs, doc(cfg(msim)))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockPersonality {
+    /// Readings are rounded down to a multiple of the resolution. Zero for exact readings.
+    pub resolution: Duration,
+
+    /// Probability that the clock freezes once during each `period`.
+    pub freeze_rate: f64,
+
+    /// How long a freeze lasts, at most `period`. During a freeze, readings return the time the
+    /// freeze started, then jump forward to the true time when it ends.
+    pub freeze: Duration,
+
+    /// Largest step of the wall clock in either direction, as when a drifting clock is
+    /// corrected. A new step is chosen every `period`. Only [`SystemTime`](super::SystemTime)
+    /// is affected, so the wall clock can go backwards.
+    pub wall_step: Duration,
+
+    /// Length of the periods in which freezes and wall clock steps are chosen.
+    pub period: Duration,
+}
+
+impl Default for ClockPersonality {
+    fn default() -> Self {
+        Self::stable()
+    }
+}
+
+impl ClockPersonality {
+    /// A bare-metal host with an exact and stable clock.
+    pub fn stable() -> Self {
+        ClockPersonality {
+            resolution: Duration::ZERO,
+            freeze_rate: 0.0,
+            freeze: Duration::ZERO,
+            wall_step: Duration::ZERO,
+            period: Duration::from_secs(1),
+        }
+    }
+
+    /// A virtual machine whose clock freezes for 250ms about once every 10 seconds, and whose
+    /// wall clock is stepped by up to 50ms every second.
+    pub fn jumpy_vm() -> Self {
+        ClockPersonality {
+            freeze_rate: 0.1,
+            freeze: Duration::from_millis(250),
+            wall_step: Duration::from_millis(50),
+            ..Self::stable()
+        }
+    }
+
+    /// A clock with a coarse resolution of 10ms, like `CLOCK_MONOTONIC_COARSE`.
+    pub fn coarse() -> Self {
+        ClockPersonality {
+            resolution: Duration::from_millis(10),
+            ..Self::stable()
+        }
+    }
+
+    /// The reading of the monotonic clock at the true time `t`.
+    pub(crate) fn monotonic(&self, seed: u64, t: Duration) -> Duration {
+        let mut t = t;
+        if let Some(index) = self.period_of(t) {
+            let hash = mix(seed ^ index);
+            if unit(hash) < self.freeze_rate {
+                let start = nanos(self.period.as_nanos() * index as u128)
+                    + self.period.mul_f64(unit(mix(hash)));
+                if (start..start + self.freeze.min(self.period)).contains(&t) {
+                    t = start;
+                }
+            }
+        }
+        if self.resolution.is_zero() {
+            return t;
+        }
+        let resolution = self.resolution.as_nanos();
+        nanos(t.as_nanos() / resolution * resolution)
+    }
+
+    /// The step of the wall clock at the true time `t`, in nanoseconds.
+    pub(crate) fn wall_step(&self, seed: u64, t: Duration) -> i64 {
+        let step = self.wall_step.as_nanos() as u64;
+        match self.period_of(t) {
+            Some(index) if step > 0 => {
+                let hash = mix(!seed ^ index);
+                (hash % (2 * step + 1)) as i64 - step as i64
+            }
+            _ => 0,
+        }
+    }
+
+    /// The index of the period containing `t`.
+    fn period_of(&self, t: Duration) -> Option<u64> {
+        if self.period.is_zero() {
+            return None;
+        }
+        Some((t.as_nanos() / self.period.as_nanos()) as u64)
+    }
+}
+
+fn nanos(nanos: u128) -> Duration {
+    Duration::from_nanos(nanos as u64)
+}
+
+/// SplitMix64 finalizer.
+pub(crate) fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Map a hash to `[0, 1)`.
+fn unit(hash: u64) -> f64 {
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
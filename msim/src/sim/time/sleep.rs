@@ -2,12 +2,14 @@ use super::*;
 use std::{fmt, future::Future, pin::Pin, task::Poll};
 
 /// Waits until `duration` has elapsed.
+#[track_caller]
 pub fn sleep(duration: Duration) -> Sleep {
     let handle = TimeHandle::current();
     handle.sleep(duration)
 }
 
 /// Waits until `deadline` is reached.
+#[track_caller]
 pub fn sleep_until(deadline: Instant) -> Sleep {
     let handle = TimeHandle::current();
     handle.sleep_until(deadline)
@@ -20,6 +22,9 @@ pub fn sleep_until(deadline: Instant) -> Sleep {
 pub struct Sleep {
     pub(super) handle: TimeHandle,
     pub(super) deadline: Instant,
+    /// When the current wait started, for profiling.
+    pub(super) start: Instant,
+    pub(super) category: Category,
 }
 
 impl Sleep {
@@ -38,14 +43,26 @@ impl Sleep {
     /// Resets the `Sleep` instance to a new deadline.
     pub fn reset(mut self: Pin<&mut Self>, deadline: Instant) {
         self.deadline = deadline;
+        self.start = self.handle.clock.now_instant();
+    }
+
+    /// Attribute the time spent in this sleep to `category` when profiling.
+    pub(crate) fn with_category(mut self, category: Category) -> Self {
+        self.category = category;
+        self
     }
 }
 
 impl Future for Sleep {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         if self.is_elapsed() {
+            if self.deadline > self.start {
+                let waited = self.deadline - self.start;
+                self.handle.profiler().record(self.category, waited);
+                self.start = self.deadline;
+            }
             return Poll::Ready(());
         }
         let waker = cx.waker().clone();
@@ -0,0 +1,117 @@
+use std::{fmt, ops, time::Duration};
+
+#[doc(no_inline)]
+pub use std::time::SystemTimeError;
+
+/// A measurement of the system clock of the simulation.
+///
+/// Mirrors `std::time::SystemTime`, but [`SystemTime::now`] reads the virtual clock.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    std: std::time::SystemTime,
+}
+
+/// An anchor in time, "1970-01-01 00:00:00 UTC".
+pub const UNIX_EPOCH: SystemTime = SystemTime::UNIX_EPOCH;
+
+impl SystemTime {
+    /// An anchor in time, "1970-01-01 00:00:00 UTC".
+    pub const UNIX_EPOCH: SystemTime = SystemTime {
+        std: std::time::SystemTime::UNIX_EPOCH,
+    };
+
+    /// Returns the system time corresponding to "now" in the simulation.
+    ///
+    /// Inside a node, this reads the wall clock of the node, see
+    /// [`ClockPersonality`](super::ClockPersonality).
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a runtime.
+    pub fn now() -> SystemTime {
+        let handle = super::TimeHandle::current();
+        SystemTime {
+            std: handle.read_time(),
+        }
+    }
+
+    /// Create a `msim::time::SystemTime` from a `std::time::SystemTime`.
+    pub fn from_std(std: std::time::SystemTime) -> SystemTime {
+        SystemTime { std }
+    }
+
+    /// Convert the value into a `std::time::SystemTime`.
+    pub fn into_std(self) -> std::time::SystemTime {
+        self.std
+    }
+
+    /// Returns the amount of time elapsed from an earlier point in time.
+    ///
+    /// Returns an error if `earlier` is later than `self`, with the amount of time it is later.
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        self.std.duration_since(earlier.std)
+    }
+
+    /// Returns the amount of time elapsed since this system time was created, in the simulation.
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self + duration` if `t` can be represented,
+    /// `None` otherwise.
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.std.checked_add(duration).map(SystemTime::from_std)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self - duration` if `t` can be represented,
+    /// `None` otherwise.
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.std.checked_sub(duration).map(SystemTime::from_std)
+    }
+}
+
+impl From<std::time::SystemTime> for SystemTime {
+    fn from(time: std::time::SystemTime) -> SystemTime {
+        SystemTime::from_std(time)
+    }
+}
+
+impl From<SystemTime> for std::time::SystemTime {
+    fn from(value: SystemTime) -> std::time::SystemTime {
+        value.into_std()
+    }
+}
+
+impl ops::Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, other: Duration) -> SystemTime {
+        SystemTime::from_std(self.std + other)
+    }
+}
+
+impl ops::AddAssign<Duration> for SystemTime {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl ops::Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, rhs: Duration) -> SystemTime {
+        SystemTime::from_std(self.std - rhs)
+    }
+}
+
+impl ops::SubAssign<Duration> for SystemTime {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl fmt::Debug for SystemTime {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.std.fmt(fmt)
+    }
+}
@@ -9,7 +9,7 @@
 
 use std::cell::Cell;
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashSet};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
 use std::time::Duration;
 
 use crate::task::NodeId;
@@ -51,8 +51,11 @@ impl Timer {
 
     /// Expire timers.
     ///
-    /// Given the current time `now`, trigger and remove all expired timers.
-    pub fn expire(&mut self, now: Duration) {
+    /// Given the current time `now`, remove all expired timers and return their callbacks in
+    /// deadline order. The callbacks should be called while no locks are held, since they may
+    /// add new timers.
+    pub fn expire(&mut self, now: Duration) -> Vec<Callback> {
+        let mut ret = Vec::new();
         while let Some(t) = self.events.peek() {
             if t.deadline > now {
                 break;
@@ -61,9 +64,10 @@ impl Timer {
 
             // event may have been cancelled
             if let Some(callback) = event.callback.take() {
-                (callback)(now);
+                ret.push(callback);
             }
         }
+        ret
     }
 
     /// Remove all events for node and return them in a vector. The vector should be
@@ -94,6 +98,37 @@ impl Timer {
     pub fn next(&self) -> Option<Duration> {
         self.events.peek().map(|e| e.deadline)
     }
+
+    /// Get the number of pending timers of each node, and the earliest deadline among them.
+    pub fn pending(&self) -> BTreeMap<NodeId, (usize, Duration)> {
+        let mut pending = BTreeMap::new();
+        for event in self.events.iter() {
+            // skip cancelled events
+            let callback = event.callback.take();
+            let live = callback.is_some();
+            event.callback.set(callback);
+            if live {
+                let (count, next) = pending.entry(event.node_id).or_insert((0, event.deadline));
+                *count += 1;
+                *next = (*next).min(event.deadline);
+            }
+        }
+        pending
+    }
+
+    /// Get the deadlines of all pending timers and the nodes owning them, in deadline order.
+    pub fn upcoming(&self) -> Vec<(NodeId, Duration)> {
+        let mut upcoming = Vec::new();
+        for event in self.events.iter() {
+            let callback = event.callback.take();
+            if callback.is_some() {
+                upcoming.push((event.node_id, event.deadline));
+            }
+            event.callback.set(callback);
+        }
+        upcoming.sort_by_key(|&(node_id, deadline)| (deadline, node_id));
+        upcoming
+    }
 }
 
 struct Event {
@@ -0,0 +1,1160 @@
+//! Recording and asserting on the timeline of a simulation.
+//!
+//! When tracing is enabled, the runtime records an [`Event`] for every message sent and
+//! delivered, for every node lifecycle change, and for every scenario choice made with
+//! [`choose`](crate::rand::choose) or [`maybe`](crate::rand::maybe). Tests can also record their own events with
+//! [`record`]. The recorded [`Timeline`] can then be checked for ordering properties:
+//!
+//! ```ignore
+//! use msim::trace::*;
+//!
+//! timeline().assert(msim::seq!(
+//!     msg_sent().tag(VOTE).from(n1),
+//!     node_restart(n2),
+//!     msg_delivered().tag(VOTE).to(n3),
+//! ));
+//! ```
+//!
+//! [`Timeline::diff`] locates where the timelines of two runs diverge, e.g. of the same seed
+//! before and after a refactor, and [`Timeline::write_to`] saves a timeline to compare it with
+//! the timeline of another build using [`diff_files`].
+//!
+//! [`Timeline::attempts`] extracts the times of repeated events, such as the retries of a request
+//! to a peer, to assert on backoff policies:
+//!
+//! ```ignore
+//! let retries = timeline().attempts(&msg_sent().tag(JOIN).from(client));
+//! retries.assert_backoff(2.0);
+//! retries.assert_capped(Duration::from_secs(30));
+//! ```
+//!
+//! Tracing is disabled by default, enable it with [`TraceConfig`] or [`Trace::enable`].
+
+use super::utils::off_thread;
+use crate::{
+    fault::{DomainFaultKind, FailureDomain},
+    net::Tag,
+    runtime::{ExitStatus, ScheduleId},
+    task::NodeId,
+    time::TimeHandle,
+};
+use futures::channel::mpsc;
+use std::{
+    fmt, fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Number of events shown around the divergence of two traces.
+const DIFF_CONTEXT: usize = 5;
+
+/// Tolerance when comparing retry intervals, for the jitter of the simulated scheduler.
+const INTERVAL_TOLERANCE: Duration = Duration::from_micros(100);
+
+/// Tracing configuration.
+#[derive(Debug, Clone, Default)]
+pub struct TraceConfig {
+    /// Record events from the start of the simulation.
+    pub enabled: bool,
+}
+
+/// Kind of a recorded event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// A message was accepted by the network.
+    MsgSent {
+        /// The sending node.
+        from: NodeId,
+        /// The receiving node.
+        to: NodeId,
+        /// The message tag.
+        tag: u64,
+    },
+    /// A message was delivered to the mailbox of its destination.
+    MsgDelivered {
+        /// The sending node.
+        from: NodeId,
+        /// The receiving node.
+        to: NodeId,
+        /// The message tag.
+        tag: u64,
+    },
+    /// A node was created.
+    NodeCreate(NodeId),
+    /// A node was killed.
+    NodeKill(NodeId),
+    /// A node was restarted.
+    NodeRestart(NodeId),
+    /// A node was paused.
+    NodePause(NodeId),
+    /// A node was resumed.
+    NodeResume(NodeId),
+    /// A node was deleted.
+    NodeDelete(NodeId),
+    /// A node exited, see [`ExitStatus`].
+    NodeExit {
+        /// The node that exited.
+        node: NodeId,
+        /// How it exited.
+        status: ExitStatus,
+    },
+    /// The supervisor of a node gave up restarting it.
+    NodeGiveUp(NodeId),
+    /// A node became ready, see [`NodeBuilder::ready_when`](crate::runtime::NodeBuilder::ready_when).
+    NodeReady(NodeId),
+    /// A latency spike started on a node, see [`LatencySpike`](crate::fault::LatencySpike).
+    LatencySpike {
+        /// The affected node.
+        node: NodeId,
+        /// How long the spike lasts.
+        duration: Duration,
+    },
+    /// The simulation host stalled, see [`HostStall`](crate::fault::HostStall).
+    HostStall {
+        /// How long the host stalled, in real time.
+        duration: Duration,
+    },
+    /// A fault was injected into a whole [`FailureDomain`](crate::fault::FailureDomain).
+    DomainFault {
+        /// The failure domain, e.g. `zone=us-east-1a`.
+        domain: String,
+        /// The fault.
+        fault: DomainFaultKind,
+        /// The nodes of the domain.
+        nodes: Vec<NodeId>,
+    },
+    /// An event scheduled with [`Handle::schedule_at`](crate::runtime::Handle::schedule_at) or
+    /// [`Handle::schedule_every`](crate::runtime::Handle::schedule_every) ran.
+    Scheduled {
+        /// The id of the scheduled event.
+        id: ScheduleId,
+        /// The name of the scheduled event.
+        name: String,
+    },
+    /// The test made a choice with [`choose`](crate::rand::choose) or
+    /// [`maybe`](crate::rand::maybe).
+    Choice {
+        /// The node that made the choice, or node 0 for the supervisor.
+        node: NodeId,
+        /// The index of the chosen option.
+        index: usize,
+        /// The number of options.
+        options: usize,
+    },
+    /// A [`LogicalClock`](crate::time::LogicalClock) advanced.
+    Clock {
+        /// The name of the clock.
+        name: String,
+        /// The new value of the clock.
+        value: u64,
+    },
+    /// An invariant was reported violated with
+    /// [`invariant_failed`](crate::orchestrator::invariant_failed).
+    InvariantFailed {
+        /// The node that checked the invariant, or node 0 outside of nodes.
+        node: NodeId,
+        /// The name of the invariant.
+        name: String,
+        /// What was observed.
+        message: String,
+    },
+    /// An event recorded by the test with [`record`].
+    Custom {
+        /// The node that recorded the event.
+        node: NodeId,
+        /// The name of the event.
+        name: String,
+    },
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MsgSent { from, to, tag } => {
+                write!(f, "msg-sent {from} -> {to} tag={}", Tag(*tag))
+            }
+            Self::MsgDelivered { from, to, tag } => {
+                write!(f, "msg-delivered {from} -> {to} tag={}", Tag(*tag))
+            }
+            Self::NodeCreate(node) => write!(f, "node-create {node}"),
+            Self::NodeKill(node) => write!(f, "node-kill {node}"),
+            Self::NodeRestart(node) => write!(f, "node-restart {node}"),
+            Self::NodePause(node) => write!(f, "node-pause {node}"),
+            Self::NodeResume(node) => write!(f, "node-resume {node}"),
+            Self::NodeDelete(node) => write!(f, "node-delete {node}"),
+            Self::NodeExit { node, status } => write!(f, "node-exit {node} {status}"),
+            Self::NodeGiveUp(node) => write!(f, "node-give-up {node}"),
+            Self::NodeReady(node) => write!(f, "node-ready {node}"),
+            Self::LatencySpike { node, duration } => {
+                write!(f, "latency-spike {node} {duration:?}")
+            }
+            Self::HostStall { duration } => write!(f, "host-stall {duration:?}"),
+            Self::DomainFault {
+                domain,
+                fault,
+                nodes,
+            } => {
+                write!(f, "domain-{fault} {domain} [")?;
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{node}")?;
+                }
+                write!(f, "]")
+            }
+            Self::Scheduled { id, name } => write!(f, "scheduled {id} {name}"),
+            Self::Choice {
+                node,
+                index,
+                options,
+            } => write!(f, "choice {node} {index}/{options}"),
+            Self::Clock { name, value } => write!(f, "clock {name}={value}"),
+            Self::InvariantFailed {
+                node,
+                name,
+                message,
+            } => write!(f, "invariant-failed {node} {name}: {message}"),
+            Self::Custom { node, name } => write!(f, "custom {node} {name}"),
+        }
+    }
+}
+
+/// A recorded event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Simulated time since the start of the run.
+    pub time: Duration,
+    /// What happened.
+    pub kind: EventKind,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:>12.6}s] {}", self.time.as_secs_f64(), self.kind)
+    }
+}
+
+/// The event recorder of a runtime.
+#[derive(Clone)]
+pub struct Trace {
+    time: TimeHandle,
+    inner: Arc<Mutex<TraceInner>>,
+}
+
+struct TraceInner {
+    enabled: bool,
+    events: Vec<Event>,
+    /// Receives every event, recorded or not, see [`Orchestrator`](crate::orchestrator::Orchestrator).
+    subscriber: Option<mpsc::UnboundedSender<Event>>,
+}
+
+impl Trace {
+    pub(crate) fn new(time: TimeHandle, config: &TraceConfig) -> Self {
+        Trace {
+            time,
+            inner: Arc::new(Mutex::new(TraceInner {
+                enabled: config.enabled,
+                events: Vec::new(),
+                subscriber: None,
+            })),
+        }
+    }
+
+    /// Start recording events.
+    pub fn enable(&self) {
+        self.inner.lock().unwrap().enabled = true;
+    }
+
+    /// Stop recording events. Events recorded so far are kept.
+    pub fn disable(&self) {
+        self.inner.lock().unwrap().enabled = false;
+    }
+
+    /// Returns true if events are being recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().enabled
+    }
+
+    /// Discard all recorded events.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().events.clear();
+    }
+
+    /// Take a snapshot of the recorded events.
+    pub fn timeline(&self) -> Timeline {
+        Timeline {
+            events: self.inner.lock().unwrap().events.clone(),
+        }
+    }
+
+    pub(crate) fn record(&self, kind: EventKind) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.enabled && inner.subscriber.is_none() {
+            return;
+        }
+        let event = Event {
+            time: self.time.time_since_clock_base(),
+            kind,
+        };
+        if let Some(subscriber) = &inner.subscriber {
+            if subscriber.unbounded_send(event.clone()).is_err() {
+                inner.subscriber = None;
+            }
+        }
+        if inner.enabled {
+            inner.events.push(event);
+        }
+    }
+
+    /// Receive every event from now on, whether tracing is enabled or not.
+    ///
+    /// Returns `None` if there is a subscriber already.
+    pub(crate) fn subscribe(&self) -> Option<mpsc::UnboundedReceiver<Event>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .subscriber
+            .as_ref()
+            .is_some_and(|subscriber| !subscriber.is_closed())
+        {
+            return None;
+        }
+        let (tx, rx) = mpsc::unbounded();
+        inner.subscriber = Some(tx);
+        Some(rx)
+    }
+}
+
+/// Record a custom event from the current node.
+pub fn record(name: impl Into<String>) {
+    let node = crate::context::current_node();
+    crate::context::current(|h| {
+        h.trace.record(EventKind::Custom {
+            node,
+            name: name.into(),
+        })
+    });
+}
+
+/// Take a snapshot of the events recorded by the current runtime.
+pub fn timeline() -> Timeline {
+    crate::context::current(|h| h.trace.timeline())
+}
+
+/// A snapshot of recorded events, in the order they happened.
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    events: Vec<Event>,
+}
+
+impl Timeline {
+    /// All recorded events.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Find the first event matching the pattern.
+    pub fn find(&self, pattern: &Pattern) -> Option<&Event> {
+        self.events.iter().find(|e| pattern.matches(e))
+    }
+
+    /// Count the events matching the pattern.
+    pub fn count(&self, pattern: &Pattern) -> usize {
+        self.events.iter().filter(|e| pattern.matches(e)).count()
+    }
+
+    /// Check that the sequence of patterns matches events in order.
+    ///
+    /// Other events may occur between the matched events. Returns the matched events, or an
+    /// error describing the first pattern that could not be matched.
+    pub fn check(&self, seq: &Seq) -> Result<Vec<&Event>, String> {
+        let mut matched = Vec::with_capacity(seq.0.len());
+        let mut events = self.events.iter();
+        for (i, pattern) in seq.0.iter().enumerate() {
+            match events.by_ref().find(|e| pattern.matches(e)) {
+                Some(event) => matched.push(event),
+                None => {
+                    let mut msg = format!("step {i} of sequence not found: {pattern}\n");
+                    if let Some(last) = matched.last() {
+                        msg.push_str(&format!("previous step matched: {last}\n"));
+                    }
+                    msg.push_str(&format!("timeline:\n{self}"));
+                    return Err(msg);
+                }
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Assert that the sequence of patterns matches events in order, see [`Timeline::check`].
+    #[track_caller]
+    pub fn assert(&self, seq: impl Into<Seq>) {
+        if let Err(msg) = self.check(&seq.into()) {
+            panic!("timeline assertion failed: {msg}");
+        }
+    }
+
+    /// Assert that no event matches the pattern.
+    #[track_caller]
+    pub fn assert_never(&self, pattern: &Pattern) {
+        if let Some(event) = self.find(pattern) {
+            panic!("timeline assertion failed: unexpected event {event}\ntimeline:\n{self}");
+        }
+    }
+
+    /// The value of the [`LogicalClock`](crate::time::LogicalClock) named `name` at `time`, 0
+    /// if it had not advanced yet.
+    pub fn clock_at(&self, name: &str, time: Duration) -> u64 {
+        let ticks = self.clock_ticks(name).take_while(|(t, _)| *t <= time);
+        ticks.last().map_or(0, |(_, value)| value)
+    }
+
+    /// When the [`LogicalClock`](crate::time::LogicalClock) named `name` reached `value` or a
+    /// later value, if it did.
+    pub fn time_of_clock(&self, name: &str, value: u64) -> Option<Duration> {
+        let mut ticks = self.clock_ticks(name);
+        ticks.find(|(_, v)| *v >= value).map(|(time, _)| time)
+    }
+
+    fn clock_ticks<'a>(&'a self, clock: &'a str) -> impl Iterator<Item = (Duration, u64)> + 'a {
+        self.events.iter().filter_map(move |e| match &e.kind {
+            EventKind::Clock { name, value } if name == clock => Some((e.time, *value)),
+            _ => None,
+        })
+    }
+
+    /// Find where this timeline and `other` diverge, e.g. the timelines of the same seed before
+    /// and after a refactor. Returns `None` if they are identical.
+    ///
+    /// Events are compared as displayed, including their time.
+    pub fn diff(&self, other: &Timeline) -> Option<Divergence> {
+        let lines = |t: &Timeline| t.events.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        Divergence::find(&lines(self), &lines(other))
+    }
+
+    /// The times of the events matching the pattern, e.g. the attempts of a request retried with
+    /// a backoff, see [`Attempts`].
+    pub fn attempts(&self, pattern: &Pattern) -> Attempts {
+        let matching = self.events.iter().filter(|e| pattern.matches(e));
+        Attempts {
+            pattern: pattern.to_string(),
+            times: matching.map(|e| e.time).collect(),
+        }
+    }
+
+    /// Write the timeline to `path`, one event per line, to compare it with the timeline of
+    /// another build with [`diff_files`].
+    pub fn write_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let (path, contents) = (path.as_ref(), self.to_string());
+        off_thread(|| fs::write(path, contents))
+    }
+}
+
+impl fmt::Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{event}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Find where the timelines written to `left` and `right` by [`Timeline::write_to`] diverge,
+/// see [`Timeline::diff`].
+pub fn diff_files(
+    left: impl AsRef<Path>,
+    right: impl AsRef<Path>,
+) -> io::Result<Option<Divergence>> {
+    let read = |path: &Path| {
+        let contents = off_thread(|| fs::read_to_string(path))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Ok::<_, io::Error>(contents.lines().map(str::to_string).collect::<Vec<_>>())
+    };
+    let (left, right) = (read(left.as_ref())?, read(right.as_ref())?);
+    Ok(Divergence::find(&left, &right))
+}
+
+/// The first difference between two timelines, with the events around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The index of the first event that differs.
+    pub index: usize,
+    /// The last events common to both timelines.
+    pub common: Vec<String>,
+    /// The events of the left timeline from the divergence, empty if it ended there.
+    pub left: Vec<String>,
+    /// The events of the right timeline from the divergence, empty if it ended there.
+    pub right: Vec<String>,
+}
+
+impl Divergence {
+    fn find(left: &[String], right: &[String]) -> Option<Self> {
+        let index = left.iter().zip(right).take_while(|(l, r)| l == r).count();
+        if index == left.len() && index == right.len() {
+            return None;
+        }
+        let after =
+            |events: &[String]| events[index..].iter().take(DIFF_CONTEXT).cloned().collect();
+        Some(Divergence {
+            index,
+            common: left[index.saturating_sub(DIFF_CONTEXT)..index].to_vec(),
+            left: after(left),
+            right: after(right),
+        })
+    }
+}
+
+/// Formats the divergence like a unified diff.
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "timelines diverge at event {}:", self.index)?;
+        for event in &self.common {
+            writeln!(f, "  {event}")?;
+        }
+        for event in &self.left {
+            writeln!(f, "- {event}")?;
+        }
+        if self.left.is_empty() {
+            writeln!(f, "- (end)")?;
+        }
+        for event in &self.right {
+            writeln!(f, "+ {event}")?;
+        }
+        if self.right.is_empty() {
+            writeln!(f, "+ (end)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The times of repeated attempts, e.g. the retries of a request, see [`Timeline::attempts`].
+///
+/// A backoff regression often shows up after a partition heals, when all clients retry at
+/// once. Narrow the attempts to the time after the heal, and check they are spread out:
+///
+/// ```ignore
+/// let joins = timeline().attempts(&msg_sent().tag(JOIN).to(server)).since(healed);
+/// joins.assert_spread(Duration::from_millis(100), 3);
+/// ```
+///
+/// Intervals are compared with a tolerance of 100us, for the jitter of the simulated scheduler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempts {
+    pattern: String,
+    times: Vec<Duration>,
+}
+
+impl Attempts {
+    /// The times of the attempts, since the start of the run.
+    pub fn times(&self) -> &[Duration] {
+        &self.times
+    }
+
+    /// The number of attempts.
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    /// Returns true if there was no attempt.
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// Only keep the attempts made at or after `time`.
+    pub fn since(mut self, time: Duration) -> Self {
+        self.times.retain(|t| *t >= time);
+        self
+    }
+
+    /// Only keep the attempts made before `time`.
+    pub fn until(mut self, time: Duration) -> Self {
+        self.times.retain(|t| *t < time);
+        self
+    }
+
+    /// The time between each attempt and the next.
+    pub fn intervals(&self) -> Vec<Duration> {
+        self.times.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    /// The largest number of attempts within any `window`.
+    pub fn max_in_window(&self, window: Duration) -> usize {
+        let mut start = 0;
+        let mut max = 0;
+        for (end, time) in self.times.iter().enumerate() {
+            while start <= end && *time - self.times[start] >= window {
+                start += 1;
+            }
+            max = max.max(end + 1 - start);
+        }
+        max
+    }
+
+    /// Assert that every interval between attempts is at least `factor` times the previous one.
+    #[track_caller]
+    pub fn assert_backoff(&self, factor: f64) {
+        let intervals = self.intervals();
+        for (i, w) in intervals.windows(2).enumerate() {
+            let expected = w[0].mul_f64(factor);
+            if w[1] + INTERVAL_TOLERANCE < expected {
+                panic!(
+                    "backoff assertion failed: attempt {} came {:?} after the previous one, \
+                     expected at least {expected:?}\n{self}",
+                    i + 2,
+                    w[1],
+                );
+            }
+        }
+    }
+
+    /// Assert that no interval between attempts is longer than `max`.
+    #[track_caller]
+    pub fn assert_capped(&self, max: Duration) {
+        let intervals = self.intervals();
+        if let Some(i) = intervals.iter().position(|d| *d > max + INTERVAL_TOLERANCE) {
+            panic!(
+                "backoff assertion failed: attempt {} came {:?} after the previous one, \
+                 expected at most {max:?}\n{self}",
+                i + 1,
+                intervals[i],
+            );
+        }
+    }
+
+    /// Assert that there are at most `max` attempts within any `window`, e.g. that clients do
+    /// not all retry at once.
+    #[track_caller]
+    pub fn assert_spread(&self, window: Duration, max: usize) {
+        let burst = self.max_in_window(window);
+        if burst > max {
+            panic!(
+                "backoff assertion failed: {burst} attempts within {window:?}, \
+                 expected at most {max}\n{self}"
+            );
+        }
+    }
+}
+
+impl fmt::Display for Attempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "attempts matching {}:", self.pattern)?;
+        let mut previous = None;
+        for (i, time) in self.times.iter().enumerate() {
+            write!(f, "{i:>4}: {:>12.6}s", time.as_secs_f64())?;
+            if let Some(previous) = previous {
+                write!(f, " (+{:?})", *time - previous)?;
+            }
+            writeln!(f)?;
+            previous = Some(*time);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatternKind {
+    MsgSent,
+    MsgDelivered,
+    NodeCreate,
+    NodeKill,
+    NodeRestart,
+    NodePause,
+    NodeResume,
+    NodeDelete,
+    NodeExit,
+    NodeGiveUp,
+    NodeReady,
+    LatencySpike,
+    HostStall,
+    DomainFault,
+    Scheduled,
+    Choice,
+    Clock,
+    InvariantFailed,
+    Custom,
+}
+
+/// A pattern matching recorded events.
+///
+/// Patterns are built with the functions in this module, e.g. [`msg_sent`], and refined with
+/// builder methods.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    kind: PatternKind,
+    tag: Option<u64>,
+    from: Option<NodeId>,
+    to: Option<NodeId>,
+    node: Option<NodeId>,
+    name: Option<String>,
+    value: Option<u64>,
+}
+
+impl Pattern {
+    fn new(kind: PatternKind) -> Self {
+        Pattern {
+            kind,
+            tag: None,
+            from: None,
+            to: None,
+            node: None,
+            name: None,
+            value: None,
+        }
+    }
+
+    fn node_event(kind: PatternKind, node: NodeId) -> Self {
+        Pattern {
+            node: Some(node),
+            ..Self::new(kind)
+        }
+    }
+
+    /// Only match messages with the given tag.
+    pub fn tag(mut self, tag: u64) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Only match messages sent by the given node.
+    pub fn from(mut self, node: NodeId) -> Self {
+        self.from = Some(node);
+        self
+    }
+
+    /// Only match messages sent to the given node.
+    pub fn to(mut self, node: NodeId) -> Self {
+        self.to = Some(node);
+        self
+    }
+
+    /// Only match custom events and choices made by the given node.
+    pub fn on(mut self, node: NodeId) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Only match clock events advancing the clock to the given value.
+    pub fn value(mut self, value: u64) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    /// Returns true if the event matches the pattern.
+    pub fn matches(&self, event: &Event) -> bool {
+        fn eq<T: PartialEq>(expected: &Option<T>, actual: &T) -> bool {
+            match expected {
+                Some(expected) => expected == actual,
+                None => true,
+            }
+        }
+        match &event.kind {
+            EventKind::MsgSent { from, to, tag } | EventKind::MsgDelivered { from, to, tag } => {
+                let kind = match event.kind {
+                    EventKind::MsgSent { .. } => PatternKind::MsgSent,
+                    _ => PatternKind::MsgDelivered,
+                };
+                self.kind == kind && eq(&self.from, from) && eq(&self.to, to) && eq(&self.tag, tag)
+            }
+            EventKind::NodeCreate(node) => {
+                self.kind == PatternKind::NodeCreate && eq(&self.node, node)
+            }
+            EventKind::NodeKill(node) => self.kind == PatternKind::NodeKill && eq(&self.node, node),
+            EventKind::NodeRestart(node) => {
+                self.kind == PatternKind::NodeRestart && eq(&self.node, node)
+            }
+            EventKind::NodePause(node) => {
+                self.kind == PatternKind::NodePause && eq(&self.node, node)
+            }
+            EventKind::NodeResume(node) => {
+                self.kind == PatternKind::NodeResume && eq(&self.node, node)
+            }
+            EventKind::NodeDelete(node) => {
+                self.kind == PatternKind::NodeDelete && eq(&self.node, node)
+            }
+            EventKind::NodeExit { node, .. } => {
+                self.kind == PatternKind::NodeExit && eq(&self.node, node)
+            }
+            EventKind::NodeGiveUp(node) => {
+                self.kind == PatternKind::NodeGiveUp && eq(&self.node, node)
+            }
+            EventKind::NodeReady(node) => {
+                self.kind == PatternKind::NodeReady && eq(&self.node, node)
+            }
+            EventKind::LatencySpike { node, .. } => {
+                self.kind == PatternKind::LatencySpike && eq(&self.node, node)
+            }
+            EventKind::HostStall { .. } => self.kind == PatternKind::HostStall,
+            EventKind::DomainFault { domain, fault, .. } => {
+                self.kind == PatternKind::DomainFault
+                    && eq(&self.name, &format!("{fault} {domain}"))
+            }
+            EventKind::Scheduled { name, .. } => {
+                self.kind == PatternKind::Scheduled && eq(&self.name, name)
+            }
+            EventKind::Choice { node, .. } => {
+                self.kind == PatternKind::Choice && eq(&self.node, node)
+            }
+            EventKind::Clock { name, value } => {
+                self.kind == PatternKind::Clock && eq(&self.name, name) && eq(&self.value, value)
+            }
+            EventKind::InvariantFailed { node, name, .. } => {
+                self.kind == PatternKind::InvariantFailed
+                    && eq(&self.node, node)
+                    && eq(&self.name, name)
+            }
+            EventKind::Custom { node, name } => {
+                self.kind == PatternKind::Custom && eq(&self.node, node) && eq(&self.name, name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+        if let Some(name) = &self.name {
+            write!(f, " name={name}")?;
+        }
+        if let Some(node) = &self.node {
+            write!(f, " node={node}")?;
+        }
+        if let Some(from) = &self.from {
+            write!(f, " from={from}")?;
+        }
+        if let Some(to) = &self.to {
+            write!(f, " to={to}")?;
+        }
+        if let Some(tag) = &self.tag {
+            write!(f, " tag={}", Tag(*tag))?;
+        }
+        if let Some(value) = &self.value {
+            write!(f, " value={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Match a message accepted by the network.
+pub fn msg_sent() -> Pattern {
+    Pattern::new(PatternKind::MsgSent)
+}
+
+/// Match a message delivered to its destination.
+pub fn msg_delivered() -> Pattern {
+    Pattern::new(PatternKind::MsgDelivered)
+}
+
+/// Match the creation of a node.
+pub fn node_create(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeCreate, node)
+}
+
+/// Match a node being killed.
+pub fn node_kill(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeKill, node)
+}
+
+/// Match a node being restarted.
+pub fn node_restart(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeRestart, node)
+}
+
+/// Match a node being paused.
+pub fn node_pause(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodePause, node)
+}
+
+/// Match a node being resumed.
+pub fn node_resume(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeResume, node)
+}
+
+/// Match a node being deleted.
+pub fn node_delete(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeDelete, node)
+}
+
+/// Match a node exiting.
+pub fn node_exit(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeExit, node)
+}
+
+/// Match the supervisor of a node giving up restarting it.
+pub fn node_give_up(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeGiveUp, node)
+}
+
+/// Match a node becoming ready.
+pub fn node_ready(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::NodeReady, node)
+}
+
+/// Match a latency spike on a node.
+pub fn latency_spike(node: NodeId) -> Pattern {
+    Pattern::node_event(PatternKind::LatencySpike, node)
+}
+
+/// Match a stall of the simulation host.
+pub fn host_stall() -> Pattern {
+    Pattern::new(PatternKind::HostStall)
+}
+
+/// Match a choice made with [`choose`](crate::rand::choose) or [`maybe`](crate::rand::maybe).
+pub fn choice() -> Pattern {
+    Pattern::new(PatternKind::Choice)
+}
+
+/// Match a fault injected into a failure domain.
+pub fn domain_fault(fault: DomainFaultKind, domain: &FailureDomain) -> Pattern {
+    Pattern {
+        name: Some(format!("{fault} {domain}")),
+        ..Pattern::new(PatternKind::DomainFault)
+    }
+}
+
+/// Match a run of a scheduled event, see
+/// [`Handle::schedule_at`](crate::runtime::Handle::schedule_at).
+pub fn scheduled(name: impl Into<String>) -> Pattern {
+    Pattern {
+        name: Some(name.into()),
+        ..Pattern::new(PatternKind::Scheduled)
+    }
+}
+
+/// Match an advance of the [`LogicalClock`](crate::time::LogicalClock) named `name`.
+pub fn clock(name: impl Into<String>) -> Pattern {
+    Pattern {
+        name: Some(name.into()),
+        ..Pattern::new(PatternKind::Clock)
+    }
+}
+
+/// Match a violation of the invariant named `name`, see
+/// [`invariant_failed`](crate::orchestrator::invariant_failed).
+pub fn invariant_failed(name: impl Into<String>) -> Pattern {
+    Pattern {
+        name: Some(name.into()),
+        ..Pattern::new(PatternKind::InvariantFailed)
+    }
+}
+
+/// Match a custom event recorded with [`record`].
+pub fn custom(name: impl Into<String>) -> Pattern {
+    Pattern {
+        name: Some(name.into()),
+        ..Pattern::new(PatternKind::Custom)
+    }
+}
+
+/// An ordered sequence of patterns, usually built with [`seq!`](crate::seq).
+#[derive(Debug, Clone)]
+pub struct Seq(pub Vec<Pattern>);
+
+impl From<Pattern> for Seq {
+    fn from(pattern: Pattern) -> Self {
+        Seq(vec![pattern])
+    }
+}
+
+impl From<Vec<Pattern>> for Seq {
+    fn from(patterns: Vec<Pattern>) -> Self {
+        Seq(patterns)
+    }
+}
+
+/// Build a [`Seq`](crate::trace::Seq) of patterns that must match events in order.
+#[macro_export]
+macro_rules! seq {
+    ($($pattern:expr),* $(,)?) => {
+        $crate::trace::Seq(vec![$($pattern),*])
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        net::{network::Payload, Endpoint},
+        runtime::Runtime,
+        time::sleep,
+        SimConfig,
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn timeline() {
+        let mut config = SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let addr1 = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let addr2 = "10.0.0.2:1".parse::<SocketAddr>().unwrap();
+        let node1 = runtime.create_node().ip(addr1.ip()).build();
+        let node2 = runtime.create_node().ip(addr2.ip()).build();
+        let (id1, id2) = (node1.id(), node2.id());
+
+        node2.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr2).await.unwrap();
+            let mut buf = [0; 1];
+            net.recv_from(7, &mut buf).await.unwrap();
+            record("got vote");
+        });
+
+        let f = node1.spawn(async move {
+            let net = Endpoint::bind(libc::SOCK_STREAM, addr1).await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+            net.send_to(addr2, 7, Payload::new_udp(Box::new(vec![1u8])))
+                .await
+                .unwrap();
+        });
+
+        runtime.block_on(async move {
+            f.await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+            let handle = crate::runtime::Handle::current();
+            handle.kill(id1);
+            handle.restart(id1);
+
+            let timeline = super::timeline();
+            timeline.assert(crate::seq!(
+                node_create(id1),
+                msg_sent().tag(7).from(id1).to(id2),
+                msg_delivered().tag(7).to(id2),
+                custom("got vote").on(id2),
+                node_kill(id1),
+                node_restart(id1),
+            ));
+            timeline.assert_never(&msg_sent().from(id2));
+            assert_eq!(timeline.count(&msg_delivered()), 1);
+
+            let err = timeline
+                .check(&crate::seq!(node_restart(id1), msg_sent()))
+                .unwrap_err();
+            assert!(err.contains("step 1"));
+        });
+    }
+
+    #[test]
+    fn diff() {
+        // the same seed, with one more message after the second
+        let run = |tags: &'static [u64]| {
+            let mut config = SimConfig::default();
+            config.trace.enabled = true;
+            let runtime = Runtime::with_seed_and_config(1, config);
+            let addr = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+            let node = runtime.create_node().ip(addr.ip()).build();
+            let f = node.spawn(async move {
+                let net = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                for tag in tags {
+                    sleep(Duration::from_secs(1)).await;
+                    let data = Payload::new_udp(Box::new(()));
+                    net.send_to_raw(addr, *tag, data).await.unwrap();
+                }
+            });
+            runtime.block_on(async move {
+                f.await.unwrap();
+                super::timeline()
+            })
+        };
+        let before = run(&[1, 2, 3]);
+        assert_eq!(before.diff(&run(&[1, 2, 3])), None);
+        let after = run(&[1, 2, 4, 3]);
+        let divergence = before.diff(&after).unwrap();
+        assert_eq!(divergence.index, 6);
+        assert_eq!(
+            divergence.common,
+            before.events()[1..6]
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert!(divergence.left[0].contains("tag=0x3"), "{divergence}");
+        assert!(divergence.right[0].contains("tag=0x4"), "{divergence}");
+        let truncated = Timeline {
+            events: before.events()[..2].to_vec(),
+        };
+        let divergence = truncated.diff(&before).unwrap();
+        assert_eq!((divergence.index, divergence.left.len()), (2, 0));
+        assert!(divergence.to_string().contains("- (end)"));
+
+        let dir = std::env::temp_dir().join(format!("msim-trace-diff-{}", std::process::id()));
+        off_thread(|| fs::create_dir_all(&dir)).unwrap();
+        before.write_to(dir.join("before.txt")).unwrap();
+        after.write_to(dir.join("after.txt")).unwrap();
+        let divergence = diff_files(dir.join("before.txt"), dir.join("after.txt")).unwrap();
+        assert_eq!(divergence, before.diff(&after));
+        off_thread(|| fs::remove_dir_all(&dir)).unwrap();
+    }
+
+    #[test]
+    fn attempts() {
+        let mut config = SimConfig::default();
+        config.trace.enabled = true;
+        let runtime = Runtime::with_seed_and_config(0, config);
+        let server = "10.0.0.1:1".parse::<SocketAddr>().unwrap();
+        let server_node = runtime.create_node().ip(server.ip()).build();
+        let server_id = server_node.id();
+        server_node.spawn(async move {
+            let _net = Endpoint::bind(libc::SOCK_DGRAM, server).await.unwrap();
+            futures::future::pending::<()>().await;
+        });
+        // clients retrying with a capped exponential backoff, the first one starting later
+        let clients: Vec<_> = (2..=4)
+            .map(|i| {
+                let addr = SocketAddr::new(format!("10.0.0.{i}").parse().unwrap(), 1);
+                let node = runtime.create_node().ip(addr.ip()).build();
+                node.spawn(async move {
+                    let net = Endpoint::bind(libc::SOCK_DGRAM, addr).await.unwrap();
+                    let delay = if i == 2 { 37 } else { 1 };
+                    sleep(Duration::from_millis(delay)).await;
+                    let mut backoff = Duration::from_millis(100);
+                    for _ in 0..7 {
+                        let data = Payload::new_udp(Box::new(()));
+                        // the server never answers
+                        net.send_to_raw(server, 1, data).await.unwrap();
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(Duration::from_millis(800));
+                    }
+                });
+                node.id()
+            })
+            .collect();
+
+        runtime.block_on(async move {
+            sleep(Duration::from_secs(10)).await;
+            let timeline = super::timeline();
+            let attempts = timeline.attempts(&msg_sent().tag(1).from(clients[0]));
+            assert_eq!(attempts.len(), 7);
+            let millis: Vec<_> = attempts.intervals().iter().map(|d| d.as_millis()).collect();
+            assert_eq!(millis, [100, 200, 400, 800, 800, 800]);
+            attempts
+                .clone()
+                .until(Duration::from_secs(1))
+                .assert_backoff(2.0);
+            attempts.assert_capped(Duration::from_millis(800));
+            let err = std::panic::catch_unwind(|| attempts.assert_backoff(2.0)).unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.contains("attempt 5 came 800"), "{msg}");
+
+            // the two other clients retry in lockstep
+            let all = timeline.attempts(&msg_sent().tag(1).to(server_id));
+            assert_eq!(all.len(), 21);
+            all.assert_spread(Duration::from_millis(10), 2);
+            assert_eq!(
+                all.since(Duration::from_secs(3))
+                    .max_in_window(Duration::ZERO),
+                0
+            );
+        });
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let runtime = Runtime::new();
+        let node = runtime.create_node().build();
+        runtime.block_on(async move {
+            crate::runtime::Handle::current().kill(node.id());
+            assert!(super::timeline().events().is_empty());
+        });
+    }
+}
@@ -1 +1,7 @@
 pub mod mpsc;
+
+/// Run `f` on another thread. File io is intercepted on simulation threads, where it needs the
+/// context of a node, so io outside of nodes is done from another thread.
+pub(crate) fn off_thread<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|s| s.spawn(f).join().unwrap())
+}
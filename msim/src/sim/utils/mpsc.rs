@@ -84,6 +84,32 @@ impl<T> Receiver<T> {
         }
     }
 
+    /// Like [`try_recv_random`](Self::try_recv_random), but returns instead the element that
+    /// comes first by `before`, starting from the random one.
+    ///
+    /// The random index is drawn in any case, so that the sequence of random numbers does not
+    /// depend on `before`.
+    pub fn try_recv_random_by(
+        &self,
+        rng: &GlobalRng,
+        before: impl Fn(&T, &T) -> bool,
+    ) -> Result<T, TryRecvError> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if !queue.is_empty() {
+            let mut idx = rng.with(|rng| rng.gen_range(0..queue.len()));
+            for i in 0..queue.len() {
+                if before(&queue[i], &queue[idx]) {
+                    idx = i;
+                }
+            }
+            Ok(queue.swap_remove(idx))
+        } else if Arc::weak_count(&self.inner) == 0 {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
     pub fn clear_inner(&self) {
         let mut old = Vec::new();
         {
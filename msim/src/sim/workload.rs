@@ -0,0 +1,215 @@
+//! Traffic generation utilities.
+//!
+//! An [`ArrivalProcess`] describes when requests arrive, and [`Arrivals`] turns it into a
+//! deterministic schedule in virtual time, so that tests can drive a system with realistic or
+//! adversarial traffic without hand-rolling timing loops.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use msim::workload::{Arrivals, Poisson};
+//!
+//! // on average 100 requests per second
+//! let mut arrivals = Arrivals::new(Poisson::new(100.0));
+//! loop {
+//!     arrivals.tick().await;
+//!     client.send_request().await;
+//! }
+//! ```
+
+use crate::{
+    net::LatencyDistribution,
+    rand::{thread_rng, GlobalRng, Rng},
+    time::{sleep_until, Duration, Instant},
+};
+
+/// A process generating the intervals between consecutive arrivals.
+pub trait ArrivalProcess {
+    /// Sample the interval until the next arrival.
+    fn next_interval(&mut self, rng: &mut GlobalRng) -> Duration;
+}
+
+/// Sample an exponentially distributed interval with the given rate per second.
+fn exponential(rng: &mut GlobalRng, rate: f64) -> Duration {
+    // 1 - u is in (0, 1], so the log is finite.
+    let u = 1.0 - rng.gen::<f64>();
+    Duration::from_secs_f64(-u.ln() / rate)
+}
+
+/// A Poisson process: arrivals are independent, with exponentially distributed intervals.
+#[derive(Debug, Clone)]
+pub struct Poisson {
+    rate: f64,
+}
+
+impl Poisson {
+    /// Create a Poisson process with `rate` arrivals per second on average.
+    pub fn new(rate: f64) -> Self {
+        assert!(rate > 0.0, "rate must be positive: {rate}");
+        Self { rate }
+    }
+}
+
+impl ArrivalProcess for Poisson {
+    fn next_interval(&mut self, rng: &mut GlobalRng) -> Duration {
+        exponential(rng, self.rate)
+    }
+}
+
+/// An on/off bursty process.
+///
+/// During "on" periods arrivals follow a Poisson process with the given rate; during "off"
+/// periods there are no arrivals. The length of each period is sampled from its distribution.
+#[derive(Debug, Clone)]
+pub struct OnOff {
+    rate: f64,
+    on: LatencyDistribution,
+    off: LatencyDistribution,
+    /// Time left in the current on period, if one has started.
+    remaining_on: Option<Duration>,
+}
+
+impl OnOff {
+    /// Create an on/off process with `rate` arrivals per second during on periods.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `on` or `off` is always zero, as the process would never advance.
+    pub fn new(rate: f64, on: LatencyDistribution, off: LatencyDistribution) -> Self {
+        assert!(rate > 0.0, "rate must be positive: {rate}");
+        assert!(
+            !on.mean().is_zero() && !off.mean().is_zero(),
+            "on and off periods must not be zero"
+        );
+        Self {
+            rate,
+            on,
+            off,
+            remaining_on: None,
+        }
+    }
+}
+
+impl ArrivalProcess for OnOff {
+    fn next_interval(&mut self, rng: &mut GlobalRng) -> Duration {
+        let mut wait = Duration::ZERO;
+        loop {
+            let remaining = match self.remaining_on {
+                Some(remaining) => remaining,
+                None => self.on.sample(rng),
+            };
+            // the exponential distribution is memoryless, so we can sample a fresh interval at
+            // the start of each on period.
+            let interval = exponential(rng, self.rate);
+            if interval < remaining {
+                self.remaining_on = Some(remaining - interval);
+                return wait + interval;
+            }
+            wait += remaining + self.off.sample(rng);
+            self.remaining_on = None;
+        }
+    }
+}
+
+/// A schedule of arrivals in virtual time.
+///
+/// Arrival times are computed from the start of the schedule rather than from the time each
+/// arrival is handled, so slow handlers do not shift the schedule.
+pub struct Arrivals<P> {
+    process: P,
+    rng: GlobalRng,
+    next: Instant,
+}
+
+impl<P: ArrivalProcess> Arrivals<P> {
+    /// Start a schedule now, drawing from the current runtime's random generator.
+    pub fn new(process: P) -> Self {
+        Self::with_rng(process, thread_rng())
+    }
+
+    /// Start a schedule now, drawing from the given random generator.
+    pub fn with_rng(mut process: P, mut rng: GlobalRng) -> Self {
+        let next = Instant::now() + process.next_interval(&mut rng);
+        Self { process, rng, next }
+    }
+
+    /// The time of the next arrival.
+    pub fn next_arrival(&self) -> Instant {
+        self.next
+    }
+
+    /// Wait for the next arrival and return its scheduled time.
+    ///
+    /// If the arrival time has already passed, returns immediately.
+    pub async fn tick(&mut self) -> Instant {
+        let at = self.next;
+        sleep_until(at).await;
+        self.next = at + self.process.next_interval(&mut self.rng);
+        at
+    }
+}
+
+/// Compute the offsets of all arrivals of `process` within `horizon`.
+pub fn schedule(
+    mut process: impl ArrivalProcess,
+    rng: &mut GlobalRng,
+    horizon: Duration,
+) -> Vec<Duration> {
+    let mut offsets = vec![];
+    let mut t = process.next_interval(rng);
+    while t < horizon {
+        offsets.push(t);
+        t += process.next_interval(rng);
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rand::RngStream, runtime::Runtime};
+
+    #[test]
+    fn poisson_rate() {
+        let mut rng = GlobalRng::new_with_seed(1).stream(RngStream::Application);
+        let offsets = schedule(Poisson::new(100.0), &mut rng, Duration::from_secs(100));
+        // expect ~10000 arrivals
+        assert!((9500..10500).contains(&offsets.len()), "{}", offsets.len());
+        assert!(offsets.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn on_off_bursts() {
+        let mut rng = GlobalRng::new_with_seed(1).stream(RngStream::Application);
+        let process = OnOff::new(
+            1000.0,
+            LatencyDistribution::Constant(Duration::from_secs(1)),
+            LatencyDistribution::Constant(Duration::from_secs(9)),
+        );
+        let offsets = schedule(process, &mut rng, Duration::from_secs(100));
+        // 10 bursts of ~1000 arrivals, all within the first second of each 10s cycle.
+        assert!((9500..10500).contains(&offsets.len()), "{}", offsets.len());
+        assert!(offsets.iter().all(|t| t.as_secs() % 10 == 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be zero")]
+    fn on_off_zero() {
+        let second = LatencyDistribution::Constant(Duration::from_secs(1));
+        OnOff::new(1.0, LatencyDistribution::Constant(Duration::ZERO), second);
+    }
+
+    #[test]
+    fn arrivals() {
+        let runtime = Runtime::new();
+        runtime.block_on(async {
+            let start = Instant::now();
+            let mut arrivals = Arrivals::new(Poisson::new(10.0));
+            let mut count = 0;
+            while arrivals.tick().await - start < Duration::from_secs(10) {
+                count += 1;
+            }
+            assert!((50..150).contains(&count), "{count}");
+        });
+    }
+}
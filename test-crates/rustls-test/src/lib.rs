@@ -0,0 +1,88 @@
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use msim_rustls::rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use msim_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use msim_rustls::{Connector, TlsListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    use msim_macros::sim_test;
+
+    fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["server.test".into()]).unwrap();
+        let cert_der = cert.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key.into())
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        (Arc::new(server), Arc::new(client))
+    }
+
+    #[sim_test]
+    async fn test() {
+        let handle = msim::runtime::Handle::current();
+        let server_addr: SocketAddr = "10.1.1.1:443".parse().unwrap();
+        let server = handle.create_node().ip(server_addr.ip()).build();
+        let client = handle.create_node().ip("10.1.1.2".parse().unwrap()).build();
+
+        let (server_config, client_config) = configs();
+
+        server.spawn(async move {
+            let listener = TlsListener::bind(server_addr, server_config).await.unwrap();
+            loop {
+                // handshakes with a mismatched server name fail
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let mut buf = [0; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                    stream.flush().await.unwrap();
+                    // keep the connection open until the client is done.
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+
+        client
+            .spawn(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                // a client that never starts its handshake does not hold back the others
+                let _stalled = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+                let connector =
+                    Connector::new(client_config).handshake_cost(Duration::from_millis(10));
+                let name = ServerName::try_from("server.test").unwrap();
+
+                let start = Instant::now();
+                let mut stream = connector.connect(server_addr, name).await.unwrap();
+                // the handshake takes at least the configured cost
+                assert!(start.elapsed() >= Duration::from_millis(10));
+
+                stream.write_all(b"hello").await.unwrap();
+                stream.flush().await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                // the certificate does not match another name
+                let name = ServerName::try_from("other.test").unwrap();
+                assert!(connector.connect(server_addr, name).await.is_err());
+            })
+            .await
+            .unwrap();
+    }
+}
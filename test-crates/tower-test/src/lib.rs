@@ -0,0 +1,117 @@
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use http::{Request, Response, Uri};
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::{Bytes, Incoming as Body};
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use msim::net::NetSim;
+    use msim::plugin::simulator;
+    use msim_tower::{Connector, Incoming};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower_service::Service;
+
+    use msim_macros::sim_test;
+
+    #[sim_test]
+    async fn test() {
+        let handle = msim::runtime::Handle::current();
+        let server_addr: SocketAddr = "10.1.1.1:80".parse().unwrap();
+        let server = handle.create_node().ip(server_addr.ip()).build();
+        let client = handle.create_node().ip("10.1.1.2".parse().unwrap()).build();
+
+        server.spawn(async move {
+            let mut incoming = Incoming::bind(server_addr).await.unwrap().nodelay(true);
+            while let Some(stream) = incoming.next().await {
+                let mut stream = stream.unwrap();
+                assert!(stream.nodelay().unwrap());
+                tokio::spawn(async move {
+                    let mut buf = [0; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                    stream.flush().await.unwrap();
+                    // keep the connection open until the client is done.
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+
+        let server_id = server.id();
+        client
+            .spawn(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let mut connector = Connector::new().connect_timeout(Duration::from_secs(5));
+
+                // the port defaults to 80 for http
+                let uri = Uri::from_static("http://10.1.1.1/echo");
+                let mut stream = connector.call(uri.clone()).await.unwrap().into_inner();
+                assert_eq!(stream.peer_addr().unwrap(), server_addr);
+                stream.write_all(b"hello").await.unwrap();
+                stream.flush().await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                // a uri without an authority cannot be connected to
+                let err = connector.call(Uri::from_static("/echo")).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+                // connections to an unreachable server time out
+                simulator::<NetSim>().disconnect(server_id);
+                let err = connector.call(uri).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            })
+            .await
+            .unwrap();
+    }
+
+    #[sim_test]
+    async fn hyper_client() {
+        let handle = msim::runtime::Handle::current();
+        let server_addr: SocketAddr = "10.1.1.1:8080".parse().unwrap();
+        let server = handle.create_node().ip(server_addr.ip()).build();
+        let client = handle.create_node().ip("10.1.1.2".parse().unwrap()).build();
+
+        server.spawn(async move {
+            let mut incoming = Incoming::bind(server_addr).await.unwrap();
+            while let Some(stream) = incoming.next().await {
+                let service = service_fn(|req: Request<Body>| async move {
+                    let path = req.uri().path().to_owned();
+                    let body = req.into_body().collect().await?.to_bytes();
+                    let reply = format!("{path}: {}", String::from_utf8_lossy(&body));
+                    Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from(reply))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream.unwrap()), service),
+                );
+            }
+        });
+
+        client
+            .spawn(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let uri = Uri::from_static("http://10.1.1.1:8080/echo");
+                let io = Connector::new().call(uri.clone()).await.unwrap();
+                let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await.unwrap();
+                tokio::spawn(conn);
+
+                for body in ["hello", "world"] {
+                    let req = Request::post(uri.clone())
+                        .body(Full::new(Bytes::from(body)))
+                        .unwrap();
+                    let resp = sender.send_request(req).await.unwrap();
+                    assert!(resp.status().is_success());
+                    let reply = resp.into_body().collect().await.unwrap().to_bytes();
+                    assert_eq!(reply, format!("/echo: {body}").as_bytes());
+                }
+            })
+            .await
+            .unwrap();
+    }
+}